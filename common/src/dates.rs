@@ -0,0 +1,140 @@
+use chrono::{DateTime, TimeZone, Utc};
+use nostr_sdk::Event;
+
+pub const DATE_FORMAT: &str = "%Y.%m.%d";
+
+pub fn index_name_for_event(prefix: &str, event: &Event) -> anyhow::Result<String> {
+    let dt = chrono::Utc.timestamp_opt(event.created_at.as_i64(), 0);
+    if let Some(dt) = dt.single() {
+        Ok(format!("{}-{}", prefix, dt.format(DATE_FORMAT).to_string()))
+    } else {
+        Err(anyhow::anyhow!("failed to parse date: {}", event.created_at).into())
+    }
+}
+
+/// Why an event is not indexed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SkipReason {
+    /// older than the index TTL
+    TooOld,
+    /// further in the future than allowed
+    TooFuture,
+    /// not representable as an index date
+    BadTimestamp,
+    /// signed by another key than its pubkey, or with an id not matching its content
+    BadSignature,
+    /// content longer than allowed
+    TooLarge,
+    /// more tags than allowed
+    TooManyTags,
+    /// left out by the sampling rate of its source relay
+    Sampled,
+    /// a replaceable event older than the version indexed
+    Stale,
+}
+
+impl SkipReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::TooOld => "too_old",
+            SkipReason::TooFuture => "too_future",
+            SkipReason::BadTimestamp => "bad_timestamp",
+            SkipReason::BadSignature => "bad_signature",
+            SkipReason::TooLarge => "too_large",
+            SkipReason::TooManyTags => "too_many_tags",
+            SkipReason::Sampled => "sampled",
+            SkipReason::Stale => "stale",
+        }
+    }
+}
+
+/// Checks whether the dated index `index_name` is within the TTL and the allowed future.
+pub fn check_index_date(
+    index_name: &str,
+    current_time: &DateTime<Utc>,
+    ttl_in_days: Option<u64>,
+    allow_future_days: u64,
+) -> Result<(), SkipReason> {
+    let date_str = index_name.rsplit('-').next().unwrap_or("");
+    let index_date = chrono::NaiveDate::parse_from_str(date_str, DATE_FORMAT)
+        .map_err(|_| SkipReason::BadTimestamp)?;
+    let index_time = index_date
+        .and_hms_opt(0, 0, 0)
+        .ok_or(SkipReason::BadTimestamp)?;
+    let index_time = Utc.from_utc_datetime(&index_time);
+    let diff: chrono::Duration = current_time.signed_duration_since(index_time);
+
+    if let Some(ttl_in_days) = ttl_in_days {
+        let ttl_duration: chrono::Duration = chrono::Duration::days(ttl_in_days as i64);
+        if diff >= ttl_duration {
+            return Err(SkipReason::TooOld);
+        }
+    }
+    if diff < -chrono::Duration::days(allow_future_days as i64) {
+        return Err(SkipReason::TooFuture);
+    }
+    Ok(())
+}
+
+/// Checks whether `event` is within the TTL and the allowed future, by the date of its index.
+pub fn check_event_date(
+    event: &Event,
+    current_time: &DateTime<Utc>,
+    ttl_in_days: Option<u64>,
+    allow_future_days: u64,
+) -> Result<(), SkipReason> {
+    // the prefix does not matter for the date
+    let index_name = index_name_for_event("", event).map_err(|_| SkipReason::BadTimestamp)?;
+    check_index_date(&index_name, current_time, ttl_in_days, allow_future_days)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use nostr_sdk::{EventBuilder, Keys, Kind, Timestamp};
+
+    use crate::dates::{check_event_date, check_index_date, SkipReason};
+
+    #[test]
+    fn test_check_index_date() {
+        let current_time = chrono::DateTime::from_str("2023-03-20T00:00:00Z").unwrap();
+        assert_eq!(
+            check_index_date("nostr-2023.03.22", &current_time, Some(2), 1),
+            Err(SkipReason::TooFuture)
+        );
+        assert_eq!(
+            check_index_date("nostr-2023.03.18", &current_time, Some(2), 1),
+            Err(SkipReason::TooOld)
+        );
+        assert_eq!(
+            check_index_date("nostr-2023.03.19", &current_time, Some(2), 1),
+            Ok(())
+        );
+        assert_eq!(
+            check_index_date("nostr-foo", &current_time, Some(2), 1),
+            Err(SkipReason::BadTimestamp)
+        );
+    }
+
+    #[test]
+    fn test_check_event_date() {
+        let current_time = chrono::DateTime::from_str("2023-03-20T12:00:00Z").unwrap();
+        let mut event = EventBuilder::new(Kind::TextNote, "hello", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let day = 24 * 60 * 60;
+        // 2023-03-19T12:00:00Z
+        event.created_at = Timestamp::from(1679313600 - day);
+        assert_eq!(check_event_date(&event, &current_time, Some(2), 1), Ok(()));
+        assert_eq!(
+            check_event_date(&event, &current_time, Some(1), 1),
+            Err(SkipReason::TooOld)
+        );
+        event.created_at = Timestamp::from(1679313600 + 2 * day);
+        assert_eq!(
+            check_event_date(&event, &current_time, None, 1),
+            Err(SkipReason::TooFuture)
+        );
+    }
+}
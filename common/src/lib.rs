@@ -0,0 +1,7 @@
+//! Event checks shared by searchnos and the indexer, so the indexer drops the events searchnos
+//! would not index without depending on the whole server.
+
+pub mod dates;
+pub mod message;
+pub mod sampling;
+pub mod ttl;
@@ -0,0 +1,43 @@
+use nostr_sdk::Event;
+use serde_json::{json, Value};
+
+/// `EVENT` message of an indexer, naming the relay `event` was received from, which nostr
+/// clients cannot append.
+pub fn forwarded_event_message(event: &Event, relay: &str) -> String {
+    json!(["EVENT", event, relay]).to_string()
+}
+
+/// Event of an indexer's `EVENT` message and the relay it was received from, if named.
+pub fn parse_forwarded_event(msg: &[Value]) -> anyhow::Result<(Event, Option<&str>)> {
+    if msg.len() != 2 && msg.len() != 3 {
+        return Err(anyhow::anyhow!("invalid array length"));
+    }
+    let event = serde_json::from_value::<Event>(msg[1].clone())
+        .map_err(|e| anyhow::anyhow!("parsing event: {}", e))?;
+    Ok((event, msg.get(2).and_then(|relay| relay.as_str())))
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind};
+    use serde_json::{json, Value};
+
+    use crate::message::{forwarded_event_message, parse_forwarded_event};
+
+    #[test]
+    fn test_forwarded_event() {
+        let event = EventBuilder::new(Kind::TextNote, "hello", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let msg = forwarded_event_message(&event, "wss://relay.example.com");
+        let msg = serde_json::from_str::<Vec<Value>>(&msg).unwrap();
+        let (parsed, relay) = parse_forwarded_event(&msg).unwrap();
+        assert_eq!(parsed.id, event.id);
+        assert!(parsed.verify().is_ok());
+        assert_eq!(relay, Some("wss://relay.example.com"));
+
+        let (_, relay) = parse_forwarded_event(&[json!("EVENT"), json!(event)]).unwrap();
+        assert_eq!(relay, None);
+        assert!(parse_forwarded_event(&[json!("EVENT")]).is_err());
+    }
+}
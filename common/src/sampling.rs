@@ -0,0 +1,140 @@
+use nostr_sdk::Event;
+
+/// Fractions of events indexed per source relay, for firehose relays too large to index fully.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sampling {
+    /// rates by relay URL without a trailing slash
+    pub rates: Vec<(String, f64)>,
+    /// rate of relays not listed
+    pub default_rate: f64,
+    /// kinds sampled; others are always indexed
+    pub kinds: Vec<u32>,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Sampling {
+            rates: vec![],
+            default_rate: 1.0,
+            kinds: vec![1],
+        }
+    }
+}
+
+fn normalize_relay(relay: &str) -> &str {
+    relay.trim().trim_end_matches('/')
+}
+
+/// Whether an event id falls into the sampled fraction; every instance agrees on the same ids.
+fn is_sampled(id: &str, rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+    if rate <= 0.0 {
+        return false;
+    }
+    // event ids are SHA-256 hashes, so their prefixes are uniformly distributed
+    match id
+        .get(..16)
+        .and_then(|prefix| u64::from_str_radix(prefix, 16).ok())
+    {
+        Some(value) => (value as f64) < rate * u64::MAX as f64,
+        None => true,
+    }
+}
+
+impl Sampling {
+    /// Parses a comma-separated list like `wss://relay.example.com=0.1,*=0.5`, where `*` sets
+    /// the default rate.
+    pub fn parse(rates: &str, kinds: Option<&str>) -> anyhow::Result<Self> {
+        let mut sampling = Sampling::default();
+        for item in rates
+            .split(',')
+            .map(|item| item.trim())
+            .filter(|item| !item.is_empty())
+        {
+            let (relay, rate) = item
+                .rsplit_once('=')
+                .ok_or_else(|| anyhow::anyhow!("invalid sampling rate: {}", item))?;
+            let rate = rate.trim().parse::<f64>()?;
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(anyhow::anyhow!("sampling rate out of range: {}", rate));
+            }
+            match normalize_relay(relay) {
+                "*" => sampling.default_rate = rate,
+                relay => sampling.rates.push((relay.to_string(), rate)),
+            }
+        }
+        if let Some(kinds) = kinds {
+            sampling.kinds = kinds
+                .split(',')
+                .map(|kind| kind.trim())
+                .filter(|kind| !kind.is_empty())
+                .map(|kind| kind.parse::<u32>())
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(sampling)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.default_rate < 1.0 || self.rates.iter().any(|(_, rate)| *rate < 1.0)
+    }
+
+    /// Events without a source relay are not sampled, as the indexer samples those it forwards.
+    pub fn rate(&self, relay: Option<&str>) -> f64 {
+        match relay.map(normalize_relay) {
+            Some(relay) => self
+                .rates
+                .iter()
+                .find(|(r, _)| r == relay)
+                .map(|(_, rate)| *rate)
+                .unwrap_or(self.default_rate),
+            None => 1.0,
+        }
+    }
+
+    /// Whether an event received from `relay` is indexed.
+    pub fn keeps(&self, event: &Event, relay: Option<&str>) -> bool {
+        !self.kinds.contains(&event.kind.as_u32())
+            || is_sampled(&event.id.to_hex(), self.rate(relay))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sampling::{is_sampled, Sampling};
+
+    #[test]
+    fn test_parse() {
+        let sampling = Sampling::parse("wss://big.example.com/=0.1, *=0.5", Some("1,6")).unwrap();
+        assert_eq!(sampling.rate(Some("wss://big.example.com")), 0.1);
+        assert_eq!(sampling.rate(Some("wss://big.example.com/")), 0.1);
+        assert_eq!(sampling.rate(Some("wss://small.example.com")), 0.5);
+        assert_eq!(sampling.rate(None), 1.0);
+        assert_eq!(sampling.kinds, vec![1, 6]);
+        assert!(sampling.is_enabled());
+
+        let sampling = Sampling::parse("", None).unwrap();
+        assert_eq!(sampling, Sampling::default());
+        assert!(!sampling.is_enabled());
+
+        assert!(Sampling::parse("wss://big.example.com", None).is_err());
+        assert!(Sampling::parse("wss://big.example.com=1.5", None).is_err());
+    }
+
+    #[test]
+    fn test_is_sampled() {
+        let low = format!("{}{}", "0".repeat(16), "f".repeat(48));
+        let high = "f".repeat(64);
+        assert!(is_sampled(&low, 0.1));
+        assert!(!is_sampled(&high, 0.1));
+        assert!(is_sampled(&high, 1.0));
+        assert!(!is_sampled(&low, 0.0));
+
+        // about the given fraction of uniformly distributed ids
+        let sampled = (0..1000u64)
+            .filter(|i| is_sampled(&format!("{:016x}", i * (u64::MAX / 1000)), 0.25))
+            .count();
+        assert_eq!(sampled, 250);
+    }
+}
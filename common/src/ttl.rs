@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+use crate::dates::{check_index_date, SkipReason};
+
+/// Days events are kept by kind, falling back to `default_days`; `None` keeps them forever.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IndexTtl {
+    pub default_days: Option<u64>,
+    pub kinds: HashMap<u64, Option<u64>>,
+}
+
+impl IndexTtl {
+    /// Parses a comma-separated list like `0=forever,1=7`, overriding `default_days` for the
+    /// listed kinds.
+    pub fn parse(default_days: Option<u64>, kinds: &str) -> anyhow::Result<Self> {
+        let mut ttl = IndexTtl {
+            default_days,
+            kinds: HashMap::new(),
+        };
+        for item in kinds
+            .split(',')
+            .map(|item| item.trim())
+            .filter(|item| !item.is_empty())
+        {
+            let (kind, days) = item
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("invalid kind TTL: {}", item))?;
+            let days = match days.trim() {
+                "forever" => None,
+                days => Some(days.parse::<u64>()?),
+            };
+            ttl.kinds.insert(kind.trim().parse::<u64>()?, days);
+        }
+        Ok(ttl)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.default_days.is_some() || self.kinds.values().any(|days| days.is_some())
+    }
+
+    pub fn for_kind(&self, kind: u64) -> Option<u64> {
+        match self.kinds.get(&kind) {
+            Some(days) => *days,
+            None => self.default_days,
+        }
+    }
+
+    /// TTL of the dated indices, which hold events of every kind: the longest one.
+    pub fn index_days(&self) -> Option<u64> {
+        let mut days = vec![self.default_days];
+        days.extend(self.kinds.values().cloned());
+        days.into_iter()
+            .collect::<Option<Vec<_>>>()
+            .and_then(|days| days.into_iter().max())
+    }
+
+    /// Unix times before which events are past their TTL at `now`: that of the kinds without a
+    /// TTL of their own, and those of the kinds with one; `None` for the kept forever.
+    pub fn cutoffs(&self, now: u64) -> (Option<u64>, Vec<(u64, Option<u64>)>) {
+        let cutoff = |days: Option<u64>| days.map(|days| now.saturating_sub(days * 24 * 60 * 60));
+        let mut kinds = self
+            .kinds
+            .iter()
+            .map(|(kind, days)| (*kind, cutoff(*days)))
+            .collect::<Vec<_>>();
+        kinds.sort();
+        (cutoff(self.default_days), kinds)
+    }
+
+    /// Query of the events in `index_name` past the TTL of their kind, if any.
+    pub fn expired_query(
+        &self,
+        index_name: &str,
+        current_time: &DateTime<Utc>,
+        allow_future_days: u64,
+    ) -> Option<Value> {
+        let expired = |days: Option<u64>| {
+            check_index_date(index_name, current_time, days, allow_future_days)
+                == Err(SkipReason::TooOld)
+        };
+        let (mut expired_kinds, mut kept_kinds) = (vec![], vec![]);
+        for (kind, days) in &self.kinds {
+            if expired(*days) {
+                expired_kinds.push(*kind);
+            } else {
+                kept_kinds.push(*kind);
+            }
+        }
+        expired_kinds.sort();
+        kept_kinds.sort();
+        if expired(self.default_days) {
+            Some(json!({
+                "bool": { "must_not": { "terms": { "event.kind": kept_kinds } } }
+            }))
+        } else if !expired_kinds.is_empty() {
+            Some(json!({ "terms": { "event.kind": expired_kinds } }))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::{DateTime, Utc};
+    use serde_json::json;
+
+    use crate::ttl::IndexTtl;
+
+    #[test]
+    fn test_parse() {
+        let ttl = IndexTtl::parse(Some(30), "0=forever, 1=7").unwrap();
+        assert_eq!(ttl.for_kind(0), None);
+        assert_eq!(ttl.for_kind(1), Some(7));
+        assert_eq!(ttl.for_kind(30023), Some(30));
+        assert!(ttl.is_enabled());
+        assert_eq!(ttl.index_days(), None);
+
+        let ttl = IndexTtl::parse(Some(30), "0=365,1=7").unwrap();
+        assert_eq!(ttl.index_days(), Some(365));
+        assert!(!IndexTtl::parse(None, "").unwrap().is_enabled());
+        assert!(IndexTtl::parse(None, "1=7").unwrap().is_enabled());
+
+        assert!(IndexTtl::parse(None, "1").is_err());
+        assert!(IndexTtl::parse(None, "note=7").is_err());
+        assert!(IndexTtl::parse(None, "1=week").is_err());
+    }
+
+    #[test]
+    fn test_expired_query() {
+        let current_time = DateTime::<Utc>::from_str("2023-03-20T12:00:00Z").unwrap();
+        let ttl = IndexTtl::parse(Some(30), "0=forever,1=7,7=3").unwrap();
+        let query = |index_name: &str| ttl.expired_query(index_name, &current_time, 1);
+        assert_eq!(query("nostr-2023.03.19"), None);
+        assert_eq!(
+            query("nostr-2023.03.16"),
+            Some(json!({"terms": {"event.kind": [7]}}))
+        );
+        assert_eq!(
+            query("nostr-2023.03.10"),
+            Some(json!({"terms": {"event.kind": [1, 7]}}))
+        );
+        assert_eq!(
+            query("nostr-2023.01.01"),
+            Some(json!({"bool": {"must_not": {"terms": {"event.kind": [0]}}}}))
+        );
+    }
+
+    #[test]
+    fn test_cutoffs() {
+        let ttl = IndexTtl::parse(Some(30), "0=forever,1=7").unwrap();
+        let now = 100 * 24 * 60 * 60;
+        assert_eq!(
+            ttl.cutoffs(now),
+            (
+                Some(70 * 24 * 60 * 60),
+                vec![(0, None), (1, Some(93 * 24 * 60 * 60))]
+            )
+        );
+        assert_eq!(
+            IndexTtl::parse(None, "").unwrap().cutoffs(now),
+            (None, vec![])
+        );
+        assert_eq!(
+            IndexTtl::parse(Some(365), "").unwrap().cutoffs(now).0,
+            Some(0)
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use nostr_sdk::prelude::*;
+use serde_json::{json, Value};
+
+use crate::filters::normalize_relay;
+
+/// ids remembered to skip events received again after resuming
+const MAX_RECENT_IDS: usize = 10_000;
+/// relays resume this long before their checkpoint, for events they received late
+const RESUME_MARGIN_SECS: u64 = 10 * 60;
+const SAVE_EVERY: Duration = Duration::from_secs(10);
+
+/// Where the stream of each source relay was processed up to, kept on disk across restarts.
+pub struct Checkpoint {
+    path: PathBuf,
+    /// latest `created_at` processed, by relay URL without a trailing slash
+    relays: HashMap<String, u64>,
+    /// latest `created_at` processed since the subscription, by relay still sending stored
+    /// events; stored events come newest first, so it is kept until the end of them
+    backfill: HashMap<String, u64>,
+    /// ids of recently forwarded events, oldest first
+    recent: VecDeque<String>,
+    seen: HashSet<String>,
+    dirty: bool,
+    saved_at: Instant,
+}
+
+fn parse(json: &str) -> Option<(HashMap<String, u64>, VecDeque<String>)> {
+    let value = serde_json::from_str::<Value>(json).ok()?;
+    let relays = value["relays"]
+        .as_object()?
+        .iter()
+        .filter_map(|(relay, created_at)| Some((relay.clone(), created_at.as_u64()?)))
+        .collect();
+    let recent = value["recent_ids"]
+        .as_array()?
+        .iter()
+        .filter_map(|id| id.as_str().map(|id| id.to_string()))
+        .collect();
+    Some((relays, recent))
+}
+
+/// Narrows subscription filters to the events since `since`, including stored ones.
+pub fn resume(filters: Vec<Filter>, since: Option<Timestamp>) -> Vec<Filter> {
+    let since = match since {
+        Some(since) => since,
+        None => return filters,
+    };
+    filters
+        .into_iter()
+        .map(|mut filter| {
+            if filter.limit == Some(0) {
+                filter.limit = None;
+            }
+            filter.since = Some(filter.since.map_or(since, |s| s.max(since)));
+            filter
+        })
+        .collect()
+}
+
+impl Checkpoint {
+    /// Reads the checkpoint at `path`; a missing or unreadable one starts empty.
+    pub fn load(path: &Path) -> Self {
+        let (relays, recent) = match std::fs::read_to_string(path) {
+            Ok(json) => parse(&json).unwrap_or_else(|| {
+                log::warn!("ignoring invalid checkpoint {}", path.display());
+                Default::default()
+            }),
+            Err(_) => Default::default(),
+        };
+        Checkpoint {
+            path: path.to_path_buf(),
+            seen: recent.iter().cloned().collect(),
+            relays,
+            backfill: HashMap::new(),
+            recent,
+            dirty: false,
+            saved_at: Instant::now(),
+        }
+    }
+
+    /// Where the subscription to `relay` resumes from, if it was processed before.
+    pub fn since(&self, relay: &str) -> Option<Timestamp> {
+        self.relays
+            .get(&normalize_relay(relay))
+            .map(|created_at| Timestamp::from(created_at.saturating_sub(RESUME_MARGIN_SECS)))
+    }
+
+    /// Where a new subscription to `relay` resumes from; the checkpoint of the relay is held
+    /// until it has sent all its stored events.
+    pub fn subscribe(&mut self, relay: &str) -> Option<Timestamp> {
+        self.backfill.insert(normalize_relay(relay), 0);
+        self.since(relay)
+    }
+
+    /// Records that `relay` sent all its stored events, moving its checkpoint to the latest
+    /// of them.
+    pub fn end_of_stored(&mut self, relay: &str) {
+        let relay = normalize_relay(relay);
+        if let Some(created_at) = self.backfill.remove(&relay) {
+            let last = self.relays.entry(relay).or_default();
+            if created_at > *last {
+                *last = created_at;
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Whether the event was forwarded recently, e.g. before a restart.
+    pub fn is_recent(&self, id: &str) -> bool {
+        self.seen.contains(id)
+    }
+
+    /// Records an event of `relay` as processed; `forwarded` ones are remembered by id.
+    pub fn record(&mut self, relay: &str, id: &str, created_at: u64, now: u64, forwarded: bool) {
+        // events from the future would skip those created meanwhile
+        let created_at = created_at.min(now);
+        let relay = normalize_relay(relay);
+        let last = match self.backfill.get_mut(&relay) {
+            Some(last) => last,
+            None => self.relays.entry(relay).or_default(),
+        };
+        if created_at > *last {
+            *last = created_at;
+        }
+        if forwarded && self.seen.insert(id.to_string()) {
+            self.recent.push_back(id.to_string());
+            if self.recent.len() > MAX_RECENT_IDS {
+                if let Some(oldest) = self.recent.pop_front() {
+                    self.seen.remove(&oldest);
+                }
+            }
+        }
+        self.dirty = true;
+    }
+
+    fn to_json(&self) -> Value {
+        json!({ "relays": self.relays, "recent_ids": self.recent })
+    }
+
+    /// Writes the checkpoint if it changed and was not written within `SAVE_EVERY`.
+    pub fn save_if_due(&mut self) -> std::io::Result<()> {
+        if !self.dirty || self.saved_at.elapsed() < SAVE_EVERY {
+            return Ok(());
+        }
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, self.to_json().to_string())?;
+        std::fs::rename(&tmp, &self.path)?;
+        self.dirty = false;
+        self.saved_at = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use nostr_sdk::prelude::*;
+
+    use crate::checkpoint::{parse, resume, Checkpoint, MAX_RECENT_IDS};
+
+    #[test]
+    fn test_record() {
+        let mut checkpoint = Checkpoint::load(Path::new("/nonexistent/checkpoint.json"));
+        assert_eq!(checkpoint.since("wss://relay.example.com"), None);
+
+        checkpoint.record("wss://relay.example.com/", "a", 10_000, 20_000, true);
+        checkpoint.record("wss://relay.example.com", "b", 9_000, 20_000, false);
+        checkpoint.record("wss://future.example.com", "c", 90_000, 20_000, true);
+        assert_eq!(
+            checkpoint.since("wss://relay.example.com"),
+            Some(Timestamp::from(9_400))
+        );
+        assert_eq!(
+            checkpoint.since("wss://future.example.com"),
+            Some(Timestamp::from(19_400))
+        );
+        assert!(checkpoint.is_recent("a"));
+        assert!(!checkpoint.is_recent("b"));
+
+        let (relays, recent) = parse(&checkpoint.to_json().to_string()).unwrap();
+        assert_eq!(relays["wss://relay.example.com"], 10_000);
+        assert_eq!(recent, vec!["a", "c"]);
+
+        for i in 0..MAX_RECENT_IDS {
+            checkpoint.record("wss://relay.example.com", &i.to_string(), 0, 0, true);
+        }
+        assert!(!checkpoint.is_recent("a"));
+        assert!(checkpoint.is_recent("0"));
+    }
+
+    #[test]
+    fn test_backfill() {
+        let mut checkpoint = Checkpoint::load(Path::new("/nonexistent/checkpoint.json"));
+        checkpoint.record("wss://relay.example.com", "a", 1_000, 20_000, true);
+        assert_eq!(
+            checkpoint.subscribe("wss://relay.example.com/"),
+            Some(Timestamp::from(400))
+        );
+
+        // stored events come newest first; a crash before the end of them resumes as before
+        checkpoint.record("wss://relay.example.com", "b", 3_000, 20_000, true);
+        checkpoint.record("wss://relay.example.com", "c", 2_000, 20_000, true);
+        assert_eq!(
+            checkpoint.since("wss://relay.example.com"),
+            Some(Timestamp::from(400))
+        );
+
+        checkpoint.end_of_stored("wss://relay.example.com/");
+        assert_eq!(
+            checkpoint.since("wss://relay.example.com"),
+            Some(Timestamp::from(2_400))
+        );
+        checkpoint.record("wss://relay.example.com", "d", 4_000, 20_000, true);
+        assert_eq!(
+            checkpoint.since("wss://relay.example.com"),
+            Some(Timestamp::from(3_400))
+        );
+    }
+
+    #[test]
+    fn test_resume() {
+        let filters = vec![
+            Filter::new().limit(0).kind(Kind::TextNote),
+            Filter::new().limit(10).since(Timestamp::from(2_000)),
+        ];
+        assert_eq!(resume(filters.clone(), None), filters);
+
+        let resumed = resume(filters, Some(Timestamp::from(1_000)));
+        assert_eq!(resumed[0].limit, None);
+        assert_eq!(resumed[0].since, Some(Timestamp::from(1_000)));
+        assert_eq!(resumed[1].limit, Some(10));
+        assert_eq!(resumed[1].since, Some(Timestamp::from(2_000)));
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashSet;
+
+use nostr_sdk::prelude::*;
+
+pub const RELAY_LIST_KIND: u64 = 10002;
+
+/// Collects write relays from NIP-65 relay list events, within a cap and a denylist.
+pub struct RelayDiscovery {
+    max_relays: usize,
+    denylist: Vec<String>,
+    known: HashSet<String>,
+}
+
+fn normalize_relay_url(url: &str) -> Option<String> {
+    let url = url.trim().trim_end_matches('/').to_lowercase();
+    if (url.starts_with("wss://") || url.starts_with("ws://")) && !url.contains(char::is_whitespace)
+    {
+        Some(url)
+    } else {
+        None
+    }
+}
+
+fn host(url: &str) -> &str {
+    let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    rest.split(|c| c == '/' || c == ':' || c == '?')
+        .next()
+        .unwrap_or(rest)
+}
+
+/// Relays marked `write`, or without a marker, in the `r` tags of a relay list.
+fn write_relays(event: &Event) -> Vec<String> {
+    event
+        .tags
+        .iter()
+        .filter_map(|tag| {
+            let tag = tag.as_vec();
+            if tag.len() < 2 || tag[0] != "r" {
+                return None;
+            }
+            match tag.get(2).map(|marker| marker.as_str()) {
+                None | Some("write") => normalize_relay_url(&tag[1]),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+impl RelayDiscovery {
+    pub fn new(initial_relays: &[String], max_relays: usize, denylist: Vec<String>) -> Self {
+        RelayDiscovery {
+            max_relays,
+            denylist: denylist
+                .into_iter()
+                .map(|d| d.trim().to_lowercase())
+                .collect(),
+            known: initial_relays
+                .iter()
+                .filter_map(|url| normalize_relay_url(url))
+                .collect(),
+        }
+    }
+
+    fn is_denied(&self, url: &str) -> bool {
+        let host = host(url);
+        self.denylist.iter().any(|denied| {
+            url == denied || host == denied || host.ends_with(&format!(".{}", denied))
+        })
+    }
+
+    /// Returns the relays of `event` to add, and remembers them as known.
+    pub fn discover(&mut self, event: &Event) -> Vec<String> {
+        let mut added = vec![];
+        for url in write_relays(event) {
+            if self.known.len() >= self.max_relays {
+                break;
+            }
+            if self.is_denied(&url) || !self.known.insert(url.clone()) {
+                continue;
+            }
+            added.push(url);
+        }
+        added
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::prelude::*;
+
+    use crate::discovery::{RelayDiscovery, RELAY_LIST_KIND};
+
+    fn relay_list(relays: &[(&str, Option<&str>)]) -> Event {
+        let tags = relays
+            .iter()
+            .map(|(url, marker)| {
+                let mut tag = vec!["r".to_string(), url.to_string()];
+                if let Some(marker) = marker {
+                    tag.push(marker.to_string());
+                }
+                Tag::parse(tag).unwrap()
+            })
+            .collect::<Vec<_>>();
+        EventBuilder::new(Kind::from(RELAY_LIST_KIND), "", &tags)
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_discover() {
+        let mut discovery = RelayDiscovery::new(
+            &["wss://relay.example.com".to_string()],
+            4,
+            vec!["spam.example".to_string()],
+        );
+        let event = relay_list(&[
+            ("wss://relay.example.com/", None),
+            ("wss://a.example.com", Some("write")),
+            ("wss://b.example.com", Some("read")),
+            ("wss://relay.spam.example", None),
+            ("https://c.example.com", None),
+        ]);
+        assert_eq!(discovery.discover(&event), vec!["wss://a.example.com"]);
+        // already known
+        assert!(discovery.discover(&event).is_empty());
+
+        // capped at max_relays
+        let event = relay_list(&[
+            ("wss://d.example.com", None),
+            ("wss://e.example.com", None),
+            ("wss://f.example.com", None),
+        ]);
+        assert_eq!(
+            discovery.discover(&event),
+            vec!["wss://d.example.com", "wss://e.example.com"]
+        );
+    }
+}
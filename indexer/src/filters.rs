@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+use nostr_sdk::prelude::*;
+
+/// Subscription filters of each source relay.
+pub struct RelayFilters {
+    /// filters of relays not listed
+    default: Vec<Filter>,
+    /// by relay URL without a trailing slash
+    relays: HashMap<String, Vec<Filter>>,
+}
+
+pub fn normalize_relay(relay: &str) -> String {
+    relay.trim().trim_end_matches('/').to_string()
+}
+
+impl RelayFilters {
+    pub fn new(default: Vec<Filter>) -> Self {
+        RelayFilters {
+            default,
+            relays: HashMap::new(),
+        }
+    }
+
+    /// Parses NIP-01 filters keyed by relay URL, where `*` replaces `default`, e.g.
+    /// `{"wss://blog.example.com": [{"kinds": [30023]}], "*": [{"kinds": [0, 1, 5]}]}`.
+    ///
+    /// Filters without `limit` only receive new events, like the default ones.
+    pub fn parse(json: &str, default: Vec<Filter>) -> Result<Self, serde_json::Error> {
+        let mut filters = RelayFilters::new(default);
+        let by_relay = serde_json::from_str::<HashMap<String, Vec<Filter>>>(json)?;
+        for (relay, relay_filters) in by_relay {
+            let relay_filters = relay_filters
+                .into_iter()
+                .map(|mut filter| {
+                    if filter.limit.is_none() {
+                        filter.limit = Some(0);
+                    }
+                    filter
+                })
+                .collect::<Vec<_>>();
+            match normalize_relay(&relay).as_str() {
+                "*" => filters.default = relay_filters,
+                relay => {
+                    filters.relays.insert(relay.to_string(), relay_filters);
+                }
+            }
+        }
+        Ok(filters)
+    }
+
+    /// Adds `filter` to the subscriptions of every relay.
+    pub fn push(&mut self, filter: Filter) {
+        self.default.push(filter.clone());
+        for relay_filters in self.relays.values_mut() {
+            relay_filters.push(filter.clone());
+        }
+    }
+
+    pub fn filters(&self, relay: &str) -> Vec<Filter> {
+        self.relays
+            .get(&normalize_relay(relay))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::prelude::*;
+
+    use crate::filters::RelayFilters;
+
+    #[test]
+    fn test_parse() {
+        let default = vec![Filter::new().limit(0).kind(Kind::TextNote)];
+        let mut filters = RelayFilters::parse(
+            r#"{"wss://blog.example.com/": [{"kinds": [30023]}], "wss://all.example.com": [{"limit": 10}]}"#,
+            default.clone(),
+        )
+        .unwrap();
+        filters.push(Filter::new().limit(0).kind(Kind::from(10002)));
+
+        let blog = filters.filters("wss://blog.example.com");
+        assert_eq!(blog.len(), 2);
+        assert_eq!(blog[0].kinds, Some(vec![Kind::LongFormTextNote]));
+        assert_eq!(blog[0].limit, Some(0));
+        assert_eq!(filters.filters("wss://all.example.com")[0].limit, Some(10));
+        assert_eq!(filters.filters("wss://other.example.com").len(), 2);
+        assert_eq!(filters.filters("wss://other.example.com")[0], default[0]);
+
+        let filters = RelayFilters::parse(r#"{"*": [{"kinds": [0]}]}"#, default.clone()).unwrap();
+        assert_eq!(
+            filters.filters("wss://other.example.com")[0].kinds,
+            Some(vec![Kind::Metadata])
+        );
+        assert!(RelayFilters::parse("[]", default).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use nostr_sdk::Event;
+use searchnos_common::message::forwarded_event_message;
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+
+/// messages waiting for each destination relay before forwarding blocks
+const QUEUE_SIZE: usize = 1000;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Destination relays the events are forwarded to, each `EVENT` naming the source relay of
+/// its event, so that searchnos applies its relay rules and sampling rates.
+///
+/// nostr clients only send `["EVENT", <event>]`, so the messages are written to the
+/// websockets directly, one connection per relay reconnecting when it drops.
+pub struct Forwarder {
+    queues: Vec<mpsc::Sender<String>>,
+}
+
+impl Forwarder {
+    pub fn connect(relays: &[String]) -> Self {
+        let queues = relays
+            .iter()
+            .map(|relay| {
+                let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+                tokio::spawn(forward(relay.trim().to_string(), receiver));
+                sender
+            })
+            .collect();
+        Forwarder { queues }
+    }
+
+    pub async fn send(&self, event: &Event, relay: &str) -> anyhow::Result<()> {
+        let message = forwarded_event_message(event, relay);
+        for queue in &self.queues {
+            queue
+                .send(message.clone())
+                .await
+                .map_err(|_| anyhow::anyhow!("forwarding stopped"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Logs the refusals and notices of a destination relay.
+fn log_reply(url: &str, text: &str) {
+    let reply = serde_json::from_str::<Value>(text).unwrap_or_default();
+    match reply[0].as_str() {
+        Some("OK") if reply[2] == Value::Bool(false) => {
+            log::warn!("{} refused event {}: {}", url, reply[1], reply[3]);
+        }
+        Some("NOTICE") => log::warn!("notice from {}: {}", url, reply[1]),
+        _ => {}
+    }
+}
+
+/// Writes the queued messages to the relay at `url` until the queue is closed.
+async fn forward(url: String, mut queue: mpsc::Receiver<String>) {
+    // the message whose write failed, written again after reconnecting
+    let mut unsent: Option<String> = None;
+    loop {
+        let socket = match connect_async(url.as_str()).await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                log::warn!("failed to connect to {}: {}", url, e);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        log::info!("connected to destination relay: {}", url);
+        let (mut sink, mut stream) = socket.split();
+        loop {
+            let message = match unsent.take() {
+                Some(message) => message,
+                None => tokio::select! {
+                    message = queue.recv() => match message {
+                        Some(message) => message,
+                        None => return,
+                    },
+                    received = stream.next() => {
+                        match received {
+                            Some(Ok(Message::Text(text))) => log_reply(&url, &text),
+                            Some(Ok(_)) => {}
+                            Some(Err(e)) => {
+                                log::warn!("connection to {} failed: {}", url, e);
+                                break;
+                            }
+                            None => {
+                                log::warn!("{} closed the connection", url);
+                                break;
+                            }
+                        }
+                        continue;
+                    }
+                },
+            };
+            if let Err(e) = sink.send(Message::Text(message.clone())).await {
+                log::warn!("failed to forward to {}: {}", url, e);
+                unsent = Some(message);
+                break;
+            }
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use futures::StreamExt;
+    use nostr_sdk::{EventBuilder, Keys, Kind};
+    use searchnos_common::message::parse_forwarded_event;
+    use serde_json::Value;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::accept_async;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use crate::forward::Forwarder;
+
+    #[tokio::test]
+    async fn test_forward() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let forwarder = Forwarder::connect(&[format!("ws://{}", addr)]);
+
+        let event = EventBuilder::new(Kind::TextNote, "hello", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        forwarder
+            .send(&event, "wss://relay.example.com")
+            .await
+            .unwrap();
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = accept_async(stream).await.unwrap();
+        let text = match socket.next().await {
+            Some(Ok(Message::Text(text))) => text,
+            received => panic!("unexpected message: {:?}", received),
+        };
+        let msg = serde_json::from_str::<Vec<Value>>(&text).unwrap();
+        let (received, relay) = parse_forwarded_event(&msg).unwrap();
+        assert_eq!(received.id, event.id);
+        assert_eq!(relay, Some("wss://relay.example.com"));
+    }
+}
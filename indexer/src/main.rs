@@ -1,11 +1,63 @@
+use chrono::Utc;
 use env_logger;
 use log::info;
 use nostr_sdk::prelude::*;
+use searchnos_common::dates::check_event_date;
+use searchnos_common::sampling::Sampling;
+use searchnos_common::ttl::IndexTtl;
 use std::env;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+
+mod checkpoint;
+mod discovery;
+mod filters;
+mod forward;
+mod watchdog;
+
+use checkpoint::{resume, Checkpoint};
+use discovery::{RelayDiscovery, RELAY_LIST_KIND};
+use filters::RelayFilters;
+use forward::Forwarder;
+use watchdog::Watchdog;
+
+/// Subscribes to each source relay with its own filters, from its checkpoint if any.
+async fn subscribe(
+    client: &Client,
+    filters: &RelayFilters,
+    mut checkpoint: Option<&mut Checkpoint>,
+) {
+    for (url, relay) in client.relays().await {
+        let since = checkpoint
+            .as_deref_mut()
+            .and_then(|checkpoint| checkpoint.subscribe(url.as_str()));
+        if let Err(e) = relay
+            .subscribe(resume(filters.filters(url.as_str()), since), None)
+            .await
+        {
+            log::warn!("failed to subscribe to {}: {}", url, e);
+        }
+    }
+}
+
+/// Reconnects to the source relays and subscribes again, e.g. after the pool stopped.
+async fn resubscribe(
+    client: &Client,
+    filters: &RelayFilters,
+    checkpoint: Option<&mut Checkpoint>,
+    watchdog: &mut Watchdog,
+) {
+    watchdog.health.resubscriptions += 1;
+    client.connect().await;
+    subscribe(client, filters, checkpoint).await;
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env::set_var("RUST_LOG", "info");
+    if env::var("RUST_LOG").is_err() {
+        env::set_var("RUST_LOG", "info");
+    }
     env_logger::init();
 
     // env vars
@@ -13,6 +65,53 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .expect("SRC_RELAYS is not set; set it to the comma-separated URLs of relays");
     let dest_relays = env::var("DEST_RELAYS")
         .expect("DEST_RELAYS is not set; set it to the comma-separated URLs of relays");
+    let relay_discovery = env::var("RELAY_DISCOVERY")
+        .map(|v| v == "true")
+        .unwrap_or(false);
+    let max_relays = if let Ok(max_relays) = env::var("MAX_RELAYS") {
+        max_relays
+            .parse::<usize>()
+            .expect("MAX_RELAYS is not a valid number")
+    } else {
+        50
+    };
+    let contact_lists = env::var("CONTACT_LISTS")
+        .map(|v| v == "true")
+        .unwrap_or(false);
+    let engagement = env::var("ENGAGEMENT").map(|v| v == "true").unwrap_or(false);
+    let zaps = env::var("ZAPS").map(|v| v == "true").unwrap_or(false);
+    // same as searchnos, so that stale events are dropped here with the relay sending them
+    let index_ttl_days: Option<u64> = env::var("INDEX_TTL_DAYS").ok().map(|index_ttl_days| {
+        index_ttl_days
+            .parse::<u64>()
+            .expect("INDEX_TTL_DAYS is not a valid number")
+    });
+    let index_ttl = IndexTtl::parse(
+        index_ttl_days,
+        &env::var("KIND_TTL_DAYS").unwrap_or_default(),
+    )
+    .expect("KIND_TTL_DAYS is not valid; expected e.g. 0=forever,1=7");
+    let index_allow_future_days = 1;
+    // sampled here too, so that the events sampled out are not forwarded
+    let sampling = Sampling::parse(
+        &env::var("SAMPLING_RATES").unwrap_or_default(),
+        env::var("SAMPLED_KINDS").ok().as_deref(),
+    )
+    .expect("SAMPLING_RATES or SAMPLED_KINDS is not valid");
+    let relay_filters_file = env::var("RELAY_FILTERS_FILE").ok();
+    let watchdog_timeout = if let Ok(watchdog_timeout) = env::var("WATCHDOG_TIMEOUT") {
+        Duration::from_secs(
+            watchdog_timeout
+                .parse::<u64>()
+                .expect("WATCHDOG_TIMEOUT is not a valid number"),
+        )
+    } else {
+        Duration::from_secs(5 * 60)
+    };
+    let checkpoint_file = env::var("CHECKPOINT_FILE").ok();
+    let relay_denylist = env::var("RELAY_DENYLIST")
+        .map(|v| v.split(',').map(|s| s.to_string()).collect::<Vec<_>>())
+        .unwrap_or_default();
 
     // prepare nostr clients
     let my_keys: Keys = Keys::generate();
@@ -24,15 +123,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     src_client.connect().await;
 
-    let dest_client = Client::new(&my_keys);
-    for relay in dest_relays.split(',') {
+    let dest_relays = dest_relays
+        .split(',')
+        .map(|relay| relay.to_string())
+        .collect::<Vec<_>>();
+    for relay in &dest_relays {
         info!("adding destination relay: {}", relay);
-        dest_client.add_relay(relay, None).await?;
     }
-    dest_client.connect().await;
+    let forwarder = Forwarder::connect(&dest_relays);
     info!("connected to relays");
 
-    let subscription = Filter::new().limit(0).kinds(vec![
+    let mut kinds = vec![
         Kind::Metadata,
         Kind::TextNote,
         Kind::EventDeletion,
@@ -42,18 +143,176 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Kind::ChannelMessage,
         Kind::ChannelHideMessage,
         Kind::ChannelMuteUser,
-    ]);
-
-    src_client.subscribe(vec![subscription]).await;
+    ];
+    if contact_lists {
+        kinds.push(Kind::ContactList);
+    }
+    if engagement {
+        // reposts and reactions
+        kinds.push(Kind::from(6));
+        kinds.push(Kind::from(7));
+    }
+    if zaps {
+        // zap receipts
+        kinds.push(Kind::from(9735));
+    }
+    let default_filters = vec![Filter::new().limit(0).kinds(kinds)];
+    let mut relay_filters = match &relay_filters_file {
+        Some(path) => {
+            info!("reading subscription filters from {}", path);
+            RelayFilters::parse(&std::fs::read_to_string(path)?, default_filters)
+                .expect("RELAY_FILTERS_FILE is not valid; expected NIP-01 filters by relay URL")
+        }
+        None => RelayFilters::new(default_filters),
+    };
+    let mut discovery = if relay_discovery {
+        info!("relay discovery enabled (max relays: {})", max_relays);
+        relay_filters.push(Filter::new().limit(0).kind(Kind::from(RELAY_LIST_KIND)));
+        let initial_relays = src_relays
+            .split(',')
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        Some(RelayDiscovery::new(
+            &initial_relays,
+            max_relays,
+            relay_denylist,
+        ))
+    } else {
+        None
+    };
+    let mut checkpoint = checkpoint_file.map(|path| {
+        info!("resuming from checkpoint {}", path);
+        Checkpoint::load(Path::new(&path))
+    });
+    subscribe(&src_client, &relay_filters, checkpoint.as_mut()).await;
     info!("ready to receive messages");
 
+    let mut watchdog = Watchdog::new(Instant::now());
+    let mut notifications = src_client.notifications();
     loop {
-        let mut notifications = src_client.notifications();
-        while let Ok(notification) = notifications.recv().await {
-            if let RelayPoolNotification::Event(_url, event) = notification {
-                log::info!("received event: {}", event.as_json());
-                // TODO check dates
-                dest_client.send_event(event).await?;
+        if let Some(health) = watchdog.report(Instant::now()) {
+            info!("health: {}", health);
+        }
+        if let Some(checkpoint) = checkpoint.as_mut() {
+            if let Err(e) = checkpoint.save_if_due() {
+                log::warn!("failed to save checkpoint: {}", e);
+            }
+        }
+        let notification = match tokio::time::timeout(watchdog_timeout, notifications.recv()).await
+        {
+            Ok(Ok(notification)) => notification,
+            Ok(Err(RecvError::Lagged(dropped))) => {
+                // the events are lost; later ones are still received
+                watchdog.health.dropped += dropped;
+                log::warn!("fell behind; dropped {} notifications", dropped);
+                continue;
+            }
+            Ok(Err(RecvError::Closed)) => {
+                watchdog.health.shutdowns += 1;
+                log::warn!("notifications closed; resubscribing");
+                resubscribe(
+                    &src_client,
+                    &relay_filters,
+                    checkpoint.as_mut(),
+                    &mut watchdog,
+                )
+                .await;
+                notifications = src_client.notifications();
+                continue;
+            }
+            Err(_) => {
+                watchdog.health.stalls += 1;
+                log::warn!(
+                    "no notification for {}s; resubscribing",
+                    watchdog_timeout.as_secs()
+                );
+                resubscribe(
+                    &src_client,
+                    &relay_filters,
+                    checkpoint.as_mut(),
+                    &mut watchdog,
+                )
+                .await;
+                continue;
+            }
+        };
+        match notification {
+            RelayPoolNotification::Event(url, event) => {
+                watchdog.health.events += 1;
+                if event.kind == Kind::from(RELAY_LIST_KIND) {
+                    if let Some(discovery) = discovery.as_mut() {
+                        let added = discovery.discover(&event);
+                        for relay in &added {
+                            info!("adding discovered source relay: {}", relay);
+                            if let Err(e) = src_client.add_relay(relay.as_str(), None).await {
+                                log::warn!("failed to add relay {}: {}", relay, e);
+                            }
+                        }
+                        if !added.is_empty() {
+                            src_client.connect().await;
+                            subscribe(&src_client, &relay_filters, checkpoint.as_mut()).await;
+                        }
+                    }
+                    // relay lists are not searchable
+                    continue;
+                }
+                let id = event.id.to_hex();
+                if checkpoint.as_ref().map_or(false, |c| c.is_recent(&id)) {
+                    log::debug!("event {} from {} was already forwarded", id, url);
+                    continue;
+                }
+                let now = Timestamp::now().as_u64();
+                let created_at = event.created_at.as_u64();
+                let checked = check_event_date(
+                    &event,
+                    &Utc::now(),
+                    index_ttl.for_kind(event.kind.as_u64()),
+                    index_allow_future_days,
+                );
+                let forward = if let Err(reason) = checked {
+                    log::debug!(
+                        "skipping event {} from {} created at {}: {}",
+                        event.id,
+                        url,
+                        event.created_at,
+                        reason.as_str()
+                    );
+                    false
+                } else if !sampling.keeps(&event, Some(url.as_str())) {
+                    log::debug!("sampled out event {} from {}", event.id, url);
+                    false
+                } else {
+                    true
+                };
+                if forward {
+                    log::info!("received event: {}", event.as_json());
+                    forwarder.send(&event, url.as_str()).await?;
+                }
+                // saved once the event has been forwarded
+                if let Some(checkpoint) = checkpoint.as_mut() {
+                    checkpoint.record(url.as_str(), &id, created_at, now, forward);
+                }
+            }
+            RelayPoolNotification::Message(url, RelayMessage::Notice { message }) => {
+                log::warn!("notice from {}: {}", url, message);
+            }
+            RelayPoolNotification::Message(url, RelayMessage::EndOfStoredEvents(_)) => {
+                if let Some(checkpoint) = checkpoint.as_mut() {
+                    checkpoint.end_of_stored(url.as_str());
+                }
+            }
+            RelayPoolNotification::Message(..) => {}
+            RelayPoolNotification::Shutdown | RelayPoolNotification::Stop => {
+                watchdog.health.shutdowns += 1;
+                log::warn!("relay pool stopped; resubscribing");
+                resubscribe(
+                    &src_client,
+                    &relay_filters,
+                    checkpoint.as_mut(),
+                    &mut watchdog,
+                )
+                .await;
+                notifications = src_client.notifications();
             }
         }
     }
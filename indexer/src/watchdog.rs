@@ -0,0 +1,79 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// interval of the health summaries logged
+const REPORT_EVERY: Duration = Duration::from_secs(5 * 60);
+
+/// Counters of the notification loop since the last summary.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Health {
+    pub events: u64,
+    /// notifications dropped because the loop fell behind
+    pub dropped: u64,
+    /// times no notification arrived within the timeout
+    pub stalls: u64,
+    /// times the relay pool stopped or shut down
+    pub shutdowns: u64,
+    pub resubscriptions: u64,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "events={} dropped={} stalls={} shutdowns={} resubscriptions={}",
+            self.events, self.dropped, self.stalls, self.shutdowns, self.resubscriptions
+        )
+    }
+}
+
+/// Keeps the health counters and when they were last reported.
+pub struct Watchdog {
+    pub health: Health,
+    last_report: Instant,
+}
+
+impl Watchdog {
+    pub fn new(now: Instant) -> Self {
+        Watchdog {
+            health: Health::default(),
+            last_report: now,
+        }
+    }
+
+    /// Returns the counters and resets them once `REPORT_EVERY` has passed.
+    pub fn report(&mut self, now: Instant) -> Option<Health> {
+        if now.duration_since(self.last_report) < REPORT_EVERY {
+            return None;
+        }
+        self.last_report = now;
+        Some(std::mem::take(&mut self.health))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::watchdog::Watchdog;
+
+    #[test]
+    fn test_report() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(start);
+        watchdog.health.events += 2;
+        watchdog.health.dropped += 10;
+        assert_eq!(watchdog.report(start + Duration::from_secs(60)), None);
+
+        let health = watchdog
+            .report(start + Duration::from_secs(5 * 60))
+            .unwrap();
+        assert_eq!(health.events, 2);
+        assert_eq!(
+            health.to_string(),
+            "events=2 dropped=10 stalls=0 shutdowns=0 resubscriptions=0"
+        );
+        assert_eq!(watchdog.health.events, 0);
+        assert_eq!(watchdog.report(start + Duration::from_secs(6 * 60)), None);
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use elasticsearch::http::{headers::HeaderMap, request::JsonBody, Method};
+use nostr_sdk::prelude::{Client, Keys, XOnlyPublicKey};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+
+/// Thresholds that fire an alert when crossed; `None` disables the check.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AlertThresholds {
+    /// time since the last event was indexed
+    pub ingest_lag: Option<Duration>,
+    /// share of failed writes among the events indexed since the previous check
+    pub error_rate_percent: Option<f64>,
+    /// highest disk usage among the Elasticsearch nodes
+    pub disk_percent: Option<f64>,
+}
+
+impl AlertThresholds {
+    pub fn is_empty(&self) -> bool {
+        self.ingest_lag.is_none()
+            && self.error_rate_percent.is_none()
+            && self.disk_percent.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlertKind {
+    IngestLag,
+    ErrorRate,
+    Disk,
+}
+
+/// Values observed by one check.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub ingest_lag: Duration,
+    /// `None` when nothing was indexed since the previous check
+    pub error_rate_percent: Option<f64>,
+    /// `None` when disk usage could not be retrieved
+    pub disk_percent: Option<f64>,
+}
+
+/// Returns the alerts whose thresholds are crossed by `sample`, with a message for each.
+pub fn crossed(thresholds: &AlertThresholds, sample: &Sample) -> Vec<(AlertKind, String)> {
+    let mut alerts = vec![];
+    if let Some(max_lag) = thresholds.ingest_lag {
+        if sample.ingest_lag > max_lag {
+            alerts.push((
+                AlertKind::IngestLag,
+                format!(
+                    "no event indexed for {} minute(s)",
+                    sample.ingest_lag.as_secs() / 60
+                ),
+            ));
+        }
+    }
+    if let (Some(max_rate), Some(rate)) = (thresholds.error_rate_percent, sample.error_rate_percent)
+    {
+        if rate > max_rate {
+            alerts.push((
+                AlertKind::ErrorRate,
+                format!("indexing error rate is {:.1}%", rate),
+            ));
+        }
+    }
+    if let (Some(max_disk), Some(disk)) = (thresholds.disk_percent, sample.disk_percent) {
+        if disk > max_disk {
+            alerts.push((
+                AlertKind::Disk,
+                format!("elasticsearch disk usage is {:.0}%", disk),
+            ));
+        }
+    }
+    alerts
+}
+
+#[derive(Clone)]
+pub enum AlertChannel {
+    /// POSTs `{"status": "firing"|"resolved", "message": "..."}`
+    Webhook(String),
+    /// encrypted direct message (NIP-04) to the operator
+    DirectMessage {
+        client: Client,
+        pubkey: XOnlyPublicKey,
+    },
+}
+
+impl AlertChannel {
+    /// Connects to `relays` to send direct messages from a newly generated key.
+    pub async fn direct_message(pubkey: &str, relays: &str) -> anyhow::Result<Self> {
+        let pubkey = XOnlyPublicKey::from_str(pubkey)?;
+        let keys = Keys::generate();
+        log::info!(
+            "alerts are sent as direct messages from {}",
+            keys.public_key()
+        );
+        let client = Client::new(&keys);
+        for relay in relays.split(',') {
+            client.add_relay(relay.trim(), None).await?;
+        }
+        client.connect().await;
+        Ok(AlertChannel::DirectMessage { client, pubkey })
+    }
+
+    async fn notify(
+        &self,
+        http_client: &reqwest::Client,
+        status: &str,
+        message: &str,
+    ) -> anyhow::Result<()> {
+        match self {
+            AlertChannel::Webhook(url) => {
+                let res = http_client
+                    .post(url)
+                    .json(&json!({ "status": status, "message": message }))
+                    .send()
+                    .await?;
+                if !res.status().is_success() {
+                    return Err(anyhow::anyhow!("webhook returned {}", res.status()));
+                }
+            }
+            AlertChannel::DirectMessage { client, pubkey } => {
+                client
+                    .send_direct_msg(*pubkey, format!("[searchnos] {}: {}", status, message))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct AlertConfig {
+    pub thresholds: AlertThresholds,
+    pub interval: Duration,
+    pub channels: Vec<AlertChannel>,
+}
+
+async fn max_disk_percent(state: &AppState) -> anyhow::Result<Option<f64>> {
+    let res = state
+        .es_client
+        .send(
+            Method::Get,
+            "/_cat/allocation",
+            HeaderMap::new(),
+            Some(&[("format", "json"), ("h", "disk.percent")]),
+            None::<JsonBody<Value>>,
+            None,
+        )
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "unexpected status code: {}",
+            res.status_code()
+        ));
+    }
+    let nodes = res.json::<Value>().await?;
+    Ok(nodes
+        .as_array()
+        .unwrap_or(&vec![])
+        .iter()
+        .filter_map(|node| node["disk.percent"].as_str()?.parse::<f64>().ok())
+        .reduce(f64::max))
+}
+
+/// Periodically checks the thresholds and notifies every channel when an alert fires or resolves.
+pub fn spawn_alert_checker(state: Arc<AppState>, config: AlertConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let http_client = reqwest::Client::new();
+        let started_at = chrono::Utc::now().timestamp() as u64;
+        let mut prev_indexed = state.metrics.events_indexed.load(Ordering::Relaxed);
+        let mut prev_errors = state.metrics.index_errors.load(Ordering::Relaxed);
+        let mut firing: HashSet<AlertKind> = HashSet::new();
+        loop {
+            tokio::time::sleep(config.interval).await;
+
+            let now = chrono::Utc::now().timestamp() as u64;
+            let last_indexed_at = state
+                .metrics
+                .last_indexed_at
+                .load(Ordering::Relaxed)
+                .max(started_at);
+            let indexed = state.metrics.events_indexed.load(Ordering::Relaxed);
+            let errors = state.metrics.index_errors.load(Ordering::Relaxed);
+            let attempts = (indexed - prev_indexed) + (errors - prev_errors);
+            let error_rate_percent = if attempts == 0 {
+                None
+            } else {
+                Some((errors - prev_errors) as f64 * 100.0 / attempts as f64)
+            };
+            prev_indexed = indexed;
+            prev_errors = errors;
+            let disk_percent = if config.thresholds.disk_percent.is_some() {
+                match max_disk_percent(&state).await {
+                    Ok(disk_percent) => disk_percent,
+                    Err(e) => {
+                        log::warn!("failed to get disk usage: {}", e);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            let sample = Sample {
+                ingest_lag: Duration::from_secs(now.saturating_sub(last_indexed_at)),
+                error_rate_percent,
+                disk_percent,
+            };
+
+            let alerts = crossed(&config.thresholds, &sample);
+            let mut notifications = vec![];
+            for (kind, message) in &alerts {
+                if firing.insert(*kind) {
+                    notifications.push(("firing", message.clone()));
+                }
+            }
+            firing.retain(|kind| {
+                let still_firing = alerts.iter().any(|(k, _)| k == kind);
+                if !still_firing {
+                    notifications.push(("resolved", format!("{:?} is back to normal", kind)));
+                }
+                still_firing
+            });
+
+            for (status, message) in notifications {
+                let message = format!("[{}] {}", state.index_alias_name, message);
+                log::warn!("alert {}: {}", status, message);
+                for channel in &config.channels {
+                    if let Err(e) = channel.notify(&http_client, status, &message).await {
+                        log::error!("failed to send alert: {}", e);
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::alerts::{crossed, AlertKind, AlertThresholds, Sample};
+
+    #[test]
+    fn test_crossed() {
+        let thresholds = AlertThresholds {
+            ingest_lag: Some(Duration::from_secs(600)),
+            error_rate_percent: Some(5.0),
+            disk_percent: Some(80.0),
+        };
+        let sample = Sample {
+            ingest_lag: Duration::from_secs(60),
+            error_rate_percent: Some(1.0),
+            disk_percent: Some(50.0),
+        };
+        assert!(crossed(&thresholds, &sample).is_empty());
+
+        let sample = Sample {
+            ingest_lag: Duration::from_secs(900),
+            error_rate_percent: Some(10.0),
+            disk_percent: Some(85.0),
+        };
+        let kinds = crossed(&thresholds, &sample)
+            .into_iter()
+            .map(|(kind, _)| kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![AlertKind::IngestLag, AlertKind::ErrorRate, AlertKind::Disk]
+        );
+
+        // unknown values and disabled checks never fire
+        let sample = Sample {
+            ingest_lag: Duration::from_secs(900),
+            error_rate_percent: None,
+            disk_percent: None,
+        };
+        assert!(crossed(&AlertThresholds::default(), &sample).is_empty());
+        assert_eq!(crossed(&thresholds, &sample).len(), 1);
+    }
+}
@@ -1,17 +1,161 @@
+use std::sync::Arc;
 use std::time::Duration;
 
 use elasticsearch::Elasticsearch;
+use nostr_sdk::{Event, Keys};
+use tokio::sync::broadcast;
+
+use crate::backend::SearchBackend;
+use crate::breaker::EsGuard;
+use crate::index::ack::AckLog;
+use crate::index::analyzer::AnalyzerConfig;
+use crate::index::chain::Chain;
+use crate::index::completion::CompletionCollector;
+use crate::index::embedding::Embedder;
+use crate::index::engagement::EngagementCounter;
+use crate::index::force_merge::ForceMergeConfig;
+use crate::index::journal::Journal;
+use crate::index::language::LanguageAllowlist;
+use crate::index::limits::EventLimits;
+use crate::index::nip05::Nip05Verifier;
+use crate::index::opt_out::OptOut;
+use crate::index::queue::IndexQueue;
+use crate::index::reactions::ReactionCounter;
+use crate::index::reconcile::IngestCounter;
+use crate::index::replacements::ReplacementQueue;
+use crate::index::sampling::Sampling;
+use crate::index::seen_on::SeenOn;
+use crate::index::sinks::Sinks;
+use crate::index::text::Extractors;
+use crate::index::tiering::TieringPolicy;
+use crate::index::ttl::IndexTtl;
+use crate::index::zaps::ZapCounter;
+use crate::kind_label::KindLabels;
+use crate::link::LinkConfig;
+use crate::metrics::Metrics;
+use crate::search::analytics::QueryAnalytics;
+use crate::search::cache::QueryCache;
+use crate::search::hybrid::HybridConfig;
+use crate::search::limiter::{IpRateLimiter, QueryLimiter};
+use crate::search::prefix::TagPrefixes;
+use crate::search::ranking::RankingConfig;
+use crate::search::trending::Trending;
+use crate::tenant::TenantRouter;
 
 #[derive(Debug)]
 pub struct AppState {
     pub es_client: Elasticsearch,
     pub index_name_prefix: String,
     pub index_alias_name: String,
+    /// store of the events instead of the Elasticsearch indices
+    pub backend: Option<Box<dyn SearchBackend>>,
+    /// secondary stores the written events are copied to
+    pub sinks: Option<Sinks>,
     pub relay_info: String,
+    pub openapi: String,
     pub max_subscriptions: usize,
     pub max_filters: usize,
+    /// see `Filter::complexity`
+    pub max_filter_complexity: Option<usize>,
+    /// cap of the `limit` of each filter of a REQ
+    pub max_results_per_req: Option<usize>,
+    /// REQs per second of each client IP address, shared by all namespaces
+    pub req_limiter: Option<Arc<IpRateLimiter>>,
+    /// whether regular connections may publish events, answered with NIP-20 `OK`s
+    pub accept_published_events: bool,
+    /// published events per second of each client IP address, shared by all namespaces
+    pub event_limiter: Option<Arc<IpRateLimiter>>,
     pub api_key: String,
     pub ping_interval: Duration,
-    pub index_ttl_days: Option<u64>,
+    pub index_ttl: IndexTtl,
+    pub tiering_policy: Option<TieringPolicy>,
+    pub force_merge: Option<ForceMergeConfig>,
     pub index_allow_future_days: u64,
+    /// round `created_at` of the searchable copy of events down to a multiple of these seconds
+    pub created_at_rounding: Option<u64>,
+    /// whether documents go through the ingest pipeline; if not, `timestamp` is set here
+    pub ingest_pipeline: bool,
+    /// events exceeding these are not indexed
+    pub event_limits: EventLimits,
+    pub ingest_chain: Arc<Chain>,
+    /// fractions of events indexed per source relay
+    pub sampling: Sampling,
+    /// index events carrying a NIP-36 content warning
+    pub index_content_warnings: bool,
+    /// leave events carrying a content warning out of searches without `nsfw:true`
+    pub exclude_content_warnings: bool,
+    /// index events carrying the NIP-70 `-` tag, which are never returned
+    pub index_protected_events: bool,
+    /// whether replaceable events are indexed into `<prefix>-replaceable`, overwriting the
+    /// versions they replace
+    pub replaceable_index: bool,
+    /// whether profiles are indexed into `<prefix>-profiles` by pubkey, and searched there
+    pub profiles_index: bool,
+    /// hashtags and profiles of indexed events, when completions are enabled
+    pub completions: Option<CompletionCollector>,
+    pub opt_out: OptOut,
+    pub analyzer_config: AnalyzerConfig,
+    /// documents routed to `searchnos-other-languages-<prefix>-*` are purged like the others
+    pub language_allowlist: Option<LanguageAllowlist>,
+    /// tags matched by prefix with values ending with `*`
+    pub tag_prefixes: TagPrefixes,
+    pub embedder: Option<Embedder>,
+    /// fuse keyword and kNN results of pre-EOSE searches; requires `embedder`
+    pub hybrid_search: Option<HybridConfig>,
+    /// suggest a corrected search string when a search yields fewer hits; 0 disables suggestions
+    pub suggest_min_hits: usize,
+    /// aggregated searches and zero-result rates, reported to the administrative API
+    pub query_analytics: Option<QueryAnalytics>,
+    /// minimum probability of the language detected from search strings, which are then also
+    /// matched against the field analyzed for that language; disabled when `None`
+    pub query_language_detection: Option<f64>,
+    /// weight of the follower count in the ranking of pre-EOSE results; also enables
+    /// follower counting from contact lists
+    pub follower_boost: Option<f64>,
+    /// scoring of pre-EOSE results; reverse chronological order when `None`
+    pub ranking: Option<RankingConfig>,
+    /// reaction and repost counts, when the engagement boost is enabled
+    pub engagement: Option<EngagementCounter>,
+    /// weight of the sats zapped in the ranking of pre-EOSE results; also enables zap counting
+    /// and `min_zaps:`
+    pub zap_boost: Option<f64>,
+    /// zap receipts, when zaps are counted
+    pub zaps: Option<ZapCounter>,
+    /// weight of the likes in the ranking of pre-EOSE results; also enables reaction counting
+    /// and their counts in HTTP search results
+    pub reaction_boost: Option<f64>,
+    /// reactions, when they are counted
+    pub reactions: Option<ReactionCounter>,
+    /// notes trending over the last hours, served at `GET /trending`
+    pub trending: Option<Trending>,
+    /// recent pre-EOSE results of REQs
+    pub query_cache: Option<QueryCache>,
+    /// shared by all namespaces
+    pub query_limiter: Option<Arc<QueryLimiter>>,
+    /// shared by all namespaces; index workers wait on it
+    pub es_guard: Option<Arc<EsGuard>>,
+    /// text searched in the events, by kind
+    pub extractors: Extractors,
+    /// newly indexed events, pushed to live subscriptions
+    pub new_events: broadcast::Sender<Event>,
+    /// signs the freshness probes, whose events are neither pushed nor exported
+    pub probe_keys: Option<Keys>,
+    pub index_queue: IndexQueue,
+    /// received events not yet handled, when acknowledgment is strict
+    pub ack_log: Option<AckLog>,
+    /// documents created per day, for the reconciliation report
+    pub ingest_counter: IngestCounter,
+    pub journal: Option<Journal>,
+    pub nip05_verifier: Option<Nip05Verifier>,
+    /// replaced versions deleted in batches, when enabled
+    pub replacements: Option<ReplacementQueue>,
+    /// namespaces that events received here are also routed to
+    pub tenants: Arc<TenantRouter>,
+    /// how events are referenced in logs and command outputs
+    pub links: LinkConfig,
+    /// relays the recent events were received from, as hints of their links
+    pub seen_on: SeenOn,
+    /// names of kinds in metrics and command outputs
+    pub kind_labels: KindLabels,
+    pub metrics: Metrics,
 }
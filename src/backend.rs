@@ -0,0 +1,258 @@
+pub mod meilisearch;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "tantivy")]
+pub mod tantivy;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::async_trait;
+use nostr_sdk::Event;
+use sha2::{Digest, Sha256};
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+use crate::backend::meilisearch::MeilisearchBackend;
+use crate::index::delegation::author;
+use crate::index::handlers::replaceable_id;
+use crate::index::text::Extractors;
+use crate::index::ttl::IndexTtl;
+use crate::kind_label::KindLabels;
+use crate::search::cache::CachedPage;
+use crate::search::filter::Filter;
+use crate::search::query::Cursor;
+use crate::search::syntax::SearchQuery;
+
+/// Where the events are stored and searched instead of the Elasticsearch indices.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackendConfig {
+    /// the indices of the index templates; the default
+    Elasticsearch,
+    Meilisearch {
+        url: String,
+        api_key: Option<String>,
+    },
+    /// embedded indices per day under `dir`, whose writes are committed every `commit_interval`
+    Tantivy {
+        dir: PathBuf,
+        commit_interval: Duration,
+    },
+    /// a table per namespace in the database of `url`
+    Postgres { url: String },
+}
+
+impl BackendConfig {
+    /// The backend of the namespace of `index_alias_name`; `None` for Elasticsearch.
+    pub fn connect(
+        &self,
+        index_alias_name: &str,
+        kind_labels: &KindLabels,
+        extractors: &Extractors,
+        exclude_content_warnings: bool,
+    ) -> Option<Box<dyn SearchBackend>> {
+        match self {
+            BackendConfig::Elasticsearch => None,
+            BackendConfig::Meilisearch { url, api_key } => Some(Box::new(MeilisearchBackend::new(
+                url,
+                api_key.clone(),
+                index_alias_name,
+                kind_labels.clone(),
+                extractors.clone(),
+                exclude_content_warnings,
+            ))),
+            #[cfg(feature = "tantivy")]
+            BackendConfig::Tantivy { dir, .. } => Some(Box::new(tantivy::TantivyBackend::new(
+                dir,
+                index_alias_name,
+                kind_labels.clone(),
+                extractors.clone(),
+                exclude_content_warnings,
+            ))),
+            // refused by `Config::from_env`
+            #[cfg(not(feature = "tantivy"))]
+            BackendConfig::Tantivy { .. } => unreachable!("built without the tantivy feature"),
+            #[cfg(feature = "postgres")]
+            BackendConfig::Postgres { url } => Some(Box::new(postgres::PostgresBackend::new(
+                url,
+                index_alias_name,
+                kind_labels.clone(),
+                extractors.clone(),
+                exclude_content_warnings,
+            ))),
+            #[cfg(not(feature = "postgres"))]
+            BackendConfig::Postgres { .. } => unreachable!("built without the postgres feature"),
+        }
+    }
+
+    /// How often the writes of backends batching them are made searchable.
+    pub fn commit_interval(&self) -> Option<Duration> {
+        match self {
+            BackendConfig::Tantivy {
+                commit_interval, ..
+            } => Some(*commit_interval),
+            _ => None,
+        }
+    }
+}
+
+/// What a write did to the stored events.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteOutcome {
+    Created,
+    Updated,
+    /// a newer version of the replaceable event is stored
+    Stale,
+}
+
+impl WriteOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WriteOutcome::Created => "created",
+            WriteOutcome::Updated => "updated",
+            WriteOutcome::Stale => "stale",
+        }
+    }
+}
+
+/// Store of the events of a namespace other than Elasticsearch.
+///
+/// Backends keep the events and the deletions recorded before their events arrive; the
+/// features kept in other Elasticsearch indices are refused by `Config::from_env`, so that a
+/// deployment with a backend does not need Elasticsearch.
+#[async_trait]
+pub trait SearchBackend: Send + Sync + std::fmt::Debug {
+    fn name(&self) -> &'static str;
+
+    /// Creates the index and applies its settings, if not up to date.
+    async fn prepare(&self) -> anyhow::Result<()>;
+
+    /// Whether searches and writes can be served, for the readiness probe.
+    async fn is_reachable(&self) -> bool;
+
+    /// Stores the event, replacing older versions of replaceable events.
+    async fn index(&self, event: &Event) -> anyhow::Result<WriteOutcome>;
+
+    /// Removes the events of `ids` by `author`, as NIP-09 deletions: those signed by them or
+    /// delegated by them.
+    async fn delete(&self, author: &str, ids: &[String]) -> anyhow::Result<()>;
+
+    /// Records the deletions of `ids` by `author` at `created_at`, so that the events are not
+    /// indexed when they arrive after the deletion.
+    async fn record_deletions(
+        &self,
+        author: &str,
+        ids: &[String],
+        created_at: u64,
+    ) -> anyhow::Result<()>;
+
+    /// Whether the deletion of the event by `pubkey` has been recorded.
+    async fn has_deletion(&self, event_id: &str, pubkey: &str) -> anyhow::Result<bool>;
+
+    /// Removes the events past the TTL of their kind, and the deletions kept as long as the
+    /// events they may delete.
+    async fn purge(&self, ttl: &IndexTtl) -> anyhow::Result<()>;
+
+    /// Makes the writes since the last commit searchable, for backends batching them.
+    async fn commit(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// A page of the events matching the filter like `ElasticsearchQuery::execute_page`: the
+    /// newest first before EOSE, and those stored after `cursor` in order after it.
+    async fn search(&self, filter: &Filter, cursor: Option<Cursor>) -> anyhow::Result<CachedPage>;
+}
+
+/// Commits the writes of the backend every `interval`.
+pub fn spawn_backend_committer(state: Arc<AppState>, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Some(backend) = &state.backend {
+                if let Err(e) = backend.commit().await {
+                    log::error!("Error committing {}: {}", backend.name(), e);
+                }
+            }
+        }
+    })
+}
+
+/// Whether the event has been deleted by its author, or its signer if delegated, before it
+/// arrived, like `deletion::is_deleted`.
+pub(crate) async fn is_deleted(backend: &dyn SearchBackend, event: &Event) -> anyhow::Result<bool> {
+    let (author, signer) = (author(event), event.pubkey.to_string());
+    let id = event.id.to_hex();
+    if backend.has_deletion(&id, &author).await? {
+        return Ok(true);
+    }
+    if author != signer {
+        return backend.has_deletion(&id, &signer).await;
+    }
+    Ok(false)
+}
+
+/// Id of the stored document of the event: the versions of a replaceable event share one, the
+/// SHA-256 of its `replaceable_id`, since backends limit the length and characters of ids.
+pub(crate) fn document_id(event: &Event) -> String {
+    match replaceable_id(event) {
+        Some(id) => Sha256::digest(id.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+        None => event.id.to_hex(),
+    }
+}
+
+/// Whether the event has any of the words and phrases excluded by `search`, which backends
+/// without negative terms filter out of their results.
+pub(crate) fn is_excluded(event: &Event, search: &SearchQuery) -> bool {
+    let content = event.content.to_lowercase();
+    search
+        .excluded
+        .iter()
+        .any(|excluded| content.contains(&excluded.to_lowercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+
+    use crate::backend::{document_id, is_excluded};
+    use crate::search::syntax::SearchQuery;
+
+    #[test]
+    fn test_document_id() {
+        let keys = Keys::generate();
+        let event = |kind: u64, tags: &[Tag]| {
+            EventBuilder::new(Kind::from(kind), "hello", tags)
+                .to_event(&keys)
+                .unwrap()
+        };
+        let note = event(1, &[]);
+        assert_eq!(document_id(&note), note.id.to_hex());
+        let profile = document_id(&event(0, &[]));
+        assert_eq!(profile.len(), 64);
+        assert_eq!(document_id(&event(0, &[])), profile);
+
+        let d = |d: &str| Tag::parse(vec!["d", d]).unwrap();
+        let article = document_id(&event(30023, &[d("a b")]));
+        assert_eq!(document_id(&event(30023, &[d("a b")])), article);
+        assert_ne!(document_id(&event(30023, &[d("a c")])), article);
+        assert_ne!(document_id(&event(30023, &[])), article);
+        // identifiers longer than the ids allowed by Meilisearch
+        assert_eq!(
+            document_id(&event(30023, &[d(&"x".repeat(1000))])).len(),
+            64
+        );
+    }
+
+    #[test]
+    fn test_is_excluded() {
+        let event = EventBuilder::new(Kind::TextNote, "Buy my Bitcoin course", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        assert!(is_excluded(&event, &SearchQuery::parse("bitcoin -course")));
+        assert!(!is_excluded(&event, &SearchQuery::parse("bitcoin -scam")));
+    }
+}
@@ -0,0 +1,614 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use axum::async_trait;
+use chrono::{TimeZone, Utc};
+use nostr_sdk::Event;
+use reqwest::Method;
+use serde_json::{json, Value};
+
+use crate::backend::{document_id, is_excluded, SearchBackend, WriteOutcome};
+use crate::index::content_warning::extract_content_warning;
+use crate::index::delegation::author;
+use crate::index::protected::is_protected;
+use crate::index::text::Extractors;
+use crate::index::ttl::IndexTtl;
+use crate::kind_label::KindLabels;
+use crate::search::cache::CachedPage;
+use crate::search::filter::Filter;
+use crate::search::query::{Cursor, PageCursor, DEFAULT_LIMIT, MAX_LIMIT};
+use crate::search::syntax::SearchQuery;
+
+/// first wait for a task to be processed, doubled up to a second
+const TASK_POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// wait for a task after which the write fails
+const TASK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Events of a namespace in a Meilisearch index named after its alias.
+///
+/// The text is matched by the Meilisearch tokenizer with all the words required; of the
+/// operators of `search`, only `from:`, `kind:`, `since:`, `until:`, `nsfw:` and excluded words
+/// are applied. Ids and authors must be complete, not prefixes.
+#[derive(Debug)]
+pub struct MeilisearchBackend {
+    http_client: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+    index: String,
+    kind_labels: KindLabels,
+    extractors: Extractors,
+    exclude_content_warnings: bool,
+}
+
+/// Quoted string of a filter expression.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn quote_all(values: &[String]) -> String {
+    values
+        .iter()
+        .map(|v| quote(v))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Document of the event; tags are `<name>:<value>` strings of the single-letter tags.
+fn to_document(event: &Event, text: String, indexed_at: i64) -> Value {
+    let tags = event
+        .tags
+        .iter()
+        .map(|tag| tag.as_vec())
+        .filter(|tag| tag.len() >= 2 && tag[0].len() == 1)
+        .map(|tag| format!("{}:{}", tag[0], tag[1]))
+        .collect::<HashSet<_>>();
+    json!({
+        "doc_id": document_id(event),
+        "id": event.id.to_hex(),
+        "pubkey": event.pubkey.to_string(),
+        // the delegator of delegated events, whose deletions apply to them
+        "author": author(event),
+        "kind": event.kind.as_u64(),
+        "created_at": event.created_at.as_u64(),
+        "content": text,
+        "tags": tags,
+        "sensitive": extract_content_warning(event).is_some(),
+        "protected": is_protected(event),
+        "indexed_at": indexed_at,
+        "event": event
+    })
+}
+
+/// Conditions of the filter, all of which must match.
+fn filter_conditions(
+    filter: &Filter,
+    kind_labels: &KindLabels,
+    exclude_sensitive: bool,
+) -> Vec<String> {
+    let search = SearchQuery::parse(filter.search.as_deref().unwrap_or_default());
+    let mut conditions = vec!["protected = false".to_string()];
+    if let Some(ids) = &filter.ids {
+        conditions.push(format!("id IN [{}]", quote_all(ids)));
+    }
+    if let Some(ids) = &filter.zapped_ids {
+        conditions.push(format!("id IN [{}]", quote_all(ids)));
+    }
+    if let Some(authors) = &filter.authors {
+        conditions.push(format!("pubkey IN [{}]", quote_all(authors)));
+    }
+    if !search.authors.is_empty() {
+        conditions.push(format!("pubkey IN [{}]", quote_all(&search.authors)));
+    }
+    if let Some(kinds) = &filter.kinds {
+        let kinds = kinds.iter().map(|kind| kind.as_u64().to_string());
+        conditions.push(format!(
+            "kind IN [{}]",
+            kinds.collect::<Vec<_>>().join(", ")
+        ));
+    }
+    let kinds = search.kinds(kind_labels);
+    if !kinds.is_empty() {
+        let kinds = kinds.iter().map(|kind| kind.to_string());
+        conditions.push(format!(
+            "kind IN [{}]",
+            kinds.collect::<Vec<_>>().join(", ")
+        ));
+    }
+    // both ends are inclusive; See NIP-01
+    let since = filter.since.map(|since| since.as_u64()).into_iter();
+    for since in since.chain(search.since) {
+        conditions.push(format!("created_at >= {}", since));
+    }
+    let until = filter.until.map(|until| until.as_u64()).into_iter();
+    for until in until.chain(search.until) {
+        conditions.push(format!("created_at <= {}", until));
+    }
+    let mut tags = filter.tags().into_iter().collect::<Vec<_>>();
+    tags.sort();
+    for (name, values) in tags {
+        let values = values
+            .iter()
+            .map(|value| format!("{}:{}", name, value))
+            .collect::<Vec<_>>();
+        conditions.push(format!("tags IN [{}]", quote_all(&values)));
+    }
+    let mut and_tags = filter.and_tags().into_iter().collect::<Vec<_>>();
+    and_tags.sort();
+    for (name, values) in and_tags {
+        for value in values {
+            conditions.push(format!("tags = {}", quote(&format!("{}:{}", name, value))));
+        }
+    }
+    if filter.excludes_sensitive(exclude_sensitive) {
+        conditions.push("sensitive = false".to_string());
+    }
+    conditions
+}
+
+/// Id of the recorded deletion of an event by `pubkey`; ids allow only `-` and `_` besides
+/// alphanumerics.
+fn deletion_id(event_id: &str, pubkey: &str) -> String {
+    format!("{}-{}", event_id, pubkey)
+}
+
+/// Filters of the documents past the TTL of their kind at `now`.
+fn expired_filters(ttl: &IndexTtl, now: u64) -> Vec<String> {
+    let (default_cutoff, kinds) = ttl.cutoffs(now);
+    let mut filters = vec![];
+    if let Some(cutoff) = default_cutoff {
+        if kinds.is_empty() {
+            filters.push(format!("created_at < {}", cutoff));
+        } else {
+            let kinds = kinds
+                .iter()
+                .map(|(kind, _)| kind.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            filters.push(format!(
+                "created_at < {} AND kind NOT IN [{}]",
+                cutoff, kinds
+            ));
+        }
+    }
+    for (kind, cutoff) in kinds {
+        if let Some(cutoff) = cutoff {
+            filters.push(format!("created_at < {} AND kind = {}", cutoff, kind));
+        }
+    }
+    filters
+}
+
+/// `q` of the search: the words and the quoted phrases.
+fn search_text(search: &SearchQuery) -> String {
+    search
+        .terms
+        .iter()
+        .cloned()
+        .chain(search.phrases.iter().map(|phrase| quote(phrase)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl MeilisearchBackend {
+    pub fn new(
+        url: &str,
+        api_key: Option<String>,
+        index: &str,
+        kind_labels: KindLabels,
+        extractors: Extractors,
+        exclude_content_warnings: bool,
+    ) -> Self {
+        MeilisearchBackend {
+            http_client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            api_key,
+            index: index.to_string(),
+            kind_labels,
+            extractors,
+            exclude_content_warnings,
+        }
+    }
+
+    fn authorize(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.api_key {
+            Some(api_key) => req.bearer_auth(api_key),
+            None => req,
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> reqwest::RequestBuilder {
+        self.authorize(self.http_client.request(
+            method,
+            format!("{}/indexes/{}{}", self.url, self.index, path),
+        ))
+    }
+
+    /// Request to the index of the deletions whose events may not have arrived yet.
+    fn deletions_request(&self, method: Method, path: &str) -> reqwest::RequestBuilder {
+        self.authorize(self.http_client.request(
+            method,
+            format!("{}/indexes/{}-deletions{}", self.url, self.index, path),
+        ))
+    }
+
+    async fn send(&self, req: reqwest::RequestBuilder, what: &str) -> anyhow::Result<Value> {
+        let res = req.send().await?;
+        if !res.status().is_success() {
+            let status = res.status();
+            let body = res.text().await?;
+            return Err(anyhow::anyhow!("failed to {}: {} {}", what, status, body));
+        }
+        Ok(res.json::<Value>().await?)
+    }
+
+    /// Waits for the task enqueued by a write, returned by `send`, and fails with its error.
+    async fn wait_for_task(&self, task: &Value, what: &str) -> anyhow::Result<Value> {
+        let uid = task["taskUid"]
+            .as_u64()
+            .ok_or_else(|| anyhow::anyhow!("failed to {}: no task in {}", what, task))?;
+        let mut wait = TASK_POLL_INTERVAL;
+        let deadline = Instant::now() + TASK_TIMEOUT;
+        loop {
+            let req = self.authorize(self.http_client.get(format!("{}/tasks/{}", self.url, uid)));
+            let task = self.send(req, "get the task").await?;
+            match task["status"].as_str() {
+                Some("succeeded") => return Ok(task),
+                Some("failed") | Some("canceled") => {
+                    return Err(anyhow::anyhow!(
+                        "failed to {}: task {} {}: {}",
+                        what,
+                        uid,
+                        task["status"],
+                        task["error"]
+                    ))
+                }
+                _ => {}
+            }
+            if Instant::now() >= deadline {
+                return Err(anyhow::anyhow!(
+                    "failed to {}: task {} still {} after {:?}",
+                    what,
+                    uid,
+                    task["status"],
+                    TASK_TIMEOUT
+                ));
+            }
+            tokio::time::sleep(wait).await;
+            wait = std::cmp::min(wait * 2, Duration::from_secs(1));
+        }
+    }
+
+    /// Sends a write and waits for its task to be processed.
+    async fn write(&self, req: reqwest::RequestBuilder, what: &str) -> anyhow::Result<Value> {
+        let task = self.send(req, what).await?;
+        self.wait_for_task(&task, what).await
+    }
+
+    /// Creates the index of `uid` with documents identified by `doc_id`, if missing.
+    async fn create_index(&self, uid: &str) -> anyhow::Result<()> {
+        let req = self.authorize(
+            self.http_client
+                .post(format!("{}/indexes", self.url))
+                .json(&json!({ "uid": uid, "primaryKey": "doc_id" })),
+        );
+        let task = self.send(req, "create the index").await?;
+        // creating an existing index fails without harm
+        match self.wait_for_task(&task, "create the index").await {
+            Err(e) if !e.to_string().contains("index_already_exists") => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// `created_at` and id of the stored version of a replaceable event.
+    async fn stored_version(&self, doc_id: &str) -> anyhow::Result<Option<(u64, String)>> {
+        let res = self
+            .request(Method::GET, &format!("/documents/{}", doc_id))
+            .query(&[("fields", "created_at,id")])
+            .send()
+            .await?;
+        if res.status().as_u16() == 404 {
+            return Ok(None);
+        }
+        if !res.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "failed to get document {}: {}",
+                doc_id,
+                res.status()
+            ));
+        }
+        let doc = res.json::<Value>().await?;
+        Ok(doc["created_at"]
+            .as_u64()
+            .zip(doc["id"].as_str().map(|id| id.to_string())))
+    }
+}
+
+#[async_trait]
+impl SearchBackend for MeilisearchBackend {
+    fn name(&self) -> &'static str {
+        "meilisearch"
+    }
+
+    async fn prepare(&self) -> anyhow::Result<()> {
+        self.create_index(&self.index).await?;
+        let deletions_index = format!("{}-deletions", self.index);
+        self.create_index(&deletions_index).await?;
+        self.write(
+            self.deletions_request(Method::PATCH, "/settings")
+                .json(&json!({ "filterableAttributes": ["created_at"] })),
+            "update the settings of the deletions",
+        )
+        .await?;
+        let settings = json!({
+            "searchableAttributes": ["content"],
+            "filterableAttributes": [
+                "id", "pubkey", "author", "kind", "created_at", "tags", "sensitive", "protected",
+                "indexed_at"
+            ],
+            "sortableAttributes": ["created_at", "indexed_at"],
+            "displayedAttributes": ["id", "created_at", "event", "indexed_at"],
+            "pagination": { "maxTotalHits": MAX_LIMIT }
+        });
+        self.write(
+            self.request(Method::PATCH, "/settings").json(&settings),
+            "update the index settings",
+        )
+        .await?;
+        log::info!("[{}] meilisearch index ready", self.index);
+        Ok(())
+    }
+
+    async fn is_reachable(&self) -> bool {
+        let req = self.http_client.get(format!("{}/health", self.url));
+        matches!(req.send().await, Ok(res) if res.status().is_success())
+    }
+
+    async fn index(&self, event: &Event) -> anyhow::Result<WriteOutcome> {
+        let doc_id = document_id(event);
+        let outcome = if doc_id == event.id.to_hex() {
+            // documents sent again are replaced by identical ones
+            WriteOutcome::Created
+        } else {
+            match self.stored_version(&doc_id).await? {
+                None => WriteOutcome::Created,
+                // of versions created at the same time, the lowest id is kept
+                Some((created_at, id))
+                    if created_at > event.created_at.as_u64()
+                        || (created_at == event.created_at.as_u64() && id < event.id.to_hex()) =>
+                {
+                    return Ok(WriteOutcome::Stale);
+                }
+                Some(_) => WriteOutcome::Updated,
+            }
+        };
+        let doc = to_document(
+            event,
+            self.extractors.extract(event),
+            Utc::now().timestamp_millis(),
+        );
+        self.write(
+            self.request(Method::POST, "/documents").json(&json!([doc])),
+            "add the document",
+        )
+        .await?;
+        Ok(outcome)
+    }
+
+    async fn delete(&self, author: &str, ids: &[String]) -> anyhow::Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let filter = format!("author = {} AND id IN [{}]", quote(author), quote_all(ids));
+        self.write(
+            self.request(Method::POST, "/documents/delete")
+                .json(&json!({ "filter": filter })),
+            "delete documents",
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn record_deletions(
+        &self,
+        author: &str,
+        ids: &[String],
+        created_at: u64,
+    ) -> anyhow::Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let docs = ids
+            .iter()
+            .map(|id| json!({ "doc_id": deletion_id(id, author), "created_at": created_at }))
+            .collect::<Vec<_>>();
+        self.write(
+            self.deletions_request(Method::POST, "/documents")
+                .json(&docs),
+            "record deletions",
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn has_deletion(&self, event_id: &str, pubkey: &str) -> anyhow::Result<bool> {
+        let res = self
+            .deletions_request(
+                Method::GET,
+                &format!("/documents/{}", deletion_id(event_id, pubkey)),
+            )
+            .send()
+            .await?;
+        match res.status().as_u16() {
+            404 => Ok(false),
+            status if (200..300).contains(&status) => Ok(true),
+            status => Err(anyhow::anyhow!("failed to look up deletions: {}", status)),
+        }
+    }
+
+    async fn purge(&self, ttl: &IndexTtl) -> anyhow::Result<()> {
+        let now = Utc::now().timestamp() as u64;
+        for filter in expired_filters(ttl, now) {
+            self.write(
+                self.request(Method::POST, "/documents/delete")
+                    .json(&json!({ "filter": filter })),
+                "purge expired documents",
+            )
+            .await?;
+        }
+        // deletions are kept as long as the events they may delete
+        if let Some(days) = ttl.index_days() {
+            let before = now.saturating_sub(days * 24 * 60 * 60);
+            self.write(
+                self.deletions_request(Method::POST, "/documents/delete")
+                    .json(&json!({ "filter": format!("created_at < {}", before) })),
+                "purge deletions",
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn search(&self, filter: &Filter, cursor: Option<Cursor>) -> anyhow::Result<CachedPage> {
+        let search = SearchQuery::parse(filter.search.as_deref().unwrap_or_default());
+        let mut conditions =
+            filter_conditions(filter, &self.kind_labels, self.exclude_content_warnings);
+        let offset = filter
+            .cursor
+            .as_ref()
+            .and_then(|cursor| cursor.values().first())
+            .and_then(|offset| offset.as_u64())
+            .unwrap_or_default() as usize;
+        let (sort, limit, offset) = match &cursor {
+            // pre-EOSE query
+            None => {
+                let limit = filter
+                    .limit
+                    .map(|l| std::cmp::min(l, MAX_LIMIT))
+                    .unwrap_or(DEFAULT_LIMIT);
+                ("created_at:desc", limit, offset)
+            }
+            // post-EOSE query; events stored in the same millisecond are told apart by id below
+            Some(cursor) => {
+                conditions.push(format!(
+                    "indexed_at >= {}",
+                    cursor.timestamp.timestamp_millis()
+                ));
+                ("indexed_at:asc", MAX_LIMIT, 0)
+            }
+        };
+        let body = self
+            .send(
+                self.request(Method::POST, "/search").json(&json!({
+                    "q": search_text(&search),
+                    "matchingStrategy": "all",
+                    "filter": conditions,
+                    "sort": [sort],
+                    "limit": limit,
+                    "offset": offset,
+                    "attributesToRetrieve": ["event", "indexed_at"]
+                })),
+                "search",
+            )
+            .await?;
+        let hits = body["hits"].as_array().cloned().unwrap_or_default();
+        let next = if cursor.is_none() && hits.len() == limit {
+            Some(PageCursor::new(vec![json!(offset + limit)]))
+        } else {
+            None
+        };
+        let mut events = vec![];
+        let mut latest_cursor = cursor.clone();
+        for hit in hits {
+            let event: Event = serde_json::from_value(hit["event"].clone())?;
+            let seen = Cursor {
+                timestamp: Utc
+                    .timestamp_millis_opt(hit["indexed_at"].as_i64().unwrap_or_default())
+                    .single()
+                    .unwrap_or_default(),
+                id: event.id.to_hex(),
+            };
+            if cursor.as_ref().map_or(false, |cursor| &seen <= cursor) {
+                continue;
+            }
+            latest_cursor = std::cmp::max(latest_cursor, Some(seen));
+            if !is_excluded(&event, &search) {
+                events.push(event);
+            }
+        }
+        Ok((events, latest_cursor, next))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+    use serde_json::json;
+
+    use crate::backend::meilisearch::{
+        expired_filters, filter_conditions, search_text, to_document,
+    };
+    use crate::index::ttl::IndexTtl;
+    use crate::kind_label::KindLabels;
+    use crate::search::filter::Filter;
+    use crate::search::syntax::SearchQuery;
+
+    #[test]
+    fn test_filter_conditions() {
+        let filter = serde_json::from_value::<Filter>(json!({
+            "authors": ["aa"],
+            "kinds": [1, 30023],
+            "since": 100,
+            "#t": ["nostr", "say \"hi\""],
+            "search": "hello nsfw:false"
+        }))
+        .unwrap();
+        assert_eq!(
+            filter_conditions(&filter, &KindLabels::default(), false),
+            vec![
+                "protected = false",
+                "pubkey IN [\"aa\"]",
+                "kind IN [1, 30023]",
+                "created_at >= 100",
+                "tags IN [\"t:nostr\", \"t:say \\\"hi\\\"\"]",
+                "sensitive = false",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expired_filters() {
+        let ttl = IndexTtl {
+            default_days: Some(1),
+            kinds: [(0, None), (7, Some(2))].into_iter().collect(),
+        };
+        assert_eq!(
+            expired_filters(&ttl, 3 * 86400),
+            vec![
+                "created_at < 172800 AND kind NOT IN [0, 7]".to_string(),
+                "created_at < 86400 AND kind = 7".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_search_text() {
+        let search = SearchQuery::parse("hello \"good morning\" -spam");
+        assert_eq!(search_text(&search), "hello \"good morning\"");
+    }
+
+    #[test]
+    fn test_to_document() {
+        let tags = [
+            Tag::parse(vec!["t", "nostr"]).unwrap(),
+            Tag::parse(vec!["title", "hello"]).unwrap(),
+        ];
+        let event = EventBuilder::new(Kind::TextNote, "hello", &tags)
+            .to_event(&Keys::generate())
+            .unwrap();
+        let doc = to_document(&event, event.content.clone(), 1_700_000_000_000);
+        assert_eq!(doc["doc_id"], event.id.to_hex());
+        assert_eq!(doc["tags"], json!(["t:nostr"]));
+        assert_eq!(doc["sensitive"], false);
+        assert_eq!(doc["event"]["content"], "hello");
+    }
+}
@@ -0,0 +1,721 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use axum::async_trait;
+use chrono::{TimeZone, Utc};
+use nostr_sdk::Event;
+use postgres_native_tls::MakeTlsConnector;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::Client;
+
+use crate::backend::{document_id, is_excluded, SearchBackend, WriteOutcome};
+use crate::index::content_warning::extract_content_warning;
+use crate::index::delegation::author;
+use crate::index::protected::is_protected;
+use crate::index::text::Extractors;
+use crate::index::ttl::IndexTtl;
+use crate::kind_label::KindLabels;
+use crate::search::cache::CachedPage;
+use crate::search::filter::Filter;
+use crate::search::query::{Cursor, PageCursor, DEFAULT_LIMIT, MAX_LIMIT};
+use crate::search::syntax::SearchQuery;
+
+/// Schema changes of the tables of a namespace by version, applied in order; `{table}` and
+/// `{deletions}` are replaced by their names. Applied versions must never change.
+const MIGRATIONS: &[(i32, &str)] = &[
+    (
+        1,
+        "CREATE TABLE {table} (
+            doc_id text PRIMARY KEY,
+            id text NOT NULL,
+            pubkey text NOT NULL,
+            kind bigint NOT NULL,
+            created_at bigint NOT NULL,
+            content text NOT NULL,
+            tags text[] NOT NULL,
+            sensitive boolean NOT NULL,
+            protected boolean NOT NULL,
+            indexed_at bigint NOT NULL,
+            event jsonb NOT NULL,
+            tsv tsvector GENERATED ALWAYS AS (to_tsvector('simple', content)) STORED
+        );
+        CREATE INDEX ON {table} USING GIN (tsv);
+        CREATE INDEX ON {table} USING GIN (tags);
+        CREATE INDEX ON {table} (id);
+        CREATE INDEX ON {table} (pubkey);
+        CREATE INDEX ON {table} (created_at DESC);
+        CREATE INDEX ON {table} (indexed_at, id)",
+    ),
+    // words of languages written without spaces are matched as substrings
+    (
+        2,
+        "CREATE EXTENSION IF NOT EXISTS pg_trgm;
+        CREATE INDEX ON {table} USING GIN (content gin_trgm_ops)",
+    ),
+    // the delegator of delegated events, whose deletions apply to them
+    (
+        3,
+        "ALTER TABLE {table} ADD COLUMN author text;
+        UPDATE {table} SET author = pubkey;
+        ALTER TABLE {table} ALTER COLUMN author SET NOT NULL;
+        CREATE INDEX ON {table} (author)",
+    ),
+    // deletions whose events may not have arrived yet
+    (
+        4,
+        "CREATE TABLE {deletions} (
+            event_id text NOT NULL,
+            pubkey text NOT NULL,
+            created_at bigint NOT NULL,
+            PRIMARY KEY (event_id, pubkey)
+        );
+        CREATE INDEX ON {deletions} (created_at)",
+    ),
+    // pages of pre-EOSE queries continue after the last event of the previous one
+    (5, "CREATE INDEX ON {table} (created_at DESC, id DESC)"),
+];
+
+/// Value bound to a placeholder of a query.
+#[derive(Debug, Clone, PartialEq)]
+enum Param {
+    Text(String),
+    Texts(Vec<String>),
+    Int(i64),
+    Ints(Vec<i64>),
+}
+
+impl Param {
+    fn as_sql(&self) -> &(dyn ToSql + Sync) {
+        match self {
+            Param::Text(value) => value,
+            Param::Texts(values) => values,
+            Param::Int(value) => value,
+            Param::Ints(values) => values,
+        }
+    }
+}
+
+/// Conditions of a query with the values of their placeholders.
+#[derive(Debug, Default)]
+struct Conditions {
+    sql: Vec<String>,
+    params: Vec<Param>,
+}
+
+impl Conditions {
+    /// Adds `condition`, in which `$` is the placeholder of `param`.
+    fn push(&mut self, condition: &str, param: Param) {
+        self.params.push(param);
+        let placeholder = format!("${}", self.params.len());
+        self.sql.push(condition.replace('$', &placeholder));
+    }
+
+    /// Adds `condition`, in which each `$` is the placeholder of the next of `params`.
+    fn push_all(&mut self, condition: &str, params: Vec<Param>) {
+        let mut parts = condition.split('$');
+        let mut sql = parts.next().unwrap_or_default().to_string();
+        for (part, param) in parts.zip(params) {
+            self.params.push(param);
+            sql.push_str(&format!("${}{}", self.params.len(), part));
+        }
+        self.sql.push(sql);
+    }
+
+    fn where_clause(&self) -> String {
+        if self.sql.is_empty() {
+            "TRUE".to_string()
+        } else {
+            self.sql.join(" AND ")
+        }
+    }
+
+    fn params(&self) -> Vec<&(dyn ToSql + Sync)> {
+        self.params.iter().map(|param| param.as_sql()).collect()
+    }
+}
+
+/// Whether the word is written in a script without spaces between words, which the `simple`
+/// text search configuration cannot split.
+fn is_unspaced(word: &str) -> bool {
+    word.chars().any(|c| {
+        matches!(c as u32,
+            0x3040..=0x30ff // hiragana and katakana
+            | 0x3400..=0x4dbf // CJK extension A
+            | 0x4e00..=0x9fff // CJK unified ideographs
+            | 0xac00..=0xd7af // hangul
+            | 0x0e00..=0x0e7f // thai
+        )
+    })
+}
+
+/// `ILIKE` pattern of a substring.
+fn like_pattern(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+fn filter_conditions(
+    filter: &Filter,
+    kind_labels: &KindLabels,
+    exclude_sensitive: bool,
+) -> Conditions {
+    let search = SearchQuery::parse(filter.search.as_deref().unwrap_or_default());
+    let mut conditions = Conditions::default();
+    conditions.sql.push("NOT protected".to_string());
+    for ids in [&filter.ids, &filter.zapped_ids].into_iter().flatten() {
+        conditions.push("id = ANY($)", Param::Texts(ids.clone()));
+    }
+    if let Some(authors) = &filter.authors {
+        conditions.push("pubkey = ANY($)", Param::Texts(authors.clone()));
+    }
+    if !search.authors.is_empty() {
+        conditions.push("pubkey = ANY($)", Param::Texts(search.authors.clone()));
+    }
+    if let Some(kinds) = &filter.kinds {
+        let kinds = kinds.iter().map(|kind| kind.as_u64() as i64).collect();
+        conditions.push("kind = ANY($)", Param::Ints(kinds));
+    }
+    let kinds = search.kinds(kind_labels);
+    if !kinds.is_empty() {
+        let kinds = kinds.into_iter().map(|kind| kind as i64).collect();
+        conditions.push("kind = ANY($)", Param::Ints(kinds));
+    }
+    // both ends are inclusive; See NIP-01
+    let since = filter.since.map(|since| since.as_u64()).into_iter();
+    for since in since.chain(search.since) {
+        conditions.push("created_at >= $", Param::Int(since as i64));
+    }
+    let until = filter.until.map(|until| until.as_u64()).into_iter();
+    for until in until.chain(search.until) {
+        conditions.push("created_at <= $", Param::Int(until as i64));
+    }
+    let mut tags = filter.tags().into_iter().collect::<Vec<_>>();
+    tags.sort();
+    for (name, values) in tags {
+        let values = values
+            .iter()
+            .map(|value| format!("{}:{}", name, value))
+            .collect();
+        conditions.push("tags && $", Param::Texts(values));
+    }
+    let mut and_tags = filter.and_tags().into_iter().collect::<Vec<_>>();
+    and_tags.sort();
+    for (name, values) in and_tags {
+        let values = values
+            .iter()
+            .map(|value| format!("{}:{}", name, value))
+            .collect();
+        conditions.push("tags @> $", Param::Texts(values));
+    }
+    if filter.excludes_sensitive(exclude_sensitive) {
+        conditions.sql.push("NOT sensitive".to_string());
+    }
+    let (unspaced, spaced): (Vec<_>, Vec<_>) =
+        search.terms.iter().partition(|term| is_unspaced(term));
+    if !spaced.is_empty() {
+        let words = spaced.into_iter().cloned().collect::<Vec<_>>().join(" ");
+        conditions.push("tsv @@ plainto_tsquery('simple', $)", Param::Text(words));
+    }
+    for text in unspaced
+        .into_iter()
+        .chain(search.phrases.iter().filter(|p| is_unspaced(p)))
+    {
+        conditions.push("content ILIKE $", Param::Text(like_pattern(text)));
+    }
+    for phrase in search.phrases.iter().filter(|p| !is_unspaced(p)) {
+        conditions.push(
+            "tsv @@ phraseto_tsquery('simple', $)",
+            Param::Text(phrase.clone()),
+        );
+    }
+    conditions
+}
+
+/// `created_at` and id of the last event of the previous page.
+fn page_key(cursor: &PageCursor) -> Option<(i64, String)> {
+    match cursor.values() {
+        [created_at, id] => Some((created_at.as_i64()?, id.as_str()?.to_string())),
+        _ => None,
+    }
+}
+
+/// bytes of an identifier PostgreSQL keeps
+const MAX_IDENTIFIER_LEN: usize = 63;
+
+/// Name of a table of a namespace, e.g. `events` or `deletions`. Aliases whose characters are
+/// replaced or that are too long get a hash of the whole alias, so that none share a table.
+fn table_name(prefix: &str, index_alias_name: &str) -> String {
+    let name = index_alias_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    let len = prefix.len() + 1 + name.len();
+    if name == index_alias_name && len <= MAX_IDENTIFIER_LEN {
+        return format!("\"{}_{}\"", prefix, name);
+    }
+    let hash = Sha256::digest(index_alias_name.as_bytes())[..4]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    let keep = MAX_IDENTIFIER_LEN - prefix.len() - hash.len() - 2;
+    let name = &name[..name.len().min(keep)];
+    format!("\"{}_{}_{}\"", prefix, name, hash)
+}
+
+/// Events of a namespace in a PostgreSQL table, matched with a `simple` text search vector and
+/// trigram indices for the languages without spaces.
+///
+/// Of the operators of `search`, only `from:`, `kind:`, `since:`, `until:`, `nsfw:` and
+/// excluded words are applied. Ids and authors must be complete, not prefixes. The schema is
+/// migrated at startup.
+#[derive(Debug)]
+pub struct PostgresBackend {
+    url: String,
+    table: String,
+    deletions: String,
+    /// replaced by a new connection once closed, e.g. by a restart of the server
+    client: Mutex<Option<Arc<Client>>>,
+    kind_labels: KindLabels,
+    extractors: Extractors,
+    exclude_content_warnings: bool,
+}
+
+impl PostgresBackend {
+    pub fn new(
+        url: &str,
+        index_alias_name: &str,
+        kind_labels: KindLabels,
+        extractors: Extractors,
+        exclude_content_warnings: bool,
+    ) -> Self {
+        PostgresBackend {
+            url: url.to_string(),
+            table: table_name("events", index_alias_name),
+            deletions: table_name("deletions", index_alias_name),
+            client: Mutex::new(None),
+            kind_labels,
+            extractors,
+            exclude_content_warnings,
+        }
+    }
+
+    /// The open connection, connecting first if there is none; concurrent callers wait for the
+    /// same connection.
+    async fn client(&self) -> anyhow::Result<Arc<Client>> {
+        let mut client = self.client.lock().await;
+        if let Some(client) = client.as_ref().filter(|client| !client.is_closed()) {
+            return Ok(client.clone());
+        }
+        // encrypted as the `sslmode` of the URL asks, by default when the server supports it
+        let tls = MakeTlsConnector::new(native_tls::TlsConnector::new()?);
+        let (connected, connection) = tokio_postgres::connect(&self.url, tls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                log::error!("postgres connection closed: {}", e);
+            }
+        });
+        let connected = Arc::new(connected);
+        *client = Some(connected.clone());
+        Ok(connected)
+    }
+
+    /// Applies the migrations the table is missing, one transaction each.
+    async fn migrate(&self) -> anyhow::Result<()> {
+        let client = self.client().await?;
+        client
+            .batch_execute(
+                "CREATE TABLE IF NOT EXISTS searchnos_migrations (
+                    table_name text NOT NULL,
+                    version integer NOT NULL,
+                    applied_at timestamptz NOT NULL DEFAULT now(),
+                    PRIMARY KEY (table_name, version)
+                )",
+            )
+            .await?;
+        let applied = client
+            .query(
+                "SELECT version FROM searchnos_migrations WHERE table_name = $1",
+                &[&self.table],
+            )
+            .await?
+            .iter()
+            .map(|row| row.get::<_, i32>(0))
+            .collect::<HashSet<_>>();
+        for (version, migration) in MIGRATIONS {
+            if applied.contains(version) {
+                continue;
+            }
+            // replicas starting together wait on the lock and skip what the first applied
+            client
+                .batch_execute("BEGIN; LOCK TABLE searchnos_migrations IN EXCLUSIVE MODE")
+                .await?;
+            let res = async {
+                let applied = client
+                    .query_opt(
+                        "SELECT 1 FROM searchnos_migrations WHERE table_name = $1 AND version = $2",
+                        &[&self.table, version],
+                    )
+                    .await?
+                    .is_some();
+                if !applied {
+                    client
+                        .batch_execute(
+                            &migration
+                                .replace("{table}", &self.table)
+                                .replace("{deletions}", &self.deletions),
+                        )
+                        .await?;
+                    client
+                        .execute(
+                            "INSERT INTO searchnos_migrations (table_name, version) VALUES ($1, $2)",
+                            &[&self.table, version],
+                        )
+                        .await?;
+                }
+                Ok::<_, anyhow::Error>(())
+            }
+            .await;
+            match res {
+                Ok(()) => client.batch_execute("COMMIT").await?,
+                Err(e) => {
+                    client.batch_execute("ROLLBACK").await?;
+                    return Err(e);
+                }
+            }
+            log::info!("[{}] applied migration {}", self.table, version);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SearchBackend for PostgresBackend {
+    fn name(&self) -> &'static str {
+        "postgres"
+    }
+
+    async fn prepare(&self) -> anyhow::Result<()> {
+        self.migrate().await?;
+        log::info!("[{}] postgres table ready", self.table);
+        Ok(())
+    }
+
+    async fn is_reachable(&self) -> bool {
+        match self.client().await {
+            Ok(client) => client.simple_query("SELECT 1").await.is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    async fn index(&self, event: &Event) -> anyhow::Result<WriteOutcome> {
+        let tags = event
+            .tags
+            .iter()
+            .map(|tag| tag.as_vec())
+            .filter(|tag| tag.len() >= 2 && tag[0].len() == 1)
+            .map(|tag| format!("{}:{}", tag[0], tag[1]))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        // of versions created at the same time, the lowest id is kept, and the same one is stale
+        let sql = format!(
+            "INSERT INTO {table} AS stored
+                (doc_id, id, pubkey, author, kind, created_at, content, tags, sensitive, protected,
+                indexed_at, event)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12::text::jsonb)
+            ON CONFLICT (doc_id) DO UPDATE SET
+                id = EXCLUDED.id, created_at = EXCLUDED.created_at, content = EXCLUDED.content,
+                tags = EXCLUDED.tags, sensitive = EXCLUDED.sensitive,
+                protected = EXCLUDED.protected, indexed_at = EXCLUDED.indexed_at,
+                event = EXCLUDED.event
+            WHERE stored.created_at < EXCLUDED.created_at
+                OR (stored.created_at = EXCLUDED.created_at AND stored.id > EXCLUDED.id)
+            RETURNING (xmax = 0)",
+            table = self.table
+        );
+        let rows = self
+            .client()
+            .await?
+            .query(
+                &sql,
+                &[
+                    &document_id(event),
+                    &event.id.to_hex(),
+                    &event.pubkey.to_string(),
+                    &author(event),
+                    &(event.kind.as_u64() as i64),
+                    &(event.created_at.as_u64() as i64),
+                    &self.extractors.extract(event),
+                    &tags,
+                    &extract_content_warning(event).is_some(),
+                    &is_protected(event),
+                    &Utc::now().timestamp_millis(),
+                    &json!(event).to_string(),
+                ],
+            )
+            .await?;
+        Ok(match rows.first() {
+            None => WriteOutcome::Stale,
+            Some(row) if row.get::<_, bool>(0) => WriteOutcome::Created,
+            Some(_) => WriteOutcome::Updated,
+        })
+    }
+
+    async fn delete(&self, author: &str, ids: &[String]) -> anyhow::Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let ids = ids.to_vec();
+        self.client()
+            .await?
+            .execute(
+                &format!(
+                    "DELETE FROM {} WHERE author = $1 AND id = ANY($2)",
+                    self.table
+                ),
+                &[&author, &ids],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn record_deletions(
+        &self,
+        author: &str,
+        ids: &[String],
+        created_at: u64,
+    ) -> anyhow::Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let ids = ids.to_vec();
+        self.client()
+            .await?
+            .execute(
+                &format!(
+                    "INSERT INTO {} (event_id, pubkey, created_at)
+                    SELECT unnest($1::text[]), $2, $3
+                    ON CONFLICT DO NOTHING",
+                    self.deletions
+                ),
+                &[&ids, &author, &(created_at as i64)],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn has_deletion(&self, event_id: &str, pubkey: &str) -> anyhow::Result<bool> {
+        let row = self
+            .client()
+            .await?
+            .query_opt(
+                &format!(
+                    "SELECT 1 FROM {} WHERE event_id = $1 AND pubkey = $2",
+                    self.deletions
+                ),
+                &[&event_id, &pubkey],
+            )
+            .await?;
+        Ok(row.is_some())
+    }
+
+    async fn purge(&self, ttl: &IndexTtl) -> anyhow::Result<()> {
+        let client = self.client().await?;
+        let now = Utc::now().timestamp() as u64;
+        let (default_cutoff, kinds) = ttl.cutoffs(now);
+        if let Some(cutoff) = default_cutoff {
+            let overridden = kinds
+                .iter()
+                .map(|(kind, _)| *kind as i64)
+                .collect::<Vec<_>>();
+            let sql = format!(
+                "DELETE FROM {} WHERE created_at < $1 AND NOT (kind = ANY($2))",
+                self.table
+            );
+            client
+                .execute(&sql, &[&(cutoff as i64), &overridden])
+                .await?;
+        }
+        for (kind, cutoff) in kinds {
+            if let Some(cutoff) = cutoff {
+                let sql = format!(
+                    "DELETE FROM {} WHERE kind = $1 AND created_at < $2",
+                    self.table
+                );
+                client
+                    .execute(&sql, &[&(kind as i64), &(cutoff as i64)])
+                    .await?;
+            }
+        }
+        // deletions are kept as long as the events they may delete
+        if let Some(days) = ttl.index_days() {
+            let before = now.saturating_sub(days * 24 * 60 * 60);
+            let sql = format!("DELETE FROM {} WHERE created_at < $1", self.deletions);
+            client.execute(&sql, &[&(before as i64)]).await?;
+        }
+        Ok(())
+    }
+
+    async fn search(&self, filter: &Filter, cursor: Option<Cursor>) -> anyhow::Result<CachedPage> {
+        let search = SearchQuery::parse(filter.search.as_deref().unwrap_or_default());
+        let mut conditions =
+            filter_conditions(filter, &self.kind_labels, self.exclude_content_warnings);
+        let (order, limit) = match &cursor {
+            // pre-EOSE query
+            None => {
+                if let Some((created_at, id)) = filter.cursor.as_ref().and_then(page_key) {
+                    conditions.push_all(
+                        "(created_at, id) < ($, $)",
+                        vec![Param::Int(created_at), Param::Text(id)],
+                    );
+                }
+                let limit = filter
+                    .limit
+                    .map(|l| std::cmp::min(l, MAX_LIMIT))
+                    .unwrap_or(DEFAULT_LIMIT);
+                ("created_at DESC, id DESC", limit)
+            }
+            // post-EOSE query
+            Some(cursor) => {
+                conditions.push(
+                    "indexed_at >= $",
+                    Param::Int(cursor.timestamp.timestamp_millis()),
+                );
+                ("indexed_at, id", MAX_LIMIT)
+            }
+        };
+        let sql = format!(
+            "SELECT event::text, indexed_at FROM {} WHERE {} ORDER BY {} LIMIT {}",
+            self.table,
+            conditions.where_clause(),
+            order,
+            limit
+        );
+        let rows = self
+            .client()
+            .await?
+            .query(&sql, &conditions.params())
+            .await?;
+        let full = rows.len() == limit;
+        let mut events = vec![];
+        let mut latest_cursor = cursor.clone();
+        let mut last = None;
+        for row in rows {
+            let event: Event = serde_json::from_str(row.get::<_, &str>(0))?;
+            last = Some((event.created_at.as_u64(), event.id.to_hex()));
+            let seen = Cursor {
+                timestamp: Utc
+                    .timestamp_millis_opt(row.get::<_, i64>(1))
+                    .single()
+                    .unwrap_or_default(),
+                id: event.id.to_hex(),
+            };
+            // events stored in the same millisecond are told apart by id
+            if cursor.as_ref().map_or(false, |cursor| &seen <= cursor) {
+                continue;
+            }
+            latest_cursor = std::cmp::max(latest_cursor, Some(seen));
+            if !is_excluded(&event, &search) {
+                events.push(event);
+            }
+        }
+        let next = match last {
+            Some((created_at, id)) if cursor.is_none() && full => {
+                Some(PageCursor::new(vec![json!(created_at), json!(id)]))
+            }
+            _ => None,
+        };
+        Ok((events, latest_cursor, next))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::backend::postgres::{
+        filter_conditions, is_unspaced, like_pattern, page_key, table_name, Conditions, Param,
+        MAX_IDENTIFIER_LEN,
+    };
+    use crate::kind_label::KindLabels;
+    use crate::search::filter::Filter;
+    use crate::search::query::PageCursor;
+
+    #[test]
+    fn test_filter_conditions() {
+        let filter = serde_json::from_value::<Filter>(json!({
+            "kinds": [1],
+            "&t": ["a", "b"],
+            "search": "hello world 東京 \"good morning\""
+        }))
+        .unwrap();
+        let conditions = filter_conditions(&filter, &KindLabels::default(), true);
+        assert_eq!(
+            conditions.where_clause(),
+            "NOT protected AND kind = ANY($1) AND tags @> $2 AND NOT sensitive \
+             AND tsv @@ plainto_tsquery('simple', $3) AND content ILIKE $4 \
+             AND tsv @@ phraseto_tsquery('simple', $5)"
+        );
+        assert_eq!(
+            conditions.params,
+            vec![
+                Param::Ints(vec![1]),
+                Param::Texts(vec!["t:a".to_string(), "t:b".to_string()]),
+                Param::Text("hello world".to_string()),
+                Param::Text("%東京%".to_string()),
+                Param::Text("good morning".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_page_key() {
+        let cursor = PageCursor::new(vec![json!(1700000000), json!("ab")]);
+        let (created_at, id) = page_key(&cursor).unwrap();
+        assert_eq!((created_at, id.as_str()), (1700000000, "ab"));
+        assert!(page_key(&PageCursor::new(vec![json!(100)])).is_none());
+
+        let mut conditions = Conditions::default();
+        conditions.push("kind = $", Param::Int(1));
+        conditions.push_all(
+            "(created_at, id) < ($, $)",
+            vec![Param::Int(created_at), Param::Text(id)],
+        );
+        assert_eq!(
+            conditions.where_clause(),
+            "kind = $1 AND (created_at, id) < ($2, $3)"
+        );
+        assert_eq!(conditions.params.len(), 3);
+    }
+
+    #[test]
+    fn test_helpers() {
+        assert!(is_unspaced("東京"));
+        assert!(is_unspaced("서울"));
+        assert!(!is_unspaced("tokyo"));
+        assert_eq!(like_pattern("100%_x"), "%100\\%\\_x%");
+        assert_eq!(table_name("deletions", "nostr"), "\"deletions_nostr\"");
+        assert_eq!(table_name("events", "nostr_main"), "\"events_nostr_main\"");
+        let replaced = table_name("events", "nostr-main");
+        assert!(replaced.starts_with("\"events_nostr_main_"));
+        assert_ne!(replaced, table_name("events", "nostr-Main"));
+        let long = "a".repeat(100);
+        assert_ne!(
+            table_name("events", &long),
+            table_name("events", &long[1..])
+        );
+        assert!(table_name("deletions", &long).len() <= MAX_IDENTIFIER_LEN + 2);
+    }
+}
@@ -0,0 +1,957 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use axum::async_trait;
+use chrono::{NaiveDate, TimeZone, Utc};
+use nostr_sdk::Event;
+use serde_json::json;
+use tantivy::collector::{Count, TopDocs};
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{AllQuery, BooleanQuery, Occur, PhraseQuery, Query, RangeQuery, TermQuery};
+use tantivy::schema::{Field, IndexRecordOption, Schema, FAST, INDEXED, STORED, STRING, TEXT};
+use tantivy::tokenizer::TokenizerManager;
+use tantivy::{DocAddress, Document, Index, IndexReader, IndexWriter, Order, ReloadPolicy, Term};
+
+use crate::backend::{document_id, is_excluded, SearchBackend, WriteOutcome};
+use crate::index::content_warning::extract_content_warning;
+use crate::index::delegation::author;
+use crate::index::protected::is_protected;
+use crate::index::text::Extractors;
+use crate::index::ttl::IndexTtl;
+use crate::kind_label::KindLabels;
+use crate::search::cache::CachedPage;
+use crate::search::filter::Filter;
+use crate::search::query::{Cursor, PageCursor, DEFAULT_LIMIT, MAX_LIMIT};
+use crate::search::syntax::SearchQuery;
+
+/// memory of the writer of a day, the minimum of tantivy
+const WRITER_HEAP_BYTES: usize = 15_000_000;
+/// writers kept open; the least recently used is committed and closed beyond
+const MAX_OPEN_WRITERS: usize = 4;
+/// locks serializing the writes of the documents, by hash of their ids
+const DOC_LOCKS: usize = 64;
+/// name of the writer of the deletions among those of the days
+const DELETIONS: &str = "deletions";
+
+#[derive(Debug, Clone, Copy)]
+struct Fields {
+    doc_id: Field,
+    id: Field,
+    pubkey: Field,
+    /// the delegator of delegated events, whose deletions apply to them
+    author: Field,
+    kind: Field,
+    created_at: Field,
+    content: Field,
+    /// `<name>:<value>` of the single-letter tags
+    tags: Field,
+    /// `sensitive` and `protected`
+    flags: Field,
+    /// unix time in milliseconds
+    indexed_at: Field,
+    event: Field,
+}
+
+fn schema() -> (Schema, Fields) {
+    let mut builder = Schema::builder();
+    let fields = Fields {
+        doc_id: builder.add_text_field("doc_id", STRING),
+        id: builder.add_text_field("id", STRING | STORED),
+        pubkey: builder.add_text_field("pubkey", STRING),
+        author: builder.add_text_field("author", STRING),
+        kind: builder.add_u64_field("kind", INDEXED),
+        created_at: builder.add_u64_field("created_at", INDEXED | FAST | STORED),
+        content: builder.add_text_field("content", TEXT),
+        tags: builder.add_text_field("tags", STRING),
+        flags: builder.add_text_field("flags", STRING),
+        indexed_at: builder.add_u64_field("indexed_at", INDEXED | FAST | STORED),
+        event: builder.add_text_field("event", STORED),
+    };
+    (builder.build(), fields)
+}
+
+/// Fields of the index of the deletions whose events may not have arrived yet.
+#[derive(Debug, Clone, Copy)]
+struct DeletionFields {
+    /// `<event id>:<pubkey>`
+    key: Field,
+    created_at: Field,
+}
+
+fn deletions_schema() -> (Schema, DeletionFields) {
+    let mut builder = Schema::builder();
+    let fields = DeletionFields {
+        key: builder.add_text_field("key", STRING),
+        created_at: builder.add_u64_field("created_at", INDEXED),
+    };
+    (builder.build(), fields)
+}
+
+/// Index of the events created on a day.
+struct DayIndex {
+    index: Index,
+    reader: IndexReader,
+}
+
+impl DayIndex {
+    fn open(path: &Path, schema: Schema) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(path)?;
+        let index = Index::open_or_create(MmapDirectory::open(path)?, schema)?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+        Ok(DayIndex { index, reader })
+    }
+}
+
+struct OpenWriter {
+    index: Arc<DayIndex>,
+    writer: IndexWriter,
+    last_used: u64,
+    /// with changes not committed
+    dirty: bool,
+}
+
+/// Writers of the indices, whose changes are searchable once committed.
+#[derive(Default)]
+struct Writers {
+    open: HashMap<String, OpenWriter>,
+    uses: u64,
+    /// day, `created_at` and id of the versions written since the last commit, by document id
+    uncommitted: HashMap<String, (String, u64, String)>,
+    /// `indexed_at` of the documents written after the last commit, so that the events stored
+    /// after a post-EOSE query are all after its cursor
+    min_indexed_at: u64,
+    max_indexed_at: u64,
+}
+
+impl Writers {
+    /// The writer of the index `name`, opened first if needed.
+    fn writer(&mut self, name: &str, index: &Arc<DayIndex>) -> anyhow::Result<&mut IndexWriter> {
+        if !self.open.contains_key(name) {
+            if self.open.len() >= MAX_OPEN_WRITERS {
+                let oldest = self
+                    .open
+                    .iter()
+                    .min_by_key(|(_, open)| open.last_used)
+                    .map(|(name, _)| name.clone());
+                if let Some(oldest) = oldest {
+                    self.commit(&oldest)?;
+                    self.open.remove(&oldest);
+                }
+            }
+            let writer = index.index.writer_with_num_threads(1, WRITER_HEAP_BYTES)?;
+            self.open.insert(
+                name.to_string(),
+                OpenWriter {
+                    index: index.clone(),
+                    writer,
+                    last_used: 0,
+                    dirty: false,
+                },
+            );
+        }
+        self.uses += 1;
+        let open = self.open.get_mut(name).unwrap();
+        open.last_used = self.uses;
+        open.dirty = true;
+        Ok(&mut open.writer)
+    }
+
+    fn is_dirty(&self, name: &str) -> bool {
+        self.open.get(name).map_or(false, |open| open.dirty)
+    }
+
+    fn indexed_at(&mut self) -> u64 {
+        let indexed_at = (Utc::now().timestamp_millis() as u64).max(self.min_indexed_at);
+        self.max_indexed_at = self.max_indexed_at.max(indexed_at);
+        indexed_at
+    }
+
+    fn commit(&mut self, name: &str) -> anyhow::Result<()> {
+        if let Some(open) = self.open.get_mut(name).filter(|open| open.dirty) {
+            open.writer.commit()?;
+            open.index.reader.reload()?;
+            open.dirty = false;
+        }
+        self.uncommitted.retain(|_, (day, _, _)| day != name);
+        self.min_indexed_at = self.max_indexed_at + 1;
+        Ok(())
+    }
+
+    fn commit_all(&mut self) -> anyhow::Result<()> {
+        let names = self.open.keys().cloned().collect::<Vec<_>>();
+        for name in names {
+            self.commit(&name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Events of a namespace in an embedded tantivy index per day of `created_at`, under
+/// `<dir>/<alias>/<yyyy-mm-dd>`, so that expired days are dropped as a whole; the deletions are
+/// kept in `<dir>/<alias>/deletions`. Writes are searchable once committed by `commit`.
+///
+/// Words are matched by the default tokenizer with all of them required; of the operators of
+/// `search`, only `from:`, `kind:`, `since:`, `until:`, `nsfw:` and excluded words are
+/// applied. Ids and authors must be complete, not prefixes. The directory must not be shared by
+/// several processes.
+pub struct TantivyBackend {
+    dir: PathBuf,
+    schema: Schema,
+    fields: Fields,
+    days: RwLock<BTreeMap<String, Arc<DayIndex>>>,
+    /// locked before `days`
+    writers: Mutex<Writers>,
+    doc_locks: Vec<Mutex<()>>,
+    deletion_fields: DeletionFields,
+    /// opened by `prepare`
+    deletions: RwLock<Option<Arc<DayIndex>>>,
+    kind_labels: KindLabels,
+    extractors: Extractors,
+    exclude_content_warnings: bool,
+}
+
+impl std::fmt::Debug for TantivyBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TantivyBackend")
+            .field("dir", &self.dir)
+            .finish()
+    }
+}
+
+/// Day of the index of an event created at `created_at`.
+fn day_of(created_at: u64) -> String {
+    Utc.timestamp_opt(created_at as i64, 0)
+        .single()
+        .unwrap_or_default()
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+/// Days older than `retention_days` before `today`.
+fn expired_days<'a>(
+    days: impl Iterator<Item = &'a String>,
+    today: NaiveDate,
+    retention_days: u64,
+) -> Vec<String> {
+    days.filter(|day| {
+        NaiveDate::parse_from_str(day, "%Y-%m-%d").map_or(false, |date| {
+            (today - date).num_days() > retention_days as i64
+        })
+    })
+    .cloned()
+    .collect()
+}
+
+fn term_query(term: Term) -> Box<dyn Query> {
+    Box::new(TermQuery::new(term, IndexRecordOption::Basic))
+}
+
+/// Matches any of `values` of the field; nothing if there are none.
+fn any_of(field: Field, values: &[String]) -> Box<dyn Query> {
+    Box::new(BooleanQuery::new(
+        values
+            .iter()
+            .map(|value| {
+                (
+                    Occur::Should,
+                    term_query(Term::from_field_text(field, value)),
+                )
+            })
+            .collect(),
+    ))
+}
+
+fn u64_range(field: &str, lower: Bound<u64>, upper: Bound<u64>) -> Box<dyn Query> {
+    Box::new(RangeQuery::new_u64_bounds(field.to_string(), lower, upper))
+}
+
+impl TantivyBackend {
+    pub fn new(
+        dir: &Path,
+        index_alias_name: &str,
+        kind_labels: KindLabels,
+        extractors: Extractors,
+        exclude_content_warnings: bool,
+    ) -> Self {
+        let (schema, fields) = schema();
+        TantivyBackend {
+            dir: dir.join(index_alias_name),
+            schema,
+            fields,
+            days: RwLock::new(BTreeMap::new()),
+            writers: Mutex::new(Writers::default()),
+            doc_locks: (0..DOC_LOCKS).map(|_| Mutex::new(())).collect(),
+            deletion_fields: deletions_schema().1,
+            deletions: RwLock::new(None),
+            kind_labels,
+            extractors,
+            exclude_content_warnings,
+        }
+    }
+
+    fn day(&self, day: &str) -> anyhow::Result<Arc<DayIndex>> {
+        if let Some(index) = self.days.read().unwrap().get(day) {
+            return Ok(index.clone());
+        }
+        let mut days = self.days.write().unwrap();
+        // opened by another write meanwhile
+        if let Some(index) = days.get(day) {
+            return Ok(index.clone());
+        }
+        let index = Arc::new(DayIndex::open(&self.dir.join(day), self.schema.clone())?);
+        days.insert(day.to_string(), index.clone());
+        Ok(index)
+    }
+
+    /// Lock of the writes of the document of `doc_id`, so that its versions are compared with
+    /// the stored one one at a time.
+    fn doc_lock(&self, doc_id: &str) -> &Mutex<()> {
+        let mut hasher = DefaultHasher::new();
+        doc_id.hash(&mut hasher);
+        &self.doc_locks[hasher.finish() as usize % DOC_LOCKS]
+    }
+
+    fn deletions(&self) -> anyhow::Result<Arc<DayIndex>> {
+        self.deletions
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("tantivy backend not prepared"))
+    }
+
+    fn purge_sync(&self, ttl: &IndexTtl) -> anyhow::Result<()> {
+        let mut writers = self.writers.lock().unwrap();
+        // whole days past the longest TTL
+        if let Some(index_days) = ttl.index_days() {
+            let mut days = self.days.write().unwrap();
+            for day in expired_days(days.keys(), Utc::now().date_naive(), index_days) {
+                writers.open.remove(&day);
+                writers
+                    .uncommitted
+                    .retain(|_, (stored, _, _)| *stored != day);
+                days.remove(&day);
+                std::fs::remove_dir_all(self.dir.join(&day))?;
+                log::info!("[{}] dropped the index of {}", self.dir.display(), day);
+            }
+        }
+        let now = Utc::now().timestamp() as u64;
+        let (default_cutoff, kinds) = ttl.cutoffs(now);
+        let mut queries = vec![];
+        if let Some(cutoff) = default_cutoff {
+            let mut clauses = vec![(
+                Occur::Must,
+                u64_range("created_at", Bound::Unbounded, Bound::Excluded(cutoff)),
+            )];
+            for (kind, _) in &kinds {
+                clauses.push((
+                    Occur::MustNot,
+                    term_query(Term::from_field_u64(self.fields.kind, *kind)),
+                ));
+            }
+            queries.push(BooleanQuery::new(clauses));
+        }
+        for (kind, cutoff) in kinds {
+            if let Some(cutoff) = cutoff {
+                queries.push(BooleanQuery::new(vec![
+                    (
+                        Occur::Must,
+                        u64_range("created_at", Bound::Unbounded, Bound::Excluded(cutoff)),
+                    ),
+                    (
+                        Occur::Must,
+                        term_query(Term::from_field_u64(self.fields.kind, kind)),
+                    ),
+                ]));
+            }
+        }
+        for (name, day) in self.all_days() {
+            let searcher = day.reader.searcher();
+            for query in &queries {
+                if writers.is_dirty(&name) || searcher.search(query, &Count)? > 0 {
+                    writers
+                        .writer(&name, &day)?
+                        .delete_query(Box::new(query.clone()))?;
+                }
+            }
+        }
+        // deletions are kept as long as the events they may delete
+        if let Some(index_days) = ttl.index_days() {
+            let before = now.saturating_sub(index_days * 24 * 60 * 60);
+            writers
+                .writer(DELETIONS, &self.deletions()?)?
+                .delete_query(u64_range(
+                    "created_at",
+                    Bound::Unbounded,
+                    Bound::Excluded(before),
+                ))?;
+        }
+        writers.commit_all()
+    }
+
+    fn all_days(&self) -> Vec<(String, Arc<DayIndex>)> {
+        let days = self.days.read().unwrap();
+        days.iter()
+            .map(|(name, day)| (name.clone(), day.clone()))
+            .collect()
+    }
+
+    /// Terms of `text` as tokenized for the `content` field.
+    fn text_terms(&self, text: &str) -> anyhow::Result<Vec<Term>> {
+        let mut analyzer = TokenizerManager::default()
+            .get("default")
+            .ok_or_else(|| anyhow::anyhow!("no default tokenizer"))?;
+        let mut stream = analyzer.token_stream(text);
+        let mut terms = vec![];
+        while stream.advance() {
+            terms.push(Term::from_field_text(
+                self.fields.content,
+                &stream.token().text,
+            ));
+        }
+        Ok(terms)
+    }
+
+    /// Query of the filter, without the order.
+    fn query(&self, filter: &Filter) -> anyhow::Result<BooleanQuery> {
+        let fields = &self.fields;
+        let search = SearchQuery::parse(filter.search.as_deref().unwrap_or_default());
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![
+            (Occur::Must, Box::new(AllQuery)),
+            (
+                Occur::MustNot,
+                term_query(Term::from_field_text(fields.flags, "protected")),
+            ),
+        ];
+        for ids in [&filter.ids, &filter.zapped_ids].into_iter().flatten() {
+            clauses.push((Occur::Must, any_of(fields.id, ids)));
+        }
+        if let Some(authors) = &filter.authors {
+            clauses.push((Occur::Must, any_of(fields.pubkey, authors)));
+        }
+        if !search.authors.is_empty() {
+            clauses.push((Occur::Must, any_of(fields.pubkey, &search.authors)));
+        }
+        let kinds = [
+            filter
+                .kinds
+                .as_ref()
+                .map(|kinds| kinds.iter().map(|kind| kind.as_u64()).collect::<Vec<_>>()),
+            Some(search.kinds(&self.kind_labels))
+                .filter(|kinds| !kinds.is_empty())
+                .map(|kinds| kinds.into_iter().map(|kind| kind as u64).collect()),
+        ];
+        for kinds in kinds.into_iter().flatten() {
+            let kinds = kinds
+                .into_iter()
+                .map(|kind| {
+                    (
+                        Occur::Should,
+                        term_query(Term::from_field_u64(fields.kind, kind)),
+                    )
+                })
+                .collect();
+            clauses.push((Occur::Must, Box::new(BooleanQuery::new(kinds))));
+        }
+        // both ends are inclusive; See NIP-01
+        let since = filter.since.map(|since| since.as_u64()).into_iter();
+        for since in since.chain(search.since) {
+            clauses.push((
+                Occur::Must,
+                u64_range("created_at", Bound::Included(since), Bound::Unbounded),
+            ));
+        }
+        let until = filter.until.map(|until| until.as_u64()).into_iter();
+        for until in until.chain(search.until) {
+            clauses.push((
+                Occur::Must,
+                u64_range("created_at", Bound::Unbounded, Bound::Included(until)),
+            ));
+        }
+        for (name, values) in filter.tags() {
+            let values = values
+                .iter()
+                .map(|value| format!("{}:{}", name, value))
+                .collect::<Vec<_>>();
+            clauses.push((Occur::Must, any_of(fields.tags, &values)));
+        }
+        for (name, values) in filter.and_tags() {
+            for value in values {
+                let term = Term::from_field_text(fields.tags, &format!("{}:{}", name, value));
+                clauses.push((Occur::Must, term_query(term)));
+            }
+        }
+        if filter.excludes_sensitive(self.exclude_content_warnings) {
+            clauses.push((
+                Occur::MustNot,
+                term_query(Term::from_field_text(fields.flags, "sensitive")),
+            ));
+        }
+        for term in self.text_terms(&search.terms.join(" "))? {
+            clauses.push((Occur::Must, term_query(term)));
+        }
+        for phrase in &search.phrases {
+            let mut terms = self.text_terms(phrase)?;
+            let query: Box<dyn Query> = if terms.len() == 1 {
+                term_query(terms.remove(0))
+            } else if terms.is_empty() {
+                continue;
+            } else {
+                Box::new(PhraseQuery::new(terms))
+            };
+            clauses.push((Occur::Must, query));
+        }
+        Ok(BooleanQuery::new(clauses))
+    }
+
+    /// Document of the event, without its `indexed_at`.
+    fn document(&self, event: &Event) -> Document {
+        let fields = &self.fields;
+        let mut doc = Document::default();
+        doc.add_text(fields.doc_id, document_id(event));
+        doc.add_text(fields.id, event.id.to_hex());
+        doc.add_text(fields.pubkey, event.pubkey.to_string());
+        doc.add_text(fields.author, author(event));
+        doc.add_u64(fields.kind, event.kind.as_u64());
+        doc.add_u64(fields.created_at, event.created_at.as_u64());
+        doc.add_text(fields.content, self.extractors.extract(event));
+        let tags = event
+            .tags
+            .iter()
+            .map(|tag| tag.as_vec())
+            .filter(|tag| tag.len() >= 2 && tag[0].len() == 1)
+            .map(|tag| format!("{}:{}", tag[0], tag[1]))
+            .collect::<HashSet<_>>();
+        for tag in tags {
+            doc.add_text(fields.tags, tag);
+        }
+        if extract_content_warning(event).is_some() {
+            doc.add_text(fields.flags, "sensitive");
+        }
+        if is_protected(event) {
+            doc.add_text(fields.flags, "protected");
+        }
+        doc.add_text(fields.event, json!(event).to_string());
+        doc
+    }
+
+    /// Stored values of a hit: `indexed_at` and the event.
+    fn hit(&self, day: &DayIndex, address: DocAddress) -> anyhow::Result<(u64, Event)> {
+        let doc = day.reader.searcher().doc(address)?;
+        let indexed_at = doc
+            .get_first(self.fields.indexed_at)
+            .and_then(|value| value.as_u64())
+            .unwrap_or_default();
+        let event = doc
+            .get_first(self.fields.event)
+            .and_then(|value| value.as_text())
+            .ok_or_else(|| anyhow::anyhow!("document without an event"))?;
+        Ok((indexed_at, serde_json::from_str(event)?))
+    }
+
+    /// The days with a committed version of the document of `doc_id`, with its `created_at`
+    /// and id.
+    fn stored_versions(&self, doc_id: &str) -> anyhow::Result<Vec<(String, u64, String)>> {
+        let query = term_query(Term::from_field_text(self.fields.doc_id, doc_id));
+        let mut versions = vec![];
+        for (name, day) in self.all_days() {
+            let searcher = day.reader.searcher();
+            for (_, address) in searcher.search(query.as_ref(), &TopDocs::with_limit(1))? {
+                let doc = searcher.doc(address)?;
+                let created_at = doc
+                    .get_first(self.fields.created_at)
+                    .and_then(|value| value.as_u64())
+                    .unwrap_or_default();
+                let id = doc
+                    .get_first(self.fields.id)
+                    .and_then(|value| value.as_text())
+                    .unwrap_or_default()
+                    .to_string();
+                versions.push((name.clone(), created_at, id));
+            }
+        }
+        Ok(versions)
+    }
+
+    fn index_sync(&self, event: &Event) -> anyhow::Result<WriteOutcome> {
+        let doc_id = document_id(event);
+        let id = event.id.to_hex();
+        let created_at = event.created_at.as_u64();
+        let _doc_lock = self.doc_lock(&doc_id).lock().unwrap();
+        let mut versions = self.stored_versions(&doc_id)?;
+        let uncommitted = self
+            .writers
+            .lock()
+            .unwrap()
+            .uncommitted
+            .get(&doc_id)
+            .cloned();
+        versions.extend(uncommitted);
+        // of versions created at the same time, the lowest id is kept, and the same one is stale
+        if versions.iter().any(|(_, stored, stored_id)| {
+            *stored > created_at || (*stored == created_at && *stored_id <= id)
+        }) {
+            return Ok(WriteOutcome::Stale);
+        }
+        let outcome = if versions.is_empty() {
+            WriteOutcome::Created
+        } else {
+            WriteOutcome::Updated
+        };
+        let name = day_of(created_at);
+        let day = self.day(&name)?;
+        // the versions of the days dropped meanwhile are gone
+        let stored_days = {
+            let days = self.days.read().unwrap();
+            versions
+                .iter()
+                .filter(|(stored, _, _)| *stored != name)
+                .filter_map(|(stored, _, _)| Some((stored.clone(), days.get(stored)?.clone())))
+                .collect::<Vec<_>>()
+        };
+        let term = Term::from_field_text(self.fields.doc_id, &doc_id);
+        let mut doc = self.document(event);
+        let mut writers = self.writers.lock().unwrap();
+        for (stored, stored_day) in &stored_days {
+            writers
+                .writer(stored, stored_day)?
+                .delete_term(term.clone());
+        }
+        doc.add_u64(self.fields.indexed_at, writers.indexed_at());
+        let writer = writers.writer(&name, &day)?;
+        writer.delete_term(term);
+        writer.add_document(doc)?;
+        writers.uncommitted.insert(doc_id, (name, created_at, id));
+        Ok(outcome)
+    }
+
+    fn delete_sync(&self, author: &str, ids: &[String]) -> anyhow::Result<()> {
+        let query = BooleanQuery::new(vec![
+            (Occur::Must, any_of(self.fields.id, ids)),
+            (
+                Occur::Must,
+                term_query(Term::from_field_text(self.fields.author, author)),
+            ),
+        ]);
+        let mut writers = self.writers.lock().unwrap();
+        for (name, day) in self.all_days() {
+            if writers.is_dirty(&name) || day.reader.searcher().search(&query, &Count)? > 0 {
+                writers
+                    .writer(&name, &day)?
+                    .delete_query(Box::new(query.clone()))?;
+            }
+        }
+        writers
+            .uncommitted
+            .retain(|_, (_, _, id)| !ids.contains(id));
+        Ok(())
+    }
+
+    fn search_sync(&self, filter: &Filter, cursor: Option<Cursor>) -> anyhow::Result<CachedPage> {
+        let search = SearchQuery::parse(filter.search.as_deref().unwrap_or_default());
+        let query = self.query(filter)?;
+        let mut hits = vec![];
+        let next = match &cursor {
+            // pre-EOSE query; the days are searched from the newest until the page is full
+            None => {
+                let offset = filter
+                    .cursor
+                    .as_ref()
+                    .and_then(|cursor| cursor.values().first())
+                    .and_then(|offset| offset.as_u64())
+                    .unwrap_or_default() as usize;
+                let limit = filter
+                    .limit
+                    .map(|l| std::cmp::min(l, MAX_LIMIT))
+                    .unwrap_or(DEFAULT_LIMIT);
+                for (_, day) in self.all_days().into_iter().rev() {
+                    let wanted = offset + limit - hits.len();
+                    if wanted == 0 {
+                        break;
+                    }
+                    let collector =
+                        TopDocs::with_limit(wanted).order_by_u64_field("created_at", Order::Desc);
+                    for (_, address) in day.reader.searcher().search(&query, &collector)? {
+                        hits.push(self.hit(&day, address)?);
+                    }
+                }
+                let full = hits.len() == offset + limit;
+                hits = hits.into_iter().skip(offset).collect();
+                if full {
+                    Some(PageCursor::new(vec![json!(offset + limit)]))
+                } else {
+                    None
+                }
+            }
+            // post-EOSE query; events stored in the same millisecond are told apart by id below
+            Some(cursor) => {
+                let since = cursor.timestamp.timestamp_millis() as u64;
+                let query = BooleanQuery::new(vec![
+                    (Occur::Must, Box::new(query) as Box<dyn Query>),
+                    (
+                        Occur::Must,
+                        u64_range("indexed_at", Bound::Included(since), Bound::Unbounded),
+                    ),
+                ]);
+                for (_, day) in self.all_days() {
+                    let collector =
+                        TopDocs::with_limit(MAX_LIMIT).order_by_u64_field("indexed_at", Order::Asc);
+                    for (_, address) in day.reader.searcher().search(&query, &collector)? {
+                        hits.push(self.hit(&day, address)?);
+                    }
+                }
+                hits.sort_by_key(|(indexed_at, event)| (*indexed_at, event.id.to_hex()));
+                hits.truncate(MAX_LIMIT);
+                None
+            }
+        };
+        let mut events = vec![];
+        let mut latest_cursor = cursor.clone();
+        for (indexed_at, event) in hits {
+            let seen = Cursor {
+                timestamp: Utc
+                    .timestamp_millis_opt(indexed_at as i64)
+                    .single()
+                    .unwrap_or_default(),
+                id: event.id.to_hex(),
+            };
+            if cursor.as_ref().map_or(false, |cursor| &seen <= cursor) {
+                continue;
+            }
+            latest_cursor = std::cmp::max(latest_cursor, Some(seen));
+            if !is_excluded(&event, &search) {
+                events.push(event);
+            }
+        }
+        Ok((events, latest_cursor, next))
+    }
+}
+
+// tantivy blocks; the relay runs on the multi-threaded runtime
+#[async_trait]
+impl SearchBackend for TantivyBackend {
+    fn name(&self) -> &'static str {
+        "tantivy"
+    }
+
+    async fn prepare(&self) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let mut days = vec![];
+        for entry in std::fs::read_dir(&self.dir)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if NaiveDate::parse_from_str(&name, "%Y-%m-%d").is_ok() {
+                days.push(name);
+            }
+        }
+        for day in &days {
+            self.day(day)?;
+        }
+        let deletions = DayIndex::open(&self.dir.join(DELETIONS), deletions_schema().0)?;
+        *self.deletions.write().unwrap() = Some(Arc::new(deletions));
+        log::info!(
+            "[{}] tantivy index ready with {} day(s)",
+            self.dir.display(),
+            self.days.read().unwrap().len()
+        );
+        Ok(())
+    }
+
+    async fn is_reachable(&self) -> bool {
+        self.dir.is_dir()
+    }
+
+    async fn index(&self, event: &Event) -> anyhow::Result<WriteOutcome> {
+        tokio::task::block_in_place(|| self.index_sync(event))
+    }
+
+    async fn delete(&self, author: &str, ids: &[String]) -> anyhow::Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        tokio::task::block_in_place(|| self.delete_sync(author, ids))
+    }
+
+    async fn record_deletions(
+        &self,
+        author: &str,
+        ids: &[String],
+        created_at: u64,
+    ) -> anyhow::Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let fields = self.deletion_fields;
+        // committed at once, so that the deleted events arriving next are skipped
+        tokio::task::block_in_place(|| {
+            let deletions = self.deletions()?;
+            let mut writers = self.writers.lock().unwrap();
+            let writer = writers.writer(DELETIONS, &deletions)?;
+            for id in ids {
+                let key = format!("{}:{}", id, author);
+                writer.delete_term(Term::from_field_text(fields.key, &key));
+                let mut doc = Document::default();
+                doc.add_text(fields.key, key);
+                doc.add_u64(fields.created_at, created_at);
+                writer.add_document(doc)?;
+            }
+            writers.commit(DELETIONS)
+        })
+    }
+
+    async fn has_deletion(&self, event_id: &str, pubkey: &str) -> anyhow::Result<bool> {
+        let key = format!("{}:{}", event_id, pubkey);
+        let query = term_query(Term::from_field_text(self.deletion_fields.key, &key));
+        let searcher = self.deletions()?.reader.searcher();
+        Ok(searcher.search(query.as_ref(), &Count)? > 0)
+    }
+
+    async fn purge(&self, ttl: &IndexTtl) -> anyhow::Result<()> {
+        tokio::task::block_in_place(|| self.purge_sync(ttl))
+    }
+
+    async fn commit(&self) -> anyhow::Result<()> {
+        tokio::task::block_in_place(|| self.writers.lock().unwrap().commit_all())
+    }
+
+    async fn search(&self, filter: &Filter, cursor: Option<Cursor>) -> anyhow::Result<CachedPage> {
+        tokio::task::block_in_place(|| self.search_sync(filter, cursor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use chrono::NaiveDate;
+    use nostr_sdk::{Event, EventBuilder, Keys, Kind, Timestamp};
+    use serde_json::json;
+
+    use crate::backend::tantivy::{day_of, expired_days, TantivyBackend, MAX_OPEN_WRITERS};
+    use crate::backend::{SearchBackend, WriteOutcome};
+    use crate::index::text::Extractors;
+    use crate::kind_label::KindLabels;
+    use crate::search::filter::Filter;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    async fn backend() -> (TantivyBackend, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("searchnos-tantivy-{}", rand::random::<u64>()));
+        let backend = TantivyBackend::new(
+            &dir,
+            "nostr",
+            KindLabels::default(),
+            Extractors::default(),
+            false,
+        );
+        backend.prepare().await.unwrap();
+        (backend, dir)
+    }
+
+    fn event(keys: &Keys, kind: u64, content: &str, created_at: u64) -> Event {
+        let mut event = EventBuilder::new(Kind::from(kind), content, &[])
+            .to_event(keys)
+            .unwrap();
+        event.created_at = Timestamp::from(created_at);
+        event
+    }
+
+    async fn search(backend: &TantivyBackend, filter: serde_json::Value) -> Vec<Event> {
+        let filter = serde_json::from_value::<Filter>(filter).unwrap();
+        backend.search(&filter, None).await.unwrap().0
+    }
+
+    #[test]
+    fn test_day_of() {
+        assert_eq!(day_of(1_700_000_000), "2023-11-14");
+        assert_eq!(day_of(0), "1970-01-01");
+    }
+
+    #[test]
+    fn test_expired_days() {
+        let days = vec![
+            "2024-03-01".to_string(),
+            "2024-03-09".to_string(),
+            "2024-03-10".to_string(),
+            "lost+found".to_string(),
+        ];
+        let today = NaiveDate::from_ymd_opt(2024, 3, 17).unwrap();
+        assert_eq!(
+            expired_days(days.iter(), today, 7),
+            vec!["2024-03-01", "2024-03-09"]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_index_search_delete() {
+        let (backend, dir) = backend().await;
+        let keys = Keys::generate();
+        let now = Timestamp::now().as_u64();
+        let note = event(&keys, 1, "hello world", now);
+        assert_eq!(backend.index(&note).await.unwrap(), WriteOutcome::Created);
+        assert_eq!(backend.index(&note).await.unwrap(), WriteOutcome::Stale);
+        // searchable once committed
+        assert!(search(&backend, json!({ "search": "hello" }))
+            .await
+            .is_empty());
+        backend.commit().await.unwrap();
+        assert_eq!(
+            search(&backend, json!({ "search": "hello" })).await,
+            vec![note.clone()]
+        );
+        assert!(search(&backend, json!({ "search": "bye" }))
+            .await
+            .is_empty());
+
+        let author = keys.public_key().to_string();
+        let ids = vec![note.id.to_hex()];
+        backend.delete(&author, &ids).await.unwrap();
+        backend.record_deletions(&author, &ids, now).await.unwrap();
+        backend.commit().await.unwrap();
+        assert!(search(&backend, json!({ "search": "hello" }))
+            .await
+            .is_empty());
+        assert!(backend.has_deletion(&ids[0], &author).await.unwrap());
+        assert!(!backend.has_deletion(&ids[0], "other").await.unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replaceable() {
+        let (backend, dir) = backend().await;
+        let keys = Keys::generate();
+        let now = Timestamp::now().as_u64();
+        let older = event(&keys, 0, "older", now - 2 * DAY);
+        let newer = event(&keys, 0, "newer", now);
+        assert_eq!(backend.index(&older).await.unwrap(), WriteOutcome::Created);
+        // not committed yet, and on another day
+        assert_eq!(backend.index(&newer).await.unwrap(), WriteOutcome::Updated);
+        assert_eq!(backend.index(&older).await.unwrap(), WriteOutcome::Stale);
+        backend.commit().await.unwrap();
+        assert_eq!(backend.index(&older).await.unwrap(), WriteOutcome::Stale);
+        assert_eq!(search(&backend, json!({ "kinds": [0] })).await, vec![newer]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_open_writers() {
+        let (backend, dir) = backend().await;
+        let keys = Keys::generate();
+        let now = Timestamp::now().as_u64();
+        let days = MAX_OPEN_WRITERS as u64 + 2;
+        for day in 0..days {
+            let note = event(&keys, 1, &format!("note {}", day), now - day * DAY);
+            backend.index(&note).await.unwrap();
+            assert!(backend.writers.lock().unwrap().open.len() <= MAX_OPEN_WRITERS);
+        }
+        backend.commit().await.unwrap();
+        let notes = search(&backend, json!({ "kinds": [1] })).await;
+        assert_eq!(notes.len(), days as usize);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Semaphore;
+
+/// wait of the callers while the trial request of a half-open breaker runs
+const HALF_OPEN_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl BreakerState {
+    /// Value of the state in the metrics.
+    pub fn as_u64(&self) -> u64 {
+        match self {
+            BreakerState::Closed => 0,
+            BreakerState::Open => 1,
+            BreakerState::HalfOpen => 2,
+        }
+    }
+}
+
+/// Opens after `max_failures` consecutive failures, then lets one trial request through
+/// every `cooldown` until one succeeds.
+#[derive(Debug)]
+struct CircuitBreaker {
+    max_failures: u64,
+    cooldown: Duration,
+    failures: u64,
+    state: BreakerState,
+    open_until: Instant,
+}
+
+impl CircuitBreaker {
+    fn new(max_failures: u64, cooldown: Duration, now: Instant) -> Self {
+        CircuitBreaker {
+            max_failures: max_failures.max(1),
+            cooldown,
+            failures: 0,
+            state: BreakerState::Closed,
+            open_until: now,
+        }
+    }
+
+    /// Lets a request through, or returns how long to wait before asking again.
+    fn check(&mut self, now: Instant) -> Result<(), Duration> {
+        match self.state {
+            BreakerState::Closed => Ok(()),
+            BreakerState::Open if now >= self.open_until => {
+                self.state = BreakerState::HalfOpen;
+                Ok(())
+            }
+            BreakerState::Open => Err(self.open_until - now),
+            BreakerState::HalfOpen => Err(HALF_OPEN_WAIT),
+        }
+    }
+
+    /// Records the outcome of a request let through; returns whether the breaker tripped.
+    fn record(&mut self, success: bool, now: Instant) -> bool {
+        if success {
+            self.failures = 0;
+            self.state = BreakerState::Closed;
+            return false;
+        }
+        self.failures += 1;
+        let trips = self.state == BreakerState::HalfOpen
+            || (self.state == BreakerState::Closed && self.failures >= self.max_failures);
+        if trips {
+            self.state = BreakerState::Open;
+            self.open_until = now + self.cooldown;
+        }
+        trips
+    }
+}
+
+/// Limits the requests in flight to Elasticsearch and stops sending them for a while once
+/// enough fail in a row, so that a degraded cluster is not hammered.
+#[derive(Debug)]
+pub struct EsGuard {
+    max_in_flight: usize,
+    permits: Semaphore,
+    breaker: Option<Mutex<CircuitBreaker>>,
+    /// times the breaker opened
+    pub trips: AtomicU64,
+}
+
+impl EsGuard {
+    /// Breaks after `max_failures` consecutive failures if given.
+    pub fn new(max_in_flight: usize, max_failures: Option<u64>, cooldown: Duration) -> Self {
+        let max_in_flight = max_in_flight.clamp(1, Semaphore::MAX_PERMITS);
+        EsGuard {
+            max_in_flight,
+            permits: Semaphore::new(max_in_flight),
+            breaker: max_failures
+                .map(|n| Mutex::new(CircuitBreaker::new(n, cooldown, Instant::now()))),
+            trips: AtomicU64::new(0),
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match &self.breaker {
+            Some(breaker) => breaker.lock().unwrap().state,
+            None => BreakerState::Closed,
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.max_in_flight - self.permits.available_permits()
+    }
+
+    /// Runs `request` once the breaker lets it through and a permit is free.
+    pub async fn run<T, F>(&self, request: F) -> anyhow::Result<T>
+    where
+        F: Future<Output = anyhow::Result<T>>,
+    {
+        if let Some(breaker) = &self.breaker {
+            loop {
+                let wait = match breaker.lock().unwrap().check(Instant::now()) {
+                    Ok(()) => break,
+                    Err(wait) => wait,
+                };
+                tokio::time::sleep(wait).await;
+            }
+        }
+        let _permit = self.permits.acquire().await?;
+        let res = request.await;
+        if let Some(breaker) = &self.breaker {
+            if breaker.lock().unwrap().record(res.is_ok(), Instant::now()) {
+                self.trips.fetch_add(1, Ordering::Relaxed);
+                log::warn!("too many Elasticsearch failures; pausing requests");
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::breaker::{BreakerState, CircuitBreaker, HALF_OPEN_WAIT};
+
+    #[test]
+    fn test_circuit_breaker() {
+        let now = Instant::now();
+        let cooldown = Duration::from_secs(30);
+        let mut breaker = CircuitBreaker::new(3, cooldown, now);
+        assert!(!breaker.record(false, now));
+        assert!(!breaker.record(false, now));
+        assert!(!breaker.record(true, now));
+        assert!(!breaker.record(false, now));
+        assert!(!breaker.record(false, now));
+        assert_eq!(breaker.check(now), Ok(()));
+        assert!(breaker.record(false, now));
+        assert_eq!(breaker.state, BreakerState::Open);
+        assert_eq!(breaker.check(now), Err(cooldown));
+
+        // one trial request after the cooldown
+        let later = now + cooldown;
+        assert_eq!(breaker.check(later), Ok(()));
+        assert_eq!(breaker.state, BreakerState::HalfOpen);
+        assert_eq!(breaker.check(later), Err(HALF_OPEN_WAIT));
+        assert!(breaker.record(false, later));
+        assert_eq!(breaker.check(later), Err(cooldown));
+
+        let later = later + cooldown;
+        assert_eq!(breaker.check(later), Ok(()));
+        assert!(!breaker.record(true, later));
+        assert_eq!(breaker.state, BreakerState::Closed);
+        assert_eq!(breaker.check(later), Ok(()));
+    }
+}
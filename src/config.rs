@@ -0,0 +1,1001 @@
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::alerts::AlertThresholds;
+use crate::backend::BackendConfig;
+use crate::breaker::EsGuard;
+use crate::export::WordFrequencyConfig;
+use crate::index::analyzer::AnalyzerConfig;
+use crate::index::chain::{Chain, DEFAULT_STAGES};
+use crate::index::embedding::{EmbeddingConfig, EmbeddingModel, Quantization};
+use crate::index::force_merge::ForceMergeConfig;
+use crate::index::language::LanguageAllowlist;
+use crate::index::limits::EventLimits;
+use crate::index::opt_out::{parse_opt_out_tags, OptOutTag};
+use crate::index::sampling::Sampling;
+use crate::index::schema::load_template_overrides;
+use crate::index::sinks::{S3Config, SinkConfig};
+use crate::index::sync::SyncConfig;
+use crate::index::tiering::TieringPolicy;
+use crate::index::ttl::IndexTtl;
+use crate::kind_label::KindLabels;
+use crate::link::LinkConfig;
+use crate::namespace::{parse_namespaces, Namespace};
+use crate::search::hybrid::HybridConfig;
+use crate::search::limiter::{IpRateLimiter, QueryLimiter};
+use crate::search::prefix::TagPrefixes;
+use crate::search::ranking::{DecayFunction, RankingConfig};
+use crate::tenant::{parse_tenant_rules, TenantRule};
+
+/// Settings read from environment variables, shared by all subcommands.
+pub struct Config {
+    /// comma-separated URLs of the Elasticsearch nodes; not needed with another backend
+    pub es_url: Option<String>,
+    /// store of the events
+    pub backend: BackendConfig,
+    /// secondary stores the written events are copied to, besides `backend`
+    pub sinks: Vec<SinkConfig>,
+    /// where the events not written to the sinks yet are kept across restarts
+    pub sink_spool_dir: Option<PathBuf>,
+    /// put the pipeline and index templates even if the stored ones are up to date
+    pub force_bootstrap: bool,
+    /// detect languages with the ingest pipeline, which requires the ML lang_ident model
+    pub ingest_pipeline: bool,
+    /// deep-merged over the generated index templates
+    pub index_template_overrides: Option<Value>,
+    /// warm and cold phases of the dated indices; all stay on the hot tier if `None`
+    pub tiering_policy: Option<TieringPolicy>,
+    /// merging of old dated indices; not merged if `None`
+    pub force_merge: Option<ForceMergeConfig>,
+    pub port: u16,
+    /// key of the administrative connection
+    pub api_key: String,
+    pub max_subscriptions: usize,
+    pub max_filters: usize,
+    pub max_filter_complexity: Option<usize>,
+    pub max_results_per_req: Option<usize>,
+    /// shared by all namespaces
+    pub req_limiter: Option<Arc<IpRateLimiter>>,
+    pub accept_published_events: bool,
+    /// shared by all namespaces
+    pub event_limiter: Option<Arc<IpRateLimiter>>,
+    pub ping_interval: Duration,
+    pub index_ttl: IndexTtl,
+    pub index_allow_future_days: u64,
+    pub opt_out_tags: Vec<OptOutTag>,
+    pub created_at_rounding: Option<u64>,
+    pub event_limits: EventLimits,
+    /// stages events go through for indexing
+    pub ingest_chain: Arc<Chain>,
+    pub sampling: Sampling,
+    /// index events carrying a NIP-36 content warning
+    pub index_content_warnings: bool,
+    /// leave events carrying a content warning out of searches without `nsfw:true`
+    pub exclude_content_warnings: bool,
+    pub index_protected_events: bool,
+    /// index replaceable events into one undated index, by author, kind and identifier
+    pub replaceable_index: bool,
+    /// index profiles into one undated index by pubkey, searched by profile searches
+    pub profiles_index: bool,
+    /// whether hashtags and profiles are collected for `GET /complete`
+    pub completion: bool,
+    pub index_queue_size: usize,
+    /// directory of the write-ahead logs of received events; strict acknowledgment if set
+    pub ack_log_dir: Option<PathBuf>,
+    pub index_concurrency: usize,
+    pub analyzer_config: AnalyzerConfig,
+    /// documents of other detected languages are dropped or routed; all are indexed if `None`
+    pub language_allowlist: Option<LanguageAllowlist>,
+    pub tag_prefixes: TagPrefixes,
+    pub embedding_config: Option<EmbeddingConfig>,
+    pub hybrid_search: Option<HybridConfig>,
+    pub suggest_min_hits: usize,
+    /// search strings counted fewer times are left out of query reports; no analytics if `None`
+    pub query_analytics_min_count: Option<u64>,
+    /// days index and delete operations are journaled for; no journal if `None`
+    pub journal_retention_days: Option<u64>,
+    /// hours between checks of each NIP-05 claim; not checked if `None`
+    pub nip05_recheck_hours: Option<u64>,
+    /// seconds between batched deletions of replaced versions; deleted at once if `None`
+    pub replacement_batch_interval: Option<u64>,
+    /// minimum probability of the language detected from search strings; disabled when `None`
+    pub query_language_detection: Option<f64>,
+    pub alert_thresholds: AlertThresholds,
+    pub alert_interval: u64,
+    pub alert_webhook_url: Option<String>,
+    /// hex public key and relays to send alerts to
+    pub alert_dm: Option<(String, String)>,
+    pub follower_boost: Option<f64>,
+    /// weight of the sats zapped in the ranking of pre-EOSE results; also enables zap counting
+    pub zap_boost: Option<f64>,
+    /// weight of the likes in the ranking of pre-EOSE results; also enables reaction counting
+    pub reaction_boost: Option<f64>,
+    /// hours over which trending notes are computed; disabled when `None`
+    pub trending_hours: Option<u64>,
+    /// seconds between recomputations of the trending notes
+    pub trending_interval: u64,
+    /// seconds between writes of the counts, completions and sink events batched in memory
+    pub flush_interval: u64,
+    pub word_frequency_config: Option<WordFrequencyConfig>,
+    pub ranking: Option<RankingConfig>,
+    pub query_limiter: Option<Arc<QueryLimiter>>,
+    /// pre-EOSE results cached per namespace; disabled when `None`
+    pub query_cache_size: Option<usize>,
+    /// seconds for which cached results are served
+    pub query_cache_ttl: u64,
+    /// limits and breaks the writes to Elasticsearch of all namespaces
+    pub es_guard: Option<Arc<EsGuard>>,
+    pub sync: Option<SyncConfig>,
+    pub probe_interval: Option<Duration>,
+    pub probe_timeout: u64,
+    pub namespaces: Vec<Namespace>,
+    /// events also routed to tenant namespaces, by the index alias of the tenant
+    pub tenant_rules: Vec<(String, TenantRule)>,
+    pub links: LinkConfig,
+    pub kind_labels: KindLabels,
+}
+
+impl Config {
+    /// Reads the settings, panicking with a message on invalid values.
+    pub fn from_env() -> Self {
+        let es_url = env::var("ES_URL").ok();
+        let force_bootstrap = env::var("FORCE_BOOTSTRAP")
+            .map(|v| v == "true")
+            .unwrap_or(false);
+        let ingest_pipeline = env::var("INGEST_PIPELINE")
+            .map(|v| v != "false")
+            .unwrap_or(true);
+        let index_template_overrides = env::var("INDEX_TEMPLATE_OVERRIDES").ok().map(|path| {
+            load_template_overrides(&path)
+                .expect("INDEX_TEMPLATE_OVERRIDES is not a JSON or YAML file of an object")
+        });
+        let tiering_policy = env::var("TIERING_POLICY").ok().map(|path| {
+            TieringPolicy::load(&path).expect(
+                "TIERING_POLICY is not valid; expected a JSON or YAML file like {\"warm\": {\"min_age_days\": 7}}",
+            )
+        });
+        let force_merge = env::var("FORCE_MERGE_AFTER_DAYS")
+            .ok()
+            .map(|days| ForceMergeConfig {
+                after_days: days
+                    .parse::<u64>()
+                    .expect("FORCE_MERGE_AFTER_DAYS is not a valid number"),
+                read_only: env::var("FORCE_MERGE_READ_ONLY")
+                    .map(|v| v != "false")
+                    .unwrap_or(true),
+            });
+        let port =
+            env::var("PORT").expect("PORT is not set; set it to the port number to listen on");
+        let port = port
+            .parse::<u16>()
+            .expect("PORT is not a valid port number");
+        let api_key = env::var("API_KEY").expect(
+            "API_KEY is not set; specify the key for the administrative connection to searchnos",
+        );
+        if api_key.is_empty() {
+            panic!("API_KEY must not be empty");
+        }
+        let max_subscriptions = if let Ok(max_subscriptions) = env::var("MAX_SUBSCRIPTIONS") {
+            max_subscriptions
+                .parse::<usize>()
+                .expect("MAX_SUBSCRIPTIONS is not a valid number")
+        } else {
+            8
+        };
+        let max_filters = if let Ok(max_filters) = env::var("MAX_FILTERS") {
+            max_filters
+                .parse::<usize>()
+                .expect("MAX_FILTERS is not a valid number")
+        } else {
+            8
+        };
+        let max_filter_complexity = env::var("MAX_FILTER_COMPLEXITY").ok().map(|v| {
+            v.parse::<usize>()
+                .expect("MAX_FILTER_COMPLEXITY is not a valid number")
+        });
+        let max_results_per_req = env::var("MAX_RESULTS_PER_REQ").ok().map(|v| {
+            v.parse::<usize>()
+                .expect("MAX_RESULTS_PER_REQ is not a valid number")
+        });
+        let req_limiter = env::var("REQ_RATE_PER_IP").ok().map(|rate| {
+            let rate = rate
+                .parse::<f64>()
+                .expect("REQ_RATE_PER_IP is not a valid number");
+            if !(rate > 0.0 && rate.is_finite()) {
+                panic!("REQ_RATE_PER_IP must be positive");
+            }
+            let burst = if let Ok(burst) = env::var("REQ_BURST_PER_IP") {
+                burst
+                    .parse::<f64>()
+                    .expect("REQ_BURST_PER_IP is not a valid number")
+            } else {
+                rate
+            };
+            Arc::new(IpRateLimiter::new(rate, burst))
+        });
+        let accept_published_events = env::var("ACCEPT_PUBLISHED_EVENTS")
+            .map(|v| v == "true")
+            .unwrap_or(false);
+        let event_limiter = env::var("EVENT_RATE_PER_IP").ok().map(|rate| {
+            let rate = rate
+                .parse::<f64>()
+                .expect("EVENT_RATE_PER_IP is not a valid number");
+            if !(rate > 0.0 && rate.is_finite()) {
+                panic!("EVENT_RATE_PER_IP must be positive");
+            }
+            let burst = if let Ok(burst) = env::var("EVENT_BURST_PER_IP") {
+                burst
+                    .parse::<f64>()
+                    .expect("EVENT_BURST_PER_IP is not a valid number")
+            } else {
+                rate
+            };
+            Arc::new(IpRateLimiter::new(rate, burst))
+        });
+        let ping_interval = if let Ok(ping_interval) = env::var("PING_INTERVAL") {
+            ping_interval
+                .parse::<u64>()
+                .expect("PING_INTERVAL is not a valid number")
+        } else {
+            55
+        };
+        let ping_interval = Duration::from_secs(ping_interval);
+        let index_ttl_days: Option<u64> = env::var("INDEX_TTL_DAYS").ok().map(|index_ttl_days| {
+            index_ttl_days
+                .parse::<u64>()
+                .expect("INDEX_TTL_DAYS is not a valid number")
+        });
+        let backend = match env::var("BACKEND").as_deref() {
+            Err(_) | Ok("elasticsearch") => BackendConfig::Elasticsearch,
+            Ok("meilisearch") => BackendConfig::Meilisearch {
+                url: env::var("MEILI_URL")
+                    .expect("MEILI_URL is not set; set it to the URL of meilisearch"),
+                api_key: env::var("MEILI_API_KEY").ok(),
+            },
+            Ok("tantivy") if cfg!(feature = "tantivy") => BackendConfig::Tantivy {
+                dir: PathBuf::from(
+                    env::var("TANTIVY_DIR")
+                        .expect("TANTIVY_DIR is not set; set it to the directory of the indices"),
+                ),
+                commit_interval: Duration::from_millis(
+                    if let Ok(interval) = env::var("TANTIVY_COMMIT_INTERVAL_MS") {
+                        interval
+                            .parse::<u64>()
+                            .expect("TANTIVY_COMMIT_INTERVAL_MS is not a valid number")
+                    } else {
+                        1000
+                    },
+                ),
+            },
+            Ok("tantivy") => panic!("BACKEND=tantivy requires building with the tantivy feature"),
+            Ok("postgres") if cfg!(feature = "postgres") => BackendConfig::Postgres {
+                url: env::var("POSTGRES_URL")
+                    .expect("POSTGRES_URL is not set; set it to the URL of the database"),
+            },
+            Ok("postgres") => {
+                panic!("BACKEND=postgres requires building with the postgres feature")
+            }
+            Ok(backend) => panic!("unknown BACKEND: {}", backend),
+        };
+        if backend == BackendConfig::Elasticsearch && es_url.is_none() {
+            panic!("ES_URL is not set; set it to the URL of elasticsearch");
+        }
+        let sinks = env::var("SINKS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|sink| !sink.is_empty())
+            .map(|sink| match sink {
+                "jsonl" => SinkConfig::Jsonl {
+                    dir: PathBuf::from(env::var("SINK_JSONL_DIR").expect(
+                        "SINK_JSONL_DIR is not set; set it to the directory of the archive",
+                    )),
+                },
+                "s3" => SinkConfig::S3(S3Config {
+                    endpoint: env::var("SINK_S3_ENDPOINT")
+                        .expect("SINK_S3_ENDPOINT is not set; set it to the URL of the S3 service"),
+                    bucket: env::var("SINK_S3_BUCKET").expect("SINK_S3_BUCKET is not set"),
+                    region: env::var("SINK_S3_REGION").unwrap_or("us-east-1".to_string()),
+                    access_key_id: env::var("SINK_S3_ACCESS_KEY_ID")
+                        .expect("SINK_S3_ACCESS_KEY_ID is not set"),
+                    secret_access_key: env::var("SINK_S3_SECRET_ACCESS_KEY")
+                        .expect("SINK_S3_SECRET_ACCESS_KEY is not set"),
+                    prefix: env::var("SINK_S3_PREFIX").unwrap_or_default(),
+                }),
+                sink => panic!("unknown sink in SINKS: {}", sink),
+            })
+            .collect::<Vec<_>>();
+        let sink_spool_dir = env::var("SINK_SPOOL_DIR").ok().map(PathBuf::from);
+        let index_ttl = IndexTtl::parse(
+            index_ttl_days,
+            &env::var("KIND_TTL_DAYS").unwrap_or_default(),
+        )
+        .expect("KIND_TTL_DAYS is not valid; expected e.g. 0=forever,1=7");
+        let index_allow_future_days = 1;
+        // opted-out authors are kept in Elasticsearch
+        let opt_out_tags =
+            parse_opt_out_tags(&env::var("OPT_OUT_TAGS").unwrap_or_else(|_| match backend {
+                BackendConfig::Elasticsearch => "noindex".to_string(),
+                _ => String::new(),
+            }));
+        let created_at_rounding =
+            env::var("ROUND_CREATED_AT")
+                .ok()
+                .map(|unit| match unit.as_str() {
+                    "hour" => 60 * 60,
+                    "day" => 24 * 60 * 60,
+                    _ => panic!("ROUND_CREATED_AT must be hour or day"),
+                });
+        let event_limits = EventLimits {
+            max_content_bytes: env::var("MAX_CONTENT_BYTES").ok().map(|max_content_bytes| {
+                max_content_bytes
+                    .parse::<usize>()
+                    .expect("MAX_CONTENT_BYTES is not a valid number")
+            }),
+            max_tags: env::var("MAX_TAGS").ok().map(|max_tags| {
+                max_tags
+                    .parse::<usize>()
+                    .expect("MAX_TAGS is not a valid number")
+            }),
+        };
+        let ingest_chain = Arc::new(
+            Chain::parse(
+                &env::var("INGEST_STAGES").unwrap_or_else(|_| DEFAULT_STAGES.to_string()),
+            )
+            .expect("INGEST_STAGES is not valid; expected e.g. verify,dedupe,filter,enrich,route,write"),
+        );
+        let sampling = Sampling::parse(
+            &env::var("SAMPLING_RATES").unwrap_or_default(),
+            env::var("SAMPLED_KINDS").ok().as_deref(),
+        )
+        .expect("SAMPLING_RATES or SAMPLED_KINDS is not valid; expected e.g. wss://relay.example.com=0.1 and 1,6");
+        let index_content_warnings = env::var("INDEX_CONTENT_WARNINGS")
+            .map(|v| v != "false")
+            .unwrap_or(true);
+        let exclude_content_warnings = env::var("EXCLUDE_CONTENT_WARNINGS")
+            .map(|v| v != "false")
+            .unwrap_or(true);
+        let index_protected_events = env::var("INDEX_PROTECTED_EVENTS")
+            .map(|v| v == "true")
+            .unwrap_or(false);
+        let replaceable_index = env::var("REPLACEABLE_INDEX")
+            .map(|v| v == "true")
+            .unwrap_or(false);
+        let profiles_index = env::var("PROFILES_INDEX")
+            .map(|v| v == "true")
+            .unwrap_or(false);
+        let completion = env::var("COMPLETION").map(|v| v == "true").unwrap_or(false);
+        let ack_log_dir = env::var("ACK_LOG_DIR").ok().map(PathBuf::from);
+        let index_queue_size = if let Ok(index_queue_size) = env::var("INDEX_QUEUE_SIZE") {
+            index_queue_size
+                .parse::<usize>()
+                .expect("INDEX_QUEUE_SIZE is not a valid number")
+        } else {
+            1024
+        };
+        let index_concurrency = if let Ok(index_concurrency) = env::var("INDEX_CONCURRENCY") {
+            index_concurrency
+                .parse::<usize>()
+                .expect("INDEX_CONCURRENCY is not a valid number")
+        } else {
+            4
+        };
+        let ngram_min_gram = if let Ok(ngram_min_gram) = env::var("NGRAM_MIN_GRAM") {
+            ngram_min_gram
+                .parse::<u32>()
+                .expect("NGRAM_MIN_GRAM is not a valid number")
+        } else {
+            1
+        };
+        let ngram_max_gram = if let Ok(ngram_max_gram) = env::var("NGRAM_MAX_GRAM") {
+            ngram_max_gram
+                .parse::<u32>()
+                .expect("NGRAM_MAX_GRAM is not a valid number")
+        } else {
+            2
+        };
+        let language_analyzers =
+            AnalyzerConfig::parse_languages(&env::var("LANGUAGE_ANALYZERS").unwrap_or_default())
+                .expect(
+                "LANGUAGE_ANALYZERS is not valid; expected e.g. ja:kuromoji,en:stemming,zh:ngram",
+            );
+        let analyzer_config =
+            AnalyzerConfig::new(ngram_min_gram, ngram_max_gram, language_analyzers)
+                .expect("invalid NGRAM_MIN_GRAM/NGRAM_MAX_GRAM");
+        let language_allowlist = env::var("LANGUAGE_ALLOWLIST").ok().map(|languages| {
+            let mode = env::var("LANGUAGE_ALLOWLIST_MODE").unwrap_or_else(|_| "skip".to_string());
+            if !ingest_pipeline {
+                panic!("LANGUAGE_ALLOWLIST requires the ingest pipeline; unset INGEST_PIPELINE=false");
+            }
+            LanguageAllowlist::parse(&languages, &mode)
+                .expect("LANGUAGE_ALLOWLIST is not valid; expected e.g. en,pt, with LANGUAGE_ALLOWLIST_MODE skip or route")
+        });
+        let min_tag_prefix_length =
+            if let Ok(min_tag_prefix_length) = env::var("MIN_TAG_PREFIX_LENGTH") {
+                min_tag_prefix_length
+                    .parse::<usize>()
+                    .expect("MIN_TAG_PREFIX_LENGTH is not a valid number")
+            } else {
+                TagPrefixes::default().min_length
+            };
+        let max_tag_prefixes = if let Ok(max_tag_prefixes) = env::var("MAX_TAG_PREFIXES") {
+            max_tag_prefixes
+                .parse::<usize>()
+                .expect("MAX_TAG_PREFIXES is not a valid number")
+        } else {
+            TagPrefixes::default().max_per_filter
+        };
+        let tag_prefixes = TagPrefixes::parse(
+            &env::var("PREFIX_TAGS").unwrap_or_default(),
+            min_tag_prefix_length,
+            max_tag_prefixes,
+        )
+        .expect(
+            "PREFIX_TAGS is not valid; expected single-letter tag names other than e and p, e.g. r",
+        );
+
+        let embedding_model = match (env::var("EMBEDDING_MODEL_ID"), env::var("EMBEDDING_URL")) {
+            (Ok(_), Ok(_)) => panic!("EMBEDDING_MODEL_ID and EMBEDDING_URL are mutually exclusive"),
+            (Ok(model_id), Err(_)) => Some(EmbeddingModel::Elasticsearch { model_id }),
+            (Err(_), Ok(url)) => Some(EmbeddingModel::Remote { url }),
+            (Err(_), Err(_)) => None,
+        };
+        let embedding_config = embedding_model.map(|model| {
+            let dims = env::var("EMBEDDING_DIMS")
+                .expect(
+                    "EMBEDDING_DIMS is not set; set it to the number of dimensions of the model",
+                )
+                .parse::<usize>()
+                .expect("EMBEDDING_DIMS is not a valid number");
+            let batch_size = if let Ok(batch_size) = env::var("EMBEDDING_BATCH_SIZE") {
+                batch_size
+                    .parse::<usize>()
+                    .expect("EMBEDDING_BATCH_SIZE is not a valid number")
+            } else {
+                32
+            };
+            let threads = if let Ok(threads) = env::var("EMBEDDING_THREADS") {
+                threads
+                    .parse::<usize>()
+                    .expect("EMBEDDING_THREADS is not a valid number")
+            } else {
+                1
+            };
+            let hnsw_m = if let Ok(hnsw_m) = env::var("EMBEDDING_HNSW_M") {
+                hnsw_m
+                    .parse::<usize>()
+                    .expect("EMBEDDING_HNSW_M is not a valid number")
+            } else {
+                16
+            };
+            let hnsw_ef_construction =
+                if let Ok(hnsw_ef_construction) = env::var("EMBEDDING_HNSW_EF_CONSTRUCTION") {
+                    hnsw_ef_construction
+                        .parse::<usize>()
+                        .expect("EMBEDDING_HNSW_EF_CONSTRUCTION is not a valid number")
+                } else {
+                    100
+                };
+            let quantization = if let Ok(quantization) = env::var("EMBEDDING_QUANTIZATION") {
+                quantization
+                    .parse::<Quantization>()
+                    .expect("EMBEDDING_QUANTIZATION must be one of none, int8 or byte")
+            } else {
+                Quantization::None
+            };
+            let memory_budget_bytes = env::var("EMBEDDING_MEMORY_BUDGET_MB").ok().map(|mb| {
+                mb.parse::<u64>()
+                    .expect("EMBEDDING_MEMORY_BUDGET_MB is not a valid number")
+                    * 1024
+                    * 1024
+            });
+            EmbeddingConfig {
+                model,
+                dims,
+                batch_size,
+                threads,
+                poll_interval: Duration::from_secs(10),
+                hnsw_m,
+                hnsw_ef_construction,
+                quantization,
+                memory_budget_bytes,
+                knn: true,
+            }
+        });
+
+        let hybrid_search = if env::var("HYBRID_SEARCH")
+            .map(|v| v == "true")
+            .unwrap_or(false)
+        {
+            if embedding_config.is_none() {
+                panic!("HYBRID_SEARCH requires EMBEDDING_MODEL_ID or EMBEDDING_URL");
+            }
+            let default = HybridConfig::default();
+            let parse_weight = |name: &str, default: f64| {
+                env::var(name)
+                    .map(|v| {
+                        v.parse::<f64>()
+                            .unwrap_or_else(|_| panic!("{} is not a valid number", name))
+                    })
+                    .unwrap_or(default)
+            };
+            Some(HybridConfig {
+                keyword_weight: parse_weight("HYBRID_KEYWORD_WEIGHT", default.keyword_weight),
+                vector_weight: parse_weight("HYBRID_VECTOR_WEIGHT", default.vector_weight),
+                rank_constant: parse_weight("HYBRID_RANK_CONSTANT", default.rank_constant),
+            })
+        } else {
+            None
+        };
+
+        let suggest_min_hits = if let Ok(suggest_min_hits) = env::var("SUGGEST_MIN_HITS") {
+            suggest_min_hits
+                .parse::<usize>()
+                .expect("SUGGEST_MIN_HITS is not a valid number")
+        } else {
+            0
+        };
+        let query_analytics = env::var("QUERY_ANALYTICS")
+            .map(|v| v == "true")
+            .unwrap_or(false);
+        let journal_retention_days = env::var("JOURNAL_RETENTION_DAYS").ok().map(|days| {
+            days.parse::<u64>()
+                .expect("JOURNAL_RETENTION_DAYS is not a valid number")
+        });
+        let nip05_recheck_hours = env::var("NIP05_RECHECK_HOURS").ok().map(|hours| {
+            hours
+                .parse::<u64>()
+                .expect("NIP05_RECHECK_HOURS is not a valid number")
+        });
+        let replacement_batch_interval = env::var("REPLACEMENT_BATCH_INTERVAL").ok().map(|secs| {
+            secs.parse::<u64>()
+                .expect("REPLACEMENT_BATCH_INTERVAL is not a valid number")
+        });
+        let query_analytics_min_count = if !query_analytics {
+            None
+        } else if let Ok(min_count) = env::var("QUERY_ANALYTICS_MIN_COUNT") {
+            Some(
+                min_count
+                    .parse::<u64>()
+                    .expect("QUERY_ANALYTICS_MIN_COUNT is not a valid number"),
+            )
+        } else {
+            Some(5)
+        };
+        let alert_thresholds = AlertThresholds {
+            ingest_lag: env::var("ALERT_INGEST_LAG_MINUTES").ok().map(|minutes| {
+                Duration::from_secs(
+                    minutes
+                        .parse::<u64>()
+                        .expect("ALERT_INGEST_LAG_MINUTES is not a valid number")
+                        * 60,
+                )
+            }),
+            error_rate_percent: env::var("ALERT_ERROR_RATE_PERCENT").ok().map(|percent| {
+                percent
+                    .parse::<f64>()
+                    .expect("ALERT_ERROR_RATE_PERCENT is not a valid number")
+            }),
+            disk_percent: env::var("ALERT_DISK_PERCENT").ok().map(|percent| {
+                percent
+                    .parse::<f64>()
+                    .expect("ALERT_DISK_PERCENT is not a valid number")
+            }),
+        };
+        let alert_interval = if let Ok(alert_interval) = env::var("ALERT_INTERVAL") {
+            alert_interval
+                .parse::<u64>()
+                .expect("ALERT_INTERVAL is not a valid number")
+        } else {
+            60
+        };
+        let alert_webhook_url = env::var("ALERT_WEBHOOK_URL").ok();
+        let alert_dm = match (env::var("ALERT_DM_PUBKEY"), env::var("ALERT_DM_RELAYS")) {
+            (Ok(pubkey), Ok(relays)) => Some((pubkey, relays)),
+            (Ok(_), Err(_)) => {
+                panic!("ALERT_DM_RELAYS is not set; set it to the relays to send alerts to")
+            }
+            _ => None,
+        };
+        let follower_boost = env::var("FOLLOWER_BOOST").ok().map(|weight| {
+            weight
+                .parse::<f64>()
+                .expect("FOLLOWER_BOOST is not a valid number")
+        });
+        let zap_boost = env::var("ZAP_BOOST").ok().map(|weight| {
+            weight
+                .parse::<f64>()
+                .expect("ZAP_BOOST is not a valid number")
+        });
+        let reaction_boost = env::var("REACTION_BOOST").ok().map(|weight| {
+            weight
+                .parse::<f64>()
+                .expect("REACTION_BOOST is not a valid number")
+        });
+        let trending_hours = env::var("TRENDING_HOURS").ok().map(|hours| {
+            hours
+                .parse::<u64>()
+                .expect("TRENDING_HOURS is not a valid number")
+        });
+        if trending_hours.is_some() && reaction_boost.is_none() && zap_boost.is_none() {
+            panic!("TRENDING_HOURS requires REACTION_BOOST or ZAP_BOOST");
+        }
+        let trending_interval = if let Ok(trending_interval) = env::var("TRENDING_INTERVAL") {
+            trending_interval
+                .parse::<u64>()
+                .expect("TRENDING_INTERVAL is not a valid number")
+        } else {
+            300
+        };
+        let flush_interval = if let Ok(flush_interval) = env::var("FLUSH_INTERVAL") {
+            let flush_interval = flush_interval
+                .parse::<u64>()
+                .expect("FLUSH_INTERVAL is not a valid number");
+            if flush_interval == 0 {
+                panic!("FLUSH_INTERVAL must be positive");
+            }
+            flush_interval
+        } else {
+            10
+        };
+        let word_frequency_config = env::var("WORD_FREQUENCY_DIR").ok().map(|dir| {
+            let ngram = if let Ok(ngram) = env::var("WORD_FREQUENCY_NGRAM") {
+                ngram
+                    .parse::<usize>()
+                    .expect("WORD_FREQUENCY_NGRAM is not a valid number")
+            } else {
+                1
+            };
+            let min_count = if let Ok(min_count) = env::var("WORD_FREQUENCY_MIN_COUNT") {
+                min_count
+                    .parse::<u64>()
+                    .expect("WORD_FREQUENCY_MIN_COUNT is not a valid number")
+            } else {
+                10
+            };
+            let min_authors = if let Ok(min_authors) = env::var("WORD_FREQUENCY_MIN_AUTHORS") {
+                min_authors
+                    .parse::<usize>()
+                    .expect("WORD_FREQUENCY_MIN_AUTHORS is not a valid number")
+            } else {
+                5
+            };
+            WordFrequencyConfig {
+                dir: dir.into(),
+                ngram,
+                min_count,
+                min_authors,
+            }
+        });
+        let ranking = RankingConfig {
+            decay: env::var("RANKING_DECAY").ok().map(|decay| {
+                decay
+                    .parse::<DecayFunction>()
+                    .expect("RANKING_DECAY must be gauss or exp")
+            }),
+            decay_scale_days: if let Ok(scale) = env::var("RANKING_DECAY_SCALE_DAYS") {
+                scale
+                    .parse::<u64>()
+                    .expect("RANKING_DECAY_SCALE_DAYS is not a valid number")
+            } else {
+                7
+            },
+            engagement_weight: env::var("RANKING_ENGAGEMENT_WEIGHT").ok().map(|weight| {
+                weight
+                    .parse::<f64>()
+                    .expect("RANKING_ENGAGEMENT_WEIGHT is not a valid number")
+            }),
+        };
+        let ranking = if ranking.is_enabled() {
+            Some(ranking)
+        } else {
+            None
+        };
+        let query_cache_size = env::var("QUERY_CACHE_SIZE").ok().map(|size| {
+            size.parse::<usize>()
+                .expect("QUERY_CACHE_SIZE is not a valid number")
+        });
+        let query_cache_ttl = if let Ok(ttl) = env::var("QUERY_CACHE_TTL") {
+            ttl.parse::<u64>()
+                .expect("QUERY_CACHE_TTL is not a valid number")
+        } else {
+            5
+        };
+        let query_limiter = env::var("ES_QUERY_RATE").ok().map(|rate| {
+            let rate = rate
+                .parse::<f64>()
+                .expect("ES_QUERY_RATE is not a valid number");
+            if !(rate > 0.0 && rate.is_finite()) {
+                panic!("ES_QUERY_RATE must be positive");
+            }
+            let burst = if let Ok(burst) = env::var("ES_QUERY_BURST") {
+                burst
+                    .parse::<f64>()
+                    .expect("ES_QUERY_BURST is not a valid number")
+            } else {
+                rate
+            };
+            let max_queue = if let Ok(max_queue) = env::var("ES_QUERY_MAX_QUEUE") {
+                max_queue
+                    .parse::<usize>()
+                    .expect("ES_QUERY_MAX_QUEUE is not a valid number")
+            } else {
+                100
+            };
+            let max_wait_ms = if let Ok(max_wait_ms) = env::var("ES_QUERY_MAX_WAIT_MS") {
+                max_wait_ms
+                    .parse::<u64>()
+                    .expect("ES_QUERY_MAX_WAIT_MS is not a valid number")
+            } else {
+                5000
+            };
+            Arc::new(QueryLimiter::new(
+                rate,
+                burst,
+                max_queue,
+                Duration::from_millis(max_wait_ms),
+            ))
+        });
+        let es_max_in_flight = env::var("ES_MAX_IN_FLIGHT").ok().map(|v| {
+            v.parse::<usize>()
+                .expect("ES_MAX_IN_FLIGHT is not a valid number")
+        });
+        let es_breaker_failures = env::var("ES_BREAKER_FAILURES").ok().map(|v| {
+            v.parse::<u64>()
+                .expect("ES_BREAKER_FAILURES is not a valid number")
+        });
+        let es_guard = if es_max_in_flight.is_some() || es_breaker_failures.is_some() {
+            let cooldown = if let Ok(cooldown) = env::var("ES_BREAKER_COOLDOWN") {
+                cooldown
+                    .parse::<u64>()
+                    .expect("ES_BREAKER_COOLDOWN is not a valid number")
+            } else {
+                30
+            };
+            Some(Arc::new(EsGuard::new(
+                es_max_in_flight.unwrap_or(usize::MAX),
+                es_breaker_failures,
+                Duration::from_secs(cooldown),
+            )))
+        } else {
+            None
+        };
+        let sync = env::var("SYNC_RELAYS").ok().map(|relays| {
+            let number = |name: &str, default: u64| {
+                env::var(name).map_or(default, |v| {
+                    v.parse::<u64>()
+                        .unwrap_or_else(|_| panic!("{} is not a valid number", name))
+                })
+            };
+            SyncConfig {
+                relays: relays
+                    .split(',')
+                    .map(|relay| relay.trim().to_string())
+                    .filter(|relay| !relay.is_empty())
+                    .collect(),
+                kinds: env::var("SYNC_KINDS")
+                    .unwrap_or_else(|_| "0,1,5,30023".to_string())
+                    .split(',')
+                    .map(|kind| kind.trim().parse::<u64>())
+                    .collect::<Result<_, _>>()
+                    .expect("SYNC_KINDS is not valid; expected comma-separated kind numbers"),
+                interval: Duration::from_secs(number("SYNC_INTERVAL", 60 * 60).max(60)),
+                window: Duration::from_secs(number("SYNC_WINDOW", 24 * 60 * 60)),
+                page_size: number("SYNC_PAGE_SIZE", 500) as usize,
+                timeout: Duration::from_secs(number("SYNC_TIMEOUT", 10)),
+            }
+        });
+        let probe_interval = env::var("PROBE_INTERVAL").ok().map(|interval| {
+            Duration::from_secs(
+                interval
+                    .parse::<u64>()
+                    .expect("PROBE_INTERVAL is not a valid number"),
+            )
+        });
+        let probe_timeout = if let Ok(probe_timeout) = env::var("PROBE_TIMEOUT") {
+            probe_timeout
+                .parse::<u64>()
+                .expect("PROBE_TIMEOUT is not a valid number")
+        } else {
+            60
+        };
+        let namespaces = parse_namespaces(&env::var("NAMESPACES").unwrap_or_default())
+            .expect("NAMESPACES is not valid; expected e.g. main,test:3001");
+        let tenant_rules = match env::var("TENANT_RULES") {
+            Ok(path) => parse_tenant_rules(
+                &std::fs::read_to_string(&path).expect("TENANT_RULES cannot be read"),
+                &namespaces,
+            )
+            .expect("TENANT_RULES is not valid; expected rules by namespace name"),
+            Err(_) => vec![],
+        };
+
+        let links = LinkConfig {
+            base_url: env::var("LINK_BASE_URL").ok(),
+        };
+        let kind_labels = KindLabels::parse(&env::var("KIND_LABELS").unwrap_or_default())
+            .expect("KIND_LABELS is not valid; expected e.g. 30023:article,9802:highlight");
+
+        let query_language_detection = if env::var("QUERY_LANGUAGE_DETECTION")
+            .map(|v| v == "true")
+            .unwrap_or(false)
+        {
+            let min_probability =
+                if let Ok(min_probability) = env::var("QUERY_LANGUAGE_MIN_PROBABILITY") {
+                    min_probability
+                        .parse::<f64>()
+                        .expect("QUERY_LANGUAGE_MIN_PROBABILITY is not a valid number")
+                } else {
+                    0.8
+                };
+            if !ingest_pipeline {
+                panic!("QUERY_LANGUAGE_DETECTION requires the model of the ingest pipeline; unset INGEST_PIPELINE=false");
+            }
+            Some(min_probability)
+        } else {
+            None
+        };
+
+        // these keep or search Elasticsearch indices, which backends do not need
+        if backend != BackendConfig::Elasticsearch {
+            let refused = [
+                ("TRENDING_HOURS", trending_hours.is_some()),
+                (
+                    "EMBEDDING_MODEL_ID and EMBEDDING_URL",
+                    embedding_config.is_some(),
+                ),
+                ("HYBRID_SEARCH", hybrid_search.is_some()),
+                (
+                    "INDEX_TEMPLATE_OVERRIDES",
+                    index_template_overrides.is_some(),
+                ),
+                ("TIERING_POLICY", tiering_policy.is_some()),
+                ("FORCE_MERGE_AFTER_DAYS", force_merge.is_some()),
+                ("OPT_OUT_TAGS", !opt_out_tags.is_empty()),
+                ("REPLACEABLE_INDEX", replaceable_index),
+                ("PROFILES_INDEX", profiles_index),
+                ("COMPLETION", completion),
+                ("LANGUAGE_ALLOWLIST", language_allowlist.is_some()),
+                ("JOURNAL_RETENTION_DAYS", journal_retention_days.is_some()),
+                ("NIP05_RECHECK_HOURS", nip05_recheck_hours.is_some()),
+                (
+                    "REPLACEMENT_BATCH_INTERVAL",
+                    replacement_batch_interval.is_some(),
+                ),
+                (
+                    "QUERY_LANGUAGE_DETECTION",
+                    query_language_detection.is_some(),
+                ),
+                ("FOLLOWER_BOOST", follower_boost.is_some()),
+                ("ZAP_BOOST", zap_boost.is_some()),
+                ("REACTION_BOOST", reaction_boost.is_some()),
+                (
+                    "RANKING_DECAY and RANKING_ENGAGEMENT_WEIGHT",
+                    ranking.is_some(),
+                ),
+                ("SYNC_RELAYS", sync.is_some()),
+                ("WORD_FREQUENCY_DIR", word_frequency_config.is_some()),
+                (
+                    "ALERT_DISK_PERCENT",
+                    alert_thresholds.disk_percent.is_some(),
+                ),
+                ("SUGGEST_MIN_HITS", suggest_min_hits > 0),
+            ];
+            for (name, enabled) in refused {
+                if enabled {
+                    panic!("{} requires BACKEND=elasticsearch", name);
+                }
+            }
+        }
+
+        Config {
+            es_url,
+            backend,
+            sinks,
+            sink_spool_dir,
+            force_bootstrap,
+            ingest_pipeline,
+            index_template_overrides,
+            tiering_policy,
+            force_merge,
+            port,
+            api_key,
+            max_subscriptions,
+            max_filters,
+            max_filter_complexity,
+            max_results_per_req,
+            req_limiter,
+            accept_published_events,
+            event_limiter,
+            ping_interval,
+            index_ttl,
+            index_allow_future_days,
+            opt_out_tags,
+            created_at_rounding,
+            event_limits,
+            ingest_chain,
+            sampling,
+            index_content_warnings,
+            exclude_content_warnings,
+            index_protected_events,
+            replaceable_index,
+            profiles_index,
+            completion,
+            index_queue_size,
+            ack_log_dir,
+            index_concurrency,
+            analyzer_config,
+            language_allowlist,
+            tag_prefixes,
+            embedding_config,
+            hybrid_search,
+            suggest_min_hits,
+            query_analytics_min_count,
+            journal_retention_days,
+            nip05_recheck_hours,
+            replacement_batch_interval,
+            query_language_detection,
+            alert_thresholds,
+            alert_interval,
+            alert_webhook_url,
+            alert_dm,
+            follower_boost,
+            zap_boost,
+            reaction_boost,
+            trending_hours,
+            trending_interval,
+            flush_interval,
+            word_frequency_config,
+            ranking,
+            query_limiter,
+            query_cache_size,
+            query_cache_ttl,
+            es_guard,
+            sync,
+            probe_interval,
+            probe_timeout,
+            namespaces,
+            tenant_rules,
+            links,
+            kind_labels,
+        }
+    }
+
+    /// Prefixes of the event indices; without namespaces, a single unnamed namespace uses the
+    /// "nostr" indices.
+    pub fn index_name_prefixes(&self) -> Vec<String> {
+        if self.namespaces.is_empty() {
+            vec!["nostr".to_string()]
+        } else {
+            self.namespaces
+                .iter()
+                .map(|ns| ns.index_prefix("nostr"))
+                .collect()
+        }
+    }
+}
+
+/// Parses an age like `7d` (or `7`) into days.
+pub fn parse_days(s: &str) -> Result<u64, String> {
+    s.strip_suffix('d')
+        .unwrap_or(s)
+        .parse::<u64>()
+        .map_err(|_| format!("invalid number of days: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::parse_days;
+
+    #[test]
+    fn test_parse_days() {
+        assert_eq!(parse_days("7d"), Ok(7));
+        assert_eq!(parse_days("30"), Ok(30));
+        assert!(parse_days("7h").is_err());
+        assert!(parse_days("").is_err());
+    }
+}
@@ -0,0 +1,133 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use elasticsearch::http::transport::{Connection, ConnectionPool};
+use elasticsearch::http::Url;
+use tokio::task::JoinHandle;
+
+/// Round-robin pool over several Elasticsearch nodes that skips nodes failing health checks.
+///
+/// Falls back to plain round robin when every node is marked unhealthy, so requests keep
+/// being attempted until a node comes back.
+#[derive(Debug, Clone)]
+pub struct HealthAwareConnectionPool {
+    urls: Arc<Vec<Url>>,
+    connections: Arc<Vec<Connection>>,
+    healthy: Arc<Vec<AtomicBool>>,
+    counter: Arc<AtomicUsize>,
+}
+
+impl HealthAwareConnectionPool {
+    pub fn new(urls: Vec<Url>) -> Self {
+        assert!(!urls.is_empty(), "at least one url is required");
+        let connections = urls
+            .iter()
+            .map(|url| Connection::new(url.clone()))
+            .collect();
+        let healthy = urls.iter().map(|_| AtomicBool::new(true)).collect();
+        HealthAwareConnectionPool {
+            urls: Arc::new(urls),
+            connections: Arc::new(connections),
+            healthy: Arc::new(healthy),
+            counter: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn next_index(&self) -> usize {
+        let n = self.connections.len();
+        let start = self.counter.fetch_add(1, Ordering::Relaxed);
+        (0..n)
+            .map(|i| (start + i) % n)
+            .find(|i| self.healthy[*i].load(Ordering::Relaxed))
+            .unwrap_or(start % n)
+    }
+
+    fn set_healthy(&self, index: usize, healthy: bool) {
+        let was_healthy = self.healthy[index].swap(healthy, Ordering::Relaxed);
+        if was_healthy != healthy {
+            let url = self.urls[index].host_str().unwrap_or_default().to_string();
+            if healthy {
+                log::info!("elasticsearch node {} is back", url);
+            } else {
+                log::warn!("elasticsearch node {} is unhealthy", url);
+            }
+        }
+    }
+
+    pub fn num_healthy(&self) -> usize {
+        self.healthy
+            .iter()
+            .filter(|h| h.load(Ordering::Relaxed))
+            .count()
+    }
+
+    /// Periodically pings every node and updates its health.
+    pub fn spawn_health_checker(&self, interval: Duration) -> JoinHandle<()> {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_secs(5))
+                .no_proxy()
+                .build()
+                .expect("failed to build http client");
+            loop {
+                for (index, url) in pool.urls.iter().enumerate() {
+                    let res = client.get(url.clone()).send().await;
+                    let healthy = match res {
+                        Ok(res) => res.status().is_success(),
+                        Err(_) => false,
+                    };
+                    pool.set_healthy(index, healthy);
+                }
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
+}
+
+impl ConnectionPool for HealthAwareConnectionPool {
+    fn next(&self) -> &Connection {
+        &self.connections[self.next_index()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use elasticsearch::http::Url;
+
+    use crate::connection_pool::HealthAwareConnectionPool;
+
+    fn pool(n: usize) -> HealthAwareConnectionPool {
+        HealthAwareConnectionPool::new(
+            (0..n)
+                .map(|i| Url::parse(&format!("http://es{}:9200", i)).unwrap())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let pool = pool(3);
+        let indices = (0..6).map(|_| pool.next_index()).collect::<Vec<_>>();
+        assert_eq!(indices, vec![0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn test_skip_unhealthy() {
+        let pool = pool(3);
+        pool.set_healthy(1, false);
+        assert_eq!(pool.num_healthy(), 2);
+        let indices = (0..4).map(|_| pool.next_index()).collect::<Vec<_>>();
+        assert_eq!(indices, vec![0, 2, 2, 0]);
+
+        // all unhealthy: plain round robin
+        pool.set_healthy(0, false);
+        pool.set_healthy(2, false);
+        let first = pool.next_index();
+        assert_eq!(pool.next_index(), (first + 1) % 3);
+
+        pool.set_healthy(1, true);
+        assert_eq!(pool.next_index(), 1);
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{NaiveDate, TimeZone, Utc};
+use elasticsearch::{ScrollParts, SearchParts};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+
+/// tokens longer than this are likely identifiers or garbage
+const MAX_TOKEN_LEN: usize = 30;
+
+#[derive(Debug, Clone)]
+pub struct WordFrequencyConfig {
+    pub dir: PathBuf,
+    /// number of words per n-gram; 1 counts single words
+    pub ngram: usize,
+    /// n-grams occurring fewer times in a day are not exported
+    pub min_count: u64,
+    /// n-grams used by fewer distinct authors in a day are not exported
+    pub min_authors: usize,
+}
+
+fn is_identifier(token: &str) -> bool {
+    [
+        "npub1",
+        "nsec1",
+        "note1",
+        "nprofile1",
+        "nevent1",
+        "naddr1",
+        "lnbc",
+    ]
+    .iter()
+    .any(|prefix| token.starts_with(prefix))
+        || token.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Lowercase word n-grams of `text`, leaving out URLs, nostr identifiers and numbers.
+fn ngrams(text: &str, n: usize) -> Vec<String> {
+    let words = text
+        .split_whitespace()
+        .filter(|word| !word.contains("://") && !word.starts_with("nostr:"))
+        .flat_map(|word| word.split(|c: char| !c.is_alphanumeric()))
+        .map(|word| word.to_lowercase())
+        .filter(|word| !word.is_empty() && word.chars().count() <= MAX_TOKEN_LEN)
+        .filter(|word| !is_identifier(word))
+        .collect::<Vec<_>>();
+    if n == 0 || words.len() < n {
+        return vec![];
+    }
+    words.windows(n).map(|w| w.join(" ")).collect()
+}
+
+/// Counts of n-grams per language, with the distinct authors using them.
+#[derive(Debug, Default)]
+struct FrequencyTable {
+    /// language -> n-gram -> (count, hashed authors)
+    counts: HashMap<String, HashMap<String, (u64, HashSet<u64>)>>,
+}
+
+impl FrequencyTable {
+    fn add(&mut self, language: &str, pubkey: &str, text: &str, n: usize) {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        pubkey.hash(&mut hasher);
+        let author = hasher.finish();
+        let table = self.counts.entry(language.to_string()).or_default();
+        for ngram in ngrams(text, n) {
+            let (count, authors) = table.entry(ngram).or_default();
+            *count += 1;
+            authors.insert(author);
+        }
+    }
+
+    /// N-grams above both thresholds per language, most frequent first.
+    fn export(&self, min_count: u64, min_authors: usize) -> BTreeMap<String, Vec<(String, u64)>> {
+        self.counts
+            .iter()
+            .map(|(language, table)| {
+                let mut rows = table
+                    .iter()
+                    .filter(|(_, (count, authors))| {
+                        *count >= min_count && authors.len() >= min_authors
+                    })
+                    .map(|(ngram, (count, _))| (ngram.clone(), *count))
+                    .collect::<Vec<_>>();
+                rows.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+                (language.clone(), rows)
+            })
+            .filter(|(_, rows)| !rows.is_empty())
+            .collect()
+    }
+}
+
+/// Writes `<date>-<language>.tsv` files with the n-gram frequencies of the events created on `date`.
+pub async fn export_word_frequencies(
+    state: &AppState,
+    config: &WordFrequencyConfig,
+    date: NaiveDate,
+) -> anyhow::Result<()> {
+    let index_name = format!("{}-{}", state.index_name_prefix, date.format("%Y.%m.%d"));
+    let res = state
+        .es_client
+        .search(SearchParts::Index(&[index_name.as_str()]))
+        .scroll("1m")
+        .size(1000)
+        .body(json!({
+            "_source": ["text", "language", "event.pubkey"],
+            "query": { "match_all": {} }
+        }))
+        .send()
+        .await?;
+    if res.status_code().as_u16() == 404 {
+        log::info!("no index for {}; nothing to export", date);
+        return Ok(());
+    }
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!("failed to search: {}", res.status_code()));
+    }
+    let mut body = res.json::<Value>().await?;
+
+    let mut table = FrequencyTable::default();
+    loop {
+        let hits = body["hits"]["hits"].as_array().cloned().unwrap_or_default();
+        if hits.is_empty() {
+            break;
+        }
+        for hit in hits {
+            let source = &hit["_source"];
+            table.add(
+                source["language"].as_str().unwrap_or("unknown"),
+                source["event"]["pubkey"].as_str().unwrap_or_default(),
+                source["text"].as_str().unwrap_or_default(),
+                config.ngram,
+            );
+        }
+        let scroll_id = body["_scroll_id"].as_str().unwrap_or_default().to_string();
+        let res = state
+            .es_client
+            .scroll(ScrollParts::None)
+            .body(json!({ "scroll": "1m", "scroll_id": scroll_id }))
+            .send()
+            .await?;
+        if !res.status_code().is_success() {
+            return Err(anyhow::anyhow!("failed to scroll: {}", res.status_code()));
+        }
+        body = res.json::<Value>().await?;
+    }
+
+    tokio::fs::create_dir_all(&config.dir).await?;
+    for (language, rows) in table.export(config.min_count, config.min_authors) {
+        let path = config.dir.join(format!("{}-{}.tsv", date, language));
+        let content = rows
+            .iter()
+            .map(|(ngram, count)| format!("{}\t{}\n", ngram, count))
+            .collect::<String>();
+        tokio::fs::write(&path, content).await?;
+        log::info!("exported {} n-gram(s) to {}", rows.len(), path.display());
+    }
+    Ok(())
+}
+
+/// Exports the frequencies of the previous day shortly after each midnight (UTC).
+pub fn spawn_word_frequency_exporter(
+    state: Arc<AppState>,
+    config: WordFrequencyConfig,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let now = Utc::now();
+            let next_day = (now + chrono::Duration::days(1)).date_naive();
+            let start_of_next_day = Utc.from_utc_datetime(&next_day.and_hms_opt(0, 10, 0).unwrap());
+            let wait = (start_of_next_day - now)
+                .to_std()
+                .unwrap_or(Duration::from_secs(60));
+            tokio::time::sleep(wait).await;
+
+            let date = next_day - chrono::Duration::days(1);
+            if let Err(e) = export_word_frequencies(&state, &config, date).await {
+                log::error!("failed to export word frequencies for {}: {}", date, e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::export::{ngrams, FrequencyTable};
+
+    #[test]
+    fn test_ngrams() {
+        assert_eq!(
+            ngrams(
+                "Hello, World! see https://example.com nostr:npub1xyz 2023",
+                1
+            ),
+            vec!["hello", "world", "see"]
+        );
+        assert_eq!(
+            ngrams("good morning nostr", 2),
+            vec!["good morning", "morning nostr"]
+        );
+        assert!(ngrams("hello", 2).is_empty());
+    }
+
+    #[test]
+    fn test_export_thresholds() {
+        let mut table = FrequencyTable::default();
+        table.add("en", "alice", "gm gm nostr", 1);
+        table.add("en", "bob", "gm", 1);
+        table.add("ja", "alice", "gm", 1);
+
+        let exported = table.export(2, 1);
+        assert_eq!(exported.len(), 1);
+        assert_eq!(exported["en"], vec![("gm".to_string(), 3)]);
+
+        // "gm" in en is used by two authors
+        let exported = table.export(1, 2);
+        assert_eq!(exported["en"], vec![("gm".to_string(), 3)]);
+        assert!(table.export(1, 3).is_empty());
+    }
+}
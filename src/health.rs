@@ -0,0 +1,84 @@
+use std::sync::atomic::Ordering;
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::app_state::AppState;
+
+/// An instance with queued events that indexed nothing for this long is considered wedged.
+const MAX_STALL_SECS: u64 = 300;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HealthReport {
+    /// `None` with a backend, which does not use Elasticsearch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub elasticsearch: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backend: Option<bool>,
+    pub admin_connections: u64,
+    pub index_queue_depth: usize,
+    /// `None` until the first event is indexed
+    pub seconds_since_last_indexed: Option<u64>,
+}
+
+impl HealthReport {
+    pub async fn collect(state: &AppState) -> Self {
+        let (elasticsearch, backend) = match &state.backend {
+            Some(backend) => (None, Some(backend.is_reachable().await)),
+            None => match state.es_client.ping().send().await {
+                Ok(res) => (Some(res.status_code().is_success()), None),
+                Err(_) => (Some(false), None),
+            },
+        };
+        let last_indexed_at = state.metrics.last_indexed_at.load(Ordering::Relaxed);
+        let now = chrono::Utc::now().timestamp() as u64;
+        HealthReport {
+            elasticsearch,
+            backend,
+            admin_connections: state.metrics.admin_connections.load(Ordering::Relaxed),
+            index_queue_depth: state.index_queue.depth(),
+            seconds_since_last_indexed: if last_indexed_at == 0 {
+                None
+            } else {
+                Some(now.saturating_sub(last_indexed_at))
+            },
+        }
+    }
+
+    /// Liveness: false when events are queued but indexing has stalled.
+    pub fn is_live(&self) -> bool {
+        match self.seconds_since_last_indexed {
+            Some(secs) => self.index_queue_depth == 0 || secs <= MAX_STALL_SECS,
+            None => true,
+        }
+    }
+
+    /// Readiness: searches can be served.
+    pub fn is_ready(&self) -> bool {
+        self.elasticsearch.unwrap_or(true) && self.backend.unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::health::HealthReport;
+
+    fn report(depth: usize, secs: Option<u64>) -> HealthReport {
+        HealthReport {
+            elasticsearch: Some(true),
+            backend: None,
+            admin_connections: 1,
+            index_queue_depth: depth,
+            seconds_since_last_indexed: secs,
+        }
+    }
+
+    #[test]
+    fn test_is_live() {
+        assert!(report(0, None).is_live());
+        assert!(report(10, None).is_live());
+        assert!(report(0, Some(3600)).is_live());
+        assert!(report(10, Some(10)).is_live());
+        assert!(!report(10, Some(3600)).is_live());
+    }
+}
@@ -1,5 +1,51 @@
+pub mod ack;
+pub mod analyzer;
+pub mod batch;
+pub mod bootstrap;
+pub mod chain;
+pub mod classified;
+pub mod completion;
+pub mod content_warning;
+pub mod dead_letter;
+pub mod delegation;
+pub mod deletion;
+pub mod embedding;
+pub mod engagement;
+#[cfg(test)]
+mod fixtures;
+pub mod followers;
+pub mod force_merge;
+pub mod geo;
 pub mod handlers;
 pub mod indexes;
+pub mod journal;
+pub mod language;
+pub mod limits;
+pub mod lock;
+pub mod media;
+pub mod mentions;
+pub mod nip05;
+pub mod occurrence;
+pub mod opt_out;
+pub mod profile;
+pub mod protected;
 pub mod purge;
+pub mod queue;
+pub mod reactions;
+pub mod reconcile;
+pub mod refresh;
+pub mod refs;
+pub mod reindex;
+pub mod replacements;
 pub mod schema;
+pub mod seen_on;
+pub mod sinks;
+pub mod stats;
+pub mod sync;
 pub mod text;
+pub mod tiering;
+pub mod upstream;
+pub mod urls;
+pub mod zaps;
+
+pub use searchnos_common::{sampling, ttl};
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use nostr_sdk::Event;
+
+/// acknowledgments after which the log is rewritten with the pending events only
+const COMPACT_EVERY: usize = 10_000;
+
+/// Write-ahead log of the events received for indexing, for at-least-once indexing.
+///
+/// Each event is appended as `+<event>` before it is queued and acknowledged with `-<id>`
+/// once it has been handled. Events not acknowledged before a crash are indexed again on
+/// the next start.
+///
+/// Appends and compactions are synced off the runtime threads, and appends made while a sync
+/// runs are synced together by the next one.
+#[derive(Debug)]
+pub struct AckLog {
+    path: PathBuf,
+    state: tokio::sync::Mutex<AckLogState>,
+    /// number of the appends synced
+    synced: tokio::sync::Mutex<u64>,
+    /// number of the pending events, readable without waiting for a compaction
+    pending: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct AckLogState {
+    file: File,
+    /// pending events by id, with their position in the log
+    pending: HashMap<String, (u64, Event)>,
+    acked: usize,
+    /// number of the appends written
+    appended: u64,
+    /// position of the next append
+    next_position: u64,
+}
+
+/// Events appended but not acknowledged, in the order they were appended.
+fn pending_events(content: &str) -> Vec<Event> {
+    let mut events: Vec<Event> = vec![];
+    for line in content.lines() {
+        if let Some(json) = line.strip_prefix('+') {
+            // a partially written last line is dropped
+            if let Ok(event) = serde_json::from_str::<Event>(json) {
+                events.push(event);
+            }
+        } else if let Some(id) = line.strip_prefix('-') {
+            events.retain(|event| event.id.to_hex() != id);
+        }
+    }
+    events
+}
+
+fn write_pending(path: &Path, events: &[Event]) -> anyhow::Result<File> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    for event in events {
+        writeln!(file, "+{}", event.as_json())?;
+    }
+    file.sync_data()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+impl AckLog {
+    /// Opens the log of `index_alias_name` in `dir`; returns it with the unacknowledged events,
+    /// which are to be queued again.
+    pub fn open(dir: &Path, index_alias_name: &str) -> anyhow::Result<(Self, Vec<Event>)> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.wal", index_alias_name));
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let events = pending_events(&content);
+        let file = write_pending(&path, &events)?;
+        let pending = events
+            .iter()
+            .enumerate()
+            .map(|(position, event)| (event.id.to_hex(), (position as u64, event.clone())))
+            .collect();
+        let log = AckLog {
+            path,
+            state: tokio::sync::Mutex::new(AckLogState {
+                file,
+                pending,
+                acked: 0,
+                appended: 0,
+                next_position: events.len() as u64,
+            }),
+            synced: tokio::sync::Mutex::new(0),
+            pending: AtomicUsize::new(events.len()),
+        };
+        Ok((log, events))
+    }
+
+    /// Records an event durably before it is queued.
+    pub async fn append(&self, event: &Event) -> anyhow::Result<()> {
+        let appended = {
+            let mut state = self.state.lock().await;
+            writeln!(state.file, "+{}", event.as_json())?;
+            let position = state.next_position;
+            state.next_position += 1;
+            state
+                .pending
+                .insert(event.id.to_hex(), (position, event.clone()));
+            self.pending.store(state.pending.len(), Ordering::Relaxed);
+            state.appended += 1;
+            state.appended
+        };
+        let mut synced = self.synced.lock().await;
+        // synced meanwhile along with the appends before
+        if *synced >= appended {
+            return Ok(());
+        }
+        let (file, appended) = {
+            let state = self.state.lock().await;
+            (state.file.try_clone()?, state.appended)
+        };
+        tokio::task::spawn_blocking(move || file.sync_data()).await??;
+        *synced = appended;
+        Ok(())
+    }
+
+    /// Marks an event as handled; events not appended to the log are ignored.
+    pub async fn ack(&self, event: &Event) -> anyhow::Result<()> {
+        let mut state = self.state.lock().await;
+        let id = event.id.to_hex();
+        if state.pending.remove(&id).is_none() {
+            return Ok(());
+        }
+        self.pending.store(state.pending.len(), Ordering::Relaxed);
+        // not synced; a lost acknowledgment only indexes the event once more
+        writeln!(state.file, "-{}", id)?;
+        state.acked += 1;
+        if state.acked >= COMPACT_EVERY {
+            self.compact(&mut state).await?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the log with the pending events only, in the order they were appended; appends
+    /// wait for it.
+    async fn compact(&self, state: &mut AckLogState) -> anyhow::Result<()> {
+        let mut events = state.pending.values().cloned().collect::<Vec<_>>();
+        events.sort_by_key(|(position, _)| *position);
+        let events = events
+            .into_iter()
+            .map(|(_, event)| event)
+            .collect::<Vec<_>>();
+        let path = self.path.clone();
+        state.file = tokio::task::spawn_blocking(move || write_pending(&path, &events)).await??;
+        state.acked = 0;
+        Ok(())
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind};
+
+    use crate::index::ack::{pending_events, AckLog};
+
+    #[test]
+    fn test_pending_events() {
+        let keys = Keys::generate();
+        let events = (0..3)
+            .map(|i| {
+                EventBuilder::new(Kind::TextNote, format!("note {}", i), &[])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let content = format!(
+            "+{}\n+{}\n-{}\n+{}\n+{{\"id\":",
+            events[0].as_json(),
+            events[1].as_json(),
+            events[0].id.to_hex(),
+            events[2].as_json()
+        );
+        let pending = pending_events(&content);
+        assert_eq!(
+            pending.iter().map(|e| e.id).collect::<Vec<_>>(),
+            vec![events[1].id, events[2].id]
+        );
+        assert!(pending_events("").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_append() {
+        let dir = std::env::temp_dir().join(format!("searchnos-ack-{}", std::process::id()));
+        let keys = Keys::generate();
+        let events = (0..3)
+            .map(|i| {
+                EventBuilder::new(Kind::TextNote, format!("note {}", i), &[])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let (log, pending) = AckLog::open(&dir, "nostr").unwrap();
+        assert!(pending.is_empty());
+        let (a, b, c) = tokio::join!(
+            log.append(&events[0]),
+            log.append(&events[1]),
+            log.append(&events[2])
+        );
+        a.unwrap();
+        b.unwrap();
+        c.unwrap();
+        assert_eq!(*log.synced.lock().await, 3);
+        log.ack(&events[1]).await.unwrap();
+        drop(log);
+
+        let (log, pending) = AckLog::open(&dir, "nostr").unwrap();
+        assert_eq!(
+            pending.iter().map(|e| e.id).collect::<Vec<_>>(),
+            vec![events[0].id, events[2].id]
+        );
+        assert_eq!(log.pending(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compact() {
+        let dir = std::env::temp_dir().join(format!("searchnos-compact-{}", std::process::id()));
+        let keys = Keys::generate();
+        let events = (0..20)
+            .map(|i| {
+                EventBuilder::new(Kind::TextNote, format!("note {}", i), &[])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let (log, _) = AckLog::open(&dir, "nostr").unwrap();
+        for event in &events {
+            log.append(event).await.unwrap();
+        }
+        log.ack(&events[3]).await.unwrap();
+        log.compact(&mut *log.state.lock().await).await.unwrap();
+        drop(log);
+
+        // kept in append order, which the ids do not follow
+        let (_, pending) = AckLog::open(&dir, "nostr").unwrap();
+        let mut expected = events.iter().map(|e| e.id).collect::<Vec<_>>();
+        expected.remove(3);
+        assert_eq!(pending.iter().map(|e| e.id).collect::<Vec<_>>(), expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::BTreeMap;
+
+use serde_json::{json, Map, Value};
+
+/// Analyzer applied to a language-specific field `texts.<language>`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnalyzerPreset {
+    /// n-gram tokenization; suitable for CJK languages
+    Ngram,
+    /// standard tokenizer followed by a stemmer for the given language (e.g. "english")
+    Stemming(String),
+    /// morphological analysis for Japanese; requires the analysis-kuromoji plugin
+    Kuromoji,
+}
+
+fn stemmer_language(language_code: &str) -> Option<&'static str> {
+    match language_code {
+        "da" => Some("danish"),
+        "de" => Some("german"),
+        "en" => Some("english"),
+        "es" => Some("spanish"),
+        "fi" => Some("finnish"),
+        "fr" => Some("french"),
+        "it" => Some("italian"),
+        "nl" => Some("dutch"),
+        "no" => Some("norwegian"),
+        "pt" => Some("portuguese"),
+        "ru" => Some("russian"),
+        "sv" => Some("swedish"),
+        _ => None,
+    }
+}
+
+impl AnalyzerPreset {
+    pub fn parse(language_code: &str, preset: &str) -> anyhow::Result<Self> {
+        match preset {
+            "ngram" => Ok(AnalyzerPreset::Ngram),
+            "kuromoji" => Ok(AnalyzerPreset::Kuromoji),
+            "stemming" => stemmer_language(language_code)
+                .map(|l| AnalyzerPreset::Stemming(l.to_string()))
+                .ok_or_else(|| anyhow::anyhow!("no stemmer for language: {}", language_code)),
+            _ => Err(anyhow::anyhow!("unknown analyzer preset: {}", preset)),
+        }
+    }
+
+    fn analyzer_name(&self) -> String {
+        match self {
+            AnalyzerPreset::Ngram => "ngram_analyzer".to_string(),
+            AnalyzerPreset::Stemming(language) => format!("{}_analyzer", language),
+            AnalyzerPreset::Kuromoji => "kuromoji_analyzer".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnalyzerConfig {
+    pub min_gram: u32,
+    pub max_gram: u32,
+    /// language code (as detected by the ingest pipeline) -> preset
+    pub languages: BTreeMap<String, AnalyzerPreset>,
+}
+
+impl Default for AnalyzerConfig {
+    fn default() -> Self {
+        AnalyzerConfig {
+            min_gram: 1,
+            max_gram: 2,
+            languages: BTreeMap::new(),
+        }
+    }
+}
+
+impl AnalyzerConfig {
+    pub fn new(
+        min_gram: u32,
+        max_gram: u32,
+        languages: BTreeMap<String, AnalyzerPreset>,
+    ) -> anyhow::Result<Self> {
+        if min_gram == 0 {
+            return Err(anyhow::anyhow!("min_gram must be greater than 0"));
+        }
+        if max_gram < min_gram {
+            return Err(anyhow::anyhow!(
+                "max_gram ({}) must not be less than min_gram ({})",
+                max_gram,
+                min_gram
+            ));
+        }
+        Ok(AnalyzerConfig {
+            min_gram,
+            max_gram,
+            languages,
+        })
+    }
+
+    /// Parses a comma-separated list of `language:preset` pairs, e.g. `ja:kuromoji,en:stemming`.
+    pub fn parse_languages(src: &str) -> anyhow::Result<BTreeMap<String, AnalyzerPreset>> {
+        let mut languages = BTreeMap::new();
+        for item in src.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            let (language_code, preset) = item
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("invalid analyzer spec: {}", item))?;
+            let language_code = language_code.trim();
+            languages.insert(
+                language_code.to_string(),
+                AnalyzerPreset::parse(language_code, preset.trim())?,
+            );
+        }
+        Ok(languages)
+    }
+
+    pub fn max_ngram_diff(&self) -> u32 {
+        std::cmp::max(self.max_gram - self.min_gram, 1)
+    }
+
+    /// Returns the `analyzer`, `tokenizer` and `filter` sections of the index analysis settings.
+    pub fn analysis(&self) -> (Value, Value, Value) {
+        let mut analyzers = Map::new();
+        let mut filters = Map::new();
+
+        analyzers.insert(
+            "ngram_analyzer".to_string(),
+            json!({
+                "type": "custom",
+                "tokenizer": "ngram_tokenizer",
+                "filter": ["icu_normalizer", "lowercase"],
+            }),
+        );
+        // prefixes of names for typeahead-like profile search
+        analyzers.insert(
+            "edge_ngram_analyzer".to_string(),
+            json!({
+                "type": "custom",
+                "tokenizer": "edge_ngram_tokenizer",
+                "filter": ["icu_normalizer", "lowercase"],
+            }),
+        );
+        let tokenizers = json!({
+            "ngram_tokenizer": {
+                "type": "ngram",
+                "min_gram": self.min_gram.to_string(),
+                "max_gram": self.max_gram.to_string(),
+            },
+            "edge_ngram_tokenizer": {
+                "type": "edge_ngram",
+                "min_gram": "1",
+                "max_gram": "20",
+                "token_chars": ["letter", "digit"],
+            },
+        });
+
+        for preset in self.languages.values() {
+            match preset {
+                AnalyzerPreset::Ngram => {}
+                AnalyzerPreset::Stemming(language) => {
+                    let filter_name = format!("{}_stemmer", language);
+                    filters.insert(
+                        filter_name.clone(),
+                        json!({
+                            "type": "stemmer",
+                            "language": language,
+                        }),
+                    );
+                    analyzers.insert(
+                        preset.analyzer_name(),
+                        json!({
+                            "type": "custom",
+                            "tokenizer": "standard",
+                            "filter": ["icu_normalizer", "lowercase", filter_name],
+                        }),
+                    );
+                }
+                AnalyzerPreset::Kuromoji => {
+                    analyzers.insert(
+                        preset.analyzer_name(),
+                        json!({
+                            "type": "custom",
+                            "tokenizer": "kuromoji_tokenizer",
+                            "filter": [
+                                "kuromoji_baseform",
+                                "kuromoji_part_of_speech",
+                                "cjk_width",
+                                "ja_stop",
+                                "kuromoji_stemmer",
+                                "lowercase"
+                            ],
+                        }),
+                    );
+                }
+            }
+        }
+
+        (Value::Object(analyzers), tokenizers, Value::Object(filters))
+    }
+
+    pub fn is_configured(&self, language_code: &str) -> bool {
+        self.languages.contains_key(language_code)
+    }
+
+    /// Returns the properties of `texts`, one field per configured language.
+    ///
+    /// The ingest pipeline copies `text` into `texts.<language>` for the detected language only.
+    pub fn language_fields(&self) -> Value {
+        let fields = self
+            .languages
+            .iter()
+            .map(|(language_code, preset)| {
+                (
+                    language_code.clone(),
+                    json!({
+                        "type": "text",
+                        "analyzer": preset.analyzer_name(),
+                    }),
+                )
+            })
+            .collect::<Map<_, _>>();
+        Value::Object(fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::index::analyzer::{AnalyzerConfig, AnalyzerPreset};
+
+    #[test]
+    fn test_parse_languages() {
+        let languages =
+            AnalyzerConfig::parse_languages("ja:kuromoji, en:stemming,zh:ngram,").unwrap();
+        assert_eq!(languages.len(), 3);
+        assert_eq!(languages["ja"], AnalyzerPreset::Kuromoji);
+        assert_eq!(
+            languages["en"],
+            AnalyzerPreset::Stemming("english".to_string())
+        );
+        assert_eq!(languages["zh"], AnalyzerPreset::Ngram);
+
+        assert!(AnalyzerConfig::parse_languages("ja").is_err());
+        assert!(AnalyzerConfig::parse_languages("ja:unknown").is_err());
+        assert!(AnalyzerConfig::parse_languages("xx:stemming").is_err());
+        assert!(AnalyzerConfig::parse_languages("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_new() {
+        assert!(AnalyzerConfig::new(0, 2, Default::default()).is_err());
+        assert!(AnalyzerConfig::new(3, 2, Default::default()).is_err());
+        assert_eq!(
+            AnalyzerConfig::new(1, 2, Default::default()).unwrap(),
+            AnalyzerConfig::default()
+        );
+        assert_eq!(
+            AnalyzerConfig::new(2, 4, Default::default())
+                .unwrap()
+                .max_ngram_diff(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_language_fields() {
+        let config = AnalyzerConfig::new(
+            1,
+            2,
+            AnalyzerConfig::parse_languages("ja:kuromoji,en:stemming").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            config.language_fields(),
+            json!({
+                "en": {"type": "text", "analyzer": "english_analyzer"},
+                "ja": {"type": "text", "analyzer": "kuromoji_analyzer"},
+            })
+        );
+
+        let (analyzers, _, filters) = config.analysis();
+        assert!(analyzers.get("ngram_analyzer").is_some());
+        assert!(analyzers.get("edge_ngram_analyzer").is_some());
+        assert!(analyzers.get("english_analyzer").is_some());
+        assert!(analyzers.get("kuromoji_analyzer").is_some());
+        assert_eq!(
+            filters,
+            json!({"english_stemmer": {"type": "stemmer", "language": "english"}})
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::async_trait;
+use serde_json::Value;
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+
+/// Entries accumulated in memory and periodically written out in batches.
+#[async_trait]
+pub trait Flush: Send + Sync {
+    /// Writes out the pending entries, putting back those that failed to be written.
+    async fn flush(&self, state: &AppState) -> anyhow::Result<()>;
+}
+
+/// Flushes the batch `select` picks from the state every `interval`, logging failures.
+pub fn spawn_flusher(
+    state: Arc<AppState>,
+    interval: Duration,
+    select: fn(&AppState) -> Option<&dyn Flush>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Some(batch) = select(&state) {
+                if let Err(e) = batch.flush(&state).await {
+                    log::error!("{}", e);
+                }
+            }
+        }
+    })
+}
+
+/// Positions of the items of the bulk response `body` that failed, with their errors; items
+/// answered with one of the `accepted` statuses have not.
+pub(crate) fn failed_items(body: &Value, accepted: &[u64]) -> Vec<(usize, Value)> {
+    body["items"]
+        .as_array()
+        .unwrap_or(&vec![])
+        .iter()
+        .enumerate()
+        .filter_map(|(i, item)| {
+            // each item is keyed by its action
+            let result = item.as_object()?.values().next()?;
+            let status = result["status"].as_u64().unwrap_or_default();
+            let failed = result.get("error").is_some() && !accepted.contains(&status);
+            failed.then(|| (i, result["error"].clone()))
+        })
+        .collect()
+}
+
+/// Puts `entries` back into `pending`, merging them into those recorded since.
+pub(crate) fn requeue<K: Eq + Hash, V>(
+    pending: &Mutex<HashMap<K, V>>,
+    entries: impl IntoIterator<Item = (K, V)>,
+    merge: impl Fn(&mut V, V),
+) {
+    let mut pending = pending.lock().unwrap();
+    for (key, value) in entries {
+        match pending.get_mut(&key) {
+            Some(recorded) => merge(recorded, value),
+            None => {
+                pending.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use serde_json::json;
+
+    use crate::index::batch::{failed_items, requeue};
+
+    #[test]
+    fn test_failed_items() {
+        let body = json!({
+            "errors": true,
+            "items": [
+                { "create": { "_id": "a", "status": 201 } },
+                { "create": { "_id": "b", "status": 409, "error": { "type": "conflict" } } },
+                { "update": { "_id": "c", "status": 429, "error": { "type": "rejected" } } }
+            ]
+        });
+        assert_eq!(
+            failed_items(&body, &[409]),
+            vec![(2, json!({ "type": "rejected" }))]
+        );
+        assert_eq!(failed_items(&body, &[]).len(), 2);
+        assert!(failed_items(&json!({}), &[]).is_empty());
+    }
+
+    #[test]
+    fn test_requeue() {
+        let pending = Mutex::new(HashMap::from([("a", 1)]));
+        requeue(&pending, [("a", 2), ("b", 3)], |recorded, value| {
+            *recorded += value
+        });
+        assert_eq!(
+            pending.into_inner().unwrap(),
+            HashMap::from([("a", 3), ("b", 3)])
+        );
+    }
+}
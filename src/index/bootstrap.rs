@@ -0,0 +1,165 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use elasticsearch::{Elasticsearch, SearchParts};
+use nostr_sdk::prelude::{Event, Filter, Timestamp};
+use serde_json::{json, Value};
+
+use crate::app_state::AppState;
+use crate::index::handlers::handle_update;
+use crate::index::upstream::Upstream;
+
+/// How the index is seeded from other NIP-50 relays.
+#[derive(Debug, Clone)]
+pub struct BootstrapConfig {
+    pub relays: Vec<String>,
+    /// search strings sent to the relays, e.g. common words of the languages of interest
+    pub queries: Vec<String>,
+    /// age of the oldest events fetched, in days
+    pub since_days: u64,
+    /// length of the time windows each query is split into, in hours
+    pub window_hours: u64,
+    /// `limit` of each request
+    pub limit: usize,
+    /// wait between requests, to stay within the rate limits of the relays
+    pub interval: Duration,
+}
+
+/// Time windows `(since, until)` covering `[now - age, now]`, newest first.
+fn windows(now: u64, age: u64, window: u64) -> Vec<(u64, u64)> {
+    let start = now.saturating_sub(age);
+    let mut windows = vec![];
+    let mut until = now;
+    while until > start {
+        let since = until.saturating_sub(window.max(1)).max(start);
+        windows.push((since, until));
+        until = since;
+    }
+    windows
+}
+
+/// Ids among `ids` that are already indexed.
+async fn indexed_ids(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    ids: &[String],
+) -> anyhow::Result<HashSet<String>> {
+    let res = es_client
+        .search(SearchParts::Index(&[index_alias_name]))
+        .size(ids.len() as i64)
+        .body(json!({
+            "_source": ["event.id"],
+            "query": { "terms": { "event.id": ids } }
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!("failed to search: {}", res.status_code()));
+    }
+    let body = res.json::<Value>().await?;
+    Ok(body["hits"]["hits"]
+        .as_array()
+        .unwrap_or(&vec![])
+        .iter()
+        .filter_map(|hit| hit["_source"]["event"]["id"].as_str())
+        .map(|id| id.to_string())
+        .collect())
+}
+
+/// Indexes the events not seen before among `events`; returns the number of unseen events
+/// and the number indexed.
+async fn index_new(
+    state: &Arc<AppState>,
+    seen: &mut HashSet<String>,
+    events: Vec<Event>,
+) -> anyhow::Result<(usize, usize)> {
+    let events = events
+        .into_iter()
+        .filter(|event| seen.insert(event.id.to_hex()))
+        .collect::<Vec<_>>();
+    if events.is_empty() {
+        return Ok((0, 0));
+    }
+    let ids = events
+        .iter()
+        .map(|event| event.id.to_hex())
+        .collect::<Vec<_>>();
+    let indexed = indexed_ids(&state.es_client, &state.index_alias_name, &ids).await?;
+    let mut n = 0;
+    for event in &events {
+        if indexed.contains(&event.id.to_hex()) {
+            continue;
+        }
+        handle_update(state.clone(), event).await?;
+        n += 1;
+    }
+    Ok((events.len(), n))
+}
+
+/// Seeds the index with the results of broad searches on other NIP-50 relays, paging through
+/// each time window by `until`. Returns the number of events fetched and indexed.
+pub async fn bootstrap(
+    state: Arc<AppState>,
+    config: &BootstrapConfig,
+) -> anyhow::Result<(usize, usize)> {
+    let upstream = Upstream::connect(&config.relays, Duration::from_secs(30)).await?;
+
+    let now = Utc::now().timestamp() as u64;
+    let windows = windows(
+        now,
+        config.since_days * 24 * 60 * 60,
+        config.window_hours * 60 * 60,
+    );
+    let mut seen = HashSet::new();
+    let (mut fetched, mut indexed) = (0, 0);
+    for query in &config.queries {
+        for (since, until) in &windows {
+            let mut until = *until;
+            loop {
+                let filter = Filter::new()
+                    .search(query)
+                    .since(Timestamp::from(*since))
+                    .until(Timestamp::from(until))
+                    .limit(config.limit);
+                // failed requests end the window like empty pages
+                let events = upstream.fetch(vec![filter]).await;
+                tokio::time::sleep(config.interval).await;
+                let oldest = events.iter().map(|event| event.created_at.as_u64()).min();
+                let (unseen, n) = index_new(&state, &mut seen, events).await?;
+                fetched += unseen;
+                indexed += n;
+                // relays may return fewer events than `limit`, so page until nothing is new
+                match oldest {
+                    Some(oldest) if unseen > 0 && oldest > *since => until = oldest,
+                    _ => break,
+                }
+            }
+        }
+        log::info!(
+            "[{}] bootstrapped \"{}\"; {} event(s) fetched, {} indexed so far",
+            state.index_alias_name,
+            query,
+            fetched,
+            indexed
+        );
+    }
+    upstream.disconnect().await?;
+    Ok((fetched, indexed))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::bootstrap::windows;
+
+    #[test]
+    fn test_windows() {
+        assert_eq!(
+            windows(1000, 250, 100),
+            vec![(900, 1000), (800, 900), (750, 800)]
+        );
+        assert_eq!(windows(1000, 0, 100), vec![]);
+        assert_eq!(windows(100, 1000, 100), vec![(0, 100)]);
+    }
+}
@@ -0,0 +1,198 @@
+use std::fmt;
+use std::sync::Arc;
+
+use axum::async_trait;
+use nostr_sdk::Event;
+
+use crate::app_state::AppState;
+use crate::index::handlers::{
+    AdmitStage, DedupeStage, Document, EnrichStage, FilterStage, RouteStage, VerifyStage,
+    WriteStage,
+};
+use crate::index::indexes::round_created_at;
+
+/// stages events go through unless `INGEST_STAGES` is set
+pub const DEFAULT_STAGES: &str = "verify,dedupe,filter,enrich,route,write";
+
+/// Whether an event goes on to the next stage.
+#[derive(Debug, PartialEq)]
+pub enum Flow {
+    Continue,
+    /// handled or skipped by the stage
+    Stop,
+}
+
+/// An event going through the chain, with what earlier stages found out about it.
+pub struct EventContext<'a> {
+    pub event: &'a Event,
+    /// searchable copy of the event; `created_at` may be rounded
+    pub searchable_event: Event,
+    /// the original event when `searchable_event` has been altered
+    pub raw: Option<Event>,
+    /// index the event is written to, set by `route`
+    pub index_name: Option<String>,
+    /// id of the document shared by all versions of the event, versioned by `created_at`;
+    /// the event id if `None`
+    pub doc_id: Option<String>,
+    /// set by `enrich`
+    pub doc: Option<Document>,
+    /// what became of the event, e.g. a skip reason or the result of `write`, for the journal
+    pub outcome: Option<String>,
+    /// whether the signature was verified on arrival, so that `verify` does not check it again
+    pub verified: bool,
+}
+
+impl<'a> EventContext<'a> {
+    fn new(state: &AppState, event: &'a Event, verified: bool) -> Self {
+        let (searchable_event, raw) = match state.created_at_rounding {
+            Some(secs) => {
+                let mut searchable_event = event.clone();
+                searchable_event.created_at = round_created_at(event.created_at, secs);
+                (searchable_event, Some(event.clone()))
+            }
+            None => (event.clone(), None),
+        };
+        EventContext {
+            event,
+            searchable_event,
+            raw,
+            index_name: None,
+            doc_id: None,
+            doc: None,
+            outcome: None,
+            verified,
+        }
+    }
+
+    /// Stops the chain with `outcome`.
+    pub fn stop(&mut self, outcome: &str) -> Flow {
+        self.outcome = Some(outcome.to_string());
+        Flow::Stop
+    }
+}
+
+/// A step of handling an event for indexing.
+#[async_trait]
+pub trait Stage: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn process(
+        &self,
+        state: &Arc<AppState>,
+        ctx: &mut EventContext<'_>,
+    ) -> anyhow::Result<Flow>;
+}
+
+/// The stages every event goes through in order, until one of them stops it.
+pub struct Chain {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl fmt::Debug for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+impl Chain {
+    /// Parses comma-separated stage names, e.g. `dedupe,filter,enrich,route,write` to not
+    /// verify events that were verified by their source.
+    ///
+    /// `enrich`, `route` and `write` are required, and `write` comes last. `admit` always runs
+    /// first.
+    pub fn parse(stages: &str) -> anyhow::Result<Self> {
+        let mut chain = Chain {
+            stages: vec![Box::new(AdmitStage)],
+        };
+        for name in stages
+            .split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+        {
+            let stage: Box<dyn Stage> = match name {
+                "verify" => Box::new(VerifyStage),
+                "dedupe" => Box::new(DedupeStage),
+                "filter" => Box::new(FilterStage),
+                "enrich" => Box::new(EnrichStage),
+                "route" => Box::new(RouteStage),
+                "write" => Box::new(WriteStage),
+                _ => return Err(anyhow::anyhow!("unknown stage: {}", name)),
+            };
+            if chain.names().contains(&stage.name()) {
+                return Err(anyhow::anyhow!("duplicate stage: {}", name));
+            }
+            chain.stages.push(stage);
+        }
+        let names = chain.names();
+        for required in ["enrich", "route", "write"] {
+            if !names.contains(&required) {
+                return Err(anyhow::anyhow!("missing stage: {}", required));
+            }
+        }
+        if names.last() != Some(&"write") {
+            return Err(anyhow::anyhow!("write must be the last stage"));
+        }
+        Ok(chain)
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.stages.iter().map(|stage| stage.name()).collect()
+    }
+
+    pub async fn run(
+        &self,
+        state: &Arc<AppState>,
+        event: &Event,
+        verified: bool,
+    ) -> anyhow::Result<()> {
+        let mut ctx = EventContext::new(state, event, verified);
+        let mut result = Ok(());
+        for stage in &self.stages {
+            match stage.process(state, &mut ctx).await {
+                Ok(Flow::Continue) => {}
+                Ok(Flow::Stop) => break,
+                Err(e) => {
+                    // unless the stage told how it failed, e.g. `failed:<status>`
+                    if ctx.outcome.is_none() {
+                        ctx.outcome = Some(format!("error:{}", stage.name()));
+                    }
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        if let Some(journal) = &state.journal {
+            journal.record(
+                &event.id.to_hex(),
+                "index",
+                ctx.index_name.as_deref(),
+                ctx.outcome.as_deref().unwrap_or("unknown"),
+            );
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::chain::{Chain, DEFAULT_STAGES};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Chain::parse(DEFAULT_STAGES).unwrap().names(),
+            vec!["admit", "verify", "dedupe", "filter", "enrich", "route", "write"]
+        );
+        assert_eq!(
+            Chain::parse(" filter, route,enrich ,write")
+                .unwrap()
+                .names(),
+            vec!["admit", "filter", "route", "enrich", "write"]
+        );
+        assert!(Chain::parse("verify,enrich,route,write,verify").is_err());
+        assert!(Chain::parse("verify,route,write").is_err());
+        assert!(Chain::parse("enrich,write,route").is_err());
+        assert!(Chain::parse("enrich,route,write,index").is_err());
+        assert!(Chain::parse("admit,enrich,route,write").is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use nostr_sdk::Event;
+use serde::Serialize;
+
+pub const KIND_CLASSIFIED: u64 = 30402;
+
+/// Fields of a NIP-99 classified listing, indexed for filtering and sorting by price and
+/// location.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Classified {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price: Option<f64>,
+    /// ISO 4217 code, uppercase
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+/// Parses the tags of a kind 30402 event; a `price` tag without a valid amount is skipped.
+pub fn extract_classified(event: &Event) -> Option<Classified> {
+    if event.kind.as_u64() != KIND_CLASSIFIED {
+        return None;
+    }
+    let mut classified = Classified::default();
+    for tag in &event.tags {
+        let tag = tag.as_vec();
+        let value = match tag.get(1).map(|value| value.trim()) {
+            Some(value) if !value.is_empty() => value,
+            _ => continue,
+        };
+        match tag[0].as_str() {
+            "title" => classified.title = Some(value.to_string()),
+            "summary" => classified.summary = Some(value.to_string()),
+            "location" => classified.location = Some(value.to_string()),
+            "price" => {
+                if let Ok(price) = value.parse::<f64>() {
+                    if price.is_finite() && price >= 0.0 {
+                        classified.price = Some(price);
+                        classified.currency = tag
+                            .get(2)
+                            .map(|currency| currency.trim().to_uppercase())
+                            .filter(|currency| !currency.is_empty());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(classified)
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+
+    use crate::index::classified::{extract_classified, Classified};
+
+    #[test]
+    fn test_extract_classified() {
+        let keys = Keys::generate();
+        let tags = [
+            vec!["d", "bike"],
+            vec!["title", "Road bike"],
+            vec!["summary", " barely used "],
+            vec!["price", "150.5", "usd"],
+            vec!["location", "Tokyo"],
+        ]
+        .iter()
+        .map(|tag| Tag::parse(tag.clone()).unwrap())
+        .collect::<Vec<_>>();
+        let event = EventBuilder::new(Kind::from(30402), "", &tags)
+            .to_event(&keys)
+            .unwrap();
+        assert_eq!(
+            extract_classified(&event),
+            Some(Classified {
+                title: Some("Road bike".to_string()),
+                summary: Some("barely used".to_string()),
+                price: Some(150.5),
+                currency: Some("USD".to_string()),
+                location: Some("Tokyo".to_string()),
+            })
+        );
+
+        let tags = [Tag::parse(vec!["price", "free"]).unwrap()];
+        let event = EventBuilder::new(Kind::from(30402), "", &tags)
+            .to_event(&keys)
+            .unwrap();
+        assert_eq!(extract_classified(&event), Some(Classified::default()));
+
+        let event = EventBuilder::new(Kind::TextNote, "", &tags)
+            .to_event(&keys)
+            .unwrap();
+        assert_eq!(extract_classified(&event), None);
+    }
+}
@@ -0,0 +1,580 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::async_trait;
+use elasticsearch::http::request::JsonBody;
+use elasticsearch::{BulkParts, DeleteByQueryParts, Elasticsearch, SearchParts, UpdateParts};
+use nostr_sdk::Event;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+use crate::index::batch::{failed_items, spawn_flusher, Flush};
+use crate::index::delegation::author;
+use crate::index::indexes::create_side_index;
+use crate::index::profile::{extract_profile, Profile};
+
+const MAX_HASHTAG_LENGTH: usize = 64;
+
+/// Hashtags and profiles by `t:<hashtag>` and `p:<pubkey>`.
+fn completions_index(index_alias_name: &str) -> String {
+    format!("searchnos-completions-{}", index_alias_name)
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    /// hashtag -> uses
+    hashtags: HashMap<String, u64>,
+    /// pubkey -> latest metadata
+    profiles: HashMap<String, PendingProfile>,
+}
+
+#[derive(Debug)]
+struct PendingProfile {
+    created_at: u64,
+    event_id: String,
+    profile: Profile,
+}
+
+/// Hashtags and profiles of indexed events, accumulated in memory and periodically added to
+/// the completions index.
+#[derive(Debug, Default)]
+pub struct CompletionCollector {
+    pending: Mutex<Pending>,
+}
+
+/// Lowercase hashtags of the `t` tags of `event`, without a leading `#`.
+fn hashtags(event: &Event) -> Vec<String> {
+    let mut hashtags = event
+        .tags
+        .iter()
+        .filter_map(|tag| {
+            let tag = tag.as_vec();
+            match tag.as_slice() {
+                [name, value, ..] if name == "t" => {
+                    let value = value.trim().trim_start_matches('#').to_lowercase();
+                    let valid = !value.is_empty()
+                        && value.len() <= MAX_HASHTAG_LENGTH
+                        && !value.contains(char::is_whitespace);
+                    valid.then_some(value)
+                }
+                _ => None,
+            }
+        })
+        .collect::<Vec<_>>();
+    hashtags.sort();
+    hashtags.dedup();
+    hashtags
+}
+
+/// Input completing to a NIP-05 identifier, `_@domain` standing for the domain itself.
+fn nip05_input(nip05: &str) -> String {
+    nip05.strip_prefix("_@").unwrap_or(nip05).to_string()
+}
+
+/// Lowercase inputs completing to a profile: its names, each of their words and its NIP-05
+/// identifier.
+fn profile_inputs(profile: &Profile) -> Vec<String> {
+    let mut inputs = vec![];
+    for name in [&profile.name, &profile.display_name].into_iter().flatten() {
+        let name = name.to_lowercase();
+        inputs.extend(name.split_whitespace().map(|word| word.to_string()));
+        inputs.push(name);
+    }
+    if let Some(nip05) = &profile.nip05 {
+        inputs.push(nip05_input(nip05));
+    }
+    inputs.sort();
+    inputs.dedup();
+    inputs
+}
+
+impl CompletionCollector {
+    /// Records the hashtags and the profile of `event`, with its NIP-05 identifier only when
+    /// verified.
+    pub fn record(&self, event: &Event, nip05_verified: bool) {
+        let hashtags = hashtags(event);
+        let profile = extract_profile(event).map(|mut profile| {
+            if !nip05_verified {
+                profile.nip05 = None;
+            }
+            profile
+        });
+        if hashtags.is_empty() && profile.is_none() {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        for hashtag in hashtags {
+            *pending.hashtags.entry(hashtag).or_default() += 1;
+        }
+        if let Some(profile) = profile {
+            let created_at = event.created_at.as_u64();
+            match pending.profiles.get(&author(event)) {
+                Some(latest) if latest.created_at >= created_at => {}
+                _ => {
+                    let pending_profile = PendingProfile {
+                        created_at,
+                        event_id: event.id.to_hex(),
+                        profile,
+                    };
+                    pending.profiles.insert(author(event), pending_profile);
+                }
+            }
+        }
+    }
+
+    /// Drops the pending profile of `pubkey` when it is one of `event_ids`.
+    pub fn forget(&self, pubkey: &str, event_ids: &[String]) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(latest) = pending.profiles.get(pubkey) {
+            if event_ids.contains(&latest.event_id) {
+                pending.profiles.remove(pubkey);
+            }
+        }
+    }
+
+    fn take(&self) -> Pending {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+
+    /// Puts back completions that failed to be written, to be retried at the next flush.
+    fn requeue(&self, failed: Pending) {
+        let mut pending = self.pending.lock().unwrap();
+        for (hashtag, count) in failed.hashtags {
+            *pending.hashtags.entry(hashtag).or_default() += count;
+        }
+        for (pubkey, profile) in failed.profiles {
+            match pending.profiles.get(&pubkey) {
+                Some(latest) if latest.created_at >= profile.created_at => {}
+                _ => {
+                    pending.profiles.insert(pubkey, profile);
+                }
+            }
+        }
+    }
+}
+
+pub async fn create_completions_index(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+) -> anyhow::Result<()> {
+    create_side_index(
+        es_client,
+        &completions_index(index_alias_name),
+        json!({
+            "dynamic": false,
+            "properties": {
+                "type": { "type": "keyword" },
+                "value": { "type": "keyword" },
+                "count": { "type": "long" },
+                "pubkey": { "type": "keyword" },
+                "event_id": { "type": "keyword" },
+                "nip05": { "type": "keyword" },
+                // inputs are lowercased beforehand, so that digits and punctuation are kept
+                "suggest": {
+                    "type": "completion",
+                    "analyzer": "keyword",
+                    "contexts": [{ "name": "type", "type": "category", "path": "type" }]
+                }
+            }
+        }),
+    )
+    .await
+}
+
+#[async_trait]
+impl Flush for CompletionCollector {
+    async fn flush(&self, state: &AppState) -> anyhow::Result<()> {
+        let mut pending = self.take();
+        if pending.hashtags.is_empty() && pending.profiles.is_empty() {
+            return Ok(());
+        }
+        let (hashtags, profiles) = match write(state, &pending).await {
+            Ok(failed) => failed,
+            Err(e) => {
+                self.requeue(pending);
+                return Err(e);
+            }
+        };
+        let failed = Pending {
+            hashtags: hashtags
+                .iter()
+                .filter_map(|hashtag| pending.hashtags.remove_entry(hashtag))
+                .collect(),
+            profiles: profiles
+                .iter()
+                .filter_map(|pubkey| pending.profiles.remove_entry(pubkey))
+                .collect(),
+        };
+        self.requeue(failed);
+        log::info!(
+            "updated completions of {} hashtag(s) and {} profile(s)",
+            pending.hashtags.len(),
+            pending.profiles.len()
+        );
+        Ok(())
+    }
+}
+
+/// Writes the pending completions; returns the hashtags and the profiles that failed to be
+/// written.
+async fn write(state: &AppState, pending: &Pending) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+    let completions_index = completions_index(&state.index_alias_name);
+    let mut body: Vec<JsonBody<Value>> = vec![];
+    // hashtag or pubkey of each item
+    let mut items = vec![];
+    for (hashtag, count) in &pending.hashtags {
+        items.push((true, hashtag.clone()));
+        let id = format!("t:{}", hashtag);
+        body.push(json!({ "update": { "_index": completions_index, "_id": id } }).into());
+        body.push(
+            json!({
+                "script": {
+                    "source": "ctx._source.count += params.count; ctx._source.suggest = ['input': [ctx._source.value], 'weight': (int) Math.min(ctx._source.count, 2147483647L)];",
+                    "params": { "count": count }
+                },
+                "scripted_upsert": true,
+                "upsert": { "type": "hashtag", "value": hashtag, "count": 0 }
+            })
+            .into(),
+        );
+    }
+    for (pubkey, pending_profile) in &pending.profiles {
+        // opted out meanwhile
+        if state.opt_out.is_opted_out(pubkey) {
+            continue;
+        }
+        let PendingProfile {
+            created_at,
+            event_id,
+            profile,
+        } = pending_profile;
+        let id = format!("p:{}", pubkey);
+        let inputs = profile_inputs(profile);
+        if inputs.is_empty() {
+            continue;
+        }
+        items.push((false, pubkey.clone()));
+        // versioned by `created_at`, so that older metadata never overwrites newer
+        body.push(
+            json!({
+                "index": {
+                    "_index": completions_index,
+                    "_id": id,
+                    "version": created_at,
+                    "version_type": "external_gte"
+                }
+            })
+            .into(),
+        );
+        body.push(
+            json!({
+                "type": "profile",
+                "value": profile.display_name.as_ref().or(profile.name.as_ref()),
+                "pubkey": pubkey,
+                "event_id": event_id,
+                "nip05": profile.nip05,
+                "suggest": { "input": inputs, "weight": 1 }
+            })
+            .into(),
+        );
+    }
+    if body.is_empty() {
+        return Ok((vec![], vec![]));
+    }
+    let res = state
+        .es_client
+        .bulk(BulkParts::None)
+        .body(body)
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to update completions: {}",
+            res.status_code()
+        ));
+    }
+    let res = res.json::<Value>().await?;
+    let (mut hashtags, mut profiles) = (vec![], vec![]);
+    // older metadata than the completion fails with a conflict
+    for (i, error) in failed_items(&res, &[409]) {
+        log::warn!("failed to update completion: {}", error);
+        match &items[i] {
+            (true, hashtag) => hashtags.push(hashtag.clone()),
+            (false, pubkey) => profiles.push(pubkey.clone()),
+        }
+    }
+    Ok((hashtags, profiles))
+}
+
+pub fn spawn_completion_flusher(state: Arc<AppState>, interval: Duration) -> JoinHandle<()> {
+    spawn_flusher(state, interval, |state| {
+        state
+            .completions
+            .as_ref()
+            .map(|collector| collector as &dyn Flush)
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HashtagCompletion {
+    pub hashtag: String,
+    /// events indexed with the hashtag
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProfileCompletion {
+    pub pubkey: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nip05: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Completions {
+    pub hashtags: Vec<HashtagCompletion>,
+    pub profiles: Vec<ProfileCompletion>,
+}
+
+/// Prefix completed and whether hashtags and profiles are completed: only hashtags after `#`
+/// and only profiles after `@`.
+fn parse_prefix(q: &str) -> (String, bool, bool) {
+    let q = q.trim().to_lowercase();
+    if let Some(prefix) = q.strip_prefix('#') {
+        (prefix.to_string(), true, false)
+    } else if let Some(prefix) = q.strip_prefix('@') {
+        (prefix.to_string(), false, true)
+    } else {
+        (q, true, true)
+    }
+}
+
+fn parse_completions(body: &Value) -> Completions {
+    let options = |name: &str| {
+        body["suggest"][name][0]["options"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default()
+    };
+    Completions {
+        hashtags: options("hashtags")
+            .iter()
+            .filter_map(|option| {
+                let source = &option["_source"];
+                Some(HashtagCompletion {
+                    hashtag: source["value"].as_str()?.to_string(),
+                    count: source["count"].as_u64().unwrap_or_default(),
+                })
+            })
+            .collect(),
+        profiles: options("profiles")
+            .iter()
+            .filter_map(|option| {
+                let source = &option["_source"];
+                Some(ProfileCompletion {
+                    pubkey: source["pubkey"].as_str()?.to_string(),
+                    name: source["value"].as_str().map(|s| s.to_string()),
+                    nip05: source["nip05"].as_str().map(|s| s.to_string()),
+                })
+            })
+            .collect(),
+    }
+}
+
+/// Hashtags and profiles completing `q`, the most used hashtags first.
+pub async fn complete(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    q: &str,
+    size: usize,
+) -> anyhow::Result<Completions> {
+    let (prefix, hashtags, profiles) = parse_prefix(q);
+    if prefix.is_empty() {
+        return Ok(Completions::default());
+    }
+    let suggester = |kind: &str| {
+        json!({
+            "prefix": prefix,
+            "completion": {
+                "field": "suggest",
+                "size": size,
+                "skip_duplicates": true,
+                "contexts": { "type": [kind] }
+            }
+        })
+    };
+    let mut suggest = serde_json::Map::new();
+    if hashtags {
+        suggest.insert("hashtags".to_string(), suggester("hashtag"));
+    }
+    if profiles {
+        suggest.insert("profiles".to_string(), suggester("profile"));
+    }
+    let completions_index = completions_index(index_alias_name);
+    let res = es_client
+        .search(SearchParts::Index(&[completions_index.as_str()]))
+        .body(json!({
+            "_source": ["value", "count", "pubkey", "nip05"],
+            "suggest": suggest
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to get completions: {}",
+            res.status_code()
+        ));
+    }
+    Ok(parse_completions(&res.json::<Value>().await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+    use serde_json::json;
+
+    use crate::index::completion::{
+        hashtags, parse_completions, parse_prefix, profile_inputs, profile_query,
+        CompletionCollector, HashtagCompletion, ProfileCompletion,
+    };
+    use crate::index::profile::extract_profile;
+
+    #[test]
+    fn test_hashtags() {
+        let tags = [
+            vec!["t", "Nostr"],
+            vec!["t", "#nostr"],
+            vec!["t", "bitcoin2024"],
+            vec!["t", "two words"],
+            vec!["t", ""],
+            vec!["p", "abc"],
+        ]
+        .iter()
+        .map(|tag| Tag::parse(tag.clone()).unwrap())
+        .collect::<Vec<_>>();
+        let event = EventBuilder::new(Kind::TextNote, "", &tags)
+            .to_event(&Keys::generate())
+            .unwrap();
+        assert_eq!(hashtags(&event), vec!["bitcoin2024", "nostr"]);
+
+        let collector = CompletionCollector::default();
+        collector.record(&event, false);
+        collector.record(&event, false);
+        let pending = collector.take();
+        assert_eq!(pending.hashtags["nostr"], 2);
+        assert!(pending.profiles.is_empty());
+    }
+
+    #[test]
+    fn test_profile_inputs() {
+        let event = EventBuilder::new(
+            Kind::Metadata,
+            r#"{"name":"alice","display_name":"Alice Liddell","nip05":"_@alice.example.com"}"#,
+            &[],
+        )
+        .to_event(&Keys::generate())
+        .unwrap();
+        let profile = extract_profile(&event).unwrap();
+        assert_eq!(
+            profile_inputs(&profile),
+            vec!["alice", "alice liddell", "alice.example.com", "liddell"]
+        );
+    }
+
+    #[test]
+    fn test_record_profile() {
+        let keys = Keys::generate();
+        let event = EventBuilder::new(
+            Kind::Metadata,
+            r#"{"name":"alice","nip05":"alice@example.com"}"#,
+            &[],
+        )
+        .to_event(&keys)
+        .unwrap();
+        let pubkey = keys.public_key().to_string();
+        let collector = CompletionCollector::default();
+
+        collector.record(&event, false);
+        let pending = collector.take();
+        assert_eq!(pending.profiles[&pubkey].profile.nip05, None);
+
+        collector.record(&event, true);
+        collector.forget(&pubkey, &["b".repeat(64)]);
+        let pending = collector.take();
+        assert_eq!(
+            pending.profiles[&pubkey].profile.nip05.as_deref(),
+            Some("alice@example.com")
+        );
+
+        collector.record(&event, true);
+        collector.forget(&pubkey, &[event.id.to_hex()]);
+        assert!(collector.take().profiles.is_empty());
+    }
+
+    #[test]
+    fn test_profile_query() {
+        assert_eq!(
+            profile_query("ab", None),
+            json!({ "bool": { "must": [{ "term": { "_id": "p:ab" } }] } })
+        );
+        let event_ids = vec!["cd".to_string()];
+        assert_eq!(
+            profile_query("ab", Some(&event_ids)),
+            json!({
+                "bool": {
+                    "must": [
+                        { "term": { "_id": "p:ab" } },
+                        { "terms": { "event_id": ["cd"] } }
+                    ]
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_prefix() {
+        assert_eq!(parse_prefix(" Nos"), ("nos".to_string(), true, true));
+        assert_eq!(parse_prefix("#Nos"), ("nos".to_string(), true, false));
+        assert_eq!(parse_prefix("@ali"), ("ali".to_string(), false, true));
+    }
+
+    #[test]
+    fn test_parse_completions() {
+        let body = json!({
+            "suggest": {
+                "hashtags": [{
+                    "text": "nos",
+                    "options": [{ "text": "nostr", "_source": { "value": "nostr", "count": 42 } }]
+                }],
+                "profiles": [{
+                    "text": "nos",
+                    "options": [{
+                        "text": "nostr.example.com",
+                        "_source": { "value": "Nostr Fan", "pubkey": "ab", "nip05": "_@nostr.example.com" }
+                    }]
+                }]
+            }
+        });
+        let completions = parse_completions(&body);
+        assert_eq!(
+            completions.hashtags,
+            vec![HashtagCompletion {
+                hashtag: "nostr".to_string(),
+                count: 42
+            }]
+        );
+        assert_eq!(
+            completions.profiles,
+            vec![ProfileCompletion {
+                pubkey: "ab".to_string(),
+                name: Some("Nostr Fan".to_string()),
+                nip05: Some("_@nostr.example.com".to_string()),
+            }]
+        );
+        assert_eq!(parse_completions(&json!({})).hashtags, vec![]);
+    }
+}
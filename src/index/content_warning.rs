@@ -0,0 +1,48 @@
+use nostr_sdk::Event;
+
+/// Reason of the NIP-36 `content-warning` tag, empty when the tag gives none.
+pub fn extract_content_warning(event: &Event) -> Option<String> {
+    event.tags.iter().find_map(|tag| {
+        let tag = tag.as_vec();
+        if tag.first().map(|name| name.as_str()) != Some("content-warning") {
+            return None;
+        }
+        Some(
+            tag.get(1)
+                .map(|reason| reason.trim().to_string())
+                .unwrap_or_default(),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+
+    use crate::index::content_warning::extract_content_warning;
+
+    fn note(tags: &[Tag]) -> nostr_sdk::Event {
+        EventBuilder::new(Kind::TextNote, "hello", tags)
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_extract_content_warning() {
+        assert_eq!(extract_content_warning(&note(&[])), None);
+        assert_eq!(
+            extract_content_warning(&note(&[Tag::parse(vec![
+                "content-warning".to_string(),
+                " nudity ".to_string()
+            ])
+            .unwrap()])),
+            Some("nudity".to_string())
+        );
+        assert_eq!(
+            extract_content_warning(&note(&[
+                Tag::parse(vec!["content-warning".to_string()]).unwrap()
+            ])),
+            Some("".to_string())
+        );
+    }
+}
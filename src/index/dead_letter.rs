@@ -0,0 +1,150 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use elasticsearch::{DeleteParts, Elasticsearch, IndexParts, ScrollParts, SearchParts};
+use nostr_sdk::Event;
+use serde_json::{json, Value};
+
+use crate::app_state::AppState;
+use crate::index::handlers::handle_update;
+use crate::index::indexes::create_side_index;
+use crate::metrics::Metrics;
+
+fn dead_letter_index(index_alias_name: &str) -> String {
+    format!("searchnos-deadletter-{}", index_alias_name)
+}
+
+/// Creates the side index holding events rejected by Elasticsearch, with the rejection.
+pub async fn create_dead_letter_index(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+) -> anyhow::Result<()> {
+    create_side_index(
+        es_client,
+        &dead_letter_index(index_alias_name),
+        json!({
+            "dynamic": false,
+            "properties": {
+                "status": { "type": "integer" },
+                "failed_at": { "type": "long" }
+            }
+        }),
+    )
+    .await
+}
+
+/// Records an event that failed to be indexed; a later failure of the same event replaces it.
+pub async fn record_dead_letter(
+    state: &AppState,
+    event: &Event,
+    status: u16,
+    error: &str,
+) -> anyhow::Result<()> {
+    let index_name = dead_letter_index(&state.index_alias_name);
+    let res = state
+        .es_client
+        .index(IndexParts::IndexId(&index_name, &event.id.to_hex()))
+        .body(json!({
+            "event": event,
+            "seen_on": state.seen_on.relays(&event.id.to_hex()),
+            "status": status,
+            "error": error,
+            "failed_at": Utc::now().timestamp()
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to record dead letter: {}",
+            res.status_code()
+        ));
+    }
+    Metrics::inc(&state.metrics.dead_letters);
+    Ok(())
+}
+
+/// Indexes the recorded events again, e.g. after fixing the mapping.
+///
+/// Events that succeed are removed from the dead-letter index; events that fail again stay
+/// with the new error. Returns `(replayed, failed)`.
+pub async fn replay_dead_letters(state: Arc<AppState>) -> anyhow::Result<(usize, usize)> {
+    let index_name = dead_letter_index(&state.index_alias_name);
+    let res = state
+        .es_client
+        .search(SearchParts::Index(&[index_name.as_str()]))
+        .scroll("1m")
+        .size(100)
+        .body(json!({
+            "seq_no_primary_term": true,
+            "query": { "match_all": {} },
+            "sort": [{ "failed_at": "asc" }]
+        }))
+        .send()
+        .await?;
+    if res.status_code().as_u16() == 404 {
+        return Ok((0, 0));
+    }
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!("failed to search: {}", res.status_code()));
+    }
+    let mut body = res.json::<Value>().await?;
+
+    let (mut replayed, mut failed) = (0, 0);
+    loop {
+        let hits = body["hits"]["hits"].as_array().cloned().unwrap_or_default();
+        if hits.is_empty() {
+            break;
+        }
+        for hit in hits {
+            let event: Event = match serde_json::from_value(hit["_source"]["event"].clone()) {
+                Ok(event) => event,
+                Err(e) => {
+                    log::warn!("skipping unreadable dead letter {}: {}", hit["_id"], e);
+                    failed += 1;
+                    continue;
+                }
+            };
+            let seen_on: Vec<String> =
+                serde_json::from_value(hit["_source"]["seen_on"].clone()).unwrap_or_default();
+            // the failed attempt replaced the dead letter
+            if let Err(e) = handle_update(state.clone(), &event).await {
+                log::warn!("{} failed again: {}", state.links.link(&event, &seen_on), e);
+                failed += 1;
+                continue;
+            }
+
+            // a failure of another attempt meanwhile replaced the document, so the delete
+            // conflicts and it stays
+            let res = state
+                .es_client
+                .delete(DeleteParts::IndexId(
+                    &index_name,
+                    hit["_id"].as_str().unwrap_or_default(),
+                ))
+                .if_seq_no(hit["_seq_no"].as_i64().unwrap_or_default())
+                .if_primary_term(hit["_primary_term"].as_i64().unwrap_or_default())
+                .send()
+                .await?;
+            match res.status_code().as_u16() {
+                409 => {
+                    log::warn!("{} failed again", state.links.link(&event, &seen_on));
+                    failed += 1;
+                }
+                status if (200..300).contains(&status) => replayed += 1,
+                status => return Err(anyhow::anyhow!("failed to delete dead letter: {}", status)),
+            }
+        }
+        let scroll_id = body["_scroll_id"].as_str().unwrap_or_default().to_string();
+        let res = state
+            .es_client
+            .scroll(ScrollParts::None)
+            .body(json!({ "scroll": "1m", "scroll_id": scroll_id }))
+            .send()
+            .await?;
+        if !res.status_code().is_success() {
+            return Err(anyhow::anyhow!("failed to scroll: {}", res.status_code()));
+        }
+        body = res.json::<Value>().await?;
+    }
+    Ok((replayed, failed))
+}
@@ -0,0 +1,89 @@
+use std::str::FromStr;
+
+use nostr_sdk::nips::nip26::{verify_delegation_signature, Conditions};
+use nostr_sdk::prelude::XOnlyPublicKey;
+use nostr_sdk::secp256k1::schnorr::Signature;
+use nostr_sdk::Event;
+use serde_json::{json, Value};
+
+/// Whether the NIP-26 conditions, e.g. `kind=1&created_at>1680000000`, allow an event.
+fn conditions_allow(conditions: &str, kind: u64, created_at: u64) -> bool {
+    let mut kinds = vec![];
+    for condition in conditions.split('&').filter(|c| !c.is_empty()) {
+        let allowed = if let Some(k) = condition.strip_prefix("kind=") {
+            match k.parse::<u64>() {
+                Ok(k) => {
+                    // kinds are alternatives; the other conditions must all hold
+                    kinds.push(k);
+                    true
+                }
+                Err(_) => false,
+            }
+        } else if let Some(t) = condition.strip_prefix("created_at>") {
+            t.parse::<u64>().map(|t| created_at > t).unwrap_or(false)
+        } else if let Some(t) = condition.strip_prefix("created_at<") {
+            t.parse::<u64>().map(|t| created_at < t).unwrap_or(false)
+        } else {
+            false
+        };
+        if !allowed {
+            return false;
+        }
+    }
+    kinds.is_empty() || kinds.contains(&kind)
+}
+
+/// The delegator of an event carrying a valid NIP-26 `delegation` tag.
+pub fn extract_delegator(event: &Event) -> Option<XOnlyPublicKey> {
+    let tag = event.tags.iter().map(|tag| tag.as_vec()).find(|tag| {
+        tag.first().map(|name| name.as_str()) == Some("delegation") && tag.len() >= 4
+    })?;
+    let (delegator, conditions, sig) = (&tag[1], &tag[2], &tag[3]);
+    if !conditions_allow(
+        conditions,
+        event.kind.as_u32() as u64,
+        event.created_at.as_u64(),
+    ) {
+        return None;
+    }
+    let delegator = XOnlyPublicKey::from_str(delegator).ok()?;
+    let sig = Signature::from_str(sig).ok()?;
+    let conditions = Conditions::from_str(conditions).ok()?;
+    verify_delegation_signature(&delegator, &sig, event.pubkey, conditions).ok()?;
+    Some(delegator)
+}
+
+/// The pubkey an event is published on behalf of: the delegator if delegated, else its signer.
+pub fn author(event: &Event) -> String {
+    extract_delegator(event).unwrap_or(event.pubkey).to_string()
+}
+
+/// Matches documents of `author`, whether signed by them or delegated by them.
+pub fn author_condition(author: &str) -> Value {
+    json!({
+        "bool": {
+            "should": [
+                { "term": { "event.pubkey": author } },
+                { "term": { "delegator": author } }
+            ],
+            "minimum_should_match": 1
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::delegation::conditions_allow;
+
+    #[test]
+    fn test_conditions_allow() {
+        let conditions = "kind=1&created_at>1680000000&created_at<1690000000";
+        assert!(conditions_allow(conditions, 1, 1685000000));
+        assert!(!conditions_allow(conditions, 0, 1685000000));
+        assert!(!conditions_allow(conditions, 1, 1670000000));
+        assert!(!conditions_allow(conditions, 1, 1695000000));
+        assert!(conditions_allow("kind=0&kind=1", 0, 1));
+        assert!(conditions_allow("", 7, 1));
+        assert!(!conditions_allow("unknown=1", 1, 1));
+    }
+}
@@ -0,0 +1,238 @@
+use elasticsearch::http::request::JsonBody;
+use elasticsearch::{BulkParts, DeleteByQueryParts, Elasticsearch, GetParts};
+use log::{error, info};
+use nostr_sdk::prelude::{Event, Tag};
+use serde_json::{json, Value};
+
+use crate::index::delegation::{author, author_condition};
+use crate::index::indexes::create_side_index;
+
+fn deletions_index(index_alias_name: &str) -> String {
+    format!("searchnos-deletions-{}", index_alias_name)
+}
+
+/// Deletions are recorded per author, so that nobody but the author can delete an event.
+fn deletion_id(event_id: &str, pubkey: &str) -> String {
+    format!("{}:{}", event_id, pubkey)
+}
+
+/// Ids of the events referred to by a deletion (kind 5).
+pub(crate) fn deleted_ids(event: &Event) -> Vec<String> {
+    event
+        .tags
+        .iter()
+        .filter_map(|tag| match tag {
+            Tag::Event(e, _, _) => Some(e.to_hex()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Creates the side index holding the deletions whose events may not have arrived yet.
+pub async fn create_deletions_index(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+) -> anyhow::Result<()> {
+    create_side_index(
+        es_client,
+        &deletions_index(index_alias_name),
+        json!({
+            "dynamic": false,
+            "properties": { "created_at": { "type": "long" } }
+        }),
+    )
+    .await
+}
+
+/// Records the deletions of the referred events by their author, so that the events are not
+/// indexed when they arrive after the deletion; returns the author and the ids.
+async fn record_deletions(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    event: &Event,
+) -> anyhow::Result<(String, Vec<String>)> {
+    let ids_to_delete = deleted_ids(event);
+    // a delegated deletion deletes events of the delegator
+    let pubkey = author(event);
+    if ids_to_delete.is_empty() {
+        return Ok((pubkey, ids_to_delete));
+    }
+    let mut body: Vec<JsonBody<Value>> = vec![];
+    for id in &ids_to_delete {
+        body.push(json!({ "index": { "_id": deletion_id(id, &pubkey) } }).into());
+        body.push(json!({ "created_at": event.created_at.as_u64() }).into());
+    }
+    let res = es_client
+        .bulk(BulkParts::Index(&deletions_index(index_alias_name)))
+        .body(body)
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to record deletions; received {}, {}",
+            status_code,
+            body
+        ));
+    }
+    Ok((pubkey, ids_to_delete))
+}
+
+/// Deletes the referred events of the author and records the deletion, so that the events
+/// are not indexed when they arrive after the deletion.
+pub async fn handle_deletion_event(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    event: &Event,
+) -> anyhow::Result<()> {
+    log::info!("deletion event: {}", event.as_json());
+    let (pubkey, ids_to_delete) = record_deletions(es_client, index_alias_name, event).await?;
+    log::info!("ids to delete: {:?}", ids_to_delete);
+    if ids_to_delete.is_empty() {
+        return Ok(());
+    }
+
+    delete_events(es_client, index_alias_name, &pubkey, &ids_to_delete).await
+}
+
+/// Deletes the events `ids_to_delete` of the author `pubkey` from all the indices of the alias.
+pub async fn delete_events(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    pubkey: &str,
+    ids_to_delete: &[String],
+) -> anyhow::Result<()> {
+    let res = es_client
+        .delete_by_query(DeleteByQueryParts::Index(&[index_alias_name]))
+        .body(json!({
+            "query": {
+                "bool": {
+                    "must": [
+                        {
+                            "bool": {
+                                "should": [
+                                    // indices created before the keyword subfield
+                                    { "terms": { "_id": ids_to_delete } },
+                                    // documents of the replaceable index have other ids
+                                    { "terms": { "event.id.keyword": ids_to_delete } }
+                                ],
+                                "minimum_should_match": 1
+                            }
+                        },
+                        author_condition(pubkey)
+                    ]
+                }
+            }
+        }))
+        .send()
+        .await?;
+
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        error!("failed to delete; received {}, {}", status_code, body);
+        return Err(anyhow::anyhow!("failed to delete"));
+    }
+
+    let response_body = res.json::<Value>().await?;
+    info!(
+        "delete event: deleted {} event(s) of for pubkey {}",
+        response_body["deleted"], pubkey,
+    );
+
+    Ok(())
+}
+
+/// Returns true when `event` has been deleted by its author, or its signer if delegated, before
+/// it arrived.
+pub async fn is_deleted(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    event: &Event,
+) -> anyhow::Result<bool> {
+    let (author, signer) = (author(event), event.pubkey.to_string());
+    if is_recorded(es_client, index_alias_name, &event.id.to_hex(), &author).await? {
+        return Ok(true);
+    }
+    if author != signer {
+        return is_recorded(es_client, index_alias_name, &event.id.to_hex(), &signer).await;
+    }
+    Ok(false)
+}
+
+async fn is_recorded(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    event_id: &str,
+    pubkey: &str,
+) -> anyhow::Result<bool> {
+    let id = deletion_id(event_id, pubkey);
+    let res = es_client
+        .get(GetParts::IndexId(&deletions_index(index_alias_name), &id))
+        .send()
+        .await?;
+    match res.status_code().as_u16() {
+        404 => Ok(false),
+        status if (200..300).contains(&status) => Ok(res.json::<Value>().await?["found"] == true),
+        status => Err(anyhow::anyhow!("failed to look up deletions: {}", status)),
+    }
+}
+
+/// Forgets deletions older than `ttl_days`, whose events can no longer be indexed.
+pub async fn purge_deletions(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    ttl_days: u64,
+) -> anyhow::Result<()> {
+    let before = chrono::Utc::now().timestamp() - ttl_days as i64 * 24 * 60 * 60;
+    let res = es_client
+        .delete_by_query(DeleteByQueryParts::Index(&[deletions_index(
+            index_alias_name,
+        )
+        .as_str()]))
+        .body(json!({
+            "query": { "range": { "created_at": { "lt": before } } }
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to purge deletions; received {}, {}",
+            status_code,
+            body
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::prelude::{EventBuilder, EventId, Keys, Kind, Tag};
+
+    use crate::index::deletion::{deleted_ids, deletion_id};
+
+    #[test]
+    fn test_deleted_ids() {
+        let keys = Keys::generate();
+        let id = EventId::from_hex("a".repeat(64)).unwrap();
+        let event = EventBuilder::new(
+            Kind::EventDeletion,
+            "",
+            &[
+                Tag::Event(id, None, None),
+                Tag::Hashtag("nostr".to_string()),
+            ],
+        )
+        .to_event(&keys)
+        .unwrap();
+        assert_eq!(deleted_ids(&event), vec!["a".repeat(64)]);
+    }
+
+    #[test]
+    fn test_deletion_id() {
+        assert_eq!(deletion_id("abc", "def"), "abc:def");
+    }
+}
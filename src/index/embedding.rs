@@ -0,0 +1,508 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use elasticsearch::{
+    http::{headers::HeaderMap, request::JsonBody, Method},
+    BulkParts, CountParts, Elasticsearch, SearchParts,
+};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+use crate::index::batch::failed_items;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmbeddingModel {
+    /// trained model deployed in the Elasticsearch cluster (e.g. an ONNX/PyTorch model imported with eland)
+    Elasticsearch { model_id: String },
+    /// HTTP endpoint accepting `{"inputs": [...]}` and returning one vector per input
+    Remote { url: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quantization {
+    /// 4 bytes per dimension
+    None,
+    /// float vectors quantized by Elasticsearch inside the HNSW index (`int8_hnsw`, ES 8.12+)
+    Int8,
+    /// vectors quantized to bytes before they are stored (`element_type: byte`)
+    Byte,
+}
+
+impl std::str::FromStr for Quantization {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Quantization::None),
+            "int8" => Ok(Quantization::Int8),
+            "byte" => Ok(Quantization::Byte),
+            _ => Err(anyhow::anyhow!("unknown quantization: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmbeddingConfig {
+    pub model: EmbeddingModel,
+    pub dims: usize,
+    pub batch_size: usize,
+    /// threads per allocation when starting an Elasticsearch model deployment
+    pub threads: usize,
+    pub poll_interval: Duration,
+    /// HNSW: number of neighbors per node
+    pub hnsw_m: usize,
+    /// HNSW: number of candidates considered while building the graph
+    pub hnsw_ef_construction: usize,
+    pub quantization: Quantization,
+    /// upper bound of the memory the HNSW index may take; see `fits_memory_budget`
+    pub memory_budget_bytes: Option<u64>,
+    /// whether vectors are indexed for kNN search; stored without an index otherwise
+    pub knn: bool,
+}
+
+impl EmbeddingConfig {
+    pub fn mapping(&self) -> Value {
+        let mut mapping = json!({
+            "type": "dense_vector",
+            "dims": self.dims,
+            "index": self.knn
+        });
+        if self.quantization == Quantization::Byte {
+            mapping["element_type"] = json!("byte");
+        }
+        // refused by Elasticsearch for vectors that are not indexed
+        if self.knn {
+            mapping["similarity"] = json!("cosine");
+            let index_type = match self.quantization {
+                Quantization::Int8 => "int8_hnsw",
+                _ => "hnsw",
+            };
+            mapping["index_options"] = json!({
+                "type": index_type,
+                "m": self.hnsw_m,
+                "ef_construction": self.hnsw_ef_construction
+            });
+        }
+        mapping
+    }
+
+    /// Approximate off-heap memory needed to keep the kNN index of `num_vectors` vectors in RAM.
+    ///
+    /// Follows the sizing guide of Elasticsearch: `num_vectors * (bytes_per_dimension * dims + 12)`
+    /// for float and byte vectors, and `num_vectors * (dims + 4)` for int8 quantized ones.
+    pub fn estimate_memory_bytes(&self, num_vectors: u64) -> u64 {
+        let dims = self.dims as u64;
+        match self.quantization {
+            Quantization::None => num_vectors * (4 * dims + 12),
+            Quantization::Int8 => num_vectors * (dims + 4),
+            Quantization::Byte => num_vectors * (dims + 12),
+        }
+    }
+
+    pub fn fits_memory_budget(&self, num_vectors: u64) -> bool {
+        match self.memory_budget_bytes {
+            Some(budget) => self.estimate_memory_bytes(num_vectors) <= budget,
+            None => true,
+        }
+    }
+
+    /// Converts a model output into the representation stored in the `embedding` field.
+    pub fn to_stored_vector(&self, vector: Vec<f32>) -> Value {
+        match self.quantization {
+            Quantization::Byte => json!(quantize_to_bytes(&vector)),
+            _ => json!(vector),
+        }
+    }
+}
+
+/// Counts the documents that would have embeddings, i.e. all documents under the alias.
+pub async fn count_documents(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+) -> anyhow::Result<u64> {
+    let res = es_client
+        .count(CountParts::Index(&[index_alias_name]))
+        .send()
+        .await?;
+    if res.status_code().as_u16() == 404 {
+        // no index has been created yet
+        return Ok(0);
+    }
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to count documents: {} {}",
+            status_code,
+            body
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    Ok(body["count"].as_u64().unwrap_or(0))
+}
+
+/// Scales a normalized vector into `[-127, 127]`.
+pub fn quantize_to_bytes(vector: &[f32]) -> Vec<i8> {
+    vector
+        .iter()
+        .map(|x| (x * 127.0).round().clamp(-127.0, 127.0) as i8)
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct Embedder {
+    es_client: Elasticsearch,
+    http_client: reqwest::Client,
+    pub config: EmbeddingConfig,
+}
+
+impl Embedder {
+    pub fn new(es_client: Elasticsearch, config: EmbeddingConfig) -> Self {
+        Embedder {
+            es_client,
+            http_client: reqwest::Client::new(),
+            config,
+        }
+    }
+
+    /// Starts the deployment of the Elasticsearch model; a no-op for remote models.
+    pub async fn prepare(&self) -> anyhow::Result<()> {
+        let model_id = match &self.config.model {
+            EmbeddingModel::Elasticsearch { model_id } => model_id,
+            EmbeddingModel::Remote { .. } => return Ok(()),
+        };
+        let path = format!(
+            "/_ml/trained_models/{}/deployment/_start?wait_for=started&threads_per_allocation={}",
+            model_id, self.config.threads
+        );
+        let res = self
+            .es_client
+            .send(
+                Method::Post,
+                &path,
+                HeaderMap::new(),
+                None::<&()>,
+                None::<JsonBody<Value>>,
+                None,
+            )
+            .await?;
+        // 409: the deployment has already been started
+        if !res.status_code().is_success() && res.status_code().as_u16() != 409 {
+            let status_code = res.status_code();
+            let body = res.text().await?;
+            return Err(anyhow::anyhow!(
+                "failed to start model deployment {}: {} {}",
+                model_id,
+                status_code,
+                body
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn embed(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let vectors = match &self.config.model {
+            EmbeddingModel::Elasticsearch { model_id } => {
+                let path = format!("/_ml/trained_models/{}/_infer", model_id);
+                let docs = texts
+                    .iter()
+                    .map(|text| json!({ "text_field": text }))
+                    .collect::<Vec<_>>();
+                let res = self
+                    .es_client
+                    .send(
+                        Method::Post,
+                        &path,
+                        HeaderMap::new(),
+                        None::<&()>,
+                        Some(JsonBody::new(json!({ "docs": docs }))),
+                        None,
+                    )
+                    .await?;
+                if !res.status_code().is_success() {
+                    let status_code = res.status_code();
+                    let body = res.text().await?;
+                    return Err(anyhow::anyhow!(
+                        "inference failed: {} {}",
+                        status_code,
+                        body
+                    ));
+                }
+                let body = res.json::<Value>().await?;
+                body["inference_results"]
+                    .as_array()
+                    .unwrap_or(&vec![])
+                    .iter()
+                    .map(|r| serde_json::from_value::<Vec<f32>>(r["predicted_value"].clone()))
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            EmbeddingModel::Remote { url } => {
+                let res = self
+                    .http_client
+                    .post(url)
+                    .json(&json!({ "inputs": texts }))
+                    .send()
+                    .await?;
+                if !res.status().is_success() {
+                    let status_code = res.status();
+                    let body = res.text().await?;
+                    return Err(anyhow::anyhow!(
+                        "inference failed: {} {}",
+                        status_code,
+                        body
+                    ));
+                }
+                res.json::<Vec<Vec<f32>>>().await?
+            }
+        };
+
+        if vectors.len() != texts.len() {
+            return Err(anyhow::anyhow!(
+                "expected {} vectors, got {}",
+                texts.len(),
+                vectors.len()
+            ));
+        }
+        if let Some(v) = vectors.iter().find(|v| v.len() != self.config.dims) {
+            return Err(anyhow::anyhow!(
+                "expected vectors of {} dimensions, got {}",
+                self.config.dims,
+                v.len()
+            ));
+        }
+        Ok(vectors)
+    }
+
+    /// Embeds one batch of documents lacking an embedding and returns the number of documents
+    /// fetched and of those updated.
+    pub async fn embed_batch(
+        &self,
+        index_alias_name: &str,
+        indexed_after: Option<DateTime<Utc>>,
+    ) -> anyhow::Result<(usize, usize)> {
+        let mut filter = vec![json!({ "exists": { "field": "text" } })];
+        if let Some(indexed_after) = indexed_after {
+            filter.push(json!({
+                "range": {
+                    "timestamp": {
+                        "gte": indexed_after.to_rfc3339()
+                    }
+                }
+            }));
+        }
+        let res = self
+            .es_client
+            .search(SearchParts::Index(&[index_alias_name]))
+            .body(json!({
+                "query": {
+                    "bool": {
+                        "filter": filter,
+                        "must_not": [
+                            { "exists": { "field": "embedding" } }
+                        ]
+                    }
+                },
+                "_source": ["text"],
+                "sort": [{ "timestamp": { "order": "desc" } }]
+            }))
+            .size(self.config.batch_size as i64)
+            .send()
+            .await?;
+        if !res.status_code().is_success() {
+            let status_code = res.status_code();
+            let body = res.text().await?;
+            return Err(anyhow::anyhow!(
+                "failed to search documents to embed: {} {}",
+                status_code,
+                body
+            ));
+        }
+        let body = res.json::<Value>().await?;
+        let hits = body["hits"]["hits"].as_array().cloned().unwrap_or_default();
+        if hits.is_empty() {
+            return Ok((0, 0));
+        }
+
+        let texts = hits
+            .iter()
+            .map(|hit| {
+                hit["_source"]["text"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string()
+            })
+            .collect::<Vec<_>>();
+        let vectors = self.embed(&texts).await?;
+
+        let mut ops: Vec<JsonBody<Value>> = Vec::with_capacity(hits.len() * 2);
+        for (hit, vector) in hits.iter().zip(vectors) {
+            ops.push(JsonBody::new(json!({
+                "update": { "_index": hit["_index"], "_id": hit["_id"] }
+            })));
+            let vector = self.config.to_stored_vector(vector);
+            ops.push(JsonBody::new(json!({ "doc": { "embedding": vector } })));
+        }
+        let res = self
+            .es_client
+            .bulk(BulkParts::None)
+            .body(ops)
+            .send()
+            .await?;
+        if !res.status_code().is_success() {
+            let status_code = res.status_code();
+            let body = res.text().await?;
+            return Err(anyhow::anyhow!(
+                "failed to update embeddings: {} {}",
+                status_code,
+                body
+            ));
+        }
+        let body = res.json::<Value>().await?;
+        let failed = failed_items(&body, &[]);
+        if !failed.is_empty() {
+            log::warn!("some embeddings failed to be stored: {:?}", failed);
+        }
+
+        Ok((hits.len(), hits.len() - failed.len()))
+    }
+}
+
+/// Embeds every document indexed without an embedding and returns their number.
+pub async fn backfill_embeddings(
+    embedder: &Embedder,
+    index_alias_name: &str,
+) -> anyhow::Result<usize> {
+    embedder.prepare().await?;
+    let mut total = 0;
+    loop {
+        let (fetched, updated) = embedder.embed_batch(index_alias_name, None).await?;
+        total += updated;
+        if fetched < embedder.config.batch_size {
+            return Ok(total);
+        }
+        // the documents failing would be fetched again and again
+        if updated == 0 {
+            return Err(anyhow::anyhow!(
+                "no embedding of a batch could be stored after {} document(s)",
+                total
+            ));
+        }
+        log::info!("embedded {} document(s)", total);
+    }
+}
+
+pub async fn spawn_embedding_worker(state: Arc<AppState>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let embedder = match &state.embedder {
+            Some(embedder) => embedder,
+            None => return,
+        };
+        if let Err(e) = embedder.prepare().await {
+            log::error!("error preparing embedding model: {}", e);
+        }
+        // documents indexed before are embedded by `searchnos backfill`
+        let indexed_after = Some(Utc::now());
+        loop {
+            match embedder
+                .embed_batch(&state.index_alias_name, indexed_after)
+                .await
+            {
+                // more documents may be waiting, unless those of the batch failed
+                Ok((fetched, updated))
+                    if fetched >= embedder.config.batch_size && updated == fetched =>
+                {
+                    continue
+                }
+                Ok((_, updated)) => {
+                    if updated > 0 {
+                        log::info!("embedded {} document(s)", updated);
+                    }
+                }
+                Err(e) => {
+                    log::error!("error embedding documents: {}", e);
+                }
+            }
+            tokio::time::sleep(embedder.config.poll_interval).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::index::embedding::{
+        quantize_to_bytes, EmbeddingConfig, EmbeddingModel, Quantization,
+    };
+
+    fn config(quantization: Quantization, memory_budget_bytes: Option<u64>) -> EmbeddingConfig {
+        EmbeddingConfig {
+            model: EmbeddingModel::Remote {
+                url: "http://localhost:8080/embed".to_string(),
+            },
+            dims: 384,
+            batch_size: 32,
+            threads: 1,
+            poll_interval: Duration::from_secs(10),
+            hnsw_m: 16,
+            hnsw_ef_construction: 100,
+            quantization,
+            memory_budget_bytes,
+            knn: true,
+        }
+    }
+
+    #[test]
+    fn test_estimate_memory_bytes() {
+        assert_eq!(
+            config(Quantization::None, None).estimate_memory_bytes(1_000_000),
+            1_000_000 * (4 * 384 + 12)
+        );
+        assert_eq!(
+            config(Quantization::Int8, None).estimate_memory_bytes(1_000_000),
+            1_000_000 * (384 + 4)
+        );
+        assert_eq!(
+            config(Quantization::Byte, None).estimate_memory_bytes(1_000_000),
+            1_000_000 * (384 + 12)
+        );
+    }
+
+    #[test]
+    fn test_fits_memory_budget() {
+        let budget = Some(1024 * 1024 * 1024);
+        assert!(config(Quantization::None, None).fits_memory_budget(u32::MAX as u64));
+        assert!(config(Quantization::None, budget).fits_memory_budget(500_000));
+        assert!(!config(Quantization::None, budget).fits_memory_budget(1_000_000));
+        assert!(config(Quantization::Int8, budget).fits_memory_budget(1_000_000));
+    }
+
+    #[test]
+    fn test_mapping() {
+        let mapping = config(Quantization::Byte, None).mapping();
+        assert_eq!(mapping["element_type"], "byte");
+        assert_eq!(mapping["index_options"]["type"], "hnsw");
+        assert_eq!(mapping["index_options"]["m"], 16);
+
+        let mapping = config(Quantization::Int8, None).mapping();
+        assert!(mapping.get("element_type").is_none());
+        assert_eq!(mapping["index_options"]["type"], "int8_hnsw");
+
+        let mut c = config(Quantization::None, None);
+        c.knn = false;
+        assert_eq!(c.mapping()["index"], false);
+        assert!(c.mapping().get("index_options").is_none());
+        assert!(c.mapping().get("similarity").is_none());
+        c.knn = true;
+        assert_eq!(c.mapping()["similarity"], "cosine");
+    }
+
+    #[test]
+    fn test_quantize_to_bytes() {
+        assert_eq!(
+            quantize_to_bytes(&[0.0, 1.0, -1.0, 0.5, 2.0]),
+            vec![0, 127, -127, 64, 127]
+        );
+    }
+}
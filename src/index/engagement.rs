@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::async_trait;
+use elasticsearch::params::Conflicts;
+use elasticsearch::UpdateByQueryParts;
+use nostr_sdk::Event;
+use serde_json::json;
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+use crate::index::batch::{requeue, spawn_flusher, Flush};
+
+const REPOST_KIND: u32 = 6;
+const REACTION_KIND: u32 = 7;
+
+pub fn is_engagement_event(event: &Event) -> bool {
+    matches!(event.kind.as_u32(), REPOST_KIND | REACTION_KIND)
+}
+
+/// Event reacted to or reposted; the last `e` tag per NIP-18/NIP-25.
+pub(crate) fn target(event: &Event) -> Option<String> {
+    event.tags.iter().rev().find_map(|tag| {
+        let tag = tag.as_vec();
+        match (tag.first().map(|s| s.as_str()), tag.get(1)) {
+            (Some("e"), Some(id)) if id.len() == 64 => Some(id.to_lowercase()),
+            _ => None,
+        }
+    })
+}
+
+/// Reaction (kind 7) and repost (kind 6) counts, accumulated in memory and periodically
+/// added to `engagement` of the target documents.
+///
+/// Reactions to events that are not indexed yet are not counted.
+#[derive(Debug, Default)]
+pub struct EngagementCounter {
+    /// event id -> (reactions, reposts)
+    pending: Mutex<HashMap<String, (u64, u64)>>,
+}
+
+impl EngagementCounter {
+    pub fn record(&self, event: &Event) {
+        let target = match target(event) {
+            Some(target) => target,
+            None => return,
+        };
+        let mut pending = self.pending.lock().unwrap();
+        let (reactions, reposts) = pending.entry(target).or_default();
+        if event.kind.as_u32() == REACTION_KIND {
+            *reactions += 1;
+        } else {
+            *reposts += 1;
+        }
+    }
+
+    fn take(&self) -> HashMap<String, (u64, u64)> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+
+    /// Adds back counts that failed to be added, to be retried at the next flush.
+    fn requeue(&self, counts: HashMap<String, (u64, u64)>) {
+        requeue(&self.pending, counts, |recorded, (reactions, reposts)| {
+            recorded.0 += reactions;
+            recorded.1 += reposts;
+        });
+    }
+}
+
+#[async_trait]
+impl Flush for EngagementCounter {
+    async fn flush(&self, state: &AppState) -> anyhow::Result<()> {
+        let pending = self.take();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let res = add_counts(state, &pending).await;
+        if res.is_err() {
+            self.requeue(pending);
+        }
+        res
+    }
+}
+
+async fn add_counts(state: &AppState, pending: &HashMap<String, (u64, u64)>) -> anyhow::Result<()> {
+    let ids = pending.keys().cloned().collect::<Vec<_>>();
+    let counts = pending
+        .iter()
+        .map(|(id, (reactions, reposts))| (id.clone(), json!([reactions, reposts])))
+        .collect::<serde_json::Map<_, _>>();
+    let res = state
+        .es_client
+        .update_by_query(UpdateByQueryParts::Index(&[state.index_alias_name.as_str()]))
+        .conflicts(Conflicts::Proceed)
+        .body(json!({
+            "query": {
+                "terms": {
+                    "event.id": ids
+                }
+            },
+            "script": {
+                "source": "if (ctx._source.engagement == null) { ctx._source.engagement = ['reactions': 0, 'reposts': 0]; } def c = params.counts[ctx._source.event.id]; ctx._source.engagement.reactions += c[0]; ctx._source.engagement.reposts += c[1];",
+                "params": {
+                    "counts": counts
+                }
+            }
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to update engagement: {} {}",
+            status_code,
+            body
+        ));
+    }
+    let body = res.json::<serde_json::Value>().await?;
+    if let Some(failures) = body["failures"].as_array().filter(|f| !f.is_empty()) {
+        // the counts of the documents updated are not added again
+        log::warn!("failed to update engagement of some events: {:?}", failures);
+    }
+    log::info!(
+        "updated engagement of {} of {} event(s)",
+        body["updated"],
+        ids.len()
+    );
+    Ok(())
+}
+
+pub fn spawn_engagement_flusher(state: Arc<AppState>, interval: Duration) -> JoinHandle<()> {
+    spawn_flusher(state, interval, |state| {
+        state
+            .engagement
+            .as_ref()
+            .map(|counter| counter as &dyn Flush)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+
+    use crate::index::engagement::{is_engagement_event, EngagementCounter};
+
+    #[test]
+    fn test_record() {
+        let keys = Keys::generate();
+        let a = "a".repeat(64);
+        let b = "b".repeat(64);
+        let event = |kind: u64, tags: Vec<Vec<&str>>| {
+            let tags = tags
+                .into_iter()
+                .map(|t| Tag::parse(t).unwrap())
+                .collect::<Vec<_>>();
+            EventBuilder::new(Kind::from(kind), "+", &tags)
+                .to_event(&keys)
+                .unwrap()
+        };
+        let counter = EngagementCounter::default();
+        // the last e tag is the target
+        let reaction = event(7, vec![vec!["e", &b], vec!["e", &a], vec!["p", &b]]);
+        assert!(is_engagement_event(&reaction));
+        counter.record(&reaction);
+        counter.record(&event(7, vec![vec!["e", &a]]));
+        counter.record(&event(6, vec![vec!["e", &a]]));
+        counter.record(&event(6, vec![vec!["e", &b]]));
+        counter.record(&event(7, vec![]));
+        assert!(!is_engagement_event(&event(1, vec![])));
+
+        let counts = counter.take();
+        assert_eq!(counts[&a], (2, 1));
+        assert_eq!(counts[&b], (0, 1));
+        assert!(counter.take().is_empty());
+    }
+}
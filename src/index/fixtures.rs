@@ -0,0 +1,299 @@
+//! Representative events as seen on public relays, for tests of the extractors.
+
+use nostr_sdk::prelude::TagKind;
+use nostr_sdk::{Event, EventBuilder, Keys, Kind, Tag};
+
+pub const EVENT_ID: &str = "5c83da77af1dec6d7289834998ad7aafbd9e2191396d75ec3cc27f5a77226f36";
+pub const PUBKEY: &str = "82341f882b6eabcd2ba7f1ef90aad961cf074af15b9ef44a09f9d2a8fbfbe6a2";
+
+/// An event by its kind, content and tags; ids, pubkeys and signatures are generated.
+pub struct Fixture {
+    pub name: &'static str,
+    pub kind: u64,
+    pub content: String,
+    pub tags: Vec<Vec<String>>,
+}
+
+fn fixture(name: &'static str, kind: u64, content: &str, tags: &[&[&str]]) -> Fixture {
+    Fixture {
+        name,
+        kind,
+        content: content.to_string(),
+        tags: tags
+            .iter()
+            .map(|tag| tag.iter().map(|value| value.to_string()).collect())
+            .collect(),
+    }
+}
+
+pub fn fixtures() -> Vec<Fixture> {
+    let mut mass_mention = fixture("mass_mention", 1, "gm", &[]);
+    // spam mentioning thousands of pubkeys
+    mass_mention.tags = (0..2000)
+        .map(|i| vec!["p".to_string(), format!("{:064x}", i)])
+        .collect();
+
+    vec![
+        fixture(
+            "note_en",
+            1,
+            "Just set up my own #nostr relay, it was easier than I thought!",
+            &[
+                &["t", "nostr"],
+                &["e", EVENT_ID, "wss://relay.damus.io", "root"],
+                &["p", PUBKEY],
+            ],
+        ),
+        fixture(
+            "note_ja",
+            1,
+            "今日は良い天気ですね。ノストラを始めました",
+            &[],
+        ),
+        fixture("empty_note", 1, "", &[]),
+        // no letters for language detection
+        fixture("emoji_only", 1, "🤙🔥🫡", &[]),
+        fixture(
+            "reply_uppercase_refs",
+            1,
+            "+1",
+            &[&["e", &EVENT_ID.to_uppercase()], &["e", EVENT_ID]],
+        ),
+        fixture(
+            "metadata",
+            0,
+            r#"{"name":"jack","about":"bitcoin & chill","nip05":"Jack@Cash.App","picture":"https://example.com/jack.jpg"}"#,
+            &[],
+        ),
+        fixture(
+            "metadata_mixed_types",
+            0,
+            r#"{"name":"bot","displayName":"News Bot","bot":true,"lud16":"News@Getalby.com"}"#,
+            &[],
+        ),
+        fixture(
+            "article",
+            30023,
+            "# Running a relay\n\nStart with a small VPS.",
+            &[
+                &["d", "running-a-relay"],
+                &["title", "Running a relay"],
+                &["summary", "Notes from a year of operating one"],
+                &["published_at", "1700000000"],
+                &["t", "relays"],
+            ],
+        ),
+        fixture(
+            "content_warning",
+            1,
+            "the ending of the show was",
+            &[&["content-warning", " spoilers "], &["t", "tv"]],
+        ),
+        fixture(
+            "malformed_tags",
+            1,
+            "tags from a buggy client",
+            &[
+                &["e"],
+                &["p", "npub1notahexkey"],
+                &["t", ""],
+                &["client", "damus"],
+                &["emoji"],
+            ],
+        ),
+        fixture(
+            "invalid_delegation",
+            1,
+            "delegated?",
+            &[&["delegation", PUBKEY, "kind=1", &"0".repeat(128)]],
+        ),
+        fixture(
+            "deletion",
+            5,
+            "posted by mistake",
+            &[&["e", EVENT_ID], &["k", "1"]],
+        ),
+        fixture(
+            "channel_message",
+            42,
+            "anyone here running strfry?",
+            &[&["e", EVENT_ID, "wss://relay.example.com", "root"]],
+        ),
+        mass_mention,
+    ]
+}
+
+impl Fixture {
+    /// Signs the fixture with a new key.
+    pub fn event(&self) -> Event {
+        let tags = self
+            .tags
+            .iter()
+            .map(|tag| {
+                // as clients would receive them, even if they do not parse as standard tags
+                Tag::parse(tag.clone()).unwrap_or_else(|_| {
+                    Tag::Generic(TagKind::Custom(tag[0].clone()), tag[1..].to_vec())
+                })
+            })
+            .collect::<Vec<_>>();
+        EventBuilder::new(Kind::from(self.kind), &self.content, &tags)
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+}
+
+pub fn fixture_event(name: &str) -> Event {
+    fixtures()
+        .into_iter()
+        .find(|fixture| fixture.name == name)
+        .unwrap_or_else(|| panic!("no fixture {}", name))
+        .event()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::index::fixtures::{fixture_event, fixtures, EVENT_ID, PUBKEY};
+    use crate::index::handlers::Document;
+    use crate::index::text::Extractors;
+
+    /// The document of a fixture, with the values of each tag sorted.
+    fn document(name: &str) -> Value {
+        let event = fixture_event(name);
+        let doc = Document::new(&event, event.clone(), None, None, &Extractors::default());
+        let mut doc = serde_json::to_value(doc).unwrap();
+        for values in doc["tags"].as_object_mut().unwrap().values_mut() {
+            values
+                .as_array_mut()
+                .unwrap()
+                .sort_by_key(|v| v.to_string());
+        }
+        doc
+    }
+
+    #[test]
+    fn test_fixtures_extract() {
+        // fields left out of an expectation are not checked; `null` ones must be absent
+        let cases = vec![
+            (
+                "note_en",
+                json!({
+                    "text": "Just set up my own #nostr relay, it was easier than I thought!",
+                    "tags": { "t": ["nostr"], "e": [EVENT_ID], "p": [PUBKEY] },
+                    "refs": { "events": [EVENT_ID], "pubkeys": [PUBKEY] },
+                    "identifier_tag": "",
+                    "sensitive": false,
+                    "profile": null,
+                    "content_warning": null,
+                }),
+            ),
+            (
+                "note_ja",
+                json!({
+                    "text": "今日は良い天気ですね。ノストラを始めました",
+                    "tags": {},
+                    "refs": null,
+                }),
+            ),
+            (
+                "empty_note",
+                json!({ "text": "", "tags": {}, "profile": null, "sensitive": false }),
+            ),
+            (
+                "emoji_only",
+                json!({ "text": "🤙🔥🫡", "tags": {}, "refs": null }),
+            ),
+            (
+                "reply_uppercase_refs",
+                json!({
+                    "refs": { "events": [EVENT_ID], "pubkeys": [] },
+                }),
+            ),
+            (
+                "metadata",
+                json!({
+                    "profile": {
+                        "name": "jack",
+                        "about": "bitcoin & chill",
+                        "nip05": "jack@cash.app",
+                    },
+                }),
+            ),
+            (
+                // non-string values fail the text extraction, but not that of the profile
+                "metadata_mixed_types",
+                json!({
+                    "text": "",
+                    "profile": {
+                        "name": "bot",
+                        "display_name": "News Bot",
+                        "lud16": "news@getalby.com",
+                    },
+                }),
+            ),
+            (
+                "article",
+                json!({
+                    "text": "# Running a relay\n\nStart with a small VPS. Running a relay Notes from a year of operating one",
+                    "identifier_tag": "running-a-relay",
+                    "tags": { "d": ["running-a-relay"], "t": ["relays"] },
+                }),
+            ),
+            (
+                "content_warning",
+                json!({
+                    "content_warning": "spoilers",
+                    "sensitive": true,
+                    "tags": { "t": ["tv"] },
+                }),
+            ),
+            (
+                "malformed_tags",
+                json!({
+                    "tags": { "p": ["npub1notahexkey"], "t": [""] },
+                    "refs": null,
+                    "identifier_tag": "",
+                }),
+            ),
+            ("invalid_delegation", json!({ "delegator": null })),
+            (
+                "deletion",
+                json!({
+                    "text": "posted by mistake",
+                    "tags": { "e": [EVENT_ID], "k": ["1"] },
+                }),
+            ),
+            (
+                "channel_message",
+                json!({
+                    "text": "anyone here running strfry?",
+                    "refs": { "events": [EVENT_ID], "pubkeys": [] },
+                }),
+            ),
+        ];
+        for (name, expected) in cases {
+            let doc = document(name);
+            for (field, value) in expected.as_object().unwrap() {
+                assert_eq!(&doc[field], value, "{}: {}", name, field);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fixtures_mass_mention() {
+        let doc = document("mass_mention");
+        assert_eq!(doc["tags"]["p"].as_array().unwrap().len(), 2000);
+        assert_eq!(doc["refs"]["pubkeys"].as_array().unwrap().len(), 2000);
+        assert_eq!(doc["text"], "gm");
+    }
+
+    #[test]
+    fn test_fixtures_unique() {
+        let mut names = fixtures().iter().map(|f| f.name).collect::<Vec<_>>();
+        let count = names.len();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), count);
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::{HashMap, HashSet};
+
+use elasticsearch::http::request::JsonBody;
+use elasticsearch::{BulkParts, Elasticsearch, GetParts, IndexParts, MgetParts};
+use nostr_sdk::Event;
+use serde_json::{json, Value};
+
+use crate::index::indexes::create_side_index;
+
+/// contact lists longer than this are truncated
+const MAX_CONTACTS: usize = 10_000;
+
+fn followers_index(index_alias_name: &str) -> String {
+    format!("searchnos-followers-{}", index_alias_name)
+}
+
+fn follows_index(index_alias_name: &str) -> String {
+    format!("searchnos-follows-{}", index_alias_name)
+}
+
+/// Pubkeys followed by a contact list (kind 3).
+fn contacts(event: &Event) -> HashSet<String> {
+    event
+        .tags
+        .iter()
+        .filter_map(|tag| {
+            let tag = tag.as_vec();
+            match (tag.first().map(|s| s.as_str()), tag.get(1)) {
+                (Some("p"), Some(pubkey)) if pubkey.len() == 64 => Some(pubkey.to_lowercase()),
+                _ => None,
+            }
+        })
+        .take(MAX_CONTACTS)
+        .collect()
+}
+
+/// Returns `(added, removed)` contacts.
+fn diff(old: &HashSet<String>, new: &HashSet<String>) -> (Vec<String>, Vec<String>) {
+    let mut added = new.difference(old).cloned().collect::<Vec<_>>();
+    let mut removed = old.difference(new).cloned().collect::<Vec<_>>();
+    added.sort();
+    removed.sort();
+    (added, removed)
+}
+
+/// Creates the side indices holding the latest contact list of each author and the
+/// approximate follower count of each pubkey.
+pub async fn create_follower_indices(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+) -> anyhow::Result<()> {
+    create_side_index(
+        es_client,
+        &follows_index(index_alias_name),
+        json!({
+            "dynamic": false,
+            "properties": { "created_at": { "type": "long" } }
+        }),
+    )
+    .await?;
+    create_side_index(
+        es_client,
+        &followers_index(index_alias_name),
+        json!({
+            "dynamic": false,
+            "properties": { "count": { "type": "long" } }
+        }),
+    )
+    .await
+}
+
+/// Updates follower counts with the difference from the previous contact list of the author.
+pub async fn handle_contact_list(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    event: &Event,
+) -> anyhow::Result<()> {
+    let pubkey = event.pubkey.to_string();
+    let follows_index = follows_index(index_alias_name);
+    let followers_index = followers_index(index_alias_name);
+
+    let res = es_client
+        .get(GetParts::IndexId(&follows_index, &pubkey))
+        .send()
+        .await?;
+    let previous = if res.status_code().is_success() {
+        res.json::<Value>().await?["_source"].clone()
+    } else {
+        Value::Null
+    };
+    if previous["created_at"].as_u64().unwrap_or(0) >= event.created_at.as_u64() {
+        return Ok(());
+    }
+    let old = previous["follows"]
+        .as_array()
+        .unwrap_or(&vec![])
+        .iter()
+        .filter_map(|v| v.as_str().map(|s| s.to_string()))
+        .collect::<HashSet<_>>();
+    let new = contacts(event);
+    let (added, removed) = diff(&old, &new);
+
+    let mut body: Vec<JsonBody<Value>> = vec![];
+    for (pubkeys, n) in [(&added, 1), (&removed, -1)] {
+        for followed in pubkeys {
+            body.push(json!({ "update": { "_index": followers_index, "_id": followed } }).into());
+            body.push(
+                json!({
+                    "script": {
+                        "source": "ctx._source.count = Math.max(0, ctx._source.count + params.n)",
+                        "params": { "n": n }
+                    },
+                    "upsert": { "count": std::cmp::max(n, 0) }
+                })
+                .into(),
+            );
+        }
+    }
+    if !body.is_empty() {
+        let res = es_client.bulk(BulkParts::None).body(body).send().await?;
+        if !res.status_code().is_success() {
+            return Err(anyhow::anyhow!(
+                "failed to update follower counts: {}",
+                res.status_code()
+            ));
+        }
+    }
+
+    let res = es_client
+        .index(IndexParts::IndexId(&follows_index, &pubkey))
+        .body(json!({
+            "created_at": event.created_at.as_u64(),
+            "follows": new.into_iter().collect::<Vec<_>>()
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to store contact list: {}",
+            res.status_code()
+        ));
+    }
+    log::info!(
+        "contact list of {}: +{} -{}",
+        pubkey,
+        added.len(),
+        removed.len()
+    );
+    Ok(())
+}
+
+/// Approximate follower counts; unknown pubkeys are omitted.
+pub async fn follower_counts(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    pubkeys: &[String],
+) -> anyhow::Result<HashMap<String, u64>> {
+    if pubkeys.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let followers_index = followers_index(index_alias_name);
+    let res = es_client
+        .mget(MgetParts::Index(&followers_index))
+        .body(json!({ "ids": pubkeys }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to get follower counts: {}",
+            res.status_code()
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    Ok(body["docs"]
+        .as_array()
+        .unwrap_or(&vec![])
+        .iter()
+        .filter_map(|doc| {
+            Some((
+                doc["_id"].as_str()?.to_string(),
+                doc["_source"]["count"].as_u64()?,
+            ))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+
+    use crate::index::followers::{contacts, diff};
+
+    #[test]
+    fn test_contacts() {
+        let a = Keys::generate().public_key().to_string();
+        let b = Keys::generate().public_key().to_string();
+        let event = EventBuilder::new(
+            Kind::ContactList,
+            "",
+            &[
+                Tag::parse(vec!["p", &a]).unwrap(),
+                Tag::parse(vec!["p", &b]).unwrap(),
+                Tag::parse(vec!["p", &a]).unwrap(),
+                Tag::parse(vec!["p", "invalid"]).unwrap(),
+                Tag::Hashtag("nostr".to_string()),
+            ],
+        )
+        .to_event(&Keys::generate())
+        .unwrap();
+        assert_eq!(contacts(&event), HashSet::from([a, b]));
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = HashSet::from(["a".to_string(), "b".to_string()]);
+        let new = HashSet::from(["b".to_string(), "c".to_string(), "d".to_string()]);
+        assert_eq!(
+            diff(&old, &new),
+            (
+                vec!["c".to_string(), "d".to_string()],
+                vec!["a".to_string()]
+            )
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use elasticsearch::indices::{IndicesGetSettingsParts, IndicesPutSettingsParts};
+use elasticsearch::Elasticsearch;
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+use crate::index::indexes::{check_index_date, SkipReason};
+use crate::index::lock::try_lock;
+use crate::index::tiering::force_merge;
+
+const FORCE_MERGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Merging of the dated indices down to one segment once they stop receiving most writes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForceMergeConfig {
+    /// age of the index date after which indices are merged
+    pub after_days: u64,
+    /// block writes to the merged indices
+    pub read_only: bool,
+}
+
+impl ForceMergeConfig {
+    pub fn is_due(&self, index_name: &str, current_time: &DateTime<Utc>) -> bool {
+        check_index_date(index_name, current_time, Some(self.after_days), 0)
+            == Err(SkipReason::TooOld)
+    }
+}
+
+/// Merges the due dated indices of `index_name_prefix` not merged yet, as far as known from
+/// their write block or `merged`.
+pub async fn merge_old_indices(
+    es_client: &Elasticsearch,
+    index_name_prefix: &str,
+    config: &ForceMergeConfig,
+    merged: &mut HashSet<String>,
+) -> anyhow::Result<()> {
+    let pattern = format!("{}-*", index_name_prefix);
+    let res = es_client
+        .indices()
+        .get_settings(IndicesGetSettingsParts::Index(&[pattern.as_str()]))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to get index settings: {} {}",
+            status_code,
+            body
+        ));
+    }
+    let current_time = Utc::now();
+    let indices = res.json::<HashMap<String, Value>>().await?;
+    let mut names = indices
+        .into_iter()
+        .filter(|(name, info)| {
+            config.is_due(name, &current_time)
+                && !merged.contains(name)
+                && info["settings"]["index"]["blocks"]["write"] != "true"
+        })
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        log::info!("force-merging index {}", name);
+        if config.read_only {
+            // blocked first, so that no segment is written after the merge
+            let res = es_client
+                .indices()
+                .put_settings(IndicesPutSettingsParts::Index(&[name.as_str()]))
+                .body(json!({ "index.blocks.write": true }))
+                .send()
+                .await?;
+            if !res.status_code().is_success() {
+                let status_code = res.status_code();
+                let body = res.text().await?;
+                return Err(anyhow::anyhow!(
+                    "failed to block writes to {}: {} {}",
+                    name,
+                    status_code,
+                    body
+                ));
+            }
+        }
+        force_merge(es_client, &name).await?;
+        merged.insert(name);
+    }
+    Ok(())
+}
+
+pub fn spawn_force_merger(state: Arc<AppState>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // left to expire, so that one replica merges per interval
+        let lock_name = format!("force-merge-{}", state.index_alias_name);
+        let lock_ttl = FORCE_MERGE_INTERVAL - Duration::from_secs(5 * 60);
+        let mut merged = HashSet::new();
+        loop {
+            match try_lock(&state.es_client, &lock_name, lock_ttl).await {
+                Ok(Some(_)) => {
+                    if let Some(config) = &state.force_merge {
+                        let res = merge_old_indices(
+                            &state.es_client,
+                            &state.index_name_prefix,
+                            config,
+                            &mut merged,
+                        )
+                        .await;
+                        if let Err(e) = res {
+                            log::error!("failed to force-merge indices: {}", e);
+                        }
+                    }
+                }
+                Ok(None) => log::debug!("another replica merges {}", state.index_alias_name),
+                Err(e) => log::error!("failed to take the force-merge lock: {}", e),
+            }
+            tokio::time::sleep(FORCE_MERGE_INTERVAL).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::{DateTime, Utc};
+
+    use crate::index::force_merge::ForceMergeConfig;
+
+    #[test]
+    fn test_is_due() {
+        let config = ForceMergeConfig {
+            after_days: 2,
+            read_only: true,
+        };
+        let current_time = DateTime::<Utc>::from_str("2023-03-20T12:00:00Z").unwrap();
+        assert!(!config.is_due("nostr-2023.03.20", &current_time));
+        assert!(!config.is_due("nostr-2023.03.19", &current_time));
+        assert!(config.is_due("nostr-2023.03.18", &current_time));
+        assert!(!config.is_due("nostr-profiles", &current_time));
+    }
+}
@@ -0,0 +1,129 @@
+use nostr_sdk::Event;
+use serde::Serialize;
+
+const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+const EARTH_RADIUS_METERS: f64 = 6_371_008.8;
+
+/// `geo_point` of Elasticsearch.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct GeoPoint {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// Center of the cell of `geohash`, case-insensitive.
+pub fn decode_geohash(geohash: &str) -> Option<GeoPoint> {
+    if geohash.is_empty() || geohash.len() > 12 {
+        return None;
+    }
+    let (mut lat, mut lon) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut even = true;
+    for c in geohash.to_ascii_lowercase().bytes() {
+        let bits = BASE32.iter().position(|b| *b == c)?;
+        for shift in (0..5).rev() {
+            // bits alternate between longitude and latitude, starting with longitude
+            let range: &mut (f64, f64) = if even { &mut lon } else { &mut lat };
+            let mid = (range.0 + range.1) / 2.0;
+            if bits >> shift & 1 == 1 {
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            even = !even;
+        }
+    }
+    Some(GeoPoint {
+        lat: (lat.0 + lat.1) / 2.0,
+        lon: (lon.0 + lon.1) / 2.0,
+    })
+}
+
+/// Point of a geohash, or of `<lat>,<lon>` in degrees.
+pub fn parse_point(value: &str) -> Option<GeoPoint> {
+    match value.split_once(',') {
+        Some((lat, lon)) => {
+            let (lat, lon) = (
+                lat.trim().parse::<f64>().ok()?,
+                lon.trim().parse::<f64>().ok()?,
+            );
+            if (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon) {
+                Some(GeoPoint { lat, lon })
+            } else {
+                None
+            }
+        }
+        None => decode_geohash(value),
+    }
+}
+
+/// Meters of a distance like `500m`, `10km` or `3mi`.
+pub fn parse_distance(value: &str) -> Option<f64> {
+    let (number, factor) = if let Some(number) = value.strip_suffix("km") {
+        (number, 1000.0)
+    } else if let Some(number) = value.strip_suffix("mi") {
+        (number, 1609.344)
+    } else if let Some(number) = value.strip_suffix('m') {
+        (number, 1.0)
+    } else {
+        return None;
+    };
+    let number = number.parse::<f64>().ok()?;
+    if number.is_finite() && number > 0.0 {
+        Some(number * factor)
+    } else {
+        None
+    }
+}
+
+/// Great-circle distance in meters, like the `arc` distance of Elasticsearch.
+pub fn distance_meters(a: &GeoPoint, b: &GeoPoint) -> f64 {
+    let (lat_a, lat_b) = (a.lat.to_radians(), b.lat.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lon = (b.lon - a.lon).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * h.sqrt().asin()
+}
+
+/// Point of the first valid `g` tag of `event`.
+pub fn extract_geo(event: &Event) -> Option<GeoPoint> {
+    event.tags.iter().find_map(|tag| {
+        let tag = tag.as_vec();
+        match tag.as_slice() {
+            [name, geohash, ..] if name == "g" => decode_geohash(geohash.trim()),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::geo::{decode_geohash, distance_meters, parse_distance, parse_point};
+
+    #[test]
+    fn test_decode_geohash() {
+        let point = decode_geohash("xn76urx6").unwrap();
+        assert!((point.lat - 35.6812).abs() < 0.001);
+        assert!((point.lon - 139.7671).abs() < 0.001);
+        let point = decode_geohash("U4PRUYD").unwrap();
+        assert!((point.lat - 57.649).abs() < 0.01);
+        assert!((point.lon - 10.407).abs() < 0.01);
+        assert_eq!(decode_geohash(""), None);
+        assert_eq!(decode_geohash("xn7a"), None);
+    }
+
+    #[test]
+    fn test_distances() {
+        assert_eq!(parse_distance("500m"), Some(500.0));
+        assert_eq!(parse_distance("10km"), Some(10_000.0));
+        assert_eq!(parse_distance("2mi"), Some(3218.688));
+        assert_eq!(parse_distance("10"), None);
+        assert_eq!(parse_distance("-1km"), None);
+
+        let tokyo = parse_point("35.6812,139.7671").unwrap();
+        let osaka = parse_point("34.7025,135.4959").unwrap();
+        let km = distance_meters(&tokyo, &osaka) / 1000.0;
+        assert!((km - 403.0).abs() < 5.0, "{}", km);
+        assert_eq!(parse_point("91,0"), None);
+        assert!(parse_point("xn76").is_some());
+    }
+}
@@ -1,24 +1,131 @@
 use anyhow::Context;
-use chrono::Utc;
-use elasticsearch::{DeleteByQueryParts, Elasticsearch, IndexParts};
-use log::{error, info, warn};
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use elasticsearch::params::VersionType;
+use elasticsearch::{CountParts, DeleteByQueryParts, Elasticsearch, IndexParts};
+use log::{debug, error, info};
 use nostr_sdk::prelude::*;
 use nostr_sdk::Event;
+use searchnos_common::message::parse_forwarded_event;
 use serde::Serialize;
 use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Instant;
 
 use crate::app_state::AppState;
-use crate::index::indexes::{can_exist, index_name_for_event};
-use crate::index::text::extract_text;
+use crate::backend::{self, SearchBackend, WriteOutcome};
+use crate::index::chain::{EventContext, Flow, Stage};
+use crate::index::classified::{extract_classified, Classified};
+use crate::index::completion::remove_profile;
+use crate::index::content_warning::extract_content_warning;
+use crate::index::dead_letter::record_dead_letter;
+use crate::index::delegation::{author, author_condition, extract_delegator};
+use crate::index::deletion::{deleted_ids, handle_deletion_event, is_deleted};
+use crate::index::engagement::is_engagement_event;
+use crate::index::followers::handle_contact_list;
+use crate::index::geo::{extract_geo, GeoPoint};
+use crate::index::indexes::{
+    check_event_date, index_name_for_event, profiles_index_name, replaceable_index_name, SkipReason,
+};
+use crate::index::limits::EventLimits;
+use crate::index::media::{extract_media, Media};
+use crate::index::mentions::{strip_mentions, Mentions};
+use crate::index::occurrence::{extract_occurrence, Occurrence};
+use crate::index::profile::{extract_profile, Profile};
+use crate::index::protected::is_protected;
+use crate::index::reconcile::is_counted;
+use crate::index::refs::{extract_refs, Refs};
+use crate::index::text::Extractors;
+use crate::index::urls::extract_links;
+use crate::index::zaps::is_zap_receipt;
+use crate::metrics::Metrics;
 
 #[derive(Debug, Serialize)]
-struct Document {
+pub struct Document {
+    /// searchable copy of the event; `created_at` may be rounded
     event: Event,
+    /// the original event when `event` has been altered
+    #[serde(rename = "_raw", skip_serializing_if = "Option::is_none")]
+    raw: Option<Event>,
+    /// text without the NIP-19 strings, which are decoded into `mentions`
     text: String,
     tags: HashMap<String, HashSet<String>>,
     identifier_tag: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<Profile>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    classified: Option<Classified>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    occurrence: Option<Occurrence>,
+    /// decoded from the `g` geohash tag
+    #[serde(skip_serializing_if = "Option::is_none")]
+    geo: Option<GeoPoint>,
+    /// reason of the NIP-36 content warning
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_warning: Option<String>,
+    /// whether the event carries a NIP-36 content warning
+    sensitive: bool,
+    /// whether the event carries the NIP-70 `-` tag; such events are never returned
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    protected: bool,
+    #[serde(skip_serializing_if = "Refs::is_empty")]
+    refs: Refs,
+    #[serde(skip_serializing_if = "Mentions::is_empty")]
+    mentions: Mentions,
+    /// NIP-92 `imeta` attachments, or the file of a NIP-94 event
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    media: Vec<Media>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    urls: Vec<String>,
+    /// hosts of `urls` and their parent domains
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    domains: Vec<String>,
+    /// URLs in the content, including those beyond the ones stored
+    url_count: usize,
+    /// pubkey of the NIP-26 delegator
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delegator: Option<String>,
+    /// time of indexing, set by the ingest pipeline if it is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<DateTime<Utc>>,
+}
+
+impl Document {
+    /// Extracts the indexed fields of `event`, stored as `searchable_event`.
+    pub(crate) fn new(
+        event: &Event,
+        searchable_event: Event,
+        raw: Option<Event>,
+        timestamp: Option<DateTime<Utc>>,
+        extractors: &Extractors,
+    ) -> Self {
+        let content_warning = extract_content_warning(event);
+        let links = extract_links(event);
+        let (text, mentions) = strip_mentions(&extractors.extract(event));
+        Document {
+            event: searchable_event,
+            raw,
+            text,
+            tags: convert_tags(&event.tags),
+            identifier_tag: extract_identifier_tag(&event.tags),
+            profile: extract_profile(event),
+            classified: extract_classified(event),
+            occurrence: extract_occurrence(event),
+            geo: extract_geo(event),
+            sensitive: content_warning.is_some(),
+            protected: is_protected(event),
+            refs: extract_refs(event),
+            mentions,
+            media: extract_media(event),
+            urls: links.urls,
+            domains: links.domains,
+            url_count: links.count,
+            delegator: extract_delegator(event).map(|delegator| delegator.to_string()),
+            content_warning,
+            timestamp,
+        }
+    }
 }
 
 fn convert_tags(tags: &Vec<nostr_sdk::Tag>) -> HashMap<String, HashSet<String>> {
@@ -47,7 +154,7 @@ fn convert_tags(tags: &Vec<nostr_sdk::Tag>) -> HashMap<String, HashSet<String>>
     tag
 }
 
-fn is_replaceable_event(event: &Event) -> bool {
+pub(crate) fn is_replaceable_event(event: &Event) -> bool {
     match event.kind {
         Kind::Replaceable(_) => true,
         Kind::Metadata | Kind::ContactList | Kind::ChannelMetadata => true,
@@ -55,54 +162,144 @@ fn is_replaceable_event(event: &Event) -> bool {
     }
 }
 
-fn is_ephemeral_event(event: &Event) -> bool {
+pub(crate) fn is_ephemeral_event(event: &Event) -> bool {
     match event.kind {
         Kind::Ephemeral(_) => true,
         _ => false,
     }
 }
 
-fn is_parameterized_replaceable_event(event: &Event) -> bool {
+pub(crate) fn is_parameterized_replaceable_event(event: &Event) -> bool {
     match event.kind {
         Kind::ParameterizedReplaceable(_) => true,
         _ => false,
     }
 }
 
-async fn delete_replaceable_event(
+/// Versions `event` replaces; of versions created at the same time, the lowest id is kept.
+fn older_than(event: &Event, inclusive: bool) -> serde_json::Value {
+    if inclusive {
+        return json!({ "range": { "event.created_at": { "lte": event.created_at.to_string() } } });
+    }
+    json!({
+        "bool": {
+            "should": [
+                { "range": { "event.created_at": { "lt": event.created_at.to_string() } } },
+                {
+                    "bool": {
+                        "must": [
+                            { "term": { "event.created_at": event.created_at.to_string() } },
+                            { "range": { "event.id.keyword": { "gt": event.id.to_hex() } } }
+                        ]
+                    }
+                }
+            ],
+            "minimum_should_match": 1
+        }
+    })
+}
+
+/// Versions replacing `event`; the counterpart of `older_than`.
+fn newer_than(event: &Event, inclusive: bool) -> serde_json::Value {
+    if inclusive {
+        return json!({ "range": { "event.created_at": { "gt": event.created_at.to_string() } } });
+    }
+    json!({
+        "bool": {
+            "should": [
+                { "range": { "event.created_at": { "gt": event.created_at.to_string() } } },
+                {
+                    "bool": {
+                        "must": [
+                            { "term": { "event.created_at": event.created_at.to_string() } },
+                            { "range": { "event.id.keyword": { "lt": event.id.to_hex() } } }
+                        ]
+                    }
+                }
+            ],
+            "minimum_should_match": 1
+        }
+    })
+}
+
+/// Whether a version replacing the (parameterized) replaceable `event` is already indexed,
+/// as when versions arrive out of order.
+async fn has_newer_version(
     es_client: &Elasticsearch,
     alias_name: &str,
     event: &Event,
-) -> anyhow::Result<()> {
+    inclusive: bool,
+) -> anyhow::Result<bool> {
+    let mut conditions = vec![
+        author_condition(&author(event)),
+        json!({ "term": { "event.kind": event.kind } }),
+        newer_than(event, inclusive),
+    ];
+    if is_parameterized_replaceable_event(event) {
+        conditions.push(json!({
+            "term": { "identifier_tag": extract_identifier_tag(&event.tags) }
+        }));
+    }
     let res = es_client
-        .delete_by_query(DeleteByQueryParts::Index(&[alias_name]))
+        .count(CountParts::Index(&[alias_name]))
         .body(json!({
             "query": {
                 "bool": {
-                    "must": [
-                        {
-                            "term": {
-                                "event.pubkey": event.pubkey.to_string()
-                            }
-                        },
-                        {
-                            "term": {
-                                "event.kind": event.kind
-                            }
-                        },
-                        {
-                            "range": {
-                                "event.created_at": {
-                                    "lt": event.created_at.to_string()
-                                }
-                            }
-                        }
-                    ]
+                    "must": conditions,
+                    "must_not": { "term": { "event.id.keyword": event.id.to_hex() } }
                 }
             }
         }))
         .send()
         .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to look up newer versions; received {}, {}",
+            status_code,
+            body
+        ));
+    }
+    let body = res.json::<serde_json::Value>().await?;
+    Ok(body["count"].as_u64().unwrap_or(0) > 0)
+}
+
+/// Query of the versions the (parameterized) replaceable `event` replaces.
+pub(crate) fn replaced_versions(event: &Event, inclusive: bool) -> serde_json::Value {
+    let mut conditions = vec![
+        author_condition(&author(event)),
+        json!({ "term": { "event.kind": event.kind } }),
+        older_than(event, inclusive),
+    ];
+    if is_parameterized_replaceable_event(event) {
+        conditions.push(json!({
+            "term": { "identifier_tag": extract_identifier_tag(&event.tags) }
+        }));
+    }
+    json!({
+        "bool": {
+            "must": conditions,
+            "must_not": {
+                "term": {
+                    "event.id.keyword": event.id.to_hex()
+                }
+            }
+        }
+    })
+}
+
+async fn delete_replaceable_event(
+    es_client: &Elasticsearch,
+    alias_name: &str,
+    event: &Event,
+    inclusive: bool,
+) -> anyhow::Result<()> {
+    let res = es_client
+        .delete_by_query(DeleteByQueryParts::Index(&[alias_name]))
+        .body(json!({ "query": replaced_versions(event, inclusive) }))
+        .send()
+        .await?;
     if !res.status_code().is_success() {
         let status_code = res.status_code();
         let body = res.text().await?;
@@ -118,7 +315,24 @@ async fn delete_replaceable_event(
     Ok(())
 }
 
-fn extract_identifier_tag(tags: &Vec<Tag>) -> String {
+/// Id of the document of a (parameterized) replaceable event in the replaceable index, shared
+/// by all its versions: `<kind>:<pubkey>` or `<kind>:<pubkey>:<identifier>`.
+pub(crate) fn replaceable_id(event: &Event) -> Option<String> {
+    if is_replaceable_event(event) {
+        Some(format!("{}:{}", event.kind.as_u64(), author(event)))
+    } else if is_parameterized_replaceable_event(event) {
+        Some(format!(
+            "{}:{}:{}",
+            event.kind.as_u64(),
+            author(event),
+            extract_identifier_tag(&event.tags)
+        ))
+    } else {
+        None
+    }
+}
+
+pub(crate) fn extract_identifier_tag(tags: &Vec<Tag>) -> String {
     tags.iter()
         .find_map(|tag| {
             if let Tag::Identifier(tag) = tag {
@@ -134,40 +348,12 @@ async fn delete_parameterized_replaceable_event(
     es_client: &Elasticsearch,
     alias_name: &str,
     event: &Event,
+    inclusive: bool,
 ) -> anyhow::Result<()> {
     let identifier_tag = extract_identifier_tag(&event.tags);
     let res = es_client
         .delete_by_query(DeleteByQueryParts::Index(&[alias_name]))
-        .body(json!({
-            "query": {
-                "bool": {
-                    "must": [
-                        {
-                            "term": {
-                                "event.pubkey": event.pubkey.to_string()
-                            }
-                        },
-                        {
-                            "term": {
-                                "event.kind": event.kind
-                            }
-                        },
-                        {
-                            "range": {
-                                "event.created_at": {
-                                    "lt": event.created_at.to_string()
-                                }
-                            }
-                        },
-                        {
-                            "term": {
-                                "identifier_tag": identifier_tag
-                            }
-                        }
-                    ]
-                }
-            }
-        }))
+        .body(json!({ "query": replaced_versions(event, inclusive) }))
         .send()
         .await?;
 
@@ -187,139 +373,635 @@ async fn delete_parameterized_replaceable_event(
     Ok(())
 }
 
-pub async fn handle_update(state: Arc<AppState>, event: &Event) -> anyhow::Result<()> {
-    let index_name = index_name_for_event(&state.index_name_prefix, event)?;
-    info!("{} {}", index_name, event.as_json());
+/// Whether the event is stored in the dated indices, unlike contact lists and engagement
+/// events, which only feed the ranking.
+fn is_searchable(event: &Event) -> bool {
+    event.kind != Kind::ContactList && !is_engagement_event(event)
+}
 
-    if is_ephemeral_event(event) {
-        return Ok(());
+fn skip(state: &AppState, ctx: &mut EventContext<'_>, reason: SkipReason) -> Flow {
+    let event = ctx.event;
+    state.metrics.skipped(reason);
+    debug!(
+        "skipping event {} created at {}: {}",
+        event.id,
+        event.created_at,
+        reason.as_str()
+    );
+    ctx.stop(reason.as_str())
+}
+
+/// Checks the signature of events not verified on arrival.
+pub struct VerifyStage;
+
+#[async_trait]
+impl Stage for VerifyStage {
+    fn name(&self) -> &'static str {
+        "verify"
     }
 
-    let ok = can_exist(
-        &index_name,
-        &Utc::now(),
-        state.index_ttl_days,
-        state.index_allow_future_days,
-    )
-    .unwrap_or(false);
-    if !ok {
-        warn!("index {} is out of range; skipping", index_name);
-        return Ok(());
+    async fn process(
+        &self,
+        state: &Arc<AppState>,
+        ctx: &mut EventContext<'_>,
+    ) -> anyhow::Result<Flow> {
+        if !ctx.verified && ctx.event.verify().is_err() {
+            return Ok(skip(state, ctx, SkipReason::BadSignature));
+        }
+        Ok(Flow::Continue)
     }
+}
 
-    let es_client = &state.es_client;
-    let index_alias_name = &state.index_alias_name;
-    let id = event.id.to_hex();
+/// Skips ephemeral events and those outside the indices kept; runs first whatever the
+/// stages.
+pub struct AdmitStage;
 
-    let doc = Document {
-        event: event.clone(),
-        text: extract_text(&event),
-        tags: convert_tags(&event.tags),
-        identifier_tag: extract_identifier_tag(&event.tags),
-    };
-    let res = es_client
-        .index(IndexParts::IndexId(index_name.as_str(), &id))
-        .body(doc)
-        .send()
-        .await?;
-    if !res.status_code().is_success() {
-        let status_code = res.status_code();
-        let body = res.text().await?;
-        error!("failed to index; received {}, {}", status_code, body);
+#[async_trait]
+impl Stage for AdmitStage {
+    fn name(&self) -> &'static str {
+        "admit"
     }
 
-    if is_replaceable_event(event) {
-        delete_replaceable_event(es_client, index_alias_name, event).await?;
+    async fn process(
+        &self,
+        state: &Arc<AppState>,
+        ctx: &mut EventContext<'_>,
+    ) -> anyhow::Result<Flow> {
+        let event = ctx.event;
+        if is_ephemeral_event(event) {
+            return Ok(ctx.stop("ephemeral"));
+        }
+        let checked = check_event_date(
+            event,
+            &Utc::now(),
+            state.index_ttl.for_kind(event.kind.as_u64()),
+            state.index_allow_future_days,
+        );
+        match checked {
+            Ok(()) => Ok(Flow::Continue),
+            Err(reason) => Ok(skip(state, ctx, reason)),
+        }
     }
-    if is_parameterized_replaceable_event(event) {
-        delete_parameterized_replaceable_event(es_client, index_alias_name, event).await?;
+}
+
+/// Skips events deleted before they arrived and replaceable events older than the version
+/// indexed.
+pub struct DedupeStage;
+
+#[async_trait]
+impl Stage for DedupeStage {
+    fn name(&self) -> &'static str {
+        "dedupe"
     }
-    if let Kind::EventDeletion = event.kind {
-        handle_deletion_event(es_client, index_alias_name, event).await?;
+
+    async fn process(
+        &self,
+        state: &Arc<AppState>,
+        ctx: &mut EventContext<'_>,
+    ) -> anyhow::Result<Flow> {
+        let event = ctx.event;
+        if !is_searchable(event) {
+            return Ok(Flow::Continue);
+        }
+        // the deletion may have arrived first; backends reject older versions themselves
+        if let Some(backend) = &state.backend {
+            if backend::is_deleted(backend.as_ref(), event).await? {
+                info!("{} has been deleted by its author; skipping", event.id);
+                return Ok(ctx.stop("deleted"));
+            }
+            return Ok(Flow::Continue);
+        }
+        let es_client = &state.es_client;
+        let index_alias_name = &state.index_alias_name;
+        if is_deleted(es_client, index_alias_name, event).await? {
+            info!("{} has been deleted by its author; skipping", event.id);
+            return Ok(ctx.stop("deleted"));
+        }
+        // older versions are looked up by the stored, possibly rounded, created_at;
+        // with rounding, versions within the same period are replaced in the order received
+        let inclusive = state.created_at_rounding.is_some();
+        if (is_replaceable_event(event) || is_parameterized_replaceable_event(event))
+            && has_newer_version(
+                es_client,
+                index_alias_name,
+                &ctx.searchable_event,
+                inclusive,
+            )
+            .await?
+        {
+            state.metrics.skipped(SkipReason::Stale);
+            debug!("{} is replaced by a newer version; skipping", event.id);
+            return Ok(ctx.stop(SkipReason::Stale.as_str()));
+        }
+        Ok(Flow::Continue)
     }
+}
 
-    Ok(())
+/// Applies the indexing policies: content warnings, protected events, limits and opt-outs.
+pub struct FilterStage;
+
+#[async_trait]
+impl Stage for FilterStage {
+    fn name(&self) -> &'static str {
+        "filter"
+    }
+
+    async fn process(
+        &self,
+        state: &Arc<AppState>,
+        ctx: &mut EventContext<'_>,
+    ) -> anyhow::Result<Flow> {
+        let event = ctx.event;
+        if !is_searchable(event) {
+            return Ok(Flow::Continue);
+        }
+
+        if extract_content_warning(event).is_some() && !state.index_content_warnings {
+            debug!("{} carries a content warning; skipping", event.id);
+            return Ok(ctx.stop("content_warning"));
+        }
+
+        if is_protected(event) && !state.index_protected_events {
+            debug!("{} is protected; skipping", event.id);
+            return Ok(ctx.stop("protected"));
+        }
+
+        // deletions are applied however many events they delete
+        if event.kind != Kind::EventDeletion {
+            if let Err(reason) = state.event_limits.check(event) {
+                return Ok(skip(state, ctx, reason));
+            }
+        }
+
+        if state
+            .opt_out
+            .handle(&state.es_client, &state.index_alias_name, event)
+            .await?
+        {
+            info!("{} opted out of search; skipping", event.pubkey);
+            return Ok(ctx.stop("opted_out"));
+        }
+        Ok(Flow::Continue)
+    }
 }
 
-async fn handle_deletion_event(
-    es_client: &Elasticsearch,
-    index_alias_name: &str,
-    event: &Event,
-) -> anyhow::Result<()> {
-    let deletion_event = event;
-    log::info!("deletion event: {}", deletion_event.as_json());
-    let ids_to_delete = deletion_event
-        .tags
-        .iter()
-        .filter_map(|tag| match tag {
-            Tag::Event(e, _, _) => Some(e.to_hex()),
-            _ => None,
-        })
-        .collect::<Vec<String>>();
-    log::info!("ids to delete: {:?}", ids_to_delete);
+/// Builds the document of the event.
+pub struct EnrichStage;
 
-    let res = es_client
-        .delete_by_query(DeleteByQueryParts::Index(&[index_alias_name]))
-        .body(json!({
-            "query": {
-                "bool": {
-                    "must": [
-                        {
-                            "terms": {
-                                "_id": ids_to_delete
-                            },
-                        },
-                        {
-                            "term": {
-                                "event.pubkey": deletion_event.pubkey.to_string()
-                            },
-                        }
-                    ]
-                }
+#[async_trait]
+impl Stage for EnrichStage {
+    fn name(&self) -> &'static str {
+        "enrich"
+    }
+
+    async fn process(
+        &self,
+        state: &Arc<AppState>,
+        ctx: &mut EventContext<'_>,
+    ) -> anyhow::Result<Flow> {
+        let event = ctx.event;
+        if !is_searchable(event) {
+            return Ok(Flow::Continue);
+        }
+        let mut doc = Document::new(
+            event,
+            ctx.searchable_event.clone(),
+            ctx.raw.clone(),
+            if state.ingest_pipeline {
+                None
+            } else {
+                Some(Utc::now())
+            },
+            &state.extractors,
+        );
+        if let (Some(verifier), Some(profile)) = (&state.nip05_verifier, doc.profile.as_mut()) {
+            verifier
+                .annotate(
+                    &state.es_client,
+                    &state.index_alias_name,
+                    &event.pubkey.to_string(),
+                    profile,
+                )
+                .await?;
+        }
+        ctx.doc = Some(doc);
+        Ok(Flow::Continue)
+    }
+}
+
+/// Picks the dated index of the event, or the undated index of its kind and its document id,
+/// or hands contact lists, engagement events and zap receipts over to the ranking.
+pub struct RouteStage;
+
+#[async_trait]
+impl Stage for RouteStage {
+    fn name(&self) -> &'static str {
+        "route"
+    }
+
+    async fn process(
+        &self,
+        state: &Arc<AppState>,
+        ctx: &mut EventContext<'_>,
+    ) -> anyhow::Result<Flow> {
+        let event = ctx.event;
+        let index_name = match index_name_for_event(&state.index_name_prefix, event) {
+            Ok(index_name) => index_name,
+            Err(_) => return Ok(skip(state, ctx, SkipReason::BadTimestamp)),
+        };
+        info!("{} {}", index_name, event.as_json());
+
+        if event.kind == Kind::ContactList {
+            // contact lists are not searchable, but count followers for ranking
+            if state.follower_boost.is_some() {
+                handle_contact_list(&state.es_client, &state.index_alias_name, event).await?;
             }
-        }))
-        .send()
-        .await?;
+            return Ok(ctx.stop("contact_list"));
+        }
 
-    if !res.status_code().is_success() {
-        let status_code = res.status_code();
-        let body = res.text().await?;
-        error!("failed to delete; received {}, {}", status_code, body);
-        return Err(anyhow::anyhow!("failed to delete"));
+        if is_engagement_event(event) {
+            // reactions and reposts are not searchable, but boost the events they refer to
+            if let Some(counter) = &state.engagement {
+                counter.record(event);
+            }
+            if let Some(counter) = &state.reactions {
+                counter.record(event);
+            }
+            return Ok(ctx.stop("engagement"));
+        }
+
+        if is_zap_receipt(event) {
+            // zap receipts are not searchable while zaps are counted
+            if let Some(counter) = &state.zaps {
+                counter.record(event);
+                return Ok(ctx.stop("zap"));
+            }
+        }
+
+        if state.profiles_index && event.kind == Kind::Metadata {
+            ctx.index_name = Some(profiles_index_name(&state.index_name_prefix));
+            ctx.doc_id = Some(author(event));
+        } else if let (true, Some(doc_id)) = (state.replaceable_index, replaceable_id(event)) {
+            ctx.index_name = Some(replaceable_index_name(&state.index_name_prefix));
+            ctx.doc_id = Some(doc_id);
+        } else {
+            ctx.index_name = Some(index_name);
+        }
+        Ok(Flow::Continue)
     }
+}
 
-    let response_body = res.json::<serde_json::Value>().await?;
-    info!(
-        "delete event: deleted {} event(s) of for pubkey {}",
-        response_body["deleted"], event.pubkey,
+/// Indexes the document, then removes the versions and events it replaces or deletes.
+pub struct WriteStage;
+
+#[async_trait]
+impl Stage for WriteStage {
+    fn name(&self) -> &'static str {
+        "write"
+    }
+
+    async fn process(
+        &self,
+        state: &Arc<AppState>,
+        ctx: &mut EventContext<'_>,
+    ) -> anyhow::Result<Flow> {
+        let event = ctx.event;
+        let (index_name, doc) = match (ctx.index_name.clone(), ctx.doc.take()) {
+            (Some(index_name), Some(doc)) => (index_name, doc),
+            _ => return Err(anyhow::anyhow!("{} was not enriched and routed", event.id)),
+        };
+        let nip05_verified = doc.profile.as_ref().and_then(|p| p.nip05_verified) == Some(true);
+        if let Some(backend) = &state.backend {
+            return write_to_backend(state, ctx, backend.as_ref(), &index_name, nip05_verified)
+                .await;
+        }
+        let es_client = &state.es_client;
+        let index_alias_name = &state.index_alias_name;
+        let id = event.id.to_hex();
+        // documents shared by the versions of an event overwrite each other
+        let versioned = ctx.doc_id.is_some();
+        let doc_id = ctx.doc_id.clone().unwrap_or_else(|| id.clone());
+
+        let req = es_client
+            .index(IndexParts::IndexId(index_name.as_str(), &doc_id))
+            .body(doc);
+        // versioned by `created_at`, so that an older version never overwrites a newer one
+        let req = if versioned {
+            req.version(ctx.searchable_event.created_at.as_i64())
+                .version_type(VersionType::ExternalGte)
+        } else {
+            req
+        };
+        // indices created with the pipeline keep it as their default
+        let req = if state.ingest_pipeline {
+            req
+        } else {
+            req.pipeline("_none")
+        };
+        let res = req.send().await?;
+        if versioned && res.status_code().as_u16() == 409 {
+            // a newer version was written meanwhile
+            state.metrics.skipped(SkipReason::Stale);
+            ctx.outcome = Some(SkipReason::Stale.as_str().to_string());
+        } else if !res.status_code().is_success() {
+            let status_code = res.status_code();
+            let body = res.text().await?;
+            ctx.outcome = Some(format!("failed:{}", status_code.as_u16()));
+            if let Err(e) = record_dead_letter(state, event, status_code.as_u16(), &body).await {
+                error!("failed to record dead letter {}: {}", id, e);
+            }
+            // left unacknowledged, so that the event is indexed again
+            return Err(anyhow::anyhow!(
+                "failed to index; received {}, {}",
+                status_code,
+                body
+            ));
+        } else {
+            let body = res.json::<serde_json::Value>().await?;
+            ctx.outcome = body["result"].as_str().map(|result| result.to_string());
+            // events sent again are not counted again, but new versions are
+            let created = body["result"] == "created";
+            record_written(
+                state,
+                event,
+                &index_name,
+                created,
+                created || versioned,
+                nip05_verified,
+            );
+        }
+
+        let inclusive = state.created_at_rounding.is_some();
+        let replaceable = is_replaceable_event(event) || is_parameterized_replaceable_event(event);
+        if versioned {
+            // the document of the previous version has been overwritten
+        } else if let (true, Some(replacements)) = (replaceable, &state.replacements) {
+            replacements.record(&ctx.searchable_event, inclusive);
+        } else if is_replaceable_event(event) {
+            delete_replaceable_event(
+                es_client,
+                index_alias_name,
+                &ctx.searchable_event,
+                inclusive,
+            )
+            .await?;
+        } else if is_parameterized_replaceable_event(event) {
+            delete_parameterized_replaceable_event(
+                es_client,
+                index_alias_name,
+                &ctx.searchable_event,
+                inclusive,
+            )
+            .await?;
+        }
+        if let Kind::EventDeletion = event.kind {
+            handle_deletion_event(es_client, index_alias_name, event).await?;
+            record_deleted(state, event, &deleted_ids(event)).await?;
+        }
+        Ok(Flow::Stop)
+    }
+}
+
+/// Counts and announces an event written to `index_name`; `fresh` unless the same version of
+/// it was written before.
+fn record_written(
+    state: &AppState,
+    event: &Event,
+    index_name: &str,
+    created: bool,
+    fresh: bool,
+    nip05_verified: bool,
+) {
+    if created && is_counted(event) {
+        state.ingest_counter.record(index_name);
+    }
+    if let (true, Some(collector)) = (fresh, &state.completions) {
+        collector.record(event, nip05_verified);
+    }
+    if let (true, false, Some(sinks)) = (fresh, is_probe(state, event), &state.sinks) {
+        sinks.record(event);
+    }
+    state
+        .metrics
+        .indexed(state.kind_labels.label(event.kind.as_u32()));
+    Metrics::set(
+        &state.metrics.last_indexed_at,
+        Utc::now().timestamp() as u64,
     );
+    // fails only when there is no live subscription
+    if !is_probe(state, event) {
+        let _ = state.new_events.send(event.clone());
+    }
+}
 
+/// Forgets the profile completion and journals the deletions of the deletion `event`, once the
+/// events deleted were removed.
+async fn record_deleted(
+    state: &AppState,
+    event: &Event,
+    deleted_ids: &[String],
+) -> anyhow::Result<()> {
+    forget_deleted_profile(state, event).await?;
+    if let Some(journal) = &state.journal {
+        let outcome = format!("deleted_by:{}", event.id.to_hex());
+        for deleted_id in deleted_ids {
+            journal.record(deleted_id, "delete", None, &outcome);
+        }
+    }
     Ok(())
 }
 
+/// Freshness probes are indexed like any other event, but are not for anybody to see.
+fn is_probe(state: &AppState, event: &Event) -> bool {
+    state
+        .probe_keys
+        .as_ref()
+        .map_or(false, |keys| keys.public_key() == event.pubkey)
+}
+
+/// Removes the profile completion of the author of the deletion `event` when it deletes their
+/// metadata.
+async fn forget_deleted_profile(state: &AppState, event: &Event) -> anyhow::Result<()> {
+    if let Some(collector) = &state.completions {
+        let (author, deleted_ids) = (author(event), deleted_ids(event));
+        collector.forget(&author, &deleted_ids);
+        remove_profile(
+            &state.es_client,
+            &state.index_alias_name,
+            &author,
+            Some(&deleted_ids),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Writes the event routed to `index_name` to the backend instead.
+async fn write_to_backend(
+    state: &Arc<AppState>,
+    ctx: &mut EventContext<'_>,
+    backend: &dyn SearchBackend,
+    index_name: &str,
+    nip05_verified: bool,
+) -> anyhow::Result<Flow> {
+    let event = ctx.event;
+    // failures are left unacknowledged, so that the event is indexed again
+    let outcome = backend
+        .index(event)
+        .await
+        .with_context(|| format!("failed to index with {}", backend.name()))?;
+    if outcome == WriteOutcome::Stale {
+        state.metrics.skipped(SkipReason::Stale);
+        ctx.outcome = Some(SkipReason::Stale.as_str().to_string());
+    } else {
+        ctx.outcome = Some(outcome.as_str().to_string());
+        // backends do not tell events sent again from new versions
+        let created = outcome == WriteOutcome::Created;
+        record_written(state, event, index_name, created, true, nip05_verified);
+    }
+    if let Kind::EventDeletion = event.kind {
+        // recorded first, so that the deleted events arriving later are skipped
+        let author = author(event);
+        let deleted_ids = deleted_ids(event);
+        backend
+            .record_deletions(&author, &deleted_ids, event.created_at.as_u64())
+            .await?;
+        backend.delete(&author, &deleted_ids).await?;
+        record_deleted(state, event, &deleted_ids).await?;
+    }
+    Ok(Flow::Stop)
+}
+
+/// Handles an event for indexing with the stages of `INGEST_STAGES`.
+pub async fn handle_update(state: Arc<AppState>, event: &Event) -> anyhow::Result<()> {
+    state.ingest_chain.run(&state, event, false).await
+}
+
+/// Handles an event whose signature was verified on arrival, like those of the index queue.
+pub async fn handle_verified_update(state: Arc<AppState>, event: &Event) -> anyhow::Result<()> {
+    state.ingest_chain.run(&state, event, true).await
+}
+
 pub async fn handle_event(
     state: Arc<AppState>,
     addr: SocketAddr,
     msg: &Vec<serde_json::Value>,
 ) -> anyhow::Result<()> {
+    // indexers append the URL of the relay the event was received from
+    let (event, relay) = parse_forwarded_event(msg)?;
+    event.verify().context("failed to verify event")?;
+
+    log::info!("{} EVENT {}", addr, event.as_json());
+    enqueue(&state, event, relay).await
+}
+
+/// Why an event published by a regular connection is refused, as the prefixed message of
+/// the NIP-20 `OK`.
+fn published_event_rejection(event: &Event, limits: &EventLimits) -> Option<String> {
+    if event.verify().is_err() {
+        return Some("invalid: bad signature".to_string());
+    }
+    // NIP-70; there is no AUTH to tell the author apart
+    if is_protected(event) {
+        return Some("blocked: protected events are only accepted from their author".to_string());
+    }
+    match limits.check(event) {
+        Err(SkipReason::TooLarge) => Some("invalid: content too large".to_string()),
+        Err(_) => Some("invalid: too many tags".to_string()),
+        Ok(()) => None,
+    }
+}
+
+/// Queues an event published by a regular connection and returns the NIP-20 `OK` to answer
+/// with; only malformed messages are errors.
+pub async fn handle_published_event(
+    state: Arc<AppState>,
+    addr: SocketAddr,
+    msg: &Vec<serde_json::Value>,
+) -> anyhow::Result<serde_json::Value> {
     if msg.len() != 2 {
         return Err(anyhow::anyhow!("invalid array length"));
     }
-
     let event = serde_json::from_value::<Event>(msg[1].clone()).context("parsing event")?;
-    event.verify().context("failed to verify event")?;
+    let id = event.id.to_hex();
+    let rejection = match &state.event_limiter {
+        Some(limiter) if limiter.try_acquire(addr.ip(), Instant::now()).is_err() => {
+            Some("rate-limited: slow down".to_string())
+        }
+        _ => published_event_rejection(&event, &state.event_limits),
+    };
+    if let Some(message) = rejection {
+        debug!("{} EVENT {} rejected: {}", addr, id, message);
+        return Ok(serde_json::json!(["OK", id, false, message]));
+    }
 
-    handle_update(state, &event).await?;
-    log::info!("{} EVENT {}", addr, event.as_json());
+    log::info!("{} published EVENT {}", addr, event.as_json());
+    match enqueue(&state, event, None).await {
+        Ok(()) => Ok(serde_json::json!(["OK", id, true, ""])),
+        Err(e) => {
+            error!("{} failed to queue {}: {}", addr, id, e);
+            Ok(serde_json::json!([
+                "OK",
+                id,
+                false,
+                "error: failed to queue the event"
+            ]))
+        }
+    }
+}
+
+/// Routes a received event to the index queues of this and the tenant namespaces.
+async fn enqueue(state: &AppState, event: Event, relay: Option<&str>) -> anyhow::Result<()> {
+    Metrics::inc(&state.metrics.events_received);
+    if let Some(relay) = relay {
+        state.seen_on.record(&event.id.to_hex(), relay);
+    }
+    if !state.sampling.keeps(&event, relay) {
+        state.metrics.skipped(SkipReason::Sampled);
+        if let Some(journal) = &state.journal {
+            journal.record(
+                &event.id.to_hex(),
+                "index",
+                None,
+                SkipReason::Sampled.as_str(),
+            );
+        }
+        return Ok(());
+    }
+    for tenant in state
+        .tenants
+        .targets(&state.index_alias_name, &event, relay)
+    {
+        debug!("routing {} to {}", event.id, tenant.index_alias_name);
+        if let Some(relay) = relay {
+            tenant.seen_on.record(&event.id.to_hex(), relay);
+        }
+        if let Some(ack_log) = &tenant.ack_log {
+            ack_log.append(&event).await?;
+        }
+        tenant
+            .index_queue
+            .push(&tenant.metrics, event.clone())
+            .await?;
+    }
+    if let Some(ack_log) = &state.ack_log {
+        ack_log.append(&event).await?;
+    }
+    state.index_queue.push(&state.metrics, event).await?;
 
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use nostr_sdk::Tag;
+    use nostr_sdk::prelude::TagKind;
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+    use serde_json::json;
 
-    use crate::index::handlers::extract_identifier_tag;
+    use crate::index::handlers::{
+        extract_identifier_tag, newer_than, older_than, published_event_rejection, replaceable_id,
+    };
+    use crate::index::limits::EventLimits;
 
     #[test]
     fn test_identifier_tag() {
@@ -346,4 +1028,82 @@ mod tests {
             "".to_string()
         );
     }
+
+    #[test]
+    fn test_replaceable_id() {
+        let keys = Keys::generate();
+        let event =
+            |kind: Kind, tags: &[Tag]| EventBuilder::new(kind, "", tags).to_event(&keys).unwrap();
+        assert_eq!(
+            replaceable_id(&event(Kind::Metadata, &[])),
+            Some(format!("0:{}", keys.public_key()))
+        );
+        assert_eq!(
+            replaceable_id(&event(
+                Kind::from(31234),
+                &[Tag::Identifier("article".to_string())]
+            )),
+            Some(format!("31234:{}:article", keys.public_key()))
+        );
+        assert_eq!(replaceable_id(&event(Kind::TextNote, &[])), None);
+    }
+
+    #[test]
+    fn test_versions() {
+        let event = EventBuilder::new(Kind::Metadata, "{}", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let created_at = event.created_at.to_string();
+        let tie = |op: &str| {
+            json!({
+                "bool": {
+                    "must": [
+                        { "term": { "event.created_at": created_at } },
+                        { "range": { "event.id.keyword": { op: event.id.to_hex() } } }
+                    ]
+                }
+            })
+        };
+        assert_eq!(older_than(&event, false)["bool"]["should"][1], tie("gt"));
+        assert_eq!(newer_than(&event, false)["bool"]["should"][1], tie("lt"));
+        assert_eq!(
+            newer_than(&event, true),
+            json!({ "range": { "event.created_at": { "gt": created_at } } })
+        );
+    }
+
+    #[test]
+    fn test_published_event_rejection() {
+        let keys = Keys::generate();
+        let limits = EventLimits {
+            max_content_bytes: Some(10),
+            max_tags: None,
+        };
+        let event = |content: &str, tags: &[Tag]| {
+            EventBuilder::new(Kind::TextNote, content, tags)
+                .to_event(&keys)
+                .unwrap()
+        };
+        assert_eq!(
+            published_event_rejection(&event("hello", &[]), &limits),
+            None
+        );
+        assert_eq!(
+            published_event_rejection(&event("hello world!", &[]), &limits),
+            Some("invalid: content too large".to_string())
+        );
+        let protected = event(
+            "hello",
+            &[Tag::Generic(TagKind::Custom("-".to_string()), vec![])],
+        );
+        assert!(published_event_rejection(&protected, &limits)
+            .unwrap()
+            .starts_with("blocked:"));
+        let mut forged = event("hello", &[]);
+        forged.content = "bye".to_string();
+        assert_eq!(
+            published_event_rejection(&forged, &limits),
+            Some("invalid: bad signature".to_string())
+        );
+    }
 }
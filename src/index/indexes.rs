@@ -1,15 +1,59 @@
-use chrono::{DateTime, TimeZone, Utc};
-use nostr_sdk::Event;
+use chrono::{DateTime, Utc};
+use elasticsearch::indices::IndicesCreateParts;
+use elasticsearch::Elasticsearch;
+use nostr_sdk::Timestamp;
+use serde_json::{json, Value};
 
-const DATE_FORMAT: &str = "%Y.%m.%d";
+pub use searchnos_common::dates::{
+    check_event_date, check_index_date, index_name_for_event, SkipReason,
+};
 
-pub fn index_name_for_event(prefix: &str, event: &Event) -> anyhow::Result<String> {
-    let dt = chrono::Utc.timestamp_opt(event.created_at.as_i64(), 0);
-    if let Some(dt) = dt.single() {
-        Ok(format!("{}-{}", prefix, dt.format(DATE_FORMAT).to_string()))
-    } else {
-        Err(anyhow::anyhow!("failed to parse date: {}", event.created_at).into())
+const REPLACEABLE_INDEX_SUFFIX: &str = "-replaceable";
+const PROFILES_INDEX_SUFFIX: &str = "-profiles";
+
+/// Undated index holding the latest version of each replaceable event.
+pub fn replaceable_index_name(prefix: &str) -> String {
+    format!("{}{}", prefix, REPLACEABLE_INDEX_SUFFIX)
+}
+
+/// Undated index holding the latest profile (kind 0) of each pubkey.
+pub fn profiles_index_name(prefix: &str) -> String {
+    format!("{}{}", prefix, PROFILES_INDEX_SUFFIX)
+}
+
+/// Whether `index_name` is an undated index of a prefix, which the TTL does not apply to.
+pub fn is_undated_index(index_name: &str) -> bool {
+    index_name.ends_with(REPLACEABLE_INDEX_SUFFIX) || index_name.ends_with(PROFILES_INDEX_SUFFIX)
+}
+
+/// Rounds `created_at` down to a multiple of `secs`.
+pub fn round_created_at(created_at: Timestamp, secs: u64) -> Timestamp {
+    Timestamp::from(created_at.as_u64() / secs * secs)
+}
+
+/// Creates an index kept beside the event indices, e.g. `searchnos-followers-<alias>`.
+pub async fn create_side_index(
+    es_client: &Elasticsearch,
+    index_name: &str,
+    mappings: Value,
+) -> anyhow::Result<()> {
+    let res = es_client
+        .indices()
+        .create(IndicesCreateParts::Index(index_name))
+        .body(json!({ "mappings": mappings }))
+        .send()
+        .await?;
+    let status_code = res.status_code();
+    let body = res.json::<Value>().await?;
+    if !status_code.is_success() && body["error"]["type"] != "resource_already_exists_exception" {
+        return Err(anyhow::anyhow!(
+            "failed to create index {}: {} {}",
+            index_name,
+            status_code,
+            body
+        ));
     }
+    Ok(())
 }
 
 pub fn can_exist(
@@ -18,35 +62,33 @@ pub fn can_exist(
     ttl_in_days: Option<u64>,
     allow_future_days: u64,
 ) -> anyhow::Result<bool> {
-    let date_str = index_name.split('-').nth(1).unwrap_or("");
-    let index_date = chrono::NaiveDate::parse_from_str(date_str, DATE_FORMAT)?;
-    let index_time = index_date.and_hms_opt(0, 0, 0);
-    let index_time = if let Some(index_time) = index_time {
-        index_time
-    } else {
-        return Ok(false);
-    };
-    let index_time = Utc.from_utc_datetime(&index_time);
-    let diff: chrono::Duration = current_time.signed_duration_since(index_time);
-
-    if let Some(ttl_in_days) = ttl_in_days {
-        let ttl_duration: chrono::Duration = chrono::Duration::days(ttl_in_days as i64);
-        if diff >= ttl_duration {
-            return Ok(false);
-        }
+    match check_index_date(index_name, current_time, ttl_in_days, allow_future_days) {
+        Ok(()) => Ok(true),
+        Err(SkipReason::BadTimestamp) => Err(anyhow::anyhow!("not a dated index: {}", index_name)),
+        Err(_) => Ok(false),
     }
-    Ok(-chrono::Duration::days(allow_future_days as i64) <= diff)
 }
 
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
 
-    use crate::index::indexes::can_exist;
+    use nostr_sdk::Timestamp;
+
+    use crate::index::indexes::{can_exist, round_created_at};
+
+    #[test]
+    fn test_round_created_at() {
+        // 2023-03-20T12:34:56Z
+        let created_at = Timestamp::from(1679315696);
+        assert_eq!(round_created_at(created_at, 3600).as_u64(), 1679313600);
+        assert_eq!(round_created_at(created_at, 86400).as_u64(), 1679270400);
+    }
 
     #[test]
     fn test_can_exist() {
         let current_time = chrono::DateTime::from_str("2023-03-20T00:00:00Z").unwrap();
+        assert!(can_exist("nostr-foo", &current_time, Some(2), 1).is_err());
         assert_eq!(
             can_exist("nostr-2023.03.22", &current_time, Some(2), 1).unwrap(),
             false
@@ -73,5 +115,15 @@ mod tests {
             can_exist("nostr-2023.03.18", &current_time, None, 1).unwrap(),
             true
         );
+
+        // namespaced index
+        assert_eq!(
+            can_exist("nostr-test-2023.03.18", &current_time, Some(2), 1).unwrap(),
+            false
+        );
+        assert_eq!(
+            can_exist("nostr-test-2023.03.20", &current_time, Some(2), 1).unwrap(),
+            true
+        );
     }
 }
@@ -0,0 +1,202 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::Utc;
+use elasticsearch::http::request::JsonBody;
+use elasticsearch::{BulkParts, DeleteByQueryParts, Elasticsearch, SearchParts};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+use crate::index::indexes::create_side_index;
+
+/// entries kept in memory until flushed; more are dropped
+const MAX_BUFFERED: usize = 100_000;
+
+fn journal_index(index_alias_name: &str) -> String {
+    format!("searchnos-journal-{}", index_alias_name)
+}
+
+/// Creates the side index of the journal of index and delete operations.
+pub async fn create_journal_index(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+) -> anyhow::Result<()> {
+    create_side_index(
+        es_client,
+        &journal_index(index_alias_name),
+        json!({
+            "dynamic": false,
+            "properties": {
+                "event_id": { "type": "keyword" },
+                "op": { "type": "keyword" },
+                "index": { "type": "keyword" },
+                "outcome": { "type": "keyword" },
+                "timestamp": { "type": "date" }
+            }
+        }),
+    )
+    .await
+}
+
+/// What happened to each event received for indexing, kept for `retention_days` to tell why
+/// an event is not searchable.
+///
+/// Entries are buffered and written in bulk, so that journaling does not slow indexing down.
+#[derive(Debug)]
+pub struct Journal {
+    pub retention_days: u64,
+    buffer: Mutex<Vec<Value>>,
+}
+
+impl Journal {
+    pub fn new(retention_days: u64) -> Self {
+        Journal {
+            retention_days,
+            buffer: Mutex::new(vec![]),
+        }
+    }
+
+    /// Records an operation `op` (`index` or `delete`) on the event `event_id`.
+    pub fn record(&self, event_id: &str, op: &str, index: Option<&str>, outcome: &str) {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.len() >= MAX_BUFFERED {
+            log::debug!("journal full; dropping {} {}", op, event_id);
+            return;
+        }
+        buffer.push(json!({
+            "event_id": event_id,
+            "op": op,
+            "index": index,
+            "outcome": outcome,
+            "timestamp": Utc::now()
+        }));
+    }
+
+    fn take(&self) -> Vec<Value> {
+        std::mem::take(&mut *self.buffer.lock().unwrap())
+    }
+}
+
+async fn flush(state: &AppState, journal: &Journal) -> anyhow::Result<()> {
+    let entries = journal.take();
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let mut body: Vec<JsonBody<Value>> = vec![];
+    for entry in entries {
+        body.push(json!({ "index": {} }).into());
+        body.push(entry.into());
+    }
+    let res = state
+        .es_client
+        .bulk(BulkParts::Index(&journal_index(&state.index_alias_name)))
+        .body(body)
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to write journal: {} {}",
+            status_code,
+            body
+        ));
+    }
+    Ok(())
+}
+
+pub fn spawn_journal_flusher(state: Arc<AppState>, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Some(journal) = &state.journal {
+                if let Err(e) = flush(&state, journal).await {
+                    log::error!("{}", e);
+                }
+            }
+        }
+    })
+}
+
+/// Deletes the journal entries older than `retention_days`.
+pub async fn purge_journal(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    retention_days: u64,
+) -> anyhow::Result<()> {
+    let res = es_client
+        .delete_by_query(DeleteByQueryParts::Index(&[&journal_index(
+            index_alias_name,
+        )]))
+        .body(json!({
+            "query": {
+                "range": { "timestamp": { "lt": format!("now-{}d", retention_days) } }
+            }
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to purge journal: {} {}",
+            status_code,
+            body
+        ));
+    }
+    Ok(())
+}
+
+/// Journal entries of the event `event_id`, oldest first.
+pub async fn journal_entries(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    event_id: &str,
+) -> anyhow::Result<Vec<Value>> {
+    let res = es_client
+        .search(SearchParts::Index(&[&journal_index(index_alias_name)]))
+        .size(1000)
+        .body(json!({
+            "query": { "term": { "event_id": event_id.to_lowercase() } },
+            "sort": [{ "timestamp": "asc" }]
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to search journal: {}",
+            res.status_code()
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    Ok(body["hits"]["hits"]
+        .as_array()
+        .unwrap_or(&vec![])
+        .iter()
+        .map(|hit| hit["_source"].clone())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::journal::{Journal, MAX_BUFFERED};
+
+    #[test]
+    fn test_record() {
+        let journal = Journal::new(7);
+        journal.record("ab", "index", Some("nostr-2023.03.20"), "created");
+        journal.record("cd", "index", None, "too_old");
+        let entries = journal.take();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["index"], "nostr-2023.03.20");
+        assert_eq!(entries[1]["index"], serde_json::Value::Null);
+        assert_eq!(entries[1]["outcome"], "too_old");
+        assert!(journal.take().is_empty());
+
+        for _ in 0..MAX_BUFFERED + 1 {
+            journal.record("ab", "index", None, "stale");
+        }
+        assert_eq!(journal.take().len(), MAX_BUFFERED);
+    }
+}
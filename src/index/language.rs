@@ -0,0 +1,307 @@
+use elasticsearch::http::request::JsonBody;
+use elasticsearch::{BulkParts, Elasticsearch, OpenPointInTimeParts, SearchParts};
+use serde_json::{json, Value};
+
+use crate::index::analyzer::AnalyzerConfig;
+use crate::search::language::detect_languages;
+
+const OTHER_LANGUAGES_PREFIX: &str = "searchnos-other-languages-";
+/// how long the point in time of a backfill is kept between two pages
+const PIT_KEEP_ALIVE: &str = "5m";
+
+/// Languages indexed; documents detected in another one are dropped by the ingest pipeline,
+/// or routed to the indices of `other_languages_prefix` if `route`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LanguageAllowlist {
+    pub languages: Vec<String>,
+    pub route: bool,
+}
+
+impl LanguageAllowlist {
+    /// Parses languages like `en,pt`; `mode` is `skip` or `route`.
+    pub fn parse(languages: &str, mode: &str) -> anyhow::Result<Self> {
+        let languages = languages
+            .split(',')
+            .map(|language| language.trim().to_lowercase())
+            .filter(|language| !language.is_empty())
+            .collect::<Vec<_>>();
+        if languages.is_empty() {
+            return Err(anyhow::anyhow!("no language"));
+        }
+        let route = match mode {
+            "skip" => false,
+            "route" => true,
+            _ => return Err(anyhow::anyhow!("unknown mode: {}", mode)),
+        };
+        Ok(LanguageAllowlist { languages, route })
+    }
+
+    /// Ingest processor applied after the language detection. Documents of an `unknown`
+    /// language are kept, as the model fails on short texts.
+    pub fn processor(&self) -> Value {
+        let condition = json!({
+            "source": "ctx.language != null && ctx.language != 'unknown' && !params.languages.contains(ctx.language)",
+            "params": { "languages": self.languages }
+        });
+        if self.route {
+            json!({
+                "script": {
+                    "description": "route documents of other languages to their own indices",
+                    "if": condition,
+                    "source": "ctx._index = params.prefix + ctx._index;",
+                    "params": { "prefix": OTHER_LANGUAGES_PREFIX }
+                }
+            })
+        } else {
+            json!({
+                "drop": {
+                    "description": "drop documents of other languages",
+                    "if": condition
+                }
+            })
+        }
+    }
+}
+
+/// Prefix of the indices documents outside the allowlist are routed to, e.g.
+/// `searchnos-other-languages-nostr`.
+pub fn other_languages_prefix(index_name_prefix: &str) -> String {
+    format!("{}{}", OTHER_LANGUAGES_PREFIX, index_name_prefix)
+}
+
+/// Partial update setting the language, routing the text into the field analyzed for it like
+/// the ingest pipeline does.
+fn language_update(text: &str, language: &str, analyzer_config: &AnalyzerConfig) -> Value {
+    if analyzer_config.is_configured(language) {
+        json!({ "doc": { "language": language, "texts": { language: text } } })
+    } else {
+        json!({ "doc": { "language": language } })
+    }
+}
+
+/// Detects the language of the documents indexed without one, e.g. before the ingest pipeline
+/// detected languages. Returns the number of documents scanned and updated.
+///
+/// Documents whose language is not detected with `min_probability` are left as they are.
+pub async fn backfill_languages(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    analyzer_config: &AnalyzerConfig,
+    batch_size: usize,
+    min_probability: f64,
+) -> anyhow::Result<(usize, usize)> {
+    // pages over a point in time in index order, so that updated documents are neither
+    // fetched again nor shift the pages, and documents of any mapping are ordered
+    let res = es_client
+        .open_point_in_time(OpenPointInTimeParts::Index(&[index_alias_name]))
+        .keep_alive(PIT_KEEP_ALIVE)
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to open a point in time: {}",
+            res.status_code()
+        ));
+    }
+    let mut pit_id = res.json::<Value>().await?["id"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("no point in time id"))?
+        .to_string();
+    let res = detect_pages(
+        es_client,
+        index_alias_name,
+        &mut pit_id,
+        analyzer_config,
+        batch_size,
+        min_probability,
+    )
+    .await;
+    let closed = es_client
+        .close_point_in_time()
+        .body(json!({ "id": pit_id }))
+        .send()
+        .await;
+    if let Err(e) = closed {
+        log::warn!("failed to close the point in time: {}", e);
+    }
+    res
+}
+
+/// Search of the page after `search_after` of the documents without a language.
+fn page_body(pit_id: &str, search_after: Option<&Value>) -> Value {
+    let mut body = json!({
+        "query": {
+            "bool": {
+                "filter": [{ "exists": { "field": "text" } }],
+                "must_not": [{ "exists": { "field": "language" } }]
+            }
+        },
+        "_source": ["text"],
+        "pit": { "id": pit_id, "keep_alive": PIT_KEEP_ALIVE },
+        "sort": [{ "_shard_doc": "asc" }]
+    });
+    if let Some(search_after) = search_after {
+        body["search_after"] = search_after.clone();
+    }
+    body
+}
+
+async fn detect_pages(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    pit_id: &mut String,
+    analyzer_config: &AnalyzerConfig,
+    batch_size: usize,
+    min_probability: f64,
+) -> anyhow::Result<(usize, usize)> {
+    let (mut scanned, mut updated) = (0, 0);
+    let mut search_after: Option<Value> = None;
+    loop {
+        // searches over a point in time name no index
+        let res = es_client
+            .search(SearchParts::None)
+            .body(page_body(pit_id, search_after.as_ref()))
+            .size(batch_size as i64)
+            .send()
+            .await?;
+        if !res.status_code().is_success() {
+            let status_code = res.status_code();
+            let body = res.text().await?;
+            return Err(anyhow::anyhow!(
+                "failed to search documents without a language: {} {}",
+                status_code,
+                body
+            ));
+        }
+        let body = res.json::<Value>().await?;
+        // the id may change from a page to the next
+        if let Some(id) = body["pit_id"].as_str() {
+            *pit_id = id.to_string();
+        }
+        let hits = body["hits"]["hits"].as_array().cloned().unwrap_or_default();
+        let last = match hits.last() {
+            Some(hit) => hit["sort"].clone(),
+            None => return Ok((scanned, updated)),
+        };
+
+        let texts = hits
+            .iter()
+            .map(|hit| {
+                hit["_source"]["text"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string()
+            })
+            .collect::<Vec<_>>();
+        let languages = detect_languages(es_client, &texts, min_probability).await?;
+
+        let mut ops: Vec<JsonBody<Value>> = vec![];
+        for ((hit, text), language) in hits.iter().zip(&texts).zip(&languages) {
+            if let Some(language) = language {
+                ops.push(JsonBody::new(json!({
+                    "update": { "_index": hit["_index"], "_id": hit["_id"] }
+                })));
+                ops.push(JsonBody::new(language_update(
+                    text,
+                    language,
+                    analyzer_config,
+                )));
+            }
+        }
+        if !ops.is_empty() {
+            let res = es_client.bulk(BulkParts::None).body(ops).send().await?;
+            if !res.status_code().is_success() {
+                let status_code = res.status_code();
+                let body = res.text().await?;
+                return Err(anyhow::anyhow!(
+                    "failed to update languages: {} {}",
+                    status_code,
+                    body
+                ));
+            }
+            let body = res.json::<Value>().await?;
+            if body["errors"].as_bool().unwrap_or(false) {
+                log::warn!("some languages failed to be stored: {}", body["items"]);
+            }
+        }
+
+        scanned += hits.len();
+        updated += languages.iter().filter(|l| l.is_some()).count();
+        log::info!(
+            "[{}] {} document(s) scanned, {} updated",
+            index_alias_name,
+            scanned,
+            updated
+        );
+        if hits.len() < batch_size {
+            return Ok((scanned, updated));
+        }
+        search_after = Some(last);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::index::analyzer::AnalyzerConfig;
+    use crate::index::language::{
+        language_update, other_languages_prefix, page_body, LanguageAllowlist,
+    };
+
+    #[test]
+    fn test_page_body() {
+        let body = page_body("pit", None);
+        assert_eq!(body["pit"], json!({ "id": "pit", "keep_alive": "5m" }));
+        assert_eq!(body["sort"], json!([{ "_shard_doc": "asc" }]));
+        assert!(body.get("search_after").is_none());
+        let search_after = json!([42]);
+        assert_eq!(
+            page_body("pit", Some(&search_after))["search_after"],
+            search_after
+        );
+    }
+
+    #[test]
+    fn test_language_update() {
+        let analyzer_config = AnalyzerConfig::new(
+            1,
+            2,
+            AnalyzerConfig::parse_languages("de:stemming").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            language_update("hallo", "de", &analyzer_config),
+            json!({ "doc": { "language": "de", "texts": { "de": "hallo" } } })
+        );
+        assert_eq!(
+            language_update("hello", "en", &analyzer_config),
+            json!({ "doc": { "language": "en" } })
+        );
+    }
+
+    #[test]
+    fn test_language_allowlist() {
+        let allowlist = LanguageAllowlist::parse("en, PT", "skip").unwrap();
+        assert_eq!(allowlist.languages, vec!["en", "pt"]);
+        let processor = allowlist.processor();
+        assert_eq!(
+            processor["drop"]["if"]["params"]["languages"],
+            json!(["en", "pt"])
+        );
+
+        let allowlist = LanguageAllowlist::parse("ja", "route").unwrap();
+        let processor = allowlist.processor();
+        assert_eq!(
+            processor["script"]["params"]["prefix"],
+            "searchnos-other-languages-"
+        );
+        assert_eq!(
+            other_languages_prefix("nostr"),
+            "searchnos-other-languages-nostr"
+        );
+
+        assert!(LanguageAllowlist::parse("", "skip").is_err());
+        assert!(LanguageAllowlist::parse("ja", "index").is_err());
+    }
+}
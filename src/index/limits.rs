@@ -0,0 +1,62 @@
+use nostr_sdk::Event;
+
+use crate::index::indexes::SkipReason;
+
+/// Upper bounds on the events indexed, keeping oversized documents out of the n-gram analysis.
+#[derive(Debug, Clone, Default)]
+pub struct EventLimits {
+    pub max_content_bytes: Option<usize>,
+    pub max_tags: Option<usize>,
+}
+
+impl EventLimits {
+    pub fn check(&self, event: &Event) -> Result<(), SkipReason> {
+        if let Some(max_content_bytes) = self.max_content_bytes {
+            if event.content.len() > max_content_bytes {
+                return Err(SkipReason::TooLarge);
+            }
+        }
+        if let Some(max_tags) = self.max_tags {
+            if event.tags.len() > max_tags {
+                return Err(SkipReason::TooManyTags);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+
+    use crate::index::indexes::SkipReason;
+    use crate::index::limits::EventLimits;
+
+    #[test]
+    fn test_check() {
+        let event = EventBuilder::new(
+            Kind::TextNote,
+            "hello",
+            &[Tag::Hashtag("a".to_string()), Tag::Hashtag("b".to_string())],
+        )
+        .to_event(&Keys::generate())
+        .unwrap();
+
+        assert_eq!(EventLimits::default().check(&event), Ok(()));
+        let limits = EventLimits {
+            max_content_bytes: Some(5),
+            max_tags: Some(2),
+        };
+        assert_eq!(limits.check(&event), Ok(()));
+        let limits = EventLimits {
+            max_content_bytes: Some(4),
+            max_tags: None,
+        };
+        assert_eq!(limits.check(&event), Err(SkipReason::TooLarge));
+        let limits = EventLimits {
+            max_content_bytes: None,
+            max_tags: Some(1),
+        };
+        assert_eq!(limits.check(&event), Err(SkipReason::TooManyTags));
+    }
+}
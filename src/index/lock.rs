@@ -0,0 +1,191 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use elasticsearch::{CreateParts, DeleteParts, Elasticsearch, GetParts, IndexParts};
+use serde_json::{json, Value};
+
+use crate::index::indexes::create_side_index;
+
+/// locks shared by the replicas of every namespace, keyed by name
+const LOCK_INDEX: &str = "searchnos-locks";
+
+/// A lock document held by one replica until it is released or expires.
+#[derive(Debug)]
+pub struct Lock {
+    pub name: String,
+    owner: String,
+}
+
+/// Identifies this replica in lock documents.
+fn owner_id() -> String {
+    format!(
+        "{}-{:08x}",
+        std::env::var("HOSTNAME").unwrap_or_else(|_| "searchnos".to_string()),
+        rand::random::<u32>()
+    )
+}
+
+/// Whether a lock document was left behind by a replica that stopped without releasing it.
+fn is_expired(source: &Value, now: i64) -> bool {
+    source["expires_at"]
+        .as_i64()
+        .map_or(true, |expires_at| expires_at <= now)
+}
+
+fn lock_document(owner: &str, ttl: Duration) -> Value {
+    json!({
+        "owner": owner,
+        "expires_at": Utc::now().timestamp() + ttl.as_secs() as i64
+    })
+}
+
+pub async fn create_lock_index(es_client: &Elasticsearch) -> anyhow::Result<()> {
+    create_side_index(
+        es_client,
+        LOCK_INDEX,
+        json!({
+            "properties": {
+                "owner": { "type": "keyword" },
+                "expires_at": { "type": "date", "format": "epoch_second" }
+            }
+        }),
+    )
+    .await
+}
+
+/// Takes the lock `name` for `ttl` if no other replica holds it.
+///
+/// Locks left to expire instead of being released keep other replicas from repeating a task
+/// within `ttl`.
+pub async fn try_lock(
+    es_client: &Elasticsearch,
+    name: &str,
+    ttl: Duration,
+) -> anyhow::Result<Option<Lock>> {
+    let owner = owner_id();
+    let res = es_client
+        .create(CreateParts::IndexId(LOCK_INDEX, name))
+        .body(lock_document(&owner, ttl))
+        .send()
+        .await?;
+    match res.status_code().as_u16() {
+        status if (200..300).contains(&status) => {
+            return Ok(Some(Lock {
+                name: name.to_string(),
+                owner,
+            }))
+        }
+        409 => {}
+        status => return Err(anyhow::anyhow!("failed to take lock {}: {}", name, status)),
+    }
+
+    // held, possibly by a replica that stopped
+    let res = es_client
+        .get(GetParts::IndexId(LOCK_INDEX, name))
+        .send()
+        .await?;
+    if res.status_code().as_u16() == 404 {
+        // released meanwhile; retried by the caller
+        return Ok(None);
+    }
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to get lock {}: {}",
+            name,
+            res.status_code()
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    if !is_expired(&body["_source"], Utc::now().timestamp()) {
+        return Ok(None);
+    }
+    // only one of the replicas taking over an expired lock succeeds
+    let res = es_client
+        .index(IndexParts::IndexId(LOCK_INDEX, name))
+        .if_seq_no(body["_seq_no"].as_i64().unwrap_or_default())
+        .if_primary_term(body["_primary_term"].as_i64().unwrap_or_default())
+        .body(lock_document(&owner, ttl))
+        .send()
+        .await?;
+    match res.status_code().as_u16() {
+        status if (200..300).contains(&status) => {
+            log::info!(
+                "took over expired lock {} of {}",
+                name,
+                body["_source"]["owner"]
+            );
+            Ok(Some(Lock {
+                name: name.to_string(),
+                owner,
+            }))
+        }
+        409 => Ok(None),
+        status => Err(anyhow::anyhow!("failed to take lock {}: {}", name, status)),
+    }
+}
+
+/// Waits until the lock `name` is taken, polling every `interval`.
+pub async fn wait_for_lock(
+    es_client: &Elasticsearch,
+    name: &str,
+    ttl: Duration,
+    interval: Duration,
+) -> anyhow::Result<Lock> {
+    let mut waiting = false;
+    loop {
+        if let Some(lock) = try_lock(es_client, name, ttl).await? {
+            return Ok(lock);
+        }
+        if !waiting {
+            log::info!("waiting for another replica holding lock {}", name);
+            waiting = true;
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+impl Lock {
+    /// Releases the lock unless it expired and was taken over.
+    pub async fn release(self, es_client: &Elasticsearch) -> anyhow::Result<()> {
+        let res = es_client
+            .get(GetParts::IndexId(LOCK_INDEX, &self.name))
+            .send()
+            .await?;
+        if !res.status_code().is_success() {
+            return Ok(());
+        }
+        let body = res.json::<Value>().await?;
+        if body["_source"]["owner"] != self.owner.as_str() {
+            log::warn!("lock {} was taken over before it was released", self.name);
+            return Ok(());
+        }
+        let res = es_client
+            .delete(DeleteParts::IndexId(LOCK_INDEX, &self.name))
+            .if_seq_no(body["_seq_no"].as_i64().unwrap_or_default())
+            .if_primary_term(body["_primary_term"].as_i64().unwrap_or_default())
+            .send()
+            .await?;
+        if !res.status_code().is_success() && res.status_code().as_u16() != 409 {
+            return Err(anyhow::anyhow!(
+                "failed to release lock {}: {}",
+                self.name,
+                res.status_code()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::index::lock::is_expired;
+
+    #[test]
+    fn test_is_expired() {
+        assert!(!is_expired(&json!({ "expires_at": 1100 }), 1000));
+        assert!(is_expired(&json!({ "expires_at": 1000 }), 1000));
+        assert!(is_expired(&json!({}), 1000));
+    }
+}
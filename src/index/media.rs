@@ -0,0 +1,198 @@
+use nostr_sdk::Event;
+use serde::Serialize;
+
+const KIND_FILE_METADATA: u64 = 1063;
+
+/// Image, video or other file attached to an event, from a NIP-92 `imeta` tag or a NIP-94
+/// file metadata event.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Media {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// guessed from the extension of `url` when not given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+}
+
+impl Media {
+    fn set(&mut self, key: &str, value: &str) {
+        let value = value.trim();
+        if value.is_empty() {
+            return;
+        }
+        match key {
+            "url" => self.url = Some(value.to_string()),
+            "m" => self.mime_type = Some(value.to_lowercase()),
+            "alt" => self.alt = Some(value.to_string()),
+            "dim" => {
+                if let Some((width, height)) = value.split_once('x') {
+                    if let (Ok(width), Ok(height)) = (width.parse(), height.parse()) {
+                        self.width = Some(width);
+                        self.height = Some(height);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn finish(mut self) -> Option<Self> {
+        if self.mime_type.is_none() {
+            self.mime_type = self.url.as_deref().and_then(guess_mime_type);
+        }
+        if self.url.is_none() && self.mime_type.is_none() && self.alt.is_none() {
+            return None;
+        }
+        Some(self)
+    }
+
+    /// Whether the media is of type `kind`, e.g. `image`, or of any type for `media`.
+    pub fn is(&self, kind: &str) -> bool {
+        kind == "media"
+            || self.mime_type.as_deref().map_or(false, |mime_type| {
+                mime_type.starts_with(&format!("{}/", kind))
+            })
+    }
+}
+
+fn guess_mime_type(url: &str) -> Option<String> {
+    let path = url.split(['?', '#']).next()?;
+    let (_, extension) = path.rsplit_once('.')?;
+    let mime_type = match extension.to_lowercase().as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "svg" => "image/svg+xml",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        _ => return None,
+    };
+    Some(mime_type.to_string())
+}
+
+/// Media of the `imeta` tags of the event, or of the tags of a kind 1063 event.
+pub fn extract_media(event: &Event) -> Vec<Media> {
+    if event.kind.as_u64() == KIND_FILE_METADATA {
+        let mut media = Media::default();
+        for tag in &event.tags {
+            let tag = tag.as_vec();
+            if let (Some(key), Some(value)) = (tag.first(), tag.get(1)) {
+                media.set(key, value);
+            }
+        }
+        return media.finish().into_iter().collect();
+    }
+    event
+        .tags
+        .iter()
+        .filter_map(|tag| {
+            let tag = tag.as_vec();
+            if tag.first().map(|name| name.as_str()) != Some("imeta") {
+                return None;
+            }
+            let mut media = Media::default();
+            // each value is a space-delimited `<key> <value>` pair
+            for item in &tag[1..] {
+                if let Some((key, value)) = item.split_once(' ') {
+                    media.set(key, value);
+                }
+            }
+            media.finish()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::prelude::TagKind;
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+
+    use crate::index::media::{extract_media, Media};
+
+    fn tag(values: &[&str]) -> Tag {
+        Tag::Generic(
+            TagKind::Custom(values[0].to_string()),
+            values[1..].iter().map(|v| v.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn test_imeta() {
+        let event = EventBuilder::new(
+            Kind::TextNote,
+            "look https://example.com/cat.JPG",
+            &[
+                tag(&[
+                    "imeta",
+                    "url https://example.com/cat.JPG",
+                    "alt a cat on a sofa",
+                    "dim 640x480",
+                ]),
+                tag(&["imeta", "url https://example.com/v", "m Video/MP4"]),
+                tag(&["imeta", "unknown"]),
+            ],
+        )
+        .to_event(&Keys::generate())
+        .unwrap();
+        let media = extract_media(&event);
+        assert_eq!(
+            media,
+            vec![
+                Media {
+                    url: Some("https://example.com/cat.JPG".to_string()),
+                    mime_type: Some("image/jpeg".to_string()),
+                    alt: Some("a cat on a sofa".to_string()),
+                    width: Some(640),
+                    height: Some(480),
+                },
+                Media {
+                    url: Some("https://example.com/v".to_string()),
+                    mime_type: Some("video/mp4".to_string()),
+                    ..Default::default()
+                },
+            ]
+        );
+        assert!(media[0].is("image") && media[0].is("media") && !media[0].is("video"));
+    }
+
+    #[test]
+    fn test_file_metadata() {
+        let event = EventBuilder::new(
+            Kind::from(1063),
+            "my podcast",
+            &[
+                tag(&["url", "https://example.com/episode"]),
+                tag(&["m", "audio/mpeg"]),
+                tag(&["dim", "invalid"]),
+                tag(&["alt", "episode 1"]),
+            ],
+        )
+        .to_event(&Keys::generate())
+        .unwrap();
+        assert_eq!(
+            extract_media(&event),
+            vec![Media {
+                url: Some("https://example.com/episode".to_string()),
+                mime_type: Some("audio/mpeg".to_string()),
+                alt: Some("episode 1".to_string()),
+                ..Default::default()
+            }]
+        );
+        let event = EventBuilder::new(Kind::TextNote, "hello", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        assert!(extract_media(&event).is_empty());
+    }
+}
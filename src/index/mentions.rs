@@ -0,0 +1,164 @@
+use nostr_sdk::prelude::{
+    EventId, FromBech32, Nip19Event, ParameterizedReplaceableEvent, Profile, XOnlyPublicKey,
+};
+use serde::Serialize;
+
+const BECH32_CHARS: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const ENTITY_PREFIXES: [&str; 5] = ["npub1", "note1", "nprofile1", "nevent1", "naddr1"];
+
+/// Entities referred to by NIP-19 strings in the text, with or without the NIP-21 `nostr:`
+/// scheme.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Mentions {
+    /// lowercase hex ids of `note` and `nevent`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<String>,
+    /// lowercase hex pubkeys of `npub` and `nprofile`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pubkeys: Vec<String>,
+    /// `<kind>:<pubkey>:<identifier>` of `naddr`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub addresses: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Entity {
+    Event(String),
+    Pubkey(String),
+    Address(String),
+}
+
+impl Mentions {
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty() && self.pubkeys.is_empty() && self.addresses.is_empty()
+    }
+
+    fn push(&mut self, entity: Entity) {
+        let (values, value) = match entity {
+            Entity::Event(id) => (&mut self.events, id),
+            Entity::Pubkey(pubkey) => (&mut self.pubkeys, pubkey),
+            Entity::Address(address) => (&mut self.addresses, address),
+        };
+        if !values.contains(&value) {
+            values.push(value);
+        }
+    }
+}
+
+fn decode_entity(bech32: &str) -> Option<Entity> {
+    let (hrp, _) = bech32.split_once('1')?;
+    match hrp {
+        "npub" => XOnlyPublicKey::from_bech32(bech32)
+            .ok()
+            .map(|pubkey| Entity::Pubkey(pubkey.to_string())),
+        "note" => EventId::from_bech32(bech32)
+            .ok()
+            .map(|id| Entity::Event(id.to_hex())),
+        "nprofile" => Profile::from_bech32(bech32)
+            .ok()
+            .map(|profile| Entity::Pubkey(profile.public_key.to_string())),
+        "nevent" => Nip19Event::from_bech32(bech32)
+            .ok()
+            .map(|event| Entity::Event(event.event_id.to_hex())),
+        "naddr" => ParameterizedReplaceableEvent::from_bech32(bech32)
+            .ok()
+            .map(|address| {
+                Entity::Address(format!(
+                    "{}:{}:{}",
+                    address.kind.as_u32(),
+                    address.pubkey,
+                    address.identifier
+                ))
+            }),
+        _ => None,
+    }
+}
+
+/// Byte range of the next NIP-19 string in `text`, including its `nostr:` scheme.
+fn find_entity(text: &str) -> Option<(usize, usize)> {
+    let bytes = text.as_bytes();
+    (0..bytes.len()).find_map(|i| {
+        if i > 0 && bytes[i - 1].is_ascii_alphanumeric() {
+            return None;
+        }
+        let prefix = ENTITY_PREFIXES
+            .iter()
+            .find(|prefix| bytes[i..].starts_with(prefix.as_bytes()))?;
+        let data = bytes[i + prefix.len()..]
+            .iter()
+            .take_while(|b| BECH32_CHARS.as_bytes().contains(*b))
+            .count();
+        let start = if text[..i].ends_with("nostr:") {
+            i - "nostr:".len()
+        } else {
+            i
+        };
+        Some((start, i + prefix.len() + data))
+    })
+}
+
+/// Removes the NIP-19 strings that decode from `text`, which would only add noise to the
+/// full-text index, and returns the entities they refer to.
+pub fn strip_mentions(text: &str) -> (String, Mentions) {
+    let mut mentions = Mentions::default();
+    let mut stripped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some((start, end)) = find_entity(rest) {
+        let entity = &rest[start..end];
+        match decode_entity(entity.strip_prefix("nostr:").unwrap_or(entity)) {
+            Some(entity) => {
+                mentions.push(entity);
+                stripped.push_str(&rest[..start]);
+            }
+            None => stripped.push_str(&rest[..end]),
+        }
+        rest = &rest[end..];
+    }
+    stripped.push_str(rest);
+    (stripped, mentions)
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::prelude::ToBech32;
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+
+    use crate::index::mentions::strip_mentions;
+    use crate::link::nip19;
+
+    #[test]
+    fn test_strip_mentions() {
+        let keys = Keys::generate();
+        let npub = keys.public_key().to_bech32().unwrap();
+        let note = EventBuilder::new(Kind::TextNote, "hello", &[])
+            .to_event(&keys)
+            .unwrap();
+        let nevent = nip19(&note, &["wss://r.example.com".to_string()]);
+        let article = EventBuilder::new(
+            Kind::LongFormTextNote,
+            "hello",
+            &[Tag::Identifier("article".to_string())],
+        )
+        .to_event(&keys)
+        .unwrap();
+        let naddr = nip19(&article, &[]);
+
+        let (text, mentions) = strip_mentions(&format!(
+            "gm nostr:{}, see nostr:{} and {}! nostr:npub1invalid {}",
+            npub, nevent, naddr, npub
+        ));
+        assert_eq!(text, "gm , see  and ! nostr:npub1invalid ");
+        assert_eq!(mentions.pubkeys, vec![keys.public_key().to_string()]);
+        assert_eq!(mentions.events, vec![note.id.to_hex()]);
+        assert_eq!(
+            mentions.addresses,
+            vec![format!("30023:{}:article", keys.public_key())]
+        );
+
+        // only whole words
+        let (text, mentions) = strip_mentions(&format!("x{}", npub));
+        assert_eq!(text, format!("x{}", npub));
+        assert!(mentions.is_empty());
+        assert_eq!(strip_mentions("hello world").0, "hello world");
+    }
+}
@@ -0,0 +1,463 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use elasticsearch::params::Conflicts;
+use elasticsearch::{
+    DeleteParts, Elasticsearch, GetParts, IndexParts, SearchParts, UpdateByQueryParts,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+use crate::index::completion::set_nip05;
+use crate::index::indexes::create_side_index;
+use crate::index::lock::try_lock;
+use crate::index::profile::Profile;
+
+const CHECK_EVERY: Duration = Duration::from_secs(60);
+/// claims rechecked per round
+const BATCH_SIZE: i64 = 100;
+/// failed checks in a row before a domain that cannot be reached is no longer trusted
+const MAX_FAILURES: u32 = 3;
+/// first retry after a failed check; doubled with every failure, up to the recheck interval
+const RETRY_AFTER_SECS: u64 = 60 * 60;
+/// fraction of the interval by which checks are spread, so that they do not come in bursts
+const JITTER: f64 = 0.2;
+
+fn nip05_index(index_alias_name: &str) -> String {
+    format!("searchnos-nip05-{}", index_alias_name)
+}
+
+/// Creates the side index of the NIP-05 claims of profiles, by pubkey.
+pub async fn create_nip05_index(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+) -> anyhow::Result<()> {
+    create_side_index(
+        es_client,
+        &nip05_index(index_alias_name),
+        json!({
+            "dynamic": false,
+            "properties": {
+                "pubkey": { "type": "keyword" },
+                "nip05": { "type": "keyword" },
+                "verified": { "type": "boolean" },
+                "failures": { "type": "integer" },
+                "checked_at": { "type": "date", "format": "epoch_second" },
+                "next_check_at": { "type": "date", "format": "epoch_second" }
+            }
+        }),
+    )
+    .await
+}
+
+/// Splits a NIP-05 identifier into the name and the domain serving `.well-known/nostr.json`.
+fn parse_identifier(nip05: &str) -> Option<(String, String)> {
+    let nip05 = nip05.trim().to_lowercase();
+    let (name, domain) = nip05.split_once('@')?;
+    if name.is_empty() || domain.is_empty() || domain.contains(['/', '?', '#', '@']) {
+        return None;
+    }
+    Some((name.to_string(), domain.to_string()))
+}
+
+/// Whether a `nostr.json` document maps `name` to `pubkey`.
+fn check_names(body: &Value, name: &str, pubkey: &str) -> bool {
+    body["names"][name]
+        .as_str()
+        .map_or(false, |value| value.to_lowercase() == pubkey)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Verified,
+    /// the domain answered without the pubkey
+    Mismatch,
+    /// the domain could not be reached or did not answer a `nostr.json` document
+    Unreachable,
+}
+
+/// Spreads `secs` by up to `JITTER` of it; `jitter` is between -1 and 1.
+fn jittered(secs: u64, jitter: f64) -> u64 {
+    (secs as f64 * (1.0 + JITTER * jitter.clamp(-1.0, 1.0))) as u64
+}
+
+/// The NIP-05 claim of the latest profile of a pubkey and when it is checked next.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Record {
+    pubkey: String,
+    nip05: String,
+    verified: bool,
+    failures: u32,
+    checked_at: Option<i64>,
+    next_check_at: i64,
+}
+
+impl Record {
+    /// A claim not checked yet, checked in the next round.
+    fn pending(pubkey: &str, nip05: &str, now: i64) -> Self {
+        Record {
+            pubkey: pubkey.to_string(),
+            nip05: nip05.to_string(),
+            verified: false,
+            failures: 0,
+            checked_at: None,
+            next_check_at: now,
+        }
+    }
+
+    /// The record after a check; a mismatch is trusted at once, unreachable domains only after
+    /// `MAX_FAILURES` checks in a row.
+    fn checked(&self, outcome: Outcome, now: i64, interval_secs: u64, jitter: f64) -> Self {
+        let (verified, failures) = match outcome {
+            Outcome::Verified => (true, 0),
+            Outcome::Mismatch => (false, self.failures + 1),
+            Outcome::Unreachable => (
+                self.verified && self.failures + 1 < MAX_FAILURES,
+                self.failures + 1,
+            ),
+        };
+        let wait = if failures == 0 {
+            interval_secs
+        } else {
+            RETRY_AFTER_SECS
+                .saturating_mul(1 << (failures - 1).min(16))
+                .min(interval_secs)
+        };
+        Record {
+            verified,
+            failures,
+            checked_at: Some(now),
+            next_check_at: now + jittered(wait, jitter) as i64,
+            ..self.clone()
+        }
+    }
+}
+
+/// Checks the NIP-05 identifiers of profiles and rechecks them every `interval`, keeping the
+/// `profile.nip05_verified` field of their documents up to date.
+#[derive(Debug)]
+pub struct Nip05Verifier {
+    pub interval: Duration,
+    http_client: reqwest::Client,
+}
+
+impl Nip05Verifier {
+    pub fn new(interval: Duration) -> Self {
+        Nip05Verifier {
+            interval,
+            http_client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                // NIP-05: fetchers must ignore redirects
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .expect("failed to build the HTTP client"),
+        }
+    }
+
+    async fn verify(&self, nip05: &str, pubkey: &str) -> Outcome {
+        let (name, domain) = match parse_identifier(nip05) {
+            Some(identifier) => identifier,
+            None => return Outcome::Mismatch,
+        };
+        let url = format!("https://{}/.well-known/nostr.json", domain);
+        let res = match self
+            .http_client
+            .get(&url)
+            .query(&[("name", &name)])
+            .send()
+            .await
+        {
+            Ok(res) if res.status().is_success() => res,
+            Ok(res) => {
+                log::debug!("{} answered {}", url, res.status());
+                return Outcome::Unreachable;
+            }
+            Err(e) => {
+                log::debug!("failed to fetch {}: {}", url, e);
+                return Outcome::Unreachable;
+            }
+        };
+        match res.json::<Value>().await {
+            Ok(body) if check_names(&body, &name, pubkey) => Outcome::Verified,
+            Ok(_) => Outcome::Mismatch,
+            Err(_) => Outcome::Unreachable,
+        }
+    }
+
+    /// Sets the verification status of a newly indexed profile from the status of its claim,
+    /// and schedules claims that changed for a check.
+    pub async fn annotate(
+        &self,
+        es_client: &Elasticsearch,
+        index_alias_name: &str,
+        pubkey: &str,
+        profile: &mut Profile,
+    ) -> anyhow::Result<()> {
+        let index_name = nip05_index(index_alias_name);
+        let nip05 = match &profile.nip05 {
+            Some(nip05) => nip05.clone(),
+            None => {
+                let res = es_client
+                    .delete(DeleteParts::IndexId(&index_name, pubkey))
+                    .send()
+                    .await?;
+                let status = res.status_code().as_u16();
+                if !res.status_code().is_success() && status != 404 {
+                    return Err(anyhow::anyhow!("failed to delete NIP-05 claim: {}", status));
+                }
+                return Ok(());
+            }
+        };
+        let res = es_client
+            .get(GetParts::IndexId(&index_name, pubkey))
+            .send()
+            .await?;
+        if res.status_code().is_success() {
+            let body = res.json::<Value>().await?;
+            if let Ok(record) = serde_json::from_value::<Record>(body["_source"].clone()) {
+                if record.nip05 == nip05 {
+                    profile.nip05_verified = Some(record.verified);
+                    return Ok(());
+                }
+            }
+        } else if res.status_code().as_u16() != 404 {
+            return Err(anyhow::anyhow!(
+                "failed to get NIP-05 claim: {}",
+                res.status_code()
+            ));
+        }
+        profile.nip05_verified = Some(false);
+        let record = Record::pending(pubkey, &nip05, Utc::now().timestamp());
+        write_record(es_client, index_alias_name, &record).await
+    }
+}
+
+async fn write_record(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    record: &Record,
+) -> anyhow::Result<()> {
+    let res = es_client
+        .index(IndexParts::IndexId(
+            &nip05_index(index_alias_name),
+            &record.pubkey,
+        ))
+        .body(record)
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to write NIP-05 claim: {} {}",
+            status_code,
+            body
+        ));
+    }
+    Ok(())
+}
+
+async fn due_records(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    now: i64,
+) -> anyhow::Result<Vec<Record>> {
+    let res = es_client
+        .search(SearchParts::Index(&[&nip05_index(index_alias_name)]))
+        .size(BATCH_SIZE)
+        .body(json!({
+            "query": { "range": { "next_check_at": { "lte": now } } },
+            "sort": [{ "next_check_at": "asc" }]
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to search NIP-05 claims: {}",
+            res.status_code()
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    Ok(body["hits"]["hits"]
+        .as_array()
+        .unwrap_or(&vec![])
+        .iter()
+        .filter_map(|hit| serde_json::from_value(hit["_source"].clone()).ok())
+        .collect())
+}
+
+/// Sets `profile.nip05_verified` on the profiles of `pubkey` claiming `nip05`.
+async fn update_profiles(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    record: &Record,
+) -> anyhow::Result<()> {
+    let res = es_client
+        .update_by_query(UpdateByQueryParts::Index(&[index_alias_name]))
+        .conflicts(Conflicts::Proceed)
+        .body(json!({
+            "query": {
+                "bool": {
+                    "must": [
+                        { "term": { "event.pubkey": record.pubkey } },
+                        { "term": { "event.kind": 0 } },
+                        { "term": { "profile.nip05": record.nip05 } }
+                    ]
+                }
+            },
+            "script": {
+                "source": "ctx._source.profile.nip05_verified = params.verified",
+                "params": { "verified": record.verified }
+            }
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to update NIP-05 status: {} {}",
+            status_code,
+            body
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the claims due, returning how many changed status.
+async fn recheck(state: &AppState, verifier: &Nip05Verifier) -> anyhow::Result<usize> {
+    let es_client = &state.es_client;
+    let index_alias_name = &state.index_alias_name;
+    let mut changed = 0;
+    for record in due_records(es_client, index_alias_name, Utc::now().timestamp()).await? {
+        let outcome = verifier.verify(&record.nip05, &record.pubkey).await;
+        let checked = record.checked(
+            outcome,
+            Utc::now().timestamp(),
+            verifier.interval.as_secs(),
+            rand::random::<f64>() * 2.0 - 1.0,
+        );
+        if checked.verified != record.verified {
+            update_profiles(es_client, index_alias_name, &checked).await?;
+            if state.completions.is_some() {
+                set_nip05(
+                    es_client,
+                    index_alias_name,
+                    &record.pubkey,
+                    &record.nip05,
+                    checked.verified,
+                )
+                .await?;
+            }
+            log::info!(
+                "{} is {} {}",
+                record.pubkey,
+                if checked.verified { "now" } else { "no longer" },
+                record.nip05
+            );
+            changed += 1;
+        }
+        write_record(es_client, index_alias_name, &checked).await?;
+    }
+    Ok(changed)
+}
+
+pub fn spawn_nip05_verifier(state: Arc<AppState>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // one replica checks per round
+        let lock_name = format!("nip05-{}", state.index_alias_name);
+        let lock_ttl = CHECK_EVERY - Duration::from_secs(5);
+        loop {
+            tokio::time::sleep(CHECK_EVERY).await;
+            let verifier = match &state.nip05_verifier {
+                Some(verifier) => verifier,
+                None => return,
+            };
+            match try_lock(&state.es_client, &lock_name, lock_ttl).await {
+                Ok(Some(_)) => {}
+                Ok(None) => continue,
+                Err(e) => {
+                    log::error!("Error taking the NIP-05 lock: {}", e);
+                    continue;
+                }
+            }
+            if let Err(e) = recheck(&state, verifier).await {
+                log::error!("Error rechecking NIP-05 claims: {}", e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::index::nip05::{
+        check_names, parse_identifier, Outcome, Record, MAX_FAILURES, RETRY_AFTER_SECS,
+    };
+
+    #[test]
+    fn test_parse_identifier() {
+        assert_eq!(
+            parse_identifier("Alice@Example.com"),
+            Some(("alice".to_string(), "example.com".to_string()))
+        );
+        assert_eq!(
+            parse_identifier("_@example.com"),
+            Some(("_".to_string(), "example.com".to_string()))
+        );
+        assert_eq!(parse_identifier("example.com"), None);
+        assert_eq!(parse_identifier("alice@"), None);
+        assert_eq!(parse_identifier("alice@example.com/path"), None);
+    }
+
+    #[test]
+    fn test_check_names() {
+        let pubkey = "b".repeat(64);
+        let body = json!({ "names": { "alice": pubkey.to_uppercase() } });
+        assert!(check_names(&body, "alice", &pubkey));
+        assert!(!check_names(&body, "bob", &pubkey));
+        assert!(!check_names(&json!({ "names": [] }), "alice", &pubkey));
+    }
+
+    #[test]
+    fn test_checked() {
+        let day = 24 * 60 * 60;
+        let pending = Record::pending("ab", "alice@example.com", 1_000);
+        let verified = pending.checked(Outcome::Verified, 1_000, day, 0.0);
+        assert!(verified.verified);
+        assert_eq!(verified.next_check_at, 1_000 + day as i64);
+        // spread by up to a fifth of the interval
+        let early = pending.checked(Outcome::Verified, 1_000, day, -1.0);
+        assert_eq!(early.next_check_at, 1_000 + (day as f64 * 0.8) as i64);
+
+        // unreachable domains are trusted until they fail repeatedly, retried sooner
+        let mut record = verified.clone();
+        for failures in 1..MAX_FAILURES {
+            record = record.checked(Outcome::Unreachable, 2_000, day, 0.0);
+            assert!(record.verified);
+            assert_eq!(record.failures, failures);
+        }
+        assert_eq!(record.next_check_at, 2_000 + (RETRY_AFTER_SECS * 2) as i64);
+        assert!(
+            !record
+                .checked(Outcome::Unreachable, 3_000, day, 0.0)
+                .verified
+        );
+
+        // the pubkey missing from the domain downgrades at once
+        assert!(
+            !verified
+                .checked(Outcome::Mismatch, 2_000, day, 0.0)
+                .verified
+        );
+        assert!(
+            !pending
+                .checked(Outcome::Unreachable, 2_000, day, 0.0)
+                .verified
+        );
+    }
+}
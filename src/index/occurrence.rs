@@ -0,0 +1,132 @@
+use chrono::NaiveDate;
+use nostr_sdk::Event;
+use serde::Serialize;
+
+pub const KIND_LIVE_EVENT: u64 = 30311;
+pub const KIND_DATE_EVENT: u64 = 31922;
+pub const KIND_TIME_EVENT: u64 = 31923;
+pub const KIND_CALENDAR: u64 = 31924;
+pub const KIND_RSVP: u64 = 31925;
+
+/// When and where a NIP-53 live activity or NIP-52 calendar event takes place, indexed for
+/// searches like `happening:weekend`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Occurrence {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// unix time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub starts: Option<u64>,
+    /// unix time, inclusive
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ends: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    /// e.g. `live` of a live activity
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+}
+
+fn parse_day(value: &str) -> Option<u64> {
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    u64::try_from(date.and_hms_opt(0, 0, 0)?.timestamp()).ok()
+}
+
+/// Parses the tags of kinds 30311, 31922 and 31923; invalid times are skipped.
+///
+/// The end date of a date-based event is exclusive, and one without an end lasts the day.
+pub fn extract_occurrence(event: &Event) -> Option<Occurrence> {
+    let kind = event.kind.as_u64();
+    let (start_tag, end_tag) = match kind {
+        KIND_LIVE_EVENT => ("starts", "ends"),
+        KIND_DATE_EVENT | KIND_TIME_EVENT => ("start", "end"),
+        _ => return None,
+    };
+    let parse_time = |value: &str| {
+        if kind == KIND_DATE_EVENT {
+            parse_day(value)
+        } else {
+            value.parse::<u64>().ok()
+        }
+    };
+    let mut occurrence = Occurrence::default();
+    for tag in &event.tags {
+        let tag = tag.as_vec();
+        let value = match tag.get(1).map(|value| value.trim()) {
+            Some(value) if !value.is_empty() => value,
+            _ => continue,
+        };
+        match tag[0].as_str() {
+            "title" => occurrence.title = Some(value.to_string()),
+            "location" => occurrence.location = Some(value.to_string()),
+            "status" => occurrence.status = Some(value.to_lowercase()),
+            name if name == start_tag => occurrence.starts = parse_time(value),
+            name if name == end_tag => occurrence.ends = parse_time(value),
+            _ => {}
+        }
+    }
+    if kind == KIND_DATE_EVENT {
+        occurrence.ends = match (occurrence.starts, occurrence.ends) {
+            (_, Some(ends)) => ends.checked_sub(1),
+            (Some(starts), None) => Some(starts + 24 * 60 * 60 - 1),
+            (None, None) => None,
+        };
+    }
+    Some(occurrence)
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+
+    use crate::index::occurrence::extract_occurrence;
+
+    fn event(kind: u64, tags: &[Vec<&str>]) -> nostr_sdk::Event {
+        let tags = tags
+            .iter()
+            .map(|tag| Tag::parse(tag.clone()).unwrap())
+            .collect::<Vec<_>>();
+        EventBuilder::new(Kind::from(kind), "", &tags)
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_extract_occurrence() {
+        let occurrence = extract_occurrence(&event(
+            31922,
+            &[
+                vec!["d", "meetup"],
+                vec!["title", "Nostr meetup"],
+                vec!["start", "2024-03-16"],
+                vec!["location", "Tokyo"],
+            ],
+        ))
+        .unwrap();
+        assert_eq!(occurrence.title, Some("Nostr meetup".to_string()));
+        assert_eq!(occurrence.starts, Some(1710547200));
+        assert_eq!(occurrence.ends, Some(1710633599));
+
+        let occurrence = extract_occurrence(&event(
+            31922,
+            &[vec!["start", "2024-03-16"], vec!["end", "2024-03-18"]],
+        ))
+        .unwrap();
+        assert_eq!(occurrence.ends, Some(1710719999));
+
+        let occurrence = extract_occurrence(&event(
+            30311,
+            &[
+                vec!["starts", "1710583200"],
+                vec!["ends", "soon"],
+                vec!["status", "LIVE"],
+            ],
+        ))
+        .unwrap();
+        assert_eq!(occurrence.starts, Some(1710583200));
+        assert_eq!(occurrence.ends, None);
+        assert_eq!(occurrence.status, Some("live".to_string()));
+
+        assert_eq!(extract_occurrence(&event(31925, &[])), None);
+    }
+}
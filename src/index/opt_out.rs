@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use elasticsearch::{DeleteByQueryParts, DeleteParts, Elasticsearch, IndexParts, SearchParts};
+use nostr_sdk::{Event, Kind};
+use serde_json::{json, Value};
+
+use crate::index::completion::remove_profile;
+
+/// Tag marking an opt-out, e.g. `["noindex"]` for `noindex` or `["t", "noindex"]` for `t:noindex`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptOutTag {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+/// Parses a comma-separated list like `noindex,t:noindex`.
+pub fn parse_opt_out_tags(s: &str) -> Vec<OptOutTag> {
+    s.split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| match item.split_once(':') {
+            Some((name, value)) => OptOutTag {
+                name: name.to_string(),
+                value: Some(value.to_string()),
+            },
+            None => OptOutTag {
+                name: item.to_string(),
+                value: None,
+            },
+        })
+        .collect()
+}
+
+fn has_opt_out_tag(event: &Event, tags: &[OptOutTag]) -> bool {
+    event.tags.iter().any(|tag| {
+        let tag = tag.as_vec();
+        tags.iter().any(|opt_out| {
+            tag.first() == Some(&opt_out.name)
+                && match &opt_out.value {
+                    Some(value) => tag.get(1) == Some(value),
+                    None => true,
+                }
+        })
+    })
+}
+
+/// Authors who opted out of search with a tagged profile (kind 0).
+///
+/// Opt-outs are kept in a side index so that they survive restarts; a newer profile
+/// without the tag opts the author back in.
+#[derive(Debug)]
+pub struct OptOut {
+    tags: Vec<OptOutTag>,
+    index_name: String,
+    /// pubkey -> created_at of the profile that opted out
+    authors: RwLock<HashMap<String, u64>>,
+}
+
+impl OptOut {
+    pub fn new(tags: Vec<OptOutTag>, index_alias_name: &str) -> Self {
+        OptOut {
+            tags,
+            index_name: format!("searchnos-optout-{}", index_alias_name),
+            authors: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub async fn load(&self, es_client: &Elasticsearch) -> anyhow::Result<()> {
+        if self.tags.is_empty() {
+            return Ok(());
+        }
+        let res = es_client
+            .search(SearchParts::Index(&[self.index_name.as_str()]))
+            .body(json!({ "query": { "match_all": {} } }))
+            .size(10_000)
+            .send()
+            .await?;
+        if res.status_code().as_u16() == 404 {
+            return Ok(());
+        }
+        if !res.status_code().is_success() {
+            return Err(anyhow::anyhow!(
+                "failed to load opt-outs: {}",
+                res.status_code()
+            ));
+        }
+        let body = res.json::<Value>().await?;
+        let mut authors = self.authors.write().unwrap();
+        for hit in body["hits"]["hits"].as_array().unwrap_or(&vec![]) {
+            if let (Some(pubkey), Some(created_at)) =
+                (hit["_id"].as_str(), hit["_source"]["created_at"].as_u64())
+            {
+                authors.insert(pubkey.to_string(), created_at);
+            }
+        }
+        log::info!("{} author(s) opted out of search", authors.len());
+        Ok(())
+    }
+
+    pub fn is_opted_out(&self, pubkey: &str) -> bool {
+        self.authors.read().unwrap().contains_key(pubkey)
+    }
+
+    /// Returns true when `event` must not be indexed, recording opt-outs and opt-ins on the way.
+    pub async fn handle(
+        &self,
+        es_client: &Elasticsearch,
+        index_alias_name: &str,
+        event: &Event,
+    ) -> anyhow::Result<bool> {
+        if self.tags.is_empty() {
+            return Ok(false);
+        }
+        let pubkey = event.pubkey.to_string();
+        let created_at = event.created_at.as_u64();
+        let opted_out_at = self.authors.read().unwrap().get(&pubkey).copied();
+
+        if has_opt_out_tag(event, &self.tags) {
+            if event.kind == Kind::Metadata && opted_out_at.is_none() {
+                self.opt_out(es_client, index_alias_name, &pubkey, created_at)
+                    .await?;
+            }
+            return Ok(true);
+        }
+        match opted_out_at {
+            Some(opted_out_at) if event.kind == Kind::Metadata && opted_out_at < created_at => {
+                self.opt_in(es_client, &pubkey).await?;
+                Ok(false)
+            }
+            Some(_) => Ok(true),
+            None => Ok(false),
+        }
+    }
+
+    async fn opt_out(
+        &self,
+        es_client: &Elasticsearch,
+        index_alias_name: &str,
+        pubkey: &str,
+        created_at: u64,
+    ) -> anyhow::Result<()> {
+        let res = es_client
+            .index(IndexParts::IndexId(&self.index_name, pubkey))
+            .body(json!({ "created_at": created_at }))
+            .send()
+            .await?;
+        if !res.status_code().is_success() {
+            return Err(anyhow::anyhow!(
+                "failed to record opt-out: {}",
+                res.status_code()
+            ));
+        }
+        self.authors
+            .write()
+            .unwrap()
+            .insert(pubkey.to_string(), created_at);
+
+        let res = es_client
+            .delete_by_query(DeleteByQueryParts::Index(&[index_alias_name]))
+            .body(json!({
+                "query": {
+                    "term": {
+                        "event.pubkey": pubkey
+                    }
+                }
+            }))
+            .send()
+            .await?;
+        if !res.status_code().is_success() {
+            return Err(anyhow::anyhow!(
+                "failed to purge events of {}: {}",
+                pubkey,
+                res.status_code()
+            ));
+        }
+        let body = res.json::<Value>().await?;
+        log::info!(
+            "{} opted out of search; purged {} event(s)",
+            pubkey,
+            body["deleted"]
+        );
+        remove_profile(es_client, index_alias_name, pubkey, None).await
+    }
+
+    async fn opt_in(&self, es_client: &Elasticsearch, pubkey: &str) -> anyhow::Result<()> {
+        let res = es_client
+            .delete(DeleteParts::IndexId(&self.index_name, pubkey))
+            .send()
+            .await?;
+        if !res.status_code().is_success() && res.status_code().as_u16() != 404 {
+            return Err(anyhow::anyhow!(
+                "failed to remove opt-out: {}",
+                res.status_code()
+            ));
+        }
+        self.authors.write().unwrap().remove(pubkey);
+        log::info!("{} opted back in to search", pubkey);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+
+    use crate::index::opt_out::{has_opt_out_tag, parse_opt_out_tags, OptOutTag};
+
+    #[test]
+    fn test_parse_opt_out_tags() {
+        assert_eq!(
+            parse_opt_out_tags("noindex, t:noindex"),
+            vec![
+                OptOutTag {
+                    name: "noindex".to_string(),
+                    value: None
+                },
+                OptOutTag {
+                    name: "t".to_string(),
+                    value: Some("noindex".to_string())
+                },
+            ]
+        );
+        assert!(parse_opt_out_tags("").is_empty());
+    }
+
+    #[test]
+    fn test_has_opt_out_tag() {
+        let keys = Keys::generate();
+        let tags = parse_opt_out_tags("noindex,t:noindex");
+        let event = |tag: Vec<&str>| {
+            EventBuilder::new(Kind::TextNote, "hello", &[Tag::parse(tag).unwrap()])
+                .to_event(&keys)
+                .unwrap()
+        };
+        assert!(has_opt_out_tag(&event(vec!["noindex"]), &tags));
+        assert!(has_opt_out_tag(&event(vec!["t", "noindex"]), &tags));
+        assert!(!has_opt_out_tag(&event(vec!["t", "nostr"]), &tags));
+        assert!(!has_opt_out_tag(&event(vec!["t", "noindex"]), &[]));
+    }
+}
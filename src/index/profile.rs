@@ -0,0 +1,133 @@
+use elasticsearch::{Elasticsearch, ReindexParts};
+use nostr_sdk::{Event, Kind};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::index::indexes::profiles_index_name;
+
+/// Fields of kind 0 metadata indexed separately, so that name matches can be ranked
+/// above matches in `about`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub about: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nip05: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lud16: Option<String>,
+    /// whether the domain of `nip05` confirms it, if NIP-05 claims are checked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nip05_verified: Option<bool>,
+}
+
+/// Parses the metadata of a kind 0 event; fields that are missing or not strings are skipped.
+pub fn extract_profile(event: &Event) -> Option<Profile> {
+    if event.kind != Kind::Metadata {
+        return None;
+    }
+    let content: Value = serde_json::from_str(&event.content).ok()?;
+    let field = |name: &str| {
+        content[name]
+            .as_str()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+    };
+    Some(Profile {
+        name: field("name"),
+        // some clients use camelCase
+        display_name: field("display_name").or_else(|| field("displayName")),
+        about: field("about"),
+        nip05: field("nip05").map(|s| s.to_lowercase()),
+        lud16: field("lud16").map(|s| s.to_lowercase()),
+        nip05_verified: None,
+    })
+}
+
+/// Copies the latest profile of each author in the dated indices into the profiles index,
+/// for the profiles indexed before it was enabled; returns the documents written.
+pub async fn backfill_profiles(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    index_name_prefix: &str,
+) -> anyhow::Result<u64> {
+    let profiles_index = profiles_index_name(index_name_prefix);
+    let res = es_client
+        .reindex(ReindexParts::None)
+        .wait_for_completion(true)
+        .refresh(true)
+        .body(json!({
+            "source": {
+                "index": index_alias_name,
+                "query": {
+                    "bool": {
+                        "filter": [{ "term": { "event.kind": 0 } }],
+                        "must_not": [{ "term": { "_index": profiles_index } }]
+                    }
+                }
+            },
+            // keyed and versioned like the profiles written since, so that the latest one wins
+            "dest": { "index": profiles_index, "version_type": "external_gte" },
+            "script": {
+                "source": "ctx._id = ctx._source.delegator != null ? ctx._source.delegator \
+                    : ctx._source.event.pubkey; ctx._version = ctx._source.event.created_at"
+            },
+            "conflicts": "proceed"
+        }))
+        .send()
+        .await?;
+    let status_code = res.status_code();
+    let body = res.json::<Value>().await?;
+    let failures = body["failures"].as_array().map(|f| f.len()).unwrap_or(0);
+    if !status_code.is_success() || failures > 0 {
+        return Err(anyhow::anyhow!(
+            "failed to backfill profiles: {} {}",
+            status_code,
+            body
+        ));
+    }
+    Ok(body["created"].as_u64().unwrap_or_default() + body["updated"].as_u64().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind};
+
+    use crate::index::profile::{extract_profile, Profile};
+
+    #[test]
+    fn test_extract_profile() {
+        let keys = Keys::generate();
+        let event = EventBuilder::new(
+            Kind::Metadata,
+            r#"{"name":"alice","displayName":"Alice","about":"nostr dev","nip05":"Alice@Example.com","lud16":"","bot":false}"#,
+            &[],
+        )
+        .to_event(&keys)
+        .unwrap();
+        assert_eq!(
+            extract_profile(&event),
+            Some(Profile {
+                name: Some("alice".to_string()),
+                display_name: Some("Alice".to_string()),
+                about: Some("nostr dev".to_string()),
+                nip05: Some("alice@example.com".to_string()),
+                lud16: None,
+                nip05_verified: None,
+            })
+        );
+
+        let event = EventBuilder::new(Kind::Metadata, "not json", &[])
+            .to_event(&keys)
+            .unwrap();
+        assert_eq!(extract_profile(&event), None);
+
+        let event = EventBuilder::new(Kind::TextNote, r#"{"name":"alice"}"#, &[])
+            .to_event(&keys)
+            .unwrap();
+        assert_eq!(extract_profile(&event), None);
+    }
+}
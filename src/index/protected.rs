@@ -0,0 +1,105 @@
+use elasticsearch::indices::IndicesPutMappingParts;
+use elasticsearch::params::Conflicts;
+use elasticsearch::{DeleteByQueryParts, Elasticsearch, UpdateByQueryParts};
+use nostr_sdk::Event;
+use serde_json::{json, Value};
+
+/// Whether the event carries the NIP-70 `["-"]` tag, i.e. only the relays its author
+/// published it to may serve it.
+pub fn is_protected(event: &Event) -> bool {
+    event.tags.iter().any(|tag| tag.as_vec() == ["-"])
+}
+
+/// Flags the protected events indexed before they were flagged, or deletes them unless
+/// `index_protected` is set. Returns the number of documents flagged or deleted.
+pub async fn backfill_protected(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    index_protected: bool,
+) -> anyhow::Result<u64> {
+    // indices created before the flag
+    let res = es_client
+        .indices()
+        .put_mapping(IndicesPutMappingParts::Index(&[index_alias_name]))
+        .body(json!({ "properties": { "protected": { "type": "boolean" } } }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to map the protected flag: {}",
+            res.status_code()
+        ));
+    }
+    let res = es_client
+        .update_by_query(UpdateByQueryParts::Index(&[index_alias_name]))
+        .conflicts(Conflicts::Proceed)
+        .refresh(true)
+        .body(json!({
+            "query": { "bool": { "must_not": [{ "exists": { "field": "protected" } }] } },
+            "script": {
+                "source": "if (ctx._source.event.tags.stream().anyMatch(t -> t.size() == 1 \
+                    && t[0] == '-')) { ctx._source.protected = true } else { ctx.op = 'noop' }"
+            }
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to flag protected events: {} {}",
+            status_code,
+            body
+        ));
+    }
+    let flagged = res.json::<Value>().await?["updated"]
+        .as_u64()
+        .unwrap_or_default();
+    if index_protected {
+        return Ok(flagged);
+    }
+    let res = es_client
+        .delete_by_query(DeleteByQueryParts::Index(&[index_alias_name]))
+        .conflicts(Conflicts::Proceed)
+        .body(json!({ "query": { "term": { "protected": true } } }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to delete protected events: {}",
+            res.status_code()
+        ));
+    }
+    Ok(res.json::<Value>().await?["deleted"]
+        .as_u64()
+        .unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::prelude::TagKind;
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+
+    use crate::index::protected::is_protected;
+
+    fn note(tags: &[Tag]) -> nostr_sdk::Event {
+        EventBuilder::new(Kind::TextNote, "hello", tags)
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_is_protected() {
+        assert!(!is_protected(&note(&[])));
+        assert!(is_protected(&note(&[Tag::Generic(
+            TagKind::Custom("-".to_string()),
+            vec![]
+        )])));
+        // only the bare tag marks the event
+        assert!(!is_protected(&note(&[Tag::Generic(
+            TagKind::Custom("-".to_string()),
+            vec!["x".to_string()]
+        )])));
+        assert!(!is_protected(&note(&[Tag::Hashtag("-".to_string())])));
+    }
+}
@@ -1,22 +1,35 @@
 use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use elasticsearch::indices::{IndicesDeleteParts, IndicesGetParts};
-use serde_json::Value;
+use elasticsearch::{DeleteByQueryParts, Elasticsearch};
+use serde_json::{json, Value};
 use tokio::task::JoinHandle;
 
-use crate::{app_state::AppState, index::indexes::can_exist};
+use crate::app_state::AppState;
+use crate::index::deletion::purge_deletions;
+use crate::index::indexes::{can_exist, is_undated_index};
+use crate::index::journal::purge_journal;
+use crate::index::language::other_languages_prefix;
+use crate::index::lock::try_lock;
+use crate::index::ttl::IndexTtl;
 
-async fn purge_indices(state: Arc<AppState>) -> anyhow::Result<()> {
-    log::info!(
-        "Purging indices (TTL={}d)",
-        state.index_ttl_days.unwrap_or(0)
-    );
-    let res = state
-        .es_client
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Deletes the dated indices of `index_name_prefix` outside the TTL and the allowed future,
+/// and the events of the indices kept that are past the TTL of their kind.
+pub async fn purge_indices(
+    es_client: &Elasticsearch,
+    index_name_prefix: &str,
+    ttl: &IndexTtl,
+    allow_future_days: u64,
+) -> anyhow::Result<()> {
+    let ttl_days = ttl.index_days();
+    log::info!("Purging indices (TTL={}d)", ttl_days.unwrap_or(0));
+    let res = es_client
         .indices()
         .get(IndicesGetParts::Index(&[format!(
             "{}-*",
-            state.index_name_prefix
+            index_name_prefix
         )
         .as_str()]))
         .send()
@@ -34,16 +47,13 @@ async fn purge_indices(state: Arc<AppState>) -> anyhow::Result<()> {
     let indices = res.json::<HashMap<String, Value>>().await?;
     log::info!("Number of ondices available: {:?}", indices.len());
     for (name, _index_info) in indices {
-        let can_exist = can_exist(
-            &name,
-            &current_time,
-            state.index_ttl_days,
-            state.index_allow_future_days,
-        )?;
+        if is_undated_index(&name) {
+            continue;
+        }
+        let can_exist = can_exist(&name, &current_time, ttl_days, allow_future_days)?;
         if !can_exist {
             log::info!("Purging index: {}", name);
-            let res = state
-                .es_client
+            let res = es_client
                 .indices()
                 .delete(IndicesDeleteParts::Index(&[name.as_str()]))
                 .send()
@@ -57,6 +67,22 @@ async fn purge_indices(state: Arc<AppState>) -> anyhow::Result<()> {
                     body
                 ));
             }
+        } else if let Some(query) = ttl.expired_query(&name, &current_time, allow_future_days) {
+            log::info!("Purging expired kinds of index: {}", name);
+            let res = es_client
+                .delete_by_query(DeleteByQueryParts::Index(&[name.as_str()]))
+                .body(json!({ "query": query }))
+                .send()
+                .await?;
+            if !res.status_code().is_success() {
+                let status_code = res.status_code();
+                let body = res.text().await?;
+                return Err(anyhow::anyhow!(
+                    "Error purging expired kinds: {} {}",
+                    status_code,
+                    body
+                ));
+            }
         }
     }
 
@@ -64,12 +90,83 @@ async fn purge_indices(state: Arc<AppState>) -> anyhow::Result<()> {
 }
 
 pub async fn spawn_index_purger(state: Arc<AppState>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // left to expire, so that one replica purges per interval
+        let lock_name = format!("purge-{}", state.index_alias_name);
+        let lock_ttl = PURGE_INTERVAL - Duration::from_secs(5 * 60);
+        loop {
+            match try_lock(&state.es_client, &lock_name, lock_ttl).await {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    log::debug!("another replica purges {}", state.index_alias_name);
+                    tokio::time::sleep(PURGE_INTERVAL).await;
+                    continue;
+                }
+                Err(e) => {
+                    log::error!("Error taking the purge lock: {}", e);
+                    tokio::time::sleep(PURGE_INTERVAL).await;
+                    continue;
+                }
+            }
+            if state.index_ttl.is_enabled() {
+                let res = purge_indices(
+                    &state.es_client,
+                    &state.index_name_prefix,
+                    &state.index_ttl,
+                    state.index_allow_future_days,
+                )
+                .await;
+                if let Err(e) = res {
+                    log::error!("Error purging index: {}", e);
+                }
+                if state.language_allowlist.as_ref().map_or(false, |a| a.route) {
+                    let res = purge_indices(
+                        &state.es_client,
+                        &other_languages_prefix(&state.index_name_prefix),
+                        &state.index_ttl,
+                        state.index_allow_future_days,
+                    )
+                    .await;
+                    if let Err(e) = res {
+                        log::error!("Error purging index: {}", e);
+                    }
+                }
+            }
+            // deletions are kept as long as the events they may delete
+            if let Some(ttl_days) = state.index_ttl.index_days() {
+                let res =
+                    purge_deletions(&state.es_client, &state.index_alias_name, ttl_days).await;
+                if let Err(e) = res {
+                    log::error!("Error purging deletions: {}", e);
+                }
+            }
+            if let Some(journal) = &state.journal {
+                let res = purge_journal(
+                    &state.es_client,
+                    &state.index_alias_name,
+                    journal.retention_days,
+                )
+                .await;
+                if let Err(e) = res {
+                    log::error!("Error purging journal: {}", e);
+                }
+            }
+            tokio::time::sleep(PURGE_INTERVAL).await;
+        }
+    })
+}
+
+/// Purges the events and deletions of the backend past the TTL; every replica purges, since
+/// the purges are idempotent and there is no Elasticsearch to lock in.
+pub fn spawn_backend_purger(state: Arc<AppState>) -> JoinHandle<()> {
     tokio::spawn(async move {
         loop {
-            if let Err(e) = purge_indices(state.clone()).await {
-                log::error!("Error purging index: {}", e);
+            if let Some(backend) = &state.backend {
+                if let Err(e) = backend.purge(&state.index_ttl).await {
+                    log::error!("Error purging {}: {}", backend.name(), e);
+                }
             }
-            tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+            tokio::time::sleep(PURGE_INTERVAL).await;
         }
     })
 }
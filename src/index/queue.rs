@@ -0,0 +1,154 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use nostr_sdk::Event;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+use crate::index::handlers::handle_verified_update;
+use crate::metrics::Metrics;
+
+/// Bounded queue between received events and Elasticsearch writes.
+///
+/// Events are sharded by pubkey over one channel per worker, so events of the same author
+/// (e.g. successive replaceable events) are written in the order they were received.
+/// When Elasticsearch is slow, `push` waits for room in the shard, which slows down
+/// the sending connection instead of piling up events in memory.
+#[derive(Debug)]
+pub struct IndexQueue {
+    senders: Vec<mpsc::Sender<Event>>,
+}
+
+fn shard_for(pubkey: &str, num_shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    pubkey.hash(&mut hasher);
+    (hasher.finish() % num_shards as u64) as usize
+}
+
+impl IndexQueue {
+    /// Splits `capacity` evenly over `num_shards` channels.
+    pub fn new(capacity: usize, num_shards: usize) -> (Self, Vec<mpsc::Receiver<Event>>) {
+        assert!(num_shards > 0, "at least one shard is required");
+        let shard_capacity = ((capacity + num_shards - 1) / num_shards).max(1);
+        let (senders, receivers) = (0..num_shards)
+            .map(|_| mpsc::channel(shard_capacity))
+            .unzip();
+        (IndexQueue { senders }, receivers)
+    }
+
+    pub async fn push(&self, metrics: &Metrics, event: Event) -> anyhow::Result<()> {
+        let sender = &self.senders[shard_for(&event.pubkey.to_string(), self.senders.len())];
+        match sender.try_send(event) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(event)) => {
+                Metrics::inc(&metrics.index_queue_full);
+                sender
+                    .send(event)
+                    .await
+                    .map_err(|_| anyhow::anyhow!("index queue closed"))
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(anyhow::anyhow!("index queue closed")),
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.senders
+            .iter()
+            .map(|sender| sender.max_capacity() - sender.capacity())
+            .sum()
+    }
+}
+
+/// Spawns one worker per shard.
+pub fn spawn_index_workers(
+    state: Arc<AppState>,
+    receivers: Vec<mpsc::Receiver<Event>>,
+) -> Vec<JoinHandle<()>> {
+    receivers
+        .into_iter()
+        .enumerate()
+        .map(|(worker_id, mut receiver)| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                while let Some(event) = receiver.recv().await {
+                    // while the breaker is open, the queue fills up and reading pauses;
+                    // queued events were verified on arrival
+                    let update = handle_verified_update(state.clone(), &event);
+                    let res = match &state.es_guard {
+                        Some(guard) => guard.run(update).await,
+                        None => update.await,
+                    };
+                    match res {
+                        Ok(()) => {
+                            if let Some(ack_log) = &state.ack_log {
+                                if let Err(e) = ack_log.ack(&event).await {
+                                    log::error!("failed to acknowledge {}: {}", event.id, e);
+                                }
+                            }
+                        }
+                        // left unacknowledged, so that it is indexed again on restart
+                        Err(e) => {
+                            Metrics::inc(&state.metrics.index_errors);
+                            log::error!("error indexing event {}: {}", event.id.to_hex(), e);
+                        }
+                    }
+                }
+                log::info!("index worker {} stopped", worker_id);
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use nostr_sdk::{EventBuilder, Keys, Kind};
+
+    use crate::index::queue::{shard_for, IndexQueue};
+    use crate::metrics::Metrics;
+
+    #[test]
+    fn test_shard_for() {
+        let pubkeys = (0..64)
+            .map(|_| Keys::generate().public_key().to_string())
+            .collect::<Vec<_>>();
+        for pubkey in &pubkeys {
+            let shard = shard_for(pubkey, 4);
+            assert!(shard < 4);
+            assert_eq!(shard_for(pubkey, 4), shard);
+        }
+        let used = pubkeys
+            .iter()
+            .map(|pubkey| shard_for(pubkey, 4))
+            .collect::<HashSet<_>>();
+        assert!(used.len() > 1);
+    }
+
+    #[tokio::test]
+    async fn test_per_pubkey_order() {
+        let (queue, mut receivers) = IndexQueue::new(16, 4);
+        let metrics = Metrics::default();
+        let keys = Keys::generate();
+        let events = (0..4)
+            .map(|i| {
+                EventBuilder::new(Kind::Metadata, format!("{{\"name\":\"v{}\"}}", i), &[])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        for event in &events {
+            queue.push(&metrics, event.clone()).await.unwrap();
+        }
+        assert_eq!(queue.depth(), 4);
+
+        let shard = shard_for(&keys.public_key().to_string(), 4);
+        let mut received = vec![];
+        while let Ok(event) = receivers[shard].try_recv() {
+            received.push(event.id);
+        }
+        assert_eq!(received, events.iter().map(|e| e.id).collect::<Vec<_>>());
+    }
+}
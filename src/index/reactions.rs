@@ -0,0 +1,457 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::async_trait;
+use elasticsearch::http::request::JsonBody;
+use elasticsearch::{BulkParts, Elasticsearch, MgetParts, SearchParts};
+use nostr_sdk::Event;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+use crate::index::batch::{failed_items, requeue, spawn_flusher, Flush};
+use crate::index::engagement::target;
+use crate::index::indexes::create_side_index;
+
+const REACTION_KIND: u64 = 7;
+/// events whose counts are recomputed per search
+const TOTALS_BATCH: usize = 1000;
+/// distinct authors counted exactly per event; beyond, the count is approximate
+const REACTORS_PRECISION: u64 = 3000;
+
+/// Reaction counts by event id.
+fn reactions_index(index_alias_name: &str) -> String {
+    format!("searchnos-reactions-{}", index_alias_name)
+}
+
+/// Reactions counted by id, with the event they react to, so that reactions received again
+/// are not counted twice.
+fn reaction_receipts_index(index_alias_name: &str) -> String {
+    format!("searchnos-reaction-receipts-{}", index_alias_name)
+}
+
+/// Reactions (kind 7) to an event; `-` reactions are dislikes and everything else, `+` or an
+/// emoji, a like.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct ReactionCounts {
+    pub likes: u64,
+    pub dislikes: u64,
+    /// distinct authors of the reactions, approximate beyond `REACTORS_PRECISION`
+    pub reactors: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Reaction {
+    target: String,
+    reactor: String,
+    dislike: bool,
+}
+
+/// Reactions accumulated in memory and periodically recorded as receipts, from which the
+/// counters index is recomputed.
+#[derive(Debug, Default)]
+pub struct ReactionCounter {
+    /// by reaction id
+    pending: Mutex<HashMap<String, Reaction>>,
+}
+
+impl ReactionCounter {
+    pub fn record(&self, event: &Event) {
+        if event.kind.as_u64() != REACTION_KIND {
+            return;
+        }
+        if let Some(target) = target(event) {
+            let reaction = Reaction {
+                target,
+                reactor: event.pubkey.to_string(),
+                dislike: event.content.trim() == "-",
+            };
+            self.pending
+                .lock()
+                .unwrap()
+                .insert(event.id.to_hex(), reaction);
+        }
+    }
+
+    fn take(&self) -> HashMap<String, Reaction> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+
+    /// Puts back reactions that failed to be counted, to be retried at the next flush.
+    fn requeue(&self, reactions: HashMap<String, Reaction>) {
+        // reactions recorded again since are the same
+        requeue(&self.pending, reactions, |_, _| {});
+    }
+}
+
+pub async fn create_reactions_index(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+) -> anyhow::Result<()> {
+    create_side_index(
+        es_client,
+        &reactions_index(index_alias_name),
+        json!({
+            "dynamic": false,
+            "properties": {
+                "likes": { "type": "long" },
+                "dislikes": { "type": "long" },
+                "reactor_count": { "type": "long" }
+            }
+        }),
+    )
+    .await?;
+    create_side_index(
+        es_client,
+        &reaction_receipts_index(index_alias_name),
+        json!({
+            "dynamic": false,
+            "properties": {
+                "target": { "type": "keyword" },
+                "reactor": { "type": "keyword" },
+                "dislike": { "type": "boolean" }
+            }
+        }),
+    )
+    .await
+}
+
+/// Records the reactions, skipping those recorded already; returns the events they react to,
+/// and the reactions that failed to be recorded.
+async fn record_receipts(
+    state: &AppState,
+    reactions: &HashMap<String, Reaction>,
+) -> anyhow::Result<(HashSet<String>, Vec<String>)> {
+    let receipts_index = reaction_receipts_index(&state.index_alias_name);
+    let ids = reactions.keys().cloned().collect::<Vec<_>>();
+    let mut body: Vec<JsonBody<Value>> = vec![];
+    for id in &ids {
+        let reaction = &reactions[id];
+        body.push(json!({ "create": { "_index": receipts_index, "_id": id } }).into());
+        body.push(
+            json!({
+                "target": reaction.target,
+                "reactor": reaction.reactor,
+                "dislike": reaction.dislike
+            })
+            .into(),
+        );
+    }
+    let res = state
+        .es_client
+        .bulk(BulkParts::None)
+        .body(body)
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to record reactions: {}",
+            res.status_code()
+        ));
+    }
+    let res = res.json::<Value>().await?;
+    // reactions already recorded fail with a conflict, and may not have been counted
+    let failed = failed_items(&res, &[409])
+        .into_iter()
+        .map(|(i, error)| {
+            log::warn!("failed to record reaction {}: {}", ids[i], error);
+            ids[i].clone()
+        })
+        .collect::<Vec<_>>();
+    let targets = ids
+        .iter()
+        .filter(|id| !failed.contains(id))
+        .map(|id| reactions[id].target.clone())
+        .collect();
+    Ok((targets, failed))
+}
+
+/// Counts the recorded reactions of each of `targets`.
+async fn receipt_counts(
+    state: &AppState,
+    targets: &[String],
+) -> anyhow::Result<HashMap<String, ReactionCounts>> {
+    let receipts_index = reaction_receipts_index(&state.index_alias_name);
+    let res = state
+        .es_client
+        .search(SearchParts::Index(&[receipts_index.as_str()]))
+        .body(json!({
+            "size": 0,
+            "query": { "terms": { "target": targets } },
+            "aggs": {
+                "targets": {
+                    "terms": { "field": "target", "size": targets.len() },
+                    "aggs": {
+                        "dislikes": { "filter": { "term": { "dislike": true } } },
+                        "reactors": {
+                            "cardinality": {
+                                "field": "reactor",
+                                "precision_threshold": REACTORS_PRECISION
+                            }
+                        }
+                    }
+                }
+            }
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to count reactions: {}",
+            res.status_code()
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    Ok(parse_receipt_counts(&body))
+}
+
+fn parse_receipt_counts(body: &Value) -> HashMap<String, ReactionCounts> {
+    body["aggregations"]["targets"]["buckets"]
+        .as_array()
+        .unwrap_or(&vec![])
+        .iter()
+        .filter_map(|bucket| {
+            let reactions = bucket["doc_count"].as_u64()?;
+            let dislikes = bucket["dislikes"]["doc_count"].as_u64()?;
+            Some((
+                bucket["key"].as_str()?.to_string(),
+                ReactionCounts {
+                    likes: reactions.saturating_sub(dislikes),
+                    dislikes,
+                    reactors: bucket["reactors"]["value"].as_u64()?,
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Sets the counts of the events to those of their recorded reactions. Counts from before the
+/// reactions were recorded are kept as a base the recorded ones are added to, without authors
+/// for those counted before the authors were.
+async fn update_counts(
+    state: &AppState,
+    counts: &HashMap<String, ReactionCounts>,
+) -> anyhow::Result<()> {
+    let reactions_index = reactions_index(&state.index_alias_name);
+    let mut body: Vec<JsonBody<Value>> = vec![];
+    for (target, counts) in counts {
+        body.push(json!({ "update": { "_index": reactions_index, "_id": target } }).into());
+        body.push(
+            json!({
+                "script": {
+                    "source": "if (ctx._source.base_likes == null) { \
+                        ctx._source.base_likes = ctx._source.likes; \
+                        ctx._source.base_dislikes = ctx._source.dislikes; \
+                        ctx._source.base_reactors = ctx._source.reactor_count ?: 0; \
+                        ctx._source.remove('reactors'); \
+                    } \
+                    ctx._source.likes = ctx._source.base_likes + params.likes; \
+                    ctx._source.dislikes = ctx._source.base_dislikes + params.dislikes; \
+                    ctx._source.reactor_count = ctx._source.base_reactors + params.reactors",
+                    "params": counts
+                },
+                "upsert": {
+                    "likes": counts.likes,
+                    "dislikes": counts.dislikes,
+                    "reactor_count": counts.reactors,
+                    "base_likes": 0,
+                    "base_dislikes": 0,
+                    "base_reactors": 0
+                }
+            })
+            .into(),
+        );
+    }
+    let res = state
+        .es_client
+        .bulk(BulkParts::None)
+        .body(body)
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to update reaction counts: {}",
+            res.status_code()
+        ));
+    }
+    let res = res.json::<Value>().await?;
+    let failed = failed_items(&res, &[]);
+    if !failed.is_empty() {
+        return Err(anyhow::anyhow!(
+            "failed to update reaction counts: {:?}",
+            failed
+        ));
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Flush for ReactionCounter {
+    /// Records the pending reactions, then recomputes the counts of the events they react to, so
+    /// that reactions received again or retried are counted once. Reactions are put back when
+    /// either fails.
+    async fn flush(&self, state: &AppState) -> anyhow::Result<()> {
+        let mut pending = self.take();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let (targets, failed) = match record_receipts(state, &pending).await {
+            Ok(recorded) => recorded,
+            Err(e) => {
+                self.requeue(pending);
+                return Err(e);
+            }
+        };
+        let failed = failed
+            .into_iter()
+            .filter_map(|id| pending.remove_entry(&id))
+            .collect::<HashMap<_, _>>();
+        self.requeue(failed);
+        let targets = targets.into_iter().collect::<Vec<_>>();
+        for (i, batch) in targets.chunks(TOTALS_BATCH).enumerate() {
+            let res = match receipt_counts(state, batch).await {
+                Ok(counts) => update_counts(state, &counts).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                // reactions recorded already are counted again with the others of their events
+                let retried = targets[i * TOTALS_BATCH..].iter().collect::<HashSet<_>>();
+                self.requeue(
+                    pending
+                        .into_iter()
+                        .filter(|(_, reaction)| retried.contains(&reaction.target))
+                        .collect(),
+                );
+                return Err(e);
+            }
+        }
+        log::info!(
+            "counted {} reaction(s) to {} event(s)",
+            pending.len(),
+            targets.len()
+        );
+        Ok(())
+    }
+}
+
+pub fn spawn_reaction_flusher(state: Arc<AppState>, interval: Duration) -> JoinHandle<()> {
+    spawn_flusher(state, interval, |state| {
+        state
+            .reactions
+            .as_ref()
+            .map(|counter| counter as &dyn Flush)
+    })
+}
+
+/// Reaction counts of `ids`; events without reactions are omitted.
+pub async fn reaction_counts(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    ids: &[String],
+) -> anyhow::Result<HashMap<String, ReactionCounts>> {
+    if ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let reactions_index = reactions_index(index_alias_name);
+    let res = es_client
+        .mget(MgetParts::Index(&reactions_index))
+        ._source_includes(&["likes", "dislikes", "reactor_count"])
+        .body(json!({ "ids": ids }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to get reaction counts: {}",
+            res.status_code()
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    Ok(body["docs"]
+        .as_array()
+        .unwrap_or(&vec![])
+        .iter()
+        .filter_map(|doc| {
+            let source = &doc["_source"];
+            Some((
+                doc["_id"].as_str()?.to_string(),
+                ReactionCounts {
+                    likes: source["likes"].as_u64()?,
+                    dislikes: source["dislikes"].as_u64().unwrap_or_default(),
+                    reactors: source["reactor_count"].as_u64().unwrap_or_default(),
+                },
+            ))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+    use serde_json::json;
+
+    use crate::index::reactions::{parse_receipt_counts, ReactionCounter, ReactionCounts};
+
+    #[test]
+    fn test_record() {
+        let alice = Keys::generate();
+        let bob = Keys::generate();
+        let a = "a".repeat(64);
+        let b = "b".repeat(64);
+        let event = |keys: &Keys, kind: u64, content: &str, target: &str| {
+            let tags = [Tag::parse(vec!["e", target]).unwrap()];
+            EventBuilder::new(Kind::from(kind), content, &tags)
+                .to_event(keys)
+                .unwrap()
+        };
+        let counter = ReactionCounter::default();
+        let like = event(&alice, 7, "+", &a);
+        counter.record(&like);
+        // the same reaction from another relay
+        counter.record(&like);
+        counter.record(&event(&bob, 7, " - ", &a));
+        counter.record(&event(&bob, 7, "", &b));
+        // reposts are not reactions
+        counter.record(&event(&alice, 6, "", &b));
+
+        let pending = counter.take();
+        assert_eq!(pending.len(), 3);
+        let reaction = &pending[&like.id.to_hex()];
+        assert_eq!(reaction.target, a);
+        assert_eq!(reaction.reactor, alice.public_key().to_string());
+        assert!(!reaction.dislike);
+        assert_eq!(pending.values().filter(|r| r.dislike).count(), 1);
+        assert!(counter.take().is_empty());
+
+        // requeued reactions do not replace those recorded since
+        counter.requeue(pending);
+        assert_eq!(counter.take().len(), 3);
+    }
+
+    #[test]
+    fn test_parse_receipt_counts() {
+        let body = json!({
+            "aggregations": {
+                "targets": {
+                    "buckets": [{
+                        "key": "a",
+                        "doc_count": 5,
+                        "dislikes": { "doc_count": 2 },
+                        "reactors": { "value": 4 }
+                    }]
+                }
+            }
+        });
+        let counts = parse_receipt_counts(&body);
+        assert_eq!(
+            counts["a"],
+            ReactionCounts {
+                likes: 3,
+                dislikes: 2,
+                reactors: 4
+            }
+        );
+        assert!(parse_receipt_counts(&json!({})).is_empty());
+    }
+}
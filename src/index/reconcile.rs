@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::NaiveDate;
+use elasticsearch::http::request::JsonBody;
+use elasticsearch::{BulkParts, Elasticsearch, SearchParts};
+use nostr_sdk::Event;
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+use crate::index::handlers::{
+    handle_update, is_ephemeral_event, is_parameterized_replaceable_event, is_replaceable_event,
+};
+use crate::index::indexes::create_side_index;
+
+fn ingest_index(index_alias_name: &str) -> String {
+    format!("searchnos-ingest-{}", index_alias_name)
+}
+
+/// Date of a per-day index, e.g. `2023.03.20` of `nostr-2023.03.20` or `nostr-v2-2023.03.20`.
+pub(crate) fn day_of(index_name: &str) -> Option<&str> {
+    let (_, day) = index_name.rsplit_once('-')?;
+    NaiveDate::parse_from_str(day, "%Y.%m.%d").ok()?;
+    Some(day)
+}
+
+/// Replaced versions of replaceable events are deleted, so only regular events are counted.
+pub fn is_counted(event: &Event) -> bool {
+    !is_replaceable_event(event)
+        && !is_parameterized_replaceable_event(event)
+        && !is_ephemeral_event(event)
+}
+
+/// Creates the side index holding the number of events indexed per day.
+pub async fn create_ingest_index(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+) -> anyhow::Result<()> {
+    create_side_index(
+        es_client,
+        &ingest_index(index_alias_name),
+        json!({
+            "dynamic": false,
+            "properties": {
+                "count": { "type": "long" }
+            }
+        }),
+    )
+    .await
+}
+
+/// Numbers of newly created documents per day, accumulated in memory and periodically
+/// added to the ingest index.
+#[derive(Debug, Default)]
+pub struct IngestCounter {
+    /// day -> created documents
+    pending: Mutex<HashMap<String, u64>>,
+}
+
+impl IngestCounter {
+    pub fn record(&self, index_name: &str) {
+        if let Some(day) = day_of(index_name) {
+            *self
+                .pending
+                .lock()
+                .unwrap()
+                .entry(day.to_string())
+                .or_default() += 1;
+        }
+    }
+
+    fn take(&self) -> HashMap<String, u64> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+}
+
+async fn flush(state: &AppState) -> anyhow::Result<()> {
+    let pending = state.ingest_counter.take();
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let mut body: Vec<JsonBody<Value>> = vec![];
+    for (day, n) in &pending {
+        body.push(json!({ "update": { "_id": day } }).into());
+        body.push(
+            json!({
+                "script": {
+                    "source": "ctx._source.count += params.n",
+                    "params": { "n": n }
+                },
+                "upsert": { "count": n }
+            })
+            .into(),
+        );
+    }
+    let res = state
+        .es_client
+        .bulk(BulkParts::Index(&ingest_index(&state.index_alias_name)))
+        .body(body)
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to update ingest counts: {} {}",
+            status_code,
+            body
+        ));
+    }
+    Ok(())
+}
+
+pub fn spawn_ingest_flusher(state: Arc<AppState>, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = flush(&state).await {
+                log::error!("{}", e);
+            }
+        }
+    })
+}
+
+/// Numbers of regular events per day in the indices of the alias.
+async fn document_counts(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+) -> anyhow::Result<HashMap<String, u64>> {
+    let res = es_client
+        .search(SearchParts::Index(&[index_alias_name]))
+        .size(0)
+        .body(json!({
+            "query": {
+                "bool": {
+                    "must_not": [
+                        { "terms": { "event.kind": [0, 3, 41] } },
+                        { "range": { "event.kind": { "gte": 10000, "lt": 40000 } } }
+                    ]
+                }
+            },
+            "aggs": {
+                "indices": {
+                    "terms": { "field": "_index", "size": 10000 }
+                }
+            }
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!("failed to search: {}", res.status_code()));
+    }
+    let body = res.json::<Value>().await?;
+    let mut counts = HashMap::new();
+    for bucket in body["aggregations"]["indices"]["buckets"]
+        .as_array()
+        .unwrap_or(&vec![])
+    {
+        // a day is split over several indices during a reindex
+        if let (Some(day), Some(n)) = (
+            bucket["key"].as_str().and_then(day_of),
+            bucket["doc_count"].as_u64(),
+        ) {
+            *counts.entry(day.to_string()).or_default() += n;
+        }
+    }
+    Ok(counts)
+}
+
+async fn ingest_counts(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+) -> anyhow::Result<HashMap<String, u64>> {
+    let index_name = ingest_index(index_alias_name);
+    let res = es_client
+        .search(SearchParts::Index(&[index_name.as_str()]))
+        .size(10_000)
+        .send()
+        .await?;
+    if res.status_code().as_u16() == 404 {
+        return Ok(HashMap::new());
+    }
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!("failed to search: {}", res.status_code()));
+    }
+    let body = res.json::<Value>().await?;
+    Ok(body["hits"]["hits"]
+        .as_array()
+        .unwrap_or(&vec![])
+        .iter()
+        .filter_map(|hit| {
+            Some((
+                hit["_id"].as_str()?.to_string(),
+                hit["_source"]["count"].as_u64()?,
+            ))
+        })
+        .collect())
+}
+
+fn archive_path(archive_dir: &Path, index_name_prefix: &str, day: &str) -> PathBuf {
+    archive_dir.join(format!("{}-{}.jsonl", index_name_prefix, day))
+}
+
+/// Regular events of an archive file, one event per line.
+fn read_archive(path: &Path) -> anyhow::Result<Vec<Event>> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| serde_json::from_str::<Event>(line).ok())
+        .filter(is_counted)
+        .collect())
+}
+
+/// Whether `documents` differs from `expected` by more than `tolerance` of the larger one.
+fn is_mismatch(documents: u64, expected: u64, tolerance: f64) -> bool {
+    let diff = documents.abs_diff(expected) as f64;
+    diff > tolerance * documents.max(expected) as f64
+}
+
+#[derive(Debug)]
+pub struct DayReport {
+    pub day: String,
+    pub documents: u64,
+    pub ingested: Option<u64>,
+    pub archived: Option<u64>,
+    pub mismatch: bool,
+}
+
+/// Compares the number of regular events indexed for each day with the ingest counters and,
+/// if `archive_dir` is given, with the archive files named after the original indices, e.g.
+/// `nostr-2023.03.20.jsonl`.
+///
+/// Deletions and opt-outs also lower the number of documents, hence `tolerance`. With
+/// `rebuild`, the archived events of mismatching days are indexed again.
+pub async fn reconcile(
+    state: Arc<AppState>,
+    archive_dir: Option<&Path>,
+    tolerance: f64,
+    rebuild: bool,
+) -> anyhow::Result<Vec<DayReport>> {
+    let documents = document_counts(&state.es_client, &state.index_alias_name).await?;
+    let ingested = ingest_counts(&state.es_client, &state.index_alias_name).await?;
+
+    let mut days = documents
+        .keys()
+        .chain(ingested.keys())
+        .cloned()
+        .collect::<Vec<_>>();
+    if let Some(archive_dir) = archive_dir {
+        let prefix = format!("{}-", state.index_name_prefix);
+        for entry in std::fs::read_dir(archive_dir)? {
+            let file_name = entry?.file_name();
+            if let Some(day) = file_name
+                .to_str()
+                .and_then(|name| name.strip_prefix(&prefix))
+                .and_then(|name| name.strip_suffix(".jsonl"))
+                .filter(|day| NaiveDate::parse_from_str(day, "%Y.%m.%d").is_ok())
+            {
+                days.push(day.to_string());
+            }
+        }
+    }
+    days.sort();
+    days.dedup();
+
+    let mut reports = vec![];
+    for day in days {
+        let path = archive_dir.map(|dir| archive_path(dir, &state.index_name_prefix, &day));
+        let archived_events = match &path {
+            Some(path) if path.exists() => Some(read_archive(path)?),
+            _ => None,
+        };
+        let report = {
+            let documents = documents.get(&day).copied().unwrap_or(0);
+            let ingested = ingested.get(&day).copied();
+            let archived = archived_events.as_ref().map(|events| events.len() as u64);
+            let mismatch = [ingested, archived]
+                .into_iter()
+                .flatten()
+                .any(|expected| is_mismatch(documents, expected, tolerance));
+            DayReport {
+                day,
+                documents,
+                ingested,
+                archived,
+                mismatch,
+            }
+        };
+        if report.mismatch && rebuild {
+            match archived_events {
+                Some(events) => {
+                    log::info!(
+                        "[{}] rebuilding {} from {} archived event(s)",
+                        state.index_alias_name,
+                        report.day,
+                        events.len()
+                    );
+                    for event in events {
+                        handle_update(state.clone(), &event).await?;
+                    }
+                }
+                None => log::warn!(
+                    "[{}] cannot rebuild {}; not archived",
+                    state.index_alias_name,
+                    report.day
+                ),
+            }
+        }
+        reports.push(report);
+    }
+    Ok(reports)
+}
+
+/// Reports as tab-separated lines of `day documents ingested archived`, with `-` for unknown
+/// counts and mismatching days marked.
+pub fn format_reports(reports: &[DayReport]) -> String {
+    let count = |n: Option<u64>| n.map(|n| n.to_string()).unwrap_or_else(|| "-".to_string());
+    let mut lines = vec!["day\tdocuments\tingested\tarchived".to_string()];
+    for report in reports {
+        lines.push(format!(
+            "{}\t{}\t{}\t{}{}",
+            report.day,
+            report.documents,
+            count(report.ingested),
+            count(report.archived),
+            if report.mismatch { "\tMISMATCH" } else { "" }
+        ));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::reconcile::{day_of, is_mismatch};
+
+    #[test]
+    fn test_day_of() {
+        assert_eq!(day_of("nostr-2023.03.20"), Some("2023.03.20"));
+        assert_eq!(day_of("nostr-v2-2023.03.20"), Some("2023.03.20"));
+        assert_eq!(day_of("nostr"), None);
+        assert_eq!(day_of("nostr-test"), None);
+    }
+
+    #[test]
+    fn test_is_mismatch() {
+        assert!(!is_mismatch(100, 100, 0.05));
+        assert!(!is_mismatch(96, 100, 0.05));
+        assert!(is_mismatch(90, 100, 0.05));
+        assert!(is_mismatch(110, 100, 0.05));
+        assert!(is_mismatch(0, 1, 0.05));
+        assert!(!is_mismatch(0, 0, 0.05));
+    }
+}
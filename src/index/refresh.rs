@@ -0,0 +1,204 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use elasticsearch::{Elasticsearch, ScrollParts, SearchParts};
+use nostr_sdk::prelude::{Event, Filter, Kind, XOnlyPublicKey};
+use serde_json::{json, Value};
+
+use crate::app_state::AppState;
+use crate::index::handlers::handle_update;
+use crate::index::upstream::Upstream;
+
+/// authors asked for in one request to the relays
+const AUTHORS_PER_REQUEST: usize = 500;
+
+/// Pubkeys of the authors of events created at or after `since`.
+async fn recent_authors(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    since: u64,
+) -> anyhow::Result<Vec<String>> {
+    let res = es_client
+        .search(SearchParts::Index(&[index_alias_name]))
+        .scroll("1m")
+        .size(1000)
+        .body(json!({
+            "_source": ["event.pubkey"],
+            "query": { "range": { "event.created_at": { "gte": since } } }
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!("failed to search: {}", res.status_code()));
+    }
+    let mut body = res.json::<Value>().await?;
+
+    let mut authors = HashSet::new();
+    loop {
+        let hits = body["hits"]["hits"].as_array().cloned().unwrap_or_default();
+        if hits.is_empty() {
+            break;
+        }
+        for hit in hits {
+            if let Some(pubkey) = hit["_source"]["event"]["pubkey"].as_str() {
+                authors.insert(pubkey.to_string());
+            }
+        }
+        let scroll_id = body["_scroll_id"].as_str().unwrap_or_default().to_string();
+        let res = es_client
+            .scroll(ScrollParts::None)
+            .body(json!({ "scroll": "1m", "scroll_id": scroll_id }))
+            .send()
+            .await?;
+        if !res.status_code().is_success() {
+            return Err(anyhow::anyhow!("failed to scroll: {}", res.status_code()));
+        }
+        body = res.json::<Value>().await?;
+    }
+    let mut authors = authors.into_iter().collect::<Vec<_>>();
+    authors.sort();
+    Ok(authors)
+}
+
+/// `created_at` of the indexed profiles of `pubkeys`.
+async fn indexed_profiles(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    pubkeys: &[String],
+) -> anyhow::Result<HashMap<String, u64>> {
+    let res = es_client
+        .search(SearchParts::Index(&[index_alias_name]))
+        .size(10_000)
+        .body(json!({
+            "_source": ["event.pubkey", "event.created_at", "_raw.created_at"],
+            "query": {
+                "bool": {
+                    "filter": [
+                        { "term": { "event.kind": 0 } },
+                        { "terms": { "event.pubkey": pubkeys } }
+                    ]
+                }
+            }
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!("failed to search: {}", res.status_code()));
+    }
+    let body = res.json::<Value>().await?;
+    let mut profiles = HashMap::new();
+    for hit in body["hits"]["hits"].as_array().unwrap_or(&vec![]) {
+        let source = &hit["_source"];
+        // the original created_at when the searchable one is rounded
+        let created_at = source["_raw"]["created_at"]
+            .as_u64()
+            .or_else(|| source["event"]["created_at"].as_u64());
+        if let (Some(pubkey), Some(created_at)) = (source["event"]["pubkey"].as_str(), created_at) {
+            let latest = profiles.entry(pubkey.to_string()).or_insert(created_at);
+            *latest = (*latest).max(created_at);
+        }
+    }
+    Ok(profiles)
+}
+
+/// The newest event of each author, ordered by pubkey.
+fn latest_per_author(events: Vec<Event>) -> Vec<Event> {
+    let mut latest: HashMap<XOnlyPublicKey, Event> = HashMap::new();
+    for event in events {
+        match latest.get(&event.pubkey) {
+            Some(current) if current.created_at.as_u64() >= event.created_at.as_u64() => {}
+            _ => {
+                latest.insert(event.pubkey, event);
+            }
+        }
+    }
+    let mut events = latest.into_values().collect::<Vec<_>>();
+    events.sort_by_key(|event| event.pubkey.to_string());
+    events
+}
+
+/// Fetches the profiles (kind 0) of the authors of events created in the last `since_days`
+/// days from `relays`, fastest first, and indexes those newer than the indexed ones. Returns
+/// their number.
+pub async fn refresh_profiles(
+    state: Arc<AppState>,
+    relays: &[String],
+    since_days: u64,
+) -> anyhow::Result<usize> {
+    let since = (Utc::now().timestamp() as u64).saturating_sub(since_days * 24 * 60 * 60);
+    let authors = recent_authors(&state.es_client, &state.index_alias_name, since).await?;
+    log::info!(
+        "[{}] refreshing profiles of {} author(s)",
+        state.index_alias_name,
+        authors.len()
+    );
+
+    let upstream = Upstream::connect(relays, Duration::from_secs(30)).await?;
+
+    let mut refreshed = 0;
+    for chunk in authors.chunks(AUTHORS_PER_REQUEST) {
+        let pubkeys = chunk
+            .iter()
+            .filter_map(|pubkey| XOnlyPublicKey::from_str(pubkey).ok())
+            .collect::<Vec<_>>();
+        let wanted = pubkeys.iter().cloned().collect::<HashSet<_>>();
+        let filter = Filter::new().kinds(vec![Kind::Metadata]).authors(pubkeys);
+        // the next relays are asked only for as long as some authors have no profile
+        let events = upstream
+            .fetch_until(vec![filter], |events| {
+                wanted.is_subset(&events.iter().map(|event| event.pubkey).collect())
+            })
+            .await;
+        let indexed = indexed_profiles(&state.es_client, &state.index_alias_name, chunk).await?;
+        for event in latest_per_author(events) {
+            if event.kind != Kind::Metadata {
+                continue;
+            }
+            let created_at = event.created_at.as_u64();
+            match indexed.get(&event.pubkey.to_string()) {
+                Some(indexed_at) if *indexed_at >= created_at => continue,
+                _ => {}
+            }
+            handle_update(state.clone(), &event).await?;
+            refreshed += 1;
+        }
+        log::info!(
+            "[{}] refreshed {} profile(s) so far",
+            state.index_alias_name,
+            refreshed
+        );
+    }
+    upstream.disconnect().await?;
+    Ok(refreshed)
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind, Timestamp};
+
+    use crate::index::refresh::latest_per_author;
+
+    #[test]
+    fn test_latest_per_author() {
+        let keys = Keys::generate();
+        let mut old = EventBuilder::new(Kind::Metadata, "{}", &[])
+            .to_event(&keys)
+            .unwrap();
+        old.created_at = Timestamp::from(1000);
+        let mut new = old.clone();
+        new.created_at = Timestamp::from(2000);
+        let other = EventBuilder::new(Kind::Metadata, "{}", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+
+        let latest = latest_per_author(vec![new.clone(), old, other.clone()]);
+        assert_eq!(latest.len(), 2);
+        assert!(latest
+            .iter()
+            .any(|e| e.pubkey == keys.public_key() && e.created_at.as_u64() == 2000));
+        assert!(latest.iter().any(|e| e.id == other.id));
+    }
+}
@@ -0,0 +1,83 @@
+use nostr_sdk::Event;
+use serde::Serialize;
+
+/// Events and pubkeys referred to by `e` and `p` tags, indexed for thread and mention lookups.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Refs {
+    pub events: Vec<String>,
+    pub pubkeys: Vec<String>,
+}
+
+impl Refs {
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty() && self.pubkeys.is_empty()
+    }
+}
+
+/// Lowercase hex when `value` is a 32-byte id or pubkey.
+pub fn normalize_ref(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(value.to_lowercase())
+    } else {
+        None
+    }
+}
+
+pub fn extract_refs(event: &Event) -> Refs {
+    let mut refs = Refs::default();
+    for tag in &event.tags {
+        let tag = tag.as_vec();
+        let refs = match tag.first().map(|name| name.as_str()) {
+            Some("e") => &mut refs.events,
+            Some("p") => &mut refs.pubkeys,
+            _ => continue,
+        };
+        if let Some(value) = tag.get(1).and_then(|value| normalize_ref(value)) {
+            if !refs.contains(&value) {
+                refs.push(value);
+            }
+        }
+    }
+    refs
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::prelude::TagKind;
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+
+    use crate::index::refs::{extract_refs, normalize_ref};
+
+    #[test]
+    fn test_normalize_ref() {
+        assert_eq!(normalize_ref(&"A".repeat(64)), Some("a".repeat(64)));
+        assert_eq!(normalize_ref("abc"), None);
+        assert_eq!(normalize_ref(&"z".repeat(64)), None);
+    }
+
+    #[test]
+    fn test_extract_refs() {
+        // generic tags, since invalid values do not parse into `e` and `p` tags
+        let tag = |name: &str, value: String| {
+            Tag::Generic(TagKind::Custom(name.to_string()), vec![value])
+        };
+        let event = EventBuilder::new(
+            Kind::TextNote,
+            "hello",
+            &[
+                tag("e", "a".repeat(64)),
+                tag("e", "B".repeat(64)),
+                tag("e", "a".repeat(64)),
+                tag("p", "c".repeat(64)),
+                tag("p", "invalid".to_string()),
+                tag("t", "d".repeat(64)),
+            ],
+        )
+        .to_event(&Keys::generate())
+        .unwrap();
+        let refs = extract_refs(&event);
+        assert_eq!(refs.events, vec!["a".repeat(64), "b".repeat(64)]);
+        assert_eq!(refs.pubkeys, vec!["c".repeat(64)]);
+    }
+}
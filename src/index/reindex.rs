@@ -0,0 +1,580 @@
+use std::collections::{HashMap, HashSet};
+
+use elasticsearch::http::request::JsonBody;
+use elasticsearch::indices::{
+    IndicesCreateParts, IndicesDeleteAliasParts, IndicesGetParts, IndicesUpdateAliasesParts,
+};
+use elasticsearch::params::Slices;
+use elasticsearch::{
+    BulkParts, ClearScrollParts, CountParts, DeleteParts, Elasticsearch, GetParts, IndexParts,
+    ReindexParts, ScrollParts, SearchParts,
+};
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::index::indexes::create_side_index;
+
+/// progress of each migration, keyed by the source index
+const PROGRESS_INDEX: &str = "searchnos-reindex";
+/// documents of `dest` checked against `source` at once
+const CHECK_BATCH: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Stage {
+    /// `dest` has been created and is being filled
+    Copying,
+    /// everything indexed before `started_at` has been copied
+    Copied,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Progress {
+    dest: String,
+    stage: Stage,
+    started_at: String,
+}
+
+/// `started_at` of a completed full copy into `dest`, from which an interrupted migration
+/// resumes with the catch-up copy.
+fn resume_point<'a>(progress: Option<&'a Progress>, dest: &str) -> Option<&'a str> {
+    match progress {
+        Some(progress) if progress.dest == dest && progress.stage == Stage::Copied => {
+            Some(progress.started_at.as_str())
+        }
+        _ => None,
+    }
+}
+
+async fn load_progress(
+    es_client: &Elasticsearch,
+    source: &str,
+) -> anyhow::Result<Option<Progress>> {
+    let res = es_client
+        .get(GetParts::IndexId(PROGRESS_INDEX, source))
+        .send()
+        .await?;
+    if res.status_code().as_u16() == 404 {
+        return Ok(None);
+    }
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to get progress of {}: {}",
+            source,
+            res.status_code()
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    Ok(serde_json::from_value(body["_source"].clone()).ok())
+}
+
+async fn save_progress(
+    es_client: &Elasticsearch,
+    source: &str,
+    progress: &Progress,
+) -> anyhow::Result<()> {
+    let res = es_client
+        .index(IndexParts::IndexId(PROGRESS_INDEX, source))
+        .body(progress)
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to save progress of {}: {}",
+            source,
+            res.status_code()
+        ));
+    }
+    Ok(())
+}
+
+async fn delete_progress(es_client: &Elasticsearch, source: &str) -> anyhow::Result<()> {
+    let res = es_client
+        .delete(DeleteParts::IndexId(PROGRESS_INDEX, source))
+        .send()
+        .await?;
+    if !res.status_code().is_success() && res.status_code().as_u16() != 404 {
+        return Err(anyhow::anyhow!(
+            "failed to delete progress of {}: {}",
+            source,
+            res.status_code()
+        ));
+    }
+    Ok(())
+}
+
+/// Name of `index_name` in `version`, e.g. `nostr-2023.03.20` -> `nostr-v2-2023.03.20`.
+fn versioned_index_name(index_name: &str, version: &str) -> Option<String> {
+    let (prefix, date) = index_name.rsplit_once('-')?;
+    Some(format!("{}-{}-{}", prefix, version, date))
+}
+
+/// Whether `index_name` is already of `version`, e.g. a destination of an interrupted run.
+fn is_of_version(index_name: &str, version: &str) -> bool {
+    index_name
+        .rsplit_once('-')
+        .map(|(prefix, _)| prefix.ends_with(&format!("-{}", version)))
+        .unwrap_or(false)
+}
+
+/// Actions swapping `source` for `dest` in `aliases` and deleting `source` at once.
+fn swap_actions(source: &str, dest: &str, aliases: &[String]) -> Value {
+    let mut actions = aliases
+        .iter()
+        .map(|alias| json!({ "add": { "index": dest, "alias": alias } }))
+        .collect::<Vec<_>>();
+    actions.push(json!({ "remove_index": { "index": source } }));
+    json!({ "actions": actions })
+}
+
+async fn count(es_client: &Elasticsearch, index_name: &str) -> anyhow::Result<u64> {
+    let res = es_client
+        .count(CountParts::Index(&[index_name]))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to count {}: {}",
+            index_name,
+            res.status_code()
+        ));
+    }
+    Ok(res.json::<Value>().await?["count"].as_u64().unwrap_or(0))
+}
+
+/// Copies the documents of `source` into `dest`, keeping documents already in `dest`.
+async fn copy(
+    es_client: &Elasticsearch,
+    source: &str,
+    dest: &str,
+    query: Value,
+) -> anyhow::Result<()> {
+    let res = es_client
+        .reindex(ReindexParts::None)
+        .wait_for_completion(true)
+        .refresh(true)
+        // one slice per shard
+        .slices(Slices::Auto)
+        .body(json!({
+            "source": { "index": source, "query": query },
+            "dest": { "index": dest, "op_type": "create" },
+            "conflicts": "proceed"
+        }))
+        .send()
+        .await?;
+    let status_code = res.status_code();
+    let body = res.json::<Value>().await?;
+    let failures = body["failures"].as_array().map(|f| f.len()).unwrap_or(0);
+    if !status_code.is_success() || failures > 0 {
+        return Err(anyhow::anyhow!(
+            "failed to reindex {}: {} {}",
+            source,
+            status_code,
+            body
+        ));
+    }
+    log::info!(
+        "copied {} document(s) of {} ({} already present)",
+        body["created"],
+        source,
+        body["version_conflicts"]
+    );
+    Ok(())
+}
+
+/// Ids of `ids` still in `source`.
+async fn present_ids(
+    es_client: &Elasticsearch,
+    source: &str,
+    ids: &[String],
+) -> anyhow::Result<HashSet<String>> {
+    let res = es_client
+        .search(SearchParts::Index(&[source]))
+        .body(json!({
+            "query": { "ids": { "values": ids } },
+            "_source": false,
+            "size": ids.len()
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to search {}: {}",
+            source,
+            res.status_code()
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    Ok(body["hits"]["hits"]
+        .as_array()
+        .unwrap_or(&vec![])
+        .iter()
+        .filter_map(|hit| hit["_id"].as_str().map(|id| id.to_string()))
+        .collect())
+}
+
+/// Deletes from `dest` the documents no longer in `source`: those deleted, or replaced by a
+/// newer version, after they were copied. Returns their number.
+async fn drop_removed(
+    es_client: &Elasticsearch,
+    source: &str,
+    dest: &str,
+) -> anyhow::Result<usize> {
+    let mut res = es_client
+        .search(SearchParts::Index(&[dest]))
+        .scroll("1m")
+        .size(CHECK_BATCH as i64)
+        .body(json!({ "_source": false, "sort": ["_doc"] }))
+        .send()
+        .await?;
+    let mut removed = 0;
+    loop {
+        if !res.status_code().is_success() {
+            return Err(anyhow::anyhow!(
+                "failed to scroll {}: {}",
+                dest,
+                res.status_code()
+            ));
+        }
+        let body = res.json::<Value>().await?;
+        let scroll_id = body["_scroll_id"].as_str().unwrap_or_default().to_string();
+        let ids = body["hits"]["hits"]
+            .as_array()
+            .unwrap_or(&vec![])
+            .iter()
+            .filter_map(|hit| hit["_id"].as_str().map(|id| id.to_string()))
+            .collect::<Vec<_>>();
+        if ids.is_empty() {
+            let res = es_client
+                .clear_scroll(ClearScrollParts::None)
+                .body(json!({ "scroll_id": scroll_id }))
+                .send()
+                .await;
+            if let Err(e) = res {
+                log::warn!("failed to clear the scroll of {}: {}", dest, e);
+            }
+            return Ok(removed);
+        }
+        let present = present_ids(es_client, source, &ids).await?;
+        let mut body: Vec<JsonBody<Value>> = vec![];
+        for id in ids.iter().filter(|id| !present.contains(*id)) {
+            body.push(json!({ "delete": { "_index": dest, "_id": id } }).into());
+        }
+        if !body.is_empty() {
+            let n = body.len();
+            let res = es_client.bulk(BulkParts::None).body(body).send().await?;
+            if !res.status_code().is_success() {
+                return Err(anyhow::anyhow!(
+                    "failed to delete from {}: {}",
+                    dest,
+                    res.status_code()
+                ));
+            }
+            let res = res.json::<Value>().await?;
+            if res["errors"].as_bool().unwrap_or(false) {
+                return Err(anyhow::anyhow!(
+                    "failed to delete from {}: {}",
+                    dest,
+                    res["items"]
+                ));
+            }
+            removed += n;
+        }
+        res = es_client
+            .scroll(ScrollParts::None)
+            .body(json!({ "scroll": "1m", "scroll_id": scroll_id }))
+            .send()
+            .await?;
+    }
+}
+
+/// Creates `dest` out of `aliases`; an index left by an interrupted run is reused.
+async fn create_dest(
+    es_client: &Elasticsearch,
+    dest: &str,
+    aliases: &[String],
+) -> anyhow::Result<()> {
+    let res = es_client
+        .indices()
+        .create(IndicesCreateParts::Index(dest))
+        .send()
+        .await?;
+    let status_code = res.status_code();
+    let body = res.json::<Value>().await?;
+    if !status_code.is_success() && body["error"]["type"] != "resource_already_exists_exception" {
+        return Err(anyhow::anyhow!(
+            "failed to create {}: {} {}",
+            dest,
+            status_code,
+            body
+        ));
+    }
+    for alias in aliases {
+        // added by the index template
+        let res = es_client
+            .indices()
+            .delete_alias(IndicesDeleteAliasParts::IndexName(
+                &[dest],
+                &[alias.as_str()],
+            ))
+            .send()
+            .await?;
+        if !res.status_code().is_success() && res.status_code().as_u16() != 404 {
+            return Err(anyhow::anyhow!(
+                "failed to remove {} from {}: {}",
+                dest,
+                alias,
+                res.status_code()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Migrates one index to `dest` without interrupting searches.
+///
+/// `dest` is created with the current template and kept out of the aliases while it is filled,
+/// so that searches never see documents twice. Documents indexed into `source` during the copy
+/// are caught up, and those deleted or replaced since are dropped from `dest`, before the
+/// aliases are swapped and `source` is deleted in one request. An interrupted migration whose
+/// full copy completed resumes with the catch-up.
+async fn migrate(
+    es_client: &Elasticsearch,
+    source: &str,
+    dest: &str,
+    aliases: &[String],
+) -> anyhow::Result<()> {
+    let progress = load_progress(es_client, source).await?;
+    let started_at = match resume_point(progress.as_ref(), dest) {
+        Some(started_at) => {
+            log::info!("resuming {} from {}", source, started_at);
+            started_at.to_string()
+        }
+        None => {
+            let started_at = chrono::Utc::now().to_rfc3339();
+            create_dest(es_client, dest, aliases).await?;
+            let mut progress = Progress {
+                dest: dest.to_string(),
+                stage: Stage::Copying,
+                started_at,
+            };
+            save_progress(es_client, source, &progress).await?;
+            copy(es_client, source, dest, json!({ "match_all": {} })).await?;
+            progress.stage = Stage::Copied;
+            save_progress(es_client, source, &progress).await?;
+            progress.started_at
+        }
+    };
+    copy(
+        es_client,
+        source,
+        dest,
+        json!({ "range": { "timestamp": { "gte": started_at } } }),
+    )
+    .await?;
+    let removed = drop_removed(es_client, source, dest).await?;
+    if removed > 0 {
+        log::info!(
+            "dropped {} document(s) removed from {} since the copy",
+            removed,
+            source
+        );
+    }
+
+    let (source_count, dest_count) = (
+        count(es_client, source).await?,
+        count(es_client, dest).await?,
+    );
+    if dest_count < source_count {
+        return Err(anyhow::anyhow!(
+            "{} has {} document(s), fewer than the {} of {}; keeping {}",
+            dest,
+            dest_count,
+            source_count,
+            source,
+            source
+        ));
+    }
+
+    let res = es_client
+        .indices()
+        .update_aliases(IndicesUpdateAliasesParts::None)
+        .body(swap_actions(source, dest, aliases))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to swap {} for {}: {} {}",
+            source,
+            dest,
+            status_code,
+            body
+        ));
+    }
+    delete_progress(es_client, source).await?;
+    log::info!("replaced {} with {}", source, dest);
+    Ok(())
+}
+
+/// Migrates every index matching `pattern` to an index of `version`, which picks up the
+/// current template, e.g. after changing the mapping.
+///
+/// Up to `concurrency` indices are migrated at once. Running it again after an interruption
+/// resumes the unfinished migrations.
+pub async fn reindex(
+    es_client: &Elasticsearch,
+    pattern: &str,
+    version: &str,
+    concurrency: usize,
+) -> anyhow::Result<()> {
+    create_side_index(
+        es_client,
+        PROGRESS_INDEX,
+        json!({
+            "dynamic": false,
+            "properties": {
+                "dest": { "type": "keyword" },
+                "stage": { "type": "keyword" }
+            }
+        }),
+    )
+    .await?;
+
+    let res = es_client
+        .indices()
+        .get(IndicesGetParts::Index(&[pattern]))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to get indices: {} {}",
+            status_code,
+            body
+        ));
+    }
+    let mut indices = res
+        .json::<HashMap<String, Value>>()
+        .await?
+        .into_iter()
+        .collect::<Vec<_>>();
+    indices.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut migrations = vec![];
+    for (source, info) in indices {
+        if is_of_version(&source, version) {
+            continue;
+        }
+        let dest = match versioned_index_name(&source, version) {
+            Some(dest) => dest,
+            None => {
+                log::warn!("skipping {}; not a dated index", source);
+                continue;
+            }
+        };
+        let aliases = info["aliases"]
+            .as_object()
+            .map(|aliases| aliases.keys().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        migrations.push((source, dest, aliases));
+    }
+
+    let total = migrations.len();
+    let mut results = stream::iter(migrations)
+        .map(|(source, dest, aliases)| async move {
+            log::info!("reindexing {} into {}", source, dest);
+            let res = migrate(es_client, &source, &dest, &aliases).await;
+            (source, res)
+        })
+        .buffer_unordered(concurrency.max(1));
+    let (mut done, mut failed) = (0, vec![]);
+    while let Some((source, res)) = results.next().await {
+        done += 1;
+        match res {
+            Ok(()) => log::info!("[{}/{}] migrated {}", done, total, source),
+            Err(e) => {
+                log::error!("[{}/{}] failed to migrate {}: {}", done, total, source, e);
+                failed.push(source);
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(anyhow::anyhow!(
+            "failed to migrate {} of {} index(es): {}; run again to resume",
+            failed.len(),
+            total,
+            failed.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::index::reindex::{
+        is_of_version, resume_point, swap_actions, versioned_index_name, Progress, Stage,
+    };
+
+    #[test]
+    fn test_versioned_index_name() {
+        assert_eq!(
+            versioned_index_name("nostr-2023.03.20", "v2"),
+            Some("nostr-v2-2023.03.20".to_string())
+        );
+        assert_eq!(
+            versioned_index_name("nostr-test-2023.03.20", "v2"),
+            Some("nostr-test-v2-2023.03.20".to_string())
+        );
+        assert_eq!(versioned_index_name("nostr", "v2"), None);
+    }
+
+    #[test]
+    fn test_is_of_version() {
+        assert!(is_of_version("nostr-v2-2023.03.20", "v2"));
+        assert!(!is_of_version("nostr-2023.03.20", "v2"));
+        assert!(!is_of_version("nostr-v2-2023.03.20", "v3"));
+    }
+
+    #[test]
+    fn test_resume_point() {
+        let progress = Progress {
+            dest: "nostr-v2-2023.03.20".to_string(),
+            stage: Stage::Copied,
+            started_at: "2023-03-21T00:00:00+00:00".to_string(),
+        };
+        assert_eq!(
+            resume_point(Some(&progress), "nostr-v2-2023.03.20"),
+            Some("2023-03-21T00:00:00+00:00")
+        );
+        assert_eq!(resume_point(Some(&progress), "nostr-v3-2023.03.20"), None);
+        let copying = Progress {
+            stage: Stage::Copying,
+            ..progress
+        };
+        assert_eq!(resume_point(Some(&copying), "nostr-v2-2023.03.20"), None);
+        assert_eq!(resume_point(None, "nostr-v2-2023.03.20"), None);
+    }
+
+    #[test]
+    fn test_swap_actions() {
+        assert_eq!(
+            swap_actions(
+                "nostr-2023.03.20",
+                "nostr-v2-2023.03.20",
+                &["nostr".to_string()]
+            ),
+            json!({
+                "actions": [
+                    { "add": { "index": "nostr-v2-2023.03.20", "alias": "nostr" } },
+                    { "remove_index": { "index": "nostr-2023.03.20" } }
+                ]
+            })
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use elasticsearch::params::Conflicts;
+use elasticsearch::DeleteByQueryParts;
+use nostr_sdk::Event;
+use serde_json::json;
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+use crate::index::delegation::author;
+use crate::index::handlers::{
+    extract_identifier_tag, is_parameterized_replaceable_event, replaced_versions,
+};
+
+/// replaced versions deleted with one query
+const KEYS_PER_QUERY: usize = 100;
+
+/// Newest indexed versions of (parameterized) replaceable events whose older versions are yet
+/// to be deleted, so that frequently updated events cost one delete-by-query per batch rather
+/// than one per version.
+///
+/// Until a batch runs, older versions may still be found.
+#[derive(Debug, Default)]
+pub struct ReplacementQueue {
+    /// (author, kind, identifier) -> newest version
+    pending: Mutex<HashMap<(String, u64, String), Event>>,
+}
+
+impl ReplacementQueue {
+    /// Records `event` as indexed; with `inclusive` versions, as when `created_at` is rounded,
+    /// the one received last replaces those created at the same time.
+    pub fn record(&self, event: &Event, inclusive: bool) {
+        let identifier = if is_parameterized_replaceable_event(event) {
+            extract_identifier_tag(&event.tags)
+        } else {
+            String::new()
+        };
+        let key = (author(event), event.kind.as_u64(), identifier);
+        let mut pending = self.pending.lock().unwrap();
+        let replaces = match pending.get(&key) {
+            Some(newest) if newest.created_at == event.created_at => {
+                // otherwise the lowest id is kept
+                inclusive || event.id.to_hex() < newest.id.to_hex()
+            }
+            Some(newest) => event.created_at > newest.created_at,
+            None => true,
+        };
+        if replaces {
+            pending.insert(key, event.clone());
+        }
+    }
+
+    fn take(&self) -> Vec<Event> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+            .into_values()
+            .collect()
+    }
+}
+
+async fn flush(state: &AppState, queue: &ReplacementQueue) -> anyhow::Result<()> {
+    let newest = queue.take();
+    let inclusive = state.created_at_rounding.is_some();
+    for (i, events) in newest.chunks(KEYS_PER_QUERY).enumerate() {
+        // retried with the next batch unless newer versions arrive meanwhile
+        let requeue = || {
+            for event in &newest[i * KEYS_PER_QUERY..] {
+                queue.record(event, false);
+            }
+        };
+        let should = events
+            .iter()
+            .map(|event| replaced_versions(event, inclusive))
+            .collect::<Vec<_>>();
+        let res = state
+            .es_client
+            .delete_by_query(DeleteByQueryParts::Index(&[state
+                .index_alias_name
+                .as_str()]))
+            .conflicts(Conflicts::Proceed)
+            .body(json!({
+                "query": {
+                    "bool": {
+                        "should": should,
+                        "minimum_should_match": 1
+                    }
+                }
+            }))
+            .send()
+            .await;
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => {
+                requeue();
+                return Err(e.into());
+            }
+        };
+        if !res.status_code().is_success() {
+            let status_code = res.status_code();
+            let body = res.text().await?;
+            requeue();
+            return Err(anyhow::anyhow!(
+                "failed to delete replaced versions; received {}, {}",
+                status_code,
+                body
+            ));
+        }
+        let body = res.json::<serde_json::Value>().await?;
+        log::info!(
+            "deleted {} replaced version(s) of {} event(s)",
+            body["deleted"],
+            events.len()
+        );
+    }
+    Ok(())
+}
+
+pub fn spawn_replacement_flusher(state: Arc<AppState>, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Some(queue) = &state.replacements {
+                if let Err(e) = flush(&state, queue).await {
+                    log::error!("{}", e);
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag, Timestamp};
+
+    use crate::index::replacements::ReplacementQueue;
+
+    #[test]
+    fn test_record() {
+        let keys = Keys::generate();
+        let event = |kind: u64, created_at: u64, tags: &[Tag]| {
+            let mut event = EventBuilder::new(Kind::from(kind), &created_at.to_string(), tags)
+                .to_event(&keys)
+                .unwrap();
+            event.created_at = Timestamp::from(created_at);
+            event
+        };
+        let queue = ReplacementQueue::default();
+        let newest = event(0, 200, &[]);
+        queue.record(&event(0, 100, &[]), false);
+        queue.record(&newest, false);
+        queue.record(&event(0, 150, &[]), false);
+        let article = event(31234, 100, &[Tag::Identifier("a".to_string())]);
+        queue.record(&article, false);
+        queue.record(
+            &event(31234, 100, &[Tag::Identifier("b".to_string())]),
+            false,
+        );
+
+        let mut ids = queue.take().into_iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(ids.len(), 3);
+        ids.retain(|id| *id == newest.id || *id == article.id);
+        assert_eq!(ids.len(), 2);
+        assert!(queue.take().is_empty());
+
+        let (a, b) = (
+            event(0, 100, &[]),
+            event(0, 100, &[Tag::Hashtag("b".to_string())]),
+        );
+        let (lowest, highest) = if a.id.to_hex() < b.id.to_hex() {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        queue.record(&lowest, false);
+        queue.record(&highest, false);
+        assert_eq!(queue.take()[0].id, lowest.id);
+        queue.record(&lowest, true);
+        queue.record(&highest, true);
+        assert_eq!(queue.take()[0].id, highest.id);
+    }
+}
@@ -1,51 +1,132 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
 use elasticsearch::{
-    indices::IndicesPutIndexTemplateParts, ingest::IngestPutPipelineParts, Elasticsearch,
+    indices::{IndicesGetIndexTemplateParts, IndicesPutIndexTemplateParts},
+    ingest::{IngestGetPipelineParts, IngestPutPipelineParts},
+    Elasticsearch,
 };
 use log::info;
 use nostr_sdk::prelude::*;
+use serde_json::Value;
+
+use crate::index::analyzer::AnalyzerConfig;
+use crate::index::embedding::EmbeddingConfig;
+use crate::index::language::LanguageAllowlist;
+use crate::search::prefix::TagPrefixes;
+
+/// Version of the pipeline and index template definitions; bump it when changing them.
+const SCHEMA_VERSION: u64 = 1;
+
+/// Digest of a generated definition, which changes with the configuration.
+fn digest(definition: &Value) -> String {
+    let mut hasher = DefaultHasher::new();
+    definition.to_string().hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Adds the version and digest to `_meta`.
+fn with_meta(mut definition: Value) -> Value {
+    let digest = digest(&definition);
+    definition["_meta"] = json!({ "version": SCHEMA_VERSION, "digest": digest });
+    definition
+}
 
+/// Whether a definition stored with `meta` is replaced by `definition`, carrying the `_meta` of
+/// `with_meta`.
+///
+/// Definitions of newer versions are kept, as are stored definitions of the same version and
+/// configuration, so that customizations of operators survive restarts.
+fn needs_update(meta: Option<&Value>, definition: &Value, force: bool) -> bool {
+    if force {
+        return true;
+    }
+    let meta = match meta {
+        Some(meta) => meta,
+        None => return true,
+    };
+    match meta["version"].as_u64() {
+        Some(version) if version > SCHEMA_VERSION => {
+            log::warn!(
+                "keeping a definition of version {} newer than {}",
+                version,
+                SCHEMA_VERSION
+            );
+            false
+        }
+        Some(version) if version == SCHEMA_VERSION => {
+            meta["digest"] != definition["_meta"]["digest"]
+        }
+        // written before definitions were versioned
+        _ => true,
+    }
+}
+
+/// `_meta` of the stored pipeline; `None` if there is no such pipeline.
+async fn pipeline_meta(
+    es_client: &Elasticsearch,
+    pipeline_name: &str,
+) -> Result<Option<Value>, Box<dyn std::error::Error>> {
+    let res = es_client
+        .ingest()
+        .get_pipeline(IngestGetPipelineParts::Id(pipeline_name))
+        .send()
+        .await?;
+    if res.status_code().as_u16() == 404 {
+        return Ok(None);
+    }
+    if !res.status_code().is_success() {
+        return Err(format!("failed to get pipeline: received {}", res.status_code()).into());
+    }
+    let body = res.json::<Value>().await?;
+    Ok(Some(body[pipeline_name]["_meta"].clone()))
+}
+
+/// `_meta` of the stored index template; `None` if there is no such template.
+async fn index_template_meta(
+    es_client: &Elasticsearch,
+    template_name: &str,
+) -> Result<Option<Value>, Box<dyn std::error::Error>> {
+    let res = es_client
+        .indices()
+        .get_index_template(IndicesGetIndexTemplateParts::Name(template_name))
+        .send()
+        .await?;
+    if res.status_code().as_u16() == 404 {
+        return Ok(None);
+    }
+    if !res.status_code().is_success() {
+        return Err(format!(
+            "failed to get index template: received {}",
+            res.status_code()
+        )
+        .into());
+    }
+    let body = res.json::<Value>().await?;
+    Ok(Some(
+        body["index_templates"][0]["index_template"]["_meta"].clone(),
+    ))
+}
+
+/// Puts the ingest pipeline unless the stored one is up to date; see `needs_update`.
 pub async fn put_pipeline(
     es_client: &Elasticsearch,
     pipeline_name: &str,
+    analyzer_config: &AnalyzerConfig,
+    language_allowlist: Option<&LanguageAllowlist>,
+    force: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let pipeline = with_meta(gen_pipeline(analyzer_config, language_allowlist));
+    let meta = pipeline_meta(es_client, pipeline_name).await?;
+    if !needs_update(meta.as_ref(), &pipeline, force) {
+        info!("pipeline is up to date: {}", pipeline_name);
+        return Ok(());
+    }
     info!("putting pipeline: {}", pipeline_name);
     let res = es_client
         .ingest()
         .put_pipeline(IngestPutPipelineParts::Id(pipeline_name))
-        .body(json!({
-            "description": "nostr pipeline",
-            "processors": [
-                {
-                    "inference": {
-                        "model_id": "lang_ident_model_1",
-                        "inference_config": {
-                            "classification": {
-                                "num_top_classes": 3
-                            }
-                        },
-                        "field_mappings": {},
-                        "target_field": "_ml.lang_ident"
-                    }
-                },
-                {
-                    "rename": {
-                        "field": "_ml.lang_ident.predicted_value",
-                        "target_field": "language"
-                    }
-                },
-                {
-                    "remove": {
-                        "field": "_ml"
-                    }
-                },
-                {
-                    "set": {
-                        "field": "timestamp",
-                        "value": "{{{_ingest.timestamp}}}"
-                    }
-                }
-            ]
-        }))
+        .body(pipeline)
         .send()
         .await?;
 
@@ -57,112 +138,468 @@ pub async fn put_pipeline(
     Ok(())
 }
 
-pub async fn create_index_template(
-    es_client: &Elasticsearch,
-    template_name: &str,
-    pipeline_name: &str,
+/// The language of documents the model fails on, e.g. for empty texts, is `unknown`; other
+/// failures leave the document as processed so far, so that it is still indexed.
+fn gen_pipeline(
+    analyzer_config: &AnalyzerConfig,
+    language_allowlist: Option<&LanguageAllowlist>,
+) -> Value {
+    let languages = analyzer_config.languages.keys().collect::<Vec<_>>();
+    let mut pipeline = json!({
+        "description": "nostr pipeline",
+        "processors": [
+            {
+                "inference": {
+                    "model_id": "lang_ident_model_1",
+                    "inference_config": {
+                        "classification": {
+                            "num_top_classes": 3
+                        }
+                    },
+                    "field_mappings": {},
+                    "target_field": "_ml.lang_ident",
+                    "on_failure": [
+                        {
+                            "set": {
+                                "field": "language",
+                                "value": "unknown"
+                            }
+                        }
+                    ]
+                }
+            },
+            {
+                "rename": {
+                    "field": "_ml.lang_ident.predicted_value",
+                    "target_field": "language",
+                    "ignore_missing": true
+                }
+            },
+            {
+                "remove": {
+                    "field": "_ml",
+                    "ignore_missing": true
+                }
+            },
+            {
+                "script": {
+                    "description": "route text into the field for the detected language",
+                    "if": "ctx.language != null && params.languages.contains(ctx.language)",
+                    "source": "ctx.texts = new HashMap(); ctx.texts.put(ctx.language, ctx.text);",
+                    "params": {
+                        "languages": languages
+                    }
+                }
+            },
+            {
+                "set": {
+                    "field": "timestamp",
+                    "value": "{{{_ingest.timestamp}}}"
+                }
+            }
+        ],
+        "on_failure": [
+            {
+                "set": {
+                    "field": "language",
+                    "value": "unknown",
+                    "override": false
+                }
+            },
+            {
+                "set": {
+                    "field": "timestamp",
+                    "value": "{{{_ingest.timestamp}}}"
+                }
+            }
+        ]
+    });
+    if let Some(allowlist) = language_allowlist {
+        pipeline["processors"]
+            .as_array_mut()
+            .unwrap()
+            .push(allowlist.processor());
+    }
+    pipeline
+}
+
+fn gen_index_template(
+    pipeline_name: Option<&str>,
     index_name_prefix: &str,
     index_alias_name: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    info!("putting index template: {}", template_name);
-    let res = es_client
-        .indices()
-        .put_index_template(IndicesPutIndexTemplateParts::Name(template_name))
-        .body(json!({
-            "index_patterns": [format!("{}-*", index_name_prefix)],
-            "template": {
-                "settings": {
-                    "index": {
-                        "number_of_shards": 1,
-                        "number_of_replicas": 0,
-                        "analysis": {
-                            "analyzer": {
-                                "ngram_analyzer": {
-                                "type": "custom",
-                                "tokenizer": "ngram_tokenizer",
-                                "filter": ["icu_normalizer", "lowercase"],
-                                },
-                            },
-                            "tokenizer": {
-                                "ngram_tokenizer": {
-                                "type": "ngram",
-                                "min_gram": "1",
-                                "max_gram": "2",
-                                },
-                            },
-                        },
-                        "default_pipeline": pipeline_name
+    analyzer_config: &AnalyzerConfig,
+    tag_prefixes: &TagPrefixes,
+    embedding_config: Option<&EmbeddingConfig>,
+) -> Value {
+    let (analyzers, tokenizers, filters) = analyzer_config.analysis();
+    // subfields of profile names: prefixes, and whole words for fuzzy matching
+    let name_fields = json!({
+        "edge": {
+            "type": "text",
+            "analyzer": "edge_ngram_analyzer",
+            "search_analyzer": "standard"
+        },
+        "words": {
+            "type": "text",
+            "analyzer": "standard"
+        }
+    });
+    let mut template = json!({
+        "index_patterns": [format!("{}-*", index_name_prefix)],
+        // namespaced prefixes (`nostr-test`) also match the pattern of the default one (`nostr-*`);
+        // the longer, more specific prefix takes precedence
+        "priority": index_name_prefix.len(),
+        "template": {
+            "settings": {
+                "index": {
+                    "number_of_shards": 1,
+                    "number_of_replicas": 0,
+                    "analysis": {
+                        "analyzer": analyzers,
+                        "tokenizer": tokenizers,
+                        "filter": filters,
                     },
+                    "max_ngram_diff": analyzer_config.max_ngram_diff(),
                 },
-                "mappings": {
-                    "dynamic": false,
-                    "properties": {
-                        "event": {
-                            "dynamic": false,
-                            "properties": {
-                                "content": {
-                                    "type": "text",
-                                    "index": false
-                                },
-                                "created_at": {
-                                    "type": "date",
-                                    "format": "epoch_second"
-                                },
-                                "kind": {
-                                    "type": "integer"
+            },
+            "mappings": {
+                "dynamic": false,
+                // tag values are matched and counted whole, as the prefix mappings below do
+                "dynamic_templates": [{
+                    "tags": {
+                        "path_match": "tags.*",
+                        "match_mapping_type": "string",
+                        "mapping": { "type": "keyword" }
+                    }
+                }],
+                "properties": {
+                    "event": {
+                        "dynamic": false,
+                        "properties": {
+                            "content": {
+                                "type": "text",
+                                "index": false
+                            },
+                            "created_at": {
+                                "type": "date",
+                                "format": "epoch_second"
+                            },
+                            "kind": {
+                                "type": "integer"
+                            },
+                            "id": {
+                                "type": "text",
+                                "index_prefixes": {
+                                    "min_chars": 1,
+                                    "max_chars": 19
                                 },
-                                "id": {
-                                    "type": "text",
-                                    "index_prefixes": {
-                                        "min_chars": 1,
-                                        "max_chars": 19
+                                "fields": {
+                                    "keyword": {
+                                        "type": "keyword"
                                     }
+                                }
+                            },
+                            "pubkey": {
+                                "type": "text",
+                                "index_prefixes": {
+                                    "min_chars": 1,
+                                    "max_chars": 19
                                 },
-                                "pubkey": {
-                                    "type": "text",
-                                    "index_prefixes": {
-                                        "min_chars": 1,
-                                        "max_chars": 19
+                                "fields": {
+                                    "keyword": {
+                                        "type": "keyword"
                                     }
-                                },
-                                "sig": {
-                                    "type": "keyword",
-                                    "index": false
-                                },
-                                "tags": {
-                                    "type": "keyword"
-                                },
+                                }
+                            },
+                            "sig": {
+                                "type": "keyword",
+                                "index": false
+                            },
+                            "tags": {
+                                "type": "keyword"
+                            },
+                        }
+                    },
+                    "text": {
+                        "type": "text",
+                        "analyzer": "ngram_analyzer",
+                        "index": "true",
+                        "fields": {
+                            "suggest": {
+                                "type": "text",
+                                "analyzer": "standard"
+                            }
+                        }
+                    },
+                    "texts": {
+                        "properties": analyzer_config.language_fields(),
+                    },
+                    "language": {
+                        "type": "keyword"
+                    },
+                    "timestamp": {
+                        "type": "date"
+                    },
+                    "tags": {
+                        "dynamic": true,
+                        "properties": {}
+                    },
+                    "identifier_tag": {
+                        "type": "keyword"
+                    },
+                    "content_warning": {
+                        "type": "text",
+                        "analyzer": "standard",
+                        "fields": {
+                            "keyword": {
+                                "type": "keyword"
                             }
-                        },
-                        "text": {
-                            "type": "text",
-                            "analyzer": "ngram_analyzer",
-                            "index": "true",
-                        },
-                        "language": {
-                            "type": "keyword"
-                        },
-                        "timestamp": {
-                            "type": "date"
-                        },
-                        "tags": {
-                            "dynamic": true,
-                            "properties": {
-                                "*": {
-                                    "type": "keyword"
+                        }
+                    },
+                    "sensitive": {
+                        "type": "boolean"
+                    },
+                    "protected": {
+                        "type": "boolean"
+                    },
+                    "delegator": {
+                        "type": "keyword"
+                    },
+                    "urls": {
+                        "type": "keyword",
+                        "ignore_above": 2048
+                    },
+                    "domains": {
+                        "type": "keyword"
+                    },
+                    "url_count": {
+                        "type": "integer"
+                    },
+                    "refs": {
+                        "properties": {
+                            "events": {
+                                "type": "keyword"
+                            },
+                            "pubkeys": {
+                                "type": "keyword"
+                            }
+                        }
+                    },
+                    "mentions": {
+                        "properties": {
+                            "events": {
+                                "type": "keyword"
+                            },
+                            "pubkeys": {
+                                "type": "keyword"
+                            },
+                            "addresses": {
+                                "type": "keyword"
+                            }
+                        }
+                    },
+                    "media": {
+                        "properties": {
+                            "url": {
+                                "type": "keyword",
+                                "ignore_above": 2048
+                            },
+                            "mime_type": {
+                                "type": "keyword"
+                            },
+                            "alt": {
+                                "type": "text"
+                            },
+                            "width": {
+                                "type": "integer"
+                            },
+                            "height": {
+                                "type": "integer"
+                            }
+                        }
+                    },
+                    "engagement": {
+                        "properties": {
+                            "reactions": {
+                                "type": "integer"
+                            },
+                            "reposts": {
+                                "type": "integer"
+                            }
+                        }
+                    },
+                    "classified": {
+                        "properties": {
+                            "title": {
+                                "type": "text",
+                                "analyzer": "ngram_analyzer"
+                            },
+                            "summary": {
+                                "type": "text",
+                                "analyzer": "ngram_analyzer"
+                            },
+                            "price": {
+                                "type": "double"
+                            },
+                            "currency": {
+                                "type": "keyword"
+                            },
+                            "location": {
+                                "type": "text",
+                                "analyzer": "standard",
+                                "fields": {
+                                    "keyword": {
+                                        "type": "keyword"
+                                    }
+                                }
+                            }
+                        }
+                    },
+                    "occurrence": {
+                        "properties": {
+                            "title": {
+                                "type": "text",
+                                "analyzer": "ngram_analyzer"
+                            },
+                            "starts": {
+                                "type": "date",
+                                "format": "epoch_second"
+                            },
+                            "ends": {
+                                "type": "date",
+                                "format": "epoch_second"
+                            },
+                            "location": {
+                                "type": "text",
+                                "analyzer": "standard",
+                                "fields": {
+                                    "keyword": {
+                                        "type": "keyword"
+                                    }
                                 }
+                            },
+                            "status": {
+                                "type": "keyword"
+                            }
+                        }
+                    },
+                    "geo": {
+                        "type": "geo_point"
+                    },
+                    "profile": {
+                        "properties": {
+                            "name": {
+                                "type": "text",
+                                "analyzer": "ngram_analyzer",
+                                "fields": name_fields
+                            },
+                            "display_name": {
+                                "type": "text",
+                                "analyzer": "ngram_analyzer",
+                                "fields": name_fields
+                            },
+                            "about": {
+                                "type": "text",
+                                "analyzer": "ngram_analyzer"
+                            },
+                            "nip05": {
+                                "type": "keyword"
+                            },
+                            "lud16": {
+                                "type": "keyword"
+                            },
+                            "nip05_verified": {
+                                "type": "boolean"
                             }
-                        },
-                        "identifier_tag": {
-                            "type": "keyword"
                         }
                     }
-                },
-                "aliases": {
-                    index_alias_name: {}
+                }
+            },
+            "aliases": {
+                index_alias_name: {}
+            }
+        }
+    });
+    if let Some(pipeline_name) = pipeline_name {
+        template["template"]["settings"]["index"]["default_pipeline"] = json!(pipeline_name);
+    }
+    if let Some(embedding_config) = embedding_config {
+        template["template"]["mappings"]["properties"]["embedding"] = embedding_config.mapping();
+    }
+    for (tag, mapping) in tag_prefixes.mappings() {
+        template["template"]["mappings"]["properties"]["tags"]["properties"][tag] = mapping;
+    }
+    template
+}
+
+/// Deep-merges `overrides` into `template`: objects are merged key by key, other values replace
+/// those of the template, and `null` removes them.
+fn merge_overrides(template: &mut Value, overrides: &Value) {
+    match (template, overrides) {
+        (Value::Object(template), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                if value.is_null() {
+                    template.remove(key);
+                } else {
+                    merge_overrides(template.entry(key.clone()).or_insert(Value::Null), value);
                 }
             }
-        }))
+        }
+        (template, overrides) => *template = overrides.clone(),
+    }
+}
+
+/// Reads the overrides of the index template from a JSON or, by the `.yaml` or `.yml`
+/// extension, YAML file.
+pub fn load_template_overrides(path: &str) -> anyhow::Result<Value> {
+    let content = std::fs::read_to_string(path)?;
+    let overrides: Value = if path.ends_with(".yaml") || path.ends_with(".yml") {
+        serde_yaml::from_str(&content)?
+    } else {
+        serde_json::from_str(&content)?
+    };
+    if !overrides.is_object() {
+        return Err(anyhow::anyhow!("the overrides are not an object"));
+    }
+    Ok(overrides)
+}
+
+/// Puts the index template unless the stored one is up to date; see `needs_update`.
+pub async fn create_index_template(
+    es_client: &Elasticsearch,
+    template_name: &str,
+    pipeline_name: Option<&str>,
+    index_name_prefix: &str,
+    index_alias_name: &str,
+    analyzer_config: &AnalyzerConfig,
+    tag_prefixes: &TagPrefixes,
+    embedding_config: Option<&EmbeddingConfig>,
+    overrides: Option<&Value>,
+    force: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut template = gen_index_template(
+        pipeline_name,
+        index_name_prefix,
+        index_alias_name,
+        analyzer_config,
+        tag_prefixes,
+        embedding_config,
+    );
+    if let Some(overrides) = overrides {
+        merge_overrides(&mut template, overrides);
+    }
+    // the digest covers the overrides, so that changing them updates the template
+    let template = with_meta(template);
+    let meta = index_template_meta(es_client, template_name).await?;
+    if !needs_update(meta.as_ref(), &template, force) {
+        info!("index template is up to date: {}", template_name);
+        return Ok(());
+    }
+    info!("putting index template: {}", template_name);
+    let res = es_client
+        .indices()
+        .put_index_template(IndicesPutIndexTemplateParts::Name(template_name))
+        .body(template)
         .send()
         .await?;
 
@@ -173,3 +610,104 @@ pub async fn create_index_template(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::index::analyzer::AnalyzerConfig;
+    use crate::index::schema::{
+        gen_index_template, gen_pipeline, merge_overrides, needs_update, with_meta, SCHEMA_VERSION,
+    };
+    use crate::search::prefix::TagPrefixes;
+
+    #[test]
+    fn test_pipeline_failures() {
+        let pipeline = gen_pipeline(&AnalyzerConfig::default(), None);
+        let processors = pipeline["processors"].as_array().unwrap();
+        assert_eq!(
+            processors[0]["inference"]["on_failure"],
+            json!([{ "set": { "field": "language", "value": "unknown" } }])
+        );
+        // the model output is missing after its failure
+        assert_eq!(processors[1]["rename"]["ignore_missing"], true);
+        assert_eq!(processors[2]["remove"]["ignore_missing"], true);
+        let on_failure = pipeline["on_failure"].as_array().unwrap();
+        assert!(on_failure.iter().any(|p| p["set"]["field"] == "timestamp"));
+    }
+
+    #[test]
+    fn test_tag_mapping() {
+        let template = gen_index_template(
+            None,
+            "nostr-",
+            "nostr",
+            &AnalyzerConfig::default(),
+            &TagPrefixes::default(),
+            None,
+        );
+        let mappings = &template["template"]["mappings"];
+        assert_eq!(
+            mappings["dynamic_templates"][0]["tags"]["path_match"],
+            "tags.*"
+        );
+        assert_eq!(
+            mappings["dynamic_templates"][0]["tags"]["mapping"]["type"],
+            "keyword"
+        );
+    }
+
+    #[test]
+    fn test_needs_update() {
+        let definition = with_meta(json!({ "description": "nostr pipeline" }));
+        let meta = definition["_meta"].clone();
+        assert!(!needs_update(Some(&meta), &definition, false));
+        assert!(needs_update(Some(&meta), &definition, true));
+        assert!(needs_update(None, &definition, false));
+
+        let changed = with_meta(json!({ "description": "nostr pipeline", "processors": [] }));
+        assert!(needs_update(Some(&meta), &changed, false));
+
+        let older = json!({ "version": SCHEMA_VERSION - 1, "digest": meta["digest"] });
+        assert!(needs_update(Some(&older), &definition, false));
+        let newer = json!({ "version": SCHEMA_VERSION + 1 });
+        assert!(!needs_update(Some(&newer), &definition, false));
+        assert!(needs_update(Some(&json!(null)), &definition, false));
+    }
+
+    #[test]
+    fn test_merge_overrides() {
+        let mut template = json!({
+            "template": {
+                "settings": { "index": { "number_of_shards": 1, "number_of_replicas": 0 } },
+                "mappings": { "properties": { "text": { "type": "text" } } }
+            },
+            "index_patterns": ["nostr-*"]
+        });
+        merge_overrides(
+            &mut template,
+            &json!({
+                "template": {
+                    "settings": { "index": { "number_of_shards": 3, "number_of_replicas": null } },
+                    "mappings": { "properties": { "extra": { "type": "keyword" } } }
+                },
+                "index_patterns": ["nostr-*", "other-*"]
+            }),
+        );
+        assert_eq!(
+            template,
+            json!({
+                "template": {
+                    "settings": { "index": { "number_of_shards": 3 } },
+                    "mappings": {
+                        "properties": {
+                            "text": { "type": "text" },
+                            "extra": { "type": "keyword" }
+                        }
+                    }
+                },
+                "index_patterns": ["nostr-*", "other-*"]
+            })
+        );
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// events whose relays are remembered, the oldest forgotten first
+const MAX_EVENTS: usize = 100_000;
+/// relays remembered per event
+const MAX_RELAYS: usize = 3;
+
+/// Relays the recently received events were seen on, as hints of the links to them.
+#[derive(Debug, Default)]
+pub struct SeenOn {
+    state: Mutex<SeenOnState>,
+}
+
+#[derive(Debug, Default)]
+struct SeenOnState {
+    relays: HashMap<String, Vec<String>>,
+    /// ids in the order they were first seen
+    order: VecDeque<String>,
+}
+
+impl SeenOn {
+    pub fn record(&self, id: &str, relay: &str) {
+        let relay = relay.trim().trim_end_matches('/');
+        if relay.is_empty() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if !state.relays.contains_key(id) {
+            if state.order.len() >= MAX_EVENTS {
+                if let Some(oldest) = state.order.pop_front() {
+                    state.relays.remove(&oldest);
+                }
+            }
+            state.order.push_back(id.to_string());
+        }
+        let relays = state.relays.entry(id.to_string()).or_default();
+        if relays.len() < MAX_RELAYS && !relays.iter().any(|r| r == relay) {
+            relays.push(relay.to_string());
+        }
+    }
+
+    pub fn relays(&self, id: &str) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .relays
+            .get(id)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::seen_on::{SeenOn, MAX_RELAYS};
+
+    #[test]
+    fn test_seen_on() {
+        let seen_on = SeenOn::default();
+        seen_on.record("a", "wss://r1.example.com/");
+        seen_on.record("a", "wss://r1.example.com");
+        seen_on.record("a", "wss://r2.example.com");
+        seen_on.record("b", "");
+        assert_eq!(
+            seen_on.relays("a"),
+            vec!["wss://r1.example.com", "wss://r2.example.com"]
+        );
+        assert!(seen_on.relays("b").is_empty());
+
+        for i in 0..10 {
+            seen_on.record("c", &format!("wss://r{}.example.com", i));
+        }
+        assert_eq!(seen_on.relays("c").len(), MAX_RELAYS);
+    }
+}
@@ -0,0 +1,244 @@
+use elasticsearch::indices::IndicesStatsParts;
+use elasticsearch::{Elasticsearch, SearchParts};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::index::reconcile::day_of;
+use crate::kind_label::KindLabels;
+
+/// Documents of one day, kind or language.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Count {
+    pub key: String,
+    pub documents: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IndexSize {
+    pub index: String,
+    pub documents: u64,
+    /// including replicas
+    pub size_bytes: u64,
+}
+
+/// What the indices of a namespace hold, for `searchnos stats` and `GET /admin/stats`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IndexStats {
+    /// by the date of the dated indices
+    pub days: Vec<Count>,
+    /// by kind label, or number for kinds without one
+    pub kinds: Vec<Count>,
+    /// `unknown` for documents without a detected language
+    pub languages: Vec<Count>,
+    pub indices: Vec<IndexSize>,
+    /// documents written over the last hour
+    pub last_hour: u64,
+}
+
+fn buckets(aggregation: &Value) -> impl Iterator<Item = (&Value, u64)> {
+    aggregation["buckets"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|bucket| Some((&bucket["key"], bucket["doc_count"].as_u64()?)))
+}
+
+/// Numbers of documents in the indices of `index_alias_name` and size of those matching
+/// `<index_name_prefix>-*`.
+pub async fn index_stats(
+    es_client: &Elasticsearch,
+    index_name_prefix: &str,
+    index_alias_name: &str,
+    kind_labels: &KindLabels,
+) -> anyhow::Result<IndexStats> {
+    let res = es_client
+        .search(SearchParts::Index(&[index_alias_name]))
+        .size(0)
+        .body(json!({
+            "aggs": {
+                "indices": { "terms": { "field": "_index", "size": 10000 } },
+                "kinds": { "terms": { "field": "event.kind", "size": 100 } },
+                "languages": {
+                    "terms": { "field": "language", "size": 100, "missing": "unknown" }
+                },
+                "last_hour": {
+                    "filter": { "range": { "timestamp": { "gte": "now-1h" } } }
+                }
+            }
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to aggregate documents: {} {}",
+            status_code,
+            body
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    let aggregations = &body["aggregations"];
+
+    let mut days = Vec::<Count>::new();
+    for (index, documents) in buckets(&aggregations["indices"]) {
+        // a day is split over several indices during a reindex
+        let day = match index.as_str().and_then(day_of) {
+            Some(day) => day,
+            None => continue,
+        };
+        match days.iter_mut().find(|count| count.key == day) {
+            Some(count) => count.documents += documents,
+            None => days.push(Count {
+                key: day.to_string(),
+                documents,
+            }),
+        }
+    }
+    days.sort_by(|a, b| a.key.cmp(&b.key));
+    let kinds = buckets(&aggregations["kinds"])
+        .filter_map(|(kind, documents)| {
+            let kind = kind.as_u64()? as u32;
+            let key = kind_labels
+                .label(kind)
+                .map(|label| label.to_string())
+                .unwrap_or_else(|| kind.to_string());
+            Some(Count { key, documents })
+        })
+        .collect();
+    let languages = buckets(&aggregations["languages"])
+        .filter_map(|(language, documents)| {
+            Some(Count {
+                key: language.as_str()?.to_string(),
+                documents,
+            })
+        })
+        .collect();
+    let last_hour = aggregations["last_hour"]["doc_count"]
+        .as_u64()
+        .unwrap_or_default();
+
+    let pattern = format!("{}-*", index_name_prefix);
+    let res = es_client
+        .indices()
+        .stats(IndicesStatsParts::Index(&[pattern.as_str()]))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to get index stats: {} {}",
+            status_code,
+            body
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    let mut indices = body["indices"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(index, stats)| IndexSize {
+            index: index.clone(),
+            documents: stats["primaries"]["docs"]["count"]
+                .as_u64()
+                .unwrap_or_default(),
+            size_bytes: stats["total"]["store"]["size_in_bytes"]
+                .as_u64()
+                .unwrap_or_default(),
+        })
+        .collect::<Vec<_>>();
+    indices.sort_by(|a, b| a.index.cmp(&b.index));
+
+    Ok(IndexStats {
+        days,
+        kinds,
+        languages,
+        indices,
+        last_hour,
+    })
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+pub fn format_stats(stats: &IndexStats) -> String {
+    let mut lines = vec![];
+    let mut section = |title: &str, counts: &[Count]| {
+        lines.push(format!("{}:", title));
+        for count in counts {
+            lines.push(format!("  {:<12}\t{}", count.key, count.documents));
+        }
+    };
+    section("documents per day", &stats.days);
+    section("documents per kind", &stats.kinds);
+    section("documents per language", &stats.languages);
+    lines.push("indices:".to_string());
+    for index in &stats.indices {
+        lines.push(format!(
+            "  {}\t{} document(s)\t{}",
+            index.index,
+            index.documents,
+            format_bytes(index.size_bytes)
+        ));
+    }
+    let total = stats.indices.iter().map(|index| index.size_bytes).sum();
+    lines.push(format!("total size: {}", format_bytes(total)));
+    lines.push(format!(
+        "ingestion rate: {} document(s) over the last hour ({:.1}/min)",
+        stats.last_hour,
+        stats.last_hour as f64 / 60.0
+    ));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::stats::{format_bytes, format_stats, Count, IndexSize, IndexStats};
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_format_stats() {
+        let stats = IndexStats {
+            days: vec![Count {
+                key: "2023.03.20".to_string(),
+                documents: 10,
+            }],
+            kinds: vec![Count {
+                key: "note".to_string(),
+                documents: 10,
+            }],
+            languages: vec![],
+            indices: vec![IndexSize {
+                index: "nostr-2023.03.20".to_string(),
+                documents: 10,
+                size_bytes: 2048,
+            }],
+            last_hour: 120,
+        };
+        let text = format_stats(&stats);
+        assert!(text.contains("  2023.03.20  \t10"));
+        assert!(text.contains("  nostr-2023.03.20\t10 document(s)\t2.0 KiB"));
+        assert!(text.contains("total size: 2.0 KiB"));
+        assert!(text.ends_with("120 document(s) over the last hour (2.0/min)"));
+    }
+}
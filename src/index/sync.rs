@@ -0,0 +1,257 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use elasticsearch::{Elasticsearch, SearchParts};
+use nostr_sdk::prelude::{Event, Filter, Kind, Timestamp};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+use crate::index::indexes::{profiles_index_name, replaceable_index_name};
+use crate::index::lock::try_lock;
+use crate::index::upstream::Upstream;
+use crate::metrics::Metrics;
+
+/// ids looked up in Elasticsearch with one query
+const IDS_PER_QUERY: usize = 500;
+/// events this recent may still be on their way from the indexer
+const SETTLE_SECS: u64 = 60;
+
+/// Periodic comparison of the recent events of upstream relays with the index, queueing the
+/// ones missing, e.g. after the indexer was down.
+#[derive(Debug, Clone)]
+pub struct SyncConfig {
+    pub relays: Vec<String>,
+    pub kinds: Vec<u64>,
+    pub interval: Duration,
+    /// how far back each sync looks
+    pub window: Duration,
+    /// `limit` of each REQ paging through the window
+    pub page_size: usize,
+    pub timeout: Duration,
+}
+
+/// `until` of the page after one of `events` requested until `until`, or `None` when the
+/// window is exhausted.
+///
+/// The next page starts at the oldest `created_at` again so that events of the same second
+/// split over pages are not missed; a page of a single second moves one second back.
+fn next_until(events: &[Event], until: Timestamp, page_size: usize) -> Option<Timestamp> {
+    if events.len() < page_size {
+        return None;
+    }
+    let oldest = events.iter().map(|event| event.created_at).min()?;
+    if oldest >= until {
+        Some(Timestamp::from(until.as_u64().checked_sub(1)?))
+    } else {
+        Some(oldest)
+    }
+}
+
+/// Events of the relay `relay` of `upstream` created from `since` to `until`, paged through by
+/// `until`.
+async fn fetch_window(
+    upstream: &Upstream,
+    relay: usize,
+    config: &SyncConfig,
+    since: Timestamp,
+    until: Timestamp,
+) -> anyhow::Result<Vec<Event>> {
+    let kinds = config
+        .kinds
+        .iter()
+        .map(|kind| Kind::from(*kind))
+        .collect::<Vec<_>>();
+    let mut seen = HashSet::new();
+    let mut events = vec![];
+    let mut until = until;
+    loop {
+        let filter = Filter::new()
+            .kinds(kinds.clone())
+            .since(since)
+            .until(until)
+            .limit(config.page_size);
+        let page = upstream.fetch_from(relay, vec![filter]).await?;
+        let next = next_until(&page, until, config.page_size);
+        for event in page {
+            if seen.insert(event.id) {
+                events.push(event);
+            }
+        }
+        match next {
+            Some(next) if next >= since => until = next,
+            _ => return Ok(events),
+        }
+    }
+}
+
+/// ids among `ids` of the documents of the namespace of `index_name_prefix`, including the
+/// undated indices of profiles and replaceable events.
+async fn indexed_ids(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    index_name_prefix: &str,
+    ids: &[String],
+) -> anyhow::Result<HashSet<String>> {
+    let (profiles, replaceable) = (
+        profiles_index_name(index_name_prefix),
+        replaceable_index_name(index_name_prefix),
+    );
+    let res = es_client
+        .search(SearchParts::Index(&[
+            index_alias_name,
+            &profiles,
+            &replaceable,
+        ]))
+        // the undated indices exist only when enabled
+        .ignore_unavailable(true)
+        .body(json!({
+            "query": {
+                "bool": {
+                    "should": [
+                        // indices created before the keyword subfield
+                        { "terms": { "_id": ids } },
+                        // documents of the undated indices have other ids
+                        { "terms": { "event.id.keyword": ids } }
+                    ],
+                    "minimum_should_match": 1
+                }
+            },
+            "_source": ["event.id"],
+            "size": ids.len()
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to look up indexed ids: {} {}",
+            status_code,
+            body
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    Ok(body["hits"]["hits"]
+        .as_array()
+        .map(|hits| {
+            hits.iter()
+                .filter_map(|hit| hit["_source"]["event"]["id"].as_str())
+                .map(|id| id.to_string())
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Queues the events of the window of each relay that are not indexed, fastest relay first.
+async fn sync(state: &AppState, config: &SyncConfig, upstream: &Upstream) -> anyhow::Result<()> {
+    let until = Timestamp::from(Timestamp::now().as_u64() - SETTLE_SECS);
+    let since = Timestamp::from(until.as_u64().saturating_sub(config.window.as_secs()));
+    for relay in upstream.ranked() {
+        let url = upstream.url(relay);
+        let events = match fetch_window(upstream, relay, config, since, until).await {
+            Ok(events) => events,
+            Err(e) => {
+                log::warn!("failed to sync with {}: {}", url, e);
+                continue;
+            }
+        };
+        let mut missing = 0;
+        for events in events.chunks(IDS_PER_QUERY) {
+            let ids = events.iter().map(|e| e.id.to_hex()).collect::<Vec<_>>();
+            let indexed = indexed_ids(
+                &state.es_client,
+                &state.index_alias_name,
+                &state.index_name_prefix,
+                &ids,
+            )
+            .await?;
+            for event in events {
+                if indexed.contains(&event.id.to_hex())
+                    || event.verify().is_err()
+                    || !state.sampling.keeps(event, Some(url))
+                {
+                    continue;
+                }
+                state.seen_on.record(&event.id.to_hex(), url);
+                // deleted, replaced and other skipped events are left out by the ingest chain
+                if let Some(ack_log) = &state.ack_log {
+                    ack_log.append(event).await?;
+                }
+                state
+                    .index_queue
+                    .push(&state.metrics, event.clone())
+                    .await?;
+                Metrics::inc(&state.metrics.events_synced);
+                missing += 1;
+            }
+        }
+        log::info!(
+            "synced with {}: {} event(s) in the window, {} missing",
+            url,
+            events.len(),
+            missing
+        );
+    }
+    Ok(())
+}
+
+pub fn spawn_sync(state: Arc<AppState>, config: SyncConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // one client per relay, so that each window is compared on its own
+        let upstream = match Upstream::connect(&config.relays, config.timeout).await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                log::error!("failed to add the sync relays: {}", e);
+                return;
+            }
+        };
+        // left to expire, so that one replica syncs per interval
+        let lock_name = format!("sync-{}", state.index_alias_name);
+        let lock_ttl = config.interval - config.interval / 12;
+        loop {
+            match try_lock(&state.es_client, &lock_name, lock_ttl).await {
+                Ok(Some(_)) => {
+                    if let Err(e) = sync(&state, &config, &upstream).await {
+                        log::error!("failed to sync with upstream relays: {}", e);
+                    }
+                }
+                Ok(None) => log::debug!("another replica syncs {}", state.index_alias_name),
+                Err(e) => log::error!("failed to take the sync lock: {}", e),
+            }
+            tokio::time::sleep(config.interval).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind, Timestamp};
+
+    use crate::index::sync::next_until;
+
+    #[test]
+    fn test_next_until() {
+        let keys = Keys::generate();
+        let events = [300, 200, 100]
+            .iter()
+            .map(|created_at| {
+                let mut event = EventBuilder::new(Kind::TextNote, "hello", &[])
+                    .to_event(&keys)
+                    .unwrap();
+                event.created_at = Timestamp::from(*created_at);
+                event
+            })
+            .collect::<Vec<_>>();
+        let until = Timestamp::from(400);
+        assert_eq!(next_until(&events, until, 3), Some(Timestamp::from(100)));
+        assert_eq!(next_until(&events, until, 4), None);
+        // the whole page was created in the second of `until`
+        assert_eq!(
+            next_until(&events, Timestamp::from(100), 3),
+            Some(Timestamp::from(99))
+        );
+        assert_eq!(next_until(&[], until, 0), None);
+    }
+}
@@ -1,34 +1,209 @@
 pub use nostr_sdk::prelude::*;
 use nostr_sdk::Event;
 use std::collections::HashMap;
+use std::sync::Arc;
 
-pub fn extract_text(event: &Event) -> String {
-    match event.kind {
-        Kind::Metadata => {
-            let content: HashMap<String, String> =
-                serde_json::from_str(&event.content).unwrap_or_default();
-            let texts: Vec<String> = content.values().map(|s| s.to_string()).collect();
-            texts.join(" ")
-        }
-        Kind::LongFormTextNote => {
-            let mut items = vec![event.content.clone()];
-            items.extend(event.tags.iter().filter_map(|tag| match tag {
-                nostr_sdk::Tag::Title(title) => Some(title.clone()),
-                nostr_sdk::Tag::Summary(summary) => Some(summary.clone()),
-                _ => None,
-            }));
-            items.join(" ")
+use crate::index::classified::KIND_CLASSIFIED;
+use crate::index::media::extract_media;
+use crate::index::occurrence::{
+    KIND_CALENDAR, KIND_DATE_EVENT, KIND_LIVE_EVENT, KIND_RSVP, KIND_TIME_EVENT,
+};
+
+/// Text searched in the events of some kinds.
+pub trait Extractor: Send + Sync {
+    fn kinds(&self) -> Vec<u64>;
+
+    fn extract(&self, event: &Event) -> String;
+}
+
+/// Values of the tags of `event` named one of `names`, in order.
+fn tag_values(event: &Event, names: &[&str]) -> Vec<String> {
+    event
+        .tags
+        .iter()
+        .filter_map(|tag| {
+            let tag = tag.as_vec();
+            if tag.len() >= 2 && names.contains(&tag[0].as_str()) {
+                Some(tag[1].clone())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// `content` followed by the values of the tags named one of `names`.
+fn content_with_tags(event: &Event, names: &[&str]) -> String {
+    let mut items = vec![event.content.clone()];
+    items.extend(tag_values(event, names));
+    items.join(" ")
+}
+
+pub struct TextNoteExtractor;
+
+impl Extractor for TextNoteExtractor {
+    fn kinds(&self) -> Vec<u64> {
+        vec![1]
+    }
+
+    fn extract(&self, event: &Event) -> String {
+        event.content.clone()
+    }
+}
+
+/// Values of the profile JSON in the content.
+pub struct MetadataExtractor;
+
+impl Extractor for MetadataExtractor {
+    fn kinds(&self) -> Vec<u64> {
+        vec![0]
+    }
+
+    fn extract(&self, event: &Event) -> String {
+        let content: HashMap<String, String> =
+            serde_json::from_str(&event.content).unwrap_or_default();
+        let texts: Vec<String> = content.values().map(|s| s.to_string()).collect();
+        texts.join(" ")
+    }
+}
+
+/// NIP-23 articles, with their title and summary.
+pub struct LongFormExtractor;
+
+impl Extractor for LongFormExtractor {
+    fn kinds(&self) -> Vec<u64> {
+        vec![30023]
+    }
+
+    fn extract(&self, event: &Event) -> String {
+        content_with_tags(event, &["title", "summary"])
+    }
+}
+
+/// NIP-28 channel creation and metadata, whose content is the JSON of the channel.
+pub struct ChannelMetadataExtractor;
+
+impl Extractor for ChannelMetadataExtractor {
+    fn kinds(&self) -> Vec<u64> {
+        vec![40, 41]
+    }
+
+    fn extract(&self, event: &Event) -> String {
+        let content: HashMap<String, serde_json::Value> =
+            serde_json::from_str(&event.content).unwrap_or_default();
+        ["name", "about"]
+            .iter()
+            .filter_map(|key| content.get(*key)?.as_str())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// NIP-53 live events, described by their tags.
+pub struct LiveEventExtractor;
+
+impl Extractor for LiveEventExtractor {
+    fn kinds(&self) -> Vec<u64> {
+        vec![KIND_LIVE_EVENT]
+    }
+
+    fn extract(&self, event: &Event) -> String {
+        tag_values(event, &["title", "summary"]).join(" ")
+    }
+}
+
+/// NIP-52 calendar events, calendars and RSVPs, with their title, summary and location.
+pub struct CalendarExtractor;
+
+impl Extractor for CalendarExtractor {
+    fn kinds(&self) -> Vec<u64> {
+        vec![KIND_DATE_EVENT, KIND_TIME_EVENT, KIND_CALENDAR, KIND_RSVP]
+    }
+
+    fn extract(&self, event: &Event) -> String {
+        content_with_tags(event, &["title", "summary", "location"])
+    }
+}
+
+/// NIP-99 classified listings, with their title, summary and location.
+pub struct ClassifiedExtractor;
+
+impl Extractor for ClassifiedExtractor {
+    fn kinds(&self) -> Vec<u64> {
+        vec![KIND_CLASSIFIED]
+    }
+
+    fn extract(&self, event: &Event) -> String {
+        content_with_tags(event, &["title", "summary", "location"])
+    }
+}
+
+/// Extractors by kind; events of other kinds are searched by their content.
+#[derive(Clone)]
+pub struct Extractors {
+    by_kind: HashMap<u64, Arc<dyn Extractor>>,
+}
+
+impl Default for Extractors {
+    fn default() -> Self {
+        let mut extractors = Extractors {
+            by_kind: HashMap::new(),
+        };
+        extractors.register(TextNoteExtractor);
+        extractors.register(MetadataExtractor);
+        extractors.register(LongFormExtractor);
+        extractors.register(ChannelMetadataExtractor);
+        extractors.register(LiveEventExtractor);
+        extractors.register(CalendarExtractor);
+        extractors.register(ClassifiedExtractor);
+        extractors
+    }
+}
+
+impl std::fmt::Debug for Extractors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Extractors")
+            .field("kinds", &self.by_kind.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Extractors {
+    /// Extracts the text of the kinds of `extractor`, replacing their current extractors.
+    pub fn register(&mut self, extractor: impl Extractor + 'static) {
+        let extractor: Arc<dyn Extractor> = Arc::new(extractor);
+        for kind in extractor.kinds() {
+            self.by_kind.insert(kind, extractor.clone());
         }
+    }
 
-        _ => event.content.clone(),
+    pub fn extract(&self, event: &Event) -> String {
+        let text = match self.by_kind.get(&event.kind.as_u64()) {
+            Some(extractor) => extractor.extract(event),
+            None => event.content.clone(),
+        };
+        // alt texts of attached media are searched along with the content
+        let alts = extract_media(event)
+            .into_iter()
+            .filter_map(|media| media.alt)
+            .collect::<Vec<_>>();
+        if alts.is_empty() {
+            text
+        } else {
+            format!("{} {}", text, alts.join(" "))
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use nostr_sdk::{Keys, Kind, Tag};
+    use nostr_sdk::{Event, Keys, Kind, Tag, TagKind};
 
-    use crate::index::text::extract_text;
+    use crate::index::text::{Extractor, Extractors};
+
+    fn extract_text(event: &Event) -> String {
+        Extractors::default().extract(event)
+    }
 
     #[test]
     fn test_extract_text_note() {
@@ -70,4 +245,72 @@ mod tests {
             "# hello\n\nworld title summary".to_string()
         );
     }
+
+    #[test]
+    fn test_extract_text_other_kinds() {
+        let keys = Keys::generate();
+        let tag = |name: &str, value: &str| {
+            Tag::Generic(TagKind::Custom(name.to_string()), vec![value.to_string()])
+        };
+        let channel = nostr_sdk::EventBuilder::new(
+            Kind::ChannelCreation,
+            r#"{"name":"rust","about":"rustaceans","picture":"https://example.com/a.png"}"#,
+            &[],
+        )
+        .to_event(&keys)
+        .unwrap();
+        assert_eq!(extract_text(&channel), "rust rustaceans");
+
+        let live = nostr_sdk::EventBuilder::new(
+            Kind::from(30311),
+            "",
+            &[tag("title", "podcast"), tag("summary", "weekly")],
+        )
+        .to_event(&keys)
+        .unwrap();
+        assert_eq!(extract_text(&live), "podcast weekly");
+
+        let meetup = nostr_sdk::EventBuilder::new(
+            Kind::from(31923),
+            "bring snacks",
+            &[tag("title", "meetup"), tag("location", "Shibuya")],
+        )
+        .to_event(&keys)
+        .unwrap();
+        assert_eq!(extract_text(&meetup), "bring snacks meetup Shibuya");
+
+        let classified = nostr_sdk::EventBuilder::new(
+            Kind::from(30402),
+            "barely used",
+            &[tag("title", "bike"), tag("location", "Tokyo")],
+        )
+        .to_event(&keys)
+        .unwrap();
+        assert_eq!(extract_text(&classified), "barely used bike Tokyo");
+    }
+
+    #[test]
+    fn test_register_extractor() {
+        struct Reversed;
+
+        impl Extractor for Reversed {
+            fn kinds(&self) -> Vec<u64> {
+                vec![1, 9802]
+            }
+
+            fn extract(&self, event: &Event) -> String {
+                event.content.chars().rev().collect()
+            }
+        }
+
+        let keys = Keys::generate();
+        let mut extractors = Extractors::default();
+        extractors.register(Reversed);
+        for kind in [1, 9802] {
+            let event = nostr_sdk::EventBuilder::new(Kind::from(kind), "abc", &[])
+                .to_event(&keys)
+                .unwrap();
+            assert_eq!(extractors.extract(&event), "cba");
+        }
+    }
 }
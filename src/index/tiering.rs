@@ -0,0 +1,270 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use elasticsearch::indices::{
+    IndicesForcemergeParts, IndicesGetSettingsParts, IndicesPutSettingsParts,
+};
+use elasticsearch::Elasticsearch;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+use crate::index::indexes::{check_index_date, SkipReason};
+use crate::index::lock::try_lock;
+
+const TIERING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Data tiers dated indices move to as they age; recent ones stay on the hot tier.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tier {
+    Warm,
+    Cold,
+}
+
+impl Tier {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Tier::Warm => "warm",
+            Tier::Cold => "cold",
+        }
+    }
+
+    /// `_tier_preference` of the indices in the tier, falling back to warmer tiers when a
+    /// cluster has no nodes of the tier.
+    fn tier_preference(&self) -> &'static str {
+        match self {
+            Tier::Warm => "data_warm,data_hot",
+            Tier::Cold => "data_cold,data_warm,data_hot",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Phase {
+    /// age of the index date at which indices enter the phase
+    pub min_age_days: u64,
+    /// merge the indices down to one segment on entering the phase
+    #[serde(default)]
+    pub force_merge: bool,
+    /// replicas kept in the phase; left as they are if `None`
+    #[serde(default)]
+    pub replicas: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPolicy {
+    warm: Option<Phase>,
+    cold: Option<Phase>,
+}
+
+/// Phases of the dated indices after the hot one, applied by searchnos by index date; the
+/// deletion that ends them is the TTL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TieringPolicy {
+    /// by increasing age
+    pub phases: Vec<(Tier, Phase)>,
+}
+
+impl TieringPolicy {
+    /// Parses a policy like `{"warm": {"min_age_days": 7, "force_merge": true, "replicas": 0},
+    /// "cold": {"min_age_days": 30}}`.
+    pub fn parse(value: Value) -> anyhow::Result<Self> {
+        let raw = serde_json::from_value::<RawPolicy>(value)?;
+        let phases = [(Tier::Warm, raw.warm), (Tier::Cold, raw.cold)]
+            .into_iter()
+            .filter_map(|(tier, phase)| phase.map(|phase| (tier, phase)))
+            .collect::<Vec<_>>();
+        if phases.is_empty() {
+            return Err(anyhow::anyhow!("no warm or cold phase"));
+        }
+        if phases
+            .windows(2)
+            .any(|w| w[0].1.min_age_days >= w[1].1.min_age_days)
+        {
+            return Err(anyhow::anyhow!("the cold phase starts before the warm one"));
+        }
+        Ok(TieringPolicy { phases })
+    }
+
+    /// Reads the policy from a JSON or, by the `.yaml` or `.yml` extension, YAML file.
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let value: Value = if path.ends_with(".yaml") || path.ends_with(".yml") {
+            serde_yaml::from_str(&content)?
+        } else {
+            serde_json::from_str(&content)?
+        };
+        Self::parse(value)
+    }
+
+    /// Phase the dated index `index_name` is in, if past the hot one.
+    pub fn phase(&self, index_name: &str, current_time: &DateTime<Utc>) -> Option<&(Tier, Phase)> {
+        self.phases.iter().rev().find(|(_, phase)| {
+            check_index_date(index_name, current_time, Some(phase.min_age_days), 0)
+                == Err(SkipReason::TooOld)
+        })
+    }
+}
+
+/// Settings of an index entering `phase`.
+fn phase_settings(tier: Tier, phase: &Phase) -> Value {
+    let mut settings = json!({
+        "index.routing.allocation.include._tier_preference": tier.tier_preference()
+    });
+    if let Some(replicas) = phase.replicas {
+        settings["index.number_of_replicas"] = json!(replicas);
+    }
+    settings
+}
+
+/// Merges `index_name` down to one segment; it should no longer receive many writes.
+pub async fn force_merge(es_client: &Elasticsearch, index_name: &str) -> anyhow::Result<()> {
+    let res = es_client
+        .indices()
+        .forcemerge(IndicesForcemergeParts::Index(&[index_name]))
+        .max_num_segments(1)
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to force-merge {}: {} {}",
+            index_name,
+            status_code,
+            body
+        ));
+    }
+    Ok(())
+}
+
+/// Moves the dated indices of `index_name_prefix` that entered a phase to its tier.
+pub async fn apply_tiering(
+    es_client: &Elasticsearch,
+    index_name_prefix: &str,
+    policy: &TieringPolicy,
+) -> anyhow::Result<()> {
+    let pattern = format!("{}-*", index_name_prefix);
+    let res = es_client
+        .indices()
+        .get_settings(IndicesGetSettingsParts::Index(&[pattern.as_str()]))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to get index settings: {} {}",
+            status_code,
+            body
+        ));
+    }
+    let current_time = Utc::now();
+    let indices = res.json::<HashMap<String, Value>>().await?;
+    for (name, info) in indices {
+        let (tier, phase) = match policy.phase(&name, &current_time) {
+            Some(phase) => phase,
+            None => continue,
+        };
+        let preference =
+            &info["settings"]["index"]["routing"]["allocation"]["include"]["_tier_preference"];
+        // the tier preference marks the indices already moved
+        if preference == tier.tier_preference() {
+            continue;
+        }
+        log::info!("moving index {} to the {} tier", name, tier.as_str());
+        let res = es_client
+            .indices()
+            .put_settings(IndicesPutSettingsParts::Index(&[name.as_str()]))
+            .body(phase_settings(*tier, phase))
+            .send()
+            .await?;
+        if !res.status_code().is_success() {
+            let status_code = res.status_code();
+            let body = res.text().await?;
+            return Err(anyhow::anyhow!(
+                "failed to move {}: {} {}",
+                name,
+                status_code,
+                body
+            ));
+        }
+        if phase.force_merge {
+            force_merge(es_client, &name).await?;
+        }
+    }
+    Ok(())
+}
+
+pub fn spawn_tiering(state: Arc<AppState>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // left to expire, so that one replica moves indices per interval
+        let lock_name = format!("tiering-{}", state.index_alias_name);
+        let lock_ttl = TIERING_INTERVAL - Duration::from_secs(5 * 60);
+        loop {
+            match try_lock(&state.es_client, &lock_name, lock_ttl).await {
+                Ok(Some(_)) => {
+                    if let Some(policy) = &state.tiering_policy {
+                        let res =
+                            apply_tiering(&state.es_client, &state.index_name_prefix, policy).await;
+                        if let Err(e) = res {
+                            log::error!("failed to apply the tiering policy: {}", e);
+                        }
+                    }
+                }
+                Ok(None) => log::debug!("another replica tiers {}", state.index_alias_name),
+                Err(e) => log::error!("failed to take the tiering lock: {}", e),
+            }
+            tokio::time::sleep(TIERING_INTERVAL).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::{DateTime, Utc};
+    use serde_json::json;
+
+    use crate::index::tiering::{phase_settings, Tier, TieringPolicy};
+
+    #[test]
+    fn test_policy() {
+        let policy = TieringPolicy::parse(json!({
+            "warm": { "min_age_days": 7, "force_merge": true, "replicas": 0 },
+            "cold": { "min_age_days": 30 }
+        }))
+        .unwrap();
+        let current_time = DateTime::<Utc>::from_str("2023-03-20T12:00:00Z").unwrap();
+        let tier = |index_name: &str| {
+            policy
+                .phase(index_name, &current_time)
+                .map(|(tier, _)| *tier)
+        };
+        assert_eq!(tier("nostr-2023.03.19"), None);
+        assert_eq!(tier("nostr-2023.03.13"), Some(Tier::Warm));
+        assert_eq!(tier("nostr-2023.02.01"), Some(Tier::Cold));
+        assert_eq!(tier("nostr-replaceable"), None);
+
+        let (tier, phase) = &policy.phases[0];
+        assert_eq!(
+            phase_settings(*tier, phase),
+            json!({
+                "index.routing.allocation.include._tier_preference": "data_warm,data_hot",
+                "index.number_of_replicas": 0
+            })
+        );
+
+        assert!(TieringPolicy::parse(json!({})).is_err());
+        assert!(TieringPolicy::parse(json!({ "delete": { "min_age_days": 90 } })).is_err());
+        assert!(TieringPolicy::parse(json!({
+            "warm": { "min_age_days": 30 },
+            "cold": { "min_age_days": 7 }
+        }))
+        .is_err());
+    }
+}
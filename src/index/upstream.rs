@@ -0,0 +1,234 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use nostr_sdk::prelude::{Client, Event, Filter, Keys};
+
+/// weight of the latest response in the average latency of a relay
+const LATENCY_WEIGHT: f64 = 0.3;
+
+/// Responses of a relay to the requests for events.
+#[derive(Debug, Default, Clone)]
+struct RelayStats {
+    /// moving average, in milliseconds
+    latency_ms: f64,
+    requests: u64,
+    /// requests answered with events
+    hits: u64,
+}
+
+impl RelayStats {
+    fn record(&mut self, latency: Duration, hit: bool) {
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        self.latency_ms = if self.requests == 0 {
+            latency_ms
+        } else {
+            LATENCY_WEIGHT * latency_ms + (1.0 - LATENCY_WEIGHT) * self.latency_ms
+        };
+        self.requests += 1;
+        if hit {
+            self.hits += 1;
+        }
+    }
+
+    /// Expected wait for a hit: the latency divided by the smoothed hit rate. Relays not
+    /// asked yet come first, so that they are measured.
+    fn score(&self) -> f64 {
+        if self.requests == 0 {
+            return 0.0;
+        }
+        let hit_rate = (self.hits as f64 + 1.0) / (self.requests as f64 + 2.0);
+        self.latency_ms / hit_rate
+    }
+}
+
+/// wait for the fastest relay before asking the next one as well
+const HEDGE_AFTER: Duration = Duration::from_secs(2);
+
+/// Upstream relays asked for events, fastest first.
+///
+/// A request goes to the relay expected to answer first, and also to the next one if it has
+/// not answered within `HEDGE_AFTER`. Relays answering without the events wanted are followed
+/// by the next ones until they are found.
+pub struct Upstream {
+    relays: Vec<(String, Client)>,
+    stats: Mutex<HashMap<String, RelayStats>>,
+    timeout: Duration,
+}
+
+/// Indices of the relays ordered by score, ties kept in the configured order.
+fn rank(relays: &[String], stats: &HashMap<String, RelayStats>) -> Vec<usize> {
+    let score = |i: usize| stats.get(&relays[i]).map_or(0.0, |stats| stats.score());
+    let mut order = (0..relays.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| score(*a).total_cmp(&score(*b)));
+    order
+}
+
+impl Upstream {
+    pub async fn connect(relays: &[String], timeout: Duration) -> anyhow::Result<Self> {
+        let keys = Keys::generate();
+        let mut clients = vec![];
+        for relay in relays {
+            // one client per relay, so that each is asked and measured on its own
+            let client = Client::new(&keys);
+            client.add_relay(relay.trim(), None).await?;
+            client.connect().await;
+            clients.push((relay.trim().to_string(), client));
+        }
+        Ok(Upstream {
+            relays: clients,
+            stats: Mutex::new(HashMap::new()),
+            timeout,
+        })
+    }
+
+    pub async fn disconnect(&self) -> anyhow::Result<()> {
+        for (_, client) in &self.relays {
+            client.disconnect().await?;
+        }
+        Ok(())
+    }
+
+    /// Indices of the relays, fastest first.
+    pub fn ranked(&self) -> Vec<usize> {
+        let urls = self
+            .relays
+            .iter()
+            .map(|(url, _)| url.clone())
+            .collect::<Vec<_>>();
+        rank(&urls, &self.stats.lock().unwrap())
+    }
+
+    pub fn url(&self, i: usize) -> &str {
+        &self.relays[i].0
+    }
+
+    fn record(&self, i: usize, latency: Duration, hit: bool) {
+        self.stats
+            .lock()
+            .unwrap()
+            .entry(self.relays[i].0.clone())
+            .or_default()
+            .record(latency, hit);
+    }
+
+    async fn ask(
+        &self,
+        i: usize,
+        filters: Vec<Filter>,
+    ) -> (usize, Duration, anyhow::Result<Vec<Event>>) {
+        let start = Instant::now();
+        let result = self.relays[i]
+            .1
+            .get_events_of(filters, Some(self.timeout))
+            .await
+            .map_err(anyhow::Error::from);
+        (i, start.elapsed(), result)
+    }
+
+    /// Events matching `filters` from the relay `i` alone, counted in its statistics.
+    pub async fn fetch_from(&self, i: usize, filters: Vec<Filter>) -> anyhow::Result<Vec<Event>> {
+        let (i, latency, result) = self.ask(i, filters).await;
+        self.record(
+            i,
+            latency,
+            result.as_ref().map_or(false, |events| !events.is_empty()),
+        );
+        result
+    }
+
+    /// Events matching `filters` with valid signatures, from the first relay that has any.
+    pub async fn fetch(&self, filters: Vec<Filter>) -> Vec<Event> {
+        self.fetch_until(filters, |events| !events.is_empty()).await
+    }
+
+    /// Events matching `filters` with valid signatures, from the relays asked fastest first
+    /// until `found` holds for the events gathered, or all of them were asked.
+    pub async fn fetch_until(
+        &self,
+        filters: Vec<Filter>,
+        found: impl Fn(&[Event]) -> bool,
+    ) -> Vec<Event> {
+        let mut order = self.ranked().into_iter();
+        let mut pending = FuturesUnordered::new();
+        match order.next() {
+            Some(i) => pending.push(self.ask(i, filters.clone())),
+            None => return vec![],
+        }
+        let hedge = tokio::time::sleep(HEDGE_AFTER);
+        tokio::pin!(hedge);
+        let mut hedged = false;
+        let mut ids = HashSet::new();
+        let mut gathered = vec![];
+        while !pending.is_empty() {
+            tokio::select! {
+                Some((i, latency, result)) = pending.next() => {
+                    let events = match result {
+                        Ok(events) => events
+                            .into_iter()
+                            .filter(|event| event.verify().is_ok())
+                            .collect::<Vec<_>>(),
+                        Err(e) => {
+                            log::warn!("failed to fetch events from {}: {}", self.url(i), e);
+                            vec![]
+                        }
+                    };
+                    self.record(i, latency, !events.is_empty());
+                    gathered.extend(events.into_iter().filter(|event| ids.insert(event.id)));
+                    if found(&gathered) {
+                        return gathered;
+                    }
+                    if let Some(next) = order.next() {
+                        pending.push(self.ask(next, filters.clone()));
+                    }
+                }
+                _ = &mut hedge, if !hedged => {
+                    hedged = true;
+                    if let Some(next) = order.next() {
+                        log::debug!("hedging the request to {}", self.url(next));
+                        pending.push(self.ask(next, filters.clone()));
+                    }
+                }
+            }
+        }
+        gathered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use crate::index::upstream::{rank, RelayStats};
+
+    #[test]
+    fn test_rank() {
+        let relays = ["wss://slow", "wss://fast", "wss://new", "wss://missing"]
+            .iter()
+            .map(|url| url.to_string())
+            .collect::<Vec<_>>();
+        let mut stats = HashMap::new();
+        let mut record = |url: &str, ms: u64, hit: bool| {
+            stats
+                .entry(url.to_string())
+                .or_insert_with(RelayStats::default)
+                .record(Duration::from_millis(ms), hit);
+        };
+        for _ in 0..3 {
+            record("wss://slow", 900, true);
+            record("wss://fast", 100, true);
+            // answers fast, but without the events
+            record("wss://missing", 50, false);
+        }
+        assert_eq!(
+            rank(&relays, &stats)
+                .iter()
+                .map(|i| relays[*i].as_str())
+                .collect::<Vec<_>>(),
+            vec!["wss://new", "wss://fast", "wss://missing", "wss://slow"]
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use nostr_sdk::Event;
+
+/// URLs stored per document; `url_count` counts them all
+const MAX_URLS: usize = 100;
+
+/// Links of the content of the event, indexed to filter by linked domain and to spot
+/// link-heavy spam.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Links {
+    /// in order of appearance, without duplicates
+    pub urls: Vec<String>,
+    /// hosts of `urls` and their parent domains, e.g. `gist.github.com` and `github.com`
+    pub domains: Vec<String>,
+    pub count: usize,
+}
+
+/// `http` and `https` URLs in `content`, without the punctuation that usually follows them.
+pub fn extract_urls(content: &str) -> Vec<String> {
+    let mut urls = vec![];
+    for word in content.split(|c: char| c.is_whitespace() || "<>\"'`".contains(c)) {
+        // ASCII lowercasing keeps the byte offsets
+        let start = match word.to_ascii_lowercase().find("http") {
+            Some(start) => start,
+            None => continue,
+        };
+        let url = &word[start..];
+        let lowercase = url.to_ascii_lowercase();
+        if !lowercase.starts_with("http://") && !lowercase.starts_with("https://") {
+            continue;
+        }
+        let mut url = url.trim_end_matches(['.', ',', ';', ':', '!', '?', ']', '}']);
+        // a closing parenthesis belongs to the URL only if it opens one
+        while url.ends_with(')') && url.matches('(').count() < url.matches(')').count() {
+            url = &url[..url.len() - 1];
+        }
+        if url_host(url).is_some() {
+            urls.push(url.to_string());
+        }
+    }
+    urls
+}
+
+/// Lowercase host of an `http` or `https` URL, without `www.` and the port.
+pub fn url_host(url: &str) -> Option<String> {
+    let (_, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?;
+    let host = host.split(':').next()?.trim_end_matches('.').to_lowercase();
+    let host = host.strip_prefix("www.").unwrap_or(&host);
+    if !host.contains('.')
+        || host.starts_with('.')
+        || host.contains("..")
+        || !host
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '.' || c == '-')
+    {
+        return None;
+    }
+    Some(host.to_string())
+}
+
+/// The host and its parent domains with at least two labels.
+fn domains_of(host: &str) -> Vec<String> {
+    let labels = host.split('.').collect::<Vec<_>>();
+    (0..labels.len().saturating_sub(1))
+        .map(|i| labels[i..].join("."))
+        .collect()
+}
+
+pub fn extract_links(event: &Event) -> Links {
+    let all = extract_urls(&event.content);
+    let mut links = Links {
+        count: all.len(),
+        ..Default::default()
+    };
+    for url in all {
+        if links.urls.len() >= MAX_URLS {
+            break;
+        }
+        if links.urls.contains(&url) {
+            continue;
+        }
+        if let Some(host) = url_host(&url) {
+            for domain in domains_of(&host) {
+                if !links.domains.contains(&domain) {
+                    links.domains.push(domain);
+                }
+            }
+        }
+        links.urls.push(url);
+    }
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind};
+
+    use crate::index::urls::{extract_links, extract_urls, url_host};
+
+    #[test]
+    fn test_extract_urls() {
+        assert_eq!(
+            extract_urls(
+                "see https://github.com/fiatjaf/searchnos, (and http://example.com/a_(b)). nostr:npub1x"
+            ),
+            vec![
+                "https://github.com/fiatjaf/searchnos",
+                "http://example.com/a_(b)"
+            ]
+        );
+        assert_eq!(
+            extract_urls("<a href=\"HTTPS://Example.com/x\">link</a>"),
+            vec!["HTTPS://Example.com/x"]
+        );
+        assert!(extract_urls("httpd is a web server, https:// is not a link").is_empty());
+    }
+
+    #[test]
+    fn test_url_host() {
+        assert_eq!(
+            url_host("https://www.GitHub.com:443/x?y#z"),
+            Some("github.com".to_string())
+        );
+        assert_eq!(
+            url_host("https://user@gist.github.com"),
+            Some("gist.github.com".to_string())
+        );
+        assert_eq!(url_host("https://localhost/x"), None);
+        assert_eq!(url_host("https://a..b/x"), None);
+    }
+
+    #[test]
+    fn test_extract_links() {
+        let event = EventBuilder::new(
+            Kind::TextNote,
+            "https://gist.github.com/a https://github.com/b https://gist.github.com/a",
+            &[],
+        )
+        .to_event(&Keys::generate())
+        .unwrap();
+        let links = extract_links(&event);
+        assert_eq!(
+            links.urls,
+            vec!["https://gist.github.com/a", "https://github.com/b"]
+        );
+        assert_eq!(links.domains, vec!["gist.github.com", "github.com"]);
+        assert_eq!(links.count, 3);
+    }
+}
@@ -0,0 +1,756 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::async_trait;
+use bech32::FromBase32;
+use elasticsearch::http::request::JsonBody;
+use elasticsearch::indices::IndicesPutMappingParts;
+use elasticsearch::{BulkParts, Elasticsearch, MgetParts, SearchParts};
+use nostr_sdk::Event;
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+use crate::index::batch::{failed_items, requeue, spawn_flusher, Flush};
+use crate::index::indexes::create_side_index;
+
+const ZAP_RECEIPT_KIND: u64 = 9735;
+/// events looked up for `min_zaps:`, most zapped first
+const MAX_ZAPPED_IDS: usize = 10_000;
+/// zapped events whose totals are recomputed per search
+const TOTALS_BATCH: usize = 1000;
+/// how long the `nostrPubkey` of an LNURL server is trusted before it is fetched again
+const NOSTR_PUBKEY_TTL: Duration = Duration::from_secs(60 * 60);
+
+pub fn is_zap_receipt(event: &Event) -> bool {
+    event.kind.as_u64() == ZAP_RECEIPT_KIND
+}
+
+/// Sats zapped and zap receipts by event id.
+fn zaps_index(index_alias_name: &str) -> String {
+    format!("searchnos-zaps-{}", index_alias_name)
+}
+
+/// Receipts counted by id, with the event they zap and their sats, so that receipts received
+/// from several relays count once and the totals can be recomputed from them.
+fn zap_receipts_index(index_alias_name: &str) -> String {
+    format!("searchnos-zap-receipts-{}", index_alias_name)
+}
+
+fn tag_value<'a>(tags: &'a [Vec<String>], name: &str) -> Option<&'a str> {
+    tags.iter()
+        .find(|tag| tag.first().map(|s| s.as_str()) == Some(name))
+        .and_then(|tag| tag.get(1))
+        .map(|s| s.as_str())
+}
+
+/// Millisats of the amount of a BOLT 11 invoice, e.g. `lnbc2500u1...`.
+fn bolt11_msats(invoice: &str) -> Option<u64> {
+    let invoice = invoice.trim().to_lowercase();
+    let invoice = invoice.strip_prefix("lightning:").unwrap_or(&invoice);
+    let (hrp, _) = invoice.rsplit_once('1')?;
+    let amount = ["lnbcrt", "lnbc", "lntbs", "lntb"]
+        .iter()
+        .find_map(|prefix| hrp.strip_prefix(prefix))?;
+    let (digits, msats_per_unit) = match amount.chars().last()? {
+        'm' => (&amount[..amount.len() - 1], 100_000_000),
+        'u' => (&amount[..amount.len() - 1], 100_000),
+        'n' => (&amount[..amount.len() - 1], 100),
+        // tenths of a millisat
+        'p' => return Some(amount[..amount.len() - 1].parse::<u64>().ok()? / 10),
+        _ => (amount, 100_000_000_000),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(msats_per_unit)
+}
+
+/// Zap of an event by a NIP-57 zap receipt.
+#[derive(Debug, Clone, PartialEq)]
+struct Zap {
+    /// zapped event
+    target: String,
+    /// of the paid invoice
+    sats: u64,
+    /// pubkey signing the receipt, which must be that of the LNURL server of the recipient
+    signer: String,
+    recipient: String,
+    /// bech32 LNURL of the recipient given by the zap request
+    lnurl: Option<String>,
+}
+
+/// Zap of a receipt, with the sats of its invoice; receipts whose invoice is not for the
+/// `amount` of the zap request in `description` are ignored.
+fn zapped(event: &Event) -> Option<Zap> {
+    let tags = event
+        .tags
+        .iter()
+        .map(|tag| tag.as_vec())
+        .collect::<Vec<_>>();
+    let target = tag_value(&tags, "e").filter(|id| id.len() == 64)?;
+    let recipient = tag_value(&tags, "p").filter(|pubkey| pubkey.len() == 64)?;
+    let msats = tag_value(&tags, "bolt11").and_then(bolt11_msats)?;
+    let request_tags = tag_value(&tags, "description")
+        .and_then(|description| serde_json::from_str::<Value>(description).ok())
+        .and_then(|request| {
+            serde_json::from_value::<Vec<Vec<String>>>(request["tags"].clone()).ok()
+        })
+        .unwrap_or_default();
+    if let Some(amount) = tag_value(&request_tags, "amount") {
+        if amount.parse::<u64>().ok() != Some(msats) {
+            return None;
+        }
+    }
+    Some(Zap {
+        target: target.to_lowercase(),
+        sats: msats / 1000,
+        signer: event.pubkey.to_string(),
+        recipient: recipient.to_lowercase(),
+        lnurl: tag_value(&request_tags, "lnurl").map(|lnurl| lnurl.to_string()),
+    })
+}
+
+/// URL of the LNURL-pay endpoint of a recipient: the bech32 `lnurl` of the zap request, or
+/// else that of the `lud16` lightning address of the profile.
+fn lnurl_pay_url(lnurl: Option<&str>, lud16: Option<&str>) -> Option<String> {
+    if let Some(lnurl) = lnurl {
+        let (hrp, data, _) = bech32::decode(lnurl).ok()?;
+        if hrp != "lnurl" {
+            return None;
+        }
+        return String::from_utf8(Vec::<u8>::from_base32(&data).ok()?).ok();
+    }
+    let (name, domain) = lud16?.trim().split_once('@')?;
+    if name.is_empty() || domain.is_empty() || domain.contains(['/', '?', '#', '@']) {
+        return None;
+    }
+    Some(format!("https://{}/.well-known/lnurlp/{}", domain, name))
+}
+
+/// `nostrPubkey` of an LNURL-pay response of a server signing zap receipts.
+fn nostr_pubkey(body: &Value) -> Option<String> {
+    if body["allowsNostr"].as_bool() != Some(true) {
+        return None;
+    }
+    body["nostrPubkey"]
+        .as_str()
+        .map(|pubkey| pubkey.to_lowercase())
+}
+
+/// Zap receipts accumulated in memory and periodically added to the zap totals.
+#[derive(Debug, Default)]
+pub struct ZapCounter {
+    /// receipt id -> zap
+    pending: Mutex<HashMap<String, Zap>>,
+    http_client: reqwest::Client,
+    /// `nostrPubkey` of the LNURL-pay endpoints by URL, with when they were fetched
+    nostr_pubkeys: Mutex<HashMap<String, (Option<String>, Instant)>>,
+}
+
+impl ZapCounter {
+    pub fn record(&self, event: &Event) {
+        if let Some(zap) = zapped(event) {
+            self.pending.lock().unwrap().insert(event.id.to_hex(), zap);
+        }
+    }
+
+    fn take(&self) -> HashMap<String, Zap> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+
+    /// Puts back receipts that failed to be counted, to be retried at the next flush.
+    fn requeue(&self, receipts: HashMap<String, Zap>) {
+        // receipts recorded again since are the same
+        requeue(&self.pending, receipts, |_, _| {});
+    }
+
+    /// `nostrPubkey` of the LNURL-pay endpoint of `url`, fetched at most once per
+    /// `NOSTR_PUBKEY_TTL`; `None` if the endpoint does not sign zap receipts or fails.
+    async fn nostr_pubkey(&self, url: &str) -> Option<String> {
+        if let Some((pubkey, fetched_at)) = self.nostr_pubkeys.lock().unwrap().get(url) {
+            if fetched_at.elapsed() < NOSTR_PUBKEY_TTL {
+                return pubkey.clone();
+            }
+        }
+        let res = self
+            .http_client
+            .get(url)
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await;
+        let pubkey = match res {
+            Ok(res) if res.status().is_success() => res
+                .json::<Value>()
+                .await
+                .ok()
+                .and_then(|body| nostr_pubkey(&body)),
+            Ok(res) => {
+                log::debug!("{} answered {}", url, res.status());
+                None
+            }
+            Err(e) => {
+                log::debug!("failed to fetch {}: {}", url, e);
+                None
+            }
+        };
+        self.nostr_pubkeys
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), (pubkey.clone(), Instant::now()));
+        pubkey
+    }
+}
+
+pub async fn create_zaps_indices(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+) -> anyhow::Result<()> {
+    create_side_index(
+        es_client,
+        &zaps_index(index_alias_name),
+        json!({
+            "dynamic": false,
+            "properties": {
+                "sats": { "type": "long" },
+                "zaps": { "type": "long" }
+            }
+        }),
+    )
+    .await?;
+    create_side_index(
+        es_client,
+        &zap_receipts_index(index_alias_name),
+        json!({
+            "dynamic": false,
+            "properties": {
+                "target": { "type": "keyword" },
+                "sats": { "type": "long" }
+            }
+        }),
+    )
+    .await?;
+    // receipts indices created before the totals were recomputed have no fields
+    let res = es_client
+        .indices()
+        .put_mapping(IndicesPutMappingParts::Index(&[&zap_receipts_index(
+            index_alias_name,
+        )]))
+        .body(json!({
+            "properties": {
+                "target": { "type": "keyword" },
+                "sats": { "type": "long" }
+            }
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to update the mapping of the zap receipts: {}",
+            res.status_code()
+        ));
+    }
+    Ok(())
+}
+
+/// Records the receipts, skipping those recorded already; returns the events they zap, and the
+/// receipts that failed to be recorded.
+async fn record_receipts(
+    state: &AppState,
+    receipts: &HashMap<String, Zap>,
+) -> anyhow::Result<(HashSet<String>, Vec<String>)> {
+    let receipts_index = zap_receipts_index(&state.index_alias_name);
+    let ids = receipts.keys().cloned().collect::<Vec<_>>();
+    let mut body: Vec<JsonBody<Value>> = vec![];
+    for id in &ids {
+        let zap = &receipts[id];
+        body.push(json!({ "create": { "_index": receipts_index, "_id": id } }).into());
+        body.push(json!({ "target": zap.target, "sats": zap.sats }).into());
+    }
+    let res = state
+        .es_client
+        .bulk(BulkParts::None)
+        .body(body)
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to record zap receipts: {}",
+            res.status_code()
+        ));
+    }
+    let res = res.json::<Value>().await?;
+    // receipts already recorded fail with a conflict, and may not have been totaled
+    let failed = failed_items(&res, &[409])
+        .into_iter()
+        .map(|(i, error)| {
+            log::warn!("failed to record zap receipt {}: {}", ids[i], error);
+            ids[i].clone()
+        })
+        .collect::<Vec<_>>();
+    let targets = ids
+        .iter()
+        .filter(|id| !failed.contains(id))
+        .map(|id| receipts[id].target.clone())
+        .collect();
+    Ok((targets, failed))
+}
+
+/// Sums the sats and the number of the recorded receipts of each of `targets`.
+async fn receipt_totals(
+    state: &AppState,
+    targets: &[String],
+) -> anyhow::Result<HashMap<String, (u64, u64)>> {
+    let receipts_index = zap_receipts_index(&state.index_alias_name);
+    let res = state
+        .es_client
+        .search(SearchParts::Index(&[receipts_index.as_str()]))
+        .body(json!({
+            "size": 0,
+            "query": { "terms": { "target": targets } },
+            "aggs": {
+                "targets": {
+                    "terms": { "field": "target", "size": targets.len() },
+                    "aggs": { "sats": { "sum": { "field": "sats" } } }
+                }
+            }
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to sum zap receipts: {}",
+            res.status_code()
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    Ok(parse_receipt_totals(&body))
+}
+
+fn parse_receipt_totals(body: &Value) -> HashMap<String, (u64, u64)> {
+    body["aggregations"]["targets"]["buckets"]
+        .as_array()
+        .unwrap_or(&vec![])
+        .iter()
+        .filter_map(|bucket| {
+            Some((
+                bucket["key"].as_str()?.to_string(),
+                (
+                    bucket["sats"]["value"].as_f64()? as u64,
+                    bucket["doc_count"].as_u64()?,
+                ),
+            ))
+        })
+        .collect()
+}
+
+/// Sets the totals of the zapped events to the sums of their receipts. Totals counted before
+/// the receipts kept their amounts are kept as a base the sums are added to.
+async fn update_totals(
+    state: &AppState,
+    totals: &HashMap<String, (u64, u64)>,
+) -> anyhow::Result<()> {
+    let zaps_index = zaps_index(&state.index_alias_name);
+    let mut body: Vec<JsonBody<Value>> = vec![];
+    for (target, (sats, zaps)) in totals {
+        body.push(json!({ "update": { "_index": zaps_index, "_id": target } }).into());
+        body.push(
+            json!({
+                "script": {
+                    "source": "if (ctx._source.base_sats == null) { \
+                        ctx._source.base_sats = ctx._source.sats; \
+                        ctx._source.base_zaps = ctx._source.zaps; \
+                    } \
+                    ctx._source.sats = ctx._source.base_sats + params.sats; \
+                    ctx._source.zaps = ctx._source.base_zaps + params.zaps",
+                    "params": { "sats": sats, "zaps": zaps }
+                },
+                "upsert": { "sats": sats, "zaps": zaps, "base_sats": 0, "base_zaps": 0 }
+            })
+            .into(),
+        );
+    }
+    let res = state
+        .es_client
+        .bulk(BulkParts::None)
+        .body(body)
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to update zap totals: {}",
+            res.status_code()
+        ));
+    }
+    let res = res.json::<Value>().await?;
+    let failed = failed_items(&res, &[]);
+    if !failed.is_empty() {
+        return Err(anyhow::anyhow!("failed to update zap totals: {:?}", failed));
+    }
+    Ok(())
+}
+
+/// `lud16` of the newest indexed profiles of `pubkeys`.
+async fn lightning_addresses(
+    state: &AppState,
+    pubkeys: &[String],
+) -> anyhow::Result<HashMap<String, String>> {
+    let res = state
+        .es_client
+        .search(SearchParts::Index(&[state.index_alias_name.as_str()]))
+        .size(10_000)
+        .body(json!({
+            "_source": ["event.pubkey", "event.created_at", "profile.lud16"],
+            "query": {
+                "bool": {
+                    "filter": [
+                        { "term": { "event.kind": 0 } },
+                        { "terms": { "event.pubkey": pubkeys } }
+                    ]
+                }
+            },
+            "sort": [{ "event.created_at": { "order": "asc" } }]
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to search profiles: {}",
+            res.status_code()
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    let mut addresses = HashMap::new();
+    // the newest last
+    for hit in body["hits"]["hits"].as_array().unwrap_or(&vec![]) {
+        let source = &hit["_source"];
+        if let Some(pubkey) = source["event"]["pubkey"].as_str() {
+            match source["profile"]["lud16"].as_str() {
+                Some(lud16) => addresses.insert(pubkey.to_string(), lud16.to_string()),
+                None => addresses.remove(pubkey),
+            };
+        }
+    }
+    Ok(addresses)
+}
+
+/// The receipts signed by the LNURL server of their recipient, as NIP-57 requires; the others
+/// are dropped.
+async fn verified(
+    state: &AppState,
+    counter: &ZapCounter,
+    receipts: HashMap<String, Zap>,
+) -> anyhow::Result<HashMap<String, Zap>> {
+    let without_lnurl = receipts
+        .values()
+        .filter(|zap| zap.lnurl.is_none())
+        .map(|zap| zap.recipient.clone())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let addresses = if without_lnurl.is_empty() {
+        HashMap::new()
+    } else {
+        lightning_addresses(state, &without_lnurl).await?
+    };
+    let mut verified = HashMap::new();
+    for (id, zap) in receipts {
+        let url = lnurl_pay_url(
+            zap.lnurl.as_deref(),
+            addresses.get(&zap.recipient).map(|lud16| lud16.as_str()),
+        );
+        let signer = match url {
+            Some(url) => counter.nostr_pubkey(&url).await,
+            None => None,
+        };
+        if signer.as_deref() == Some(zap.signer.as_str()) {
+            verified.insert(id, zap);
+        } else {
+            log::debug!(
+                "zap receipt {} is not signed by the LNURL server; ignored",
+                id
+            );
+        }
+    }
+    Ok(verified)
+}
+
+#[async_trait]
+impl Flush for ZapCounter {
+    /// Records the pending receipts, then recomputes the totals of the events they zap, so that
+    /// retrying a flush counts no receipt twice. Receipts are put back when either fails.
+    async fn flush(&self, state: &AppState) -> anyhow::Result<()> {
+        let pending = self.take();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let mut pending = match verified(state, self, pending.clone()).await {
+            Ok(verified) => verified,
+            Err(e) => {
+                self.requeue(pending);
+                return Err(e);
+            }
+        };
+        let (targets, failed) = match record_receipts(state, &pending).await {
+            Ok(recorded) => recorded,
+            Err(e) => {
+                self.requeue(pending);
+                return Err(e);
+            }
+        };
+        let failed = failed
+            .into_iter()
+            .filter_map(|id| pending.remove_entry(&id))
+            .collect::<HashMap<_, _>>();
+        self.requeue(failed);
+        let targets = targets.into_iter().collect::<Vec<_>>();
+        for (i, batch) in targets.chunks(TOTALS_BATCH).enumerate() {
+            let res = match receipt_totals(state, batch).await {
+                Ok(totals) => update_totals(state, &totals).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                // receipts recorded already are totaled again with the others of their events
+                let retried = targets[i * TOTALS_BATCH..].iter().collect::<HashSet<_>>();
+                self.requeue(
+                    pending
+                        .into_iter()
+                        .filter(|(_, zap)| retried.contains(&zap.target))
+                        .collect(),
+                );
+                return Err(e);
+            }
+        }
+        log::info!(
+            "counted {} zap receipt(s) for {} event(s)",
+            pending.len(),
+            targets.len()
+        );
+        Ok(())
+    }
+}
+
+pub fn spawn_zap_flusher(state: Arc<AppState>, interval: Duration) -> JoinHandle<()> {
+    spawn_flusher(state, interval, |state| {
+        state.zaps.as_ref().map(|counter| counter as &dyn Flush)
+    })
+}
+
+/// Sats zapped to `ids`; events without zaps are omitted.
+pub async fn zap_totals(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    ids: &[String],
+) -> anyhow::Result<HashMap<String, u64>> {
+    if ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let zaps_index = zaps_index(index_alias_name);
+    let res = es_client
+        .mget(MgetParts::Index(&zaps_index))
+        .body(json!({ "ids": ids }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to get zap totals: {}",
+            res.status_code()
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    Ok(body["docs"]
+        .as_array()
+        .unwrap_or(&vec![])
+        .iter()
+        .filter_map(|doc| {
+            Some((
+                doc["_id"].as_str()?.to_string(),
+                doc["_source"]["sats"].as_u64()?,
+            ))
+        })
+        .collect())
+}
+
+/// Ids of the events zapped at least `min_sats`, the most zapped first.
+pub async fn zapped_ids(
+    es_client: &Elasticsearch,
+    index_alias_name: &str,
+    min_sats: u64,
+) -> anyhow::Result<Vec<String>> {
+    let zaps_index = zaps_index(index_alias_name);
+    let res = es_client
+        .search(SearchParts::Index(&[zaps_index.as_str()]))
+        .body(json!({
+            "query": { "range": { "sats": { "gte": min_sats } } },
+            "sort": [{ "sats": { "order": "desc" } }],
+            "_source": false,
+            "size": MAX_ZAPPED_IDS
+        }))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "failed to search zapped events: {}",
+            res.status_code()
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    Ok(body["hits"]["hits"]
+        .as_array()
+        .unwrap_or(&vec![])
+        .iter()
+        .filter_map(|hit| hit["_id"].as_str().map(|id| id.to_string()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use bech32::{ToBase32, Variant};
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+    use serde_json::json;
+
+    use crate::index::zaps::{
+        bolt11_msats, is_zap_receipt, lnurl_pay_url, nostr_pubkey, parse_receipt_totals, zapped,
+        Zap, ZapCounter,
+    };
+
+    #[test]
+    fn test_bolt11_msats() {
+        assert_eq!(bolt11_msats("lnbc2500u1pvjluezpp5qqq"), Some(250_000_000));
+        assert_eq!(bolt11_msats("LNBC10N1PXYZ"), Some(1_000));
+        assert_eq!(bolt11_msats("lnbc1m1pxyz"), Some(100_000_000));
+        assert_eq!(bolt11_msats("lnbc25p1pxyz"), Some(2));
+        assert_eq!(bolt11_msats("lntb20m1pxyz"), Some(2_000_000_000));
+        // invoices without an amount
+        assert_eq!(bolt11_msats("lnbc1pvjluez"), None);
+        assert_eq!(bolt11_msats("not an invoice"), None);
+    }
+
+    #[test]
+    fn test_zapped() {
+        let keys = Keys::generate();
+        let target = "a".repeat(64);
+        let recipient = "b".repeat(64);
+        let request = |amount: &str| {
+            json!({
+                "kind": 9734,
+                "content": "",
+                "tags": [
+                    ["e", target],
+                    ["p", recipient],
+                    ["amount", amount],
+                    ["lnurl", "lnurl1dp68gurn8ghj7"]
+                ]
+            })
+        };
+        let receipt = |tags: Vec<Vec<String>>| {
+            let tags = tags
+                .into_iter()
+                .map(|t| Tag::parse(t).unwrap())
+                .collect::<Vec<_>>();
+            EventBuilder::new(Kind::from(9735), "", &tags)
+                .to_event(&keys)
+                .unwrap()
+        };
+        let event = receipt(vec![
+            vec!["e".to_string(), target.clone()],
+            vec!["p".to_string(), recipient.clone()],
+            vec!["bolt11".to_string(), "lnbc210n1pxyz".to_string()],
+            vec!["description".to_string(), request("21000").to_string()],
+        ]);
+        assert!(is_zap_receipt(&event));
+        assert_eq!(
+            zapped(&event),
+            Some(Zap {
+                target: target.clone(),
+                sats: 21,
+                signer: keys.public_key().to_string(),
+                recipient: recipient.clone(),
+                lnurl: Some("lnurl1dp68gurn8ghj7".to_string()),
+            })
+        );
+
+        // the invoice is not for the amount requested
+        let event = receipt(vec![
+            vec!["e".to_string(), target.clone()],
+            vec!["p".to_string(), recipient.clone()],
+            vec!["bolt11".to_string(), "lnbc210n1pxyz".to_string()],
+            vec!["description".to_string(), request("1000000").to_string()],
+        ]);
+        assert_eq!(zapped(&event), None);
+
+        // without a zap request, the amount of the invoice
+        let event = receipt(vec![
+            vec!["e".to_string(), target.clone()],
+            vec!["p".to_string(), recipient.clone()],
+            vec!["bolt11".to_string(), "lnbc1u1pxyz".to_string()],
+        ]);
+        assert_eq!(zapped(&event).map(|zap| zap.sats), Some(100));
+
+        let counter = ZapCounter::default();
+        counter.record(&event);
+        counter.record(&event);
+        // without an invoice
+        counter.record(&receipt(vec![
+            vec!["e".to_string(), target],
+            vec!["p".to_string(), recipient],
+        ]));
+        let taken = counter.take();
+        assert_eq!(taken.len(), 1);
+        assert!(counter.take().is_empty());
+        counter.requeue(taken);
+        assert_eq!(counter.take().len(), 1);
+    }
+
+    #[test]
+    fn test_lnurl_pay_url() {
+        let url = "https://example.com/.well-known/lnurlp/alice";
+        let lnurl = bech32::encode("lnurl", url.as_bytes().to_base32(), Variant::Bech32).unwrap();
+        assert_eq!(lnurl_pay_url(Some(&lnurl), None), Some(url.to_string()));
+        assert_eq!(
+            lnurl_pay_url(None, Some("alice@example.com")),
+            Some(url.to_string())
+        );
+        // the zap request takes precedence over the profile
+        assert_eq!(
+            lnurl_pay_url(Some(&lnurl), Some("bob@example.org")),
+            Some(url.to_string())
+        );
+        assert_eq!(lnurl_pay_url(Some("npub1xyz"), None), None);
+        assert_eq!(lnurl_pay_url(None, Some("example.com/alice")), None);
+        assert_eq!(lnurl_pay_url(None, None), None);
+    }
+
+    #[test]
+    fn test_nostr_pubkey() {
+        let pubkey = "C".repeat(64);
+        assert_eq!(
+            nostr_pubkey(&json!({ "allowsNostr": true, "nostrPubkey": pubkey })),
+            Some("c".repeat(64))
+        );
+        assert_eq!(
+            nostr_pubkey(&json!({ "allowsNostr": false, "nostrPubkey": pubkey })),
+            None
+        );
+        assert_eq!(
+            nostr_pubkey(&json!({ "callback": "https://example.com" })),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_receipt_totals() {
+        let body = json!({
+            "aggregations": {
+                "targets": {
+                    "buckets": [
+                        { "key": "a", "doc_count": 2, "sats": { "value": 121.0 } },
+                        { "key": "b", "doc_count": 1, "sats": { "value": 21.0 } }
+                    ]
+                }
+            }
+        });
+        let totals = parse_receipt_totals(&body);
+        assert_eq!(totals["a"], (121, 2));
+        assert_eq!(totals["b"], (21, 1));
+        assert!(parse_receipt_totals(&json!({})).is_empty());
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+
+/// Human-readable names of kinds, used in metrics and command outputs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KindLabels {
+    labels: BTreeMap<u32, String>,
+}
+
+impl Default for KindLabels {
+    /// The kinds forwarded by the indexer.
+    fn default() -> Self {
+        let labels = [
+            (0, "profile"),
+            (1, "note"),
+            (5, "deletion"),
+            (40, "channel"),
+            (41, "channel_metadata"),
+            (42, "channel_message"),
+            (43, "channel_hide_message"),
+            (44, "channel_mute_user"),
+            (30023, "article"),
+        ]
+        .into_iter()
+        .map(|(kind, label)| (kind, label.to_string()))
+        .collect();
+        KindLabels { labels }
+    }
+}
+
+impl KindLabels {
+    /// Adds or overrides the labels of a comma-separated list like `30023:article,9802:highlight`.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut kind_labels = KindLabels::default();
+        for item in s
+            .split(',')
+            .map(|item| item.trim())
+            .filter(|item| !item.is_empty())
+        {
+            let (kind, label) = item
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("invalid kind label: {}", item))?;
+            let kind = kind.trim().parse::<u32>()?;
+            let label = label.trim();
+            // used as a Prometheus label value and a search operand
+            if label.is_empty()
+                || !label
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+            {
+                return Err(anyhow::anyhow!("invalid label: {}", label));
+            }
+            if label.chars().all(|c| c.is_ascii_digit()) {
+                return Err(anyhow::anyhow!("label must not be a number: {}", label));
+            }
+            kind_labels.labels.retain(|_, l| l != label);
+            kind_labels.labels.insert(kind, label.to_string());
+        }
+        Ok(kind_labels)
+    }
+
+    pub fn label(&self, kind: u32) -> Option<&str> {
+        self.labels.get(&kind).map(|label| label.as_str())
+    }
+
+    /// The kind of a label, or of a number.
+    pub fn kind(&self, label_or_number: &str) -> Option<u32> {
+        self.labels
+            .iter()
+            .find(|(_, label)| label.as_str() == label_or_number)
+            .map(|(kind, _)| *kind)
+            .or_else(|| label_or_number.parse::<u32>().ok())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &str)> {
+        self.labels
+            .iter()
+            .map(|(kind, label)| (*kind, label.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kind_label::KindLabels;
+
+    #[test]
+    fn test_parse() {
+        let labels = KindLabels::parse("9802:highlight, 30023:longform").unwrap();
+        assert_eq!(labels.label(9802), Some("highlight"));
+        assert_eq!(labels.label(30023), Some("longform"));
+        assert_eq!(labels.label(1), Some("note"));
+        assert_eq!(labels.label(7), None);
+        assert_eq!(labels.kind("highlight"), Some(9802));
+        assert_eq!(labels.kind("7"), Some(7));
+        assert_eq!(labels.kind("unknown"), None);
+
+        // a label moved to another kind
+        let labels = KindLabels::parse("30024:article").unwrap();
+        assert_eq!(labels.label(30023), None);
+        assert_eq!(labels.kind("article"), Some(30024));
+
+        assert!(KindLabels::parse("article").is_err());
+        assert!(KindLabels::parse("x:article").is_err());
+        assert!(KindLabels::parse("1:Note").is_err());
+        assert!(KindLabels::parse("1:123").is_err());
+    }
+}
@@ -1,3 +1,17 @@
+pub mod alerts;
 pub mod app_state;
+pub mod backend;
+pub mod breaker;
+pub mod config;
+pub mod connection_pool;
+pub mod export;
+pub mod health;
 pub mod index;
+pub mod kind_label;
+pub mod link;
+pub mod metrics;
+pub mod namespace;
+pub mod openapi;
+pub mod probe;
 pub mod search;
+pub mod tenant;
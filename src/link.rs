@@ -0,0 +1,82 @@
+use nostr_sdk::prelude::{Nip19Event, ParameterizedReplaceableEvent, ToBech32};
+use nostr_sdk::Event;
+
+use crate::index::handlers::{extract_identifier_tag, is_parameterized_replaceable_event};
+
+/// How events are referenced in human-facing outputs.
+#[derive(Debug, Clone, Default)]
+pub struct LinkConfig {
+    /// prefix of web links, e.g. `https://njump.me/`; bare NIP-19 strings when `None`
+    pub base_url: Option<String>,
+}
+
+/// NIP-19 `naddr` of parameterized replaceable events, which stays valid across versions,
+/// or `nevent` of others, with the relays the event was seen on as hints.
+pub fn nip19(event: &Event, relays: &[String]) -> String {
+    let encoded = if is_parameterized_replaceable_event(event) {
+        ParameterizedReplaceableEvent {
+            kind: event.kind,
+            pubkey: event.pubkey,
+            identifier: extract_identifier_tag(&event.tags),
+            relays: relays.to_vec(),
+        }
+        .to_bech32()
+    } else {
+        Nip19Event::new(event.id, relays.to_vec()).to_bech32()
+    };
+    encoded.unwrap_or_default()
+}
+
+impl LinkConfig {
+    pub fn link(&self, event: &Event, relays: &[String]) -> String {
+        let nip19 = nip19(event, relays);
+        match &self.base_url {
+            Some(base_url) => format!("{}{}", base_url, nip19),
+            None => nip19,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::prelude::{FromBech32, Nip19Event, ParameterizedReplaceableEvent};
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+
+    use crate::link::{nip19, LinkConfig};
+
+    #[test]
+    fn test_nevent() {
+        let event = EventBuilder::new(Kind::TextNote, "hello", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let relay = "wss://r.example.com".to_string();
+        let nevent = nip19(&event, &[relay.clone()]);
+        assert!(nevent.starts_with("nevent1"));
+        let decoded = Nip19Event::from_bech32(&nevent).unwrap();
+        assert_eq!(decoded.event_id, event.id);
+        assert_eq!(decoded.relays, vec![relay]);
+    }
+
+    #[test]
+    fn test_naddr() {
+        let keys = Keys::generate();
+        let event = EventBuilder::new(
+            Kind::LongFormTextNote,
+            "hello",
+            &[Tag::Identifier("article".to_string())],
+        )
+        .to_event(&keys)
+        .unwrap();
+        let link = LinkConfig {
+            base_url: Some("https://njump.me/".to_string()),
+        }
+        .link(&event, &[]);
+        let naddr = link.strip_prefix("https://njump.me/").unwrap();
+        assert!(naddr.starts_with("naddr1"));
+        let decoded = ParameterizedReplaceableEvent::from_bech32(naddr).unwrap();
+        assert_eq!(decoded.identifier, "article");
+        assert_eq!(decoded.kind, Kind::LongFormTextNote);
+        assert_eq!(decoded.pubkey, keys.public_key());
+        assert!(decoded.relays.is_empty());
+    }
+}
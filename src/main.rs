@@ -6,9 +6,10 @@ use axum::{
     extract::ws::{Message, WebSocket, WebSocketUpgrade},
     http::StatusCode,
     response::IntoResponse,
-    routing::get,
-    Router,
+    routing::{get, MethodRouter},
+    Json, Router,
 };
+use clap::{Parser, Subcommand};
 use elasticsearch::{
     http::{
         transport::{SingleNodeConnectionPool, TransportBuilder},
@@ -18,21 +19,80 @@ use elasticsearch::{
 };
 use env_logger;
 use futures::{sink::SinkExt, stream::StreamExt};
-use nostr_sdk::prelude::{RelayInformationDocument, RelayMessage};
+use nostr_sdk::prelude::{Keys, RelayInformationDocument, RelayMessage};
+use searchnos::alerts::{spawn_alert_checker, AlertChannel, AlertConfig};
 use searchnos::app_state::AppState;
-use searchnos::index::handlers::handle_event;
-use searchnos::index::purge::spawn_index_purger;
+use searchnos::backend::{spawn_backend_committer, BackendConfig};
+use searchnos::config::{parse_days, Config};
+use searchnos::connection_pool::HealthAwareConnectionPool;
+use searchnos::export::spawn_word_frequency_exporter;
+use searchnos::health::HealthReport;
+use searchnos::index::ack::AckLog;
+use searchnos::index::bootstrap::{bootstrap, BootstrapConfig};
+use searchnos::index::completion::{
+    create_completions_index, spawn_completion_flusher, CompletionCollector,
+};
+use searchnos::index::dead_letter::{create_dead_letter_index, replay_dead_letters};
+use searchnos::index::deletion::create_deletions_index;
+use searchnos::index::embedding::{
+    backfill_embeddings, count_documents, spawn_embedding_worker, Embedder,
+};
+use searchnos::index::engagement::{spawn_engagement_flusher, EngagementCounter};
+use searchnos::index::followers::create_follower_indices;
+use searchnos::index::force_merge::spawn_force_merger;
+use searchnos::index::handlers::{handle_event, handle_published_event};
+use searchnos::index::journal::{create_journal_index, spawn_journal_flusher, Journal};
+use searchnos::index::language::{backfill_languages, other_languages_prefix};
+use searchnos::index::lock::{create_lock_index, wait_for_lock};
+use searchnos::index::nip05::{create_nip05_index, spawn_nip05_verifier, Nip05Verifier};
+use searchnos::index::opt_out::OptOut;
+use searchnos::index::profile::backfill_profiles;
+use searchnos::index::protected::backfill_protected;
+use searchnos::index::purge::{purge_indices, spawn_backend_purger, spawn_index_purger};
+use searchnos::index::queue::{spawn_index_workers, IndexQueue};
+use searchnos::index::reactions::{
+    create_reactions_index, spawn_reaction_flusher, ReactionCounter,
+};
+use searchnos::index::reconcile::{
+    create_ingest_index, format_reports, reconcile, spawn_ingest_flusher, IngestCounter,
+};
+use searchnos::index::refresh::refresh_profiles;
+use searchnos::index::reindex::reindex;
+use searchnos::index::replacements::{spawn_replacement_flusher, ReplacementQueue};
 use searchnos::index::schema::{create_index_template, put_pipeline};
+use searchnos::index::seen_on::SeenOn;
+use searchnos::index::sinks::{spawn_sink_flusher, Sinks};
+use searchnos::index::stats::{format_stats, index_stats};
+use searchnos::index::sync::spawn_sync;
+use searchnos::index::text::Extractors;
+use searchnos::index::tiering::spawn_tiering;
+use searchnos::index::ttl::IndexTtl;
+use searchnos::index::zaps::{create_zaps_indices, spawn_zap_flusher, ZapCounter};
+use searchnos::metrics::{self, Metrics};
+use searchnos::namespace::Namespace;
+use searchnos::openapi;
+use searchnos::probe::spawn_probe;
+use searchnos::search::analytics::QueryAnalytics;
+use searchnos::search::api;
+use searchnos::search::cache::QueryCache;
 use searchnos::search::handlers::{handle_close, handle_req};
+use searchnos::search::trending::{spawn_trending, Trending};
+use searchnos::tenant::TenantRouter;
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::time::Duration;
 use std::{env, net::SocketAddr, sync::Arc};
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, Mutex};
 use tokio::task::JoinHandle;
+#[cfg(feature = "demo-ui")]
+use utoipa::Path;
+use utoipa::{IntoParams, OpenApi};
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
 struct Parameter {
+    /// API key for the administrative connection that accepts EVENT messages
     api_key: Option<String>,
 }
 
@@ -55,8 +115,15 @@ async fn handle_text_message(
         Some("EVENT") => {
             if is_admin_connection {
                 handle_event(state, addr, &msg).await?
+            } else if state.accept_published_events {
+                let ok = handle_published_event(state, addr, &msg).await?;
+                sender
+                    .lock()
+                    .await
+                    .send(Message::Text(ok.to_string()))
+                    .await?;
             } else {
-                return Err(anyhow::anyhow!("EVENT message not allowed")); // TODO support NIP-20
+                return Err(anyhow::anyhow!("EVENT message not allowed"));
             }
         }
         _ => {
@@ -141,6 +208,9 @@ async fn websocket(
         addr,
         is_admin_connection
     );
+    if is_admin_connection {
+        Metrics::inc(&state.metrics.admin_connections);
+    }
     let (sender, mut receiver) = socket.split();
     let sender = Arc::new(Mutex::new(sender));
     let join_handles = Arc::new(Mutex::new(HashMap::<String, JoinHandle<()>>::new()));
@@ -161,6 +231,9 @@ async fn websocket(
                                 let res = send_notice(sender, &format!("Error: {}", e)).await;
                                 if let Err(e) = res {
                                     log::error!("{} error sending notice: {}", addr, e);
+                                    if is_admin_connection {
+                                        Metrics::dec(&state.metrics.admin_connections);
+                                    }
                                     return;
                                 }
                             }
@@ -176,6 +249,9 @@ async fn websocket(
                                 join_handle.abort();
                             }
                             pinger_handle.abort();
+                            if is_admin_connection {
+                                Metrics::dec(&state.metrics.admin_connections);
+                            }
                             log::info!("{} disconnected", addr);
                             return;
                         }
@@ -186,12 +262,198 @@ async fn websocket(
     });
 }
 
+/// This document
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    responses((
+        status = 200,
+        description = "OpenAPI document",
+        content_type = "application/json"
+    ))
+)]
+async fn openapi_json(Extension(state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(axum::http::header::CONTENT_TYPE, "application/json")],
+        state.openapi.clone(),
+    )
+}
+
+/// Metrics in the Prometheus text format
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses((
+        status = 200,
+        description = "Counters and gauges",
+        body = String,
+        content_type = "text/plain"
+    ))
+)]
+async fn metrics_text(Extension(state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4",
+        )],
+        metrics::render(&state),
+    )
+}
+
+/// Liveness probe
+///
+/// Fails when events are queued but nothing has been indexed for 5 minutes.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    responses(
+        (status = 200, description = "The instance is healthy", body = HealthReport),
+        (status = 503, description = "The instance is wedged"),
+    )
+)]
+async fn healthz(Extension(state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    let report = HealthReport::collect(&state).await;
+    let status = if report.is_live() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+/// Readiness probe
+///
+/// Fails when Elasticsearch or the backend is unreachable.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = 200, description = "The instance can serve searches", body = HealthReport),
+        (status = 503, description = "Elasticsearch or the backend is unreachable"),
+    )
+)]
+async fn readyz(Extension(state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    let report = HealthReport::collect(&state).await;
+    let status = if report.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+/// Liveness probe
+#[utoipa::path(
+    get,
+    path = "/ping",
+    responses((status = 200, description = "The server is running"))
+)]
 async fn ping() -> impl IntoResponse {
     println!("PING");
 
     StatusCode::OK
 }
 
+/// Search page for validating deployments
+#[cfg(feature = "demo-ui")]
+#[utoipa::path(
+    get,
+    path = "/demo",
+    responses((status = 200, description = "HTML page", content_type = "text/html"))
+)]
+async fn demo() -> axum::response::Html<&'static str> {
+    axum::response::Html(include_str!("demo.html"))
+}
+
+/// OpenAPI document of the routes, served at `/openapi.json`.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        websocket_handler,
+        ping,
+        healthz,
+        readyz,
+        metrics_text,
+        openapi_json,
+        api::search,
+        api::trending,
+        api::complete,
+        api::query_report,
+        api::journal,
+        api::stats,
+    ),
+    components(schemas(
+        HealthReport,
+        openapi::RelayInformation,
+        openapi::Event,
+        openapi::SearchResults,
+        openapi::FacetCount,
+        openapi::ReactionCounts,
+        openapi::Completions,
+        openapi::HashtagCompletion,
+        openapi::ProfileCompletion,
+        openapi::TrendingNotes,
+        openapi::QueryReport,
+        openapi::JournalEntries,
+        openapi::IndexStats,
+    ))
+)]
+struct ApiDoc;
+
+fn api_doc(version: &str) -> utoipa::openapi::OpenApi {
+    #[allow(unused_mut)]
+    let mut doc = ApiDoc::openapi();
+    #[cfg(feature = "demo-ui")]
+    doc.paths.paths.insert(
+        __path_demo::path().to_string(),
+        __path_demo::path_item(None),
+    );
+    openapi::with_info(doc, version)
+}
+
+/// Routes served at the root and under each namespace.
+fn namespace_routes() -> Vec<(&'static str, MethodRouter)> {
+    #[allow(unused_mut)]
+    let mut routes = vec![
+        ("/healthz", get(healthz)),
+        ("/readyz", get(readyz)),
+        ("/openapi.json", get(openapi_json)),
+        ("/metrics", get(metrics_text)),
+        ("/search", get(api::search)),
+        ("/trending", get(api::trending)),
+        ("/complete", get(api::complete)),
+        ("/admin/queries", get(api::query_report)),
+        ("/admin/journal", get(api::journal)),
+        ("/admin/stats", get(api::stats)),
+        ("/", get(websocket_handler)),
+    ];
+    #[cfg(feature = "demo-ui")]
+    routes.push(("/demo", get(demo)));
+    routes
+}
+
+fn namespace_router(state: Arc<AppState>) -> Router {
+    namespace_routes()
+        .into_iter()
+        .fold(Router::new(), |router, (path, route)| {
+            router.route(path, route)
+        })
+        .layer(Extension(state))
+}
+
+fn app(default_state: Arc<AppState>, namespaces: &[(Namespace, Arc<AppState>)]) -> Router {
+    let mut app = Router::new()
+        .route("/ping", get(ping))
+        .merge(namespace_router(default_state));
+    for (namespace, state) in namespaces {
+        app = app.nest(
+            &format!("/{}", namespace.name),
+            namespace_router(state.clone()),
+        );
+    }
+    app
+}
+
 struct ReturnRelayInfoExtractor {}
 
 #[async_trait]
@@ -236,6 +498,24 @@ where
     }
 }
 
+/// Relay endpoint
+///
+/// Upgrades to the Nostr WebSocket protocol. With `Accept: application/nostr+json`, returns
+/// the NIP-11 relay information document instead.
+#[utoipa::path(
+    get,
+    path = "/",
+    params(Parameter),
+    responses(
+        (status = 101, description = "Switching to the WebSocket protocol"),
+        (
+            status = 200,
+            description = "NIP-11 relay information document",
+            body = openapi::RelayInformation,
+            content_type = "application/nostr+json"
+        ),
+    )
+)]
 async fn websocket_handler(
     _: ReturnRelayInfoExtractor,
     params: Query<Parameter>,
@@ -252,121 +532,902 @@ async fn websocket_handler(
     ws.on_upgrade(move |socket| websocket(socket, state, addr, is_admin_connection))
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env::set_var("RUST_LOG", "info");
-    env_logger::init();
-
-    let version = format!(
-        "v{}-{}",
-        env!("CARGO_PKG_VERSION"),
-        env!("GIT_HASH").chars().take(7).collect::<String>()
-    );
-    let pkg_name = env!("CARGO_PKG_NAME").to_string();
-
-    log::info!("{} {}", pkg_name, version);
-
-    // env vars
-    let es_url = env::var("ES_URL").expect("ES_URL is not set; set it to the URL of elasticsearch");
-    let port = env::var("PORT").expect("PORT is not set; set it to the port number to listen on");
-    let port = port
-        .parse::<u16>()
-        .expect("PORT is not a valid port number");
-    let api_key = env::var("API_KEY").expect(
-        "API_KEY is not set; specify the key for the administrative connection to searchnos",
-    );
-    if api_key.is_empty() {
-        panic!("API_KEY must not be empty");
-    }
-    let max_subscriptions = if let Ok(max_subscriptions) = env::var("MAX_SUBSCRIPTIONS") {
-        max_subscriptions
-            .parse::<usize>()
-            .expect("MAX_SUBSCRIPTIONS is not a valid number")
-    } else {
-        8
-    };
-    let max_filters = if let Ok(max_filters) = env::var("MAX_FILTERS") {
-        max_filters
-            .parse::<usize>()
-            .expect("MAX_FILTERS is not a valid number")
-    } else {
-        8
+/// Connects to the Elasticsearch nodes of `ES_URL`; without it, with another backend, the
+/// client is never used.
+fn connect_elasticsearch(config: &Config) -> anyhow::Result<Elasticsearch> {
+    let es_url = match &config.es_url {
+        Some(es_url) => es_url,
+        None => return Ok(Elasticsearch::default()),
     };
-    let ping_interval = if let Ok(ping_interval) = env::var("PING_INTERVAL") {
-        ping_interval
-            .parse::<u64>()
-            .expect("PING_INTERVAL is not a valid number")
+    log::info!("connecting to elasticsearch");
+    let es_urls = es_url
+        .split(',')
+        .map(|url| Url::parse(url.trim()).expect("invalid elasticsearch url"))
+        .collect::<Vec<_>>();
+    let es_transport = if es_urls.len() == 1 {
+        let conn_pool = SingleNodeConnectionPool::new(es_urls[0].clone());
+        TransportBuilder::new(conn_pool).disable_proxy().build()?
     } else {
-        55
+        log::info!("using {} elasticsearch nodes", es_urls.len());
+        let conn_pool = HealthAwareConnectionPool::new(es_urls);
+        conn_pool.spawn_health_checker(Duration::from_secs(10));
+        TransportBuilder::new(conn_pool).disable_proxy().build()?
     };
-    let ping_interval = Duration::from_secs(ping_interval);
-    let index_ttl_days: Option<u64> = env::var("INDEX_TTL_DAYS").ok().map(|index_ttl_days| {
-        index_ttl_days
-            .parse::<u64>()
-            .expect("INDEX_TTL_DAYS is not a valid number")
-    });
-    let index_allow_future_days = 1;
-
-    log::info!("connecting to elasticsearch");
+    Ok(Elasticsearch::new(es_transport))
+}
 
-    // prepare elasticsearch client
-    let es_url = Url::parse(&es_url).expect("invalid elasticsearch url");
-    let conn_pool = SingleNodeConnectionPool::new(es_url);
-    let es_transport = TransportBuilder::new(conn_pool).disable_proxy().build()?;
-    let es_client = Elasticsearch::new(es_transport);
-    let index_name_prefix = "nostr";
-    let index_alias_name = "nostr";
-    let pipeline_name = "nostr-pipeline";
-    let index_template_name = "nostr";
-    put_pipeline(&es_client, pipeline_name).await?;
-    create_index_template(
-        &es_client,
-        index_template_name,
-        pipeline_name,
-        index_name_prefix,
-        index_template_name,
+/// Prepares the indices of every namespace, one replica at a time; background tasks are only
+/// spawned for `serve`.
+async fn build_states(
+    config: &Config,
+    es_client: &Elasticsearch,
+    version: &str,
+    serve: bool,
+) -> anyhow::Result<Vec<Arc<AppState>>> {
+    // backends prepare their own stores
+    if config.backend != BackendConfig::Elasticsearch {
+        return prepare_states(config, es_client, version, serve).await;
+    }
+    // replicas starting together would race to put the pipeline and templates; those waiting
+    // find them up to date afterwards
+    create_lock_index(es_client).await?;
+    let lock = wait_for_lock(
+        es_client,
+        "setup",
+        Duration::from_secs(5 * 60),
+        Duration::from_secs(1),
     )
     .await?;
-    log::info!("elasticsearch index ready");
+    let res = prepare_states(config, es_client, version, serve).await;
+    if let Err(e) = lock.release(es_client).await {
+        log::warn!("failed to release the setup lock: {}", e);
+    }
+    res
+}
+
+async fn prepare_states(
+    config: &Config,
+    es_client: &Elasticsearch,
+    version: &str,
+    serve: bool,
+) -> anyhow::Result<Vec<Arc<AppState>>> {
+    let use_elasticsearch = config.backend == BackendConfig::Elasticsearch;
+    let pipeline_name = if !use_elasticsearch {
+        None
+    } else if config.ingest_pipeline {
+        let pipeline_name = "nostr-pipeline";
+        put_pipeline(
+            es_client,
+            pipeline_name,
+            &config.analyzer_config,
+            config.language_allowlist.as_ref(),
+            config.force_bootstrap,
+        )
+        .await?;
+        Some(pipeline_name)
+    } else {
+        log::info!("ingest pipeline disabled; languages are not detected");
+        None
+    };
+
+    // shared by the namespaces
+    let extractors = Extractors::default();
 
     let mut relay_info = RelayInformationDocument::new();
     relay_info.name = Some("searchnos".to_string()); // TODO make this configurable
     relay_info.description = Some("searchnos relay".to_string()); // TODO make this configurable
-    relay_info.supported_nips = Some(vec![1, 9, 11, 12, 16, 22, 28, 33, 50]);
-    relay_info.software = Some(pkg_name);
-    relay_info.version = Some(version);
+    let mut supported_nips = vec![1, 9, 11, 12, 16, 22, 28, 33, 50];
+    if config.accept_published_events {
+        supported_nips.push(20);
+        supported_nips.sort();
+    }
+    relay_info.supported_nips = Some(supported_nips);
+    relay_info.software = Some(env!("CARGO_PKG_NAME").to_string());
+    relay_info.version = Some(version.to_string());
     let relay_info = serde_json::to_string(&relay_info).unwrap();
+    let openapi = serde_json::to_string(&api_doc(version)).unwrap();
 
-    let app_state = Arc::new(AppState {
-        relay_info,
-        es_client,
-        index_name_prefix: index_name_prefix.to_string(),
-        index_alias_name: index_alias_name.to_string(),
-        max_subscriptions, // TODO include this in relay info
-        max_filters,       // TODO include this in relay info
-        api_key,
-        ping_interval,
-        index_ttl_days,
-        index_allow_future_days,
+    let mut alert_channels = vec![];
+    if serve && !config.alert_thresholds.is_empty() {
+        if let Some(url) = &config.alert_webhook_url {
+            alert_channels.push(AlertChannel::Webhook(url.clone()));
+        }
+        if let Some((pubkey, relays)) = &config.alert_dm {
+            alert_channels.push(
+                AlertChannel::direct_message(pubkey, relays)
+                    .await
+                    .expect("ALERT_DM_PUBKEY is not a valid hex public key"),
+            );
+        }
+        if alert_channels.is_empty() {
+            log::warn!("no alert channel is configured; alerts are only logged");
+        }
+    }
+
+    let tenants = Arc::new(TenantRouter::new(config.tenant_rules.clone()));
+    let mut app_states = vec![];
+    for index_name_prefix in config.index_name_prefixes() {
+        let index_alias_name = index_name_prefix.clone();
+        let index_template_name = index_name_prefix.clone();
+        let embedding_config = match config.embedding_config.clone() {
+            Some(mut config) => {
+                let num_documents = count_documents(es_client, &index_alias_name).await?;
+                config.knn = config.fits_memory_budget(num_documents);
+                if config.knn {
+                    log::info!(
+                        "[{}] kNN index enabled; estimated memory for {} vector(s): {} MB",
+                        index_alias_name,
+                        num_documents,
+                        config.estimate_memory_bytes(num_documents) / 1024 / 1024
+                    );
+                } else {
+                    log::warn!(
+                        "[{}] kNN index disabled; {} vector(s) would take {} MB, exceeding the memory budget",
+                        index_alias_name,
+                        num_documents,
+                        config.estimate_memory_bytes(num_documents) / 1024 / 1024
+                    );
+                }
+                Some(config)
+            }
+            None => None,
+        };
+        let embedder = embedding_config
+            .clone()
+            .map(|config| Embedder::new(es_client.clone(), config));
+        if use_elasticsearch {
+            create_index_template(
+                es_client,
+                &index_template_name,
+                pipeline_name,
+                &index_name_prefix,
+                &index_alias_name,
+                &config.analyzer_config,
+                &config.tag_prefixes,
+                embedding_config.as_ref(),
+                config.index_template_overrides.as_ref(),
+                config.force_bootstrap,
+            )
+            .await?;
+            if config
+                .language_allowlist
+                .as_ref()
+                .map_or(false, |a| a.route)
+            {
+                // already processed by the pipeline of the indices they were routed from
+                let other_prefix = other_languages_prefix(&index_name_prefix);
+                create_index_template(
+                    es_client,
+                    &other_languages_prefix(&index_template_name),
+                    None,
+                    &other_prefix,
+                    &other_prefix,
+                    &config.analyzer_config,
+                    &config.tag_prefixes,
+                    embedding_config.as_ref(),
+                    config.index_template_overrides.as_ref(),
+                    config.force_bootstrap,
+                )
+                .await?;
+            }
+            log::info!("[{}] elasticsearch index ready", index_alias_name);
+
+            if config.follower_boost.is_some() {
+                create_follower_indices(es_client, &index_alias_name).await?;
+            }
+            if config.zap_boost.is_some() {
+                create_zaps_indices(es_client, &index_alias_name).await?;
+            }
+            if config.reaction_boost.is_some() {
+                create_reactions_index(es_client, &index_alias_name).await?;
+            }
+            if config.completion {
+                create_completions_index(es_client, &index_alias_name).await?;
+            }
+
+            create_deletions_index(es_client, &index_alias_name).await?;
+            create_dead_letter_index(es_client, &index_alias_name).await?;
+            create_ingest_index(es_client, &index_alias_name).await?;
+            if config.journal_retention_days.is_some() {
+                create_journal_index(es_client, &index_alias_name).await?;
+            }
+            if config.nip05_recheck_hours.is_some() {
+                create_nip05_index(es_client, &index_alias_name).await?;
+            }
+        }
+        let opt_out = OptOut::new(config.opt_out_tags.clone(), &index_alias_name);
+        opt_out.load(es_client).await?;
+        let backend = config.backend.connect(
+            &index_alias_name,
+            &config.kind_labels,
+            &extractors,
+            config.exclude_content_warnings,
+        );
+        if let Some(backend) = &backend {
+            backend.prepare().await?;
+        }
+        let sinks = (!config.sinks.is_empty())
+            .then(|| {
+                Sinks::new(
+                    &config.sinks,
+                    &index_alias_name,
+                    config.sink_spool_dir.as_deref(),
+                )
+            })
+            .transpose()?;
+
+        let (index_queue, index_queue_receivers) =
+            IndexQueue::new(config.index_queue_size, config.index_concurrency);
+        // only the relay receives events to acknowledge
+        let (ack_log, unacknowledged) = match &config.ack_log_dir {
+            Some(dir) if serve => {
+                let (ack_log, events) = AckLog::open(dir, &index_alias_name)?;
+                (Some(ack_log), events)
+            }
+            _ => (None, vec![]),
+        };
+
+        let app_state = Arc::new(AppState {
+            relay_info: relay_info.clone(),
+            openapi: openapi.clone(),
+            es_client: es_client.clone(),
+            index_name_prefix,
+            index_alias_name,
+            backend,
+            sinks,
+            max_subscriptions: config.max_subscriptions, // TODO include this in relay info
+            max_filters: config.max_filters,             // TODO include this in relay info
+            max_filter_complexity: config.max_filter_complexity,
+            max_results_per_req: config.max_results_per_req,
+            req_limiter: config.req_limiter.clone(),
+            accept_published_events: config.accept_published_events,
+            event_limiter: config.event_limiter.clone(),
+            api_key: config.api_key.clone(),
+            ping_interval: config.ping_interval,
+            index_ttl: config.index_ttl.clone(),
+            tiering_policy: config.tiering_policy.clone(),
+            force_merge: config.force_merge.clone(),
+            index_allow_future_days: config.index_allow_future_days,
+            created_at_rounding: config.created_at_rounding,
+            ingest_pipeline: config.ingest_pipeline,
+            event_limits: config.event_limits.clone(),
+            ingest_chain: config.ingest_chain.clone(),
+            sampling: config.sampling.clone(),
+            index_content_warnings: config.index_content_warnings,
+            exclude_content_warnings: config.exclude_content_warnings,
+            index_protected_events: config.index_protected_events,
+            replaceable_index: config.replaceable_index,
+            profiles_index: config.profiles_index,
+            completions: if config.completion {
+                Some(CompletionCollector::default())
+            } else {
+                None
+            },
+            opt_out,
+            analyzer_config: config.analyzer_config.clone(),
+            language_allowlist: config.language_allowlist.clone(),
+            tag_prefixes: config.tag_prefixes.clone(),
+            embedder,
+            hybrid_search: config.hybrid_search.clone(),
+            suggest_min_hits: config.suggest_min_hits,
+            query_analytics: config.query_analytics_min_count.map(QueryAnalytics::new),
+            query_language_detection: config.query_language_detection,
+            follower_boost: config.follower_boost,
+            engagement: config
+                .ranking
+                .as_ref()
+                .and_then(|r| r.engagement_weight)
+                .map(|_| EngagementCounter::default()),
+            zap_boost: config.zap_boost,
+            zaps: config.zap_boost.map(|_| ZapCounter::default()),
+            reaction_boost: config.reaction_boost,
+            reactions: config.reaction_boost.map(|_| ReactionCounter::default()),
+            trending: config.trending_hours.map(Trending::new),
+            ranking: config.ranking.clone(),
+            query_cache: config
+                .query_cache_size
+                .map(|size| QueryCache::new(size, Duration::from_secs(config.query_cache_ttl))),
+            query_limiter: config.query_limiter.clone(),
+            es_guard: config.es_guard.clone(),
+            extractors: extractors.clone(),
+            new_events: broadcast::channel(1024).0,
+            probe_keys: config.probe_interval.map(|_| Keys::generate()),
+            index_queue,
+            ack_log,
+            ingest_counter: IngestCounter::default(),
+            journal: config.journal_retention_days.map(Journal::new),
+            nip05_verifier: config
+                .nip05_recheck_hours
+                .map(|hours| Nip05Verifier::new(Duration::from_secs(hours * 60 * 60))),
+            replacements: config
+                .replacement_batch_interval
+                .map(|_| ReplacementQueue::default()),
+            tenants: tenants.clone(),
+            links: config.links.clone(),
+            seen_on: SeenOn::default(),
+            kind_labels: config.kind_labels.clone(),
+            metrics: Metrics::default(),
+        });
+
+        if !serve {
+            app_states.push(app_state);
+            continue;
+        }
+
+        spawn_index_workers(app_state.clone(), index_queue_receivers);
+        if !unacknowledged.is_empty() {
+            log::info!(
+                "[{}] indexing {} unacknowledged event(s) again",
+                app_state.index_alias_name,
+                unacknowledged.len()
+            );
+            let app_state = app_state.clone();
+            tokio::spawn(async move {
+                for event in unacknowledged {
+                    if let Err(e) = app_state.index_queue.push(&app_state.metrics, event).await {
+                        log::error!("failed to queue an unacknowledged event: {}", e);
+                    }
+                }
+            });
+        }
+
+        if app_state.embedder.is_some() {
+            spawn_embedding_worker(app_state.clone()).await;
+        } else {
+            log::info!("embedding is disabled");
+        }
+
+        if !config.alert_thresholds.is_empty() {
+            spawn_alert_checker(
+                app_state.clone(),
+                AlertConfig {
+                    thresholds: config.alert_thresholds.clone(),
+                    interval: Duration::from_secs(config.alert_interval),
+                    channels: alert_channels.clone(),
+                },
+            );
+        }
+
+        if let Some(word_frequency_config) = &config.word_frequency_config {
+            let mut word_frequency_config = word_frequency_config.clone();
+            if !config.namespaces.is_empty() {
+                word_frequency_config.dir =
+                    word_frequency_config.dir.join(&app_state.index_alias_name);
+            }
+            spawn_word_frequency_exporter(app_state.clone(), word_frequency_config);
+        }
+
+        let flush_interval = Duration::from_secs(config.flush_interval);
+        if app_state.engagement.is_some() {
+            spawn_engagement_flusher(app_state.clone(), flush_interval);
+        }
+        if app_state.zaps.is_some() {
+            spawn_zap_flusher(app_state.clone(), flush_interval);
+        }
+        if app_state.reactions.is_some() {
+            spawn_reaction_flusher(app_state.clone(), flush_interval);
+        }
+        if app_state.sinks.is_some() {
+            spawn_sink_flusher(app_state.clone(), flush_interval);
+        }
+        if app_state.completions.is_some() {
+            spawn_completion_flusher(app_state.clone(), flush_interval);
+        }
+        if app_state.trending.is_some() {
+            spawn_trending(
+                app_state.clone(),
+                Duration::from_secs(config.trending_interval),
+            );
+        }
+        if use_elasticsearch {
+            spawn_ingest_flusher(app_state.clone(), Duration::from_secs(10));
+        }
+        if app_state.journal.is_some() {
+            spawn_journal_flusher(app_state.clone(), Duration::from_secs(10));
+        }
+        if app_state.nip05_verifier.is_some() {
+            spawn_nip05_verifier(app_state.clone());
+        }
+        if let Some(interval) = config.replacement_batch_interval {
+            spawn_replacement_flusher(app_state.clone(), Duration::from_secs(interval));
+        }
+
+        if let (Some(probe_interval), Some(keys)) = (config.probe_interval, &app_state.probe_keys) {
+            spawn_probe(
+                app_state.clone(),
+                keys.clone(),
+                probe_interval,
+                Duration::from_secs(config.probe_timeout),
+            );
+        }
+
+        if let Some(sync_config) = &config.sync {
+            spawn_sync(app_state.clone(), sync_config.clone());
+        }
+
+        if app_state.tiering_policy.is_some() {
+            spawn_tiering(app_state.clone());
+        }
+        if app_state.force_merge.is_some() {
+            spawn_force_merger(app_state.clone());
+        }
+        if let Some(interval) = config.backend.commit_interval() {
+            spawn_backend_committer(app_state.clone(), interval);
+        }
+        if !use_elasticsearch {
+            if config.index_ttl.is_enabled() {
+                spawn_backend_purger(app_state.clone());
+            } else {
+                log::info!("index ttl is disabled");
+            }
+        } else if config.index_ttl.is_enabled() || app_state.journal.is_some() {
+            spawn_index_purger(app_state.clone()).await;
+        } else {
+            log::info!("index ttl is disabled");
+        }
+
+        app_states.push(app_state);
+    }
+    tenants.register(&app_states);
+    Ok(app_states)
+}
+
+async fn serve(config: &Config, app_states: Vec<Arc<AppState>>) -> anyhow::Result<()> {
+    let namespace_states = config
+        .namespaces
+        .iter()
+        .cloned()
+        .zip(app_states.iter().cloned())
+        .collect::<Vec<_>>();
+
+    // PORT serves the first namespace at `/` and every namespace at `/<name>`;
+    // a namespace with its own port is served at `/` there
+    let mut listeners = vec![(config.port, app_states[0].clone())];
+    for (namespace, app_state) in &namespace_states {
+        if let Some(port) = namespace.port {
+            listeners.push((port, app_state.clone()));
+        }
+    }
+    let servers = listeners.into_iter().map(|(port, default_state)| {
+        let app = app(default_state, &namespace_states);
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        log::info!("listening on {}", addr);
+        axum::Server::bind(&addr).serve(app.into_make_service_with_connect_info::<SocketAddr>())
     });
+    for res in futures::future::join_all(servers).await {
+        res?;
+    }
+    Ok(())
+}
 
-    if index_ttl_days.is_some() {
-        spawn_index_purger(app_state.clone()).await;
+/// Reports whether Elasticsearch, or the backend, is reachable with the configuration; invalid
+/// settings have already panicked while reading it.
+async fn check_config(config: &Config, es_client: &Elasticsearch) -> anyhow::Result<()> {
+    let backends = config
+        .index_name_prefixes()
+        .into_iter()
+        .filter_map(|prefix| {
+            config.backend.connect(
+                &prefix,
+                &config.kind_labels,
+                &Extractors::default(),
+                config.exclude_content_warnings,
+            )
+        });
+    let backends = backends.collect::<Vec<_>>();
+    if backends.is_empty() {
+        let res = es_client.ping().send().await?;
+        if !res.status_code().is_success() {
+            return Err(anyhow::anyhow!(
+                "elasticsearch is not reachable: {}",
+                res.status_code()
+            ));
+        }
+        println!("elasticsearch: reachable");
+    }
+    for backend in backends {
+        if !backend.is_reachable().await {
+            return Err(anyhow::anyhow!("{} is not reachable", backend.name()));
+        }
+        println!("{}: reachable", backend.name());
+    }
+    println!("indices: {}", config.index_name_prefixes().join(", "));
+    println!(
+        "embeddings: {}",
+        if config.embedding_config.is_some() {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    match config.index_ttl.default_days {
+        Some(days) => println!("index ttl: {} day(s)", days),
+        None => println!("index ttl: disabled"),
+    }
+    let mut kind_ttls = config.index_ttl.kinds.iter().collect::<Vec<_>>();
+    kind_ttls.sort();
+    for (kind, days) in kind_ttls {
+        match days {
+            Some(days) => println!("  kind {:>5}  {} day(s)", kind, days),
+            None => println!("  kind {:>5}  forever", kind),
+        }
+    }
+    if config.sampling.is_enabled() {
+        println!("sampling: default rate {}", config.sampling.default_rate);
+        for (relay, rate) in &config.sampling.rates {
+            println!("  {}  {}", relay, rate);
+        }
     } else {
-        log::info!("index ttl is disabled");
+        println!("sampling: disabled");
     }
+    println!("kind labels:");
+    for (kind, label) in config.kind_labels.iter() {
+        println!("  {:>5}  {}", kind, label);
+    }
+    println!("configuration is valid");
+    Ok(())
+}
 
-    let app = Router::new()
-        .route("/ping", get(ping))
-        .route("/", get(websocket_handler))
-        .layer(Extension(app_state));
+/// Fails the commands that work on the Elasticsearch indices when another backend is used.
+fn require_elasticsearch(config: &Config, command: &str) -> anyhow::Result<()> {
+    if config.backend != BackendConfig::Elasticsearch {
+        return Err(anyhow::anyhow!(
+            "{} requires BACKEND=elasticsearch",
+            command
+        ));
+    }
+    Ok(())
+}
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    log::info!("listening on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .await
-        .unwrap();
+#[derive(Parser)]
+#[command(version, about = "NIP-50 search relay backed by Elasticsearch")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Put the pipeline and index templates even if they are up to date, like `FORCE_BOOTSTRAP=true`
+    #[arg(long, global = true)]
+    force_bootstrap: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve the relay (default)
+    Serve,
+    /// Embed the documents indexed without an embedding, then exit
+    Backfill,
+    /// Detect the language of the documents indexed without one, then exit
+    BackfillLanguages {
+        /// documents whose language is detected at once
+        #[arg(long, default_value_t = 500)]
+        batch_size: usize,
+        /// documents whose language is detected less probably are left without one
+        #[arg(long, default_value_t = 0.0)]
+        min_probability: f64,
+    },
+    /// Flag the NIP-70 protected events indexed before they were flagged, or delete them
+    /// unless `INDEX_PROTECTED_EVENTS=true`, then exit
+    BackfillProtected,
+    /// Copy the profiles indexed into the dated indices into the profiles index, then exit
+    BackfillProfiles,
+    /// Delete the event indices older than the given age, e.g. `7d`
+    Purge {
+        #[arg(long, value_parser = parse_days)]
+        older_than: u64,
+    },
+    /// Copy the event indices matching a pattern into indices of another version
+    Reindex {
+        /// index pattern, e.g. `nostr-2023.03.*`
+        #[arg(long)]
+        from: String,
+        /// version of the destination indices, e.g. `v2` for `nostr-v2-2023.03.20`
+        #[arg(long)]
+        to: String,
+        /// number of indices migrated at once
+        #[arg(long, default_value_t = 2)]
+        concurrency: usize,
+    },
+    /// Fetch and index the profiles of recently active authors from relays
+    RefreshProfiles {
+        /// authors of events created within this age, e.g. `30d`
+        #[arg(long, value_parser = parse_days, default_value = "30d")]
+        since: u64,
+        /// comma-separated relay URLs to fetch the profiles from
+        #[arg(long, value_delimiter = ',', required = true)]
+        relays: Vec<String>,
+    },
+    /// Seed the index with the results of broad searches on other NIP-50 relays
+    Bootstrap {
+        /// comma-separated URLs of the NIP-50 relays, asked fastest first
+        #[arg(long, value_delimiter = ',', required = true)]
+        relays: Vec<String>,
+        /// comma-separated search strings
+        #[arg(long, value_delimiter = ',', required = true)]
+        queries: Vec<String>,
+        /// age of the oldest events fetched, e.g. `30d`
+        #[arg(long, value_parser = parse_days, default_value = "30d")]
+        since: u64,
+        /// length of the time windows each query is split into, in hours
+        #[arg(long, default_value_t = 24)]
+        window_hours: u64,
+        /// `limit` of each request
+        #[arg(long, default_value_t = 500)]
+        limit: usize,
+        /// wait between requests in milliseconds
+        #[arg(long, default_value_t = 1000)]
+        interval_ms: u64,
+    },
+    /// Validate the configuration and the connection to Elasticsearch
+    CheckConfig,
+    /// Index the events of the dead-letter index again
+    ReplayDeadLetters,
+    /// Print the documents per day, kind and language, the index sizes and the ingestion rate
+    Stats,
+    /// Compare the number of documents per day with the ingest counters and archives
+    Reconcile {
+        /// directory of archived events, one `<index>.jsonl` file per day
+        #[arg(long)]
+        archive_dir: Option<PathBuf>,
+        /// relative difference above which a day is flagged
+        #[arg(long, default_value_t = 0.05)]
+        tolerance: f64,
+        /// index the archived events of flagged days again
+        #[arg(long, requires = "archive_dir")]
+        rebuild: bool,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if env::var("RUST_LOG").is_err() {
+        env::set_var("RUST_LOG", "info");
+    }
+    env_logger::init();
+
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Serve);
+
+    let version = format!(
+        "v{}-{}",
+        env!("CARGO_PKG_VERSION"),
+        env!("GIT_HASH").chars().take(7).collect::<String>()
+    );
+    log::info!("{} {}", env!("CARGO_PKG_NAME"), version);
+
+    let mut config = Config::from_env();
+    config.force_bootstrap |= cli.force_bootstrap;
+
+    let es_client = connect_elasticsearch(&config)?;
+
+    match command {
+        Command::Serve => {
+            let app_states = build_states(&config, &es_client, &version, true).await?;
+            serve(&config, app_states).await?;
+        }
+        Command::Backfill => {
+            require_elasticsearch(&config, "backfill")?;
+            for app_state in build_states(&config, &es_client, &version, false).await? {
+                let embedder = app_state.embedder.as_ref().ok_or_else(|| {
+                    anyhow::anyhow!("backfill requires EMBEDDING_MODEL_ID or EMBEDDING_URL")
+                })?;
+                let n = backfill_embeddings(embedder, &app_state.index_alias_name).await?;
+                log::info!(
+                    "[{}] embedded {} document(s)",
+                    app_state.index_alias_name,
+                    n
+                );
+            }
+        }
+        Command::BackfillLanguages {
+            batch_size,
+            min_probability,
+        } => {
+            require_elasticsearch(&config, "backfill-languages")?;
+            for app_state in build_states(&config, &es_client, &version, false).await? {
+                let (scanned, updated) = backfill_languages(
+                    &app_state.es_client,
+                    &app_state.index_alias_name,
+                    &app_state.analyzer_config,
+                    batch_size,
+                    min_probability,
+                )
+                .await?;
+                log::info!(
+                    "[{}] detected the language of {} of {} document(s)",
+                    app_state.index_alias_name,
+                    updated,
+                    scanned
+                );
+            }
+        }
+        Command::BackfillProtected => {
+            require_elasticsearch(&config, "backfill-protected")?;
+            for app_state in build_states(&config, &es_client, &version, false).await? {
+                let n = backfill_protected(
+                    &app_state.es_client,
+                    &app_state.index_alias_name,
+                    app_state.index_protected_events,
+                )
+                .await?;
+                log::info!(
+                    "[{}] {} {} protected event(s)",
+                    app_state.index_alias_name,
+                    if app_state.index_protected_events {
+                        "flagged"
+                    } else {
+                        "deleted"
+                    },
+                    n
+                );
+            }
+        }
+        Command::BackfillProfiles => {
+            require_elasticsearch(&config, "backfill-profiles")?;
+            if !config.profiles_index {
+                return Err(anyhow::anyhow!(
+                    "backfill-profiles requires PROFILES_INDEX=true"
+                ));
+            }
+            for app_state in build_states(&config, &es_client, &version, false).await? {
+                let n = backfill_profiles(
+                    &app_state.es_client,
+                    &app_state.index_alias_name,
+                    &app_state.index_name_prefix,
+                )
+                .await?;
+                log::info!(
+                    "[{}] backfilled {} profile(s)",
+                    app_state.index_alias_name,
+                    n
+                );
+            }
+        }
+        Command::Purge { older_than } => {
+            let ttl = IndexTtl {
+                default_days: Some(older_than),
+                ..Default::default()
+            };
+            for index_name_prefix in config.index_name_prefixes() {
+                if let Some(backend) = config.backend.connect(
+                    &index_name_prefix,
+                    &config.kind_labels,
+                    &Extractors::default(),
+                    config.exclude_content_warnings,
+                ) {
+                    backend.prepare().await?;
+                    backend.purge(&ttl).await?;
+                    continue;
+                }
+                purge_indices(
+                    &es_client,
+                    &index_name_prefix,
+                    &ttl,
+                    config.index_allow_future_days,
+                )
+                .await?;
+            }
+        }
+        Command::Reindex {
+            from,
+            to,
+            concurrency,
+        } => {
+            require_elasticsearch(&config, "reindex")?;
+            reindex(&es_client, &from, &to, concurrency).await?;
+        }
+        Command::RefreshProfiles { since, relays } => {
+            require_elasticsearch(&config, "refresh-profiles")?;
+            for app_state in build_states(&config, &es_client, &version, false).await? {
+                let n = refresh_profiles(app_state.clone(), &relays, since).await?;
+                log::info!(
+                    "[{}] refreshed {} profile(s)",
+                    app_state.index_alias_name,
+                    n
+                );
+            }
+        }
+        Command::Bootstrap {
+            relays,
+            queries,
+            since,
+            window_hours,
+            limit,
+            interval_ms,
+        } => {
+            require_elasticsearch(&config, "bootstrap")?;
+            let bootstrap_config = BootstrapConfig {
+                relays,
+                queries,
+                since_days: since,
+                window_hours,
+                limit,
+                interval: Duration::from_millis(interval_ms),
+            };
+            for app_state in build_states(&config, &es_client, &version, false).await? {
+                let (fetched, indexed) = bootstrap(app_state.clone(), &bootstrap_config).await?;
+                log::info!(
+                    "[{}] bootstrapped; {} event(s) fetched, {} indexed",
+                    app_state.index_alias_name,
+                    fetched,
+                    indexed
+                );
+            }
+        }
+        Command::CheckConfig => {
+            check_config(&config, &es_client).await?;
+        }
+        Command::ReplayDeadLetters => {
+            require_elasticsearch(&config, "replay-dead-letters")?;
+            for app_state in build_states(&config, &es_client, &version, false).await? {
+                let (replayed, failed) = replay_dead_letters(app_state.clone()).await?;
+                log::info!(
+                    "[{}] replayed {} dead letter(s); {} failed again",
+                    app_state.index_alias_name,
+                    replayed,
+                    failed
+                );
+            }
+        }
+        Command::Stats => {
+            require_elasticsearch(&config, "stats")?;
+            for app_state in build_states(&config, &es_client, &version, false).await? {
+                let stats = index_stats(
+                    &app_state.es_client,
+                    &app_state.index_name_prefix,
+                    &app_state.index_alias_name,
+                    &app_state.kind_labels,
+                )
+                .await?;
+                println!("[{}]", app_state.index_alias_name);
+                println!("{}", format_stats(&stats));
+            }
+        }
+        Command::Reconcile {
+            archive_dir,
+            tolerance,
+            rebuild,
+        } => {
+            require_elasticsearch(&config, "reconcile")?;
+            let mut mismatches = 0;
+            for app_state in build_states(&config, &es_client, &version, false).await? {
+                let reports = reconcile(
+                    app_state.clone(),
+                    archive_dir.as_deref(),
+                    tolerance,
+                    rebuild,
+                )
+                .await?;
+                mismatches += reports.iter().filter(|report| report.mismatch).count();
+                println!("[{}]", app_state.index_alias_name);
+                println!("{}", format_reports(&reports));
+            }
+            if mismatches > 0 {
+                log::warn!("{} day(s) mismatch", mismatches);
+            }
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use crate::{api_doc, namespace_routes};
+
+    #[test]
+    fn test_api_doc_matches_routes() {
+        let mut routes = namespace_routes()
+            .into_iter()
+            .map(|(path, _)| path.to_string())
+            .collect::<BTreeSet<_>>();
+        routes.insert("/ping".to_string());
+        let paths = api_doc("test")
+            .paths
+            .paths
+            .keys()
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        assert_eq!(routes, paths);
+    }
+}
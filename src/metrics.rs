@@ -0,0 +1,261 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::app_state::AppState;
+use crate::index::indexes::SkipReason;
+
+/// Counters exported at `/metrics` in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub events_received: AtomicU64,
+    pub events_indexed: AtomicU64,
+    /// events indexed, by kind label or `other`
+    pub events_indexed_by_kind: Mutex<BTreeMap<String, u64>>,
+    pub index_errors: AtomicU64,
+    /// events skipped before indexing, by reason
+    pub skipped_too_old: AtomicU64,
+    pub skipped_too_future: AtomicU64,
+    pub skipped_bad_timestamp: AtomicU64,
+    pub skipped_bad_signature: AtomicU64,
+    pub skipped_too_large: AtomicU64,
+    pub skipped_too_many_tags: AtomicU64,
+    pub skipped_sampled: AtomicU64,
+    pub skipped_stale: AtomicU64,
+    /// events recorded in the dead-letter index
+    pub dead_letters: AtomicU64,
+    /// events missing from the index queued by the sync with upstream relays
+    pub events_synced: AtomicU64,
+    /// times an event had to wait for room in the index queue
+    pub index_queue_full: AtomicU64,
+    /// searches rejected by the query limiter
+    pub queries_shed: AtomicU64,
+    /// pre-EOSE searches answered from the query cache, and those that were not
+    pub query_cache_hits: AtomicU64,
+    pub query_cache_misses: AtomicU64,
+    /// REQs rejected by the per-IP rate limit
+    pub reqs_rate_limited: AtomicU64,
+    /// time from queueing the last probe event until it was searchable
+    pub probe_latency_ms: AtomicU64,
+    pub probe_failures: AtomicU64,
+    /// connected indexers
+    pub admin_connections: AtomicU64,
+    /// unix time of the last indexed event
+    pub last_indexed_at: AtomicU64,
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(counter: &AtomicU64) {
+        counter.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(gauge: &AtomicU64, value: u64) {
+        gauge.store(value, Ordering::Relaxed);
+    }
+
+    pub fn skipped(&self, reason: SkipReason) {
+        Metrics::inc(match reason {
+            SkipReason::TooOld => &self.skipped_too_old,
+            SkipReason::TooFuture => &self.skipped_too_future,
+            SkipReason::BadTimestamp => &self.skipped_bad_timestamp,
+            SkipReason::BadSignature => &self.skipped_bad_signature,
+            SkipReason::TooLarge => &self.skipped_too_large,
+            SkipReason::TooManyTags => &self.skipped_too_many_tags,
+            SkipReason::Sampled => &self.skipped_sampled,
+            SkipReason::Stale => &self.skipped_stale,
+        });
+    }
+
+    pub fn indexed(&self, kind_label: Option<&str>) {
+        Metrics::inc(&self.events_indexed);
+        let mut by_kind = self.events_indexed_by_kind.lock().unwrap();
+        *by_kind
+            .entry(kind_label.unwrap_or("other").to_string())
+            .or_default() += 1;
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+pub fn render(state: &AppState) -> String {
+    let metrics = &state.metrics;
+    let mut out = String::new();
+    write_metric(
+        &mut out,
+        "searchnos_events_received_total",
+        "counter",
+        "Events received for indexing",
+        metrics.events_received.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "searchnos_events_indexed_total",
+        "counter",
+        "Events written to Elasticsearch",
+        metrics.events_indexed.load(Ordering::Relaxed),
+    );
+    let name = "searchnos_events_indexed_by_kind_total";
+    let _ = writeln!(
+        out,
+        "# HELP {} Events written to Elasticsearch, by kind",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (kind, count) in metrics.events_indexed_by_kind.lock().unwrap().iter() {
+        let _ = writeln!(out, "{}{{kind=\"{}\"}} {}", name, kind, count);
+    }
+    write_metric(
+        &mut out,
+        "searchnos_index_errors_total",
+        "counter",
+        "Events that failed to be indexed",
+        metrics.index_errors.load(Ordering::Relaxed),
+    );
+    let name = "searchnos_events_skipped_total";
+    let _ = writeln!(out, "# HELP {} Events skipped before indexing", name);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (reason, counter) in [
+        (SkipReason::TooOld, &metrics.skipped_too_old),
+        (SkipReason::TooFuture, &metrics.skipped_too_future),
+        (SkipReason::BadTimestamp, &metrics.skipped_bad_timestamp),
+        (SkipReason::BadSignature, &metrics.skipped_bad_signature),
+        (SkipReason::TooLarge, &metrics.skipped_too_large),
+        (SkipReason::TooManyTags, &metrics.skipped_too_many_tags),
+        (SkipReason::Sampled, &metrics.skipped_sampled),
+        (SkipReason::Stale, &metrics.skipped_stale),
+    ] {
+        let _ = writeln!(
+            out,
+            "{}{{reason=\"{}\"}} {}",
+            name,
+            reason.as_str(),
+            counter.load(Ordering::Relaxed)
+        );
+    }
+    write_metric(
+        &mut out,
+        "searchnos_dead_letters_total",
+        "counter",
+        "Events rejected by Elasticsearch and recorded in the dead-letter index",
+        metrics.dead_letters.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "searchnos_events_synced_total",
+        "counter",
+        "Events missing from the index queued by the sync with upstream relays",
+        metrics.events_synced.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "searchnos_index_queue_full_total",
+        "counter",
+        "Times an event waited for room in the index queue",
+        metrics.index_queue_full.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "searchnos_queries_shed_total",
+        "counter",
+        "Searches rejected by the Elasticsearch query limiter",
+        metrics.queries_shed.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "searchnos_query_cache_hits_total",
+        "counter",
+        "Pre-EOSE searches answered from the query cache",
+        metrics.query_cache_hits.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "searchnos_query_cache_misses_total",
+        "counter",
+        "Pre-EOSE searches not found in the query cache",
+        metrics.query_cache_misses.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "searchnos_reqs_rate_limited_total",
+        "counter",
+        "REQs rejected by the per-IP rate limit",
+        metrics.reqs_rate_limited.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "searchnos_probe_latency_milliseconds",
+        "gauge",
+        "Time from queueing the last probe event until it was searchable",
+        metrics.probe_latency_ms.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "searchnos_probe_failures_total",
+        "counter",
+        "Probe events that were not searchable within the timeout",
+        metrics.probe_failures.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "searchnos_admin_connections",
+        "gauge",
+        "Connected indexers",
+        metrics.admin_connections.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "searchnos_last_indexed_timestamp_seconds",
+        "gauge",
+        "Unix time of the last indexed event",
+        metrics.last_indexed_at.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "searchnos_index_queue_depth",
+        "gauge",
+        "Events waiting in the index queue",
+        state.index_queue.depth() as u64,
+    );
+    if let Some(guard) = &state.es_guard {
+        write_metric(
+            &mut out,
+            "searchnos_es_breaker_state",
+            "gauge",
+            "State of the Elasticsearch circuit breaker: 0 closed, 1 open, 2 half-open",
+            guard.state().as_u64(),
+        );
+        write_metric(
+            &mut out,
+            "searchnos_es_breaker_trips_total",
+            "counter",
+            "Times the Elasticsearch circuit breaker opened",
+            guard.trips.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "searchnos_es_requests_in_flight",
+            "gauge",
+            "Index requests being sent to Elasticsearch",
+            guard.in_flight() as u64,
+        );
+    }
+    if let Some(ack_log) = &state.ack_log {
+        write_metric(
+            &mut out,
+            "searchnos_unacknowledged_events",
+            "gauge",
+            "Received events in the write-ahead log not handled yet",
+            ack_log.pending() as u64,
+        );
+    }
+    out
+}
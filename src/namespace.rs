@@ -0,0 +1,75 @@
+/// Logical network indexed into its own indices (`<base>-<name>-*`) and served at `/<name>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Namespace {
+    pub name: String,
+    /// additional port serving this namespace at `/`
+    pub port: Option<u16>,
+}
+
+impl Namespace {
+    pub fn index_prefix(&self, base: &str) -> String {
+        format!("{}-{}", base, self.name)
+    }
+}
+
+/// Parses a comma-separated list like `main,test:3001`.
+pub fn parse_namespaces(s: &str) -> anyhow::Result<Vec<Namespace>> {
+    let mut namespaces: Vec<Namespace> = vec![];
+    for item in s
+        .split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+    {
+        let (name, port) = match item.split_once(':') {
+            Some((name, port)) => (name, Some(port.parse::<u16>()?)),
+            None => (item, None),
+        };
+        // index names must be lowercase, and dates are separated by '-'
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        {
+            return Err(anyhow::anyhow!("invalid namespace name: {}", name));
+        }
+        if namespaces.iter().any(|ns| ns.name == name) {
+            return Err(anyhow::anyhow!("duplicate namespace: {}", name));
+        }
+        namespaces.push(Namespace {
+            name: name.to_string(),
+            port,
+        });
+    }
+    Ok(namespaces)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::namespace::{parse_namespaces, Namespace};
+
+    #[test]
+    fn test_parse_namespaces() {
+        assert_eq!(
+            parse_namespaces("main, test:3001").unwrap(),
+            vec![
+                Namespace {
+                    name: "main".to_string(),
+                    port: None
+                },
+                Namespace {
+                    name: "test".to_string(),
+                    port: Some(3001)
+                },
+            ]
+        );
+        assert_eq!(parse_namespaces("").unwrap(), vec![]);
+        assert!(parse_namespaces("Main").is_err());
+        assert!(parse_namespaces("a-b").is_err());
+        assert!(parse_namespaces("main:x").is_err());
+        assert!(parse_namespaces("main,main").is_err());
+        assert_eq!(
+            parse_namespaces("test").unwrap()[0].index_prefix("nostr"),
+            "nostr-test"
+        );
+    }
+}
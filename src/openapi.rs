@@ -0,0 +1,111 @@
+//! Schemas of the responses documented at `/openapi.json` that have no type of their own; the
+//! paths are derived from the handlers in `main.rs` and `search::api`.
+//!
+//! With namespaces, every path except `/ping` is also served under `/<namespace>`.
+
+use std::collections::HashMap;
+
+use utoipa::openapi::OpenApi;
+use utoipa::ToSchema;
+
+/// NIP-11 relay information document
+#[derive(ToSchema)]
+pub struct RelayInformation {
+    pub name: String,
+    pub description: String,
+    pub supported_nips: Vec<u32>,
+    pub software: String,
+    pub version: String,
+}
+
+/// Nostr event as defined in NIP-01
+#[derive(ToSchema)]
+pub struct Event {
+    pub id: String,
+    pub pubkey: String,
+    pub created_at: u64,
+    pub kind: u64,
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+    pub sig: String,
+}
+
+#[derive(ToSchema)]
+pub struct SearchResults {
+    pub events: Vec<Event>,
+    /// Token of the next page, absent on the last page
+    pub next: Option<String>,
+    /// Fragments where searches with `highlight:true` matched, by event id
+    pub highlights: HashMap<String, Vec<String>>,
+    /// Reactions to the events by event id, when reactions are counted
+    pub reactions: HashMap<String, ReactionCounts>,
+    /// Counts of the requested facets (`kinds`, `languages`, `days`, `hashtags`, `authors`),
+    /// the most frequent values first and days in order
+    pub facets: HashMap<String, Vec<FacetCount>>,
+}
+
+#[derive(ToSchema)]
+pub struct FacetCount {
+    pub key: String,
+    pub documents: u64,
+}
+
+#[derive(ToSchema)]
+pub struct ReactionCounts {
+    pub likes: u64,
+    pub dislikes: u64,
+    /// Distinct authors of the reactions, counted up to 1000
+    pub reactors: u64,
+}
+
+#[derive(ToSchema)]
+pub struct Completions {
+    pub hashtags: Vec<HashtagCompletion>,
+    pub profiles: Vec<ProfileCompletion>,
+}
+
+#[derive(ToSchema)]
+pub struct HashtagCompletion {
+    pub hashtag: String,
+    pub count: u64,
+}
+
+#[derive(ToSchema)]
+pub struct ProfileCompletion {
+    pub pubkey: String,
+    pub name: String,
+    pub nip05: String,
+}
+
+#[derive(ToSchema)]
+pub struct TrendingNotes {
+    pub hours: u64,
+    /// Unix time of the last recomputation
+    pub updated_at: Option<u64>,
+    pub events: Vec<Event>,
+}
+
+/// The most frequent search strings, those most often without results and the zero-result
+/// rates by language and kind
+#[derive(ToSchema)]
+pub struct QueryReport {}
+
+/// Operations and outcomes of an event, oldest first
+#[derive(ToSchema)]
+pub struct JournalEntries {
+    #[schema(value_type = Vec<Object>)]
+    pub entries: Vec<serde_json::Value>,
+}
+
+/// Documents per day, kind and language, index sizes and the ingestion rate
+#[derive(ToSchema)]
+pub struct IndexStats {}
+
+/// Completes the document derived from the handlers with what they do not tell.
+pub fn with_info(mut doc: OpenApi, version: &str) -> OpenApi {
+    doc.info.title = "searchnos".to_string();
+    doc.info.description =
+        Some("Nostr full-text search relay (NIP-50) backed by Elasticsearch".to_string());
+    doc.info.version = version.to_string();
+    doc
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use nostr_sdk::prelude::{EventBuilder, Keys, Kind};
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+use crate::index::deletion::delete_events;
+use crate::metrics::Metrics;
+use crate::search::filter::Filter;
+use crate::search::query::ElasticsearchQuery;
+
+/// Measures how long an event takes from the index queue until a search finds it.
+///
+/// Each probe is a text note with a random token from a key generated at startup, which is
+/// not pushed to live subscriptions nor exported, and whose document is deleted again once
+/// found. Ids of probes that timed out are kept in `missing` and deleted with the next ones,
+/// in case they are indexed late.
+async fn probe(
+    state: &AppState,
+    keys: &Keys,
+    timeout: Duration,
+    missing: &mut Vec<String>,
+) -> anyhow::Result<Duration> {
+    let token = format!(
+        "{:016x}{:016x}",
+        rand::random::<u64>(),
+        rand::random::<u64>()
+    );
+    let event = EventBuilder::new(Kind::TextNote, &token, &[]).to_event(keys)?;
+    let filter = Filter {
+        ids: None,
+        authors: Some(vec![keys.public_key().to_string()]),
+        kinds: None,
+        search: Some(token),
+        since: None,
+        until: None,
+        limit: Some(1),
+        cursor: None,
+        extra: HashMap::new(),
+        detected_language: None,
+        tag_prefixes: HashMap::new(),
+        zapped_ids: None,
+    };
+
+    let t0 = Instant::now();
+    state
+        .index_queue
+        .push(&state.metrics, event.clone())
+        .await?;
+    let found = loop {
+        let events = match &state.backend {
+            Some(backend) => backend.search(&filter, None).await?.0,
+            None => {
+                let query = ElasticsearchQuery::from_filter(
+                    filter.clone(),
+                    None,
+                    &state.analyzer_config,
+                    &state.kind_labels,
+                    state.exclude_content_warnings,
+                );
+                query
+                    .execute(&state.es_client, &state.index_alias_name, None)
+                    .await?
+                    .0
+            }
+        };
+        if events.iter().any(|e| e.id == event.id) {
+            break true;
+        }
+        if t0.elapsed() > timeout {
+            break false;
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    };
+    let latency = t0.elapsed();
+
+    // without a deletion event, which would be pushed and recorded like any other
+    missing.push(event.id.to_hex());
+    let author = keys.public_key().to_string();
+    match &state.backend {
+        Some(backend) => backend.delete(&author, missing).await?,
+        None => delete_events(&state.es_client, &state.index_alias_name, &author, missing).await?,
+    }
+
+    if found {
+        missing.clear();
+        Ok(latency)
+    } else {
+        Err(anyhow::anyhow!(
+            "probe event not searchable after {} s",
+            timeout.as_secs()
+        ))
+    }
+}
+
+pub fn spawn_probe(
+    state: Arc<AppState>,
+    keys: Keys,
+    interval: Duration,
+    timeout: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        log::info!("probing search freshness as {}", keys.public_key());
+        let mut missing = vec![];
+        loop {
+            tokio::time::sleep(interval).await;
+            match probe(&state, &keys, timeout, &mut missing).await {
+                Ok(latency) => {
+                    log::info!("probe searchable in {} ms", latency.as_millis());
+                    Metrics::set(&state.metrics.probe_latency_ms, latency.as_millis() as u64);
+                }
+                Err(e) => {
+                    log::warn!("probe failed: {}", e);
+                    Metrics::inc(&state.metrics.probe_failures);
+                }
+            }
+        }
+    })
+}
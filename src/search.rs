@@ -1,3 +1,15 @@
+pub mod analytics;
+pub mod api;
+pub mod cache;
+pub mod facets;
 pub mod filter;
 pub mod handlers;
+pub mod hybrid;
+pub mod language;
+pub mod limiter;
+pub mod prefix;
 pub mod query;
+pub mod ranking;
+pub mod suggest;
+pub mod syntax;
+pub mod trending;
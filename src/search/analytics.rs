@@ -0,0 +1,216 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::kind_label::KindLabels;
+use crate::search::filter::Filter;
+use crate::search::syntax::SearchQuery;
+
+/// distinct search strings counted; searches beyond are only counted in the totals
+const MAX_TRACKED_QUERIES: usize = 10_000;
+
+/// Searches and their hits, aggregated without connections or addresses.
+#[derive(Debug)]
+pub struct QueryAnalytics {
+    /// search strings counted fewer times are left out of reports
+    pub min_count: u64,
+    started_at: DateTime<Utc>,
+    state: Mutex<AnalyticsState>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct Counts {
+    pub searches: u64,
+    pub zero_results: u64,
+}
+
+impl Counts {
+    fn add(&mut self, hits: usize) {
+        self.searches += 1;
+        if hits == 0 {
+            self.zero_results += 1;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct AnalyticsState {
+    total: Counts,
+    queries: HashMap<String, Counts>,
+    languages: BTreeMap<String, Counts>,
+    kinds: BTreeMap<String, Counts>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct QueryCount {
+    pub query: String,
+    #[serde(flatten)]
+    pub counts: Counts,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AnalyticsReport {
+    pub since: DateTime<Utc>,
+    #[serde(flatten)]
+    pub total: Counts,
+    pub zero_result_rate: f64,
+    /// most frequent search strings
+    pub top: Vec<QueryCount>,
+    /// search strings most often without results
+    pub top_zero_results: Vec<QueryCount>,
+    /// by `language:` or the detected language; `none` without either
+    pub languages: BTreeMap<String, Counts>,
+    /// by kind label or number; `any` without kinds
+    pub kinds: BTreeMap<String, Counts>,
+}
+
+/// Words and phrases of a search, lowercased and sorted so that reorderings count together.
+fn normalize(query: &SearchQuery) -> String {
+    let mut words = query
+        .terms
+        .iter()
+        .map(|term| term.to_lowercase())
+        .chain(
+            query
+                .phrases
+                .iter()
+                .map(|phrase| format!("\"{}\"", phrase.to_lowercase())),
+        )
+        .collect::<Vec<_>>();
+    words.sort();
+    words.dedup();
+    words.join(" ")
+}
+
+/// The `n` entries with the largest `key`, ties broken by query.
+fn top_by(
+    queries: &HashMap<String, Counts>,
+    min_count: u64,
+    n: usize,
+    key: impl Fn(&Counts) -> u64,
+) -> Vec<QueryCount> {
+    let mut top = queries
+        .iter()
+        .filter(|(_, counts)| counts.searches >= min_count && key(counts) > 0)
+        .map(|(query, counts)| QueryCount {
+            query: query.clone(),
+            counts: *counts,
+        })
+        .collect::<Vec<_>>();
+    top.sort_by(|a, b| {
+        key(&b.counts)
+            .cmp(&key(&a.counts))
+            .then_with(|| a.query.cmp(&b.query))
+    });
+    top.truncate(n);
+    top
+}
+
+impl QueryAnalytics {
+    pub fn new(min_count: u64) -> Self {
+        QueryAnalytics {
+            min_count,
+            started_at: Utc::now(),
+            state: Mutex::new(AnalyticsState::default()),
+        }
+    }
+
+    /// Counts a pre-EOSE search of `filter` that found `hits` events.
+    pub fn record(&self, filter: &Filter, kind_labels: &KindLabels, hits: usize) {
+        let query = SearchQuery::parse(filter.search.as_deref().unwrap_or_default());
+        let text = normalize(&query);
+        let language = query
+            .language
+            .clone()
+            .or_else(|| filter.detected_language.clone())
+            .unwrap_or_else(|| "none".to_string());
+        let mut kinds = filter
+            .kinds
+            .iter()
+            .flatten()
+            .map(|kind| kind.as_u32())
+            .chain(query.kinds(kind_labels))
+            .map(|kind| match kind_labels.label(kind) {
+                Some(label) => label.to_string(),
+                None => kind.to_string(),
+            })
+            .collect::<Vec<_>>();
+        kinds.sort();
+        kinds.dedup();
+        if kinds.is_empty() {
+            kinds.push("any".to_string());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.total.add(hits);
+        if !text.is_empty()
+            && (state.queries.len() < MAX_TRACKED_QUERIES || state.queries.contains_key(&text))
+        {
+            state.queries.entry(text).or_default().add(hits);
+        }
+        state.languages.entry(language).or_default().add(hits);
+        for kind in kinds {
+            state.kinds.entry(kind).or_default().add(hits);
+        }
+    }
+
+    /// The `n` most frequent search strings and those most often without results.
+    pub fn report(&self, n: usize) -> AnalyticsReport {
+        let state = self.state.lock().unwrap();
+        AnalyticsReport {
+            since: self.started_at,
+            total: state.total,
+            zero_result_rate: if state.total.searches == 0 {
+                0.0
+            } else {
+                state.total.zero_results as f64 / state.total.searches as f64
+            },
+            top: top_by(&state.queries, self.min_count, n, |c| c.searches),
+            top_zero_results: top_by(&state.queries, self.min_count, n, |c| c.zero_results),
+            languages: state.languages.clone(),
+            kinds: state.kinds.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::kind_label::KindLabels;
+    use crate::search::analytics::{Counts, QueryAnalytics};
+    use crate::search::filter::Filter;
+
+    #[test]
+    fn test_report() {
+        let analytics = QueryAnalytics::new(2);
+        let kind_labels = KindLabels::default();
+        let record = |filter: serde_json::Value, hits: usize| {
+            let filter = serde_json::from_value::<Filter>(filter).unwrap();
+            analytics.record(&filter, &kind_labels, hits);
+        };
+        record(json!({"search": "Nostr relay"}), 3);
+        record(json!({"search": "relay nostr language:en"}), 0);
+        record(json!({"search": "rare kind:article", "kinds": [1]}), 0);
+
+        let report = analytics.report(10);
+        assert_eq!(
+            report.total,
+            Counts {
+                searches: 3,
+                zero_results: 2
+            }
+        );
+        assert_eq!(report.top.len(), 1);
+        assert_eq!(report.top[0].query, "nostr relay");
+        assert_eq!(report.top[0].counts.searches, 2);
+        assert_eq!(report.top_zero_results[0].counts.zero_results, 1);
+        assert_eq!(report.languages["en"].zero_results, 1);
+        assert_eq!(report.languages["none"].searches, 2);
+        assert_eq!(report.kinds["article"].zero_results, 1);
+        assert_eq!(report.kinds["note"].searches, 1);
+        assert_eq!(report.kinds["any"].searches, 2);
+    }
+}
@@ -0,0 +1,589 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use axum::extract::Query;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use nostr_sdk::Event;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::IntoParams;
+
+use crate::app_state::AppState;
+use crate::index::completion::complete as complete_prefix;
+use crate::index::geo::{parse_distance, parse_point};
+use crate::index::journal::journal_entries;
+use crate::index::reactions::{reaction_counts, ReactionCounts};
+use crate::index::stats::{index_stats, Count};
+use crate::kind_label::KindLabels;
+use crate::metrics::Metrics;
+use crate::search::facets::{facet_aggregations, parse_facet_counts, parse_facets};
+use crate::search::filter::Filter;
+use crate::search::handlers::{resolve_zapped_ids, search_index};
+use crate::search::query::ElasticsearchQuery;
+use crate::search::ranking::by_likes;
+use crate::search::syntax::SearchQuery;
+use crate::search::trending::MAX_TRENDING;
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+const DEFAULT_COMPLETIONS: usize = 5;
+const MAX_COMPLETIONS: usize = 20;
+
+/// Query parameters of `GET /search`.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchParams {
+    /// Search string, with the operators of NIP-50 searches
+    pub q: String,
+    /// Comma-separated kinds or kind labels, e.g. `1,article`
+    pub kinds: Option<String>,
+    /// Language code, same as `language:` in the search string
+    pub lang: Option<String>,
+    /// Comma-separated pubkeys or their prefixes
+    pub authors: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    /// Geohash or `<lat>,<lon>`, same as `near:` in the search string; keeps events whose `g`
+    /// tag is within `within` of it
+    pub near: Option<String>,
+    /// Distance around `near`, e.g. `500m`, `10km` or `3mi` (default: 10km)
+    pub within: Option<String>,
+    /// Events per page, at most 100 (default: 20)
+    pub limit: Option<usize>,
+    /// `next` of the previous page
+    pub page: Option<String>,
+    /// `reactions` to order each page by likes, when reactions are counted
+    pub sort: Option<String>,
+    /// Comma-separated facets counted over all the results: `kind`, `language`, `day`,
+    /// `hashtag` and `author`
+    pub facets: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResponse {
+    events: Vec<Event>,
+    /// token of the next page, absent on the last page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<String>,
+    /// fragments where searches with `highlight:true` matched, by event id
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    highlights: HashMap<String, Vec<String>>,
+    /// reactions to the events by event id, when reactions are counted
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    reactions: HashMap<String, ReactionCounts>,
+    /// counts of the requested facets by facet, with the most frequent values first
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    facets: BTreeMap<String, Vec<Count>>,
+}
+
+fn split_list(s: &str) -> impl Iterator<Item = &str> {
+    s.split(',').map(|s| s.trim()).filter(|s| !s.is_empty())
+}
+
+/// The NIP-50 filter equivalent to the parameters.
+fn to_filter(params: &SearchParams, kind_labels: &KindLabels) -> Result<Filter, String> {
+    let mut search = params.q.trim().to_string();
+    if search.is_empty() {
+        return Err("q is required".to_string());
+    }
+    if let Some(lang) = &params.lang {
+        search = format!("{} language:{}", search, lang);
+    }
+    if let Some(near) = &params.near {
+        if parse_point(near).is_none() {
+            return Err(format!("invalid near: {}", near));
+        }
+        search = format!("{} near:{}", search, near);
+    }
+    if let Some(within) = &params.within {
+        if params.near.is_none() {
+            return Err("within requires near".to_string());
+        }
+        if parse_distance(within).is_none() {
+            return Err(format!("invalid within: {}", within));
+        }
+        search = format!("{} within:{}", search, within);
+    }
+    if let Some(sort) = &params.sort {
+        if sort != "reactions" {
+            return Err(format!("invalid sort: {}", sort));
+        }
+    }
+    if let Some(facets) = &params.facets {
+        parse_facets(facets)?;
+    }
+    let kinds = match &params.kinds {
+        Some(kinds) => Some(
+            split_list(kinds)
+                .map(|kind| {
+                    kind_labels
+                        .kind(kind)
+                        .ok_or_else(|| format!("unknown kind: {}", kind))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        None => None,
+    };
+    let authors = params
+        .authors
+        .as_deref()
+        .map(|authors| split_list(authors).collect::<Vec<_>>());
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    serde_json::from_value::<Filter>(json!({
+        "search": search,
+        "kinds": kinds,
+        "authors": authors,
+        "since": params.since,
+        "until": params.until,
+        "limit": limit,
+        "cursor": params.page,
+    }))
+    .map_err(|e| e.to_string())
+}
+
+/// Query parameters of `GET /trending`.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TrendingParams {
+    /// Events returned, at most 100 (default: 20)
+    pub limit: Option<usize>,
+}
+
+/// Query parameters of `GET /complete`.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CompleteParams {
+    /// Prefix typed so far; `#` completes only hashtags and `@` only profiles
+    pub q: String,
+    /// Hashtags and profiles each, at most 20 (default: 5)
+    pub limit: Option<usize>,
+}
+
+/// Query parameters of `GET /admin/queries`.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReportParams {
+    /// `API_KEY` of searchnos
+    pub api_key: String,
+    /// Search strings listed in each part of the report (default: 50)
+    pub top: Option<usize>,
+}
+
+/// Query parameters of `GET /admin/journal`.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct JournalParams {
+    /// `API_KEY` of searchnos
+    pub api_key: String,
+    /// Hex id of the event
+    pub id: String,
+}
+
+/// Query parameters of `GET /admin/stats`.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StatsParams {
+    /// `API_KEY` of searchnos
+    pub api_key: String,
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
+
+/// Search events
+///
+/// Searches like the initial results of a NIP-50 subscription, for web frontends without a
+/// Nostr client.
+#[utoipa::path(
+    get,
+    path = "/search",
+    params(SearchParams),
+    responses(
+        (status = 200, description = "A page of events", body = crate::openapi::SearchResults),
+        (status = 400, description = "Invalid parameters"),
+        (status = 429, description = "Shed by the query limiter"),
+    )
+)]
+pub async fn search(
+    Extension(state): Extension<Arc<AppState>>,
+    Query(params): Query<SearchParams>,
+) -> Response {
+    let mut filter = match to_filter(&params, &state.kind_labels) {
+        Ok(filter) => filter,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &e),
+    };
+    if params.facets.is_some() && state.backend.is_some() {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "facets require Elasticsearch as the backend",
+        );
+    }
+    if let Err(e) = resolve_zapped_ids(&state, &mut filter).await {
+        log::warn!("failed to look up zapped events: {}", e);
+        return error(StatusCode::INTERNAL_SERVER_ERROR, "search failed");
+    }
+    if let Some(limiter) = &state.query_limiter {
+        if let Err(e) = limiter.acquire(1).await {
+            Metrics::inc(&state.metrics.queries_shed);
+            return error(StatusCode::TOO_MANY_REQUESTS, &e.to_string());
+        }
+    }
+    let query = ElasticsearchQuery::from_filter(
+        filter.clone(),
+        None,
+        &state.analyzer_config,
+        &state.kind_labels,
+        state.exclude_content_warnings,
+    );
+    let query = match &state.ranking {
+        Some(ranking) => query.with_ranking(ranking),
+        None => query,
+    };
+    let page = match &state.backend {
+        Some(backend) => backend.search(&filter, None).await,
+        None => {
+            query
+                .execute_page(&state.es_client, &search_index(&state, &filter), None)
+                .await
+        }
+    };
+    let (events, next) = match page {
+        Ok((events, _, next)) => (events, next.map(|cursor| cursor.token())),
+        Err(e) => {
+            log::warn!("failed to search over HTTP: {}", e);
+            return error(StatusCode::INTERNAL_SERVER_ERROR, "search failed");
+        }
+    };
+    if let Some(analytics) = &state.query_analytics {
+        // later pages of the same search are not counted again
+        if params.page.is_none() {
+            analytics.record(&filter, &state.kind_labels, events.len());
+        }
+    }
+
+    // backends keep no highlighted fields
+    let highlights = if SearchQuery::parse(&params.q).highlight && state.backend.is_none() {
+        let ids = events.iter().map(|e| e.id.to_hex()).collect::<Vec<_>>();
+        query
+            .highlights(&state.es_client, &state.index_alias_name, &ids)
+            .await
+            .unwrap_or_else(|e| {
+                log::warn!("failed to get highlights: {}", e);
+                HashMap::new()
+            })
+    } else {
+        HashMap::new()
+    };
+
+    let reactions = if state.reactions.is_some() {
+        let ids = events.iter().map(|e| e.id.to_hex()).collect::<Vec<_>>();
+        reaction_counts(&state.es_client, &state.index_alias_name, &ids)
+            .await
+            .unwrap_or_else(|e| {
+                log::warn!("failed to get reaction counts: {}", e);
+                HashMap::new()
+            })
+    } else {
+        HashMap::new()
+    };
+    let events = match params.sort.as_deref() {
+        Some("reactions") => by_likes(&events, &reactions),
+        _ => events,
+    };
+
+    // validated by `to_filter`
+    let facets = parse_facets(params.facets.as_deref().unwrap_or_default()).unwrap_or_default();
+    let facets = if facets.is_empty() {
+        BTreeMap::new()
+    } else {
+        query
+            .aggregate(
+                &state.es_client,
+                &search_index(&state, &filter),
+                facet_aggregations(&facets),
+            )
+            .await
+            .map(|aggregations| parse_facet_counts(&aggregations, &facets, &state.kind_labels))
+            .unwrap_or_else(|e| {
+                log::warn!("failed to count facets: {}", e);
+                BTreeMap::new()
+            })
+    };
+    Json(SearchResponse {
+        events,
+        next,
+        highlights,
+        reactions,
+        facets,
+    })
+    .into_response()
+}
+
+/// Notes trending over the last `TRENDING_HOURS`
+///
+/// Notes with the most engagement, as of the last recomputation.
+#[utoipa::path(
+    get,
+    path = "/trending",
+    params(TrendingParams),
+    responses(
+        (
+            status = 200,
+            description = "The most trending notes first",
+            body = crate::openapi::TrendingNotes
+        ),
+        (status = 404, description = "Trending notes are disabled"),
+    )
+)]
+pub async fn trending(
+    Extension(state): Extension<Arc<AppState>>,
+    Query(params): Query<TrendingParams>,
+) -> Response {
+    let trending = match &state.trending {
+        Some(trending) => trending,
+        None => return error(StatusCode::NOT_FOUND, "trending notes are disabled"),
+    };
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_TRENDING);
+    let notes = trending.get(limit);
+    Json(json!({
+        "hours": trending.hours,
+        "updated_at": notes.updated_at,
+        "events": notes.events,
+    }))
+    .into_response()
+}
+
+/// Hashtags and profiles starting with a prefix, for search-as-you-type
+#[utoipa::path(
+    get,
+    path = "/complete",
+    params(CompleteParams),
+    responses(
+        (
+            status = 200,
+            description = "Completions, the most used hashtags first",
+            body = crate::openapi::Completions
+        ),
+        (status = 404, description = "Completions are disabled"),
+    )
+)]
+pub async fn complete(
+    Extension(state): Extension<Arc<AppState>>,
+    Query(params): Query<CompleteParams>,
+) -> Response {
+    if state.completions.is_none() {
+        return error(StatusCode::NOT_FOUND, "completions are disabled");
+    }
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_COMPLETIONS)
+        .clamp(1, MAX_COMPLETIONS);
+    match complete_prefix(&state.es_client, &state.index_alias_name, &params.q, limit).await {
+        Ok(completions) => Json(completions).into_response(),
+        Err(e) => {
+            log::warn!("failed to complete {}: {}", params.q, e);
+            error(StatusCode::INTERNAL_SERVER_ERROR, "completion failed")
+        }
+    }
+}
+
+/// Report of the search strings counted with `QUERY_ANALYTICS`
+///
+/// The most frequent searches and those without results, for operators to see where the index
+/// falls short.
+#[utoipa::path(
+    get,
+    path = "/admin/queries",
+    params(ReportParams),
+    responses(
+        (status = 200, description = "The report", body = crate::openapi::QueryReport),
+        (status = 401, description = "Invalid API key"),
+        (status = 404, description = "Query analytics are disabled"),
+    )
+)]
+pub async fn query_report(
+    Extension(state): Extension<Arc<AppState>>,
+    Query(params): Query<ReportParams>,
+) -> Response {
+    if params.api_key != state.api_key {
+        return error(StatusCode::UNAUTHORIZED, "invalid api key");
+    }
+    match &state.query_analytics {
+        Some(analytics) => Json(analytics.report(params.top.unwrap_or(50))).into_response(),
+        None => error(StatusCode::NOT_FOUND, "query analytics are disabled"),
+    }
+}
+
+/// Journal entries of an event received for indexing, oldest first
+///
+/// What happened to the event, for telling why it is not searchable.
+#[utoipa::path(
+    get,
+    path = "/admin/journal",
+    params(JournalParams),
+    responses(
+        (
+            status = 200,
+            description = "Operations and outcomes of the event",
+            body = crate::openapi::JournalEntries
+        ),
+        (status = 401, description = "Invalid API key"),
+        (status = 404, description = "The journal is disabled"),
+    )
+)]
+pub async fn journal(
+    Extension(state): Extension<Arc<AppState>>,
+    Query(params): Query<JournalParams>,
+) -> Response {
+    if params.api_key != state.api_key {
+        return error(StatusCode::UNAUTHORIZED, "invalid api key");
+    }
+    if state.journal.is_none() {
+        return error(StatusCode::NOT_FOUND, "the journal is disabled");
+    }
+    match journal_entries(&state.es_client, &state.index_alias_name, &params.id).await {
+        Ok(entries) => Json(json!({ "entries": entries })).into_response(),
+        Err(e) => {
+            log::error!("failed to read the journal: {}", e);
+            error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to read the journal",
+            )
+        }
+    }
+}
+
+/// Documents per day, kind and language, index sizes and the ingestion rate
+///
+/// The figures printed by `searchnos stats`.
+#[utoipa::path(
+    get,
+    path = "/admin/stats",
+    params(StatsParams),
+    responses(
+        (status = 200, description = "Index statistics", body = crate::openapi::IndexStats),
+        (status = 401, description = "Invalid API key"),
+        (
+            status = 404,
+            description = "Not available with a backend other than Elasticsearch"
+        ),
+    )
+)]
+pub async fn stats(
+    Extension(state): Extension<Arc<AppState>>,
+    Query(params): Query<StatsParams>,
+) -> Response {
+    if params.api_key != state.api_key {
+        return error(StatusCode::UNAUTHORIZED, "invalid api key");
+    }
+    if state.backend.is_some() {
+        return error(
+            StatusCode::NOT_FOUND,
+            "index stats require Elasticsearch as the backend",
+        );
+    }
+    let res = index_stats(
+        &state.es_client,
+        &state.index_name_prefix,
+        &state.index_alias_name,
+        &state.kind_labels,
+    )
+    .await;
+    match res {
+        Ok(stats) => Json(stats).into_response(),
+        Err(e) => {
+            log::error!("failed to get index stats: {}", e);
+            error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to get index stats",
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::Kind;
+
+    use crate::kind_label::KindLabels;
+    use crate::search::api::{to_filter, SearchParams};
+
+    #[test]
+    fn test_to_filter() {
+        let params = SearchParams {
+            q: "hello".to_string(),
+            kinds: Some("1, article".to_string()),
+            lang: Some("en".to_string()),
+            authors: Some("ab,cd".to_string()),
+            limit: Some(1000),
+            ..Default::default()
+        };
+        let filter = to_filter(&params, &KindLabels::default()).unwrap();
+        assert_eq!(filter.search, Some("hello language:en".to_string()));
+        assert_eq!(
+            filter.kinds,
+            Some(vec![Kind::TextNote, Kind::LongFormTextNote])
+        );
+        assert_eq!(
+            filter.authors,
+            Some(vec!["ab".to_string(), "cd".to_string()])
+        );
+        assert_eq!(filter.limit, Some(100));
+        assert_eq!(filter.cursor, None);
+
+        let params = SearchParams {
+            q: "hello".to_string(),
+            page: Some("zz".to_string()),
+            ..Default::default()
+        };
+        assert!(to_filter(&params, &KindLabels::default()).is_err());
+
+        let params = SearchParams {
+            q: "hello".to_string(),
+            kinds: Some("unknown".to_string()),
+            ..Default::default()
+        };
+        assert!(to_filter(&params, &KindLabels::default()).is_err());
+        assert!(to_filter(&SearchParams::default(), &KindLabels::default()).is_err());
+
+        let params = SearchParams {
+            q: "ramen".to_string(),
+            near: Some("xn76urx6".to_string()),
+            within: Some("2km".to_string()),
+            ..Default::default()
+        };
+        let filter = to_filter(&params, &KindLabels::default()).unwrap();
+        assert_eq!(
+            filter.search,
+            Some("ramen near:xn76urx6 within:2km".to_string())
+        );
+        let params = SearchParams {
+            q: "ramen".to_string(),
+            within: Some("2km".to_string()),
+            ..Default::default()
+        };
+        assert!(to_filter(&params, &KindLabels::default()).is_err());
+
+        let params = SearchParams {
+            q: "hello".to_string(),
+            sort: Some("reactions".to_string()),
+            ..Default::default()
+        };
+        assert!(to_filter(&params, &KindLabels::default()).is_ok());
+        let params = SearchParams {
+            q: "hello".to_string(),
+            sort: Some("likes".to_string()),
+            ..Default::default()
+        };
+        assert!(to_filter(&params, &KindLabels::default()).is_err());
+        let params = SearchParams {
+            q: "hello".to_string(),
+            facets: Some("kind,colour".to_string()),
+            ..Default::default()
+        };
+        assert!(to_filter(&params, &KindLabels::default()).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use nostr_sdk::Event;
+use serde_json::json;
+
+use crate::search::filter::Filter;
+use crate::search::query::{Cursor, PageCursor};
+
+/// Events of a pre-EOSE search with its cursors, as returned by the query.
+pub type CachedPage = (Vec<Event>, Option<Cursor>, Option<PageCursor>);
+
+#[derive(Debug)]
+struct Entry {
+    page: CachedPage,
+    inserted_at: Instant,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<String, Entry>,
+    /// keys by `last_used`, the least recently used first
+    order: BTreeMap<u64, String>,
+    /// incremented on every use
+    clock: u64,
+    /// searches being run by key, which identical searches wait for
+    in_flight: HashMap<String, Arc<tokio::sync::Mutex<()>>>,
+}
+
+impl CacheState {
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.order.remove(&entry.last_used);
+            entry.last_used = clock;
+            self.order.insert(clock, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.last_used);
+        }
+    }
+}
+
+/// Least recently used pre-EOSE results by normalized filter, kept for a few seconds so that
+/// identical searches sent at once, like typeahead or default feeds, query Elasticsearch once;
+/// searches sent while an identical one runs wait for its results.
+#[derive(Debug)]
+pub struct QueryCache {
+    capacity: usize,
+    ttl: Duration,
+    state: Mutex<CacheState>,
+}
+
+fn sorted<T: Ord + Clone>(values: &Option<Vec<T>>) -> Option<Vec<T>> {
+    values.clone().map(|mut values| {
+        values.sort();
+        values.dedup();
+        values
+    })
+}
+
+/// Key of the filter, the same for filters differing only in the order of their values or in
+/// the whitespace of `search`.
+pub fn cache_key(filter: &Filter) -> String {
+    let sorted_map = |map: &HashMap<String, Vec<String>>| {
+        map.iter()
+            .map(|(name, values)| (name.clone(), sorted(&Some(values.clone()))))
+            .collect::<BTreeMap<_, _>>()
+    };
+    let kinds = filter
+        .kinds
+        .as_ref()
+        .map(|kinds| kinds.iter().map(|kind| kind.as_u64()).collect::<Vec<_>>());
+    json!({
+        "ids": sorted(&filter.ids),
+        "authors": sorted(&filter.authors),
+        "kinds": sorted(&kinds),
+        "search": filter.search.as_ref().map(|search| search.split_whitespace().collect::<Vec<_>>().join(" ")),
+        "since": filter.since.map(|since| since.as_u64()),
+        "until": filter.until.map(|until| until.as_u64()),
+        "limit": filter.limit,
+        "cursor": filter.cursor.as_ref().map(|cursor| cursor.token()),
+        "extra": sorted_map(&filter.extra),
+        "tag_prefixes": sorted_map(&filter.tag_prefixes),
+    })
+    .to_string()
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        QueryCache {
+            capacity,
+            ttl,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub fn get(&self, key: &str, now: Instant) -> Option<CachedPage> {
+        let mut state = self.state.lock().unwrap();
+        let fresh = match state.entries.get(key) {
+            Some(entry) => now.duration_since(entry.inserted_at) < self.ttl,
+            None => return None,
+        };
+        if !fresh {
+            state.remove(key);
+            return None;
+        }
+        state.touch(key);
+        state.entries.get(key).map(|entry| entry.page.clone())
+    }
+
+    pub fn insert(&self, key: String, page: CachedPage, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.remove(&key);
+        if state.entries.len() >= self.capacity {
+            let oldest = state.order.values().next().cloned();
+            if let Some(oldest) = oldest {
+                state.remove(&oldest);
+            }
+        }
+        state.entries.insert(
+            key.clone(),
+            Entry {
+                page,
+                inserted_at: now,
+                last_used: 0,
+            },
+        );
+        state.touch(&key);
+    }
+
+    /// The cached results of `key`, or those `fetch` returns, fetched once for identical
+    /// searches sent meanwhile. Returns whether they were cached.
+    pub async fn get_or_fetch<F, Fut>(
+        &self,
+        key: String,
+        fetch: F,
+    ) -> anyhow::Result<(CachedPage, bool)>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<CachedPage>>,
+    {
+        if let Some(page) = self.get(&key, Instant::now()) {
+            return Ok((page, true));
+        }
+        let flight = self
+            .state
+            .lock()
+            .unwrap()
+            .in_flight
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let guard = flight.lock().await;
+        // the search waited for may have cached its results; a failed one is run again
+        let res = match self.get(&key, Instant::now()) {
+            Some(page) => Ok((page, true)),
+            None => fetch().await.map(|page| {
+                self.insert(key.clone(), page.clone(), Instant::now());
+                (page, false)
+            }),
+        };
+        drop(guard);
+        let mut state = self.state.lock().unwrap();
+        // held by the map and this search only, so no other one is waiting
+        if Arc::strong_count(&flight) <= 2 {
+            state.in_flight.remove(&key);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    use nostr_sdk::{EventBuilder, Keys, Kind};
+    use serde_json::json;
+
+    use crate::search::cache::{cache_key, QueryCache};
+    use crate::search::filter::Filter;
+
+    #[test]
+    fn test_cache_key() {
+        let filter = |value| serde_json::from_value::<Filter>(value).unwrap();
+        assert_eq!(
+            cache_key(&filter(
+                json!({"search": " hello  world", "kinds": [1, 30023], "#t": ["b", "a"]})
+            )),
+            cache_key(&filter(
+                json!({"search": "hello world ", "kinds": [30023, 1], "#t": ["a", "b"]})
+            ))
+        );
+        assert_ne!(
+            cache_key(&filter(json!({"search": "hello", "limit": 10}))),
+            cache_key(&filter(json!({"search": "hello", "limit": 20})))
+        );
+        assert_ne!(
+            cache_key(&filter(json!({"search": "hello"}))),
+            cache_key(&filter(json!({"search": "Hello"})))
+        );
+    }
+
+    #[test]
+    fn test_query_cache() {
+        let event = EventBuilder::new(Kind::TextNote, "hello", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let page = || (vec![event.clone()], None, None);
+        let cache = QueryCache::new(2, Duration::from_secs(5));
+        let t0 = Instant::now();
+        cache.insert("a".to_string(), page(), t0);
+        cache.insert("b".to_string(), page(), t0);
+        assert_eq!(cache.get("a", t0).unwrap().0, vec![event.clone()]);
+        // "b" is the least recently used
+        cache.insert("c".to_string(), page(), t0);
+        assert!(cache.get("b", t0).is_none());
+        assert!(cache.get("a", t0).is_some());
+        assert!(cache.get("c", t0).is_some());
+
+        assert!(cache.get("a", t0 + Duration::from_secs(5)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_or_fetch() {
+        let event = EventBuilder::new(Kind::TextNote, "hello", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let cache = QueryCache::new(2, Duration::from_secs(5));
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok((vec![event.clone()], None, None))
+        };
+        let (first, second) = tokio::join!(
+            cache.get_or_fetch("a".to_string(), fetch),
+            cache.get_or_fetch("a".to_string(), fetch)
+        );
+        assert!(!first.unwrap().1);
+        assert_eq!(second.unwrap().0 .0, vec![event.clone()]);
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        let failed = cache
+            .get_or_fetch("b".to_string(), || async { Err(anyhow::anyhow!("failed")) })
+            .await;
+        assert!(failed.is_err());
+        let (_, cached) = cache.get_or_fetch("b".to_string(), fetch).await.unwrap();
+        assert!(!cached);
+        assert!(cache.state.lock().unwrap().in_flight.is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use serde_json::{json, Value};
+
+use crate::index::stats::Count;
+use crate::kind_label::KindLabels;
+
+/// values counted per facet
+const FACET_SIZE: usize = 20;
+
+/// Field counted over all the results of a search, for filter UIs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Facet {
+    Kind,
+    Language,
+    Day,
+    Hashtag,
+    Author,
+}
+
+impl FromStr for Facet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kind" => Ok(Facet::Kind),
+            "language" => Ok(Facet::Language),
+            "day" => Ok(Facet::Day),
+            "hashtag" => Ok(Facet::Hashtag),
+            "author" => Ok(Facet::Author),
+            _ => Err(format!("unknown facet: {}", s)),
+        }
+    }
+}
+
+impl Facet {
+    /// Key of the facet in the response.
+    fn name(&self) -> &'static str {
+        match self {
+            Facet::Kind => "kinds",
+            Facet::Language => "languages",
+            Facet::Day => "days",
+            Facet::Hashtag => "hashtags",
+            Facet::Author => "authors",
+        }
+    }
+
+    fn aggregation(&self) -> Value {
+        let terms = |field: &str| json!({ "terms": { "field": field, "size": FACET_SIZE } });
+        match self {
+            Facet::Kind => terms("event.kind"),
+            Facet::Language => terms("language"),
+            Facet::Day => json!({
+                "date_histogram": {
+                    "field": "event.created_at",
+                    "calendar_interval": "day",
+                    "format": "yyyy-MM-dd",
+                    "min_doc_count": 1
+                }
+            }),
+            // only in indices created since tags are mapped as keywords, like the authors
+            Facet::Hashtag => terms("tags.t"),
+            // only in indices created since the keyword was mapped
+            Facet::Author => terms("event.pubkey.keyword"),
+        }
+    }
+}
+
+/// Parses comma-separated facet names, ignoring duplicates.
+pub fn parse_facets(s: &str) -> Result<Vec<Facet>, String> {
+    let mut facets = vec![];
+    for name in s.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        let facet = name.parse::<Facet>()?;
+        if !facets.contains(&facet) {
+            facets.push(facet);
+        }
+    }
+    Ok(facets)
+}
+
+/// `aggs` of a search counting the facets.
+pub fn facet_aggregations(facets: &[Facet]) -> Value {
+    facets
+        .iter()
+        .map(|facet| (facet.name().to_string(), facet.aggregation()))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// Counts by facet name from the `aggregations` of a response; kinds by label, or number for
+/// kinds without one, and days in ascending order.
+pub fn parse_facet_counts(
+    aggregations: &Value,
+    facets: &[Facet],
+    kind_labels: &KindLabels,
+) -> BTreeMap<String, Vec<Count>> {
+    facets
+        .iter()
+        .map(|facet| {
+            let buckets = aggregations[facet.name()]["buckets"]
+                .as_array()
+                .cloned()
+                .unwrap_or_default();
+            let counts = buckets
+                .iter()
+                .filter_map(|bucket| {
+                    let key = match facet {
+                        Facet::Day => bucket["key_as_string"].as_str()?.to_string(),
+                        Facet::Kind => {
+                            let kind = bucket["key"].as_u64()? as u32;
+                            kind_labels
+                                .label(kind)
+                                .map(|label| label.to_string())
+                                .unwrap_or_else(|| kind.to_string())
+                        }
+                        _ => bucket["key"].as_str()?.to_string(),
+                    };
+                    Some(Count {
+                        key,
+                        documents: bucket["doc_count"].as_u64()?,
+                    })
+                })
+                .collect();
+            (facet.name().to_string(), counts)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::index::stats::Count;
+    use crate::kind_label::KindLabels;
+    use crate::search::facets::{facet_aggregations, parse_facet_counts, parse_facets, Facet};
+
+    #[test]
+    fn test_parse_facets() {
+        assert_eq!(
+            parse_facets("kind, day,kind").unwrap(),
+            vec![Facet::Kind, Facet::Day]
+        );
+        assert!(parse_facets("kind,color").is_err());
+        assert_eq!(
+            facet_aggregations(&[Facet::Hashtag])["hashtags"]["terms"]["field"],
+            "tags.t"
+        );
+    }
+
+    #[test]
+    fn test_parse_facet_counts() {
+        let aggregations = json!({
+            "kinds": { "buckets": [{ "key": 1, "doc_count": 10 }, { "key": 9999, "doc_count": 1 }] },
+            "days": { "buckets": [{ "key": 1710547200000u64, "key_as_string": "2024-03-16", "doc_count": 4 }] },
+            "hashtags": { "buckets": [{ "key": "nostr", "doc_count": 3 }] }
+        });
+        let counts = parse_facet_counts(
+            &aggregations,
+            &[Facet::Kind, Facet::Day, Facet::Hashtag, Facet::Author],
+            &KindLabels::default(),
+        );
+        let count = |key: &str, documents| Count {
+            key: key.to_string(),
+            documents,
+        };
+        assert_eq!(counts["kinds"], vec![count("note", 10), count("9999", 1)]);
+        assert_eq!(counts["days"], vec![count("2024-03-16", 4)]);
+        assert_eq!(counts["hashtags"], vec![count("nostr", 3)]);
+        assert!(counts["authors"].is_empty());
+    }
+}
@@ -1,8 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use nostr_sdk::{Kind, Timestamp};
+use nostr_sdk::{Event, Kind, Timestamp};
 use serde::Deserialize;
 
+use crate::index::content_warning::extract_content_warning;
+use crate::index::mentions::strip_mentions;
+use crate::index::protected::is_protected;
+use crate::index::text::Extractors;
+use crate::kind_label::KindLabels;
+use crate::search::query::PageCursor;
+use crate::search::syntax::SearchQuery;
+
 #[derive(Debug, Clone, Deserialize, PartialEq)]
 pub struct Filter {
     pub ids: Option<Vec<String>>,
@@ -12,27 +20,199 @@ pub struct Filter {
     pub since: Option<Timestamp>,
     pub until: Option<Timestamp>,
     pub limit: Option<usize>,
+    /// non-standard; pages through pre-EOSE results from the `next` cursor of the previous page
+    pub cursor: Option<PageCursor>,
 
     #[serde(flatten)]
     pub extra: HashMap<String, Vec<String>>,
+
+    /// language detected from `search` when it has no `language:` extension
+    #[serde(skip)]
+    pub detected_language: Option<String>,
+    /// prefixes of tag values by tag name, taken out of `extra`; see `TagPrefixes`
+    #[serde(skip)]
+    pub tag_prefixes: HashMap<String, Vec<String>>,
+    /// ids of the events zapped enough for `min_zaps:` of `search`
+    #[serde(skip)]
+    pub zapped_ids: Option<Vec<String>>,
 }
 
 impl Filter {
+    /// Tags of which any value must match, without the values that must all match.
     pub fn tags(&self) -> HashMap<String, Vec<String>> {
+        let and_tags = self.and_tags();
         self.extra
             .iter()
             .filter(|(k, _)| k.starts_with('#') && k.len() == 2)
+            .filter_map(|(k, v)| {
+                let name = k[1..].to_string();
+                let values = match and_tags.get(&name) {
+                    Some(all) => {
+                        let values = v
+                            .iter()
+                            .filter(|value| !all.contains(value))
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        if values.is_empty() {
+                            return None;
+                        }
+                        values
+                    }
+                    None => v.clone(),
+                };
+                Some((name, values))
+            })
+            .collect::<HashMap<_, _>>()
+    }
+
+    /// Tags of which all values must match, by the NIP-119 `&` extension, e.g. `"&t": ["a", "b"]`.
+    pub fn and_tags(&self) -> HashMap<String, Vec<String>> {
+        self.extra
+            .iter()
+            .filter(|(k, v)| k.starts_with('&') && k.len() == 2 && !v.is_empty())
             .map(|(k, v)| (k[1..].to_string(), v.clone()))
             .collect::<HashMap<_, _>>()
     }
+
+    /// Whether events with content warnings are left out, by the `nsfw:` extension or else by
+    /// `default`.
+    pub fn excludes_sensitive(&self, default: bool) -> bool {
+        match self
+            .search
+            .as_deref()
+            .and_then(|search| SearchQuery::parse(search).nsfw)
+        {
+            Some(nsfw) => !nsfw,
+            None => default,
+        }
+    }
+
+    /// Rough cost of the filter in Elasticsearch: the values it matches against, counting each
+    /// word of `search`.
+    pub fn complexity(&self) -> usize {
+        let search_terms = self
+            .search
+            .as_deref()
+            .map_or(0, |search| search.split_whitespace().count());
+        let tag_values = self
+            .extra
+            .iter()
+            .filter(|(k, _)| k.starts_with('#') || k.starts_with('&'))
+            .map(|(_, v)| v.len())
+            .sum::<usize>();
+        self.ids.as_ref().map_or(0, |ids| ids.len())
+            + self.authors.as_ref().map_or(0, |authors| authors.len())
+            + self.kinds.as_ref().map_or(0, |kinds| kinds.len())
+            + tag_values
+            + search_terms
+    }
+
+    /// Tests whether a newly indexed event matches this filter without querying Elasticsearch.
+    ///
+    /// Search terms are matched as case-insensitive substrings, which approximates
+    /// the n-gram phrase match. Searches with the `language:` extension never match
+    /// since the language is only known after ingestion.
+    pub fn matches(
+        &self,
+        event: &Event,
+        kind_labels: &KindLabels,
+        extractors: &Extractors,
+        exclude_sensitive: bool,
+    ) -> bool {
+        if self.excludes_sensitive(exclude_sensitive) && extract_content_warning(event).is_some() {
+            return false;
+        }
+        if is_protected(event) {
+            return false;
+        }
+        if let Some(ids) = &self.ids {
+            let id = event.id.to_hex();
+            if !ids.iter().any(|prefix| id.starts_with(prefix.as_str())) {
+                return false;
+            }
+        }
+        if let Some(authors) = &self.authors {
+            let pubkey = event.pubkey.to_string();
+            if !authors
+                .iter()
+                .any(|prefix| pubkey.starts_with(prefix.as_str()))
+            {
+                return false;
+            }
+        }
+        if let Some(kinds) = &self.kinds {
+            if !kinds.contains(&event.kind) {
+                return false;
+            }
+        }
+        if let Some(since) = self.since {
+            if event.created_at.as_u64() < since.as_u64() {
+                return false;
+            }
+        }
+        if let Some(until) = self.until {
+            if event.created_at.as_u64() > until.as_u64() {
+                return false;
+            }
+        }
+        let tags = self.tags();
+        let tag_names = tags
+            .keys()
+            .chain(self.tag_prefixes.keys())
+            .collect::<HashSet<_>>();
+        for tag_name in tag_names {
+            let values = tags.get(tag_name);
+            let prefixes = self.tag_prefixes.get(tag_name);
+            // only the first value of each tag is indexed
+            let found = event.tags.iter().any(|tag| {
+                let tag = tag.as_vec();
+                tag.len() >= 2
+                    && tag[0] == *tag_name
+                    && (values.map_or(false, |values| values.contains(&tag[1]))
+                        || prefixes.map_or(false, |prefixes| {
+                            prefixes
+                                .iter()
+                                .any(|prefix| tag[1].starts_with(prefix.as_str()))
+                        }))
+            });
+            if !found {
+                return false;
+            }
+        }
+        for (tag_name, values) in self.and_tags() {
+            let all = values.iter().all(|value| {
+                event.tags.iter().any(|tag| {
+                    let tag = tag.as_vec();
+                    tag.len() >= 2 && tag[0] == tag_name && tag[1] == *value
+                })
+            });
+            if !all {
+                return false;
+            }
+        }
+        if let Some(search) = &self.search {
+            let query = SearchQuery::parse(search);
+            if query.language.is_some() {
+                return false;
+            }
+            let text = strip_mentions(&extractors.extract(event)).0.to_lowercase();
+            if !query.matches(event, &text, kind_labels) {
+                return false;
+            }
+        }
+        true
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
 
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
     use serde_json::json;
 
+    use crate::index::text::Extractors;
+    use crate::kind_label::KindLabels;
     use crate::search::filter::Filter;
 
     #[test]
@@ -49,7 +229,11 @@ mod tests {
                 since: None,
                 until: None,
                 limit: None,
+                cursor: None,
                 extra: HashMap::new(),
+                detected_language: None,
+                tag_prefixes: HashMap::new(),
+                zapped_ids: None,
             }
         );
     }
@@ -78,7 +262,11 @@ mod tests {
                 since: None,
                 until: None,
                 limit: None,
+                cursor: None,
                 extra,
+                detected_language: None,
+                tag_prefixes: HashMap::new(),
+                zapped_ids: None,
             }
         );
     }
@@ -97,4 +285,138 @@ mod tests {
             .collect::<HashMap<_, _>>()
         );
     }
+
+    #[test]
+    fn test_and_tags() {
+        let filter = serde_json::from_value::<Filter>(
+            json!({"&t": ["meme", "cat"], "#t": ["black", "cat"], "&p": []}),
+        )
+        .unwrap();
+        assert_eq!(
+            filter.and_tags(),
+            HashMap::from([("t".to_string(), vec!["meme".to_string(), "cat".to_string()])])
+        );
+        // values that must all match are left out of those of which any must
+        assert_eq!(
+            filter.tags(),
+            HashMap::from([("t".to_string(), vec!["black".to_string()])])
+        );
+
+        let note = |tags: &[&str]| {
+            let tags = tags
+                .iter()
+                .map(|t| Tag::Hashtag(t.to_string()))
+                .collect::<Vec<_>>();
+            EventBuilder::new(Kind::TextNote, "hello", &tags)
+                .to_event(&Keys::generate())
+                .unwrap()
+        };
+        let matches = |event: nostr_sdk::Event| {
+            filter.matches(
+                &event,
+                &KindLabels::default(),
+                &Extractors::default(),
+                false,
+            )
+        };
+        assert!(matches(note(&["meme", "cat", "black"])));
+        assert!(!matches(note(&["meme", "black"])));
+        assert!(!matches(note(&["meme", "cat", "white"])));
+    }
+
+    #[test]
+    fn test_matches() {
+        let keys = Keys::generate();
+        let event = EventBuilder::new(
+            Kind::TextNote,
+            "Hello Nostr World",
+            &[Tag::Hashtag("nostr".to_string())],
+        )
+        .to_event(&keys)
+        .unwrap();
+        let matches = |filter: serde_json::Value| {
+            serde_json::from_value::<Filter>(filter).unwrap().matches(
+                &event,
+                &KindLabels::default(),
+                &Extractors::default(),
+                true,
+            )
+        };
+
+        assert!(matches(json!({"search": "hello world"})));
+        assert!(matches(json!({"search": "NOSTR", "kinds": [1]})));
+        assert!(!matches(json!({"search": "hello", "kinds": [0]})));
+        assert!(!matches(json!({"search": "goodbye"})));
+        assert!(!matches(json!({"search": "hello language:en"})));
+        assert!(matches(
+            json!({"search": "hello", "#t": ["nostr", "bitcoin"]})
+        ));
+        assert!(!matches(json!({"search": "hello", "#t": ["bitcoin"]})));
+        assert!(matches(
+            json!({"search": "hello", "authors": [keys.public_key().to_string()[..8]]})
+        ));
+        assert!(!matches(json!({"search": "hello", "ids": ["zz"]})));
+        assert!(matches(
+            json!({"search": "hello", "since": event.created_at.as_u64(), "until": event.created_at.as_u64()})
+        ));
+        assert!(!matches(
+            json!({"search": "hello", "since": event.created_at.as_u64() + 1})
+        ));
+        assert!(matches(json!({"search": "\"nostr world\" kind:note"})));
+        assert!(!matches(json!({"search": "\"hello world\""})));
+        assert!(!matches(json!({"search": "hello -world"})));
+        assert!(!matches(json!({"search": "hello kind:article"})));
+        assert!(matches(
+            json!({"search": format!("hello from:{}", keys.public_key())})
+        ));
+        assert!(!matches(
+            json!({"search": format!("hello from:{}", "a".repeat(64))})
+        ));
+
+        let sensitive = EventBuilder::new(
+            Kind::TextNote,
+            "Hello Nostr World",
+            &[Tag::parse(vec!["content-warning".to_string()]).unwrap()],
+        )
+        .to_event(&keys)
+        .unwrap();
+        let matches_sensitive = |filter: serde_json::Value, exclude_sensitive: bool| {
+            serde_json::from_value::<Filter>(filter).unwrap().matches(
+                &sensitive,
+                &KindLabels::default(),
+                &Extractors::default(),
+                exclude_sensitive,
+            )
+        };
+        assert!(!matches_sensitive(json!({"search": "hello"}), true));
+        assert!(matches_sensitive(
+            json!({"search": "hello nsfw:true"}),
+            true
+        ));
+        assert!(matches_sensitive(json!({"search": "hello"}), false));
+        assert!(!matches_sensitive(
+            json!({"search": "hello nsfw:false"}),
+            false
+        ));
+    }
+
+    #[test]
+    fn test_complexity() {
+        let complexity = |filter: serde_json::Value| {
+            serde_json::from_value::<Filter>(filter)
+                .unwrap()
+                .complexity()
+        };
+        assert_eq!(complexity(json!({"search": "hello"})), 1);
+        assert_eq!(
+            complexity(json!({
+                "search": "hello  world kind:note",
+                "kinds": [1, 30023],
+                "authors": ["aa", "bb"],
+                "#t": ["a", "b"],
+                "&t": ["c"]
+            })),
+            10
+        );
+    }
 }
@@ -1,40 +1,85 @@
 use anyhow::Context;
 use axum::extract::ws::{Message, WebSocket};
-use chrono::{DateTime, Utc};
 use futures::sink::SinkExt;
 use nostr_sdk::prelude::{RelayMessage, SubscriptionId};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, Mutex};
 use tokio::task::JoinHandle;
 
 use crate::app_state::AppState;
+use crate::index::indexes::profiles_index_name;
+use crate::index::zaps::zapped_ids;
+use crate::metrics::Metrics;
+use crate::search::cache::{cache_key, CachedPage};
 use crate::search::filter::Filter;
-use crate::search::query::ElasticsearchQuery;
+use crate::search::language::detect_language;
+use crate::search::query::{
+    is_profile_search, Cursor, ElasticsearchQuery, PageCursor, DEFAULT_LIMIT,
+};
+use crate::search::suggest::suggest;
+use crate::search::syntax::SearchQuery;
 
-use super::query;
+use super::{hybrid, ranking};
+
+const MAX_PUSHED_IDS: usize = 10_000;
+
+/// Index or alias searched for `filter`: profile searches only go through the profiles index
+/// when there is one.
+pub(crate) fn search_index(state: &AppState, filter: &Filter) -> String {
+    if state.profiles_index && is_profile_search(filter) {
+        profiles_index_name(&state.index_name_prefix)
+    } else {
+        state.index_alias_name.clone()
+    }
+}
+
+/// Looks up the events zapped enough for `min_zaps:`; none are when zaps are not counted.
+pub(crate) async fn resolve_zapped_ids(
+    state: &AppState,
+    filter: &mut Filter,
+) -> anyhow::Result<()> {
+    let min_sats = match SearchQuery::parse(filter.search.as_deref().unwrap_or_default()).min_zaps {
+        Some(min_sats) => min_sats,
+        None => return Ok(()),
+    };
+    filter.zapped_ids = Some(if state.zaps.is_some() {
+        zapped_ids(&state.es_client, &state.index_alias_name, min_sats).await?
+    } else {
+        vec![]
+    });
+    Ok(())
+}
 
 async fn send_events(
     sender: Arc<Mutex<futures::stream::SplitSink<WebSocket, Message>>>,
     subscription_id: &SubscriptionId,
     events: Vec<nostr_sdk::Event>,
+    highlights: &HashMap<String, Vec<String>>,
 ) -> anyhow::Result<()> {
     for event in events {
-        let relay_msg = RelayMessage::new_event(subscription_id.clone(), event);
-        sender
-            .lock()
-            .await
-            .send(Message::Text(relay_msg.as_json()))
-            .await?;
+        let msg = match highlights.get(&event.id.to_hex()) {
+            // non-standard fourth element, sent to searches with `highlight:true` only
+            Some(highlights) => serde_json::json!([
+                "EVENT",
+                subscription_id,
+                event,
+                { "highlights": highlights }
+            ])
+            .to_string(),
+            None => RelayMessage::new_event(subscription_id.clone(), event).as_json(),
+        };
+        sender.lock().await.send(Message::Text(msg)).await?;
     }
 
     Ok(())
 }
-async fn send_eose(
+
+async fn send_notice(
     sender: Arc<Mutex<futures::stream::SplitSink<WebSocket, Message>>>,
-    subscription_id: &SubscriptionId,
+    msg: &str,
 ) -> anyhow::Result<()> {
-    let relay_msg = RelayMessage::new_eose(subscription_id.clone());
+    let relay_msg = RelayMessage::new_notice(msg);
     sender
         .lock()
         .await
@@ -43,21 +88,210 @@ async fn send_eose(
     Ok(())
 }
 
+/// Machine-readable prefixes of NIP-01 `CLOSED` reasons.
+const CLOSED_PREFIXES: [&str; 3] = ["rate-limited:", "invalid:", "error:"];
+
+/// Reason of the `CLOSED` sent for `e`, or `error: ` and the error when it has no prefix.
+fn closed_reason(e: &anyhow::Error) -> String {
+    let reason = format!("{:#}", e);
+    if CLOSED_PREFIXES
+        .iter()
+        .any(|prefix| reason.starts_with(prefix))
+    {
+        reason
+    } else {
+        format!("error: {}", reason)
+    }
+}
+
+/// Sends a NIP-01 `CLOSED` with `reason`, prefixed like `rate-limited: ` or `invalid: `.
+async fn send_closed(
+    sender: Arc<Mutex<futures::stream::SplitSink<WebSocket, Message>>>,
+    subscription_id: &SubscriptionId,
+    reason: &str,
+) -> anyhow::Result<()> {
+    let msg = serde_json::json!(["CLOSED", subscription_id, reason]).to_string();
+    sender.lock().await.send(Message::Text(msg)).await?;
+    Ok(())
+}
+
+async fn send_eose(
+    sender: Arc<Mutex<futures::stream::SplitSink<WebSocket, Message>>>,
+    subscription_id: &SubscriptionId,
+    next: Option<Vec<Option<PageCursor>>>,
+) -> anyhow::Result<()> {
+    let msg = match next {
+        // non-standard third element, sent to subscriptions with cursors only
+        Some(next) => serde_json::json!([
+            "EOSE",
+            subscription_id,
+            {
+                "next": next
+                    .iter()
+                    .map(|cursor| cursor.as_ref().map(|cursor| cursor.token()))
+                    .collect::<Vec<_>>()
+            }
+        ])
+        .to_string(),
+        None => RelayMessage::new_eose(subscription_id.clone()).as_json(),
+    };
+    sender.lock().await.send(Message::Text(msg)).await?;
+    Ok(())
+}
+
+/// Searches a page of events for the filter, boosting pre-EOSE results.
+async fn search_page(
+    state: &AppState,
+    filter: &Filter,
+    cursor: Option<Cursor>,
+) -> anyhow::Result<CachedPage> {
+    let is_initial = cursor.is_none();
+    if let Some(limiter) = &state.query_limiter {
+        // a hybrid search sends a keyword and a kNN query
+        let hybrid = is_initial && state.hybrid_search.is_some() && state.embedder.is_some();
+        if let Err(e) = limiter.acquire(if hybrid { 2 } else { 1 }).await {
+            Metrics::inc(&state.metrics.queries_shed);
+            return Err(anyhow::anyhow!("rate-limited: {}", e));
+        }
+    }
+    let (events, new_cursor, next) = if let Some(backend) = &state.backend {
+        backend.search(filter, cursor).await?
+    } else {
+        match (&state.hybrid_search, &state.embedder, is_initial) {
+            // pre-EOSE; kNN results cannot be paged through by cursor
+            (Some(hybrid_config), Some(embedder), true)
+                if embedder.config.knn && filter.cursor.is_none() =>
+            {
+                let (events, cursor) =
+                    hybrid::search(state, embedder, hybrid_config, filter).await?;
+                (events, cursor, None)
+            }
+            _ => {
+                let query = ElasticsearchQuery::from_filter(
+                    filter.clone(),
+                    cursor.clone(),
+                    &state.analyzer_config,
+                    &state.kind_labels,
+                    state.exclude_content_warnings,
+                );
+                let query = match &state.ranking {
+                    Some(ranking) if is_initial => query.with_ranking(ranking),
+                    _ => query,
+                };
+                query
+                    .execute_page(&state.es_client, &search_index(state, filter), cursor)
+                    .await?
+            }
+        }
+    };
+    let events = match state.follower_boost {
+        Some(weight) if is_initial => ranking::boost_by_followers(state, weight, events).await,
+        _ => events,
+    };
+    let events = match state.zap_boost {
+        Some(weight) if is_initial && weight > 0.0 => {
+            ranking::boost_by_zaps(state, weight, events).await
+        }
+        _ => events,
+    };
+    let events = match state.reaction_boost {
+        Some(weight) if is_initial && weight > 0.0 => {
+            ranking::boost_by_reactions(state, weight, events).await
+        }
+        _ => events,
+    };
+    Ok((events, new_cursor, next))
+}
+
 async fn query_then_send(
     addr: SocketAddr,
     state: Arc<AppState>,
     sender: Arc<Mutex<futures::stream::SplitSink<WebSocket, Message>>>,
     subscription_id: SubscriptionId,
-    query: query::ElasticsearchQuery,
-    cursor: Option<DateTime<Utc>>,
-) -> anyhow::Result<Option<DateTime<Utc>>> {
+    filter: &Filter,
+    cursor: Option<Cursor>,
+    pushed_ids: &mut HashSet<String>,
+) -> anyhow::Result<(Option<Cursor>, Option<PageCursor>)> {
     let t0 = std::time::Instant::now();
-    let (events, new_cursor) = query
-        .execute(&state.es_client, &state.index_alias_name, cursor)
-        .await?;
+    let is_initial = cursor.is_none();
+    // only pre-EOSE searches are cached; later ones depend on the subscription
+    let (events, new_cursor, next) = match &state.query_cache {
+        Some(cache) if is_initial => {
+            let (page, cached) = cache
+                .get_or_fetch(cache_key(filter), || search_page(&state, filter, cursor))
+                .await?;
+            if cached {
+                Metrics::inc(&state.metrics.query_cache_hits);
+            } else {
+                Metrics::inc(&state.metrics.query_cache_misses);
+            }
+            page
+        }
+        _ => search_page(&state, filter, cursor).await?,
+    };
     let search_time = t0.elapsed().as_millis();
+    if let Some(analytics) = &state.query_analytics {
+        // later pages of the same search are not counted again
+        let is_first_page = filter
+            .cursor
+            .as_ref()
+            .map_or(true, |c| c == &PageCursor::default());
+        if is_initial && is_first_page {
+            analytics.record(filter, &state.kind_labels, events.len());
+        }
+    }
+    // skip events already pushed by the live subscription
+    let events = events
+        .into_iter()
+        .filter(|e| !pushed_ids.remove(&e.id.to_hex()))
+        .collect::<Vec<_>>();
     let num_hits = events.len();
-    send_events(sender.clone(), &subscription_id, events).await?;
+    // backends keep no highlighted fields
+    let highlights = if SearchQuery::parse(filter.search.as_deref().unwrap_or_default()).highlight
+        && state.backend.is_none()
+    {
+        let ids = events.iter().map(|e| e.id.to_hex()).collect::<Vec<_>>();
+        let query = ElasticsearchQuery::from_filter(
+            filter.clone(),
+            None,
+            &state.analyzer_config,
+            &state.kind_labels,
+            state.exclude_content_warnings,
+        );
+        match query
+            .highlights(&state.es_client, &state.index_alias_name, &ids)
+            .await
+        {
+            Ok(highlights) => highlights,
+            Err(e) => {
+                log::warn!("{} failed to get highlights: {}", addr, e);
+                HashMap::new()
+            }
+        }
+    } else {
+        HashMap::new()
+    };
+    send_events(sender.clone(), &subscription_id, events, &highlights).await?;
+
+    // pre-EOSE searches with few hits get a "did you mean" hint
+    // suggestions are skipped rather than waited for when queries are limited
+    if is_initial
+        && num_hits < state.suggest_min_hits
+        && state
+            .query_limiter
+            .as_ref()
+            .map(|limiter| limiter.try_acquire())
+            .unwrap_or(true)
+    {
+        let text = SearchQuery::parse(filter.search.as_deref().unwrap_or_default()).text();
+        match suggest(&state.es_client, &state.index_alias_name, &text).await {
+            Ok(Some(suggestion)) => {
+                send_notice(sender.clone(), &format!("did you mean: {}", suggestion)).await?;
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("{} failed to get suggestions: {}", addr, e),
+        }
+    }
 
     log::info!(
         "{} [{}] sent {} event(s), searched in {} ms",
@@ -66,7 +300,7 @@ async fn query_then_send(
         num_hits,
         search_time,
     );
-    Ok(new_cursor)
+    Ok((new_cursor, next))
 }
 
 pub async fn handle_req(
@@ -91,29 +325,105 @@ pub async fn handle_req(
         filters
     );
 
-    let num_ongoing_subscriptions = join_handles.lock().await.len();
+    let res = subscribe(
+        state,
+        sender.clone(),
+        join_handles.clone(),
+        addr,
+        subscription_id.clone(),
+        filters,
+    )
+    .await;
+    if let Err(e) = res {
+        // a subscription of the same id is closed to the client as well
+        stop_subscription(join_handles, &subscription_id).await;
+        let reason = closed_reason(&e);
+        log::warn!("{} [{}] closed: {}", addr, subscription_id, reason);
+        send_closed(sender, &subscription_id, &reason).await?;
+    }
+    Ok(())
+}
+
+/// Runs the pre-EOSE searches of a REQ, then keeps the subscription open.
+///
+/// Errors starting with a NIP-01 prefix like `invalid:` are sent as is in the `CLOSED`
+/// rejecting the subscription; others get the `error:` prefix.
+async fn subscribe(
+    state: Arc<AppState>,
+    sender: Arc<Mutex<futures::stream::SplitSink<WebSocket, Message>>>,
+    join_handles: Arc<Mutex<HashMap<String, JoinHandle<()>>>>,
+    addr: SocketAddr,
+    subscription_id: SubscriptionId,
+    filters: Vec<serde_json::Value>,
+) -> anyhow::Result<()> {
+    if let Some(limiter) = &state.req_limiter {
+        if let Err(wait) = limiter.try_acquire(addr.ip(), std::time::Instant::now()) {
+            Metrics::inc(&state.metrics.reqs_rate_limited);
+            let reason = format!(
+                "rate-limited: too many requests; retry in {} s",
+                wait.as_secs() + 1
+            );
+            return Err(anyhow::anyhow!(reason));
+        }
+    }
+
+    // a REQ replacing a subscription of the same id does not count
+    let num_ongoing_subscriptions = join_handles
+        .lock()
+        .await
+        .keys()
+        .filter(|id| **id != subscription_id.to_string())
+        .count();
     if num_ongoing_subscriptions + 1 > state.max_subscriptions {
-        return Err(anyhow::anyhow!(
-            "too many ongoing subscriptions: {}",
+        let reason = format!(
+            "rate-limited: too many ongoing subscriptions: {}",
             num_ongoing_subscriptions
-        ));
+        );
+        return Err(anyhow::anyhow!(reason));
     }
 
     // expire old subscription if exists
     stop_subscription(join_handles.clone(), &subscription_id.clone()).await;
 
     // prepare filters and cursors
-    let filters: Vec<Filter> = filters
+    let mut filters: Vec<Filter> = filters
         .into_iter()
-        .map(|f| serde_json::from_value::<Filter>(f).context("parsing filter"))
+        .map(|f| serde_json::from_value::<Filter>(f).context("invalid: parsing filter"))
         .collect::<Result<_, _>>()?;
+    for filter in filters.iter_mut() {
+        state
+            .tag_prefixes
+            .apply(filter)
+            .map_err(|e| anyhow::anyhow!("invalid: {}", e))?;
+    }
 
     // check filter length
     if filters.len() > state.max_filters {
-        return Err(anyhow::anyhow!("too many filters: {}", filters.len()));
+        return Err(anyhow::anyhow!(
+            "invalid: too many filters: {}",
+            filters.len()
+        ));
+    }
+    if let Some(max_complexity) = state.max_filter_complexity {
+        if let Some(complexity) = filters
+            .iter()
+            .map(|f| f.complexity())
+            .find(|complexity| *complexity > max_complexity)
+        {
+            let reason = format!(
+                "invalid: filter too complex: {} values and search terms, at most {}",
+                complexity, max_complexity
+            );
+            return Err(anyhow::anyhow!(reason));
+        }
+    }
+    if let Some(max_results) = state.max_results_per_req {
+        for filter in filters.iter_mut() {
+            filter.limit = Some(filter.limit.unwrap_or(DEFAULT_LIMIT).min(max_results));
+        }
     }
 
-    let filters: Vec<Filter> = filters
+    let mut filters: Vec<Filter> = filters
         .into_iter()
         .filter(|f| {
             if let Some(s) = &f.search {
@@ -125,49 +435,109 @@ pub async fn handle_req(
         .collect();
 
     if filters.is_empty() {
-        return Err(anyhow::anyhow!("only filter with search is supported"));
+        return Err(anyhow::anyhow!(
+            "invalid: only filter with search is supported"
+        ));
     }
 
-    let mut cursors: Vec<Option<DateTime<Utc>>> = filters.iter().map(|_| None).collect();
+    if let Some(min_probability) = state.query_language_detection {
+        for filter in filters.iter_mut() {
+            let query = SearchQuery::parse(filter.search.as_deref().unwrap_or_default());
+            let text = query.text();
+            if query.language.is_some() || text.is_empty() {
+                continue;
+            }
+            match detect_language(&state.es_client, &text, min_probability).await {
+                Ok(detected) => filter.detected_language = detected,
+                Err(e) => log::warn!("{} failed to detect the language: {}", addr, e),
+            }
+        }
+    }
+
+    for filter in filters.iter_mut() {
+        resolve_zapped_ids(&state, filter).await?;
+    }
+
+    let mut cursors: Vec<Option<Cursor>> = filters.iter().map(|_| None).collect();
+    let mut pushed_ids = HashSet::new();
+
+    // subscribe before the first search so that no event indexed in the meantime is missed
+    let mut new_events = state.new_events.subscribe();
 
     // do the first search
+    let mut next = vec![];
     for (filter, cursor) in filters.iter().zip(cursors.iter_mut()) {
-        let query = ElasticsearchQuery::from_filter(filter.clone(), None);
-
-        let new_cursor = query_then_send(
+        let (new_cursor, page_cursor) = query_then_send(
             addr,
             state.clone(),
             sender.clone(),
             subscription_id.clone(),
-            query,
+            filter,
             None,
-        );
-        *cursor = new_cursor.await?;
+            &mut pushed_ids,
+        )
+        .await?;
+        *cursor = new_cursor;
+        if filter.cursor.is_some() {
+            next.push(page_cursor);
+        }
     }
-    send_eose(sender.clone(), &subscription_id).await?;
+    let next = if next.is_empty() { None } else { Some(next) };
+    send_eose(sender.clone(), &subscription_id, next).await?;
 
     let sid_ = subscription_id.clone();
     let join_handle = tokio::spawn(async move {
         let mut cursors = cursors;
+        let mut live = true;
         loop {
             let wait = 5.0 + (rand::random::<f64>() * 5.0); // TODO better scheduling
-            tokio::time::sleep(tokio::time::Duration::from_secs_f64(wait)).await;
+            let poll_at = tokio::time::Instant::now() + tokio::time::Duration::from_secs_f64(wait);
+
+            // push matching events as they are indexed until it is time to poll
+            while live {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(poll_at) => break,
+                    res = new_events.recv() => match res {
+                        Ok(event) => {
+                            if !filters.iter().any(|f| f.matches(&event, &state.kind_labels, &state.extractors, state.exclude_content_warnings)) {
+                                continue;
+                            }
+                            if pushed_ids.len() >= MAX_PUSHED_IDS {
+                                // may cause duplicates, which clients must tolerate anyway
+                                pushed_ids.clear();
+                            }
+                            pushed_ids.insert(event.id.to_hex());
+                            let res = send_events(sender.clone(), &sid_, vec![event], &HashMap::new()).await;
+                            if let Err(e) = res {
+                                log::warn!("{} [{}] error pushing event: {}", addr, sid_, e);
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(n)) => {
+                            // missed events will be picked up by polling
+                            log::warn!("{} [{}] live subscription lagged by {}", addr, sid_, n);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => {
+                            live = false;
+                        }
+                    }
+                }
+            }
+            tokio::time::sleep_until(poll_at).await;
             log::info!("{} [{}] cont. {:?}", addr, &sid_.to_string(), filters);
 
             for (filter, cursor) in filters.iter().zip(cursors.iter_mut()) {
-                let query = ElasticsearchQuery::from_filter(filter.clone(), *cursor);
-
                 let res = query_then_send(
                     addr,
                     state.clone(),
                     sender.clone(),
                     sid_.clone(),
-                    query,
-                    *cursor,
+                    filter,
+                    cursor.clone(),
+                    &mut pushed_ids,
                 )
                 .await;
                 match res {
-                    Ok(new_cursor) => {
+                    Ok((new_cursor, _)) => {
                         *cursor = new_cursor;
                     }
                     Err(e) => {
@@ -216,3 +586,18 @@ pub async fn handle_close(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::search::handlers::closed_reason;
+
+    #[test]
+    fn test_closed_reason() {
+        let e = anyhow::anyhow!("invalid: too many filters: 9");
+        assert_eq!(closed_reason(&e), "invalid: too many filters: 9");
+        let e = anyhow::anyhow!("missing field").context("invalid: parsing filter");
+        assert_eq!(closed_reason(&e), "invalid: parsing filter: missing field");
+        let e = anyhow::anyhow!("connection refused");
+        assert_eq!(closed_reason(&e), "error: connection refused");
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use nostr_sdk::Event;
+
+use crate::app_state::AppState;
+use crate::index::embedding::Embedder;
+use crate::search::filter::Filter;
+use crate::search::query::{Cursor, ElasticsearchQuery};
+use crate::search::syntax::SearchQuery;
+
+/// Weights of reciprocal rank fusion of keyword and vector search results.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HybridConfig {
+    pub keyword_weight: f64,
+    pub vector_weight: f64,
+    /// `k` of RRF; larger values flatten the contribution of the top ranks
+    pub rank_constant: f64,
+}
+
+impl Default for HybridConfig {
+    fn default() -> Self {
+        HybridConfig {
+            keyword_weight: 1.0,
+            vector_weight: 1.0,
+            rank_constant: 60.0,
+        }
+    }
+}
+
+/// Merges ranked lists with weighted reciprocal rank fusion: `score(d) = Σ weight / (k + rank(d))`.
+///
+/// Ties are broken by `created_at` desc, then id asc, matching the order of keyword results.
+pub fn fuse(lists: Vec<(f64, Vec<Event>)>, rank_constant: f64, limit: usize) -> Vec<Event> {
+    let mut scores: HashMap<String, (f64, Event)> = HashMap::new();
+    for (weight, events) in lists {
+        for (i, event) in events.into_iter().enumerate() {
+            let score = weight / (rank_constant + (i + 1) as f64);
+            scores
+                .entry(event.id.to_hex())
+                .and_modify(|(s, _)| *s += score)
+                .or_insert((score, event));
+        }
+    }
+
+    let mut fused = scores.into_iter().collect::<Vec<_>>();
+    fused.sort_by(|(id_a, (score_a, event_a)), (id_b, (score_b, event_b))| {
+        score_b
+            .partial_cmp(score_a)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| {
+                event_b
+                    .created_at
+                    .as_u64()
+                    .cmp(&event_a.created_at.as_u64())
+            })
+            .then_with(|| id_a.cmp(id_b))
+    });
+    fused
+        .into_iter()
+        .take(limit)
+        .map(|(_, (_, event))| event)
+        .collect()
+}
+
+/// Runs the keyword and kNN searches of a pre-EOSE query and fuses the results.
+///
+/// Falls back to keyword results alone when the search string cannot be embedded.
+/// The returned cursor only reflects keyword results, which are what post-EOSE polling continues.
+pub async fn search(
+    state: &AppState,
+    embedder: &Embedder,
+    config: &HybridConfig,
+    filter: &Filter,
+) -> anyhow::Result<(Vec<Event>, Option<Cursor>)> {
+    let keyword_query = ElasticsearchQuery::from_filter(
+        filter.clone(),
+        None,
+        &state.analyzer_config,
+        &state.kind_labels,
+        state.exclude_content_warnings,
+    );
+    let limit = keyword_query.size();
+    let (keyword_events, cursor) = keyword_query
+        .execute(&state.es_client, &state.index_alias_name, None)
+        .await?;
+
+    let text = SearchQuery::parse(filter.search.as_deref().unwrap_or_default()).text();
+    if text.is_empty() {
+        return Ok((keyword_events, cursor));
+    }
+    let vector = match embedder.embed(&[text]).await {
+        Ok(mut vectors) => vectors.pop().unwrap_or_default(),
+        Err(e) => {
+            log::warn!(
+                "failed to embed search string; using keyword results only: {}",
+                e
+            );
+            return Ok((keyword_events, cursor));
+        }
+    };
+
+    let knn_query = ElasticsearchQuery::knn_from_filter(
+        filter.clone(),
+        embedder.config.to_stored_vector(vector),
+        &state.kind_labels,
+        state.exclude_content_warnings,
+    );
+    let (vector_events, _) = knn_query
+        .execute(&state.es_client, &state.index_alias_name, None)
+        .await?;
+
+    let events = fuse(
+        vec![
+            (config.keyword_weight, keyword_events),
+            (config.vector_weight, vector_events),
+        ],
+        config.rank_constant,
+        limit,
+    );
+    Ok((events, cursor))
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind};
+
+    use crate::search::hybrid::fuse;
+
+    fn events(n: usize) -> Vec<nostr_sdk::Event> {
+        let keys = Keys::generate();
+        (0..n)
+            .map(|i| {
+                EventBuilder::new(Kind::TextNote, format!("note {}", i), &[])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fuse() {
+        let e = events(4);
+        let keyword = vec![e[0].clone(), e[1].clone(), e[2].clone()];
+        let vector = vec![e[2].clone(), e[3].clone()];
+
+        // e[2] appears in both lists and wins
+        let fused = fuse(
+            vec![(1.0, keyword.clone()), (0.9, vector.clone())],
+            60.0,
+            10,
+        );
+        let ids = fused.iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![e[2].id, e[0].id, e[1].id, e[3].id]);
+
+        // limit applies after fusion
+        let fused = fuse(vec![(1.0, keyword.clone()), (0.9, vector.clone())], 60.0, 2);
+        assert_eq!(fused.len(), 2);
+
+        // a heavy vector weight lets semantic results come first
+        let fused = fuse(vec![(1.0, keyword), (10.0, vector)], 60.0, 10);
+        let ids = fused.iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![e[2].id, e[3].id, e[0].id, e[1].id]);
+    }
+
+    #[test]
+    fn test_fuse_ties() {
+        let e = events(2);
+        // same rank in different lists with the same weight
+        let fused = fuse(
+            vec![(1.0, vec![e[0].clone()]), (1.0, vec![e[1].clone()])],
+            60.0,
+            10,
+        );
+        let mut expected = vec![e[0].clone(), e[1].clone()];
+        expected.sort_by(|a, b| {
+            b.created_at
+                .as_u64()
+                .cmp(&a.created_at.as_u64())
+                .then_with(|| a.id.to_hex().cmp(&b.id.to_hex()))
+        });
+        assert_eq!(
+            fused.iter().map(|e| e.id).collect::<Vec<_>>(),
+            expected.iter().map(|e| e.id).collect::<Vec<_>>()
+        );
+    }
+}
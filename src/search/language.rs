@@ -0,0 +1,111 @@
+use elasticsearch::http::headers::HeaderMap;
+use elasticsearch::http::request::JsonBody;
+use elasticsearch::http::Method;
+use elasticsearch::Elasticsearch;
+use serde_json::{json, Value};
+
+/// the model detecting the language of events in the ingest pipeline
+const LANG_IDENT_MODEL: &str = "lang_ident_model_1";
+
+fn parse_prediction(result: &Value, min_probability: f64) -> Option<String> {
+    let probability = result["prediction_probability"].as_f64()?;
+    if probability < min_probability {
+        return None;
+    }
+    result["predicted_value"].as_str().map(|s| s.to_string())
+}
+
+fn parse_predictions(body: &Value, min_probability: f64) -> Vec<Option<String>> {
+    body["inference_results"]
+        .as_array()
+        .map(|results| {
+            results
+                .iter()
+                .map(|result| parse_prediction(result, min_probability))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Detects the language of a search string with the model used at ingestion, so that it can be
+/// matched against the field analyzed for that language.
+///
+/// Returns `None` when the prediction is less probable than `min_probability`, as is common for
+/// short search strings.
+pub async fn detect_language(
+    es_client: &Elasticsearch,
+    text: &str,
+    min_probability: f64,
+) -> anyhow::Result<Option<String>> {
+    let languages = detect_languages(es_client, &[text.to_string()], min_probability).await?;
+    Ok(languages.into_iter().next().flatten())
+}
+
+/// Detects the languages of `texts` at once; `None` for each prediction less probable than
+/// `min_probability`.
+pub async fn detect_languages(
+    es_client: &Elasticsearch,
+    texts: &[String],
+    min_probability: f64,
+) -> anyhow::Result<Vec<Option<String>>> {
+    let docs = texts
+        .iter()
+        .map(|text| json!({ "text": text }))
+        .collect::<Vec<_>>();
+    let path = format!("/_ml/trained_models/{}/_infer", LANG_IDENT_MODEL);
+    let res = es_client
+        .send(
+            Method::Post,
+            &path,
+            HeaderMap::new(),
+            None::<&()>,
+            Some(JsonBody::new(json!({
+                "docs": docs,
+                "inference_config": { "classification": { "num_top_classes": 1 } }
+            }))),
+            None,
+        )
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "language detection failed: {} {}",
+            status_code,
+            body
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    let languages = parse_predictions(&body, min_probability);
+    if languages.len() != texts.len() {
+        return Err(anyhow::anyhow!(
+            "language detection returned {} result(s) for {} text(s)",
+            languages.len(),
+            texts.len()
+        ));
+    }
+    Ok(languages)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::search::language::parse_predictions;
+
+    #[test]
+    fn test_parse_predictions() {
+        let body = json!({
+            "inference_results": [
+                { "predicted_value": "de", "prediction_probability": 0.93 },
+                { "predicted_value": "en", "prediction_probability": 0.6 }
+            ]
+        });
+        assert_eq!(
+            parse_predictions(&body, 0.8),
+            vec![Some("de".to_string()), None]
+        );
+        assert_eq!(parse_predictions(&body, 0.95), vec![None, None]);
+        assert!(parse_predictions(&json!({}), 0.8).is_empty());
+    }
+}
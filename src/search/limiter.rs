@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    /// tokens per second
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64, now: Instant) -> Self {
+        TokenBucket {
+            capacity,
+            rate,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    /// Takes `n` tokens, or returns how long to wait until they are available; more than the
+    /// capacity take a full bucket.
+    fn try_take(&mut self, n: f64, now: Instant) -> Result<(), Duration> {
+        let n = n.min(self.capacity);
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+        if self.tokens >= n {
+            self.tokens -= n;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((n - self.tokens) / self.rate))
+        }
+    }
+}
+
+/// Caps the queries per second the frontend sends to Elasticsearch, independently of clients.
+///
+/// Queries wait in line for tokens; they are shed when the line is full or the wait would
+/// exceed `max_wait`.
+#[derive(Debug)]
+pub struct QueryLimiter {
+    bucket: Mutex<TokenBucket>,
+    max_queue: usize,
+    max_wait: Duration,
+    waiting: AtomicUsize,
+}
+
+impl QueryLimiter {
+    pub fn new(rate: f64, burst: f64, max_queue: usize, max_wait: Duration) -> Self {
+        QueryLimiter {
+            bucket: Mutex::new(TokenBucket::new(rate, burst.max(1.0), Instant::now())),
+            max_queue,
+            max_wait,
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes a token for one query if available without waiting.
+    pub fn try_acquire(&self) -> bool {
+        match self.bucket.try_lock() {
+            Ok(mut bucket) => bucket.try_take(1.0, Instant::now()).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Waits until `n` queries may be sent.
+    pub async fn acquire(&self, n: usize) -> anyhow::Result<()> {
+        if self.waiting.fetch_add(1, Ordering::Relaxed) >= self.max_queue {
+            self.waiting.fetch_sub(1, Ordering::Relaxed);
+            return Err(anyhow::anyhow!("too many queued queries; try again later"));
+        }
+        let deadline = Instant::now() + self.max_wait;
+        let res = loop {
+            let wait = match self.bucket.lock().await.try_take(n as f64, Instant::now()) {
+                Ok(()) => break Ok(()),
+                Err(wait) => wait,
+            };
+            if Instant::now() + wait > deadline {
+                break Err(anyhow::anyhow!("search is rate limited; try again later"));
+            }
+            tokio::time::sleep(wait).await;
+        };
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+        res
+    }
+}
+
+/// buckets kept before the refilled ones are first dropped
+const MAX_TRACKED_IPS: usize = 10_000;
+
+#[derive(Debug)]
+struct IpBuckets {
+    buckets: HashMap<IpAddr, TokenBucket>,
+    /// size at which the refilled buckets are dropped next, twice the size left by the last
+    /// sweep so that sweeping costs a constant time per new address
+    sweep_at: usize,
+}
+
+/// Caps the REQs per second of each client IP address, over all its connections.
+#[derive(Debug)]
+pub struct IpRateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: std::sync::Mutex<IpBuckets>,
+}
+
+impl IpRateLimiter {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        IpRateLimiter {
+            rate,
+            burst: burst.max(1.0),
+            buckets: std::sync::Mutex::new(IpBuckets {
+                buckets: HashMap::new(),
+                sweep_at: MAX_TRACKED_IPS,
+            }),
+        }
+    }
+
+    /// Takes a token for one REQ of `ip`, or returns how long to wait for one.
+    pub fn try_acquire(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut ip_buckets = self.buckets.lock().unwrap();
+        let IpBuckets { buckets, sweep_at } = &mut *ip_buckets;
+        if buckets.len() >= *sweep_at && !buckets.contains_key(&ip) {
+            // a full bucket is the same as a new one
+            buckets.retain(|_, bucket| {
+                let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+                bucket.tokens + elapsed * bucket.rate < bucket.capacity
+            });
+            *sweep_at = (buckets.len() * 2).max(MAX_TRACKED_IPS);
+        }
+        buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(self.rate, self.burst, now))
+            .try_take(1.0, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    use crate::search::limiter::{IpRateLimiter, TokenBucket, MAX_TRACKED_IPS};
+
+    #[test]
+    fn test_token_bucket() {
+        let t0 = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 2.0, t0);
+        assert!(bucket.try_take(1.0, t0).is_ok());
+        assert!(bucket.try_take(1.0, t0).is_ok());
+        let wait = bucket.try_take(1.0, t0).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(100));
+
+        // refilled, but never above the capacity
+        let t1 = t0 + Duration::from_secs(10);
+        assert!(bucket.try_take(2.0, t1).is_ok());
+        assert!(bucket.try_take(1.0, t1).is_err());
+
+        // larger than the burst, e.g. a hybrid search
+        let mut bucket = TokenBucket::new(10.0, 1.0, t0);
+        assert!(bucket.try_take(2.0, t0).is_ok());
+        assert_eq!(
+            bucket.try_take(2.0, t0).unwrap_err(),
+            Duration::from_millis(100)
+        );
+    }
+
+    #[test]
+    fn test_ip_rate_limiter() {
+        let t0 = Instant::now();
+        let limiter = IpRateLimiter::new(1.0, 2.0);
+        let a = "192.0.2.1".parse::<IpAddr>().unwrap();
+        let b = "2001:db8::1".parse::<IpAddr>().unwrap();
+        assert!(limiter.try_acquire(a, t0).is_ok());
+        assert!(limiter.try_acquire(a, t0).is_ok());
+        assert_eq!(limiter.try_acquire(a, t0), Err(Duration::from_secs(1)));
+        assert!(limiter.try_acquire(b, t0).is_ok());
+        assert!(limiter.try_acquire(a, t0 + Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn test_ip_rate_limiter_sweep() {
+        let t0 = Instant::now();
+        let limiter = IpRateLimiter::new(1.0, 1.0);
+        let tracked = || limiter.buckets.lock().unwrap().buckets.len();
+        let ip = |i: usize| IpAddr::from([10, (i >> 16) as u8, (i >> 8) as u8, i as u8]);
+        for i in 0..MAX_TRACKED_IPS {
+            assert!(limiter.try_acquire(ip(i), t0).is_ok());
+        }
+        assert_eq!(tracked(), MAX_TRACKED_IPS);
+
+        // none refilled: kept, and not swept again before twice as many
+        assert!(limiter.try_acquire(ip(MAX_TRACKED_IPS), t0).is_ok());
+        assert_eq!(tracked(), MAX_TRACKED_IPS + 1);
+        assert!(limiter.try_acquire(ip(MAX_TRACKED_IPS + 1), t0).is_ok());
+        assert_eq!(tracked(), MAX_TRACKED_IPS + 2);
+
+        // all refilled by the time of the next sweep
+        let t1 = t0 + Duration::from_secs(1);
+        for i in MAX_TRACKED_IPS + 2..MAX_TRACKED_IPS * 2 {
+            assert!(limiter.try_acquire(ip(i), t1).is_ok());
+        }
+        assert_eq!(tracked(), MAX_TRACKED_IPS * 2);
+        assert!(limiter
+            .try_acquire(ip(MAX_TRACKED_IPS * 2), t1 + Duration::from_secs(1))
+            .is_ok());
+        assert_eq!(tracked(), 1);
+    }
+}
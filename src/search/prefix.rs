@@ -0,0 +1,151 @@
+use serde_json::{json, Value};
+
+use crate::search::filter::Filter;
+
+/// Tags whose filter values can end with `*` to match by prefix, e.g.
+/// `"#r": ["https://github.com/*"]`.
+///
+/// Prefixes are matched against a `wildcard` subfield of the tag, and limited in length and
+/// number so that a filter cannot scan most of the index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagPrefixes {
+    pub tags: Vec<String>,
+    /// characters before the `*`
+    pub min_length: usize,
+    pub max_per_filter: usize,
+}
+
+impl Default for TagPrefixes {
+    fn default() -> Self {
+        TagPrefixes {
+            tags: vec![],
+            min_length: 8,
+            max_per_filter: 3,
+        }
+    }
+}
+
+impl TagPrefixes {
+    /// Parses a comma-separated list of single-letter tag names, e.g. `r,t`.
+    pub fn parse(tags: &str, min_length: usize, max_per_filter: usize) -> anyhow::Result<Self> {
+        let tags = tags
+            .split(',')
+            .map(|tag| tag.trim())
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| match tag {
+                // matched by `refs`, which hold full ids
+                "e" | "p" => Err(anyhow::anyhow!(
+                    "prefixes of {} tags are not supported",
+                    tag
+                )),
+                tag if tag.chars().count() != 1 => {
+                    Err(anyhow::anyhow!("not a single-letter tag: {}", tag))
+                }
+                tag => Ok(tag.to_string()),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(TagPrefixes {
+            tags,
+            min_length,
+            max_per_filter,
+        })
+    }
+
+    /// Mapping of the tags matched by prefix, for the `tags` object of the index template.
+    pub fn mappings(&self) -> Vec<(String, Value)> {
+        self.tags
+            .iter()
+            .map(|tag| {
+                (
+                    tag.clone(),
+                    json!({
+                        "type": "keyword",
+                        "fields": {
+                            "prefix": { "type": "wildcard" }
+                        }
+                    }),
+                )
+            })
+            .collect()
+    }
+
+    /// Moves the values ending with `*` of the prefix tags of `filter` into its `tag_prefixes`.
+    pub fn apply(&self, filter: &mut Filter) -> anyhow::Result<()> {
+        let mut count = 0;
+        for tag in &self.tags {
+            let key = format!("#{}", tag);
+            let values = match filter.extra.remove(&key) {
+                Some(values) => values,
+                None => continue,
+            };
+            let (prefixes, values): (Vec<_>, Vec<_>) =
+                values.into_iter().partition(|value| value.ends_with('*'));
+            if !values.is_empty() {
+                filter.extra.insert(key, values);
+            }
+            for prefix in prefixes {
+                let prefix = prefix.trim_end_matches('*');
+                if prefix.chars().count() < self.min_length {
+                    return Err(anyhow::anyhow!(
+                        "tag prefix shorter than {} characters: {}",
+                        self.min_length,
+                        prefix
+                    ));
+                }
+                count += 1;
+                if count > self.max_per_filter {
+                    return Err(anyhow::anyhow!(
+                        "more than {} tag prefixes",
+                        self.max_per_filter
+                    ));
+                }
+                filter
+                    .tag_prefixes
+                    .entry(tag.clone())
+                    .or_default()
+                    .push(prefix.to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::search::filter::Filter;
+    use crate::search::prefix::TagPrefixes;
+
+    #[test]
+    fn test_apply() {
+        let prefixes = TagPrefixes::parse("r", 8, 2).unwrap();
+        let filter = |value: serde_json::Value| serde_json::from_value::<Filter>(value).unwrap();
+
+        let mut f = filter(json!({
+            "#r": ["https://github.com/*", "https://example.com"],
+            "#t": ["nostr*"]
+        }));
+        prefixes.apply(&mut f).unwrap();
+        assert_eq!(f.tag_prefixes["r"], vec!["https://github.com/"]);
+        assert_eq!(f.extra["#r"], vec!["https://example.com"]);
+        // other tags are matched as they are
+        assert_eq!(f.extra["#t"], vec!["nostr*"]);
+
+        let mut f = filter(json!({ "#r": ["https://github.com/*"] }));
+        prefixes.apply(&mut f).unwrap();
+        assert!(!f.extra.contains_key("#r"));
+
+        assert!(prefixes
+            .apply(&mut filter(json!({ "#r": ["https:*"] })))
+            .is_err());
+        assert!(prefixes
+            .apply(&mut filter(json!({
+                "#r": ["https://a.example/*", "https://b.example/*", "https://c.example/*"]
+            })))
+            .is_err());
+
+        assert!(TagPrefixes::parse("e", 8, 2).is_err());
+        assert!(TagPrefixes::parse("url", 8, 2).is_err());
+    }
+}
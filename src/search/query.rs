@@ -1,29 +1,161 @@
+use std::collections::HashMap;
 use std::fmt;
 
 use chrono::{DateTime, Utc};
 use elasticsearch::{Elasticsearch, SearchParts};
-use nostr_sdk::prelude::Event;
+use nostr_sdk::prelude::{Event, Kind};
 use serde::Deserialize;
 use serde_json::{json, Value};
 
 use super::filter::Filter;
+use crate::index::analyzer::AnalyzerConfig;
+use crate::index::refs::normalize_ref;
+use crate::kind_label::KindLabels;
+use crate::search::ranking::RankingConfig;
+use crate::search::syntax::SearchQuery;
 
 #[derive(Deserialize, Debug)]
 struct Document {
     event: Event,
+    /// original event when `created_at` of `event` is rounded
+    #[serde(rename = "_raw")]
+    raw: Option<Event>,
     #[allow(dead_code)]
     text: String,
     #[allow(dead_code)]
     timestamp: DateTime<Utc>,
+    /// absent without the ingest pipeline
     #[allow(dead_code)]
-    language: String,
+    #[serde(default)]
+    language: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ElasticsearchQuery {
     query: Value,
     size: i64,
-    sort: Vec<&'static str>,
+    sort: Value,
+    /// sort values of the last result of the previous page
+    search_after: Option<Vec<Value>>,
+    /// sorted by a `sort:` operator, which ranking does not override
+    sorted: bool,
+}
+
+/// Position of the last event sent to a subscription.
+///
+/// Ordered by `(timestamp, id)` so that events sharing the same timestamp are
+/// neither sent twice nor skipped when a page is cut off in the middle of a tie.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cursor {
+    pub timestamp: DateTime<Utc>,
+    pub id: String,
+}
+
+impl Cursor {
+    fn condition(&self) -> Value {
+        let timestamp = self.timestamp.to_rfc3339();
+        json!({
+            "bool": {
+                "should": [
+                    {
+                        "range": {
+                            "timestamp": {
+                                "gt": timestamp
+                            }
+                        }
+                    },
+                    {
+                        "bool": {
+                            "must": [
+                                {
+                                    "term": {
+                                        "timestamp": timestamp
+                                    }
+                                },
+                                {
+                                    "range": {
+                                        "event.id.keyword": {
+                                            "gt": self.id
+                                        }
+                                    }
+                                }
+                            ]
+                        }
+                    }
+                ],
+                "minimum_should_match": 1
+            }
+        })
+    }
+}
+
+/// Position after the last result of a page of a pre-EOSE query, for paging through more
+/// results than Elasticsearch pages through by offset.
+///
+/// Tokens are the hex-encoded sort values of the last result; the empty token asks for the
+/// first page.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PageCursor(Vec<Value>);
+
+impl PageCursor {
+    pub fn parse(token: &str) -> anyhow::Result<Self> {
+        if token.is_empty() {
+            return Ok(PageCursor::default());
+        }
+        let invalid = || anyhow::anyhow!("invalid cursor: {}", token);
+        if token.len() % 2 != 0 || !token.is_ascii() {
+            return Err(invalid());
+        }
+        let bytes = (0..token.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&token[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        let values = serde_json::from_slice::<Vec<Value>>(&bytes).map_err(|_| invalid())?;
+        Ok(PageCursor(values))
+    }
+
+    /// Cursor of other backends, which page by values of their own.
+    pub(crate) fn new(values: Vec<Value>) -> Self {
+        PageCursor(values)
+    }
+
+    pub(crate) fn values(&self) -> &[Value] {
+        &self.0
+    }
+
+    pub fn token(&self) -> String {
+        if self.0.is_empty() {
+            return String::new();
+        }
+        serde_json::to_vec(&self.0)
+            .unwrap_or_default()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+impl TryFrom<String> for PageCursor {
+    type Error = anyhow::Error;
+
+    fn try_from(token: String) -> anyhow::Result<Self> {
+        PageCursor::parse(&token)
+    }
+}
+
+// `event.id.keyword` does not exist in indices created before it was added to the template
+fn gen_sort(primary_field: &str, primary_order: &str) -> Value {
+    json!([
+        { primary_field: { "order": primary_order } },
+        { "event.id.keyword": { "order": "asc", "unmapped_type": "keyword" } }
+    ])
+}
+
+/// NIP-70 protected events are indexed only if configured so, and never returned.
+pub(crate) fn gen_excluded_conditions() -> Value {
+    json!([{ "term": { "protected": true } }])
 }
 
 fn gen_query(must_conditions: Vec<Option<Value>>) -> Value {
@@ -31,7 +163,8 @@ fn gen_query(must_conditions: Vec<Option<Value>>) -> Value {
         "query": {
             "bool": {
                 // exclude None
-                "must": must_conditions.into_iter().filter_map(|c| c).collect::<Vec<_>>()
+                "must": must_conditions.into_iter().filter_map(|c| c).collect::<Vec<_>>(),
+                "must_not": gen_excluded_conditions()
             }
         }
     })
@@ -99,71 +232,336 @@ where
     })
 }
 
-impl ElasticsearchQuery {
-    pub fn from_filter(filter: Filter, cursor: Option<DateTime<Utc>>) -> Self {
-        const MAX_LIMIT: usize = 10_000;
-        const DEFAULT_LIMIT: usize = 500;
-
-        let tags = &filter.tags();
-
-        let created_at_condition = match (filter.since, filter.until) {
-            (Some(since), Some(until)) => Some(json!({
-                "range": {
-                    "event.created_at": {
-                        "gt": since.as_u64(),
-                        "lt": until.as_u64()
-                    }
+/// Matches tag values by prefix, or exactly by `values`.
+fn gen_tag_prefix_query(tag_name: &str, values: &[String], prefixes: &[String]) -> Option<Value> {
+    let field = format!("tags.{}", tag_name);
+    let mut should = prefixes
+        .iter()
+        .map(|prefix| json!({ "prefix": { format!("{}.prefix", field): prefix } }))
+        .collect::<Vec<_>>();
+    should.extend(gen_tag_query(&field, Some(values.to_vec())));
+    Some(json!({
+        "bool": {
+            "should": should,
+            "minimum_should_match": 1
+        }
+    }))
+}
+
+/// Matches `e` and `p` tags by `refs`, falling back to the first tag values stored in `tags`
+/// for documents indexed before `refs` existed.
+fn gen_ref_query(refs_field: &str, tags_field: &str, values: &[String]) -> Option<Value> {
+    if values.is_empty() {
+        return None;
+    }
+    let refs = values
+        .iter()
+        .filter_map(|value| normalize_ref(value))
+        .collect::<Vec<_>>();
+    Some(json!({
+        "bool": {
+            "should": [
+                { "terms": { refs_field: refs } },
+                { "terms": { tags_field: values } }
+            ],
+            "minimum_should_match": 1
+        }
+    }))
+}
+
+/// Fragments of the highlighted fields by event id.
+fn parse_highlights(body: &Value) -> HashMap<String, Vec<String>> {
+    let mut highlights = HashMap::new();
+    for hit in body["hits"]["hits"].as_array().unwrap_or(&vec![]) {
+        let id = match hit["_source"]["event"]["id"].as_str() {
+            Some(id) => id.to_string(),
+            None => continue,
+        };
+        let fragments = hit["highlight"]
+            .as_object()
+            .map(|fields| {
+                fields
+                    .values()
+                    .filter_map(|fragments| fragments.as_array())
+                    .flatten()
+                    .filter_map(|fragment| fragment.as_str().map(|s| s.to_string()))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if !fragments.is_empty() {
+            highlights.insert(id, fragments);
+        }
+    }
+    highlights
+}
+
+/// Sort values to search after, if the filter continues from a page.
+fn page_cursor(filter: &Filter) -> Option<Vec<Value>> {
+    filter
+        .cursor
+        .as_ref()
+        .filter(|cursor| !cursor.0.is_empty())
+        .map(|cursor| cursor.0.clone())
+}
+
+fn advance_cursor(current: Option<Cursor>, seen: Cursor) -> Option<Cursor> {
+    match current {
+        Some(current) if current >= seen => Some(current),
+        _ => Some(seen),
+    }
+}
+
+/// Conditions of the filter and of the operators in `search`, other than words and phrases.
+///
+/// Events with content warnings are excluded if `exclude_sensitive` unless the search opts in.
+fn gen_filter_conditions(
+    filter: &Filter,
+    kind_labels: &KindLabels,
+    exclude_sensitive: bool,
+) -> Vec<Option<Value>> {
+    // both ends are inclusive; See NIP-01
+    let created_at_condition = match (filter.since, filter.until) {
+        (Some(since), Some(until)) => Some(json!({
+            "range": {
+                "event.created_at": {
+                    "gte": since.as_u64(),
+                    "lte": until.as_u64()
                 }
-            })),
-            (Some(since), None) => Some(json!({
-                "range": {
-                    "event.created_at": {
-                        "gt": since.as_u64()
-                    }
+            }
+        })),
+        (Some(since), None) => Some(json!({
+            "range": {
+                "event.created_at": {
+                    "gte": since.as_u64()
                 }
-            })),
-            (None, Some(until)) => Some(json!({
-                "range": {
-                    "event.created_at": {
-                        "lt": until.as_u64()
-                    }
+            }
+        })),
+        (None, Some(until)) => Some(json!({
+            "range": {
+                "event.created_at": {
+                    "lte": until.as_u64()
                 }
-            })),
-            (None, None) => None,
+            }
+        })),
+        (None, None) => None,
+    };
+
+    let kinds_condition = filter.kinds.clone().and_then(|kinds| {
+        Some(json!({
+            "terms": {
+                "event.kind": kinds
+            }
+        }))
+    });
+
+    let ids_condition = gen_prefix_search_query("event.id", filter.ids.clone());
+    let authors_condition = gen_prefix_search_query("event.pubkey", filter.authors.clone());
+
+    let mut conditions = vec![
+        ids_condition,
+        authors_condition,
+        kinds_condition,
+        created_at_condition,
+    ];
+
+    let tags = filter.tags();
+    let mut tag_names = tags
+        .keys()
+        .chain(filter.tag_prefixes.keys())
+        .collect::<Vec<_>>();
+    tag_names.sort();
+    tag_names.dedup();
+    for tag_name in tag_names {
+        let values = tags.get(tag_name).cloned().unwrap_or_default();
+        let tag_condition = match (tag_name.as_str(), filter.tag_prefixes.get(tag_name)) {
+            ("e", _) => gen_ref_query("refs.events", "tags.e", &values),
+            ("p", _) => gen_ref_query("refs.pubkeys", "tags.p", &values),
+            (_, Some(prefixes)) => gen_tag_prefix_query(tag_name, &values, prefixes),
+            (_, None) => gen_tag_query(&format!("tags.{}", tag_name), Some(values)),
         };
+        conditions.push(tag_condition);
+    }
 
-        let kinds_condition = filter.kinds.and_then(|kinds| {
-            Some(json!({
-                "terms": {
-                    "event.kind": kinds
-                }
-            }))
-        });
+    // one condition per value, so that all must match
+    for (tag_name, values) in &filter.and_tags() {
+        for value in values {
+            let value = vec![value.clone()];
+            let tag_condition = match tag_name.as_str() {
+                "e" => gen_ref_query("refs.events", "tags.e", &value),
+                "p" => gen_ref_query("refs.pubkeys", "tags.p", &value),
+                _ => gen_tag_query(&format!("tags.{}", tag_name), Some(value)),
+            };
+            conditions.push(tag_condition);
+        }
+    }
 
-        let ids_condition = gen_prefix_search_query("event.id", filter.ids);
-        let authors_condition = gen_prefix_search_query("event.pubkey", filter.authors);
+    if let Some(search) = &filter.search {
+        let query = SearchQuery::parse(search);
+        if let Some(language) = &query.language {
+            conditions.push(Some(json!({
+                "term": {
+                    "language": language
+                }
+            })));
+        }
+        conditions.extend(query.conditions(kind_labels).into_iter().map(Some));
+    }
 
-        let mut must_conditinos = vec![
-            ids_condition,
-            authors_condition,
-            kinds_condition,
-            created_at_condition,
-        ];
+    if let Some(ids) = &filter.zapped_ids {
+        conditions.push(Some(json!({
+            "terms": {
+                "event.id": ids
+            }
+        })));
+    }
 
-        if let Some(search) = filter.search {
-            let terms = search.trim().split_ascii_whitespace();
-            for term in terms {
-                must_conditinos.push(Some(json!({
-                    "match_phrase": {
-                        "text": term,
+    if filter.excludes_sensitive(exclude_sensitive) {
+        conditions.push(Some(json!({
+            "bool": {
+                "must_not": {
+                    "term": {
+                        "sensitive": true
                     }
-                })));
+                }
+            }
+        })));
+    }
+
+    conditions
+}
+
+/// Searches for kind 0 events only are ranked by how well the profile matches.
+pub(crate) fn is_profile_search(filter: &Filter) -> bool {
+    match &filter.kinds {
+        Some(kinds) => !kinds.is_empty() && kinds.iter().all(|kind| *kind == Kind::Metadata),
+        None => false,
+    }
+}
+
+/// Name and nip05 matches, tolerating typos, rank above matches in `about`.
+fn gen_profile_condition(text: &str) -> Value {
+    let lowercase = text.to_lowercase();
+    json!({
+        "bool": {
+            "should": [
+                { "match": { "profile.name.edge": { "query": text, "operator": "and", "boost": 4 } } },
+                { "match": { "profile.display_name.edge": { "query": text, "operator": "and", "boost": 3 } } },
+                { "match": { "profile.name.words": { "query": text, "fuzziness": "AUTO", "boost": 3 } } },
+                { "match": { "profile.display_name.words": { "query": text, "fuzziness": "AUTO", "boost": 2 } } },
+                { "prefix": { "profile.nip05": { "value": lowercase, "boost": 3 } } },
+                { "fuzzy": { "profile.nip05": { "value": lowercase, "fuzziness": "AUTO" } } },
+                { "match_phrase": { "profile.about": { "query": text, "boost": 0.5 } } }
+            ],
+            "minimum_should_match": 1
+        }
+    })
+}
+
+pub(crate) const MAX_LIMIT: usize = 10_000;
+pub(crate) const DEFAULT_LIMIT: usize = 500;
+
+impl ElasticsearchQuery {
+    pub fn from_filter(
+        filter: Filter,
+        cursor: Option<Cursor>,
+        analyzer_config: &AnalyzerConfig,
+        kind_labels: &KindLabels,
+        exclude_sensitive: bool,
+    ) -> Self {
+        let mut must_conditinos = gen_filter_conditions(&filter, kind_labels, exclude_sensitive);
+
+        if cursor.is_none() && is_profile_search(&filter) {
+            let search = filter.search.clone().unwrap_or_default();
+            let text = SearchQuery::parse(&search).text();
+            if !text.is_empty() {
+                must_conditinos.push(Some(gen_profile_condition(&text)));
             }
+            let size = filter
+                .limit
+                .map(|l| std::cmp::min(l, MAX_LIMIT))
+                .unwrap_or(DEFAULT_LIMIT) as i64;
+            return ElasticsearchQuery {
+                query: gen_query(must_conditinos),
+                size,
+                sort: json!([
+                    "_score",
+                    { "event.created_at": { "order": "desc" } },
+                    { "event.id.keyword": { "order": "asc", "unmapped_type": "keyword" } }
+                ]),
+                search_after: page_cursor(&filter),
+                sorted: false,
+            };
         }
 
-        for (tag_name, values) in tags {
-            let tag_condition = gen_tag_query(&format!("tags.{}", tag_name), Some(values.clone()));
-            must_conditinos.push(tag_condition);
+        let sort = filter
+            .search
+            .as_deref()
+            .and_then(|search| SearchQuery::parse(search).sort);
+        if let Some(search) = filter.search {
+            let SearchQuery {
+                terms,
+                phrases,
+                language,
+                ..
+            } = SearchQuery::parse(&search);
+            match language {
+                // prefer the field analyzed for the language over n-grams if available
+                Some(language) if analyzer_config.is_configured(&language) && !terms.is_empty() => {
+                    let field = format!("texts.{}", language);
+                    must_conditinos.push(Some(json!({
+                        "match": {
+                            field.as_str(): {
+                                "query": terms.join(" "),
+                                "operator": "and"
+                            }
+                        }
+                    })));
+                    must_conditinos.extend(phrases.iter().map(|phrase| {
+                        Some(json!({
+                            "match_phrase": {
+                                field.as_str(): phrase
+                            }
+                        }))
+                    }));
+                }
+                _ => {
+                    let to_phrase = |term: &String| {
+                        json!({
+                            "match_phrase": {
+                                "text": term,
+                            }
+                        })
+                    };
+                    let term_phrases = terms.iter().map(to_phrase).collect::<Vec<_>>();
+                    // quoted phrases are matched exactly, also with a detected language
+                    must_conditinos.extend(phrases.iter().map(to_phrase).map(Some));
+                    match &filter.detected_language {
+                        // also match inflected forms in the field analyzed for the language
+                        Some(detected)
+                            if analyzer_config.is_configured(detected) && !terms.is_empty() =>
+                        {
+                            let field = format!("texts.{}", detected);
+                            must_conditinos.push(Some(json!({
+                                "bool": {
+                                    "should": [
+                                        { "bool": { "must": term_phrases } },
+                                        {
+                                            "match": {
+                                                field: {
+                                                    "query": terms.join(" "),
+                                                    "operator": "and"
+                                                }
+                                            }
+                                        }
+                                    ],
+                                    "minimum_should_match": 1
+                                }
+                            })));
+                        }
+                        _ => must_conditinos.extend(term_phrases.into_iter().map(Some)),
+                    }
+                }
+            }
         }
 
         match cursor {
@@ -175,43 +573,194 @@ impl ElasticsearchQuery {
                     .and_then(|l| Some(std::cmp::min(l, MAX_LIMIT)))
                     .unwrap_or(DEFAULT_LIMIT) as i64;
 
+                let (sort, sorted) = match sort {
+                    Some(sort) => (
+                        json!([
+                            sort.clause(),
+                            { "event.created_at": { "order": "desc" } },
+                            { "event.id.keyword": { "order": "asc", "unmapped_type": "keyword" } }
+                        ]),
+                        true,
+                    ),
+                    None => (gen_sort("event.created_at", "desc"), false), // respect created_at for pre-EOSE search
+                };
                 ElasticsearchQuery {
                     query: gen_query(must_conditinos),
                     size,
-                    sort: vec!["event.created_at:desc"], // respect created_at for pre-EOSE search
+                    sort,
+                    search_after: page_cursor(&filter),
+                    sorted,
                 }
             }
             Some(cursor) => {
                 // post-EOSE query
                 // ignore `limit` of the filter and fetch in chronological order
-                let cursor_condition = Some(json!({
-                    "range": {
-                        "timestamp": {
-                            "gt": cursor.to_rfc3339()
-                        }
-                    }
-                }));
-                must_conditinos.push(cursor_condition);
+                must_conditinos.push(Some(cursor.condition()));
 
                 ElasticsearchQuery {
                     query: gen_query(must_conditinos),
                     size: MAX_LIMIT as i64,
-                    sort: vec!["timestamp:asc"], // use timestamp because events with past create_at may arrive
+                    sort: gen_sort("timestamp", "asc"), // use timestamp because events with past create_at may arrive
+                    search_after: None,
+                    sorted: false,
                 }
             }
         }
     }
 
+    /// Approximate kNN search over `embedding`, restricted by the conditions of the filter other than `search`.
+    pub fn knn_from_filter(
+        filter: Filter,
+        query_vector: Value,
+        kind_labels: &KindLabels,
+        exclude_sensitive: bool,
+    ) -> Self {
+        let size = filter
+            .limit
+            .map(|l| std::cmp::min(l, MAX_LIMIT))
+            .unwrap_or(DEFAULT_LIMIT);
+        let filter_conditions = gen_filter_conditions(&filter, kind_labels, exclude_sensitive);
+        let filter_conditions = filter_conditions.into_iter().flatten().collect::<Vec<_>>();
+
+        ElasticsearchQuery {
+            query: json!({
+                "knn": {
+                    "field": "embedding",
+                    "query_vector": query_vector,
+                    "k": size,
+                    "num_candidates": std::cmp::min(std::cmp::max(size * 2, 100), MAX_LIMIT),
+                    "filter": {
+                        "bool": {
+                            "must": filter_conditions,
+                            "must_not": gen_excluded_conditions()
+                        }
+                    }
+                }
+            }),
+            size: size as i64,
+            sort: json!(["_score", { "event.id.keyword": { "order": "asc", "unmapped_type": "keyword" } }]),
+            search_after: None,
+            sorted: false,
+        }
+    }
+
+    /// Scores results with the ranking functions instead of sorting them by `created_at`.
+    pub fn with_ranking(mut self, ranking: &RankingConfig) -> Self {
+        if self.sorted {
+            return self;
+        }
+        self.query = json!({
+            "query": {
+                "function_score": {
+                    "query": self.query["query"].clone(),
+                    "functions": ranking.functions(),
+                    "score_mode": "multiply",
+                    "boost_mode": "multiply"
+                }
+            }
+        });
+        self.sort = json!([
+            "_score",
+            { "event.created_at": { "order": "desc" } },
+            { "event.id.keyword": { "order": "asc", "unmapped_type": "keyword" } }
+        ]);
+        self
+    }
+
+    /// Fragments of the events `ids` where this query matched, marked with `<em>`.
+    pub async fn highlights(
+        &self,
+        es_client: &Elasticsearch,
+        index_name: &str,
+        ids: &[String],
+    ) -> anyhow::Result<HashMap<String, Vec<String>>> {
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let body = json!({
+            "_source": ["event.id"],
+            "query": {
+                "bool": {
+                    "must": [self.query["query"].clone()],
+                    "filter": [{ "terms": { "event.id": ids } }]
+                }
+            },
+            "highlight": {
+                "fields": { "text": {}, "texts.*": {} },
+                "fragment_size": 150,
+                "number_of_fragments": 3
+            }
+        });
+        let res = es_client
+            .search(SearchParts::Index(&[index_name]))
+            .body(body)
+            .size(ids.len() as i64)
+            .send()
+            .await?;
+        if !res.status_code().is_success() {
+            return Err(anyhow::anyhow!(
+                "unexpected status code: {}",
+                res.status_code()
+            ));
+        }
+        Ok(parse_highlights(&res.json::<Value>().await?))
+    }
+
+    /// `aggregations` of `aggs` over all the events matching this query.
+    pub async fn aggregate(
+        &self,
+        es_client: &Elasticsearch,
+        index_name: &str,
+        aggs: Value,
+    ) -> anyhow::Result<Value> {
+        let body = json!({
+            "query": self.query["query"].clone(),
+            "aggs": aggs
+        });
+        let res = es_client
+            .search(SearchParts::Index(&[index_name]))
+            .body(body)
+            .size(0)
+            .send()
+            .await?;
+        if !res.status_code().is_success() {
+            return Err(anyhow::anyhow!(
+                "unexpected status code: {}",
+                res.status_code()
+            ));
+        }
+        Ok(res.json::<Value>().await?["aggregations"].take())
+    }
+
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
     pub async fn execute(
         &self,
         es_client: &Elasticsearch,
         index_name: &String,
-        cursor: Option<DateTime<Utc>>,
-    ) -> anyhow::Result<(Vec<Event>, Option<DateTime<Utc>>)> {
+        cursor: Option<Cursor>,
+    ) -> anyhow::Result<(Vec<Event>, Option<Cursor>)> {
+        let (events, cursor, _) = self.execute_page(es_client, index_name, cursor).await?;
+        Ok((events, cursor))
+    }
+
+    /// Like `execute`, also returning the cursor of the next page if the page is full.
+    pub async fn execute_page(
+        &self,
+        es_client: &Elasticsearch,
+        index_name: &String,
+        cursor: Option<Cursor>,
+    ) -> anyhow::Result<(Vec<Event>, Option<Cursor>, Option<PageCursor>)> {
+        let mut body = self.query.clone();
+        body["sort"] = self.sort.clone();
+        if let Some(search_after) = &self.search_after {
+            body["search_after"] = json!(search_after);
+        }
         let search_response = es_client
             .search(SearchParts::Index(&[index_name.as_str()]))
-            .body(&self.query)
-            .sort(&self.sort)
+            .body(body)
             .size(self.size)
             .send()
             .await;
@@ -233,28 +782,506 @@ impl ElasticsearchQuery {
 
         let response_body = search_response.json::<Value>().await?;
 
-        let mut notes = vec![];
-        let mut latest_timestamp: Option<DateTime<Utc>> = cursor.clone();
-        for hit in response_body["hits"]["hits"]
+        let hits = response_body["hits"]["hits"]
             .as_array()
-            .unwrap_or(&vec![])
-            .iter()
-        {
+            .cloned()
+            .unwrap_or_default();
+        let next = match hits.last() {
+            Some(hit) if hits.len() as i64 == self.size => hit["sort"]
+                .as_array()
+                .map(|values| PageCursor(values.clone())),
+            _ => None,
+        };
+        let mut notes = vec![];
+        let mut latest_cursor: Option<Cursor> = cursor;
+        for hit in hits.iter() {
             let doc: Document = serde_json::from_value(hit["_source"].clone())?;
-            match latest_timestamp {
-                Some(t) => {
-                    if t < doc.timestamp {
-                        latest_timestamp = Some(doc.timestamp);
-                    }
+            latest_cursor = advance_cursor(
+                latest_cursor,
+                Cursor {
+                    timestamp: doc.timestamp,
+                    id: doc.event.id.to_hex(),
+                },
+            );
+            let note: Event = doc.raw.unwrap_or(doc.event);
+            notes.push(note);
+        }
+
+        Ok((notes, latest_cursor, next))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeZone, Utc};
+    use serde_json::json;
+
+    use crate::index::analyzer::AnalyzerConfig;
+    use crate::kind_label::KindLabels;
+    use crate::search::filter::Filter;
+    use crate::search::prefix::TagPrefixes;
+    use crate::search::query::{
+        advance_cursor, parse_highlights, Cursor, ElasticsearchQuery, PageCursor,
+    };
+    use crate::search::ranking::{DecayFunction, RankingConfig};
+
+    fn cursor(timestamp: DateTime<Utc>, id: &str) -> Cursor {
+        Cursor {
+            timestamp,
+            id: id.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_from_filter() {
+        let id = "a".repeat(64);
+        let filter = serde_json::from_value::<Filter>(json!({
+            "ids": [id, "bb"],
+            "authors": ["cc"],
+            "kinds": [1, 30023],
+            "since": 1680000000,
+            "until": 1680001000,
+            "limit": 20,
+            "#t": ["nostr"],
+            "search": "hello world"
+        }))
+        .unwrap();
+        let query = ElasticsearchQuery::from_filter(
+            filter,
+            None,
+            &AnalyzerConfig::default(),
+            &KindLabels::default(),
+            false,
+        );
+
+        assert_eq!(query.size, 20);
+        let must = query.query["query"]["bool"]["must"].as_array().unwrap();
+        let expected = vec![
+            json!({"bool": {"should": [{"terms": {"event.id": [id]}}, {"prefix": {"event.id": "bb"}}], "minimum_should_match": 1}}),
+            json!({"bool": {"should": [{"prefix": {"event.pubkey": "cc"}}], "minimum_should_match": 1}}),
+            json!({"terms": {"event.kind": [1, 30023]}}),
+            json!({"range": {"event.created_at": {"gte": 1680000000, "lte": 1680001000}}}),
+            json!({"match_phrase": {"text": "hello"}}),
+            json!({"match_phrase": {"text": "world"}}),
+            json!({"terms": {"tags.t": ["nostr"]}}),
+        ];
+        for condition in expected {
+            assert!(must.contains(&condition), "missing {}", condition);
+        }
+        assert_eq!(must.len(), 7);
+        assert_eq!(
+            query.query["query"]["bool"]["must_not"],
+            json!([{"term": {"protected": true}}])
+        );
+    }
+
+    #[test]
+    fn test_and_tags() {
+        let filter = serde_json::from_value::<Filter>(json!({
+            "&t": ["meme", "cat"],
+            "#t": ["black", "white"],
+            "&e": ["A".repeat(64)]
+        }))
+        .unwrap();
+        let query = ElasticsearchQuery::from_filter(
+            filter,
+            None,
+            &AnalyzerConfig::default(),
+            &KindLabels::default(),
+            false,
+        );
+        let must = query.query["query"]["bool"]["must"].as_array().unwrap();
+        let expected = vec![
+            json!({"terms": {"tags.t": ["meme"]}}),
+            json!({"terms": {"tags.t": ["cat"]}}),
+            json!({"terms": {"tags.t": ["black", "white"]}}),
+        ];
+        for condition in expected {
+            assert!(must.contains(&condition), "missing {}", condition);
+        }
+        assert_eq!(must.len(), 4);
+    }
+
+    #[test]
+    fn test_tag_prefixes() {
+        let mut filter = serde_json::from_value::<Filter>(json!({
+            "#r": ["https://github.com/*", "https://example.com"]
+        }))
+        .unwrap();
+        TagPrefixes::parse("r", 8, 3)
+            .unwrap()
+            .apply(&mut filter)
+            .unwrap();
+        let query = ElasticsearchQuery::from_filter(
+            filter,
+            None,
+            &AnalyzerConfig::default(),
+            &KindLabels::default(),
+            false,
+        );
+        let must = query.query["query"]["bool"]["must"].as_array().unwrap();
+        assert_eq!(
+            must,
+            &vec![json!({"bool": {"should": [
+                {"prefix": {"tags.r.prefix": "https://github.com/"}},
+                {"terms": {"tags.r": ["https://example.com"]}}
+            ], "minimum_should_match": 1}})]
+        );
+    }
+
+    #[test]
+    fn test_ref_tags() {
+        let filter = serde_json::from_value::<Filter>(json!({ "#e": ["A".repeat(64)] })).unwrap();
+        let query = ElasticsearchQuery::from_filter(
+            filter,
+            None,
+            &AnalyzerConfig::default(),
+            &KindLabels::default(),
+            false,
+        );
+        let must = query.query["query"]["bool"]["must"].as_array().unwrap();
+        assert_eq!(
+            must[0]["bool"]["should"],
+            json!([
+                { "terms": { "refs.events": ["a".repeat(64)] } },
+                { "terms": { "tags.e": ["A".repeat(64)] } }
+            ])
+        );
+    }
+
+    #[test]
+    fn test_profile_search() {
+        let filter =
+            serde_json::from_value::<Filter>(json!({"kinds": [0], "search": "alice"})).unwrap();
+        let query = ElasticsearchQuery::from_filter(
+            filter.clone(),
+            None,
+            &AnalyzerConfig::default(),
+            &KindLabels::default(),
+            false,
+        );
+        let must = query.query["query"]["bool"]["must"].as_array().unwrap();
+        assert_eq!(must.len(), 2);
+        let should = must[1]["bool"]["should"].as_array().unwrap();
+        assert!(should
+            .contains(&json!({ "prefix": { "profile.nip05": { "value": "alice", "boost": 3 } } })));
+        assert_eq!(query.sort[0], "_score");
+
+        // post-EOSE queries and other kinds use the regular text search
+        let cursor = Cursor {
+            timestamp: chrono::Utc::now(),
+            id: "a".repeat(64),
+        };
+        let query = ElasticsearchQuery::from_filter(
+            filter,
+            Some(cursor),
+            &AnalyzerConfig::default(),
+            &KindLabels::default(),
+            false,
+        );
+        let must = query.query["query"]["bool"]["must"].as_array().unwrap();
+        assert!(must.contains(&json!({"match_phrase": {"text": "alice"}})));
+
+        let filter =
+            serde_json::from_value::<Filter>(json!({"kinds": [0, 1], "search": "alice"})).unwrap();
+        let query = ElasticsearchQuery::from_filter(
+            filter,
+            None,
+            &AnalyzerConfig::default(),
+            &KindLabels::default(),
+            false,
+        );
+        let must = query.query["query"]["bool"]["must"].as_array().unwrap();
+        assert!(must.contains(&json!({"match_phrase": {"text": "alice"}})));
+    }
+
+    #[test]
+    fn test_page_cursor() {
+        let cursor = PageCursor(vec![json!(1690000000000u64), json!("ab")]);
+        assert_eq!(PageCursor::parse(&cursor.token()).unwrap(), cursor);
+        assert_eq!(PageCursor::parse("").unwrap(), PageCursor::default());
+        assert!(PageCursor::parse("abc").is_err());
+        assert!(PageCursor::parse("zz").is_err());
+
+        let filter = serde_json::from_value::<Filter>(
+            json!({"search": "a", "limit": 20, "cursor": cursor.token()}),
+        )
+        .unwrap();
+        let query = ElasticsearchQuery::from_filter(
+            filter,
+            None,
+            &AnalyzerConfig::default(),
+            &KindLabels::default(),
+            false,
+        );
+        assert_eq!(query.search_after, Some(cursor.0.clone()));
+        let ranked = query.with_ranking(&RankingConfig {
+            decay: Some(DecayFunction::Gauss),
+            decay_scale_days: 7,
+            engagement_weight: None,
+        });
+        assert_eq!(ranked.search_after, Some(cursor.0));
+
+        let filter =
+            serde_json::from_value::<Filter>(json!({"search": "a", "cursor": ""})).unwrap();
+        let query = ElasticsearchQuery::from_filter(
+            filter,
+            None,
+            &AnalyzerConfig::default(),
+            &KindLabels::default(),
+            false,
+        );
+        assert_eq!(query.search_after, None);
+        assert!(serde_json::from_value::<Filter>(json!({"search": "a", "cursor": "zz"})).is_err());
+    }
+
+    #[test]
+    fn test_with_ranking() {
+        let filter = serde_json::from_value::<Filter>(json!({"search": "hello"})).unwrap();
+        let query = ElasticsearchQuery::from_filter(
+            filter,
+            None,
+            &AnalyzerConfig::default(),
+            &KindLabels::default(),
+            false,
+        );
+        let original = query.query["query"].clone();
+        let ranking = RankingConfig {
+            decay: Some(DecayFunction::Gauss),
+            decay_scale_days: 7,
+            engagement_weight: None,
+        };
+        let query = query.with_ranking(&ranking);
+        assert_eq!(query.query["query"]["function_score"]["query"], original);
+        assert_eq!(
+            query.query["query"]["function_score"]["functions"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(query.sort[0], "_score");
+    }
+
+    #[test]
+    fn test_detected_language() {
+        let analyzer_config = AnalyzerConfig::new(
+            1,
+            2,
+            AnalyzerConfig::parse_languages("de:stemming").unwrap(),
+        )
+        .unwrap();
+        let mut filter =
+            serde_json::from_value::<Filter>(json!({"search": "kleine Häuser"})).unwrap();
+        filter.detected_language = Some("de".to_string());
+        let query = ElasticsearchQuery::from_filter(
+            filter.clone(),
+            None,
+            &analyzer_config,
+            &KindLabels::default(),
+            false,
+        );
+        let must = query.query["query"]["bool"]["must"].as_array().unwrap();
+        assert_eq!(must.len(), 1);
+        assert_eq!(
+            must[0]["bool"]["should"][1],
+            json!({"match": {"texts.de": {"query": "kleine Häuser", "operator": "and"}}})
+        );
+
+        // languages without a configured analyzer are matched by n-grams only
+        filter.detected_language = Some("fr".to_string());
+        let query = ElasticsearchQuery::from_filter(
+            filter,
+            None,
+            &analyzer_config,
+            &KindLabels::default(),
+            false,
+        );
+        let must = query.query["query"]["bool"]["must"].as_array().unwrap();
+        assert!(must.contains(&json!({"match_phrase": {"text": "kleine"}})));
+    }
+
+    #[test]
+    fn test_from_filter_limit() {
+        let filter = serde_json::from_value::<Filter>(json!({"search": "a"})).unwrap();
+        let query = ElasticsearchQuery::from_filter(
+            filter,
+            None,
+            &AnalyzerConfig::default(),
+            &KindLabels::default(),
+            false,
+        );
+        assert_eq!(query.size, 500);
+
+        let filter =
+            serde_json::from_value::<Filter>(json!({"search": "a", "limit": 100000})).unwrap();
+        let query = ElasticsearchQuery::from_filter(
+            filter,
+            None,
+            &AnalyzerConfig::default(),
+            &KindLabels::default(),
+            false,
+        );
+        assert_eq!(query.size, 10_000);
+    }
+
+    #[test]
+    fn test_search_operators() {
+        let filter = serde_json::from_value::<Filter>(json!({
+            "search": "\"hello world\" nostr -spam kind:article lang:en"
+        }))
+        .unwrap();
+        let query = ElasticsearchQuery::from_filter(
+            filter,
+            None,
+            &AnalyzerConfig::default(),
+            &KindLabels::default(),
+            false,
+        );
+        let must = query.query["query"]["bool"]["must"].as_array().unwrap();
+        let expected = vec![
+            json!({"term": {"language": "en"}}),
+            json!({"terms": {"event.kind": [30023]}}),
+            json!({"bool": {"must_not": [{"match_phrase": {"text": "spam"}}]}}),
+            json!({"match_phrase": {"text": "hello world"}}),
+            json!({"match_phrase": {"text": "nostr"}}),
+        ];
+        for condition in expected {
+            assert!(must.contains(&condition), "missing {}", condition);
+        }
+        assert_eq!(must.len(), 5);
+    }
+
+    #[test]
+    fn test_sort_by_price() {
+        let filter =
+            serde_json::from_value::<Filter>(json!({"search": "bike sort:price price:<100"}))
+                .unwrap();
+        let query = ElasticsearchQuery::from_filter(
+            filter,
+            None,
+            &AnalyzerConfig::default(),
+            &KindLabels::default(),
+            false,
+        );
+        assert_eq!(
+            query.sort[0],
+            json!({"classified.price": {"order": "asc", "missing": "_last", "unmapped_type": "double"}})
+        );
+        // the order asked for is kept over the ranking
+        let ranked = query.with_ranking(&RankingConfig {
+            decay: Some(DecayFunction::Gauss),
+            decay_scale_days: 7,
+            engagement_weight: None,
+        });
+        assert_eq!(ranked.sort[0]["classified.price"]["order"], "asc");
+    }
+
+    #[test]
+    fn test_parse_highlights() {
+        let body = json!({
+            "hits": {
+                "hits": [
+                    {
+                        "_source": { "event": { "id": "a" } },
+                        "highlight": {
+                            "text": ["<em>hello</em> world"],
+                            "texts.en": ["<em>hellos</em>"]
+                        }
+                    },
+                    { "_source": { "event": { "id": "b" } } }
+                ]
+            }
+        });
+        let highlights = parse_highlights(&body);
+        assert_eq!(highlights.len(), 1);
+        let mut fragments = highlights["a"].clone();
+        fragments.sort();
+        assert_eq!(fragments, vec!["<em>hello</em> world", "<em>hellos</em>"]);
+    }
+
+    #[test]
+    fn test_exclude_sensitive() {
+        let excluded = json!({"bool": {"must_not": {"term": {"sensitive": true}}}});
+        let must = |search: &str, exclude_sensitive: bool| {
+            let filter = serde_json::from_value::<Filter>(json!({ "search": search })).unwrap();
+            let query = ElasticsearchQuery::from_filter(
+                filter,
+                None,
+                &AnalyzerConfig::default(),
+                &KindLabels::default(),
+                exclude_sensitive,
+            );
+            query.query["query"]["bool"]["must"]
+                .as_array()
+                .unwrap()
+                .clone()
+        };
+        assert!(must("hello", true).contains(&excluded));
+        assert!(!must("hello nsfw:true", true).contains(&excluded));
+        assert!(!must("hello", false).contains(&excluded));
+        assert!(must("hello nsfw:false", false).contains(&excluded));
+        assert!(!must("hello nsfw:true", true)
+            .contains(&json!({"match_phrase": {"text": "nsfw:true"}})));
+    }
+
+    #[test]
+    fn test_advance_cursor() {
+        let t0 = Utc.timestamp_opt(1_680_000_000, 0).unwrap();
+        let t1 = Utc.timestamp_opt(1_680_000_001, 0).unwrap();
+
+        assert_eq!(advance_cursor(None, cursor(t0, "b")), Some(cursor(t0, "b")));
+        // same timestamp: the larger id wins
+        assert_eq!(
+            advance_cursor(Some(cursor(t0, "b")), cursor(t0, "a")),
+            Some(cursor(t0, "b"))
+        );
+        assert_eq!(
+            advance_cursor(Some(cursor(t0, "b")), cursor(t0, "c")),
+            Some(cursor(t0, "c"))
+        );
+        // newer timestamp wins regardless of id
+        assert_eq!(
+            advance_cursor(Some(cursor(t0, "z")), cursor(t1, "a")),
+            Some(cursor(t1, "a"))
+        );
+    }
+
+    #[test]
+    fn test_paging_with_ties() {
+        // emulates the post-EOSE query: sort by (timestamp, id) and take documents after the cursor
+        let t0 = Utc.timestamp_opt(1_680_000_000, 0).unwrap();
+        let t1 = Utc.timestamp_opt(1_680_000_001, 0).unwrap();
+        let mut docs = vec![
+            cursor(t1, "03"),
+            cursor(t0, "05"),
+            cursor(t0, "01"),
+            cursor(t1, "01"),
+            cursor(t0, "04"),
+            cursor(t0, "02"),
+            cursor(t1, "02"),
+        ];
+        docs.sort();
+
+        for page_size in 1..=docs.len() {
+            let mut seen = vec![];
+            let mut current: Option<Cursor> = None;
+            loop {
+                let page = docs
+                    .iter()
+                    .filter(|d| current.as_ref().map(|c| *d > c).unwrap_or(true))
+                    .take(page_size)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if page.is_empty() {
+                    break;
                 }
-                None => {
-                    latest_timestamp = Some(doc.timestamp);
+                for doc in page {
+                    current = advance_cursor(current, doc.clone());
+                    seen.push(doc);
                 }
             }
-            let note: Event = doc.event;
-            notes.push(note);
+            assert_eq!(seen, docs, "page size {}", page_size);
         }
-
-        Ok((notes, latest_timestamp))
     }
 }
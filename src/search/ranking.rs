@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use nostr_sdk::Event;
+use serde_json::{json, Value};
+
+use crate::app_state::AppState;
+use crate::index::followers::follower_counts;
+use crate::index::reactions::{reaction_counts, ReactionCounts};
+use crate::index::zaps::zap_totals;
+use crate::search::hybrid::fuse;
+
+const RANK_CONSTANT: f64 = 60.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecayFunction {
+    Gauss,
+    Exp,
+}
+
+impl FromStr for DecayFunction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gauss" => Ok(DecayFunction::Gauss),
+            "exp" => Ok(DecayFunction::Exp),
+            _ => Err(anyhow::anyhow!("unknown decay function: {}", s)),
+        }
+    }
+}
+
+/// Scoring of pre-EOSE results, replacing the reverse chronological order when enabled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RankingConfig {
+    /// decay of the score with the age of events
+    pub decay: Option<DecayFunction>,
+    /// age at which the decay halves the score
+    pub decay_scale_days: u64,
+    /// boost from reaction and repost counts; also enables counting them
+    pub engagement_weight: Option<f64>,
+}
+
+impl RankingConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.decay.is_some() || self.engagement_weight.is_some()
+    }
+
+    /// Functions of a `function_score` query, multiplied with the text score.
+    pub fn functions(&self) -> Vec<Value> {
+        let mut functions = vec![];
+        if let Some(decay) = self.decay {
+            let name = match decay {
+                DecayFunction::Gauss => "gauss",
+                DecayFunction::Exp => "exp",
+            };
+            functions.push(json!({
+                name: {
+                    "event.created_at": {
+                        "origin": "now",
+                        "scale": format!("{}d", self.decay_scale_days),
+                        "decay": 0.5
+                    }
+                }
+            }));
+        }
+        if let Some(weight) = self.engagement_weight {
+            // log2p keeps events without engagement at a positive factor
+            for (field, factor) in [
+                ("engagement.reactions", weight),
+                ("engagement.reposts", weight * 2.0),
+            ] {
+                functions.push(json!({
+                    "field_value_factor": {
+                        "field": field,
+                        "modifier": "log2p",
+                        "factor": factor,
+                        "missing": 0
+                    }
+                }));
+            }
+        }
+        functions
+    }
+}
+
+/// Orders events by the follower count of their authors, most followed first; stable otherwise.
+fn by_followers(events: &[Event], counts: &HashMap<String, u64>) -> Vec<Event> {
+    let mut events = events.to_vec();
+    events.sort_by_key(|event| {
+        std::cmp::Reverse(counts.get(&event.pubkey.to_string()).copied().unwrap_or(0))
+    });
+    events
+}
+
+/// Boosts events of well-followed authors by fusing the given order with the order by
+/// follower count, weighted by `weight`.
+pub async fn boost_by_followers(state: &AppState, weight: f64, events: Vec<Event>) -> Vec<Event> {
+    let mut pubkeys = events
+        .iter()
+        .map(|event| event.pubkey.to_string())
+        .collect::<Vec<_>>();
+    pubkeys.sort();
+    pubkeys.dedup();
+    let counts = match follower_counts(&state.es_client, &state.index_alias_name, &pubkeys).await {
+        Ok(counts) => counts,
+        Err(e) => {
+            log::warn!("failed to get follower counts; not boosting: {}", e);
+            return events;
+        }
+    };
+    let limit = events.len();
+    let followed = by_followers(&events, &counts);
+    fuse(
+        vec![(1.0, events), (weight, followed)],
+        RANK_CONSTANT,
+        limit,
+    )
+}
+
+/// Orders events by the sats zapped to them, most zapped first; stable otherwise.
+fn by_zaps(events: &[Event], totals: &HashMap<String, u64>) -> Vec<Event> {
+    let mut events = events.to_vec();
+    events.sort_by_key(|event| {
+        std::cmp::Reverse(totals.get(&event.id.to_hex()).copied().unwrap_or(0))
+    });
+    events
+}
+
+/// Boosts zapped events by fusing the given order with the order by sats zapped, weighted by
+/// `weight`.
+pub async fn boost_by_zaps(state: &AppState, weight: f64, events: Vec<Event>) -> Vec<Event> {
+    let ids = events
+        .iter()
+        .map(|event| event.id.to_hex())
+        .collect::<Vec<_>>();
+    let totals = match zap_totals(&state.es_client, &state.index_alias_name, &ids).await {
+        Ok(totals) => totals,
+        Err(e) => {
+            log::warn!("failed to get zap totals; not boosting: {}", e);
+            return events;
+        }
+    };
+    let limit = events.len();
+    let zapped = by_zaps(&events, &totals);
+    fuse(vec![(1.0, events), (weight, zapped)], RANK_CONSTANT, limit)
+}
+
+/// Orders events by their likes, most liked first; stable otherwise.
+pub(crate) fn by_likes(events: &[Event], counts: &HashMap<String, ReactionCounts>) -> Vec<Event> {
+    let mut events = events.to_vec();
+    events.sort_by_key(|event| {
+        std::cmp::Reverse(
+            counts
+                .get(&event.id.to_hex())
+                .map_or(0, |counts| counts.likes),
+        )
+    });
+    events
+}
+
+/// Boosts liked events by fusing the given order with the order by likes, weighted by
+/// `weight`.
+pub async fn boost_by_reactions(state: &AppState, weight: f64, events: Vec<Event>) -> Vec<Event> {
+    let ids = events
+        .iter()
+        .map(|event| event.id.to_hex())
+        .collect::<Vec<_>>();
+    let counts = match reaction_counts(&state.es_client, &state.index_alias_name, &ids).await {
+        Ok(counts) => counts,
+        Err(e) => {
+            log::warn!("failed to get reaction counts; not boosting: {}", e);
+            return events;
+        }
+    };
+    let limit = events.len();
+    let liked = by_likes(&events, &counts);
+    fuse(vec![(1.0, events), (weight, liked)], RANK_CONSTANT, limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use nostr_sdk::{EventBuilder, Keys, Kind};
+
+    use serde_json::json;
+
+    use crate::search::ranking::{by_followers, by_zaps, DecayFunction, RankingConfig};
+
+    #[test]
+    fn test_functions() {
+        let config = RankingConfig {
+            decay: None,
+            decay_scale_days: 7,
+            engagement_weight: None,
+        };
+        assert!(!config.is_enabled());
+        assert!(config.functions().is_empty());
+
+        let config = RankingConfig {
+            decay: Some(DecayFunction::Exp),
+            decay_scale_days: 3,
+            engagement_weight: Some(1.0),
+        };
+        let functions = config.functions();
+        assert_eq!(functions.len(), 3);
+        assert_eq!(
+            functions[0],
+            json!({"exp": {"event.created_at": {"origin": "now", "scale": "3d", "decay": 0.5}}})
+        );
+        assert_eq!(
+            functions[2]["field_value_factor"]["field"],
+            "engagement.reposts"
+        );
+    }
+
+    #[test]
+    fn test_by_followers() {
+        let keys = (0..3).map(|_| Keys::generate()).collect::<Vec<_>>();
+        let events = keys
+            .iter()
+            .map(|k| {
+                EventBuilder::new(Kind::TextNote, "hello", &[])
+                    .to_event(k)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let counts = HashMap::from([
+            (keys[1].public_key().to_string(), 100),
+            (keys[2].public_key().to_string(), 5),
+        ]);
+        let ids = by_followers(&events, &counts)
+            .iter()
+            .map(|e| e.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![events[1].id, events[2].id, events[0].id]);
+    }
+
+    #[test]
+    fn test_by_zaps() {
+        let keys = Keys::generate();
+        let events = (0..3)
+            .map(|i| {
+                EventBuilder::new(Kind::TextNote, format!("hello {}", i), &[])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let totals = HashMap::from([(events[2].id.to_hex(), 21)]);
+        let ids = by_zaps(&events, &totals)
+            .iter()
+            .map(|e| e.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![events[2].id, events[0].id, events[1].id]);
+    }
+}
@@ -0,0 +1,96 @@
+use elasticsearch::{Elasticsearch, SearchParts};
+use serde_json::{json, Value};
+
+/// Phrase suggester request over `text.suggest`, which keeps whole words unlike the n-gram `text`.
+fn suggest_body(text: &str) -> Value {
+    json!({
+        "size": 0,
+        "suggest": {
+            "text": text,
+            "correction": {
+                "phrase": {
+                    "field": "text.suggest",
+                    "size": 1,
+                    "gram_size": 1,
+                    "direct_generator": [
+                        {
+                            "field": "text.suggest",
+                            "suggest_mode": "always"
+                        }
+                    ]
+                }
+            }
+        }
+    })
+}
+
+/// Extracts the best correction, ignoring suggestions identical to the input.
+fn parse_suggestion(text: &str, response_body: &Value) -> Option<String> {
+    let suggestion = response_body["suggest"]["correction"][0]["options"][0]["text"].as_str()?;
+    if suggestion.to_lowercase() == text.to_lowercase() {
+        None
+    } else {
+        Some(suggestion.to_string())
+    }
+}
+
+/// Returns a corrected search string ("did you mean") for `text`, if any.
+pub async fn suggest(
+    es_client: &Elasticsearch,
+    index_name: &str,
+    text: &str,
+) -> anyhow::Result<Option<String>> {
+    let res = es_client
+        .search(SearchParts::Index(&[index_name]))
+        .body(suggest_body(text))
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        return Err(anyhow::anyhow!(
+            "unexpected status code: {}",
+            res.status_code()
+        ));
+    }
+    let response_body = res.json::<Value>().await?;
+    Ok(parse_suggestion(text, &response_body))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::search::suggest::{parse_suggestion, suggest_body};
+
+    #[test]
+    fn test_suggest_body() {
+        let body = suggest_body("nostr realy");
+        assert_eq!(body["size"], 0);
+        assert_eq!(body["suggest"]["text"], "nostr realy");
+        assert_eq!(
+            body["suggest"]["correction"]["phrase"]["field"],
+            "text.suggest"
+        );
+    }
+
+    #[test]
+    fn test_parse_suggestion() {
+        let res = json!({
+            "suggest": {
+                "correction": [
+                    { "text": "nostr realy", "options": [{ "text": "nostr relay", "score": 0.5 }] }
+                ]
+            }
+        });
+        assert_eq!(
+            parse_suggestion("nostr realy", &res),
+            Some("nostr relay".to_string())
+        );
+        assert_eq!(parse_suggestion("Nostr Relay", &res), None);
+
+        let res = json!({
+            "suggest": { "correction": [{ "text": "nostr", "options": [] }] }
+        });
+        assert_eq!(parse_suggestion("nostr", &res), None);
+        assert_eq!(parse_suggestion("nostr", &json!({})), None);
+    }
+}
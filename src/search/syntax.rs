@@ -0,0 +1,742 @@
+use chrono::{Datelike, Duration, NaiveDate, Utc, Weekday};
+use nostr_sdk::prelude::{FromBech32, XOnlyPublicKey};
+use nostr_sdk::Event;
+use serde_json::{json, Value};
+
+use crate::index::classified::extract_classified;
+use crate::index::delegation::author;
+use crate::index::geo::{distance_meters, extract_geo, parse_distance, parse_point, GeoPoint};
+use crate::index::media::extract_media;
+use crate::index::occurrence::extract_occurrence;
+use crate::index::refs::normalize_ref;
+use crate::index::urls::{extract_links, url_host};
+use crate::kind_label::KindLabels;
+
+/// Search string of a filter with its operators split off.
+///
+/// Words are separated by whitespace. `"..."` is an exact phrase and a leading `-` excludes a
+/// word or phrase. The operators are `language:<code>` (or `lang:`), `from:<npub or hex>`,
+/// `kind:<number or label>`, `since:<YYYY-MM-DD>`, `until:<YYYY-MM-DD>`, `nsfw:<true|false>`,
+/// `domain:<host>`, `has:<image|video|audio|media>`, `highlight:true`, and for classified listings
+/// `price:<range>`, `currency:<code>`, `location:<word>` and `sort:<price|-price>`, and for live
+/// activities and calendar events `happening:<period>`, and `near:<geohash or lat,lon>` with
+/// `within:<distance>`, and `min_zaps:<sats>`; operators with invalid values are searched as
+/// words.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchQuery {
+    pub terms: Vec<String>,
+    pub phrases: Vec<String>,
+    /// words and phrases that must not appear
+    pub excluded: Vec<String>,
+    pub language: Option<String>,
+    /// hex pubkeys of `from:`
+    pub authors: Vec<String>,
+    /// operands of `kind:`, resolved by `kinds`
+    pub kinds: Vec<String>,
+    /// unix time of the start of the `since:` day
+    pub since: Option<u64>,
+    /// unix time of the end of the `until:` day
+    pub until: Option<u64>,
+    pub nsfw: Option<bool>,
+    /// linked domains of `domain:`, lowercase without `www.`
+    pub domains: Vec<String>,
+    /// media types of `has:`, all of which must be attached
+    pub has: Vec<String>,
+    /// whether highlighted fragments are sent along with the events
+    pub highlight: bool,
+    pub price: Option<PriceRange>,
+    /// uppercase currency code of `currency:`
+    pub currency: Option<String>,
+    /// words of `location:`, all of which must be in the location of a listing
+    pub locations: Vec<String>,
+    /// order of the results before EOSE instead of `created_at`
+    pub sort: Option<Sort>,
+    /// unix times of the start and end of `happening:`, inclusive
+    pub happening: Option<(u64, u64)>,
+    pub near: Option<GeoPoint>,
+    /// meters of `within:`; `DEFAULT_WITHIN_METERS` around `near` if `None`
+    pub within: Option<f64>,
+    /// sats zapped of `min_zaps:`, resolved to event ids by the zap totals
+    pub min_zaps: Option<u64>,
+}
+
+/// radius of `near:` without `within:`
+pub const DEFAULT_WITHIN_METERS: f64 = 10_000.0;
+
+/// Bounds of `price:`, each with whether it is inclusive, e.g. `price:<100`, `price:>=10`,
+/// `price:10..50` or `price:25`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PriceRange {
+    pub min: Option<(f64, bool)>,
+    pub max: Option<(f64, bool)>,
+}
+
+impl PriceRange {
+    fn parse(value: &str) -> Option<Self> {
+        let number = |value: &str| value.parse::<f64>().ok().filter(|n| n.is_finite());
+        let range = if let Some(value) = value.strip_prefix("<=") {
+            PriceRange {
+                min: None,
+                max: Some((number(value)?, true)),
+            }
+        } else if let Some(value) = value.strip_prefix('<') {
+            PriceRange {
+                min: None,
+                max: Some((number(value)?, false)),
+            }
+        } else if let Some(value) = value.strip_prefix(">=") {
+            PriceRange {
+                min: Some((number(value)?, true)),
+                max: None,
+            }
+        } else if let Some(value) = value.strip_prefix('>') {
+            PriceRange {
+                min: Some((number(value)?, false)),
+                max: None,
+            }
+        } else if let Some((min, max)) = value.split_once("..") {
+            PriceRange {
+                min: Some((number(min)?, true)),
+                max: Some((number(max)?, true)),
+            }
+        } else {
+            let price = number(value)?;
+            PriceRange {
+                min: Some((price, true)),
+                max: Some((price, true)),
+            }
+        };
+        Some(range)
+    }
+
+    fn condition(&self) -> Value {
+        let mut range = json!({});
+        if let Some((min, inclusive)) = self.min {
+            range[if inclusive { "gte" } else { "gt" }] = json!(min);
+        }
+        if let Some((max, inclusive)) = self.max {
+            range[if inclusive { "lte" } else { "lt" }] = json!(max);
+        }
+        json!({ "range": { "classified.price": range } })
+    }
+
+    pub fn contains(&self, price: f64) -> bool {
+        let above = match self.min {
+            Some((min, true)) => price >= min,
+            Some((min, false)) => price > min,
+            None => true,
+        };
+        let below = match self.max {
+            Some((max, true)) => price <= max,
+            Some((max, false)) => price < max,
+            None => true,
+        };
+        above && below
+    }
+}
+
+/// Order of `sort:`; results without the field come last.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sort {
+    PriceAscending,
+    PriceDescending,
+}
+
+impl Sort {
+    /// Primary sort clause; the field is missing in indices created before it was mapped.
+    pub fn clause(&self) -> Value {
+        let order = match self {
+            Sort::PriceAscending => "asc",
+            Sort::PriceDescending => "desc",
+        };
+        json!({
+            "classified.price": { "order": order, "missing": "_last", "unmapped_type": "double" }
+        })
+    }
+}
+
+/// Words and quoted phrases, each flagged whether it is quoted.
+fn tokenize(search: &str) -> Vec<(String, bool)> {
+    let mut tokens = vec![];
+    let mut chars = search.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut token = String::new();
+        if c == '-' {
+            token.push(c);
+            chars.next();
+        }
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            for c in chars.by_ref() {
+                if c == '"' {
+                    break;
+                }
+                token.push(c);
+            }
+            tokens.push((token, true));
+            continue;
+        }
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                break;
+            }
+            token.push(c);
+            chars.next();
+        }
+        tokens.push((token, false));
+    }
+    tokens
+}
+
+fn parse_pubkey(value: &str) -> Option<String> {
+    if let Some(hex) = normalize_ref(value) {
+        return Some(hex);
+    }
+    XOnlyPublicKey::from_bech32(value)
+        .ok()
+        .map(|pubkey| pubkey.to_string())
+}
+
+fn parse_date(value: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()
+}
+
+/// Days of `happening:`, in UTC: `today`, `tomorrow`, `weekend` (the coming or current one),
+/// `week` (the next 7 days), `YYYY-MM-DD` or `YYYY-MM-DD..YYYY-MM-DD`.
+fn parse_period(value: &str, today: NaiveDate) -> Option<(u64, u64)> {
+    let (first, last) = match value {
+        "today" => (today, today),
+        "tomorrow" => (today + Duration::days(1), today + Duration::days(1)),
+        "weekend" => {
+            let saturday = match today.weekday() {
+                Weekday::Sun => today - Duration::days(1),
+                weekday => today + Duration::days(5 - weekday.num_days_from_monday() as i64),
+            };
+            (saturday.max(today), saturday + Duration::days(1))
+        }
+        "week" => (today, today + Duration::days(6)),
+        _ => match value.split_once("..") {
+            Some((first, last)) => (parse_date(first)?, parse_date(last)?),
+            None => (parse_date(value)?, parse_date(value)?),
+        },
+    };
+    if first > last {
+        return None;
+    }
+    Some((
+        first.and_hms_opt(0, 0, 0)?.timestamp() as u64,
+        last.and_hms_opt(23, 59, 59)?.timestamp() as u64,
+    ))
+}
+
+impl SearchQuery {
+    pub fn parse(search: &str) -> Self {
+        let mut query = SearchQuery::default();
+        for (token, quoted) in tokenize(search) {
+            let (token, negated) = match token.strip_prefix('-') {
+                Some(rest) if !rest.is_empty() => (rest.to_string(), true),
+                _ => (token, false),
+            };
+            if token.is_empty() {
+                continue;
+            }
+            if quoted {
+                if negated {
+                    query.excluded.push(token);
+                } else {
+                    query.phrases.push(token);
+                }
+                continue;
+            }
+            if !negated && query.parse_operator(&token) {
+                continue;
+            }
+            if negated {
+                query.excluded.push(token);
+            } else {
+                query.terms.push(token);
+            }
+        }
+        query
+    }
+
+    fn parse_operator(&mut self, token: &str) -> bool {
+        let (name, value) = match token.split_once(':') {
+            Some((name, value)) if !value.is_empty() => (name, value),
+            _ => return false,
+        };
+        match name {
+            "language" | "lang" => self.language = Some(value.to_string()),
+            "from" => match parse_pubkey(value) {
+                Some(pubkey) => self.authors.push(pubkey),
+                None => return false,
+            },
+            "kind" => self.kinds.push(value.to_string()),
+            "since" => match parse_date(value) {
+                Some(date) => {
+                    self.since = Some(date.and_hms_opt(0, 0, 0).unwrap().timestamp() as u64)
+                }
+                None => return false,
+            },
+            "until" => match parse_date(value) {
+                Some(date) => {
+                    self.until = Some(date.and_hms_opt(23, 59, 59).unwrap().timestamp() as u64)
+                }
+                None => return false,
+            },
+            "domain" => match url_host(&format!("https://{}", value)) {
+                Some(domain) => self.domains.push(domain),
+                None => return false,
+            },
+            "has" if ["image", "video", "audio", "media"].contains(&value) => {
+                self.has.push(value.to_string())
+            }
+            "highlight" if value == "true" => self.highlight = true,
+            "price" => match PriceRange::parse(value) {
+                Some(range) => self.price = Some(range),
+                None => return false,
+            },
+            "currency" if value.chars().all(|c| c.is_ascii_alphabetic()) => {
+                self.currency = Some(value.to_uppercase())
+            }
+            "location" => self.locations.push(value.to_lowercase()),
+            "happening" => match parse_period(value, Utc::now().date_naive()) {
+                Some(period) => self.happening = Some(period),
+                None => return false,
+            },
+            "near" => match parse_point(value) {
+                Some(point) => self.near = Some(point),
+                None => return false,
+            },
+            "within" => match parse_distance(value) {
+                Some(meters) => self.within = Some(meters),
+                None => return false,
+            },
+            "min_zaps" => match value.parse::<u64>() {
+                Ok(sats) => self.min_zaps = Some(sats),
+                Err(_) => return false,
+            },
+            "sort" => match value {
+                "price" => self.sort = Some(Sort::PriceAscending),
+                "-price" => self.sort = Some(Sort::PriceDescending),
+                _ => return false,
+            },
+            "nsfw" => match value {
+                "true" => self.nsfw = Some(true),
+                "false" => self.nsfw = Some(false),
+                _ => return false,
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    /// Words and phrases searched for, e.g. for embeddings and suggestions.
+    pub fn text(&self) -> String {
+        self.terms
+            .iter()
+            .chain(self.phrases.iter())
+            .map(|s| s.as_str())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Kinds of `kind:`; unknown labels are left out, so that they match nothing.
+    pub fn kinds(&self, kind_labels: &KindLabels) -> Vec<u32> {
+        self.kinds
+            .iter()
+            .filter_map(|kind| kind_labels.kind(kind))
+            .collect()
+    }
+
+    /// Conditions of the `from:`, `kind:`, `since:`, `until:`, `domain:`, `has:`, `price:`,
+    /// `currency:`, `location:`, `happening:` and `near:` operators and the exclusions.
+    pub fn conditions(&self, kind_labels: &KindLabels) -> Vec<Value> {
+        let mut conditions = vec![];
+        if !self.authors.is_empty() {
+            conditions.push(json!({
+                "bool": {
+                    "should": [
+                        { "terms": { "event.pubkey": self.authors } },
+                        { "terms": { "delegator": self.authors } }
+                    ],
+                    "minimum_should_match": 1
+                }
+            }));
+        }
+        if !self.kinds.is_empty() {
+            conditions.push(json!({
+                "terms": {
+                    "event.kind": self.kinds(kind_labels)
+                }
+            }));
+        }
+        if self.since.is_some() || self.until.is_some() {
+            let mut range = json!({});
+            if let Some(since) = self.since {
+                range["gte"] = json!(since);
+            }
+            if let Some(until) = self.until {
+                range["lte"] = json!(until);
+            }
+            conditions.push(json!({
+                "range": {
+                    "event.created_at": range
+                }
+            }));
+        }
+        if !self.domains.is_empty() {
+            conditions.push(json!({
+                "terms": {
+                    "domains": self.domains
+                }
+            }));
+        }
+        for kind in &self.has {
+            conditions.push(if kind == "media" {
+                json!({ "exists": { "field": "media" } })
+            } else {
+                json!({ "prefix": { "media.mime_type": format!("{}/", kind) } })
+            });
+        }
+        if let Some(price) = &self.price {
+            conditions.push(price.condition());
+        }
+        if let Some(currency) = &self.currency {
+            conditions.push(json!({ "term": { "classified.currency": currency } }));
+        }
+        for location in &self.locations {
+            conditions.push(json!({ "match": { "classified.location": location } }));
+        }
+        if let Some((from, to)) = self.happening {
+            // overlapping the period; occurrences without an end are taken to be instants
+            conditions.push(json!({
+                "bool": {
+                    "filter": [{ "range": { "occurrence.starts": { "lte": to } } }],
+                    "should": [
+                        { "range": { "occurrence.ends": { "gte": from } } },
+                        {
+                            "bool": {
+                                "must_not": [{ "exists": { "field": "occurrence.ends" } }],
+                                "filter": [{ "range": { "occurrence.starts": { "gte": from } } }]
+                            }
+                        }
+                    ],
+                    "minimum_should_match": 1
+                }
+            }));
+        }
+        if let Some(near) = &self.near {
+            let meters = self.within.unwrap_or(DEFAULT_WITHIN_METERS);
+            conditions.push(json!({
+                // indices created before the geo mapping match nothing instead of failing
+                "geo_distance": {
+                    "distance": format!("{}m", meters),
+                    "geo": near,
+                    "ignore_unmapped": true
+                }
+            }));
+        }
+        if !self.excluded.is_empty() {
+            let excluded = self
+                .excluded
+                .iter()
+                .map(|s| json!({ "match_phrase": { "text": s } }))
+                .collect::<Vec<_>>();
+            conditions.push(json!({
+                "bool": {
+                    "must_not": excluded
+                }
+            }));
+        }
+        conditions
+    }
+
+    /// Tests the operators and the lowercase `text` of an event, approximating the conditions.
+    pub fn matches(&self, event: &Event, text: &str, kind_labels: &KindLabels) -> bool {
+        // new events have not been zapped yet
+        if self.min_zaps.is_some() {
+            return false;
+        }
+        if !self.authors.is_empty() && !self.authors.contains(&author(event)) {
+            return false;
+        }
+        if !self.kinds.is_empty() && !self.kinds(kind_labels).contains(&event.kind.as_u32()) {
+            return false;
+        }
+        if !self.domains.is_empty()
+            && !extract_links(event)
+                .domains
+                .iter()
+                .any(|domain| self.domains.contains(domain))
+        {
+            return false;
+        }
+        if !self.has.is_empty() {
+            let media = extract_media(event);
+            if !self
+                .has
+                .iter()
+                .all(|kind| media.iter().any(|media| media.is(kind)))
+            {
+                return false;
+            }
+        }
+        if self.price.is_some() || self.currency.is_some() || !self.locations.is_empty() {
+            let classified = match extract_classified(event) {
+                Some(classified) => classified,
+                None => return false,
+            };
+            if let Some(price) = &self.price {
+                if !classified.price.map_or(false, |p| price.contains(p)) {
+                    return false;
+                }
+            }
+            if self.currency.is_some() && classified.currency != self.currency {
+                return false;
+            }
+            let location = classified.location.unwrap_or_default().to_lowercase();
+            if !self.locations.iter().all(|l| location.contains(l.as_str())) {
+                return false;
+            }
+        }
+        if let Some((from, to)) = self.happening {
+            let overlaps = extract_occurrence(event).map_or(false, |occurrence| {
+                match (occurrence.starts, occurrence.ends) {
+                    (Some(starts), Some(ends)) => starts <= to && ends >= from,
+                    (Some(starts), None) => starts <= to && starts >= from,
+                    _ => false,
+                }
+            });
+            if !overlaps {
+                return false;
+            }
+        }
+        if let Some(near) = &self.near {
+            let meters = self.within.unwrap_or(DEFAULT_WITHIN_METERS);
+            if !extract_geo(event).map_or(false, |geo| distance_meters(near, &geo) <= meters) {
+                return false;
+            }
+        }
+        let created_at = event.created_at.as_u64();
+        if self.since.map(|since| created_at < since).unwrap_or(false)
+            || self.until.map(|until| created_at > until).unwrap_or(false)
+        {
+            return false;
+        }
+        self.terms
+            .iter()
+            .chain(self.phrases.iter())
+            .all(|s| text.contains(&s.to_lowercase()))
+            && !self
+                .excluded
+                .iter()
+                .any(|s| text.contains(&s.to_lowercase()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::prelude::ToBech32;
+    use nostr_sdk::Keys;
+    use serde_json::json;
+
+    use crate::kind_label::KindLabels;
+    use chrono::NaiveDate;
+
+    use crate::search::syntax::{parse_period, PriceRange, SearchQuery, Sort};
+
+    #[test]
+    fn test_parse() {
+        let query = SearchQuery::parse(r#"hello "nostr world"  -spam -"buy now" lang:ja"#);
+        assert_eq!(query.terms, vec!["hello"]);
+        assert_eq!(query.phrases, vec!["nostr world"]);
+        assert_eq!(query.excluded, vec!["spam", "buy now"]);
+        assert_eq!(query.language, Some("ja".to_string()));
+        assert_eq!(query.text(), "hello nostr world");
+
+        let query = SearchQuery::parse("language:en kind:30023 kind:note since:2024-01-01");
+        assert_eq!(query.language, Some("en".to_string()));
+        assert_eq!(query.kinds(&KindLabels::default()), vec![30023, 1]);
+        assert_eq!(query.since, Some(1704067200));
+        assert!(query.terms.is_empty());
+
+        // invalid operands are searched as words
+        let query = SearchQuery::parse("language: since:yesterday from:alice nsfw:maybe - x:y");
+        assert_eq!(
+            query.terms,
+            vec![
+                "language:",
+                "since:yesterday",
+                "from:alice",
+                "nsfw:maybe",
+                "-",
+                "x:y"
+            ]
+        );
+        assert_eq!(SearchQuery::parse("hello nsfw:true").nsfw, Some(true));
+        assert_eq!(SearchQuery::parse("nsfw:false hello").nsfw, Some(false));
+        assert_eq!(SearchQuery::parse("hello").nsfw, None);
+        assert!(SearchQuery::parse("hello highlight:true").highlight);
+        assert_eq!(
+            SearchQuery::parse("rust domain:www.GitHub.com domain:localhost").domains,
+            vec!["github.com"]
+        );
+        assert_eq!(
+            SearchQuery::parse("cats has:image has:photo").has,
+            vec!["image"]
+        );
+        assert_eq!(
+            SearchQuery::parse("highlight:maybe").terms,
+            vec!["highlight:maybe"]
+        );
+    }
+
+    #[test]
+    fn test_from() {
+        let keys = Keys::generate();
+        let hex = keys.public_key().to_string();
+        let npub = keys.public_key().to_bech32().unwrap();
+        let query = SearchQuery::parse(&format!("from:{} from:{}", npub, hex.to_uppercase()));
+        assert_eq!(query.authors, vec![hex.clone(), hex]);
+
+        let nsec = keys.secret_key().unwrap().to_bech32().unwrap();
+        assert!(SearchQuery::parse(&format!("from:{}", nsec))
+            .authors
+            .is_empty());
+    }
+
+    #[test]
+    fn test_conditions() {
+        let query = SearchQuery::parse(
+            "kind:article kind:unknown until:2024-01-01 domain:github.com has:image -spam",
+        );
+        assert_eq!(
+            query.conditions(&KindLabels::default()),
+            vec![
+                json!({"terms": {"event.kind": [30023]}}),
+                json!({"range": {"event.created_at": {"lte": 1704153599}}}),
+                json!({"terms": {"domains": ["github.com"]}}),
+                json!({"prefix": {"media.mime_type": "image/"}}),
+                json!({"bool": {"must_not": [{"match_phrase": {"text": "spam"}}]}}),
+            ]
+        );
+        assert!(SearchQuery::parse("hello")
+            .conditions(&KindLabels::default())
+            .is_empty());
+    }
+
+    #[test]
+    fn test_classified_operators() {
+        let query =
+            SearchQuery::parse("bike price:10..50.5 currency:usd location:Tokyo sort:-price");
+        assert_eq!(query.terms, vec!["bike"]);
+        assert_eq!(
+            query.price,
+            Some(PriceRange {
+                min: Some((10.0, true)),
+                max: Some((50.5, true))
+            })
+        );
+        assert_eq!(query.sort, Some(Sort::PriceDescending));
+        assert_eq!(
+            query.conditions(&KindLabels::default()),
+            vec![
+                json!({"range": {"classified.price": {"gte": 10.0, "lte": 50.5}}}),
+                json!({"term": {"classified.currency": "USD"}}),
+                json!({"match": {"classified.location": "tokyo"}}),
+            ]
+        );
+
+        let price = SearchQuery::parse("price:<100").price.unwrap();
+        assert!(price.contains(99.9));
+        assert!(!price.contains(100.0));
+        assert!(SearchQuery::parse("price:>=100")
+            .price
+            .unwrap()
+            .contains(100.0));
+        assert_eq!(
+            SearchQuery::parse("price:cheap currency:u$d sort:date").terms,
+            vec!["price:cheap", "currency:u$d", "sort:date"]
+        );
+    }
+
+    #[test]
+    fn test_parse_period() {
+        let day = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+        let period = |first: &str, last: &str| {
+            Some((
+                day(first).and_hms_opt(0, 0, 0).unwrap().timestamp() as u64,
+                day(last).and_hms_opt(23, 59, 59).unwrap().timestamp() as u64,
+            ))
+        };
+        // 2024-03-13 is a Wednesday
+        let wednesday = day("2024-03-13");
+        assert_eq!(
+            parse_period("weekend", wednesday),
+            period("2024-03-16", "2024-03-17")
+        );
+        assert_eq!(
+            parse_period("weekend", day("2024-03-16")),
+            period("2024-03-16", "2024-03-17")
+        );
+        assert_eq!(
+            parse_period("weekend", day("2024-03-17")),
+            period("2024-03-17", "2024-03-17")
+        );
+        assert_eq!(
+            parse_period("tomorrow", wednesday),
+            period("2024-03-14", "2024-03-14")
+        );
+        assert_eq!(
+            parse_period("week", wednesday),
+            period("2024-03-13", "2024-03-19")
+        );
+        assert_eq!(
+            parse_period("2024-04-01..2024-04-03", wednesday),
+            period("2024-04-01", "2024-04-03")
+        );
+        assert_eq!(parse_period("2024-04-03..2024-04-01", wednesday), None);
+        assert_eq!(parse_period("someday", wednesday), None);
+    }
+
+    #[test]
+    fn test_near() {
+        let query = SearchQuery::parse("ramen near:xn76urx6 within:2km");
+        assert_eq!(query.terms, vec!["ramen"]);
+        assert_eq!(query.within, Some(2000.0));
+        let near = query.near.unwrap();
+        assert_eq!(
+            query.conditions(&KindLabels::default()),
+            vec![json!({"geo_distance": {
+                "distance": "2000m",
+                "geo": {"lat": near.lat, "lon": near.lon},
+                "ignore_unmapped": true
+            }})]
+        );
+        let query = SearchQuery::parse("near:35.68,139.76");
+        assert_eq!(
+            query.conditions(&KindLabels::default())[0]["geo_distance"]["distance"],
+            "10000m"
+        );
+        assert_eq!(
+            SearchQuery::parse("near:atlantis within:far").terms,
+            vec!["near:atlantis", "within:far"]
+        );
+    }
+
+    #[test]
+    fn test_min_zaps() {
+        let query = SearchQuery::parse("bitcoin min_zaps:1000");
+        assert_eq!(query.terms, vec!["bitcoin"]);
+        assert_eq!(query.min_zaps, Some(1000));
+        assert_eq!(
+            SearchQuery::parse("min_zaps:lots").terms,
+            vec!["min_zaps:lots"]
+        );
+    }
+}
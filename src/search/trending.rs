@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use chrono::Utc;
+use elasticsearch::SearchParts;
+use nostr_sdk::Event;
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+use crate::index::reactions::{reaction_counts, ReactionCounts};
+use crate::index::zaps::zap_totals;
+use crate::search::query::gen_excluded_conditions;
+
+/// recent notes scored per recomputation
+const MAX_CANDIDATES: usize = 10_000;
+/// ids per lookup of the counts
+const CHUNK_SIZE: usize = 1000;
+/// trending notes kept
+pub const MAX_TRENDING: usize = 100;
+
+/// Notes trending over the last `hours`, recomputed on a schedule by `spawn_trending`.
+#[derive(Debug)]
+pub struct Trending {
+    pub hours: u64,
+    notes: RwLock<TrendingNotes>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrendingNotes {
+    /// unix time of the last recomputation, `None` before the first
+    pub updated_at: Option<i64>,
+    pub events: Vec<Event>,
+}
+
+impl Trending {
+    pub fn new(hours: u64) -> Self {
+        Trending {
+            hours,
+            notes: RwLock::new(TrendingNotes::default()),
+        }
+    }
+
+    /// The `limit` most trending notes.
+    pub fn get(&self, limit: usize) -> TrendingNotes {
+        let notes = self.notes.read().unwrap();
+        TrendingNotes {
+            updated_at: notes.updated_at,
+            events: notes.events.iter().take(limit).cloned().collect(),
+        }
+    }
+}
+
+/// Engagement of a note decayed with its age, like the Hacker News ranking: distinct authors
+/// reacting weigh more than likes, so that one account reacting repeatedly counts little, and
+/// zapped sats count logarithmically.
+fn trending_score(reactions: &ReactionCounts, sats: u64, age_hours: f64) -> f64 {
+    let engagement =
+        reactions.likes as f64 + 2.0 * reactions.reactors as f64 + (sats as f64).ln_1p();
+    engagement / (age_hours.max(0.0) + 2.0).powf(1.5)
+}
+
+/// Ids of the candidates with any engagement, the most trending first.
+fn rank(
+    candidates: &[(String, u64)],
+    reactions: &HashMap<String, ReactionCounts>,
+    zaps: &HashMap<String, u64>,
+    now: u64,
+) -> Vec<String> {
+    let mut scored = candidates
+        .iter()
+        .filter_map(|(id, created_at)| {
+            let reactions = reactions.get(id).copied().unwrap_or_default();
+            let sats = zaps.get(id).copied().unwrap_or_default();
+            let age_hours = now.saturating_sub(*created_at) as f64 / 3600.0;
+            let score = trending_score(&reactions, sats, age_hours);
+            if score > 0.0 {
+                Some((id.clone(), score))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scored.into_iter().map(|(id, _)| id).collect()
+}
+
+async fn search(state: &AppState, body: Value) -> anyhow::Result<Vec<Value>> {
+    let res = state
+        .es_client
+        .search(SearchParts::Index(&[state.index_alias_name.as_str()]))
+        .body(body)
+        .send()
+        .await?;
+    if !res.status_code().is_success() {
+        let status_code = res.status_code();
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "failed to search trending notes: {} {}",
+            status_code,
+            body
+        ));
+    }
+    let body = res.json::<Value>().await?;
+    Ok(body["hits"]["hits"].as_array().cloned().unwrap_or_default())
+}
+
+async fn recompute(state: &AppState, trending: &Trending) -> anyhow::Result<()> {
+    let now = Utc::now().timestamp() as u64;
+    let mut filter = vec![
+        json!({ "term": { "event.kind": 1 } }),
+        json!({
+            "range": {
+                "event.created_at": { "gte": now.saturating_sub(trending.hours.saturating_mul(3600)) }
+            }
+        }),
+    ];
+    if state.exclude_content_warnings {
+        filter.push(json!({ "bool": { "must_not": { "term": { "sensitive": true } } } }));
+    }
+    let hits = search(
+        state,
+        json!({
+            "_source": ["event.id", "event.created_at"],
+            "query": { "bool": { "filter": filter, "must_not": gen_excluded_conditions() } },
+            "sort": [{ "event.created_at": { "order": "desc" } }],
+            "size": MAX_CANDIDATES
+        }),
+    )
+    .await?;
+    let candidates = hits
+        .iter()
+        .filter_map(|hit| {
+            let event = &hit["_source"]["event"];
+            Some((
+                event["id"].as_str()?.to_string(),
+                event["created_at"].as_u64()?,
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    let mut reactions = HashMap::new();
+    let mut zaps = HashMap::new();
+    for chunk in candidates.chunks(CHUNK_SIZE) {
+        let ids = chunk.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>();
+        if state.reactions.is_some() {
+            reactions
+                .extend(reaction_counts(&state.es_client, &state.index_alias_name, &ids).await?);
+        }
+        if state.zaps.is_some() {
+            zaps.extend(zap_totals(&state.es_client, &state.index_alias_name, &ids).await?);
+        }
+    }
+    let mut ids = rank(&candidates, &reactions, &zaps, now);
+    ids.truncate(MAX_TRENDING);
+
+    let mut events = vec![];
+    if !ids.is_empty() {
+        let hits = search(
+            state,
+            json!({
+                "_source": ["event", "_raw"],
+                "query": { "terms": { "event.id": ids } },
+                "size": ids.len()
+            }),
+        )
+        .await?;
+        let mut by_id = HashMap::new();
+        for hit in hits {
+            // the original event when `created_at` of `event` is rounded
+            let source = &hit["_source"];
+            let event = match &source["_raw"] {
+                Value::Null => &source["event"],
+                raw => raw,
+            };
+            let event: Event = serde_json::from_value(event.clone())?;
+            by_id.insert(event.id.to_hex(), event);
+        }
+        events = ids.iter().filter_map(|id| by_id.remove(id)).collect();
+    }
+    log::info!(
+        "{} trending note(s) out of {} over the last {} hour(s)",
+        events.len(),
+        candidates.len(),
+        trending.hours
+    );
+    *trending.notes.write().unwrap() = TrendingNotes {
+        updated_at: Some(now as i64),
+        events,
+    };
+    Ok(())
+}
+
+pub fn spawn_trending(state: Arc<AppState>, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            if let Some(trending) = &state.trending {
+                if let Err(e) = recompute(&state, trending).await {
+                    log::error!("{}", e);
+                }
+            }
+            tokio::time::sleep(interval).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::index::reactions::ReactionCounts;
+    use crate::search::trending::rank;
+
+    #[test]
+    fn test_rank() {
+        let now = 1_700_000_000;
+        let hour = 3600;
+        let candidates = vec![
+            ("old".to_string(), now - 20 * hour),
+            ("new".to_string(), now - hour),
+            ("spammed".to_string(), now - hour),
+            ("zapped".to_string(), now - 2 * hour),
+            ("ignored".to_string(), now),
+        ];
+        let reactions = HashMap::from([
+            (
+                "old".to_string(),
+                ReactionCounts {
+                    likes: 10,
+                    dislikes: 0,
+                    reactors: 10,
+                },
+            ),
+            (
+                "new".to_string(),
+                ReactionCounts {
+                    likes: 5,
+                    dislikes: 0,
+                    reactors: 5,
+                },
+            ),
+            // one author reacting over and over
+            (
+                "spammed".to_string(),
+                ReactionCounts {
+                    likes: 6,
+                    dislikes: 0,
+                    reactors: 1,
+                },
+            ),
+        ]);
+        let zaps = HashMap::from([("zapped".to_string(), 21_000)]);
+        assert_eq!(
+            rank(&candidates, &reactions, &zaps, now),
+            vec!["new", "spammed", "zapped", "old"]
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock, Weak};
+
+use nostr_sdk::{Event, Kind};
+use serde::Deserialize;
+
+use crate::app_state::AppState;
+use crate::index::delegation::author;
+use crate::namespace::Namespace;
+
+/// Events of other namespaces that a tenant namespace also indexes: those of its authors,
+/// tagged with one of its tag values, or received from one of its relays.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TenantRule {
+    /// hex pubkeys, matching events signed or delegated by them
+    pub authors: HashSet<String>,
+    /// values by single-letter tag name, e.g. `t` -> `bitcoin`
+    pub tags: HashMap<String, HashSet<String>>,
+    /// relay URLs without a trailing slash
+    pub relays: HashSet<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRule {
+    #[serde(default)]
+    authors: Vec<String>,
+    #[serde(default)]
+    tags: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    relays: Vec<String>,
+}
+
+fn normalize_relay(relay: &str) -> String {
+    relay.trim().trim_end_matches('/').to_string()
+}
+
+impl TenantRule {
+    pub fn matches(&self, event: &Event, relay: Option<&str>) -> bool {
+        if !self.authors.is_empty()
+            && (self.authors.contains(&event.pubkey.to_string())
+                || self.authors.contains(&author(event)))
+        {
+            return true;
+        }
+        if let Some(relay) = relay {
+            if self.relays.contains(&normalize_relay(relay)) {
+                return true;
+            }
+        }
+        event.tags.iter().any(|tag| {
+            let tag = tag.as_vec();
+            match (tag.first(), tag.get(1)) {
+                (Some(name), Some(value)) => self
+                    .tags
+                    .get(name)
+                    .map_or(false, |values| values.contains(&value.to_lowercase())),
+                _ => false,
+            }
+        })
+    }
+}
+
+/// Parses the rules of tenant namespaces keyed by namespace name, e.g.
+/// `{"bitcoin": {"tags": {"t": ["bitcoin"]}, "authors": ["<hex pubkey>"], "relays": ["wss://..."]}}`,
+/// into rules keyed by the index alias of the namespace.
+pub fn parse_tenant_rules(
+    json: &str,
+    namespaces: &[Namespace],
+) -> anyhow::Result<Vec<(String, TenantRule)>> {
+    let raw = serde_json::from_str::<HashMap<String, RawRule>>(json)?;
+    let mut rules = vec![];
+    for (name, raw) in raw {
+        let namespace = namespaces
+            .iter()
+            .find(|ns| ns.name == name)
+            .ok_or_else(|| anyhow::anyhow!("no namespace named {}", name))?;
+        let rule = TenantRule {
+            authors: raw
+                .authors
+                .iter()
+                .map(|a| a.trim().to_lowercase())
+                .collect(),
+            tags: raw
+                .tags
+                .into_iter()
+                .map(|(name, values)| {
+                    let values = values.iter().map(|v| v.trim().to_lowercase()).collect();
+                    (name, values)
+                })
+                .collect(),
+            relays: raw.relays.iter().map(|r| normalize_relay(r)).collect(),
+        };
+        rules.push((namespace.index_prefix("nostr"), rule));
+    }
+    rules.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(rules)
+}
+
+/// Finds the tenant namespaces an event received by a namespace is also routed to.
+#[derive(Debug, Default)]
+pub struct TenantRouter {
+    /// by index alias
+    rules: Vec<(String, TenantRule)>,
+    /// filled once the states of all namespaces are built
+    states: RwLock<HashMap<String, Weak<AppState>>>,
+}
+
+impl TenantRouter {
+    pub fn new(rules: Vec<(String, TenantRule)>) -> Self {
+        TenantRouter {
+            rules,
+            states: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn register(&self, states: &[Arc<AppState>]) {
+        let mut registered = self.states.write().unwrap();
+        for state in states {
+            registered.insert(state.index_alias_name.clone(), Arc::downgrade(state));
+        }
+    }
+
+    /// Aliases of the tenants other than `source` whose rules match the event.
+    ///
+    /// Deletions go to every tenant, since any of them may have been routed events of their
+    /// author by tag or relay, and they are recorded for the events arriving later.
+    fn matching(&self, source: &str, event: &Event, relay: Option<&str>) -> Vec<&str> {
+        let deletion = event.kind == Kind::EventDeletion;
+        self.rules
+            .iter()
+            .filter(|(alias, rule)| {
+                alias.as_str() != source && (deletion || rule.matches(event, relay))
+            })
+            .map(|(alias, _)| alias.as_str())
+            .collect()
+    }
+
+    pub fn targets(&self, source: &str, event: &Event, relay: Option<&str>) -> Vec<Arc<AppState>> {
+        if self.rules.is_empty() {
+            return vec![];
+        }
+        let states = self.states.read().unwrap();
+        self.matching(source, event, relay)
+            .into_iter()
+            .filter_map(|alias| states.get(alias).and_then(|state| state.upgrade()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+
+    use crate::namespace::parse_namespaces;
+    use crate::tenant::{parse_tenant_rules, TenantRouter};
+
+    #[test]
+    fn test_tenant_rules() {
+        let namespaces = parse_namespaces("main,bitcoin,art").unwrap();
+        let member = Keys::generate();
+        let rules = parse_tenant_rules(
+            &format!(
+                r#"{{"bitcoin": {{"tags": {{"t": ["Bitcoin"]}}, "authors": ["{}"]}}, "art": {{"relays": ["wss://art.example.com/"]}}}}"#,
+                member.public_key()
+            ),
+            &namespaces,
+        )
+        .unwrap();
+        assert_eq!(rules[0].0, "nostr-art");
+        let router = TenantRouter::new(rules);
+
+        let tagged = EventBuilder::new(
+            Kind::TextNote,
+            "hello",
+            &[Tag::Hashtag("bitcoin".to_string())],
+        )
+        .to_event(&Keys::generate())
+        .unwrap();
+        assert_eq!(
+            router.matching("nostr-main", &tagged, None),
+            vec!["nostr-bitcoin"]
+        );
+        assert!(router.matching("nostr-bitcoin", &tagged, None).is_empty());
+
+        let by_member = EventBuilder::new(Kind::TextNote, "hello", &[])
+            .to_event(&member)
+            .unwrap();
+        assert_eq!(
+            router.matching("nostr-main", &by_member, Some("wss://art.example.com")),
+            vec!["nostr-art", "nostr-bitcoin"]
+        );
+
+        let other = EventBuilder::new(Kind::TextNote, "hello", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        assert!(router
+            .matching("nostr-main", &other, Some("wss://relay.example.com"))
+            .is_empty());
+
+        let deletion = EventBuilder::new(
+            Kind::EventDeletion,
+            "",
+            &[Tag::Event(tagged.id, None, None)],
+        )
+        .to_event(&Keys::generate())
+        .unwrap();
+        assert_eq!(
+            router.matching("nostr-main", &deletion, None),
+            vec!["nostr-art", "nostr-bitcoin"]
+        );
+
+        assert!(parse_tenant_rules(r#"{"unknown": {}}"#, &namespaces).is_err());
+        assert!(parse_tenant_rules(r#"{"art": {"kinds": [1]}}"#, &namespaces).is_err());
+    }
+}